@@ -0,0 +1,59 @@
+/*
+ * C ABI of vasp-tools interactive client (libvasp_tools), for calling the
+ * interactive VASP server (run-vasp --interactive) from Fortran, C or C++.
+ *
+ * Functions returning int return 0 on success and -1 on error, with the
+ * error message available from vasp_client_last_error().
+ *
+ * Example:
+ *
+ *     VaspClient *c = vasp_client_connect(NULL);
+ *     int n = vasp_client_natoms(c);
+ *     vasp_client_interact(c, n, positions);
+ *     vasp_client_energy(c, &energy);
+ *     vasp_client_forces(c, n, forces);
+ *     vasp_client_free(c);
+ */
+#ifndef VASP_TOOLS_H
+#define VASP_TOOLS_H
+
+#include <stddef.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct VaspClient VaspClient;
+
+/* Connect to VASP server at socket_file, or the default one of current
+ * directory if NULL. Return NULL on error. */
+VaspClient *vasp_client_connect(const char *socket_file);
+
+/* The number of atoms in POSCAR of server, or -1 on error. */
+int vasp_client_natoms(const VaspClient *client);
+
+/* Compute energy and forces of structure with natoms Cartesian positions
+ * in Angstrom (x1, y1, z1, x2, ...). */
+int vasp_client_interact(VaspClient *client, size_t natoms, const double *positions);
+
+/* Energy in eV of last interaction. */
+int vasp_client_energy(const VaspClient *client, double *energy);
+
+/* Forces in eV/Angstrom of last interaction (fx1, fy1, fz1, fx2, ...). */
+int vasp_client_forces(const VaspClient *client, size_t natoms, double *forces);
+
+int vasp_client_pause(VaspClient *client);
+int vasp_client_resume(VaspClient *client);
+int vasp_client_quit(VaspClient *client);
+
+/* Disconnect and free client. */
+void vasp_client_free(VaspClient *client);
+
+/* The message of last error in current thread, valid until next call. */
+const char *vasp_client_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* VASP_TOOLS_H */
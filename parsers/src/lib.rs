@@ -0,0 +1,700 @@
+// [[file:../../vasp-tools.note::*docs][docs:1]]
+//! Pure text parsers of VASP files (OUTCAR, OSZICAR, POSCAR, XDATCAR and
+//! frequencies), without file IO or heavy dependencies, so that they can be
+//! built for wasm32, e.g. for a browser-based OUTCAR summarizer. These
+//! parsers are shared with vasp-tools.
+// docs:1 ends here
+
+// [[file:../../vasp-tools.note::89027374][89027374]]
+use anyhow::{ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+// 89027374 ends here
+
+// [[file:../../vasp-tools.note::7440a40c][7440a40c]]
+pub mod poscar {
+    use super::*;
+
+    /// Return the line index where atomic positions start in POSCAR
+    /// `lines`, and the number of atoms.
+    pub fn locate_positions(lines: &[&str]) -> Result<(usize, usize)> {
+        ensure!(lines.len() > 7, "invalid POSCAR: too few lines");
+        // VASP 5 format has an extra line for element symbols
+        let mut i = 5;
+        let is_numbers = |line: &str| line.split_whitespace().all(|x| x.parse::<usize>().is_ok());
+        if !is_numbers(lines[i]) {
+            i += 1;
+        }
+        let natoms: usize = lines[i]
+            .split_whitespace()
+            .map(|x| x.parse::<usize>())
+            .sum::<std::result::Result<_, _>>()
+            .with_context(|| format!("invalid POSCAR: no atom counts in {:?}", lines[i]))?;
+        i += 1;
+        // skip "Selective dynamics" line
+        if lines.get(i).map(|l| l.trim_start().to_uppercase().starts_with('S')) == Some(true) {
+            i += 1;
+        }
+        // skip "Direct" or "Cartesian" line
+        i += 1;
+        ensure!(lines.len() >= i + natoms, "invalid POSCAR: expect {} atomic positions", natoms);
+        Ok((i, natoms))
+    }
+
+    /// Return lattice vectors (scaled) in POSCAR `lines`
+    pub fn read_lattice_vectors(lines: &[&str]) -> Result<[[f64; 3]; 3]> {
+        let parse = |line: &str| -> Result<Vec<f64>> {
+            line.split_whitespace()
+                .take(3)
+                .map(|x| x.parse().with_context(|| format!("invalid POSCAR line: {:?}", line)))
+                .collect()
+        };
+        ensure!(lines.len() > 4, "invalid POSCAR: too few lines");
+        let scale = parse(lines[1])?;
+        ensure!(scale.len() == 1 && scale[0] > 0.0, "unsupported scaling factor: {:?}", lines[1]);
+        let mut vectors = [[0.0; 3]; 3];
+        for i in 0..3 {
+            let v = parse(lines[2 + i])?;
+            ensure!(v.len() == 3, "invalid lattice vector: {:?}", lines[2 + i]);
+            for k in 0..3 {
+                vectors[i][k] = v[k] * scale[0];
+            }
+        }
+        Ok(vectors)
+    }
+
+    #[test]
+    fn test_poscar() -> Result<()> {
+        let s = "POSCAR
+2.0
+1.0 0.0 0.0
+0.0 1.0 0.0
+0.0 0.0 2.0
+H O
+2 1
+Selective dynamics
+Direct
+0.0 0.0 0.0 T T T
+0.5 0.0 0.0 T T T
+0.0 0.5 0.0 F F F
+";
+        let lines: Vec<_> = s.lines().collect();
+        assert_eq!(locate_positions(&lines)?, (9, 3));
+        assert_eq!(read_lattice_vectors(&lines)?[2], [0.0, 0.0, 4.0]);
+        assert!(locate_positions(&lines[..10]).is_err());
+
+        Ok(())
+    }
+}
+// 7440a40c ends here
+
+// [[file:../../vasp-tools.note::01f25ae3][01f25ae3]]
+pub mod outcar {
+    use super::*;
+
+    //   in kB      -5.33553    -5.33553    -5.01808     0.00000     0.00000     0.00000
+    /// Parse the stress tensor (XX, YY, ZZ, XY, YZ, ZX) in kB from "in kB"
+    /// line.
+    pub fn parse_stress_line(line: &str) -> Result<[f64; 6]> {
+        let attrs: Vec<f64> = line
+            .split_whitespace()
+            .skip(2)
+            .map(|x| x.parse().with_context(|| format!("invalid stress line: {:?}", line)))
+            .collect::<Result<_>>()?;
+        ensure!(attrs.len() == 6, "invalid stress line: {:?}", line);
+        Ok([attrs[0], attrs[1], attrs[2], attrs[3], attrs[4], attrs[5]])
+    }
+
+    /// Return true if VASP finished normally: timing information written at
+    /// the end of OUTCAR `s`.
+    pub fn vasp_finished(s: &str) -> bool {
+        s.contains("General timing and accounting informations")
+    }
+
+    /// Parse timing and memory information at the end of OUTCAR `s`.
+    pub fn parse_timing(s: &str) -> Vec<[String; 2]> {
+        let tail = match s.rsplit_once("General timing and accounting informations") {
+            Some((_, tail)) => tail,
+            None => return vec![],
+        };
+        //                   Total CPU time used (sec):     3061.787
+        //                   Maximum memory used (kb):      624112.
+        tail.lines()
+            .filter(|line| line.contains("(sec):") || line.contains("(kb):"))
+            .filter_map(|line| line.split_once(':'))
+            .map(|(k, v)| [k.trim().to_owned(), v.trim().to_owned()])
+            .collect()
+    }
+
+    /// Parse the number of atoms (NIONS) in OUTCAR `s`.
+    pub fn parse_number_of_atoms(s: &str) -> Option<usize> {
+        //    number of dos      NEDOS =    301   number of ions     NIONS =     52
+        let line = s.lines().find(|line| line.contains("NIONS ="))?;
+        line.rsplit('=').next()?.trim().parse().ok()
+    }
+
+    /// Parse number of SCF iterations of the last ionic step in OUTCAR `s`.
+    pub fn parse_last_nscf(s: &str) -> Option<usize> {
+        // ----------------------------------------- Iteration    1(  23)  ---------------------------------------
+        s.lines()
+            .rev()
+            .find(|line| line.contains("-- Iteration"))
+            .and_then(|line| line.split('(').nth(1))
+            .and_then(|x| x.split(')').next())
+            .and_then(|x| x.trim().parse().ok())
+    }
+
+    /// Parse number of SCF iterations of each ionic step in OUTCAR `s`.
+    pub fn parse_scf_counts(s: &str) -> Vec<usize> {
+        let mut counts: Vec<usize> = vec![];
+        // ----------------------------------------- Iteration    1(  23)  ---------------------------------------
+        for line in s.lines().filter(|line| line.contains("-- Iteration")) {
+            let mut parts = line.split(['(', ')']);
+            let istep = parts
+                .next()
+                .and_then(|x| x.split_whitespace().last())
+                .and_then(|x| x.parse::<usize>().ok());
+            let iscf = parts.next().and_then(|x| x.trim().parse::<usize>().ok());
+            if let (Some(istep), Some(iscf)) = (istep, iscf) {
+                if istep > counts.len() {
+                    counts.resize(istep, 0);
+                }
+                counts[istep - 1] = counts[istep - 1].max(iscf);
+            }
+        }
+        counts
+    }
+
+    /// Parse total magnetization from the last " number of electron" line in
+    /// OUTCAR `s`.
+    pub fn parse_last_magnetization(s: &str) -> Option<f64> {
+        //  number of electron     699.9999451 magnetization     114.0418239
+        s.lines()
+            .rev()
+            .find(|line| line.starts_with(" number of electron"))
+            .and_then(|line| line.split_whitespace().nth(5))
+            .and_then(|x| x.parse().ok())
+    }
+
+    /// Parse forces in eV/Å of atoms from the last "TOTAL-FORCE" block in
+    /// OUTCAR `s`.
+    pub fn parse_last_forces(s: &str) -> Option<Vec<[f64; 3]>> {
+        // POSITION                                       TOTAL-FORCE (eV/Angst)
+        // -----------------------------------------------------------------------------------
+        //      -0.04844      0.25073      4.19570         0.005351      0.001537     -0.846521
+        let i = s.rfind("TOTAL-FORCE (eV/Angst)")?;
+        let forces: Vec<_> = s[i..]
+            .lines()
+            .skip(2)
+            .take_while(|l| !l.trim_start().starts_with("---"))
+            .filter_map(|l| {
+                let f: Vec<f64> = l.split_whitespace().skip(3).filter_map(|x| x.parse().ok()).collect();
+                (f.len() == 3).then(|| [f[0], f[1], f[2]])
+            })
+            .collect();
+        (!forces.is_empty()).then_some(forces)
+    }
+
+    /// Which energy of VASP to use as the computed energy
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+    #[serde(rename_all = "kebab-case")]
+    pub enum EnergyKind {
+        /// The free energy TOTEN (F), which is consistent with forces
+        #[default]
+        Free,
+        /// The energy without entropy
+        WithoutEntropy,
+        /// The energy extrapolated to sigma → 0 (E0), usually preferred for
+        /// molecules with Gaussian smearing
+        Sigma0,
+    }
+
+    impl std::str::FromStr for EnergyKind {
+        type Err = anyhow::Error;
+
+        /// Parse from "free", "without-entropy" or "sigma0"
+        fn from_str(s: &str) -> Result<Self> {
+            match s.trim().to_lowercase().as_str() {
+                "free" | "toten" => Ok(Self::Free),
+                "without-entropy" | "wo-entropy" => Ok(Self::WithoutEntropy),
+                "sigma0" | "e0" => Ok(Self::Sigma0),
+                _ => anyhow::bail!("invalid energy kind {:?}, expect free, without-entropy or sigma0", s),
+            }
+        }
+    }
+
+    /// The energies in eV reported by VASP for one ionic step
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+    pub struct Energies {
+        /// The free energy TOTEN
+        pub free: f64,
+        /// The energy without entropy
+        pub without_entropy: Option<f64>,
+        /// The energy extrapolated to sigma → 0
+        pub sigma0: Option<f64>,
+    }
+
+    impl Energies {
+        /// Return the energy of `kind` if available.
+        pub fn get(&self, kind: EnergyKind) -> Option<f64> {
+            match kind {
+                EnergyKind::Free => Some(self.free),
+                EnergyKind::WithoutEntropy => self.without_entropy,
+                EnergyKind::Sigma0 => self.sigma0,
+            }
+        }
+    }
+
+    /// Parse energy without entropy and energy(sigma->0) from the line
+    /// following "free  energy   TOTEN" of each ionic step.
+    pub fn parse_entropy_line(line: &str) -> Option<(f64, f64)> {
+        //   energy  without entropy=      -10.10000000  energy(sigma->0) =      -10.05000000
+        let (_, tail) = line.split_once("energy  without entropy=")?;
+        let (wo, e0) = tail.split_once("energy(sigma->0) =")?;
+        Some((wo.trim().parse().ok()?, e0.trim().parse().ok()?))
+    }
+
+    /// Parse energies of the last ionic step in OUTCAR `s`.
+    pub fn parse_last_energies(s: &str) -> Option<Energies> {
+        // free  energy   TOTEN  =      -402.83834064 eV
+        let i = s.rfind("free  energy   TOTEN  =")?;
+        let mut lines = s[i..].lines();
+        let free = lines.next()?.split_whitespace().nth(4)?.parse().ok()?;
+        let (without_entropy, sigma0) = lines.take(3).find_map(parse_entropy_line).unzip();
+        Some(Energies {
+            free,
+            without_entropy,
+            sigma0,
+        })
+    }
+
+    /// The summary of one ionic step
+    #[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct IonicStep {
+        /// The ionic step (starting from 1)
+        pub step: usize,
+        /// The free energy TOTEN in eV
+        pub energy: Option<f64>,
+        /// The energy without entropy in eV
+        pub energy_without_entropy: Option<f64>,
+        /// The energy extrapolated to sigma → 0 in eV
+        pub energy_sigma0: Option<f64>,
+        /// The number of SCF iterations
+        pub nscf: usize,
+        /// The volume of cell in Å^3
+        pub volume: Option<f64>,
+        /// The total magnetization in μB
+        pub mag: Option<f64>,
+        /// The max force of all atoms in eV/Å. NOTE: fixed atoms in
+        /// selective dynamics are not excluded, as POSCAR is not available.
+        pub fmax: Option<f64>,
+    }
+
+    /// The summary of OUTCAR
+    #[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct OutcarSummary {
+        pub natoms: Option<usize>,
+        pub steps: Vec<IonicStep>,
+        /// VASP finished normally
+        pub finished: bool,
+        /// Ionic relaxation reached required accuracy
+        pub reached_accuracy: bool,
+        /// The timing and memory information
+        pub timing: Vec<[String; 2]>,
+    }
+
+    /// Summarize ionic steps in OUTCAR `s`.
+    pub fn summarize(s: &str) -> OutcarSummary {
+        let mut steps = vec![];
+        let mut current = IonicStep::default();
+        let mut volume = None;
+        let mut lines = s.lines();
+        while let Some(line) = lines.next() {
+            if line.contains("-- Iteration") {
+                current.nscf += 1;
+            } else if line.contains("volume of cell :") {
+                volume = line.split_whitespace().last().and_then(|x| x.parse().ok());
+            } else if line.starts_with(" number of electron") {
+                //  number of electron     699.9999451 magnetization     114.0418239
+                current.mag = line.split_whitespace().nth(5).and_then(|x| x.parse().ok());
+            } else if line.contains("TOTAL-FORCE (eV/Angst)") {
+                //      -0.04844      0.25073      4.19570         0.005351      0.001537     -0.846521
+                current.fmax = lines
+                    .by_ref()
+                    .skip(1)
+                    .take_while(|l| !l.trim_start().starts_with("---"))
+                    .filter_map(|l| {
+                        let f: Vec<f64> = l.split_whitespace().skip(3).filter_map(|x| x.parse().ok()).collect();
+                        (f.len() == 3).then(|| (f[0] * f[0] + f[1] * f[1] + f[2] * f[2]).sqrt())
+                    })
+                    .reduce(f64::max);
+            } else if line.contains("free  energy   TOTEN  =") {
+                // free  energy   TOTEN  =      -402.83834064 eV
+                current.energy = line.split_whitespace().nth(4).and_then(|x| x.parse().ok());
+                current.volume = volume;
+                current.step = steps.len() + 1;
+                steps.push(std::mem::take(&mut current));
+            } else if let Some((wo, e0)) = parse_entropy_line(line) {
+                // NOTE: printed after TOTEN of the ionic step
+                if let Some(step) = steps.last_mut() {
+                    step.energy_without_entropy = wo.into();
+                    step.energy_sigma0 = e0.into();
+                }
+            }
+        }
+        OutcarSummary {
+            natoms: parse_number_of_atoms(s),
+            steps,
+            finished: vasp_finished(s),
+            reached_accuracy: s.contains("reached required accuracy"),
+            timing: parse_timing(s),
+        }
+    }
+
+    #[test]
+    fn test_outcar_summary() -> Result<()> {
+        let s = "   number of dos      NEDOS =    301   number of ions     NIONS =      2
+  volume of cell :       64.00
+ ----------------------------------------- Iteration    1(   1)  ---------------------------------------
+ ----------------------------------------- Iteration    1(   2)  ---------------------------------------
+ number of electron       8.0000000 magnetization       2.0000000
+  in kB      -5.33553    -5.33553    -5.01808     0.00000     0.00000     0.00000
+ POSITION                                       TOTAL-FORCE (eV/Angst)
+ -----------------------------------------------------------------------------------
+      0.00000      0.00000      0.00000         0.300000      0.000000     -0.400000
+      1.00000      0.00000      0.00000        -0.300000      0.000000      0.400000
+ -----------------------------------------------------------------------------------
+  free  energy   TOTEN  =       -10.00000000 eV
+
+  energy  without entropy=      -10.10000000  energy(sigma->0) =      -10.05000000
+ ----------------------------------------- Iteration    2(   1)  ---------------------------------------
+  free  energy   TOTEN  =       -10.50000000 eV
+ reached required accuracy - stopping structural energy minimisation
+ General timing and accounting informations for this job:
+                  Total CPU time used (sec):     3061.787
+";
+        let summary = summarize(s);
+        assert_eq!(summary.natoms, Some(2));
+        assert_eq!(summary.steps.len(), 2);
+        let step = &summary.steps[0];
+        assert_eq!(step.nscf, 2);
+        assert_eq!(step.volume, Some(64.0));
+        assert_eq!(step.mag, Some(2.0));
+        assert!((step.fmax.unwrap() - 0.5).abs() < 1e-8);
+        assert_eq!(summary.steps[1].energy, Some(-10.5));
+        assert_eq!(summary.steps[1].fmax, None);
+        assert_eq!(step.energy_sigma0, Some(-10.05));
+        assert_eq!(summary.steps[1].energy_sigma0, None);
+        let energies = parse_last_energies(s).unwrap();
+        assert_eq!(energies.free, -10.5);
+        assert_eq!(energies.get(EnergyKind::Sigma0), None);
+        let energies = parse_last_energies(&s[..s.find("Iteration    2").unwrap()]).unwrap();
+        assert_eq!(energies.get(EnergyKind::WithoutEntropy), Some(-10.1));
+        assert_eq!("e0".parse::<EnergyKind>()?, EnergyKind::Sigma0);
+        assert!("x".parse::<EnergyKind>().is_err());
+        assert!(summary.finished && summary.reached_accuracy);
+        assert_eq!(summary.timing.len(), 1);
+
+        assert_eq!(parse_scf_counts(s), vec![2, 1]);
+        assert_eq!(parse_last_nscf(s), Some(1));
+        assert_eq!(parse_last_magnetization(s), Some(2.0));
+        assert_eq!(parse_last_forces(s), Some(vec![[0.3, 0.0, -0.4], [-0.3, 0.0, 0.4]]));
+        let line = s.lines().find(|l| l.contains("in kB")).unwrap();
+        assert_eq!(parse_stress_line(line)?[2], -5.01808);
+
+        Ok(())
+    }
+}
+// 01f25ae3 ends here
+
+// [[file:../../vasp-tools.note::477d7bbe][477d7bbe]]
+pub mod oszicar {
+    use super::*;
+
+    /// One ionic step in OSZICAR
+    #[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct OszicarStep {
+        /// The ionic step (starting from 1)
+        pub step: usize,
+        /// The free energy F in eV
+        pub free_energy: Option<f64>,
+        /// The energy E0 (sigma -> 0) in eV
+        pub e0: Option<f64>,
+        /// The energy change from last step in eV
+        pub de: Option<f64>,
+        /// The total magnetization in μB
+        pub mag: Option<f64>,
+        /// The number of SCF iterations
+        pub nscf: usize,
+    }
+
+    /// Return the value after `key` in `line`, e.g. "F=" in "F= -.40841292E+03".
+    fn value_after(line: &str, key: &str) -> Option<f64> {
+        let (_, tail) = line.split_once(key)?;
+        tail.split_whitespace().next()?.parse().ok()
+    }
+
+    /// Parse ionic steps in OSZICAR text `s`.
+    pub fn parse_oszicar(s: &str) -> Vec<OszicarStep> {
+        //        N       E                     dE             d eps       ncg     rms          rms(c)
+        // DAV:   1     0.123453125167E+03    0.12345E+03   -0.16305E+04  1512   0.121E+03
+        //    1 F= -.40841292E+03 E0= -.40840842E+03  d E =-.408413E+03  mag=    18.0000
+        const SCF_TAGS: &[&str] = &["DAV:", "RMM:", "CG :", "SDA:", "DIA:"];
+        let mut steps = vec![];
+        let mut nscf = 0;
+        for line in s.lines() {
+            if SCF_TAGS.iter().any(|t| line.starts_with(t)) {
+                nscf += 1;
+            } else if line.contains(" F=") {
+                steps.push(OszicarStep {
+                    step: steps.len() + 1,
+                    free_energy: value_after(line, " F="),
+                    e0: value_after(line, "E0="),
+                    de: value_after(line, "d E ="),
+                    mag: value_after(line, "mag="),
+                    nscf,
+                });
+                nscf = 0;
+            }
+        }
+        steps
+    }
+
+    #[test]
+    fn test_oszicar() {
+        let s = "       N       E                     dE             d eps       ncg     rms          rms(c)
+DAV:   1     0.123453125167E+03    0.12345E+03   -0.16305E+04  1512   0.121E+03
+RMM:   2    -0.400000000000E+03   -0.52345E+03   -0.16305E+02  1512   0.121E+02
+   1 F= -.40841292E+03 E0= -.40840842E+03  d E =-.408413E+03  mag=    18.0000
+RMM:   1    -0.400000000000E+03   -0.52345E+03   -0.16305E+02  1512   0.121E+02
+   2 T=   300. E= -.40000000E+03 F= -.40900000E+03 E0= -.40900000E+03  EK= 0.1E+00
+";
+        let steps = parse_oszicar(s);
+        assert_eq!(steps.len(), 2);
+        assert_eq!(steps[0].nscf, 2);
+        assert_eq!(steps[0].free_energy, Some(-408.41292));
+        assert_eq!(steps[0].de, Some(-408.413));
+        assert_eq!(steps[0].mag, Some(18.0));
+        assert_eq!(steps[1].nscf, 1);
+        assert_eq!(steps[1].free_energy, Some(-409.0));
+        assert_eq!(steps[1].mag, None);
+    }
+}
+// 477d7bbe ends here
+
+// [[file:../../vasp-tools.note::49601bab][49601bab]]
+pub mod freq {
+    use super::*;
+
+    /// A vibrational mode from frequency calculation
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct VibMode {
+        /// The index of the mode as numbered by VASP (starting from 1)
+        pub index: usize,
+        /// The frequency in cm-1, negative for imaginary modes
+        pub frequency: f64,
+        /// The energy in meV
+        pub energy: f64,
+        /// The atom positions in Å
+        pub positions: Vec<[f64; 3]>,
+        /// The displacements (eigenvector) of each atom
+        pub displacements: Vec<[f64; 3]>,
+    }
+
+    impl VibMode {
+        pub fn is_imaginary(&self) -> bool {
+            self.frequency < 0.0
+        }
+    }
+
+    /// Parse a frequency line of `VibMode` without positions and
+    /// displacements.
+    ///
+    ///   21 f/i=   10.478975 THz    65.841344 2PiTHz  349.540982 cm-1    43.337574 meV
+    pub fn parse_vib_mode_line(line: &str) -> Option<VibMode> {
+        let (head, tail) = line.split_once('=')?;
+        let mut items = head.split_whitespace();
+        let index = items.next()?.parse().ok()?;
+        let imaginary = match items.next()? {
+            "f" => false,
+            "f/i" => true,
+            _ => return None,
+        };
+        let items: Vec<_> = tail.split_whitespace().collect();
+        let pos = |unit: &str| items.iter().position(|x| *x == unit).and_then(|i| items.get(i.checked_sub(1)?));
+        let cm: f64 = pos("cm-1")?.parse().ok()?;
+        let mev: f64 = pos("meV")?.parse().ok()?;
+        let sign = if imaginary { -1.0 } else { 1.0 };
+        let mode = VibMode {
+            index,
+            frequency: sign * cm,
+            energy: sign * mev,
+            positions: vec![],
+            displacements: vec![],
+        };
+        Some(mode)
+    }
+
+    /// Parse all vibrational modes in OUTCAR `s`, sorted by the mode index.
+    /// If eigenvectors are printed more than once (e.g. with and without
+    /// division by SQRT(mass)), the last ones are taken.
+    pub fn parse_vib_modes(s: &str) -> Result<Vec<VibMode>> {
+        let natoms = outcar::parse_number_of_atoms(s).context("no number of ions (NIONS) found")?;
+        let mut modes = std::collections::BTreeMap::new();
+        let mut lines = s.lines();
+        while let Some(line) = lines.next() {
+            let Some(mut mode) = parse_vib_mode_line(line) else {
+                continue;
+            };
+            // skip the header line: X Y Z dx dy dz
+            for line in lines.by_ref().skip(1).take(natoms) {
+                let v: Vec<f64> = line.split_whitespace().filter_map(|x| x.parse().ok()).collect();
+                ensure!(v.len() == 6, "invalid eigenvector line of mode {}: {:?}", mode.index, line);
+                mode.positions.push([v[0], v[1], v[2]]);
+                mode.displacements.push([v[3], v[4], v[5]]);
+            }
+            ensure!(mode.positions.len() == natoms, "incomplete eigenvector of mode {}", mode.index);
+            modes.insert(mode.index, mode);
+        }
+        Ok(modes.into_values().collect())
+    }
+
+    #[test]
+    fn test_vib_modes() -> Result<()> {
+        let s = "   number of dos      NEDOS =    301   number of ions     NIONS =      2
+   1 f  =   10.478975 THz    65.841344 2PiTHz  349.540982 cm-1    43.337574 meV
+             X         Y         Z           dx          dy          dz
+      0.000000  0.000000  0.000000            0.5           0           0
+      1.000000  0.000000  0.000000           -0.5           0           0
+   2 f/i=    1.000000 THz     6.283185 2PiTHz   33.356410 cm-1     4.135667 meV
+             X         Y         Z           dx          dy          dz
+      0.000000  0.000000  0.000000            0           0.5         0
+      1.000000  0.000000  0.000000            0          -0.5         0
+";
+        let modes = parse_vib_modes(s)?;
+        assert_eq!(modes.len(), 2);
+        assert_eq!(modes[0].displacements[1], [-0.5, 0.0, 0.0]);
+        assert!(modes[1].is_imaginary());
+        assert_eq!(modes[1].energy, -4.135667);
+        assert!(parse_vib_modes(&s[..s.len() - 60]).is_err());
+
+        Ok(())
+    }
+}
+// 49601bab ends here
+
+// [[file:../../vasp-tools.note::4afa7c98][4afa7c98]]
+pub mod xdatcar {
+    use super::*;
+
+    /// One configuration in XDATCAR
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct XdatcarFrame {
+        /// The lattice vectors in Å
+        pub lattice: [[f64; 3]; 3],
+        /// The element symbols of atoms
+        pub symbols: Vec<String>,
+        /// The fractional coordinates of atoms
+        pub fracs: Vec<[f64; 3]>,
+    }
+
+    /// Parse the header of 7 lines (comment, scaling factor, lattice
+    /// vectors, element symbols and counts). Return lattice vectors and
+    /// symbols of atoms.
+    fn parse_header(lines: &[&str]) -> Result<([[f64; 3]; 3], Vec<String>)> {
+        ensure!(lines.len() >= 7, "invalid XDATCAR: incomplete header");
+        let lattice = poscar::read_lattice_vectors(lines)?;
+        let elements: Vec<_> = lines[5].split_whitespace().collect();
+        ensure!(
+            elements.iter().all(|x| x.parse::<usize>().is_err()),
+            "XDATCAR without element symbols is not supported"
+        );
+        let counts: Vec<usize> = lines[6]
+            .split_whitespace()
+            .map(|x| x.parse())
+            .collect::<std::result::Result<_, _>>()
+            .with_context(|| format!("invalid XDATCAR: no atom counts in {:?}", lines[6]))?;
+        ensure!(elements.len() == counts.len(), "invalid XDATCAR: elements and counts mismatch");
+        let symbols = elements
+            .iter()
+            .zip(counts)
+            .flat_map(|(e, n)| vec![e.to_string(); n])
+            .collect();
+        Ok((lattice, symbols))
+    }
+
+    /// Parse all configurations in XDATCAR `s`. The header repeated for
+    /// each configuration in variable cell runs is also supported.
+    pub fn parse_xdatcar(s: &str) -> Result<Vec<XdatcarFrame>> {
+        let lines: Vec<_> = s.lines().collect();
+        let mut frames = vec![];
+        let mut header = None;
+        let mut i = 0;
+        while i < lines.len() {
+            let line = lines[i];
+            if line.trim().is_empty() {
+                i += 1;
+            } else if line.contains("configuration=") {
+                // Direct configuration=     1
+                ensure!(line.trim_start().starts_with('D'), "only direct coordinates supported: {:?}", line);
+                let (lattice, symbols): &([[f64; 3]; 3], Vec<String>) =
+                    header.as_ref().context("invalid XDATCAR: no header")?;
+                let natoms = symbols.len();
+                ensure!(lines.len() > i + natoms, "incomplete configuration: {:?}", line);
+                let fracs = lines[i + 1..=i + natoms]
+                    .iter()
+                    .map(|l| {
+                        let v: Vec<f64> = l.split_whitespace().take(3).filter_map(|x| x.parse().ok()).collect();
+                        ensure!(v.len() == 3, "invalid coordinates line: {:?}", l);
+                        Ok([v[0], v[1], v[2]])
+                    })
+                    .collect::<Result<_>>()?;
+                frames.push(XdatcarFrame {
+                    lattice: *lattice,
+                    symbols: symbols.clone(),
+                    fracs,
+                });
+                i += natoms + 1;
+            } else {
+                header = parse_header(&lines[i..])?.into();
+                i += 7;
+            }
+        }
+        Ok(frames)
+    }
+
+    #[test]
+    fn test_xdatcar() -> Result<()> {
+        let s = "H2O
+           1
+    10.000000    0.000000    0.000000
+     0.000000   10.000000    0.000000
+     0.000000    0.000000   10.000000
+   O    H
+     1     2
+Direct configuration=     1
+  0.00000000  0.00000000  0.00000000
+  0.09570000  0.00000000  0.00000000
+  0.00000000  0.09570000  0.00000000
+Direct configuration=     2
+  0.00000000  0.00000000  0.00000000
+  0.09600000  0.00000000  0.00000000
+  0.00000000  0.09600000  0.00000000
+";
+        let frames = parse_xdatcar(s)?;
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].symbols, vec!["O", "H", "H"]);
+        assert_eq!(frames[1].fracs[1], [0.096, 0.0, 0.0]);
+        assert_eq!(frames[1].lattice[2][2], 10.0);
+
+        // variable cell with repeated header
+        let header: String = s.lines().take(7).map(|l| format!("{}\n", l)).collect();
+        let s2 = format!("{}{}Direct configuration=     3\n  0.1 0.0 0.0\n  0.2 0.0 0.0\n  0.3 0.0 0.0\n", s, header);
+        let frames = parse_xdatcar(&s2)?;
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[2].fracs[2], [0.3, 0.0, 0.0]);
+        assert!(parse_xdatcar(&s[..s.len() - 40]).is_err());
+
+        Ok(())
+    }
+}
+// 4afa7c98 ends here
@@ -0,0 +1,761 @@
+// [[file:../vasp-tools.note::*docs][docs:1]]
+//! Robust calculations using BlackBoxModel (BBM)
+// docs:1 ends here
+
+// [[file:../vasp-tools.note::5ddaa752][5ddaa752]]
+use super::*;
+
+use gosh::gchemol::{Atom, Lattice, Molecule};
+use gosh::model::*;
+use serde::{Deserialize, Serialize};
+// 5ddaa752 ends here
+
+// [[file:../vasp-tools.note::35fc3d71][35fc3d71]]
+/// The env var for the scratch root directory used by BBM scripts
+const BBM_SCR_DIR_ENV: &str = "BBM_SCR_DIR";
+
+/// Set `BBM_SCR_DIR` env var for BBM scripts while alive, and restore the
+/// previous value when dropped.
+///
+/// NOTE: the scripts are spawned inside `BlackBoxModel`, so there is no
+/// `Command` to set env on. The env var is process-wide, and a lock is held
+/// to serialize evaluations using it.
+struct ScratchEnv {
+    previous: Option<std::ffi::OsString>,
+    _lock: tokio::sync::MutexGuard<'static, ()>,
+}
+
+impl ScratchEnv {
+    async fn set(scr: &Path) -> Self {
+        static LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+        let lock = LOCK.lock().await;
+        let previous = std::env::var_os(BBM_SCR_DIR_ENV);
+        std::env::set_var(BBM_SCR_DIR_ENV, scr);
+        Self { previous, _lock: lock }
+    }
+}
+
+impl Drop for ScratchEnv {
+    fn drop(&mut self) {
+        match &self.previous {
+            Some(v) => std::env::set_var(BBM_SCR_DIR_ENV, v),
+            None => std::env::remove_var(BBM_SCR_DIR_ENV),
+        }
+    }
+}
+
+/// Retry policy for BBM calculations, which could fail for transient
+/// filesystem or scheduler problems.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Max number of retries after the first failure.
+    pub max_retries: usize,
+    /// The delay in seconds before the first retry, which will be doubled for
+    /// each retry.
+    pub backoff: f64,
+    /// The wall time limit in seconds for each evaluation. Timed out
+    /// evaluation will not be retried.
+    pub timeout: Option<f64>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 0,
+            backoff: 1.0,
+            timeout: None,
+        }
+    }
+}
+
+/// Check if the results computed by BBM are usable for `mol`.
+fn check_model_properties(mp: &ModelProperties, mol: &Molecule) -> Result<()> {
+    let energy = mp.get_energy().ok_or(format_err!("no energy in BBM output"))?;
+    ensure!(energy.is_finite(), "invalid energy in BBM output: {}", energy);
+    if let Some(forces) = mp.get_forces() {
+        ensure!(
+            forces.len() == mol.natoms(),
+            "expect forces of {} atoms in BBM output, got {}",
+            mol.natoms(),
+            forces.len()
+        );
+    }
+    Ok(())
+}
+
+/// The error returned when BBM evaluation exceeds the time limit
+#[derive(Debug, Clone)]
+pub struct EvaluationTimeout {
+    /// The time limit in seconds
+    pub seconds: f64,
+}
+
+impl std::fmt::Display for EvaluationTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "BBM evaluation timed out after {} seconds", self.seconds)
+    }
+}
+
+impl std::error::Error for EvaluationTimeout {}
+
+// The direct child processes of current process, e.g. spawned by BBM for
+// running scripts.
+fn child_processes() -> Vec<crate::process::ProcessInfo> {
+    let this = std::process::id();
+    match crate::process::list_descendants(this) {
+        Ok(procs) => procs.into_iter().filter(|p| p.ppid == this).collect(),
+        Err(e) => {
+            error!("list child processes failed: {:?}", e);
+            vec![]
+        }
+    }
+}
+
+// Terminate the processes of timed out evaluation: the process groups of
+// `children` spawned for it, or the children with their descendants if they
+// stay in the process group of current process. SIGKILL is sent if they are
+// still alive after a grace period of 2 seconds.
+fn terminate_evaluation(children: &[crate::process::ProcessInfo]) {
+    use crate::process::list_descendants;
+    use crate::session::SessionHandler;
+
+    let this_pgid = unsafe { libc::getpgrp() } as u32;
+    let mut groups = vec![];
+    let mut procs = vec![];
+    for child in children {
+        if child.pgid != this_pgid {
+            groups.push(SessionHandler::for_process_group(child.pgid));
+        } else {
+            procs.extend(list_descendants(child.pid).unwrap_or_default());
+            procs.push(child.clone());
+        }
+    }
+    let kill_all = |signal| {
+        for g in groups.iter() {
+            debug!("send signal {} to process group {}", signal, g.id());
+            let _ = g.signal(signal);
+        }
+        for p in procs.iter() {
+            debug!("send signal {} to process {}: {}", signal, p.pid, p.cmdline);
+            let _ = p.kill(signal);
+        }
+    };
+    // signal 0 checks if any process of the group is alive
+    let alive = || groups.iter().any(|g| unsafe { libc::killpg(g.id() as i32, 0) } == 0) || procs.iter().any(|p| p.kill(0).is_ok());
+
+    kill_all(libc::SIGTERM);
+    let t = std::time::Instant::now();
+    while alive() && t.elapsed().as_secs_f64() < 2.0 {
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+    if alive() {
+        kill_all(libc::SIGKILL);
+    }
+}
+
+/// Compute `mol` using `bbm` in wall time limit of `timeout` seconds. When
+/// timed out, the spawned processes will be terminated, and
+/// `EvaluationTimeout` error will be returned.
+///
+/// NOTE: the child processes of current process spawned during the
+/// evaluation will be terminated on timeout, so it should not be used with
+/// other BBM evaluations running in parallel.
+pub fn compute_with_timeout(bbm: &mut BlackBoxModel, mol: &Molecule, timeout: Option<f64>) -> Result<ModelProperties> {
+    use std::sync::mpsc::{channel, RecvTimeoutError};
+
+    let timeout = match timeout {
+        Some(t) => t,
+        None => return bbm.compute(mol),
+    };
+
+    // the children existed before are not spawned for this evaluation
+    let existing: std::collections::HashSet<_> = child_processes().into_iter().map(|p| p.pid).collect();
+    let (tx, rx) = channel::<()>();
+    std::thread::scope(|s| {
+        let watchdog = s.spawn(move || match rx.recv_timeout(std::time::Duration::from_secs_f64(timeout)) {
+            Err(RecvTimeoutError::Timeout) => {
+                warn!("BBM evaluation exceeds {} seconds, terminating ...", timeout);
+                let children: Vec<_> = child_processes().into_iter().filter(|p| !existing.contains(&p.pid)).collect();
+                terminate_evaluation(&children);
+                true
+            }
+            // computation done: the sender dropped
+            _ => false,
+        });
+        let r = bbm.compute(mol);
+        drop(tx);
+        if watchdog.join().unwrap_or(false) {
+            Err(EvaluationTimeout { seconds: timeout }.into())
+        } else {
+            r
+        }
+    })
+}
+
+/// Compute `mol` using `bbm` with retries following `policy`. Failures of
+/// the run script and unparsable output are both retried. The last error
+/// is returned when all retries failed.
+///
+/// NOTE: this blocks until done, so use `crate::process::run_blocking` in
+/// async code.
+pub fn compute_with_retry(bbm: &mut BlackBoxModel, mol: &Molecule, policy: &RetryPolicy) -> Result<ModelProperties> {
+    let mut delay = policy.backoff;
+    let mut i = 0;
+    loop {
+        let r = compute_with_timeout(bbm, mol, policy.timeout).and_then(|mp| {
+            check_model_properties(&mp, mol)?;
+            Ok(mp)
+        });
+        match r {
+            Ok(mp) => return Ok(mp),
+            // it is not likely to finish in time when retried
+            Err(e) if e.is::<EvaluationTimeout>() => return Err(e),
+            Err(e) if i >= policy.max_retries => {
+                return Err(e).with_context(|| format!("BBM calculation failed after {} retries", policy.max_retries));
+            }
+            Err(e) => {
+                i += 1;
+                warn!("BBM calculation failed: {:?}", e);
+                info!("retry in {} seconds ({}/{})", delay, i, policy.max_retries);
+                std::thread::sleep(std::time::Duration::from_secs_f64(delay));
+                delay *= 2.0;
+            }
+        }
+    }
+}
+
+/// Which scratch directories of BBM evaluations to keep
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScratchPolicy {
+    /// Remove scratch directory after each evaluation
+    KeepNone,
+    /// Keep scratch directories of failed evaluations only
+    KeepFailed,
+    /// Keep scratch directories of the last N evaluations
+    KeepLast(usize),
+    /// Keep all scratch directories
+    KeepAll,
+}
+
+impl Default for ScratchPolicy {
+    fn default() -> Self {
+        Self::KeepNone
+    }
+}
+
+impl std::str::FromStr for ScratchPolicy {
+    type Err = Error;
+
+    /// Parse from "none", "failed", "all" or "last-N"
+    fn from_str(s: &str) -> Result<Self> {
+        let policy = match s.trim().to_lowercase().as_str() {
+            "none" => Self::KeepNone,
+            "failed" => Self::KeepFailed,
+            "all" => Self::KeepAll,
+            x => match x.strip_prefix("last-") {
+                Some(n) => Self::KeepLast(n.parse().with_context(|| format!("invalid scratch policy: {:?}", s))?),
+                None => bail!("invalid scratch policy: {:?}", s),
+            },
+        };
+        Ok(policy)
+    }
+}
+
+#[test]
+fn test_scratch_policy() -> Result<()> {
+    assert_eq!("none".parse::<ScratchPolicy>()?, ScratchPolicy::KeepNone);
+    assert_eq!("Failed".parse::<ScratchPolicy>()?, ScratchPolicy::KeepFailed);
+    assert_eq!("last-3".parse::<ScratchPolicy>()?, ScratchPolicy::KeepLast(3));
+    assert!("last-x".parse::<ScratchPolicy>().is_err());
+    assert!("some".parse::<ScratchPolicy>().is_err());
+    Ok(())
+}
+// 35fc3d71 ends here
+
+// [[file:../vasp-tools.note::893154b3][893154b3]]
+/// `ModelProperties` with the stress tensor and all energies of VASP, which
+/// are not available in `ModelProperties`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Properties {
+    pub mp: ModelProperties,
+    /// The stress tensor in kB (XX, YY, ZZ, XY, YZ, ZX) as in VASP OUTCAR
+    pub stress: Option<[f64; 6]>,
+    /// The free energy, energy without entropy and energy(sigma->0)
+    #[serde(default)]
+    pub energies: Option<crate::vasp::outcar::Energies>,
+}
+
+impl From<ModelProperties> for Properties {
+    fn from(mp: ModelProperties) -> Self {
+        Self {
+            mp,
+            stress: None,
+            energies: None,
+        }
+    }
+}
+
+impl Properties {
+    /// Use the energy of `kind` as the energy of `ModelProperties`. Return
+    /// error if it is not available.
+    pub fn select_energy(&mut self, kind: crate::vasp::outcar::EnergyKind) -> Result<()> {
+        let energy = self
+            .energies
+            .and_then(|e| e.get(kind))
+            .with_context(|| format!("no {:?} energy in computed results", kind))?;
+        self.mp.set_energy(energy);
+        Ok(())
+    }
+
+    /// Return the max force in eV/Å, excluding components fixed in
+    /// selective dynamics `frozen` flags. Return None if no forces.
+    pub fn fmax(&self, frozen: Option<&[[bool; 3]]>) -> Option<f64> {
+        let forces = self.mp.get_forces()?;
+        crate::vasp::forces::fmax(forces, frozen).into()
+    }
+}
+
+/// Parse the last stress tensor from the most recently modified OUTCAR
+/// found in `dir` recursively. Return None if not found.
+pub fn find_last_stress(dir: &Path) -> Option<[f64; 6]> {
+    fn find_outcars(dir: &Path, found: &mut Vec<PathBuf>) {
+        if let Ok(entries) = std::fs::read_dir(dir) {
+            for p in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+                if p.is_dir() {
+                    find_outcars(&p, found);
+                } else if p.file_name().map_or(false, |x| x == "OUTCAR") {
+                    found.push(p);
+                }
+            }
+        }
+    }
+
+    let mut outcars = vec![];
+    find_outcars(dir, &mut outcars);
+    let f = outcars.into_iter().max_by_key(|f| f.metadata().and_then(|m| m.modified()).ok())?;
+    match crate::vasp::outcar::parse_last_stress(&f) {
+        Ok(stress) => Some(stress),
+        Err(e) => {
+            debug!("no stress: {:?}", e);
+            None
+        }
+    }
+}
+// 893154b3 ends here
+
+// [[file:../vasp-tools.note::1500a89d][1500a89d]]
+/// The geometry stored in checkpoint file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Geometry {
+    symbols: Vec<String>,
+    positions: Vec<[f64; 3]>,
+    cell: Option<[[f64; 3]; 3]>,
+}
+
+impl Geometry {
+    fn from_molecule(mol: &Molecule) -> Self {
+        Self {
+            symbols: mol.symbols().map(|s| s.to_string()).collect(),
+            positions: mol.positions().collect(),
+            cell: mol.get_lattice().map(|lat| lat.vectors()),
+        }
+    }
+
+    fn to_molecule(&self) -> Molecule {
+        let atoms = self.symbols.iter().zip(&self.positions).map(|(s, &p)| Atom::new(s.as_str(), p));
+        let mut mol = Molecule::from_atoms(atoms);
+        if let Some(cell) = self.cell {
+            mol.set_lattice(Lattice::new(cell));
+        }
+        mol
+    }
+
+    /// Return true if `mol` has the same geometry within numerical noise.
+    fn matches(&self, mol: &Molecule) -> bool {
+        let other = Self::from_molecule(mol);
+        let close = |a: &[f64; 3], b: &[f64; 3]| a.iter().zip(b).all(|(x, y)| (x - y).abs() < 1e-8);
+        self.symbols == other.symbols
+            && self.positions.len() == other.positions.len()
+            && self.positions.iter().zip(&other.positions).all(|(a, b)| close(a, b))
+            && match (&self.cell, &other.cell) {
+                (Some(a), Some(b)) => a.iter().zip(b).all(|(a, b)| close(a, b)),
+                (None, None) => true,
+                _ => false,
+            }
+    }
+}
+
+/// The computed results stored in checkpoint file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Computed {
+    energy: f64,
+    forces: Option<Vec<[f64; 3]>>,
+    dipole: Option<[f64; 3]>,
+    #[serde(default)]
+    stress: Option<[f64; 6]>,
+}
+
+impl Computed {
+    fn from_properties(props: &Properties) -> Self {
+        let mp = &props.mp;
+        Self {
+            energy: mp.get_energy().unwrap_or(f64::NAN),
+            forces: mp.get_forces().cloned(),
+            dipole: mp.get_dipole(),
+            stress: props.stress,
+        }
+    }
+
+    fn to_properties(&self, mol: &Molecule) -> Properties {
+        let mut mp = ModelProperties::default();
+        mp.set_energy(self.energy);
+        if let Some(forces) = &self.forces {
+            mp.set_forces(forces.clone());
+        }
+        if let Some(dipole) = self.dipole {
+            mp.set_dipole(dipole);
+        }
+        mp.set_molecule(mol.clone());
+        Properties {
+            mp,
+            stress: self.stress,
+            energies: None,
+        }
+    }
+}
+
+/// The state of BBM calculations saved after each evaluation
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// The number of finished evaluations
+    ncalls: usize,
+    /// The last evaluated geometry
+    geometry: Option<Geometry>,
+    /// The results of last evaluation
+    computed: Option<Computed>,
+    /// The scratch directory of last evaluation if kept
+    scratch: Option<PathBuf>,
+}
+
+impl Checkpoint {
+    /// Load checkpoint from file `f` in JSON format.
+    pub fn load(f: &Path) -> Result<Self> {
+        let s = gut::fs::read_file(f)?;
+        let state = serde_json::from_str(&s).with_context(|| format!("invalid checkpoint file: {:?}", f))?;
+        Ok(state)
+    }
+
+    /// Save checkpoint into file `f` in JSON format. The file is replaced
+    /// atomically, so it is always valid even when the job is killed.
+    pub fn save(&self, f: &Path) -> Result<()> {
+        let s = serde_json::to_string_pretty(self)?;
+        let tmp = f.with_extension("tmp");
+        gut::fs::write_to_file(&tmp, &s)?;
+        std::fs::rename(&tmp, f).with_context(|| format!("save checkpoint to {:?}", f))?;
+        Ok(())
+    }
+
+    /// The last evaluated geometry
+    pub fn last_molecule(&self) -> Option<Molecule> {
+        self.geometry.as_ref().map(|g| g.to_molecule())
+    }
+
+    /// The number of finished evaluations
+    pub fn ncalls(&self) -> usize {
+        self.ncalls
+    }
+}
+
+/// Options for creating `BbmDriver`
+#[derive(Debug, Clone, Default)]
+pub struct BbmOptions {
+    pub retry: RetryPolicy,
+    pub scratch_policy: ScratchPolicy,
+    /// The parent directory of scratch directories. Default to "bbm-scratch"
+    /// in current directory.
+    pub scratch_root: Option<PathBuf>,
+    /// Save checkpoint into this file, and resume from it if exists.
+    pub checkpoint: Option<PathBuf>,
+}
+
+impl BbmOptions {
+    /// Return true if the options involve process-wide or file states, which
+    /// cannot be shared by multiple drivers.
+    pub fn is_exclusive(&self) -> bool {
+        self.scratch_policy != ScratchPolicy::KeepNone || self.checkpoint.is_some() || self.retry.timeout.is_some()
+    }
+
+    /// Create `BbmDriver` for BBM template files in `bbm_dir`.
+    pub fn build_driver(&self, bbm_dir: &Path) -> Result<BbmDriver> {
+        let mut driver = match &self.checkpoint {
+            Some(f) => BbmDriver::resume(bbm_dir, self.retry.clone(), f)?,
+            None => BbmDriver::new(bbm_dir, self.retry.clone())?,
+        };
+        if let Some(root) = &self.scratch_root {
+            driver.set_scratch_policy(self.scratch_policy, root);
+        } else {
+            driver.scratch_policy = self.scratch_policy;
+        }
+        Ok(driver)
+    }
+}
+
+/// Drive BBM calculations with retries and checkpoints
+pub struct BbmDriver {
+    bbm: BlackBoxModel,
+    retry: RetryPolicy,
+    state: Checkpoint,
+    checkpoint_file: Option<PathBuf>,
+    scratch_policy: ScratchPolicy,
+    // the parent directory of scratch directories for each evaluation
+    scratch_root: PathBuf,
+}
+
+impl BbmDriver {
+    /// Create BBM from template files in `bbm_dir`, which will be retried on
+    /// failure following `retry` policy.
+    pub fn new(bbm_dir: &Path, retry: RetryPolicy) -> Result<Self> {
+        let bbm = BlackBoxModel::from_dir(bbm_dir)?;
+        Ok(Self {
+            bbm,
+            retry,
+            state: Checkpoint::default(),
+            checkpoint_file: None,
+            scratch_policy: ScratchPolicy::default(),
+            scratch_root: "bbm-scratch".into(),
+        })
+    }
+
+    /// Set the policy for keeping scratch directories of evaluations, which
+    /// will be created in `scratch_root`.
+    ///
+    /// NOTE: the scratch directory is passed to BBM scripts using
+    /// `BBM_SCR_DIR` env var, which is process-wide, so evaluations in
+    /// scratch directories are serialized across threads.
+    pub fn set_scratch_policy(&mut self, policy: ScratchPolicy, scratch_root: &Path) {
+        self.scratch_policy = policy;
+        self.scratch_root = scratch_root.to_owned();
+    }
+
+    /// Restore the state from `checkpoint_file` if it exists, and save
+    /// checkpoint into it after each evaluation. The last evaluated geometry
+    /// will not be recomputed.
+    pub fn resume(bbm_dir: &Path, retry: RetryPolicy, checkpoint_file: &Path) -> Result<Self> {
+        let mut driver = Self::new(bbm_dir, retry)?;
+        if checkpoint_file.exists() {
+            driver.state = Checkpoint::load(checkpoint_file)?;
+            info!("resumed from {:?} after {} calls", checkpoint_file, driver.state.ncalls);
+        }
+        driver.checkpoint_file = checkpoint_file.to_owned().into();
+        Ok(driver)
+    }
+
+    /// The number of evaluations, including those restored from checkpoint
+    pub fn ncalls(&self) -> usize {
+        self.state.ncalls
+    }
+
+    /// Compute `mol`. The results will be taken from checkpoint if `mol` is
+    /// the last evaluated geometry. The stress tensor is read from VASP
+    /// OUTCAR found in scratch directory if available.
+    pub async fn compute(&mut self, mol: &Molecule) -> Result<Properties> {
+        if let (Some(geom), Some(computed)) = (&self.state.geometry, &self.state.computed) {
+            if geom.matches(mol) {
+                info!("reuse results of call {} from checkpoint", self.state.ncalls);
+                return Ok(computed.to_properties(mol));
+            }
+        }
+
+        let props = self.compute_in_scratch(mol).await?;
+        self.state.ncalls += 1;
+        self.state.geometry = Geometry::from_molecule(mol).into();
+        self.state.computed = Computed::from_properties(&props).into();
+        if let Some(f) = &self.checkpoint_file {
+            self.state.save(f)?;
+        }
+        Ok(props)
+    }
+}
+
+impl BbmDriver {
+    // Run BBM in a new scratch directory, which will be kept or removed
+    // following scratch policy.
+    async fn compute_in_scratch(&mut self, mol: &Molecule) -> Result<Properties> {
+        // NOTE: scratch directory is required for keeping timed out
+        // evaluation
+        if self.scratch_policy == ScratchPolicy::KeepNone && self.retry.timeout.is_none() {
+            let mp = crate::process::run_blocking(|| compute_with_retry(&mut self.bbm, mol, &self.retry))?;
+            return Ok(mp.into());
+        }
+
+        let ncall = self.state.ncalls + 1;
+        let scr = self.scratch_root.join(format!("call-{:05}", ncall));
+        std::fs::create_dir_all(&scr).with_context(|| format!("create scratch dir {:?}", scr))?;
+        let env = ScratchEnv::set(&scr).await;
+        let r = crate::process::run_blocking(|| compute_with_retry(&mut self.bbm, mol, &self.retry)).map(|mp| Properties {
+            mp,
+            // read stress before the scratch directory removed
+            stress: find_last_stress(&scr),
+            energies: None,
+        });
+        drop(env);
+
+        // always keep scratch of timed out evaluation for inspection
+        let timed_out = matches!(&r, Err(e) if e.is::<EvaluationTimeout>());
+        let keep = match self.scratch_policy {
+            _ if timed_out => true,
+            ScratchPolicy::KeepNone => false,
+            ScratchPolicy::KeepFailed => r.is_err(),
+            ScratchPolicy::KeepLast(n) => n > 0,
+            ScratchPolicy::KeepAll => true,
+        };
+        if keep {
+            self.append_manifest(ncall, &scr, r.is_ok())?;
+            self.state.scratch = scr.clone().into();
+        } else {
+            std::fs::remove_dir_all(&scr).ok();
+            self.state.scratch = None;
+        }
+        if let ScratchPolicy::KeepLast(n) = self.scratch_policy {
+            if !timed_out {
+                self.prune_scratch(n)?;
+            }
+        }
+
+        r.with_context(|| format!("BBM evaluation {} failed; scratch directory: {:?}", ncall, scr))
+    }
+
+    // Record the mapping from ncall to scratch directory
+    fn append_manifest(&self, ncall: usize, scr: &Path, ok: bool) -> Result<()> {
+        use std::io::Write;
+
+        let f = self.scratch_root.join("manifest.txt");
+        let mut fp = std::fs::OpenOptions::new().create(true).append(true).open(&f)?;
+        let status = if ok { "ok" } else { "failed" };
+        writeln!(fp, "{:5} {:6} {}", ncall, status, scr.display())?;
+        Ok(())
+    }
+
+    // Remove old scratch directories, keeping the last `n` ones
+    fn prune_scratch(&self, n: usize) -> Result<()> {
+        let mut dirs: Vec<_> = std::fs::read_dir(&self.scratch_root)?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.is_dir() && p.file_name().map_or(false, |x| x.to_string_lossy().starts_with("call-")))
+            .collect();
+        // NOTE: zero padded names sorted in order of ncall
+        dirs.sort();
+        let nremove = dirs.len().saturating_sub(n);
+        for d in &dirs[..nremove] {
+            debug!("remove old scratch directory {:?}", d);
+            std::fs::remove_dir_all(d).with_context(|| format!("remove scratch dir {:?}", d))?;
+        }
+        Ok(())
+    }
+}
+// 1500a89d ends here
+
+// [[file:../vasp-tools.note::57217285][57217285]]
+#[test]
+fn test_bbm_checkpoint() -> Result<()> {
+    let atoms = vec![Atom::new("C", [0.0, 0.0, 0.0]), Atom::new("O", [1.2, 0.0, 0.0])];
+    let mut mol = Molecule::from_atoms(atoms);
+    mol.set_lattice(Lattice::new([[10.0, 0.0, 0.0], [0.0, 10.0, 0.0], [0.0, 0.0, 10.0]]));
+    let mut mp = ModelProperties::default();
+    mp.set_energy(-1.5);
+    mp.set_forces(vec![[0.1, 0.0, 0.0], [-0.1, 0.0, 0.0]]);
+
+    let state = Checkpoint {
+        ncalls: 3,
+        geometry: Geometry::from_molecule(&mol).into(),
+        computed: Computed::from_properties(&mp.into()).into(),
+        scratch: None,
+    };
+    let dir = tempfile::tempdir()?;
+    let f = dir.path().join("bbm.ckpt");
+    state.save(&f)?;
+    let state = Checkpoint::load(&f)?;
+    assert_eq!(state.ncalls(), 3);
+    let mol2 = state.last_molecule().unwrap();
+    assert!(state.geometry.as_ref().unwrap().matches(&mol2));
+    mol.set_position(1, [1.3, 0.0, 0.0]);
+    assert!(!state.geometry.as_ref().unwrap().matches(&mol));
+    let props = state.computed.as_ref().unwrap().to_properties(&mol2);
+    assert_eq!(props.mp.get_energy(), Some(-1.5));
+    assert_eq!(props.stress, None);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_bbm_scratch() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let tdir = tempfile::tempdir()?;
+    let bbm_dir = tdir.path().join("bbm");
+    std::fs::create_dir(&bbm_dir)?;
+    let mut mp = ModelProperties::default();
+    mp.set_energy(-1.5);
+    mp.set_forces(vec![[0.1, 0.0, 0.0], [-0.1, 0.0, 0.0]]);
+    gut::fs::write_to_file(bbm_dir.join("results.txt"), &mp.to_string())?;
+    gut::fs::write_to_file(bbm_dir.join(".env"), "BBM_TPL_FILE=input.tera\nBBM_RUN_FILE=submit.sh\n")?;
+    gut::fs::write_to_file(bbm_dir.join("input.tera"), "fake\n")?;
+    // fake VASP writing OUTCAR into scratch directory
+    let script = r#"#! /bin/bash
+cat > /dev/null
+echo "  in kB      4.0     5.0     6.0     0.0     0.0     0.0" > "$BBM_SCR_DIR/OUTCAR"
+cat "$BBM_TPL_DIR/results.txt"
+"#;
+    let f = bbm_dir.join("submit.sh");
+    gut::fs::write_to_file(&f, script)?;
+    std::fs::set_permissions(&f, std::fs::Permissions::from_mode(0o755))?;
+
+    let root = tdir.path().join("scratch");
+    let mut driver = BbmDriver::new(&bbm_dir, RetryPolicy::default())?;
+    driver.set_scratch_policy(ScratchPolicy::KeepLast(2), &root);
+    let atoms = vec![Atom::new("C", [0.0, 0.0, 0.0]), Atom::new("O", [1.2, 0.0, 0.0])];
+    let mut mol = Molecule::from_atoms(atoms);
+    for i in 0..3 {
+        mol.set_position(1, [0.1 * i as f64, 0.0, 0.0]);
+        let props = driver.compute(&mol).await?;
+        assert_eq!(props.mp.get_energy(), Some(-1.5));
+        // read before the scratch directory pruned
+        assert_eq!(props.stress, Some([4.0, 5.0, 6.0, 0.0, 0.0, 0.0]));
+    }
+    assert_eq!(driver.ncalls(), 3);
+    assert!(!root.join("call-00001").exists());
+    assert!(root.join("call-00002/OUTCAR").exists());
+    assert!(root.join("call-00003/OUTCAR").exists());
+    assert_eq!(driver.state.scratch.as_deref(), Some(root.join("call-00003").as_path()));
+    let manifest = gut::fs::read_file(root.join("manifest.txt"))?;
+    let calls: Vec<_> = manifest.lines().map(|line| line.split_whitespace().collect::<Vec<_>>()).collect();
+    assert_eq!(calls.len(), 3);
+    assert_eq!(calls[2][..2], ["3", "ok"]);
+    assert!(calls[2][2].ends_with("call-00003"));
+
+    Ok(())
+}
+// 57217285 ends here
+
+// [[file:../vasp-tools.note::0b46f28c][0b46f28c]]
+#[test]
+fn test_terminate_evaluation() -> Result<()> {
+    use std::os::unix::process::CommandExt;
+
+    let mut child = std::process::Command::new("sleep").arg("30").process_group(0).spawn()?;
+    // NOTE: other tests may spawn child processes in parallel
+    let pid = child.id();
+    let children: Vec<_> = child_processes().into_iter().filter(|p| p.pid == pid).collect();
+    assert_eq!(children.len(), 1);
+    assert_eq!(children[0].pgid, pid);
+
+    // reap the child as BBM does, or it stays alive as zombie
+    let waiter = std::thread::spawn(move || child.wait());
+    let t = std::time::Instant::now();
+    terminate_evaluation(&children);
+    assert!(t.elapsed().as_secs_f64() < 2.0);
+    assert_eq!(waiter.join().unwrap()?.code(), None);
+
+    Ok(())
+}
+// 0b46f28c ends here
@@ -0,0 +1,9 @@
+// [[file:../../vasp-tools.note::77ed3b42][77ed3b42]]
+use gut::prelude::*;
+
+fn main() -> Result<()> {
+    vasp_tools::cli::fake_ipi_driver_enter_main()?;
+
+    Ok(())
+}
+// 77ed3b42 ends here
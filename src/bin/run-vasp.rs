@@ -1,9 +1,7 @@
 // [[file:../../vasp-tools.note::9814a64e][9814a64e]]
 use gut::prelude::*;
 
-fn main() -> Result<()> {
-    vasp_tools::cli::run_vasp_enter_main()?;
-
-    Ok(())
+fn main() -> Result<std::process::ExitCode> {
+    vasp_tools::cli::run_vasp_enter_main()
 }
 // 9814a64e ends here
@@ -0,0 +1,9 @@
+// [[file:../../vasp-tools.note::dd2c1187][dd2c1187]]
+use gut::prelude::*;
+
+fn main() -> Result<()> {
+    vasp_tools::cli::vasp_enter_main()?;
+
+    Ok(())
+}
+// dd2c1187 ends here
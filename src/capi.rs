@@ -0,0 +1,291 @@
+// [[file:../vasp-tools.note::*docs][docs:1]]
+//! C ABI of the interactive client, for calling the VASP server from
+//! simulation codes in Fortran, C or C++. See `include/vasp_tools.h`.
+//!
+//! All functions returning `int` return 0 on success and -1 on error, with
+//! the error message available from `vasp_client_last_error`.
+// docs:1 ends here
+
+// [[file:../vasp-tools.note::5a56427c][5a56427c]]
+use super::*;
+
+use crate::bbm::Properties;
+use crate::socket::Client;
+use gosh::gchemol::prelude::*;
+use gosh::gchemol::Molecule;
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+// 5a56427c ends here
+
+// [[file:../vasp-tools.note::7073cd1b][7073cd1b]]
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+fn set_last_error(msg: &str) {
+    let msg = CString::new(msg.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|e| *e.borrow_mut() = msg);
+}
+
+/// Call `f` and convert error or panic into -1, which must not unwind
+/// across FFI boundary.
+fn ffi_call(f: impl FnOnce() -> Result<()>) -> c_int {
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)) {
+        Ok(Ok(())) => 0,
+        Ok(Err(e)) => {
+            set_last_error(&format!("{:#}", e));
+            -1
+        }
+        Err(_) => {
+            set_last_error("panic in vasp-tools");
+            -1
+        }
+    }
+}
+
+/// The opaque handle of interactive client
+pub struct VaspClient {
+    rt: tokio::runtime::Runtime,
+    client: Client,
+    // the working directory of VASP server
+    dir: PathBuf,
+    // the structure in POSCAR of server, updated with positions of each
+    // interaction
+    mol: Molecule,
+    read_pattern: String,
+    // the results of last interaction
+    last: Option<Properties>,
+}
+
+impl VaspClient {
+    fn connect(socket_file: Option<&Path>) -> Result<Self> {
+        let rt = tokio::runtime::Runtime::new()?;
+        let socket_file = match socket_file {
+            Some(f) => f.to_owned(),
+            None => crate::socket::default_socket_file()?,
+        };
+        let client = rt.block_on(Client::connect(&socket_file))?;
+        let dir = match client.engine_dir() {
+            Some(d) => d.to_owned(),
+            None => std::env::current_dir()?,
+        };
+        let poscar = dir.join("POSCAR");
+        let mol = Molecule::from_file(&poscar).with_context(|| format!("read {:?}", poscar))?;
+        Ok(Self {
+            rt,
+            client,
+            dir,
+            mol,
+            read_pattern: crate::vasp::stdout::VASP_READ_PATTERN.into(),
+            last: None,
+        })
+    }
+
+    /// Compute energy and forces of structure with Cartesian `positions`
+    /// in Å.
+    fn interact(&mut self, positions: Vec<[f64; 3]>) -> Result<()> {
+        ensure!(
+            positions.len() == self.mol.natoms(),
+            "expect {} atoms as in POSCAR, got {}",
+            self.mol.natoms(),
+            positions.len()
+        );
+        self.mol.set_positions(positions);
+        // for the first time run, VASP reads coordinates from POSCAR
+        let input = if self.dir.join("OUTCAR").exists() {
+            crate::vasp::stdin::format_scaled_positions(&self.mol)?
+        } else {
+            gut::fs::write_to_file(self.dir.join("POSCAR"), &self.mol.format_as("vasp/input")?)?;
+            String::new()
+        };
+        let out = self.rt.block_on(self.client.interact(&input, &self.read_pattern))?;
+        let parsed = crate::vasp::results::parse_last_results(&out, &self.dir, Some(self.mol.natoms()));
+        let (props, source) = self.rt.block_on(parsed)?;
+        debug!("VASP results read from {:?}", source);
+        self.last = props.into();
+        Ok(())
+    }
+
+    fn last(&self) -> Result<&Properties> {
+        self.last.as_ref().context("no results: call vasp_client_interact first")
+    }
+}
+
+/// Connect to VASP server at `socket_file`, or the default one of current
+/// directory if NULL. Return NULL on error.
+///
+/// # Safety
+///
+/// `socket_file` must be NULL or a valid C string.
+#[no_mangle]
+pub unsafe extern "C" fn vasp_client_connect(socket_file: *const c_char) -> *mut VaspClient {
+    let mut handle = std::ptr::null_mut();
+    ffi_call(|| {
+        let f = if socket_file.is_null() {
+            None
+        } else {
+            Some(PathBuf::from(CStr::from_ptr(socket_file).to_str()?))
+        };
+        handle = Box::into_raw(Box::new(VaspClient::connect(f.as_deref())?));
+        Ok(())
+    });
+    handle
+}
+
+/// Return the number of atoms in structure of server, or -1 on error.
+///
+/// # Safety
+///
+/// `client` must be returned by `vasp_client_connect`.
+#[no_mangle]
+pub unsafe extern "C" fn vasp_client_natoms(client: *const VaspClient) -> c_int {
+    match client.as_ref() {
+        Some(c) => c.mol.natoms() as c_int,
+        None => {
+            set_last_error("null client");
+            -1
+        }
+    }
+}
+
+/// Compute energy and forces of structure with `natoms` Cartesian
+/// `positions` in Å (x1, y1, z1, x2, ...). Results can be fetched using
+/// `vasp_client_energy` and `vasp_client_forces`.
+///
+/// # Safety
+///
+/// `client` must be returned by `vasp_client_connect`, and `positions`
+/// must point to `3 * natoms` doubles.
+#[no_mangle]
+pub unsafe extern "C" fn vasp_client_interact(client: *mut VaspClient, natoms: usize, positions: *const f64) -> c_int {
+    ffi_call(|| {
+        let client = client.as_mut().context("null client")?;
+        ensure!(!positions.is_null(), "null positions");
+        let positions = std::slice::from_raw_parts(positions, 3 * natoms);
+        let positions = positions.chunks_exact(3).map(|p| [p[0], p[1], p[2]]).collect();
+        client.interact(positions)
+    })
+}
+
+/// Write the energy in eV of last interaction into `energy`.
+///
+/// # Safety
+///
+/// `client` must be returned by `vasp_client_connect`, and `energy` must
+/// point to a double.
+#[no_mangle]
+pub unsafe extern "C" fn vasp_client_energy(client: *const VaspClient, energy: *mut f64) -> c_int {
+    ffi_call(|| {
+        let client = client.as_ref().context("null client")?;
+        ensure!(!energy.is_null(), "null energy");
+        *energy = client.last()?.mp.get_energy().context("no energy")?;
+        Ok(())
+    })
+}
+
+/// Write the forces in eV/Å of last interaction into `forces` of `natoms`
+/// atoms (fx1, fy1, fz1, fx2, ...).
+///
+/// # Safety
+///
+/// `client` must be returned by `vasp_client_connect`, and `forces` must
+/// point to `3 * natoms` doubles.
+#[no_mangle]
+pub unsafe extern "C" fn vasp_client_forces(client: *const VaspClient, natoms: usize, forces: *mut f64) -> c_int {
+    ffi_call(|| {
+        let client = client.as_ref().context("null client")?;
+        ensure!(!forces.is_null(), "null forces");
+        let computed = client.last()?.mp.get_forces().context("no forces")?;
+        ensure!(computed.len() == natoms, "expect forces of {} atoms, got {}", natoms, computed.len());
+        let forces = std::slice::from_raw_parts_mut(forces, 3 * natoms);
+        for (f, c) in forces.chunks_exact_mut(3).zip(computed) {
+            f.copy_from_slice(c);
+        }
+        Ok(())
+    })
+}
+
+/// Send control signal `sig` to server.
+unsafe fn control(client: *mut VaspClient, sig: &str) -> c_int {
+    ffi_call(|| {
+        let c = client.as_mut().context("null client")?;
+        match sig {
+            "pause" => c.rt.block_on(c.client.try_pause()),
+            "resume" => c.rt.block_on(c.client.try_resume()),
+            _ => c.rt.block_on(c.client.try_quit()),
+        }
+    })
+}
+
+/// Pause VASP for saving CPU times when idle.
+///
+/// # Safety
+///
+/// `client` must be returned by `vasp_client_connect`.
+#[no_mangle]
+pub unsafe extern "C" fn vasp_client_pause(client: *mut VaspClient) -> c_int {
+    control(client, "pause")
+}
+
+/// Resume paused VASP.
+///
+/// # Safety
+///
+/// `client` must be returned by `vasp_client_connect`.
+#[no_mangle]
+pub unsafe extern "C" fn vasp_client_resume(client: *mut VaspClient) -> c_int {
+    control(client, "resume")
+}
+
+/// Stop VASP server.
+///
+/// # Safety
+///
+/// `client` must be returned by `vasp_client_connect`.
+#[no_mangle]
+pub unsafe extern "C" fn vasp_client_quit(client: *mut VaspClient) -> c_int {
+    control(client, "quit")
+}
+
+/// Disconnect and free `client`.
+///
+/// # Safety
+///
+/// `client` must be returned by `vasp_client_connect` and not freed before.
+#[no_mangle]
+pub unsafe extern "C" fn vasp_client_free(client: *mut VaspClient) {
+    if !client.is_null() {
+        drop(Box::from_raw(client));
+    }
+}
+
+/// Return the message of last error in current thread, which is valid until
+/// next call of vasp-tools functions.
+#[no_mangle]
+pub extern "C" fn vasp_client_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ptr())
+}
+// 7073cd1b ends here
+
+// [[file:../vasp-tools.note::ace82567][ace82567]]
+#[test]
+fn test_capi_errors() -> Result<()> {
+    unsafe {
+        let f = CString::new("/nonexistent/vasp.sock")?;
+        assert!(vasp_client_connect(f.as_ptr()).is_null());
+        let msg = CStr::from_ptr(vasp_client_last_error()).to_string_lossy();
+        assert!(msg.contains("/nonexistent/vasp.sock"), "{}", msg);
+
+        assert_eq!(vasp_client_natoms(std::ptr::null()), -1);
+        let mut energy = 0.0;
+        assert_eq!(vasp_client_energy(std::ptr::null(), &mut energy), -1);
+        let msg = CStr::from_ptr(vasp_client_last_error()).to_string_lossy();
+        assert_eq!(msg, "null client");
+        vasp_client_free(std::ptr::null_mut());
+    }
+
+    Ok(())
+}
+// ace82567 ends here
@@ -2,82 +2,608 @@
 use super::*;
 use crate::socket::Client;
 
-use gosh::model::ModelProperties;
 use gut::cli::*;
 use gut::fs::*;
 // 9fd9c449 ends here
 
 // [[file:../vasp-tools.note::234c75e6][234c75e6]]
-// const VASP_READ_PATTERN: &str = "POSITIONS: read from stdin";
-const VASP_READ_PATTERN: &str = "POSITIONS: reading from stdin";
+/// The output format of computed results
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    /// The text format of `ModelProperties`
+    Text,
+    Json,
+    Msgpack,
+}
 
-/// # Parameters
-///
-/// * control: try to pause/resume running process to reduce CPU usages
-async fn interactive_vasp_session_bbm(client: &mut Client, control: bool) -> Result<()> {
-    use gosh::adaptor::ModelAdaptor;
+impl std::str::FromStr for OutputFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            "msgpack" => Ok(Self::Msgpack),
+            _ => bail!("unsupported output format: {:?}", s),
+        }
+    }
+}
+
+/// The binary or structured output formats of computed results
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EncodedFormat {
+    Json,
+    Msgpack,
+}
+
+impl OutputFormat {
+    /// The encoded format, or None for text format.
+    fn encoded(self) -> Option<EncodedFormat> {
+        match self {
+            Self::Text => None,
+            Self::Json => Some(EncodedFormat::Json),
+            Self::Msgpack => Some(EncodedFormat::Msgpack),
+        }
+    }
+}
+
+/// Where to read computed results in client path
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ResultSource {
+    /// Use vasprun.xml if present, otherwise OUTCAR
+    Auto,
+    Outcar,
+    Vasprun,
+    /// Energies and forces in VASP stdout of the interaction
+    Stdout,
+    /// Energies only in OSZICAR
+    Oszicar,
+}
+
+impl std::str::FromStr for ResultSource {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "auto" => Ok(Self::Auto),
+            "outcar" => Ok(Self::Outcar),
+            "vasprun" => Ok(Self::Vasprun),
+            "stdout" => Ok(Self::Stdout),
+            "oszicar" => Ok(Self::Oszicar),
+            _ => bail!("unsupported result source: {:?}", s),
+        }
+    }
+}
+
+impl ResultSource {
+    /// Read the results of last calculation in current directory, or in
+    /// VASP `stdout` of the interaction.
+    async fn read_last(self, stdout: &str) -> Result<crate::bbm::Properties> {
+        use gosh::adaptor::ModelAdaptor;
+        use gosh::model::ModelProperties;
+
+        let vasprun: &Path = "vasprun.xml".as_ref();
+        // the number of ionic steps for checking if vasprun.xml is outdated.
+        // NOTE: parse the full OUTCAR only when needed
+        let mut nsteps = None;
+        let use_vasprun = match self {
+            // vasprun.xml could be left by previous run, e.g. with LVASPRUN
+            // disabled in current run
+            Self::Auto if vasprun.exists() => {
+                nsteps = crate::vasp::outcar::parse_scf_counts("OUTCAR".as_ref()).ok().map(|x| x.len());
+                !nsteps.is_some_and(|n| crate::vasp::vasprun::is_outdated(vasprun, n))
+            }
+            Self::Auto => false,
+            Self::Outcar => false,
+            Self::Vasprun => true,
+            Self::Stdout => {
+                // NOTE: for larger system, there may have no forces in stdout
+                let (energies, forces) = crate::vasp::stdout::parse_energies_and_forces(stdout)
+                    .context("no forces found in VASP stdout, try another result source")?;
+                let mut mp = ModelProperties::default();
+                mp.set_energy(energies.free);
+                mp.set_forces(forces);
+                let mut props: crate::bbm::Properties = mp.into();
+                props.energies = energies.into();
+                return Ok(props);
+            }
+            Self::Oszicar => {
+                let s = gut::fs::read_file("OSZICAR")?;
+                let (energies, _) =
+                    crate::vasp::stdout::parse_last_energy_line(&s).context("no ionic step found in OSZICAR")?;
+                let mut mp = ModelProperties::default();
+                mp.set_energy(energies.free);
+                let mut props: crate::bbm::Properties = mp.into();
+                props.energies = energies.into();
+                return Ok(props);
+            }
+        };
+        if use_vasprun {
+            debug!("read results from {:?}", vasprun);
+            // VASP may be still writing the last calculation
+            crate::vasp::vasprun::read_last_calculation(vasprun, 5.0, nsteps).await
+        } else {
+            let mp = gosh::adaptor::Vasp().parse_last("OUTCAR")?;
+            let mut props: crate::bbm::Properties = mp.into();
+            props.energies = crate::vasp::outcar::parse_last_energies("OUTCAR".as_ref())?;
+            Ok(props)
+        }
+    }
+}
+
+/// The computed properties returned by client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputField {
+    Energy,
+    Forces,
+    Stress,
+    /// The total magnetization
+    Magmom,
+}
+
+impl std::str::FromStr for OutputField {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "energy" => Ok(Self::Energy),
+            "forces" => Ok(Self::Forces),
+            "stress" => Ok(Self::Stress),
+            "magmom" => Ok(Self::Magmom),
+            _ => bail!("unsupported output field: {:?}", s),
+        }
+    }
+}
+
+/// Read the total magnetization of last ionic step in VASP `stdout`, or
+/// OSZICAR/OUTCAR in current directory.
+fn read_last_magmom(stdout: &str) -> Option<f64> {
+    use crate::vasp::stdout::parse_last_energy_line;
+
+    if let Some((_, Some(mag))) = parse_last_energy_line(stdout) {
+        return Some(mag);
+    }
+    if let Some((_, Some(mag))) = gut::fs::read_file("OSZICAR").ok().and_then(|s| parse_last_energy_line(&s)) {
+        return Some(mag);
+    }
+    crate::vasp::outcar::parse_last_magnetization("OUTCAR".as_ref()).ok()?
+}
+
+/// Computed results in structured output
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct ComputedOutput {
+    /// The energy in eV
+    energy: Option<f64>,
+    /// The free energy, energy without entropy and energy(sigma->0) in eV
+    energies: Option<crate::vasp::outcar::Energies>,
+    /// The forces in eV/Å
+    forces: Option<Vec<[f64; 3]>>,
+    /// The max force in eV/Å, excluding atoms fixed in selective dynamics
+    /// unless disabled
+    #[serde(default)]
+    fmax: Option<f64>,
+    /// The stress tensor in kB (XX, YY, ZZ, XY, YZ, ZX) as in OUTCAR
+    stress: Option<[f64; 6]>,
+    dipole: Option<[f64; 3]>,
+    /// The total magnetization in μB
+    #[serde(default)]
+    magmom: Option<f64>,
+    metadata: OutputMetadata,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct OutputMetadata {
+    /// The working directory of VASP calculation
+    directory: PathBuf,
+    /// The number of SCF iterations in last ionic step
+    nscf: Option<usize>,
+    /// The time when the results parsed
+    timestamp: String,
+}
+
+impl ComputedOutput {
+    /// Collect results in `props` and OUTCAR `outcar`. Components fixed in
+    /// `frozen` flags are excluded from fmax. Only `fields` are collected if
+    /// not empty, and OUTCAR is parsed only for stress or magmom not found
+    /// elsewhere.
+    fn from_vasp_outcar(
+        props: &crate::bbm::Properties,
+        outcar: &Path,
+        frozen: Option<&[[bool; 3]]>,
+        fields: &[OutputField],
+        stdout: &str,
+    ) -> Result<Self> {
+        use crate::vasp::outcar::*;
+
+        let all = fields.is_empty();
+        let want = |f: OutputField| all || fields.contains(&f);
+        let mp = &props.mp;
+        let metadata = OutputMetadata {
+            directory: std::env::current_dir()?,
+            nscf: if all { parse_last_nscf(outcar)? } else { None },
+            timestamp: chrono::Local::now().to_rfc3339(),
+        };
+        let forces = want(OutputField::Forces).then(|| mp.get_forces().cloned()).flatten();
+        if !all {
+            ensure!(!want(OutputField::Forces) || forces.is_some(), "no forces in computed results");
+        }
+        let output = Self {
+            energy: mp.get_energy(),
+            energies: props.energies,
+            fmax: forces.as_ref().and(props.fmax(frozen)),
+            forces,
+            stress: want(OutputField::Stress)
+                .then(|| props.stress.or_else(|| parse_last_stress(outcar).ok()))
+                .flatten(),
+            dipole: if all { mp.get_dipole() } else { None },
+            magmom: want(OutputField::Magmom).then(|| read_last_magmom(stdout)).flatten(),
+            metadata,
+        };
+        Ok(output)
+    }
+
+    /// Encode in `format` for writing into stdout
+    fn to_bytes(&self, format: EncodedFormat) -> Result<Vec<u8>> {
+        let bytes = match format {
+            EncodedFormat::Json => {
+                let mut bytes = serde_json::to_vec(self)?;
+                bytes.push(b'\n');
+                bytes
+            }
+            EncodedFormat::Msgpack => rmp_serde::to_vec_named(self)?,
+        };
+        Ok(bytes)
+    }
+}
+
+/// Write computed `output` into stdout.
+fn write_output(output: &[u8]) -> Result<()> {
+    use std::io::Write;
+
+    let mut stdout = std::io::stdout();
+    stdout.write_all(output)?;
+    stdout.flush()?;
+    Ok(())
+}
+
+/// Read ionic velocities from POSCAR/CONTCAR file `f`.
+fn read_velocities_from(f: &Path) -> Result<Vec<[f64; 3]>> {
+    let s = gut::fs::read_file(f)?;
+    crate::vasp::poscar::read_velocities(&s)?.with_context(|| format!("no velocities found in {:?}", f))
+}
+
+/// The options for interacting with VASP server in BBM mode
+struct BbmSessionOptions {
+    /// Try to pause/resume running process to reduce CPU usages
+    control: bool,
+    /// The output format of computed results
+    format: OutputFormat,
+    /// Where to read computed results
+    source: ResultSource,
+    /// The properties returned, all if empty
+    fields: Vec<OutputField>,
+    energy: crate::vasp::outcar::EnergyKind,
+    /// The regex for reading VASP stdout
+    read_pattern: String,
+    /// The initial ionic velocities written into POSCAR
+    velocities: Option<Vec<[f64; 3]>>,
+    /// The extxyz file for appending computed structures
+    trajectory: Option<PathBuf>,
+    /// The constraints applied to returned forces
+    constraints: Option<crate::constraint::Constraints>,
+    /// The hook receiving each evaluation with computed results
+    eval_hook: Option<crate::hooks::EvaluationHook>,
+    /// Exclude atoms fixed in selective dynamics from fmax
+    force_mask: bool,
+}
+
+/// Interact with VASP server for structure in `txt` using `opts`. Return
+/// the computed output written into stdout.
+async fn interactive_vasp_session_bbm(client: &mut Client, txt: &str, opts: &BbmSessionOptions) -> Result<Vec<u8>> {
+    use crate::vasp::stdin::PositionsMode;
+
+    // the structure in protocol v2 with explicit mode, or a complete POSCAR
+    let positions = crate::vasp::stdin::parse_positions_input(txt)?;
+    let started = match client.engine_steps() {
+        Some(n) => n > 0,
+        // NOTE: old server does not report engine state
+        None => std::path::Path::new("OUTCAR").exists(),
+    };
+    let mode = match &positions {
+        Some(p) => {
+            match (p.mode, started) {
+                (PositionsMode::Start, true) => {
+                    bail!("VASP has already started, restart the server for a new structure, or use mode=update")
+                }
+                (PositionsMode::Update, false) => bail!("VASP is waiting for the initial structure, use mode=start"),
+                _ => {}
+            }
+            p.mode
+        }
+        None if started => PositionsMode::Update,
+        None => PositionsMode::Start,
+    };
+    if let (Some(p), Some((natoms, species))) = (&positions, client.engine_structure()) {
+        if p.mode == PositionsMode::Update {
+            ensure!(
+                p.natoms == natoms,
+                "structure mismatch: expect {} atoms ({}) as VASP started with, got {}",
+                natoms,
+                species,
+                p.natoms
+            );
+        }
+    }
 
     // for the first time run, VASP reads coordinates from POSCAR
-    let input: String = if !std::path::Path::new("OUTCAR").exists() {
+    let (input, poscar): (String, String) = if mode == PositionsMode::Start {
         debug!("Write complete POSCAR file for initial calculation.");
-        let txt = crate::vasp::stdin::read_txt_from_stdin()?;
-        gut::fs::write_to_file("POSCAR", &txt)?;
+        let poscar = positions.map_or_else(|| txt.to_owned(), |p| p.payload);
+        match &opts.velocities {
+            Some(velocities) => {
+                let txt = crate::vasp::poscar::with_velocities(&poscar, velocities)?;
+                gut::fs::write_to_file("POSCAR", &txt)?;
+            }
+            None => gut::fs::write_to_file("POSCAR", &poscar)?,
+        }
         // inform server to start with empty input
-        "".into()
+        ("".into(), poscar)
     } else {
         // resume paused calculation
-        if control {
+        if opts.control {
             client.try_resume().await?;
         }
         // redirect scaled positions to server for interactive VASP calculationsSP
         debug!("Send scaled coordinates to interactive VASP server.");
-        crate::vasp::stdin::get_scaled_positions_from_stdin()?
+        match positions {
+            Some(p) => {
+                if let Some(lattice) = &p.lattice {
+                    let s = gut::fs::read_file("POSCAR")?;
+                    let lines: Vec<_> = s.lines().collect();
+                    crate::vasp::stdin::check_lattice(lattice, &crate::vasp::poscar::read_lattice_vectors(&lines)?)?;
+                }
+                (p.payload, String::new())
+            }
+            None => (
+                crate::vasp::stdin::get_scaled_positions_from_poscar_str(txt, "POSCAR".as_ref())?,
+                txt.to_owned(),
+            ),
+        }
     };
 
     // wait for output
-    let s = client.interact(&input, VASP_READ_PATTERN).await?;
+    let s = client.interact(&input, &opts.read_pattern).await?;
     // NOTE: for larger system, there may have no energy/forces information in
     // stdout
     // let (energy, forces) = crate::vasp::stdout::parse_energy_and_forces(&s)?;
     // let mut mp = ModelProperties::default();
     // mp.set_energy(energy);
     // mp.set_forces(forces);
-    let mp = gosh::adaptor::Vasp().parse_last("OUTCAR")?;
-    println!("{}", mp);
+    let mut props = opts.source.read_last(&s).await?;
+    props.select_energy(opts.energy)?;
+    let mol = {
+        use gosh::gchemol::prelude::*;
+        if poscar.is_empty() {
+            // positions only: the other info is the same as in POSCAR
+            let mut mol = gosh::gchemol::Molecule::from_file("POSCAR")?;
+            mol.set_scaled_positions(crate::vasp::guard::parse_scaled_positions(&input)?);
+            mol
+        } else {
+            gosh::gchemol::Molecule::from_str(&poscar, "vasp/input")?
+        }
+    };
+    if opts.trajectory.is_some() || opts.constraints.is_some() || opts.eval_hook.is_some() {
+        // NOTE: the trajectory records the unconstrained forces
+        if let Some(f) = &opts.trajectory {
+            crate::trajectory::append_extxyz(f, &mol, &props)?;
+        }
+        // NOTE: the hook receives the VASP results before corrections and
+        // constraints
+        if let Some(hook) = &opts.eval_hook {
+            let record = crate::hooks::EvaluationRecord::new(&mol, &props)?;
+            hook.call(&record)?.apply(&mut props)?;
+        }
+        if let Some(constraints) = &opts.constraints {
+            for (c, value) in constraints.values(&mol)? {
+                info!("constraint {}: {:.4}", c, value);
+            }
+            let mut forces = props.mp.get_forces().context("no forces to constrain")?.clone();
+            constraints.project_forces(&mol, &mut forces)?;
+            props.mp.set_forces(forces);
+        }
+    }
+    let frozen = crate::vasp::forces::frozen_flags(&mol);
+    let frozen = opts.force_mask.then_some(frozen.as_slice());
+    if let Some(fmax) = props.fmax(frozen) {
+        info!("fmax = {:.6} eV/Å", fmax);
+    }
+    let output = match opts.format.encoded() {
+        None if !opts.fields.is_empty() && !opts.fields.contains(&OutputField::Forces) => {
+            let mut mp = gosh::model::ModelProperties::default();
+            mp.set_energy(props.mp.get_energy().context("no energy in computed results")?);
+            format!("{}\n", mp).into_bytes()
+        }
+        None => format!("{}\n", props.mp).into_bytes(),
+        Some(encoded) => {
+            let output = ComputedOutput::from_vasp_outcar(&props, "OUTCAR".as_ref(), frozen, &opts.fields, &s)?;
+            output.to_bytes(encoded)?
+        }
+    };
+    write_output(&output)?;
 
     // pause VASP to avoid wasting CPU times, which will be resumed on next calculation
-    if control {
+    if opts.control {
         client.try_pause().await?;
     }
 
+    Ok(output)
+}
+
+/// Simulate interactive VASP calculation for testing. All options can also
+/// be set using environment variables, as `fake-vasp` is usually invoked by
+/// other programs.
+#[derive(Debug, StructOpt)]
+struct FakeVaspCli {
+    /// The number of atoms. If not provided, canned output of a real VASP
+    /// calculation with 25 atoms will be used.
+    #[structopt(long, env = "FAKE_VASP_NATOMS")]
+    natoms: Option<usize>,
+
+    /// The delay in seconds for each ionic step
+    #[structopt(long, env = "FAKE_VASP_LATENCY", default_value = "0.1")]
+    latency: f64,
+
+    /// Fail with SCF error and exit with nonzero code at this step
+    #[structopt(long, env = "FAKE_VASP_SCF_FAIL_AT")]
+    scf_fail_at: Option<usize>,
+
+    /// Crash (abort without any message) at this step
+    #[structopt(long, env = "FAKE_VASP_CRASH_AT")]
+    crash_at: Option<usize>,
+
+    /// Exit normally after this many steps as if NSW is exhausted
+    #[structopt(long, env = "FAKE_VASP_NSW")]
+    nsw: Option<usize>,
+
+    /// Print garbage instead of energy and forces at this step
+    #[structopt(long, env = "FAKE_VASP_GARBAGE_AT")]
+    garbage_at: Option<usize>,
+
+    /// Compute energy and forces using this potential (harmonic or lj) for
+    /// structure in POSCAR updated with positions from stdin. The results
+    /// are also written into vasprun.xml.
+    #[structopt(long, env = "FAKE_VASP_POTENTIAL", conflicts_with = "natoms")]
+    potential: Option<crate::potential::Potential>,
+
+    /// Replay stdout recorded in this transcript file (see `run-vasp
+    /// --transcript`), instead of simulating VASP. Exit as recorded, or
+    /// normally when the transcript is exhausted.
+    #[structopt(long, env = "FAKE_VASP_REPLAY", conflicts_with = "potential")]
+    replay: Option<PathBuf>,
+}
+
+/// Replay recorded interactions in transcript `f`: read the same number of
+/// lines from stdin as recorded input, and print the recorded stdout, with
+/// `latency` in seconds for each step.
+fn replay_transcript(f: &Path, latency: f64) -> Result<()> {
+    use std::io::{BufRead, Write};
+
+    let entries = crate::session::read_transcript(f)?;
+    let stdin = std::io::stdin();
+    let mut handler = stdin.lock();
+    for entry in entries {
+        let mut input = String::new();
+        for _ in 0..entry.input.lines().count() {
+            if handler.read_line(&mut input)? == 0 {
+                // stdin closed
+                return Ok(());
+            }
+        }
+        if input != entry.input {
+            eprintln!("fake-vasp: input differs from transcript at step {}", entry.step);
+        }
+        gut::utils::sleep(latency);
+        print!("{}", entry.output);
+        std::io::stdout().flush()?;
+        if entry.exited {
+            std::process::exit(entry.code.unwrap_or(1));
+        }
+    }
     Ok(())
 }
 
+/// Print synthetic output of ionic step `i` for `natoms` atoms. The energy
+/// (E0) is set as the step number.
+fn print_fake_vasp_step(i: usize, natoms: usize) {
+    if i == 1 {
+        println!(" POSCAR found type information on POSCAR  H");
+        println!(" POSCAR found :  1 types and {:6} ions", natoms);
+        println!(" entering main loop");
+    }
+    println!("       N       E                     dE             d eps       ncg     rms          rms(c)");
+    for n in 1..=3 {
+        println!(
+            "RMM: {:3}    {:-19.12E}   -0.12964E+03   -0.42761E+02  1152   0.831E+01",
+            n,
+            -(i as f64)
+        );
+    }
+    println!("FORCES:");
+    for j in 0..natoms {
+        let f = 0.01 * ((i + j) % 7) as f64;
+        println!("{:14.7}{:14.7}{:14.7}", f, -f, 0.5 * f);
+    }
+    let energy = format!("F= -.85097948E+02 E0={:-12.8E}  d E =-.850979E+02  mag=     2.9646", i);
+    println!("{:4} {}", i, energy);
+}
+
 /// for creating `fake-vasp` binary, simulating interactive VASP caclulation
 pub fn simulate_interactive_vasp() -> Result<()> {
     use gut::utils::sleep;
 
+    let args = FakeVaspCli::parse();
+    if let Some(f) = &args.replay {
+        return replay_transcript(f, args.latency);
+    }
     let part0 = include_str!("../tests/files/interactive_iter0.txt");
     let part1 = include_str!("../tests/files/interactive_iter1.txt");
     // let energy = "F= -.85097948E+02 E0= -.85096866E+02  d E =-.850979E+02  mag=     2.9646";
 
-    let natoms = 25;
+    let mut fake = match args.potential {
+        Some(pot) => crate::potential::PotentialVasp::from_poscar(pot, "POSCAR".as_ref())?.into(),
+        None => None,
+    };
+    let natoms = fake.as_ref().map(|x| x.natoms()).or(args.natoms).unwrap_or(25);
     let stdin = std::io::stdin();
-    print!("{}", part0);
-    for i in 2.. {
-        println!("POSITIONS: reading from stdin");
-        let mut handler = stdin.lock();
-        let mut positions = String::new();
-        for _ in 0..natoms {
-            handler.read_line(&mut positions)?;
-        }
-        // make it slower: 0.1 second delay
-        sleep(0.1);
-        print!("{}", part1);
-        // replace energy with iter number, so we can test against it to make
-        // sure we are correct during multiple interactions.
-        let energy = format!("F= -.85097948E+02 E0={:-12.8E}  d E =-.850979E+02  mag=     2.9646", i);
-        println!("{:4} {}", i, energy);
+    for i in 1.. {
+        if i > 1 {
+            println!("POSITIONS: reading from stdin");
+            let mut handler = stdin.lock();
+            let mut positions = String::new();
+            for _ in 0..natoms {
+                if handler.read_line(&mut positions)? == 0 {
+                    // stdin closed
+                    return Ok(());
+                }
+            }
+            if let Some(fake) = fake.as_mut() {
+                fake.update_scaled_positions(&positions)?;
+            }
+        }
+        // make it slower: 0.1 second delay by default
+        sleep(args.latency);
+
+        if args.crash_at == Some(i) {
+            std::process::abort();
+        }
+        if args.scf_fail_at == Some(i) {
+            println!("DAV:   1    -0.129636840222E+03   -0.12964E+03   -0.42761E+02  1152   0.831E+01");
+            println!(" Error EDDDAV: Call to ZHEGV failed. Returncode =   8 1  16");
+            std::process::exit(1);
+        }
+        if args.garbage_at == Some(i) {
+            println!("#@!$%^&* garbage output at step {} *&^%$!@#", i);
+            println!("FORCES: NaN ******** ????");
+            continue;
+        }
+
+        match (&fake, args.natoms) {
+            (Some(fake), _) => fake.compute_step(i)?,
+            (None, Some(n)) => print_fake_vasp_step(i, n),
+            (None, None) if i == 1 => print!("{}", part0),
+            (None, None) => {
+                print!("{}", part1);
+                // replace energy with iter number, so we can test against it to make
+                // sure we are correct during multiple interactions.
+                let energy = format!("F= -.85097948E+02 E0={:-12.8E}  d E =-.850979E+02  mag=     2.9646", i);
+                println!("{:4} {}", i, energy);
+            }
+        }
+
+        if args.nsw == Some(i) {
+            println!(" writing wavefunctions");
+            break;
+        }
     }
     Ok(())
 }
@@ -99,6 +625,22 @@ struct ServerCli {
     #[structopt(long, name = "VASP_WORK_DIR")]
     stop: Option<PathBuf>,
 
+    /// How to stop VASP: soft for stopping after current ionic step
+    /// finished (LSTOP), hard for stopping at the next electronic step
+    /// immediately (LABORT). The default is hard.
+    #[structopt(long, requires = "VASP_WORK_DIR")]
+    stop_mode: Option<crate::vasp::stopcar::StopMode>,
+
+    /// Stop VASP after current ionic step finished, the same as
+    /// `--stop-mode soft`.
+    #[structopt(long, requires = "VASP_WORK_DIR", conflicts_with_all = ["stop_mode", "stop_hard"])]
+    stop_soft: bool,
+
+    /// Stop VASP at the next electronic step immediately, the same as
+    /// `--stop-mode hard`.
+    #[structopt(long, requires = "VASP_WORK_DIR", conflicts_with_all = ["stop_mode", "stop_soft"])]
+    stop_hard: bool,
+
     /// Run VASP for one-time single point calculation. The mandatory
     /// parameters in INCAR will be automatically updated.
     #[structopt(long, conflicts_with = "interactive, frequency")]
@@ -110,38 +652,561 @@ struct ServerCli {
     frequency: bool,
 
     /// Run VASP in interactive mode for long-live calculation. The
-    /// mandatory parameters in INCAR will be automatically updated.
+    /// mandatory parameters in INCAR will be automatically updated. Touch
+    /// a `CANCEL` file in working directory to stop VASP gracefully and
+    /// shut down the server.
     #[structopt(long, conflicts_with = "single_point")]
     interactive: bool,
 
-    /// Path to the socket file to bind (only valid for interactive calculation)
-    #[structopt(short = 'u', default_value = "vasp.sock")]
-    socket_file: PathBuf,
+    /// Exit with code 3 if VASP finished but the calculation is not
+    /// converged (SCF reached NELM, or ionic relaxation exhausted NSW), as
+    /// judged in `vasp report`. VASP failures always set nonzero exit code:
+    /// the exit code of VASP, or 128 + signal number if VASP was killed.
+    #[structopt(long, conflicts_with = "interactive")]
+    fail_on_unconverged: bool,
+
+    /// Render VASP input files in current directory from templates in
+    /// this directory before launching: "*.hbs" files (e.g. INCAR.hbs,
+    /// KPOINTS.hbs) are rendered with the structure as BBM templates, and
+    /// other files (e.g. POTCAR) are copied. POSCAR is written from the
+    /// structure if no template for it.
+    #[structopt(long, requires = "structure")]
+    from_template: Option<PathBuf>,
+
+    /// The structure file (xyz, cif, POSCAR, ...) for rendering templates
+    #[structopt(long, requires = "from_template")]
+    structure: Option<PathBuf>,
+
+    /// Path to the socket file to bind (only valid for interactive
+    /// calculation). The default is in per-user runtime directory
+    /// (XDG_RUNTIME_DIR) if available, otherwise "vasp.sock".
+    #[structopt(short = 'u')]
+    socket_file: Option<PathBuf>,
+
+    /// The file mode (octal) of socket file
+    #[structopt(long, default_value = "600")]
+    socket_mode: String,
+
+    /// The group owning socket file, for sharing the server with group
+    /// members (use with --socket-mode 660)
+    #[structopt(long)]
+    socket_group: Option<String>,
+
+    /// Write Prometheus metrics (interactions, failures, state, step
+    /// durations) into this textfile for the textfile collector of node
+    /// exporter.
+    #[structopt(long, requires = "interactive")]
+    metrics_file: Option<PathBuf>,
+
+    /// Serve Prometheus metrics over HTTP at this address, e.g.
+    /// "0.0.0.0:9100".
+    #[structopt(long, requires = "interactive")]
+    metrics_addr: Option<String>,
+
+    /// Also serve clients with JSON-RPC 2.0 over TCP at this address, e.g.
+    /// "127.0.0.1:9200", for clients in other languages. Each request is
+    /// one line of JSON with method "interact", "control" or "status".
+    /// Addresses accessible from other hosts require --jsonrpc-token.
+    #[cfg(feature = "jsonrpc")]
+    #[structopt(long, requires = "interactive")]
+    jsonrpc_addr: Option<String>,
+
+    /// The token every JSON-RPC request must carry as "token" member
+    #[cfg(feature = "jsonrpc")]
+    #[structopt(long, env = "VASP_JSONRPC_TOKEN", requires = "jsonrpc_addr", hide_env_values = true)]
+    jsonrpc_token: Option<String>,
+
+    /// Also write structured logs in JSON lines into this file, with ids of
+    /// client and interaction for each event. The level of terminal logs is
+    /// set by RUST_LOG instead of -v in this case.
+    #[structopt(long)]
+    log_json: Option<PathBuf>,
+
+    /// Register the interactive server with this name, for discovery using
+    /// `vasp-client --list` and `vasp-client --name`. The server is not
+    /// registered by default.
+    #[structopt(long)]
+    name: Option<String>,
+
+    /// Save OUTCAR, vasprun.xml, OSZICAR and CONTCAR into `step_%04d/` after
+    /// each interaction by copy or (hard) link, instead of being overwritten
+    /// (only valid for interactive calculation). Files appended by VASP are
+    /// always copied.
+    #[structopt(long, requires = "interactive")]
+    snapshot: Option<crate::vasp::snapshot::SnapshotMode>,
+
+    /// The max number of pending interactions from all clients (including
+    /// the running one). Further interactions will be rejected with a BUSY
+    /// reply, instead of queueing unboundedly.
+    #[structopt(long, requires = "interactive")]
+    max_queue: Option<usize>,
+
+    /// Reject interactions with atoms closer than this distance in Å, as
+    /// well as NaN positions or atoms escaping the cell, instead of feeding
+    /// unphysical geometries to VASP.
+    #[structopt(long, default_value = "0.5")]
+    min_distance: f64,
+
+    /// Reject interactions with atoms outside the cell farther than this
+    /// distance in Å.
+    #[structopt(long, default_value = "5.0")]
+    max_escape: f64,
+
+    /// Do not check geometries of interactions.
+    #[structopt(long, requires = "interactive")]
+    no_geometry_guard: bool,
+
+    /// Pre-compute the next geometry extrapolated from previous steps
+    /// ("linear" or "quadratic") while the client is busy, and answer the
+    /// request with its results if the geometry matches. A hit returns
+    /// results computed for the extrapolated positions, not the requested
+    /// ones, which differ by up to `--speculate-tol`. Speculation starts
+    /// after the reply is sent, only for clients reading results from
+    /// stdout (`vasp-client --source stdout`) and only if VASP prints forces
+    /// in stdout, as the speculative step appends to OUTCAR and vasprun.xml.
+    #[structopt(long, requires = "interactive")]
+    speculate: Option<crate::speculate::SpeculateOptions>,
+
+    /// The max deviation in Å of any atom for a speculated geometry to be
+    /// taken as matched.
+    #[structopt(long, default_value = "0.005", requires = "speculate")]
+    speculate_tol: f64,
+
+    /// INCAR tags of the "fast" profile clients can switch to at runtime
+    /// (`vasp-client --profile fast`), separated by semicolon.
+    #[structopt(long, default_value = "PREC = Normal; EDIFF = 1E-4")]
+    fast_profile: String,
+
+    /// INCAR tags of the "accurate" profile, separated by semicolon.
+    #[structopt(long, default_value = "PREC = Accurate; EDIFF = 1E-6")]
+    accurate_profile: String,
+
+    /// Respawn VASP when it crashed or exited (e.g. NSW exhausted) instead
+    /// of stopping the interactive server. Positions of the pending
+    /// interaction are written into POSCAR for the new VASP process.
+    #[structopt(long, requires = "interactive")]
+    respawn: bool,
+
+    /// What to do with WAVECAR on respawn: reuse (ISTART = 1), discard
+    /// (ISTART = 0) or keep (INCAR untouched). Files left by crashed VASP
+    /// are never reused.
+    #[structopt(long, default_value = "reuse")]
+    wavecar: crate::vasp::restart::ReusePolicy,
+
+    /// What to do with CHGCAR on respawn: reuse (ICHARG = 1), discard
+    /// (ICHARG = 2) or keep (INCAR untouched).
+    #[structopt(long, default_value = "reuse")]
+    chgcar: crate::vasp::restart::ReusePolicy,
+
+    /// Record each interaction (input and stdout of VASP) into this
+    /// transcript file in JSON lines.
+    #[structopt(long, requires = "interactive")]
+    transcript: Option<PathBuf>,
+
+    /// Replay stdout recorded in this transcript file using `fake-vasp`,
+    /// instead of running VASP, for developing and testing client side
+    /// workflows on machines without VASP.
+    #[structopt(long, requires = "interactive")]
+    playback: Option<PathBuf>,
+
+    /// Host an additional interactive engine in "name:dir[:program]",
+    /// e.g. VASP with a different functional or a fast ML engine, which
+    /// clients select by name (`vasp-client --engine`). The engine runs in
+    /// its own directory with the same program as default if not set. Can
+    /// be repeated. Note that the server stops with all engines once any
+    /// engine exits, unless respawned (--respawn).
+    #[structopt(long, requires = "interactive")]
+    engine: Vec<crate::socket::Engine>,
+
+    /// Run VASP in a fresh scratch directory with a copy of input files
+    /// (POTCAR symlinked) instead of in place, so that servers started
+    /// from the same directory will not trample each other's output
+    /// files. Output files are synced back on shutdown. A `CANCEL` file
+    /// touched in the submit directory also works.
+    #[structopt(long, requires = "interactive")]
+    scratch: bool,
+
+    /// Create scratch directory under this directory. The default is the
+    /// system temporary directory.
+    #[structopt(long, env = "VASP_SCRATCH_ROOT", requires = "scratch")]
+    scratch_root: Option<PathBuf>,
+
+    /// Sync this output file back from scratch directory on shutdown.
+    /// Repeat it for multiple files. The default is OUTCAR, CONTCAR,
+    /// OSZICAR and vasprun.xml.
+    #[structopt(long, requires = "scratch")]
+    sync_back: Vec<String>,
+
+    /// Keep scratch directory after shutdown for inspection
+    #[structopt(long, requires = "scratch")]
+    keep_scratch: bool,
+
+    /// Pin VASP process to a CPU set, e.g. "0-3,8"
+    #[structopt(long)]
+    cpu_set: Option<String>,
+
+    /// Run VASP process with this niceness
+    #[structopt(long)]
+    nice: Option<i32>,
+
+    /// Limit the virtual memory (in MB) of VASP process (RLIMIT_AS)
+    #[structopt(long)]
+    max_memory: Option<u64>,
+
+    /// Limit the CPU time (in seconds) of VASP process (RLIMIT_CPU)
+    #[structopt(long)]
+    max_cpu_time: Option<u64>,
+
+    /// Run VASP in a dedicated cgroup (v2) if the user has a delegated
+    /// cgroup. Pause/resume will freeze/thaw the cgroup instead of sending
+    /// signals. With memory or CPU limits, vasp-tools moves itself into a
+    /// "server" leaf cgroup, so that controllers can be enabled for the
+    /// VASP cgroup, and fails if the limits cannot be applied.
+    #[structopt(long)]
+    cgroup: bool,
+
+    /// Limit the memory (in MB) of the cgroup for VASP (requires --cgroup)
+    #[structopt(long, requires = "cgroup")]
+    cgroup_memory_max: Option<u64>,
+
+    /// Limit the number of CPUs of the cgroup for VASP (requires --cgroup)
+    #[structopt(long, requires = "cgroup")]
+    cgroup_cpu_max: Option<f64>,
+
+    /// The MPI launcher used in the script for running VASP: openmpi, srun
+    /// or auto. If set, pause/resume/terminate signals will be sent to all
+    /// MPI ranks using launcher specific mechanism. For OpenMPI, the script
+    /// should call `mpirun --report-pid $VASP_TOOLS_MPI_PIDFILE`. For srun,
+    /// the script should write `$SLURM_STEP_ID` from the first task into
+    /// `$VASP_TOOLS_MPI_STEPFILE`, so that only this step is signaled.
+    #[structopt(long)]
+    mpi: Option<String>,
+
+    /// The signal for pausing VASP instead of SIGSTOP, e.g. SIGTSTP for MPI
+    /// stacks misbehaving under SIGSTOP. Not used with --cgroup, which
+    /// freezes the cgroup instead.
+    #[structopt(long)]
+    pause_signal: Option<crate::process::UnixSignal>,
+
+    /// The signal for resuming paused VASP instead of SIGCONT
+    #[structopt(long)]
+    resume_signal: Option<crate::process::UnixSignal>,
+
+    /// Run this shell command when VASP converged, finished, crashed or
+    /// walltime nearly exhausted. The payload is passed in stdin, and the
+    /// event in env var VASP_HOOK_EVENT.
+    #[structopt(long, env = "VASP_HOOK_COMMAND")]
+    hook_command: Option<String>,
+
+    /// Post the payload to this webhook URL on the events as for
+    /// --hook-command.
+    #[structopt(long, env = "VASP_HOOK_URL")]
+    hook_url: Option<String>,
+
+    /// Read payload template from this file. Placeholders like {{event}},
+    /// {{directory}}, {{message}}, {{energy}}, {{fmax}}, {{nsteps}} and
+    /// {{elapsed}} will be replaced with run summary. The default payload
+    /// is the summary in JSON.
+    #[structopt(long)]
+    hook_template: Option<PathBuf>,
+
+    /// The walltime of the job ("HH:MM:SS" or seconds) for triggering
+    /// hooks before it is exhausted. Use "auto" to query the remaining
+    /// walltime of SLURM or PBS job, which is also used if not set when
+    /// running in such jobs.
+    #[structopt(long, env = "VASP_WALLTIME")]
+    walltime: Option<String>,
+
+    /// Trigger hooks when only this many seconds left for walltime
+    #[structopt(long, default_value = "600")]
+    walltime_margin: f64,
+
+    /// Requeue the SLURM or PBS job when only `walltime-margin` seconds
+    /// left for walltime, instead of being killed on timeout.
+    #[structopt(long)]
+    requeue: bool,
+
+    /// Read resource limits from env file (VASP_CPU_SET, VASP_NICE,
+    /// VASP_MAX_MEMORY, VASP_MAX_CPU_TIME). Values from command line take
+    /// precedence.
+    #[structopt(long)]
+    env_file: Option<PathBuf>,
+}
+
+impl ServerCli {
+    fn hooks(&self) -> Result<crate::hooks::Hooks> {
+        let template = match &self.hook_template {
+            Some(f) => gut::fs::read_file(f)?.into(),
+            None => None,
+        };
+        let hooks = crate::hooks::Hooks {
+            command: self.hook_command.clone(),
+            webhook: self.hook_url.clone(),
+            template,
+        };
+        Ok(hooks)
+    }
+
+    fn resource_limits(&self) -> Result<crate::process::ResourceLimits> {
+        use crate::process::{MpiLauncher, ResourceLimits};
+
+        let mut limits = match &self.env_file {
+            Some(f) => ResourceLimits::from_env_file(f)?,
+            None => ResourceLimits::default(),
+        };
+        let cpu_set = match &self.cpu_set {
+            Some(s) => crate::process::parse_cpu_list(s)?.into(),
+            None => None,
+        };
+        let mpi = match self.mpi.as_deref() {
+            Some("auto") => self.program.as_ref().and_then(|p| MpiLauncher::detect(p)),
+            Some(s) => Some(s.parse()?),
+            None => None,
+        };
+        let max_memory = self.max_memory.map(crate::process::megabytes_to_bytes).transpose()?;
+        let cgroup_memory_max = self.cgroup_memory_max.map(crate::process::megabytes_to_bytes).transpose()?;
+        limits.merge(ResourceLimits {
+            cpu_set,
+            nice: self.nice,
+            max_memory,
+            max_cpu_time: self.max_cpu_time,
+            use_cgroup: self.cgroup,
+            cgroup_memory_max,
+            cgroup_cpu_max: self.cgroup_cpu_max,
+            mpi,
+            pause_signal: self.pause_signal,
+            resume_signal: self.resume_signal,
+        });
+
+        Ok(limits)
+    }
+}
+
+/// Register the server serving at `socket_file` with `name`. Registration
+/// failure is not fatal, as the server is still reachable by its socket
+/// file.
+fn register_server(name: &str, socket_file: &Path) -> Option<crate::registry::Registration> {
+    use crate::registry::{Registry, ServerEntry};
+
+    let registration = Registry::open_default().and_then(|registry| {
+        let entry = ServerEntry::new(name, socket_file)?;
+        registry.register(&entry)
+    });
+    match registration {
+        Ok(r) => r.into(),
+        Err(e) => {
+            warn!("server not registered as {:?}: {:?}", name, e);
+            None
+        }
+    }
+}
+
+/// Return the path to `fake-vasp` installed with current program, or the
+/// one found in PATH.
+fn fake_vasp_program() -> PathBuf {
+    std::env::current_exe()
+        .ok()
+        .map(|exe| exe.with_file_name("fake-vasp"))
+        .filter(|f| f.exists())
+        .unwrap_or_else(|| "fake-vasp".into())
+}
+
+/// The exit code of run-vasp when calculation is not converged
+const EXIT_UNCONVERGED: u8 = 3;
+
+/// Return the exit code for child process exited with `status`: the exit
+/// code of child, or 128 + signal number if killed by signal as shells do.
+fn exit_code_of(status: &std::process::ExitStatus) -> u8 {
+    use std::os::unix::process::ExitStatusExt;
+
+    match (status.code(), status.signal()) {
+        (Some(code), _) => code as u8,
+        (None, Some(sig)) => (128 + sig) as u8,
+        (None, None) => 1,
+    }
+}
+
+/// Record provenance metadata of VASP run in current directory. Failure is
+/// not fatal.
+fn record_provenance() {
+    if let Err(e) = crate::vasp::provenance::Provenance::record(".".as_ref()) {
+        warn!("failed to record provenance: {:?}", e);
+    }
 }
 
+/// Return the exit code of process, which is nonzero if VASP failed or the
+/// calculation is not converged (`--fail-on-unconverged`).
 #[tokio::main]
-pub async fn run_vasp_enter_main() -> Result<()> {
+pub async fn run_vasp_enter_main() -> Result<std::process::ExitCode> {
     use crate::vasp::VaspTask;
 
     let args = ServerCli::parse();
-    args.verbose.setup_logger();
+    match &args.log_json {
+        // NOTE: terminal logs are also written by the JSON log subscriber
+        Some(f) => crate::logging::setup_json_log(f)?,
+        None => args.verbose.setup_logger(),
+    }
 
     // write STOPCAR only
     if let Some(wrk_dir) = &args.stop {
-        crate::vasp::stopcar::write(wrk_dir)?;
-        return Ok(());
+        use crate::vasp::stopcar::StopMode;
+
+        let mode = match (args.stop_soft, args.stop_hard) {
+            (true, _) => StopMode::Soft,
+            (_, true) => StopMode::Hard,
+            _ => args.stop_mode.unwrap_or(StopMode::Hard),
+        };
+        crate::vasp::stopcar::write(wrk_dir, mode)?;
+        return Ok(std::process::ExitCode::SUCCESS);
+    }
+
+    if let (Some(tpl_dir), Some(f)) = (&args.from_template, &args.structure) {
+        crate::vasp::template::render_inputs_from(tpl_dir, f, ".".as_ref())?;
     }
 
     let vasp_program = &args.program;
     let interactive = args.interactive;
+    let limits = args.resource_limits()?;
+    let hooks = args.hooks()?;
+    let t0 = std::time::Instant::now();
+    let scheduler = crate::scheduler::Scheduler::detect();
+    let walltime = match args.walltime.as_deref() {
+        Some("auto") => {
+            let scheduler = scheduler.context("--walltime auto requires a SLURM or PBS job")?;
+            Some(scheduler.remaining_walltime()?)
+        }
+        Some(s) => Some(crate::hooks::parse_walltime(s)?),
+        // failure is not fatal, e.g. interactive job without time limit
+        None => scheduler.and_then(|s| match s.remaining_walltime() {
+            Ok(t) => Some(t),
+            Err(e) => {
+                warn!("cannot get walltime from {:?}: {:?}", s, e);
+                None
+            }
+        }),
+    };
+    if let Some(walltime) = walltime {
+        info!("walltime budget: {:.0} seconds", walltime);
+        hooks.watch_walltime(walltime, args.walltime_margin);
+    }
+    if args.requeue {
+        let scheduler = scheduler.context("--requeue requires a SLURM or PBS job")?;
+        let walltime = walltime.context("--requeue requires walltime of the job")?;
+        scheduler.watch_requeue(walltime, args.walltime_margin);
+    }
+    let notify = |failure: Option<String>| {
+        let summary = crate::hooks::RunSummary::collect(".".as_ref(), failure, t0.elapsed().as_secs_f64());
+        hooks.notify(&summary);
+    };
 
     if interactive {
         crate::vasp::update_incar_for_bbm(&VaspTask::Interactive)?;
-        if let Some(vasp_program) = &args.program {
+        let program = match &args.playback {
+            Some(f) => {
+                let f = f.canonicalize().with_context(|| format!("invalid transcript file: {:?}", f))?;
+                info!("replay transcript {:?} using fake-vasp", f);
+                std::env::set_var("FAKE_VASP_REPLAY", f);
+                Some(fake_vasp_program())
+            }
+            None => args.program.clone(),
+        };
+        if let Some(vasp_program) = &program {
             debug!("Run VASP for interactive calculation ...");
-            crate::socket::Server::create(&args.socket_file)?
-                .run_and_serve(vasp_program)
-                .await;
+            // NOTE: paths are relative to this directory when running in
+            // scratch directory
+            let cwd = std::env::current_dir()?;
+            // NOTE: the socket is created by systemd with socket activation
+            let mut server = match crate::systemd::listen_fds()?.as_slice() {
+                [fd, ..] => crate::socket::Server::from_listen_fd(*fd)?,
+                [] => {
+                    let socket_file = match &args.socket_file {
+                        Some(f) => cwd.join(f),
+                        None => crate::socket::default_socket_file()?,
+                    };
+                    let server = crate::socket::Server::create(&socket_file)?;
+                    server.set_socket_permissions(&crate::socket::SocketPermissions {
+                        mode: crate::socket::parse_file_mode(&args.socket_mode)?,
+                        group: args.socket_group.clone(),
+                    })?;
+                    server
+                }
+            };
+            let socket_file = server.socket_file().to_owned();
+            // the entry will be removed from registry when server exits
+            let _registration = args.name.as_deref().and_then(|name| register_server(name, &socket_file));
+            server.set_resource_limits(limits);
+            if let Some(mode) = args.snapshot {
+                server.set_snapshot_mode(mode);
+            }
+            if let Some(n) = args.max_queue {
+                server.set_max_queue(n);
+            }
+            let guard = crate::vasp::guard::GeometryGuard {
+                min_distance: args.min_distance,
+                max_escape: args.max_escape,
+            };
+            server.set_geometry_guard((!args.no_geometry_guard).then_some(guard));
+            if let Some(mut opts) = args.speculate {
+                opts.tolerance = args.speculate_tol;
+                server.set_speculation(opts);
+            }
+            let parse = crate::vasp::profile::parse_incar_params;
+            server.set_incar_profiles(crate::vasp::profile::IncarProfiles {
+                fast: parse(&args.fast_profile)?,
+                accurate: parse(&args.accurate_profile)?,
+            });
+            if let Some(f) = &args.transcript {
+                server.set_transcript(&cwd.join(f));
+            }
+            for engine in &args.engine {
+                let mut engine = engine.clone();
+                engine.dir = cwd.join(&engine.dir);
+                crate::vasp::update_incar_in_dir(&engine.dir, &VaspTask::Interactive)?;
+                // NOTE: the engine runs in its own directory
+                if let Some(p) = engine.program.as_mut().filter(|p| p.to_string_lossy().contains('/')) {
+                    *p = p.canonicalize().with_context(|| format!("invalid program for engine: {:?}", p))?;
+                }
+                server.add_engine(engine)?;
+            }
+            if args.respawn {
+                server.set_respawn(crate::vasp::restart::ReuseOptions {
+                    wavecar: args.wavecar,
+                    chgcar: args.chgcar,
+                });
+            }
+            let metrics_file = args.metrics_file.as_ref().map(|f| cwd.join(f));
+            server.set_metrics_exporter(metrics_file, args.metrics_addr.clone());
+            #[cfg(feature = "jsonrpc")]
+            if let Some(addr) = &args.jsonrpc_addr {
+                server.set_jsonrpc_addr(addr, args.jsonrpc_token.as_deref())?;
+            }
+            let scratch = if args.scratch {
+                let scratch = crate::vasp::scratch::ScratchDir::create(&cwd, args.scratch_root.as_deref())?;
+                std::env::set_current_dir(scratch.path())?;
+                // users touch CANCEL in the submit directory
+                server.add_cancel_dir(&cwd);
+                Some(scratch)
+            } else {
+                None
+            };
+            let res = server.run_and_serve(vasp_program).await;
+            // VASP exited: STOPCAR is not needed any more
+            crate::vasp::stopcar::remove(".".as_ref())?;
+            if let Some(scratch) = scratch {
+                let files: Vec<_> = if args.sync_back.is_empty() {
+                    crate::vasp::scratch::DEFAULT_SYNC_FILES.iter().map(|x| x.to_string()).collect()
+                } else {
+                    args.sync_back.clone()
+                };
+                std::env::set_current_dir(scratch.origin())?;
+                scratch.sync_back(&files)?;
+                if args.keep_scratch {
+                    info!("scratch dir kept in {:?}", scratch.keep());
+                }
+            }
+            record_provenance();
+            notify(res.as_ref().err().map(|e| e.to_string()));
+            res?;
         }
     } else {
         let task = if args.single_point {
@@ -150,7 +1215,7 @@ pub async fn run_vasp_enter_main() -> Result<()> {
             VaspTask::Frequency
         } else {
             ServerCli::command().print_help();
-            return Ok(());
+            return Ok(std::process::ExitCode::SUCCESS);
         };
         crate::vasp::update_incar_for_bbm(&task)?;
         if let Some(vasp_program) = &args.program {
@@ -160,14 +1225,35 @@ pub async fn run_vasp_enter_main() -> Result<()> {
             // registered under PATH env var or the path (relative or full) to
             // the program file?
             let _cmd = vasp_program.to_string_lossy();
-            if _cmd.contains("/") {
+            let out = if _cmd.contains("/") {
                 duct::cmd!(vasp_program)
             } else {
                 duct::cmd!(_cmd.into_owned())
             }
+            .before_spawn(move |cmd| {
+                use crate::process::ProcessGroupExt;
+                cmd.apply_resource_limits(&limits);
+                Ok(())
+            })
             .unchecked()
             .run()
             .with_context(|| format!("Run VASP failure using {:?}", vasp_program))?;
+            crate::vasp::stopcar::remove(".".as_ref())?;
+            record_provenance();
+            if !out.status.success() {
+                notify(format!("VASP exited with {}", out.status).into());
+                error!("VASP exited with {}", out.status);
+                return Ok(exit_code_of(&out.status).into());
+            }
+            notify(None);
+            if args.fail_on_unconverged {
+                let conv = crate::vasp::report::check_convergence(".".as_ref())?;
+                if !conv.outcome.is_acceptable() {
+                    error!("{}", conv.verdict);
+                    return Ok(EXIT_UNCONVERGED.into());
+                }
+                info!("{}", conv.verdict);
+            }
 
             // or we can use `std::process::Command` directly
             //
@@ -181,21 +1267,35 @@ pub async fn run_vasp_enter_main() -> Result<()> {
         }
     }
 
-    Ok(())
+    Ok(std::process::ExitCode::SUCCESS)
 }
 // 79d54340 ends here
 
 // [[file:../vasp-tools.note::28b92274][28b92274]]
 /// A client of a unix domain socket server for interacting with the program
 /// run in background
+///
+/// The structure is read from stdin as a complete POSCAR, or in protocol v2
+/// with a header line "#vasp-tools-positions v2 mode=start|update natoms=N
+/// [lattice=a1,a2,...,c3]" followed by a complete POSCAR (start) or scaled
+/// positions, one atom per line (update).
 #[derive(Debug, StructOpt)]
 struct ClientCli {
     #[structopt(flatten)]
     verbose: gut::cli::Verbosity,
 
-    /// Path to the socket file to connect
-    #[structopt(short = 'u', default_value = "vasp.sock")]
-    socket_file: PathBuf,
+    /// Path to the socket file to connect. The default is the same as the
+    /// server started in current directory.
+    #[structopt(short = 'u')]
+    socket_file: Option<PathBuf>,
+
+    /// Connect to the registered server with this name
+    #[structopt(long, conflicts_with = "socket_file")]
+    name: Option<String>,
+
+    /// List registered servers that are running
+    #[structopt(long)]
+    list: bool,
 
     /// Control child process for saving CPU times when idle
     #[structopt(long)]
@@ -204,24 +1304,300 @@ struct ClientCli {
     /// Stop VASP server
     #[structopt(short = 'q')]
     quit: bool,
-}
 
-#[tokio::main]
-pub async fn vasp_client_enter_main() -> Result<()> {
-    let args = ClientCli::parse();
-    args.verbose.setup_logger();
+    /// Relay SIGINT to VASP, letting it finish the current SCF step
+    /// cleanly, e.g. before pause
+    #[structopt(long, conflicts_with = "quit")]
+    interrupt: bool,
 
-    // wait a moment for socke file ready
-    let timeout = 5;
-    wait_file(&args.socket_file, timeout)?;
-    let mut client = Client::connect(&args.socket_file).await?;
+    /// Stop VASP gracefully using STOPCAR after the current interaction,
+    /// and shut down the server, as touching a `CANCEL` file in working
+    /// directory of server.
+    #[structopt(long, conflicts_with = "quit")]
+    cancel: bool,
 
+    /// Switch the server to INCAR profile "fast" or "accurate", e.g. for
+    /// cheap early steps of optimization. VASP is restarted with updated
+    /// INCAR on next interaction, and WAVECAR is kept.
+    #[structopt(long, conflicts_with = "quit")]
+    profile: Option<crate::vasp::profile::Profile>,
+
+    /// Compute finite-difference Hessian rows of the structure from stdin
+    /// on server side, displacing atoms by this step in Å. The results are
+    /// printed in json. VASP must have been started.
+    #[structopt(long)]
+    fd_step: Option<f64>,
+
+    /// The atoms to be displaced in finite differences, e.g. "1,3-5". All
+    /// atoms are displaced by default.
+    #[structopt(long)]
+    fd_atoms: Option<String>,
+
+    /// Also compute derivatives of dipole moment in finite differences
+    #[structopt(long)]
+    fd_dipole: bool,
+
+    /// The output format of computed results: text, json or msgpack
+    #[structopt(long, default_value = "text")]
+    format: OutputFormat,
+
+    /// Where to read computed results: auto, outcar, vasprun, stdout or
+    /// oszicar. The default is to read from vasprun.xml if it is present.
+    /// stdout (energies and forces) and oszicar (energies only) avoid
+    /// parsing the growing OUTCAR on every step.
+    #[structopt(long, default_value = "auto")]
+    source: ResultSource,
+
+    /// The properties returned, separated by comma: energy, forces, stress
+    /// and magmom. Energy is always returned. All available properties are
+    /// returned by default.
+    #[structopt(long, use_delimiter = true)]
+    fields: Vec<OutputField>,
+
+    /// Which energy to return: free (TOTEN, consistent with forces),
+    /// without-entropy or sigma0 (extrapolated to sigma → 0, usually for
+    /// molecules with Gaussian smearing). All three are available in json
+    /// or msgpack output.
+    #[structopt(long, env = "VASP_ENERGY", default_value = "free")]
+    energy: crate::vasp::outcar::EnergyKind,
+
+    /// Include forces of atoms fixed in selective dynamics of input POSCAR
+    /// in reported fmax. Returned forces are not affected.
+    #[structopt(long)]
+    no_force_mask: bool,
+
+    /// The pattern (regex) in VASP stdout when it is ready for next input.
+    /// Repeat it to accept any of multiple patterns.
+    #[structopt(long, default_value = crate::vasp::stdout::VASP_READ_PATTERN)]
+    read_pattern: Vec<String>,
+
+    /// Read ionic velocities from this POSCAR/CONTCAR, and write them into
+    /// POSCAR for the initial calculation, for restarting MD with
+    /// consistent initial velocities.
+    #[structopt(long)]
+    velocities: Option<PathBuf>,
+
+    /// Append each evaluated structure with computed energy and forces
+    /// into this extxyz trajectory file.
+    #[structopt(long)]
+    trajectory: Option<PathBuf>,
+
+    /// Keep bond lengths and angles listed in this file fixed, by
+    /// projecting out constrained components of returned forces. Each
+    /// line is "distance i j" or "angle i j k" with 1-based atom indices.
+    #[structopt(long)]
+    constraints: Option<PathBuf>,
+
+    /// Send each evaluated structure with computed results as one line of
+    /// JSON into stdin of this shell command, e.g. for active learning with
+    /// ML committee. A JSON reply with "energy" and "forces" corrections
+    /// will be added to returned results, e.g. for delta-learning.
+    #[structopt(long, env = "VASP_EVAL_HOOK")]
+    eval_hook: Option<String>,
+
+    /// Send evaluations to this unix socket as `--eval-hook`, and read one
+    /// line of reply.
+    #[structopt(long, env = "VASP_EVAL_HOOK_SOCKET", conflicts_with = "eval_hook")]
+    eval_hook_socket: Option<PathBuf>,
+
+    /// Cache computed results in this local file, keyed by the hash of
+    /// input structure and output options, and the working directory of
+    /// engine with its INCAR and POSCAR. Identical evaluations, e.g. in a
+    /// re-run analysis script, will be answered from the cache without
+    /// asking the server to compute. Trajectory and evaluation hook are
+    /// skipped for cached results.
+    #[structopt(long, env = "VASP_CLIENT_CACHE")]
+    cache: Option<PathBuf>,
+
+    /// Evaluate using the engine with this name hosted by server (see
+    /// `run-vasp --engine`). Input and output files are in the working
+    /// directory of the engine.
+    #[structopt(long, env = "VASP_ENGINE")]
+    engine: Option<String>,
+
+    /// Upload this input file (POSCAR, INCAR or KPOINTS) into working
+    /// directory of server, for servers without shared filesystem.
+    /// Repeat it for multiple files.
+    #[structopt(long)]
+    upload: Vec<PathBuf>,
+
+    /// Download this output file (OUTCAR, CONTCAR, OSZICAR or vasprun.xml)
+    /// from working directory of server into current directory. Repeat it
+    /// for multiple files.
+    #[structopt(long)]
+    download: Vec<String>,
+}
+
+#[tokio::main]
+pub async fn vasp_client_enter_main() -> Result<()> {
+    let args = ClientCli::parse();
+    args.verbose.setup_logger();
+
+    if args.list {
+        let servers = crate::registry::Registry::open_default()?.list()?;
+        print!("{}", crate::registry::format_server_table(&servers));
+        return Ok(());
+    }
+
+    // file transfer only, without interaction
+    let transfer = !args.upload.is_empty() || !args.download.is_empty();
+    // control only, without interaction
+    let signal_only = args.quit || args.interrupt || args.cancel || args.profile.is_some();
+    let txt = if signal_only || transfer {
+        String::new()
+    } else {
+        crate::vasp::stdin::read_txt_from_stdin()?
+    };
+    // NOTE: the working directory will be changed for selected engine, so
+    // file paths in options are relative to this one
+    let cwd = std::env::current_dir()?;
+    let cache_file = args.cache.as_ref().map(|f| cwd.join(f));
+    let mut cache = cache_file.as_deref().map(crate::socket::ResponseCache::open).transpose()?;
+    let constraints_txt = match &args.constraints {
+        Some(f) => gut::fs::read_file(f)?,
+        None => String::new(),
+    };
+
+    // wait a moment for socke file ready
+    let timeout = 5.0;
+    let socket_file = match (&args.socket_file, &args.name) {
+        (Some(f), _) => f.to_owned(),
+        (None, Some(name)) => crate::registry::Registry::open_default()?.find(name)?.socket_file,
+        (None, None) => crate::socket::default_socket_file()?,
+    };
+    wait_file(&socket_file, timeout).await?;
+    let mut client = Client::connect(&socket_file).await?;
+    // NOTE: OUTCAR or vasprun.xml may be appended by speculative step
+    // right after the reply
+    if args.source == ResultSource::Stdout {
+        client.declare_stdout_only().await?;
+    }
+    if let Some(name) = &args.engine {
+        let dir = client.select_engine(name).await?;
+        info!("evaluate using engine {:?} in {:?}", name, dir);
+        std::env::set_current_dir(&dir).with_context(|| format!("enter directory of engine: {:?}", dir))?;
+    } else if let Some(dir) = client.engine_dir().filter(|d| d.is_dir()) {
+        // the server may run VASP in a scratch directory (`run-vasp --scratch`)
+        if dir.canonicalize().ok() != cwd.canonicalize().ok() {
+            info!("enter working directory of server: {:?}", dir);
+            std::env::set_current_dir(dir)?;
+        }
+    }
+
+    // NOTE: the same input on another server or with another INCAR gives
+    // different results
+    let key = crate::socket::ResponseCache::key(&[
+        &txt,
+        &format!("{:?}", args.format),
+        &format!("{:?}", args.source),
+        &format!("{:?}", args.fields),
+        &format!("{:?}", args.energy),
+        &format!("{:?}", args.no_force_mask),
+        &constraints_txt,
+        args.engine.as_deref().unwrap_or_default(),
+        &crate::socket::ResponseCache::engine_scope(&std::env::current_dir()?),
+    ]);
+    let interaction = !signal_only && !transfer && args.fd_step.is_none();
+    if let Some(output) = cache.as_ref().filter(|_| interaction).and_then(|c| c.get(&key)) {
+        info!("use cached results from {:?}", cache_file);
+        return write_output(output);
+    }
+
+    if transfer {
+        for f in &args.upload {
+            let f = cwd.join(f);
+            let name = f.file_name().with_context(|| format!("invalid file: {:?}", f))?.to_string_lossy();
+            client.upload_file(&f, &name).await?;
+            info!("uploaded {:?}", f);
+        }
+        for name in &args.download {
+            let f = cwd.join(name);
+            client.download_file(name, &f).await?;
+            info!("downloaded {:?}", f);
+        }
+    }
     if args.quit {
         client.try_quit().await?;
         return Ok(());
     }
+    if args.interrupt {
+        client.try_interrupt().await?;
+        return Ok(());
+    }
+    if args.cancel {
+        client.cancel().await?;
+        info!("server cancelled");
+        return Ok(());
+    }
+    if let Some(profile) = args.profile {
+        client.set_profile(&profile.to_string()).await?;
+        info!("switched to {} profile", profile);
+        return Ok(());
+    }
+    if transfer {
+        return Ok(());
+    }
+    if let Some(step) = args.fd_step {
+        use crate::vasp::stdin::PositionsMode;
 
-    interactive_vasp_session_bbm(&mut client, args.control).await?;
+        let positions = match crate::vasp::stdin::parse_positions_input(&txt)? {
+            Some(p) if p.mode == PositionsMode::Update => p.payload,
+            Some(_) => bail!("finite differences require a running VASP, use mode=update"),
+            None => crate::vasp::stdin::get_scaled_positions_from_poscar_str(&txt, "POSCAR".as_ref())?,
+        };
+        let natoms = positions.lines().count();
+        let atoms = match &args.fd_atoms {
+            Some(s) => crate::vasp::vibration::parse_atom_selection(s, natoms)?,
+            None => vec![],
+        };
+        let req = crate::hessian::FiniteDiffRequest {
+            positions,
+            atoms,
+            step,
+            dipole: args.fd_dipole,
+            read_pattern: crate::session::join_read_patterns(&args.read_pattern)?,
+        };
+        if args.control {
+            client.try_resume().await?;
+        }
+        let result = client.finite_diff(&req).await?;
+        if args.control {
+            client.try_pause().await?;
+        }
+        let mut output = serde_json::to_vec(&result)?;
+        output.push(b'\n');
+        return write_output(&output);
+    }
+
+    let opts = BbmSessionOptions {
+        control: args.control,
+        format: args.format,
+        source: args.source,
+        fields: args.fields,
+        energy: args.energy,
+        read_pattern: crate::session::join_read_patterns(&args.read_pattern)?,
+        velocities: args
+            .velocities
+            .as_ref()
+            .map(|f| read_velocities_from(&cwd.join(f)))
+            .transpose()?,
+        trajectory: args.trajectory.as_ref().map(|f| cwd.join(f)),
+        constraints: args
+            .constraints
+            .as_ref()
+            .map(|f| crate::constraint::Constraints::from_file(&cwd.join(f)))
+            .transpose()?,
+        eval_hook: match (args.eval_hook, args.eval_hook_socket) {
+            (Some(cmd), _) => Some(crate::hooks::EvaluationHook::Command(cmd)),
+            (None, Some(f)) => Some(crate::hooks::EvaluationHook::Socket(cwd.join(f))),
+            (None, None) => None,
+        },
+        force_mask: !args.no_force_mask,
+    };
+    let output = interactive_vasp_session_bbm(&mut client, &txt, &opts).await?;
+    if let Some(cache) = cache.as_mut() {
+        cache.insert(&key, &output)?;
+    }
 
     Ok(())
 }
@@ -234,31 +1610,116 @@ struct VibCli {
     #[structopt(flatten)]
     verbose: gut::cli::Verbosity,
 
-    /// Extract last imaginary frequency mode
+    /// Extract frequency modes from this OUTCAR. Without --mode or
+    /// --range, all available modes are listed, or the last imaginary mode
+    /// is written if -o is given.
     #[structopt(long, name = "OUTCAR")]
     extract_vib_mode: PathBuf,
 
+    /// Select the mode by its index as numbered by VASP
+    #[structopt(long, conflicts_with = "range")]
+    mode: Option<usize>,
+
+    /// Select modes with frequencies in window "min:max" in cm-1, where
+    /// imaginary frequencies are negative, e.g. "-1000:0" or "3000:"
+    #[structopt(long, allow_hyphen_values = true)]
+    range: Option<String>,
+
     /// Run VASP for frequency calculation. The mandatory parameters in INCAR
     /// will be automatically updated.
     #[structopt(long, conflicts_with = "interactive, single_point")]
     frequency: bool,
 
-    /// The output file for writing vibrational mode
+    /// The output file for writing vibrational mode. If multiple modes
+    /// selected, each mode is written into a file with its index as suffix.
     #[structopt(short = 'o')]
-    outfile: PathBuf,
+    outfile: Option<PathBuf>,
+
+    /// Export selected modes (all modes by default) into this file for
+    /// visualization. Element symbols are read from POSCAR or CONTCAR next
+    /// to OUTCAR.
+    #[structopt(long)]
+    export: Option<PathBuf>,
+
+    /// The format for exporting modes: molden or gaussian
+    #[structopt(long, default_value = "molden")]
+    export_format: crate::vasp::vibration::VibFormat,
+
+    /// Project selected modes (all modes by default) onto these atoms
+    /// (1-based, e.g. "37-40" for the adsorbate), and report the weight on
+    /// them and the participation ratio of each mode.
+    #[structopt(long)]
+    project: Option<String>,
+
+    /// Modes with projected weight no less than this are regarded as
+    /// belonging to the projected atoms
+    #[structopt(long, default_value = "0.5", requires = "project")]
+    project_threshold: f64,
+}
+
+/// Write displacements of vibrational `mode` into file `f`.
+fn write_vib_mode(f: &Path, mode: &[[f64; 3]]) -> Result<()> {
+    let s: String = mode
+        .iter()
+        .map(|x| format!("{:-18.6} {:-18.6} {:-18.6}\n", x[0], x[1], x[2]))
+        .collect();
+    gut::fs::write_to_file(f, &s)?;
+    Ok(())
 }
 
 pub fn vib_mode_enter_main() -> Result<()> {
     let args = VibCli::parse();
     args.verbose.setup_logger();
 
+    use crate::vasp::*;
+
     let outcar = &args.extract_vib_mode;
-    let mode = crate::vasp::VaspOutcar::parse_last_imaginary_freq_mode_from(outcar)?;
-    let s: String = mode
-        .into_iter()
-        .map(|x| format!("{:-18.6} {:-18.6} {:-18.6}\n", x[0], x[1], x[2]))
-        .collect();
-    gut::fs::write_to_file(&args.outfile, &s)?;
+    if args.mode.is_none() && args.range.is_none() && args.export.is_none() && args.project.is_none() {
+        match &args.outfile {
+            Some(outfile) => {
+                let mode = VaspOutcar::parse_last_imaginary_freq_mode_from(outcar)?;
+                write_vib_mode(outfile, &mode)?;
+            }
+            None => {
+                let modes = VaspOutcar::parse_vib_modes_from(outcar)?;
+                print!("{}", format_vib_modes(&modes.iter().collect::<Vec<_>>()));
+            }
+        }
+        return Ok(());
+    }
+
+    let modes = VaspOutcar::parse_vib_modes_from(outcar)?;
+    let selected = match (args.mode, &args.range) {
+        (Some(i), _) => vec![select_mode_by_index(&modes, i)?],
+        (None, Some(range)) => select_modes_in_range(&modes, range)?,
+        (None, None) => modes.iter().collect(),
+    };
+    if let Some(s) = &args.project {
+        let natoms = selected.first().map_or(0, |m| m.positions.len());
+        let atoms = vibration::parse_atom_selection(s, natoms)?;
+        let projections = vibration::project_modes(&selected, &atoms);
+        print!("{}", vibration::format_projections(&projections, args.project_threshold));
+    } else {
+        print!("{}", format_vib_modes(&selected));
+    }
+    if let Some(f) = &args.export {
+        let mol = vibration::read_structure_for_outcar(outcar)?;
+        let s = vibration::format_vib_modes_as(&mol, &selected, args.export_format)?;
+        gut::fs::write_to_file(f, &s)?;
+        println!("{} modes exported into {:?}", selected.len(), f);
+    }
+    if let Some(outfile) = &args.outfile {
+        match selected.as_slice() {
+            [] => bail!("no mode selected"),
+            [mode] => write_vib_mode(outfile, &mode.displacements)?,
+            _ => {
+                for mode in &selected {
+                    let f = format!("{}.{}", outfile.display(), mode.index);
+                    write_vib_mode(f.as_ref(), &mode.displacements)?;
+                }
+            }
+        }
+    }
 
     Ok(())
 }
@@ -274,13 +1735,1984 @@ struct SummaryCli {
     /// Show a plot on optimization.
     #[structopt(long)]
     plot: bool,
+
+    /// Include forces of atoms fixed in selective dynamics in fmax.
+    #[structopt(long)]
+    no_force_mask: bool,
+
+    /// Wait for OUTCAR to appear for max time in seconds.
+    #[structopt(long)]
+    wait: Option<f64>,
+
+    /// Keep redrawing the plot as new ionic steps are appended to OUTCAR,
+    /// until VASP finished.
+    #[structopt(long)]
+    follow: bool,
+
+    /// The refresh interval in seconds for `--follow` mode
+    #[structopt(long, default_value = "5")]
+    interval: f64,
 }
 
 pub fn vasp_summary_enter_main() -> Result<()> {
     let args = SummaryCli::parse();
     args.verbose.setup_logger();
 
-    crate::vasp::outcar::summarize_outcar("OUTCAR".as_ref(), args.plot)?;
+    if let Some(timeout) = args.wait {
+        crate::utils::wait_file_blocking("OUTCAR".as_ref(), timeout)?;
+    }
+
+    if args.follow {
+        crate::vasp::outcar::follow_outcar("OUTCAR".as_ref(), args.interval, !args.no_force_mask)?;
+    } else {
+        crate::vasp::outcar::summarize_outcar("OUTCAR".as_ref(), args.plot, !args.no_force_mask)?;
+    }
     Ok(())
 }
 // 3fdb5cf5 ends here
+
+// [[file:../vasp-tools.note::c9274095][c9274095]]
+/// Utilities for managing VASP calculations
+#[derive(Debug, StructOpt)]
+struct VaspCli {
+    #[structopt(flatten)]
+    verbose: gut::cli::Verbosity,
+
+    #[structopt(subcommand)]
+    task: VaspTaskCli,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum VaspTaskCli {
+    /// List running VASP processes of current user
+    Ps {
+        /// The pattern for matching VASP command line
+        #[structopt(long, default_value = "vasp")]
+        pattern: String,
+
+        /// Show orphaned processes only
+        #[structopt(long)]
+        orphans: bool,
+    },
+
+    /// Terminate orphaned VASP processes (left behind by a crashed server)
+    KillOrphans {
+        /// The pattern for matching VASP command line
+        #[structopt(long, default_value = "vasp")]
+        pattern: String,
+
+        /// Send SIGKILL instead of SIGTERM
+        #[structopt(long)]
+        force: bool,
+    },
+
+    /// Write job script running interactive VASP server for SLURM or PBS
+    Submit {
+        /// The command for running VASP, e.g. "srun vasp_std"
+        #[structopt(short = 'x')]
+        program: String,
+
+        /// The batch scheduler: slurm or pbs
+        #[structopt(long, default_value = "slurm")]
+        scheduler: crate::scheduler::Scheduler,
+
+        /// The job name
+        #[structopt(long, default_value = "vasp")]
+        job_name: String,
+
+        /// The number of nodes
+        #[structopt(long, default_value = "1")]
+        nodes: usize,
+
+        /// The number of MPI tasks per node
+        #[structopt(long, default_value = "1")]
+        ntasks_per_node: usize,
+
+        /// The walltime of the job ("HH:MM:SS" or seconds)
+        #[structopt(long, default_value = "24:00:00")]
+        time: String,
+
+        /// The partition (SLURM) or queue (PBS)
+        #[structopt(long)]
+        partition: Option<String>,
+
+        /// Requeue the job before timeout
+        #[structopt(long)]
+        requeue: bool,
+
+        /// Write the script into this file instead of stdout
+        #[structopt(short = 'o')]
+        output: Option<PathBuf>,
+    },
+
+    /// Generate systemd unit files (socket and service) for running
+    /// interactive VASP server as an always-on user service with socket
+    /// activation
+    SystemdUnit {
+        /// The name of service
+        #[structopt(long)]
+        name: String,
+
+        /// The command for running VASP, e.g. "mpirun vasp_std"
+        #[structopt(short = 'x')]
+        program: String,
+
+        /// The working directory of VASP. The default is current directory.
+        #[structopt(long)]
+        workdir: Option<PathBuf>,
+
+        /// The socket file. The default is in user runtime directory.
+        #[structopt(short = 'u')]
+        socket_file: Option<PathBuf>,
+
+        /// Restart the service if no watchdog notification in this many
+        /// seconds
+        #[structopt(long)]
+        watchdog: Option<u64>,
+
+        /// Write unit files into this directory (e.g.
+        /// ~/.config/systemd/user) instead of stdout
+        #[structopt(short = 'o')]
+        output: Option<PathBuf>,
+    },
+
+    /// Run as an i-PI client computing energy and forces using BBM
+    IpiClient {
+        /// The i-PI server address: "unix:<name or path>" for unix socket
+        /// (`/tmp/ipi_<name>` as in i-PI), or "<host:port>" for TCP socket.
+        #[structopt(short = 'a', long, default_value = "localhost:10244")]
+        address: crate::ipi::Endpoint,
+
+        /// The directory containing BBM template files. Repeat it to run a
+        /// pool of clients, one for each bead in path-integral simulations.
+        #[structopt(long = "bbm-dir", default_value = ".")]
+        bbm_dirs: Vec<PathBuf>,
+
+        /// The template structure for element symbols. If not provided, the
+        /// element symbols are read from the INIT message of i-PI server.
+        #[structopt(long)]
+        mol: Option<PathBuf>,
+
+        /// Read stress tensor (for virial) and extra properties from this
+        /// OUTCAR after each computation (e.g. the OUTCAR of running VASP
+        /// server). For multiple clients, relative path is resolved against
+        /// each BBM directory.
+        #[structopt(long)]
+        outcar: Option<PathBuf>,
+
+        /// Max number of retries for reconnecting to i-PI server. Set it to
+        /// zero to disable reconnection.
+        #[structopt(long, default_value = "10")]
+        max_retries: usize,
+
+        /// Recreate BBM after reconnection, instead of keeping the
+        /// underlying interactive VASP session alive.
+        #[structopt(long)]
+        restart_session: bool,
+
+        /// Run this VASP program in interactive mode in current directory,
+        /// and pass positions to its stdin directly instead of calling BBM.
+        #[structopt(long, conflicts_with = "bbm_dirs")]
+        vasp: Option<PathBuf>,
+
+        /// The pattern (regex) in VASP stdout when it is ready for next
+        /// input (for --vasp). Repeat it to accept any of multiple patterns.
+        #[structopt(long, default_value = crate::vasp::stdout::VASP_READ_PATTERN)]
+        read_pattern: Vec<String>,
+
+        /// Read ionic velocities from this POSCAR/CONTCAR for the initial
+        /// calculation (for --vasp), e.g. restarting MD of VASP.
+        #[structopt(long, requires = "vasp")]
+        velocities: Option<PathBuf>,
+
+        /// Compute with this analytical potential (harmonic or lj) instead
+        /// of VASP, for testing optimizers and i-PI stack.
+        #[structopt(long, conflicts_with_all = ["bbm_dirs", "vasp"])]
+        potential: Option<crate::potential::Potential>,
+
+        /// Max number of retries when BBM calculation fails
+        #[structopt(long, default_value = "0")]
+        bbm_retries: usize,
+
+        /// The wall time limit in seconds for each BBM evaluation
+        #[structopt(long)]
+        bbm_timeout: Option<f64>,
+
+        /// Which scratch directories of BBM evaluations to keep: none,
+        /// failed, all or last-N. The mapping from evaluation to scratch
+        /// directory is recorded in manifest.txt in scratch root.
+        #[structopt(long, default_value = "none")]
+        keep_scratch: crate::bbm::ScratchPolicy,
+
+        /// The parent directory of scratch directories to keep
+        #[structopt(long)]
+        scratch_root: Option<PathBuf>,
+
+        /// Save the state of BBM calculations into this file after each
+        /// evaluation, and resume from it if it exists.
+        #[structopt(long)]
+        checkpoint: Option<PathBuf>,
+    },
+
+    /// Run as an i-PI driver (server), sending structures to a connected
+    /// client for computation.
+    IpiDriver {
+        /// The address to bind: "unix:<name or path>" for unix socket, or
+        /// "<host:port>" for TCP socket.
+        #[structopt(short = 'a', long, default_value = "localhost:10244")]
+        address: crate::ipi::Endpoint,
+
+        /// The structures to compute (trajectory file). If not provided,
+        /// structures will be read from stdin.
+        input: Option<PathBuf>,
+
+        /// The file format for reading structures from stdin, e.g. xyz,
+        /// vasp/input
+        #[structopt(long, default_value = "xyz")]
+        format: String,
+
+        /// Write computed results into file instead of stdout
+        #[structopt(short = 'o')]
+        output: Option<PathBuf>,
+    },
+
+    /// Archive important input/output files of a VASP run directory into
+    /// tar.zst with a manifest of SHA-256 checksums
+    Archive {
+        /// The VASP run directory
+        #[structopt(default_value = ".")]
+        dir: PathBuf,
+
+        /// The archive file to write. The default is `<dir name>.tar.zst`.
+        #[structopt(short = 'o')]
+        output: Option<PathBuf>,
+
+        /// Store OUTCAR as OUTCAR.zst with high compression level
+        #[structopt(long)]
+        compress_outcar: bool,
+
+        /// Include large files (WAVECAR, CHG, CHGCAR), which are skipped by
+        /// default
+        #[structopt(long)]
+        include_large: bool,
+    },
+
+    /// Remove large files (WAVECAR, CHG, CHGCAR, PROCAR) in VASP run
+    /// directories for reclaiming disk space
+    Clean {
+        /// The VASP run directories
+        #[structopt(default_value = ".")]
+        dirs: Vec<PathBuf>,
+
+        /// Also search sub-directories recursively
+        #[structopt(short = 'r', long)]
+        recursive: bool,
+
+        /// Only remove files no less than this size in MB
+        #[structopt(long, default_value = "1")]
+        min_size: f64,
+
+        /// List files to be removed without removing them
+        #[structopt(short = 'n', long)]
+        dry_run: bool,
+    },
+
+    /// Compare INCAR, final energy/forces/structure and SCF behaviour of two
+    /// VASP run directories
+    Compare {
+        /// The first VASP run directory
+        dir_a: PathBuf,
+
+        /// The second VASP run directory
+        dir_b: PathBuf,
+    },
+
+    /// Analyze structure in POSCAR/CONTCAR, or each frame in XDATCAR
+    Poscar {
+        /// The structure file. Files named with "XDATCAR" are read as
+        /// trajectory.
+        #[structopt(default_value = "POSCAR")]
+        file: PathBuf,
+
+        /// Report coordination numbers, nearest neighbor distances and
+        /// unphysical close contacts, based on neighbor list
+        #[structopt(long)]
+        analyze: bool,
+
+        /// Atoms within this distance in Å are neighbors. The default is
+        /// `--scale` times the sum of covalent radii.
+        #[structopt(long)]
+        cutoff: Option<f64>,
+
+        /// The scaling factor on the sum of covalent radii for neighbor
+        /// cutoff
+        #[structopt(long, default_value = "1.2")]
+        scale: f64,
+
+        /// Atoms closer than this ratio of the sum of covalent radii are
+        /// flagged as close contacts
+        #[structopt(long, default_value = "0.6")]
+        close_ratio: f64,
+    },
+
+    /// Detect point group and lattice points of periodic structure, and
+    /// write its primitive or reduced cell. With `--compare`, report
+    /// symmetry before and after relaxation, e.g. POSCAR and CONTCAR. The
+    /// space group type (e.g. ITA number) is not resolved.
+    Symmetry {
+        /// The structure file
+        #[structopt(default_value = "POSCAR")]
+        file: PathBuf,
+
+        /// The tolerance in Å for matching atom positions
+        #[structopt(long, default_value = "0.01")]
+        symprec: f64,
+
+        /// Compare symmetry with this structure, e.g. CONTCAR
+        #[structopt(long, conflicts_with = "primitive")]
+        compare: Option<PathBuf>,
+
+        /// Write the primitive cell
+        #[structopt(long, conflicts_with = "reduce")]
+        primitive: bool,
+
+        /// Write the primitive cell with reduced lattice vectors and atoms
+        /// grouped by element. This is not the conventional standard cell
+        /// in International Tables.
+        #[structopt(long, conflicts_with = "compare")]
+        reduce: bool,
+
+        /// Write the new cell in POSCAR format into this file instead of
+        /// stdout
+        #[structopt(short = 'o')]
+        output: Option<PathBuf>,
+    },
+
+    /// Compute adsorption energy from converged runs of slab, molecule and
+    /// slab with adsorbate, and check their settings for consistency
+    Eads {
+        /// The run directory of clean slab
+        #[structopt(long)]
+        slab: PathBuf,
+
+        /// The run directory of gas phase molecule
+        #[structopt(long)]
+        mol: PathBuf,
+
+        /// The run directory of slab with adsorbate
+        #[structopt(long)]
+        total: PathBuf,
+    },
+
+    /// Compute surface energies of slabs relative to bulk, and report the
+    /// convergence with slab thickness
+    Surface {
+        /// The run directory of bulk
+        #[structopt(long)]
+        bulk: PathBuf,
+
+        /// The run directory of slab. Repeat it for slabs in increasing
+        /// thickness to check convergence.
+        #[structopt(long = "slab", required = true)]
+        slabs: Vec<PathBuf>,
+
+        /// Run VASP with this program in bulk and slab directories before
+        /// computing surface energies
+        #[structopt(short = 'x')]
+        program: Option<PathBuf>,
+
+        /// The max number of jobs running in parallel
+        #[structopt(short = 'j', long, default_value = "1", requires = "program")]
+        jobs: usize,
+    },
+
+    /// Detect layers of slab along surface normal, and report interlayer
+    /// spacings and their relaxation
+    Layers {
+        /// The slab structure before relaxation
+        #[structopt(default_value = "POSCAR")]
+        file: PathBuf,
+
+        /// The slab structure after relaxation (e.g. CONTCAR) to compare
+        #[structopt(long)]
+        relaxed: Option<PathBuf>,
+
+        /// The max height difference in Å of atoms in the same layer
+        #[structopt(long, default_value = "0.5")]
+        tol: f64,
+
+        /// The bulk interlayer spacing in Å as the reference for relaxation.
+        /// The spacings before relaxation are used if not set.
+        #[structopt(long)]
+        bulk_spacing: Option<f64>,
+    },
+
+    /// Report energy terms of charged defect: net charge, electrostatic
+    /// corrections in OUTCAR and potential alignment from LOCPOT
+    Defect {
+        /// The run directory of defect supercell
+        #[structopt(long)]
+        defect: PathBuf,
+
+        /// The run directory of pristine bulk supercell
+        #[structopt(long)]
+        bulk: PathBuf,
+
+        /// The valence band maximum of bulk in eV, required for formation
+        /// energy of charged defect
+        #[structopt(long, allow_hyphen_values = true)]
+        vbm: Option<f64>,
+
+        /// The lattice vector for planar averaged potential: x, y or z
+        #[structopt(long, default_value = "z")]
+        axis: String,
+
+        /// The region "start:end" in fractional coordinates along axis for
+        /// potential alignment, which should be far from the defect
+        #[structopt(long, default_value = "0.4:0.6")]
+        region: String,
+
+        /// Add the energy correction for charged system in OUTCAR to
+        /// formation energy, if not included in total energy
+        #[structopt(long)]
+        add_correction: bool,
+    },
+
+    /// Sample frames with energies and forces from OUTCAR of MD run, and
+    /// export them as training set for machine learning potentials
+    Dataset {
+        /// The OUTCAR of MD run. Element symbols are read from POSCAR or
+        /// CONTCAR next to it.
+        #[structopt(default_value = "OUTCAR")]
+        outcar: PathBuf,
+
+        /// Skip the first frames for equilibration
+        #[structopt(long, default_value = "0")]
+        skip: usize,
+
+        /// Take every n-th frame
+        #[structopt(long, default_value = "1", conflicts_with = "fps")]
+        stride: usize,
+
+        /// Select this number of frames by farthest point sampling on
+        /// histograms of interatomic distances
+        #[structopt(long)]
+        fps: Option<usize>,
+
+        /// The cutoff of distance histograms for farthest point sampling (Å)
+        #[structopt(long, default_value = "6.0", requires = "fps")]
+        cutoff: f64,
+
+        /// Only keep frames with energy in window "min:max" (eV), where
+        /// either bound can be omitted, e.g. "-520.5:"
+        #[structopt(long, allow_hyphen_values = true)]
+        energy_window: Option<String>,
+
+        /// The output format: extxyz or json
+        #[structopt(long, default_value = "extxyz")]
+        format: crate::vasp::dataset::DatasetFormat,
+
+        /// The output file
+        #[structopt(short = 'o')]
+        output: PathBuf,
+    },
+
+    /// Export trajectories into DeePMD-kit raw/npy data layout, grouped
+    /// into systems of the same atoms
+    Deepmd {
+        /// OUTCAR files with POSCAR next to them, or extxyz trajectories
+        /// (.xyz or .extxyz) written in interactive mode
+        #[structopt(required = true)]
+        files: Vec<PathBuf>,
+
+        /// The element symbols for atom types, e.g. "O,H". Derived from
+        /// species in the order of appearance if not set.
+        #[structopt(long, use_delimiter = true)]
+        type_map: Vec<String>,
+
+        /// The maximum number of frames in each set.xxx directory
+        #[structopt(long, default_value = "5000")]
+        set_size: usize,
+
+        /// The output directory
+        #[structopt(short = 'o')]
+        output: PathBuf,
+    },
+
+    /// Print the final per-atom forces in OUTCAR with element, frozen
+    /// status and |F|, sorted by magnitude. Elements and selective dynamics
+    /// flags are read from POSCAR or CONTCAR in the same directory.
+    Forces {
+        /// The OUTCAR file
+        #[structopt(default_value = "OUTCAR")]
+        file: PathBuf,
+
+        /// Only print the N atoms with largest forces
+        #[structopt(long)]
+        top: Option<usize>,
+    },
+
+    /// Track total and per-atom magnetization across ionic steps, and flag
+    /// spin flips or oscillating moments, which usually indicate a bad
+    /// MAGMOM starting guess.
+    Magnetization {
+        /// OUTCAR, or OSZICAR for total magnetization only. Per-atom
+        /// moments in OUTCAR require LORBIT set.
+        #[structopt(default_value = "OUTCAR")]
+        file: PathBuf,
+
+        /// Only moments larger than this (μB) are considered for spin flips
+        #[structopt(long, default_value = "0.5")]
+        threshold: f64,
+
+        /// Changes of moments smaller than this (μB) are ignored for
+        /// oscillation
+        #[structopt(long, default_value = "0.1")]
+        amplitude: f64,
+
+        /// The number of steps with moments moving up and down alternately
+        /// to be flagged as oscillation
+        #[structopt(long, default_value = "6")]
+        window: usize,
+
+        /// Keep monitoring as new ionic steps are appended, until VASP
+        /// finished
+        #[structopt(long)]
+        follow: bool,
+
+        /// The refresh interval in seconds for `--follow` mode
+        #[structopt(long, default_value = "5")]
+        interval: f64,
+
+        /// Pause the interactive VASP server on the first event in
+        /// `--follow` mode
+        #[structopt(long, requires = "follow")]
+        pause: bool,
+
+        /// The socket file of the interactive VASP server to pause. The
+        /// default is the same as the server started in current directory.
+        #[structopt(short = 'u')]
+        socket_file: Option<PathBuf>,
+
+        /// Run this shell command on each event in `--follow` mode. The
+        /// payload is passed in stdin, as for hooks of VASP server.
+        #[structopt(long, env = "VASP_HOOK_COMMAND")]
+        hook_command: Option<String>,
+
+        /// Post the payload to this webhook URL on each event in
+        /// `--follow` mode
+        #[structopt(long, env = "VASP_HOOK_URL")]
+        hook_url: Option<String>,
+    },
+
+    /// Generate a self-contained HTML or Markdown report of a VASP run
+    /// directory, including convergence verdict, plots, final structure,
+    /// key INCAR tags and timing information.
+    Report {
+        /// The VASP run directory
+        #[structopt(default_value = ".")]
+        dir: PathBuf,
+
+        /// The report format: html or markdown
+        #[structopt(long, default_value = "html")]
+        format: crate::vasp::report::ReportFormat,
+
+        /// The report file to write. The default is `report.html` or
+        /// `report.md` in run directory.
+        #[structopt(short = 'o')]
+        output: Option<PathBuf>,
+    },
+
+    /// Continue a VASP calculation: back up POSCAR/CONTCAR/OUTCAR/OSZICAR
+    /// with step-indexed suffixes and copy CONTCAR to POSCAR
+    Continue {
+        /// The VASP run directory
+        #[structopt(default_value = ".")]
+        dir: PathBuf,
+
+        /// Update INCAR to restart from WAVECAR (ISTART = 1) and CHGCAR
+        /// (ICHARG = 1) if available
+        #[structopt(long)]
+        restart_from_files: bool,
+    },
+
+    /// Show provenance metadata (VASP version, POTCAR hashes, INCAR,
+    /// hostname, MPI ranks) recorded in provenance.json of a VASP run
+    Provenance {
+        /// The VASP run directory
+        #[structopt(default_value = ".")]
+        dir: PathBuf,
+
+        /// Capture provenance metadata now and write provenance.json
+        #[structopt(long)]
+        record: bool,
+    },
+
+    /// Watch a tree of VASP run directories, and append convergence
+    /// summaries of finished runs into an index file (CSV or JSONL) for
+    /// high-throughput screening.
+    WatchDir {
+        /// The root directory of VASP runs
+        root: PathBuf,
+
+        /// The index file to append. Runs already in it are skipped. The
+        /// default is `index.jsonl` in root directory.
+        #[structopt(long)]
+        index: Option<PathBuf>,
+
+        /// The format of index file: csv or jsonl. The default is guessed
+        /// from the extension of index file.
+        #[structopt(long)]
+        format: Option<crate::vasp::watch::IndexFormat>,
+
+        /// Rescan the whole tree every this many seconds, in addition to
+        /// inotify events
+        #[structopt(long, default_value = "300")]
+        interval: f64,
+
+        /// Scan once and exit without watching
+        #[structopt(long)]
+        once: bool,
+    },
+
+    /// Run VASP in many prepared run directories with bounded parallelism,
+    /// and show a consolidated table of results.
+    Batch {
+        /// The command or the path to invoking VASP program
+        #[structopt(short = 'x')]
+        program: PathBuf,
+
+        /// The run directories. Wildcards (* and ?) are expanded, so
+        /// patterns can be quoted for very many directories.
+        dirs: Vec<String>,
+
+        /// Also read run directories from this file, one per line
+        #[structopt(long)]
+        list: Option<PathBuf>,
+
+        /// The max number of jobs running in parallel
+        #[structopt(short = 'j', long, default_value = "1")]
+        jobs: usize,
+
+        /// Update INCAR for this task before running: single-point or
+        /// frequency
+        #[structopt(long)]
+        task: Option<crate::vasp::VaspTask>,
+
+        /// Max number of retries for crashed jobs
+        #[structopt(long, default_value = "0")]
+        retries: usize,
+
+        /// The wall time limit in seconds for each job
+        #[structopt(long)]
+        timeout: Option<f64>,
+
+        /// Also write results into this CSV file
+        #[structopt(short = 'o')]
+        output: Option<PathBuf>,
+    },
+
+    /// Test convergence of ENCUT and k-mesh with a series of single-point
+    /// calculations, and report the converged settings
+    Converge {
+        /// The command or the path to invoking VASP program
+        #[structopt(short = 'x')]
+        program: PathBuf,
+
+        /// The directory containing input files (INCAR, POSCAR, POTCAR and
+        /// KPOINTS)
+        #[structopt(long, default_value = ".")]
+        template: PathBuf,
+
+        /// The range of tested ENCUT in eV: start:stop:step
+        #[structopt(long)]
+        encut: Option<crate::scan::ScanRange>,
+
+        /// The range of length parameters (Å) for automatic k-mesh as in
+        /// VASP fully automatic scheme: start:stop:step. The k-mesh is
+        /// tested with the converged ENCUT.
+        #[structopt(long)]
+        kpoints_length: Option<crate::scan::ScanRange>,
+
+        /// The energy tolerance in eV/atom
+        #[structopt(long, default_value = "0.001")]
+        energy_tol: f64,
+
+        /// Also require max force to agree within this tolerance (eV/Å)
+        #[structopt(long)]
+        force_tol: Option<f64>,
+
+        /// The max number of jobs running in parallel
+        #[structopt(short = 'j', long, default_value = "1")]
+        jobs: usize,
+
+        /// The wall time limit in seconds for each job
+        #[structopt(long)]
+        timeout: Option<f64>,
+
+        /// Run calculations in sub-directories of this directory
+        #[structopt(long, default_value = "converge")]
+        output_dir: PathBuf,
+    },
+
+    /// Calculate equation of state by single-point calculations at scaled
+    /// volumes, and fit energies to Birch-Murnaghan equation
+    Eos {
+        /// The command or the path to invoking VASP program
+        #[structopt(short = 'x')]
+        program: PathBuf,
+
+        /// The directory containing input files (INCAR, POSCAR, POTCAR and
+        /// KPOINTS)
+        #[structopt(long, default_value = ".")]
+        template: PathBuf,
+
+        /// The range of volume scaling factors: start:stop:step
+        #[structopt(long, default_value = "0.94:1.06:0.02")]
+        scale: crate::scan::ScanRange,
+
+        /// The max number of jobs running in parallel
+        #[structopt(short = 'j', long, default_value = "1")]
+        jobs: usize,
+
+        /// The wall time limit in seconds for each job
+        #[structopt(long)]
+        timeout: Option<f64>,
+
+        /// Run calculations in sub-directories of this directory
+        #[structopt(long, default_value = "eos")]
+        output_dir: PathBuf,
+
+        /// Also write the table of volumes and energies into this file
+        #[structopt(short = 'o')]
+        output: Option<PathBuf>,
+    },
+
+    /// Extract named blocks (forces, stress, eigenvalues, frequency, ...) or
+    /// blocks matching a pattern from VASP output
+    Grep {
+        /// The VASP output file
+        #[structopt(default_value = "OUTCAR")]
+        file: PathBuf,
+
+        /// The predefined block to extract. Use --list to show available
+        /// blocks.
+        #[structopt(short = 'b', long, conflicts_with = "pattern")]
+        block: Option<String>,
+
+        /// Extract blocks starting at lines matching this regex pattern
+        #[structopt(short = 'e', long)]
+        pattern: Option<String>,
+
+        /// The number of lines of each block, including the matched line.
+        /// The default for predefined blocks depends on the number of atoms
+        /// or bands.
+        #[structopt(short = 'A', long)]
+        context: Option<usize>,
+
+        /// Extract the last block only
+        #[structopt(long)]
+        last: bool,
+
+        /// Output blocks in JSON
+        #[structopt(long)]
+        json: bool,
+
+        /// List predefined blocks
+        #[structopt(long)]
+        list: bool,
+    },
+
+    /// Optimize geometry with built-in LBFGS or FIRE optimizer using
+    /// interactive VASP, BBM or analytical potential
+    Optimize {
+        /// The initial structure. Atoms fixed in selective dynamics will
+        /// not be moved.
+        #[structopt(long, default_value = "POSCAR")]
+        mol: PathBuf,
+
+        /// Run this VASP program in interactive mode in current directory
+        #[structopt(long, conflicts_with = "bbm_dir")]
+        vasp: Option<PathBuf>,
+
+        /// Evaluate with BBM in this directory
+        #[structopt(long)]
+        bbm_dir: Option<PathBuf>,
+
+        /// Compute with this analytical potential (harmonic or lj) instead
+        /// of VASP, for testing.
+        #[structopt(long, conflicts_with_all = ["bbm_dir", "vasp"])]
+        potential: Option<crate::potential::Potential>,
+
+        /// The pattern (regex) in VASP stdout when it is ready for next
+        /// input (for --vasp). Repeat it to accept any of multiple patterns.
+        #[structopt(long, default_value = crate::vasp::stdout::VASP_READ_PATTERN)]
+        read_pattern: Vec<String>,
+
+        /// The optimization algorithm: lbfgs or fire
+        #[structopt(long, default_value = "lbfgs")]
+        algorithm: crate::optim::OptAlgorithm,
+
+        /// Converged if max force on free atoms is below this (eV/Å)
+        #[structopt(long, default_value = "0.05")]
+        fmax: f64,
+
+        /// The max number of optimization steps
+        #[structopt(long, default_value = "200")]
+        max_steps: usize,
+
+        /// The max displacement of any atom in one step (Å)
+        #[structopt(long, default_value = "0.2")]
+        max_step: f64,
+
+        /// Write the latest structure into this file after each step
+        #[structopt(long, default_value = "CONTCAR")]
+        snapshot: PathBuf,
+
+        /// Append each evaluated structure with computed energy and forces
+        /// into this extxyz trajectory file.
+        #[structopt(long)]
+        trajectory: Option<PathBuf>,
+    },
+
+    /// Find minimum energy path between two structures using nudged
+    /// elastic band, with images evaluated concurrently by a pool of BBM
+    /// drivers (each with its own interactive VASP session)
+    Neb {
+        /// The initial structure. Atoms fixed in selective dynamics will
+        /// not be moved.
+        initial: PathBuf,
+
+        /// The final structure
+        #[structopt(value_name = "FINAL")]
+        final_: PathBuf,
+
+        /// The number of intermediate images
+        #[structopt(short = 'n', long, default_value = "5")]
+        nimages: usize,
+
+        /// Evaluate with BBM in this directory. Repeat it to evaluate
+        /// images concurrently, usually one directory per image.
+        #[structopt(long = "bbm-dir")]
+        bbm_dirs: Vec<PathBuf>,
+
+        /// Compute with this analytical potential (harmonic or lj) instead
+        /// of VASP, for testing.
+        #[structopt(long, conflicts_with = "bbm_dirs")]
+        potential: Option<crate::potential::Potential>,
+
+        /// The spring constant between images (eV/Å^2)
+        #[structopt(long, default_value = "5.0")]
+        spring: f64,
+
+        /// Use climbing image for the highest energy image
+        #[structopt(long)]
+        climb: bool,
+
+        /// The optimization algorithm: fire or lbfgs
+        #[structopt(long, default_value = "fire")]
+        algorithm: crate::optim::OptAlgorithm,
+
+        /// Converged if max NEB force on free atoms is below this (eV/Å)
+        #[structopt(long, default_value = "0.05")]
+        fmax: f64,
+
+        /// The max number of NEB iterations
+        #[structopt(long, default_value = "200")]
+        max_steps: usize,
+
+        /// The max displacement of any atom in one step (Å)
+        #[structopt(long, default_value = "0.2")]
+        max_step: f64,
+
+        /// Write images into sub-directories (00, 01, ...) of this
+        /// directory after each iteration
+        #[structopt(long, default_value = "neb")]
+        output_dir: PathBuf,
+
+        /// Append the energy profile of each iteration into this file
+        #[structopt(long, default_value = "neb-profile.dat")]
+        profile: PathBuf,
+    },
+
+    /// Search saddle point with the dimer method, starting along an
+    /// imaginary mode from OUTCAR or a random direction
+    Dimer {
+        /// The initial structure. Atoms fixed in selective dynamics will
+        /// not be moved.
+        #[structopt(long, default_value = "POSCAR")]
+        mol: PathBuf,
+
+        /// Run this VASP program in interactive mode in current directory
+        #[structopt(long, conflicts_with = "bbm_dir")]
+        vasp: Option<PathBuf>,
+
+        /// Evaluate with BBM in this directory
+        #[structopt(long)]
+        bbm_dir: Option<PathBuf>,
+
+        /// Compute with this analytical potential (harmonic or lj) instead
+        /// of VASP, for testing.
+        #[structopt(long, conflicts_with_all = ["bbm_dir", "vasp"])]
+        potential: Option<crate::potential::Potential>,
+
+        /// The pattern (regex) in VASP stdout when it is ready for next
+        /// input (for --vasp). Repeat it to accept any of multiple patterns.
+        #[structopt(long, default_value = crate::vasp::stdout::VASP_READ_PATTERN)]
+        read_pattern: Vec<String>,
+
+        /// Start along a vibrational mode from this OUTCAR of frequency
+        /// calculation. Without it, a random direction is used.
+        #[structopt(long, value_name = "OUTCAR")]
+        mode_from: Option<PathBuf>,
+
+        /// Select the mode by its index as numbered by VASP. The default is
+        /// the last imaginary mode.
+        #[structopt(long, requires = "mode_from")]
+        mode: Option<usize>,
+
+        /// The seed for random initial direction
+        #[structopt(long, default_value = "1", conflicts_with = "mode_from")]
+        seed: u64,
+
+        /// The distance between the center and the end point of dimer (Å)
+        #[structopt(long, default_value = "0.01")]
+        separation: f64,
+
+        /// The optimization algorithm for translation: fire or lbfgs
+        #[structopt(long, default_value = "fire")]
+        algorithm: crate::optim::OptAlgorithm,
+
+        /// Converged if max force on free atoms is below this (eV/Å)
+        #[structopt(long, default_value = "0.05")]
+        fmax: f64,
+
+        /// The max number of translation steps
+        #[structopt(long, default_value = "200")]
+        max_steps: usize,
+
+        /// The max displacement of any atom in one step (Å)
+        #[structopt(long, default_value = "0.1")]
+        max_step: f64,
+
+        /// Write the latest structure into this file after each step
+        #[structopt(long, default_value = "CONTCAR")]
+        snapshot: PathBuf,
+    },
+
+    /// Scan the distance between two atoms rigidly, and report the energy
+    /// profile with a plot
+    Scan {
+        /// The first atom (1-based), which is fixed in scan
+        i: usize,
+
+        /// The second atom (1-based), which is moved along the bond
+        j: usize,
+
+        /// The range of distances in Å: start:stop:step
+        range: crate::scan::ScanRange,
+
+        /// Move these atoms (1-based, e.g. "2,5-7" for a fragment) together
+        /// with atom j. The default is atom j only.
+        #[structopt(long)]
+        moving: Option<String>,
+
+        /// The reference structure
+        #[structopt(long, default_value = "POSCAR")]
+        mol: PathBuf,
+
+        /// Run this VASP program in interactive mode in current directory,
+        /// and evaluate points one by one in the same session.
+        #[structopt(long, conflicts_with = "bbm_dirs")]
+        vasp: Option<PathBuf>,
+
+        /// Evaluate with BBM in this directory. Repeat it to evaluate
+        /// points in parallel with a pool of BBM drivers.
+        #[structopt(long = "bbm-dir")]
+        bbm_dirs: Vec<PathBuf>,
+
+        /// Compute with this analytical potential (harmonic or lj) instead
+        /// of VASP, for testing.
+        #[structopt(long, conflicts_with_all = ["bbm_dirs", "vasp"])]
+        potential: Option<crate::potential::Potential>,
+
+        /// The pattern (regex) in VASP stdout when it is ready for next
+        /// input (for --vasp). Repeat it to accept any of multiple patterns.
+        #[structopt(long, default_value = crate::vasp::stdout::VASP_READ_PATTERN)]
+        read_pattern: Vec<String>,
+
+        /// Append scanned structures with computed energy and forces into
+        /// this extxyz trajectory file.
+        #[structopt(long)]
+        trajectory: Option<PathBuf>,
+
+        /// Write the energy profile table into this file
+        #[structopt(short = 'o')]
+        output: Option<PathBuf>,
+    },
+
+    /// Compute (partial) Hessian by finite differences of forces in a
+    /// persistent interactive VASP session, and report normal modes
+    Hessian {
+        /// Run this VASP program in interactive mode in current directory
+        #[structopt(long)]
+        vasp: Option<PathBuf>,
+
+        /// Compute with this analytical potential (harmonic or lj) instead
+        /// of VASP, for testing.
+        #[structopt(long, conflicts_with = "vasp")]
+        potential: Option<crate::potential::Potential>,
+
+        /// The reference structure
+        #[structopt(long, default_value = "POSCAR")]
+        mol: PathBuf,
+
+        /// Displace these atoms only (1-based, e.g. "37-40") for a partial
+        /// Hessian. All atoms are displaced by default.
+        #[structopt(long)]
+        atoms: Option<String>,
+
+        /// The displacement step in Å
+        #[structopt(long, default_value = "0.015")]
+        step: f64,
+
+        /// The pattern (regex) in VASP stdout when it is ready for next
+        /// input. Repeat it to accept any of multiple patterns.
+        #[structopt(long, default_value = crate::vasp::stdout::VASP_READ_PATTERN)]
+        read_pattern: Vec<String>,
+
+        /// Write Hessian matrix (eV/Å^2) into this file
+        #[structopt(short = 'o')]
+        output: Option<PathBuf>,
+
+        /// Export normal modes into this file for visualization
+        #[structopt(long)]
+        export: Option<PathBuf>,
+
+        /// The format for exporting modes: molden or gaussian
+        #[structopt(long, default_value = "molden")]
+        export_format: crate::vasp::vibration::VibFormat,
+    },
+}
+
+/// Read molecules from file `input`, or from stdin in `format` if `input` is
+/// None.
+fn read_molecules(input: Option<&Path>, format: &str) -> Result<Vec<gosh::gchemol::Molecule>> {
+    let mols = match input {
+        Some(f) => gosh::gchemol::io::read(f)?.collect(),
+        None => {
+            // NOTE: gchemol guesses the format from file extension
+            let txt = crate::vasp::stdin::read_txt_from_stdin()?;
+            let dir = tempfile::tempdir()?;
+            let ext = format.replace('/', "_");
+            let f = match format {
+                "vasp/input" => dir.path().join("POSCAR"),
+                _ => dir.path().join(format!("stdin.{}", ext)),
+            };
+            gut::fs::write_to_file(&f, &txt)?;
+            gosh::gchemol::io::read(&f)?.collect()
+        }
+    };
+    Ok(mols)
+}
+
+pub fn vasp_enter_main() -> Result<()> {
+    let args = VaspCli::parse();
+    args.verbose.setup_logger();
+
+    match args.task {
+        VaspTaskCli::Ps { pattern, orphans } => {
+            crate::process::show_vasp_processes(&pattern, orphans, false, false)?;
+        }
+        VaspTaskCli::KillOrphans { pattern, force } => {
+            crate::process::show_vasp_processes(&pattern, true, true, force)?;
+        }
+        VaspTaskCli::Submit {
+            program,
+            scheduler,
+            job_name,
+            nodes,
+            ntasks_per_node,
+            time,
+            partition,
+            requeue,
+            output,
+        } => {
+            use crate::scheduler::*;
+
+            let opts = SubmitOptions {
+                scheduler,
+                job_name,
+                nodes,
+                ntasks_per_node,
+                walltime: crate::hooks::parse_walltime(&time)?,
+                partition,
+                program,
+                requeue,
+            };
+            let txt = format_submit_script(&opts);
+            match output {
+                Some(f) => gut::fs::write_to_file(&f, &txt)?,
+                None => print!("{}", txt),
+            }
+        }
+        VaspTaskCli::SystemdUnit {
+            name,
+            program,
+            workdir,
+            socket_file,
+            watchdog,
+            output,
+        } => {
+            use crate::systemd::*;
+
+            let cwd = std::env::current_dir()?;
+            let run_vasp = std::env::current_exe()?.with_file_name("run-vasp");
+            let opts = UnitOptions {
+                name,
+                workdir: workdir.map_or_else(|| cwd.clone(), |d| cwd.join(d)),
+                run_vasp: if run_vasp.exists() { run_vasp } else { "run-vasp".into() },
+                program,
+                socket_file: socket_file.map(|f| cwd.join(f)),
+                watchdog,
+            };
+            for (fname, txt) in format_unit_files(&opts) {
+                match &output {
+                    Some(dir) => {
+                        let f = dir.join(&fname);
+                        gut::fs::write_to_file(&f, &txt)?;
+                        println!("wrote {:?}", f);
+                    }
+                    None => println!("# {}\n{}", fname, txt),
+                }
+            }
+        }
+        VaspTaskCli::IpiClient {
+            address,
+            bbm_dirs,
+            mol,
+            outcar,
+            max_retries,
+            restart_session,
+            vasp,
+            read_pattern,
+            velocities,
+            potential,
+            bbm_retries,
+            bbm_timeout,
+            keep_scratch,
+            scratch_root,
+            checkpoint,
+        } => {
+            use gosh::gchemol::prelude::*;
+            use gosh::gchemol::Molecule;
+
+            let mol = match &mol {
+                Some(f) => Molecule::from_file(f)?.into(),
+                None => None,
+            };
+            let opts = crate::ipi::IpiClientOptions {
+                endpoint: address,
+                outcar,
+                reconnect: crate::ipi::ReconnectOptions {
+                    max_retries,
+                    keep_session: !restart_session,
+                },
+                bbm: crate::bbm::BbmOptions {
+                    retry: crate::bbm::RetryPolicy {
+                        max_retries: bbm_retries,
+                        timeout: bbm_timeout,
+                        ..Default::default()
+                    },
+                    scratch_policy: keep_scratch,
+                    scratch_root,
+                    checkpoint,
+                },
+            };
+            if let Some(pot) = potential {
+                let rt = tokio::runtime::Runtime::new()?;
+                rt.block_on(crate::ipi::potential_as_ipi_client(pot, mol, &opts))?;
+            } else if let Some(program) = &vasp {
+                let rt = tokio::runtime::Runtime::new()?;
+                let vasp = crate::ipi::VaspEngineOptions {
+                    read_pattern: crate::session::join_read_patterns(&read_pattern)?,
+                    velocities: velocities.as_deref().map(read_velocities_from).transpose()?,
+                    ..Default::default()
+                };
+                rt.block_on(crate::ipi::vasp_as_ipi_client(program, &vasp, mol, &opts))?;
+            } else if let [bbm_dir] = bbm_dirs.as_slice() {
+                let rt = tokio::runtime::Runtime::new()?;
+                rt.block_on(crate::ipi::bbm_as_ipi_client(bbm_dir, mol, &opts))?;
+            } else {
+                crate::ipi::bbm_pool_as_ipi_client(&bbm_dirs, mol, &opts)?;
+            }
+        }
+        VaspTaskCli::IpiDriver {
+            address,
+            input,
+            format,
+            output,
+        } => {
+            use std::io::Write;
+
+            let mols = read_molecules(input.as_deref(), &format)?;
+            let mut out: Box<dyn std::io::Write> = match &output {
+                Some(f) => Box::new(std::fs::File::create(f)?),
+                None => Box::new(std::io::stdout()),
+            };
+            let rt = tokio::runtime::Runtime::new()?;
+            rt.block_on(crate::ipi::ipi_driver(&address, mols, |i, mol, computed| {
+                info!("structure {}: energy = {}", i, computed.energy());
+                if let Some(extra) = computed.extra_data() {
+                    info!("structure {}: extra = {:?}", i, extra);
+                }
+                let mp = computed.to_model_properties(mol);
+                writeln!(out, "{}", mp)?;
+                out.flush()?;
+                Ok(())
+            }))?;
+        }
+        VaspTaskCli::Archive {
+            dir,
+            output,
+            compress_outcar,
+            include_large,
+        } => {
+            let opts = crate::vasp::archive::ArchiveOptions {
+                compress_outcar,
+                include_large,
+            };
+            let output = match output {
+                Some(f) => f,
+                None => {
+                    let dir = dir.canonicalize()?;
+                    let name = dir.file_name().and_then(|x| x.to_str()).unwrap_or("run");
+                    format!("{}.tar.zst", name).into()
+                }
+            };
+            let manifest = crate::vasp::archive::archive_run_dir(&dir, &output, &opts)?;
+            println!("{} files archived into {:?}", manifest.len(), output);
+        }
+        VaspTaskCli::Clean {
+            dirs,
+            recursive,
+            min_size,
+            dry_run,
+        } => {
+            let min_size = (min_size * 1024.0 * 1024.0) as u64;
+            crate::vasp::clean::clean_run_dirs(&dirs, min_size, recursive, dry_run)?;
+        }
+        VaspTaskCli::Compare { dir_a, dir_b } => {
+            crate::vasp::compare::compare_runs(&dir_a, &dir_b)?;
+        }
+        VaspTaskCli::Poscar {
+            file,
+            analyze,
+            cutoff,
+            scale,
+            close_ratio,
+        } => {
+            use crate::vasp::neighbors::*;
+            use gosh::gchemol::prelude::*;
+            use gosh::gchemol::Molecule;
+
+            let opts = NeighborOptions {
+                cutoff,
+                scale,
+                close_ratio,
+            };
+            let is_xdatcar = file.file_name().map_or(false, |x| x.to_string_lossy().contains("XDATCAR"));
+            match (is_xdatcar, analyze) {
+                (true, true) => print!("{}", format_frames_summary(&analyze_xdatcar(&file, &opts)?)),
+                (false, true) => {
+                    let mol = Molecule::from_file(&file)?;
+                    print!("{}", format_neighbor_analysis(&analyze_molecule(&mol, &opts)?));
+                }
+                (true, false) => {
+                    let frames = vasp_parsers::xdatcar::parse_xdatcar(&gut::fs::read_file(&file)?)?;
+                    println!("{} frames in {:?}", frames.len(), file);
+                }
+                (false, false) => {
+                    let mol = Molecule::from_file(&file)?;
+                    let volume = mol.get_lattice().map(|lat| format!("{:.4} Å^3", lat.volume()));
+                    println!("number of atoms: {}", mol.natoms());
+                    println!("cell volume: {}", volume.unwrap_or("--".into()));
+                    println!("use --analyze for coordination analysis");
+                }
+            }
+        }
+        VaspTaskCli::Symmetry {
+            file,
+            symprec,
+            compare,
+            primitive,
+            reduce,
+            output,
+        } => {
+            use crate::vasp::symmetry::*;
+            use gosh::gchemol::prelude::*;
+            use gosh::gchemol::Molecule;
+
+            let mol = Molecule::from_file(&file)?;
+            if primitive || reduce {
+                let cell = if reduce {
+                    reduced_cell(&mol, symprec)?
+                } else {
+                    primitive_cell(&mol, symprec)?
+                };
+                let txt = cell.format_as("vasp/input")?;
+                match output {
+                    Some(f) => gut::fs::write_to_file(&f, &txt)?,
+                    None => print!("{}", txt),
+                }
+            } else if let Some(other) = compare {
+                let before = analyze_symmetry(&mol, symprec)?;
+                let after = analyze_symmetry(&Molecule::from_file(&other)?, symprec)?;
+                print!("{}", format_symmetry_change(&before, &after));
+            } else {
+                print!("{}", format_symmetry(&analyze_symmetry(&mol, symprec)?));
+            }
+        }
+        VaspTaskCli::Eads { slab, mol, total } => {
+            use crate::vasp::eads::*;
+
+            let eads = adsorption_energy(&slab, &mol, &total)?;
+            print!("{}", format_eads(&eads));
+        }
+        VaspTaskCli::Surface {
+            bulk,
+            slabs,
+            program,
+            jobs,
+        } => {
+            use crate::vasp::surface::*;
+
+            if let Some(program) = program {
+                use crate::vasp::batch::*;
+
+                let dirs: Vec<_> = std::iter::once(bulk.clone()).chain(slabs.iter().cloned()).collect();
+                let opts = BatchOptions {
+                    jobs,
+                    ..Default::default()
+                };
+                let results = run_batch(&program, &dirs, &opts)?;
+                print!("{}", format_results_table(&results));
+            }
+            let report = surface_energies(&bulk, &slabs)?;
+            print!("{}", format_surface(&report));
+        }
+        VaspTaskCli::Layers {
+            file,
+            relaxed,
+            tol,
+            bulk_spacing,
+        } => {
+            use crate::vasp::surface::*;
+            use gosh::gchemol::Molecule;
+
+            let before = detect_layers(&Molecule::from_file(&file)?, tol)?;
+            let after = relaxed
+                .map(|f| Molecule::from_file(&f).and_then(|mol| detect_layers(&mol, tol)))
+                .transpose()?;
+            println!("found {} layers in {:?}", before.len(), file);
+            print!("{}", format_layers(&before, after.as_deref(), bulk_spacing)?);
+        }
+        VaspTaskCli::Defect {
+            defect,
+            bulk,
+            vbm,
+            axis,
+            region,
+            add_correction,
+        } => {
+            use crate::vasp::defect::*;
+
+            let opts = DefectOptions {
+                vbm,
+                axis: parse_axis(&axis)?,
+                region: parse_region(&region)?,
+                add_correction,
+            };
+            let report = defect_report(&defect, &bulk, &opts)?;
+            print!("{}", format_defect_report(&report));
+        }
+        VaspTaskCli::Dataset {
+            outcar,
+            skip,
+            stride,
+            fps,
+            cutoff,
+            energy_window,
+            format,
+            output,
+        } => {
+            use crate::vasp::dataset::*;
+
+            let mol = crate::vasp::vibration::read_structure_for_outcar(&outcar)?;
+            let frames = parse_md_frames(&outcar)?;
+            let opts = SampleOptions {
+                skip,
+                energy_window: energy_window.as_deref().map(parse_energy_window).transpose()?.unwrap_or((None, None)),
+                sampling: fps.map_or(Sampling::Stride(stride), Sampling::Fps),
+                cutoff,
+            };
+            let selected = sample_frames(&frames, &opts);
+            ensure!(!selected.is_empty(), "no frame selected from {} frames", frames.len());
+            let txt = format_dataset(&mol, &selected, format)?;
+            gut::fs::write_to_file(&output, &txt)?;
+            println!("{} of {} frames written into {:?}", selected.len(), frames.len(), output);
+        }
+        VaspTaskCli::Deepmd {
+            files,
+            type_map,
+            set_size,
+            output,
+        } => {
+            let systems = crate::vasp::deepmd::export_deepmd(&files, &output, &type_map, set_size)?;
+            for (i, s) in systems.iter().enumerate() {
+                println!("sys.{:03}: {} atoms, {} frames", i, s.symbols.len(), s.frames.len());
+            }
+        }
+        VaspTaskCli::Forces { file, top } => {
+            let table = crate::vasp::forces::read_force_table(&file)?;
+            print!("{}", crate::vasp::forces::format_force_table(&table, top));
+        }
+        VaspTaskCli::Magnetization {
+            file,
+            threshold,
+            amplitude,
+            window,
+            follow,
+            interval,
+            pause,
+            socket_file,
+            hook_command,
+            hook_url,
+        } => {
+            use crate::vasp::magnetization::*;
+
+            let opts = MagCheckOptions {
+                threshold,
+                amplitude,
+                window,
+            };
+            if follow {
+                let hooks = crate::hooks::Hooks {
+                    command: hook_command,
+                    webhook: hook_url,
+                    template: None,
+                };
+                let dir = file.parent().filter(|d| !d.as_os_str().is_empty()).unwrap_or(".".as_ref());
+                let t0 = std::time::Instant::now();
+                let mut paused = false;
+                monitor_magnetization(&file, &opts, interval, |e| {
+                    warn!("step {}: {}", e.step, e.message);
+                    let mut summary = crate::hooks::RunSummary::collect(dir, None, t0.elapsed().as_secs_f64());
+                    summary.event = crate::hooks::HookEvent::Magnetization;
+                    summary.message = e.message.clone();
+                    hooks.notify(&summary);
+                    if pause && !paused {
+                        let socket_file = match &socket_file {
+                            Some(f) => f.to_owned(),
+                            None => crate::socket::default_socket_file()?,
+                        };
+                        let rt = tokio::runtime::Runtime::new()?;
+                        rt.block_on(async {
+                            let mut client = Client::connect(&socket_file).await?;
+                            client.try_pause().await
+                        })?;
+                        info!("VASP server paused. Resume it after checking MAGMOM.");
+                        paused = true;
+                    }
+                    Ok(())
+                })?;
+            } else {
+                let steps = read_mag_steps(&file)?;
+                let events = check_magnetization(&steps, &opts);
+                print!("{}", format_magnetization(&steps, &events, threshold));
+            }
+        }
+        VaspTaskCli::Report { dir, format, output } => {
+            let doc = crate::vasp::report::generate_report(&dir, format)?;
+            let output = output.unwrap_or_else(|| dir.join(format!("report.{}", format.extension())));
+            gut::fs::write_to_file(&output, &doc)?;
+            println!("report written into {:?}", output);
+        }
+        VaspTaskCli::Continue { dir, restart_from_files } => {
+            let n = crate::vasp::restart::continue_run(&dir, restart_from_files)?;
+            println!("previous files backed up with suffix .{}", n);
+        }
+        VaspTaskCli::Provenance { dir, record } => {
+            use crate::vasp::provenance::Provenance;
+
+            let prov = if record { Provenance::record(&dir)? } else { Provenance::read(&dir)? };
+            println!("{}", serde_json::to_string_pretty(&prov)?);
+        }
+        VaspTaskCli::WatchDir {
+            root,
+            index,
+            format,
+            interval,
+            once,
+        } => {
+            use crate::vasp::watch::{DirWatcher, IndexFormat};
+
+            let index = index.unwrap_or_else(|| root.join("index.jsonl"));
+            let format = format.unwrap_or_else(|| IndexFormat::from_path(&index));
+            let mut watcher = DirWatcher::new(&root, &index, format)?;
+            if once {
+                let n = watcher.scan()?;
+                println!("{} finished runs indexed into {:?}", n, index);
+            } else {
+                watcher.watch(interval)?;
+            }
+        }
+        VaspTaskCli::Grep {
+            file,
+            block,
+            pattern,
+            context,
+            last,
+            json,
+            list,
+        } => {
+            use crate::vasp::grep::*;
+
+            if list {
+                for (name, help) in predefined_blocks() {
+                    println!("{:<15} {}", name, help);
+                }
+                return Ok(());
+            }
+            let blocks = match (&block, &pattern) {
+                (Some(name), _) => grep_named_blocks(&file, name, context, last)?,
+                (None, Some(pattern)) => grep_blocks(&file, pattern, pattern, context.unwrap_or(1), last)?,
+                (None, None) => bail!("either --block or --pattern is required"),
+            };
+            if json {
+                println!("{}", serde_json::to_string_pretty(&blocks)?);
+            } else {
+                print!("{}", format_blocks(&blocks));
+            }
+        }
+        VaspTaskCli::Batch {
+            program,
+            dirs,
+            list,
+            jobs,
+            task,
+            retries,
+            timeout,
+            output,
+        } => {
+            use crate::vasp::batch::*;
+
+            if let Some(crate::vasp::VaspTask::Interactive) = task {
+                bail!("interactive task is not supported in batch mode");
+            }
+            let dirs = collect_run_dirs(&dirs, list.as_deref())?;
+            ensure!(!dirs.is_empty(), "no run directory to process");
+            let opts = BatchOptions {
+                jobs,
+                task,
+                retry: crate::bbm::RetryPolicy {
+                    max_retries: retries,
+                    timeout,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let results = run_batch(&program, &dirs, &opts)?;
+            print!("{}", format_results_table(&results));
+            if let Some(f) = output {
+                write_results_csv(&f, &results)?;
+                println!("results written into {:?}", f);
+            }
+        }
+        VaspTaskCli::Converge {
+            program,
+            template,
+            encut,
+            kpoints_length,
+            energy_tol,
+            force_tol,
+            jobs,
+            timeout,
+            output_dir,
+        } => {
+            use crate::vasp::converge::*;
+
+            let mut opts = ConvergeOptions {
+                encuts: encut.map(|r| r.values()).unwrap_or_default(),
+                kpoints_lengths: kpoints_length.map(|r| r.values()).unwrap_or_default(),
+                energy_tol,
+                force_tol,
+                output_dir,
+                ..Default::default()
+            };
+            opts.batch.jobs = jobs;
+            opts.batch.retry.timeout = timeout;
+            let report = run_convergence(&program, &template, &opts)?;
+            let natoms = report.natoms;
+            if !report.encut_points.is_empty() {
+                let i = report.encut_points.iter().position(|p| Some(p.value) == report.encut);
+                print!("{}", format_convergence(&report.encut_points, natoms, i));
+                println!("{}", plot_convergence(&report.encut_points, natoms, "ENCUT convergence", "ENCUT (eV)")?);
+            }
+            if !report.kmesh_points.is_empty() {
+                let i = report.kmesh.and_then(|m| {
+                    let s = format!("KPOINTS = {}x{}x{}", m[0], m[1], m[2]);
+                    report.kmesh_points.iter().position(|p| p.setting == s)
+                });
+                print!("{}", format_convergence(&report.kmesh_points, natoms, i));
+                println!("{}", plot_convergence(&report.kmesh_points, natoms, "k-mesh convergence", "number of k-points")?);
+            }
+            match report.encut {
+                Some(e) => println!("converged ENCUT: {} eV", e),
+                None if !report.encut_points.is_empty() => println!("ENCUT not converged within {} eV/atom", energy_tol),
+                None => {}
+            }
+            match report.kmesh {
+                Some(m) => println!("converged k-mesh: {}x{}x{}", m[0], m[1], m[2]),
+                None if !report.kmesh_points.is_empty() => println!("k-mesh not converged within {} eV/atom", energy_tol),
+                None => {}
+            }
+        }
+        VaspTaskCli::Eos {
+            program,
+            template,
+            scale,
+            jobs,
+            timeout,
+            output_dir,
+            output,
+        } => {
+            use crate::vasp::eos::*;
+
+            let mut opts = EosOptions {
+                scales: scale.values(),
+                output_dir,
+                ..Default::default()
+            };
+            opts.batch.jobs = jobs;
+            opts.batch.retry.timeout = timeout;
+            let points = run_eos(&program, &template, &opts)?;
+            let fit = fit_eos_points(&points);
+            if let Err(e) = &fit {
+                warn!("EOS fitting failed: {:?}", e);
+            }
+            let txt = format_eos(&points, fit.as_ref().ok());
+            print!("{}", txt);
+            println!("{}", plot_eos(&points)?);
+            if let Some(f) = output {
+                gut::fs::write_to_file(&f, &txt)?;
+                println!("EOS written into {:?}", f);
+            }
+        }
+        VaspTaskCli::Optimize {
+            mol,
+            vasp,
+            bbm_dir,
+            potential,
+            read_pattern,
+            algorithm,
+            fmax,
+            max_steps,
+            max_step,
+            snapshot,
+            trajectory,
+        } => {
+            use crate::ipi::{ForceEngine, VaspEngine};
+            use crate::optim::*;
+            use gosh::gchemol::prelude::*;
+            use gosh::gchemol::Molecule;
+
+            let mol = Molecule::from_file(&mol).with_context(|| format!("read structure from {:?}", mol))?;
+            let opts = OptOptions {
+                algorithm,
+                fmax,
+                max_steps,
+                max_step,
+                snapshot: Some(snapshot.clone()),
+                trajectory,
+            };
+            let rt = tokio::runtime::Runtime::new()?;
+            // NOTE: VASP engine spawns its task server in the runtime
+            let _guard = rt.enter();
+            let mut engine = match (&vasp, &bbm_dir, potential) {
+                (_, _, Some(pot)) => ForceEngine::Potential(pot),
+                (Some(program), _, None) => {
+                    let read_pattern = crate::session::join_read_patterns(&read_pattern)?;
+                    ForceEngine::Vasp(VaspEngine::start(program, &Default::default(), &read_pattern)?)
+                }
+                (None, Some(d), None) => ForceEngine::Bbm(crate::bbm::BbmOptions::default().build_driver(d)?),
+                (None, None, None) => bail!("one of --vasp, --bbm-dir or --potential is required"),
+            };
+            let r = rt.block_on(optimize(&mut engine, &mol, &opts));
+            if let ForceEngine::Vasp(vasp) = &engine {
+                rt.block_on(vasp.terminate())?;
+            }
+            let r = r?;
+            let status = if r.converged { "converged" } else { "not converged" };
+            println!(
+                "optimization {} in {} steps: energy = {:.8} eV, fmax = {:.6} eV/Å",
+                status, r.nsteps, r.energy, r.fmax
+            );
+            let txt = r.mol.format_as("vasp/input")?;
+            gut::fs::write_to_file(&snapshot, &txt)?;
+        }
+        VaspTaskCli::Neb {
+            initial,
+            final_,
+            nimages,
+            bbm_dirs,
+            potential,
+            spring,
+            climb,
+            algorithm,
+            fmax,
+            max_steps,
+            max_step,
+            output_dir,
+            profile,
+        } => {
+            use crate::ipi::ForceEngine;
+            use crate::neb::*;
+            use crate::pool::EnginePool;
+            use gosh::gchemol::prelude::*;
+            use gosh::gchemol::Molecule;
+
+            let read = |f: &Path| Molecule::from_file(f).with_context(|| format!("read structure from {:?}", f));
+            let images = interpolate(&read(&initial)?, &read(&final_)?, nimages)?;
+            let pool = match potential {
+                Some(pot) => EnginePool::new(nimages.min(4), move |_| Ok(ForceEngine::Potential(pot)))?,
+                None => {
+                    ensure!(!bbm_dirs.is_empty(), "either --bbm-dir or --potential is required");
+                    info!("evaluate {} images with {} BBM drivers", nimages, bbm_dirs.len());
+                    let n = bbm_dirs.len();
+                    let new_engine = move |i: usize| -> Result<ForceEngine> {
+                        let driver = crate::bbm::BbmOptions::default().build_driver(&bbm_dirs[i])?;
+                        Ok(ForceEngine::Bbm(driver))
+                    };
+                    EnginePool::new(n, new_engine)?
+                }
+            };
+            let opts = NebOptions {
+                spring,
+                climb,
+                algorithm,
+                fmax,
+                max_steps,
+                max_step,
+                output_dir: output_dir.into(),
+                profile: profile.into(),
+            };
+            let r = run_neb(&pool, images, &opts)?;
+            let status = if r.converged { "converged" } else { "not converged" };
+            println!("NEB {} in {} iterations: fmax = {:.6} eV/Å", status, r.nsteps, r.fmax);
+            print!("{}", format_mep_profile(&r.images, &r.energies));
+            println!("{}", plot_mep_profile(&r.images, &r.energies)?);
+        }
+        VaspTaskCli::Dimer {
+            mol,
+            vasp,
+            bbm_dir,
+            potential,
+            read_pattern,
+            mode_from,
+            mode,
+            seed,
+            separation,
+            algorithm,
+            fmax,
+            max_steps,
+            max_step,
+            snapshot,
+        } => {
+            use crate::dimer::*;
+            use crate::ipi::{ForceEngine, VaspEngine};
+            use crate::vasp::VaspOutcar;
+            use gosh::gchemol::prelude::*;
+            use gosh::gchemol::Molecule;
+
+            let mol = Molecule::from_file(&mol).with_context(|| format!("read structure from {:?}", mol))?;
+            let mask = crate::optim::free_coords_mask(&mol);
+            let initial = match &mode_from {
+                Some(outcar) => {
+                    let modes = VaspOutcar::parse_vib_modes_from(outcar)?;
+                    let selected = match mode {
+                        Some(i) => crate::vasp::select_mode_by_index(&modes, i)?,
+                        None => modes
+                            .iter()
+                            .filter(|m| m.frequency < 0.0)
+                            .last()
+                            .with_context(|| format!("no imaginary mode in {:?}", outcar))?,
+                    };
+                    println!("start along mode {} ({:.2} cm-1)", selected.index, selected.frequency);
+                    initial_mode(&selected.displacements, &mask)?
+                }
+                None => random_mode(&mask, seed)?,
+            };
+            let opts = DimerOptions {
+                separation,
+                algorithm,
+                fmax,
+                max_steps,
+                max_step,
+                snapshot: Some(snapshot.clone()),
+                ..Default::default()
+            };
+            let rt = tokio::runtime::Runtime::new()?;
+            // NOTE: VASP engine spawns its task server in the runtime
+            let _guard = rt.enter();
+            let mut engine = match (&vasp, &bbm_dir, potential) {
+                (_, _, Some(pot)) => ForceEngine::Potential(pot),
+                (Some(program), _, None) => {
+                    let read_pattern = crate::session::join_read_patterns(&read_pattern)?;
+                    ForceEngine::Vasp(VaspEngine::start(program, &Default::default(), &read_pattern)?)
+                }
+                (None, Some(d), None) => ForceEngine::Bbm(crate::bbm::BbmOptions::default().build_driver(d)?),
+                (None, None, None) => bail!("one of --vasp, --bbm-dir or --potential is required"),
+            };
+            let r = rt.block_on(run_dimer(&mut engine, &mol, initial, &opts));
+            if let ForceEngine::Vasp(vasp) = &engine {
+                rt.block_on(vasp.terminate())?;
+            }
+            let r = r?;
+            let status = if r.converged { "converged" } else { "not converged" };
+            println!(
+                "dimer search {} in {} steps: energy = {:.8} eV, fmax = {:.6} eV/Å, curvature = {:.6} eV/Å^2",
+                status, r.nsteps, r.energy, r.fmax, r.curvature
+            );
+            let txt = r.mol.format_as("vasp/input")?;
+            gut::fs::write_to_file(&snapshot, &txt)?;
+        }
+        VaspTaskCli::Scan {
+            i,
+            j,
+            range,
+            moving,
+            mol,
+            vasp,
+            bbm_dirs,
+            potential,
+            read_pattern,
+            trajectory,
+            output,
+        } => {
+            use crate::ipi::{ForceEngine, VaspEngine};
+            use crate::scan::*;
+            use gosh::gchemol::prelude::*;
+            use gosh::gchemol::Molecule;
+
+            let mol = Molecule::from_file(&mol).with_context(|| format!("read structure from {:?}", mol))?;
+            let moving = match &moving {
+                Some(s) => crate::vasp::vibration::parse_atom_selection(s, mol.natoms())?,
+                None => vec![],
+            };
+            let distances = range.values();
+            let mols = scan_structures(&mol, i, j, &moving, &distances)?;
+            let all = if !bbm_dirs.is_empty() {
+                evaluate_with_bbm_pool(&bbm_dirs, &Default::default(), &mols)?
+            } else {
+                let rt = tokio::runtime::Runtime::new()?;
+                // NOTE: VASP engine spawns its task server in the runtime
+                let _guard = rt.enter();
+                let mut engine = match (&vasp, potential) {
+                    (_, Some(pot)) => ForceEngine::Potential(pot),
+                    (Some(program), None) => {
+                        let read_pattern = crate::session::join_read_patterns(&read_pattern)?;
+                        ForceEngine::Vasp(VaspEngine::start(program, &Default::default(), &read_pattern)?)
+                    }
+                    (None, None) => bail!("one of --vasp, --bbm-dir or --potential is required"),
+                };
+                let all = rt.block_on(evaluate_in_sequence(&mut engine, &mols));
+                if let ForceEngine::Vasp(vasp) = &engine {
+                    rt.block_on(vasp.terminate())?;
+                }
+                all?
+            };
+            let points = collect_profile(&distances, &mols, &all, trajectory.as_deref())?;
+            let table = format_profile(&points);
+            print!("{}", table);
+            println!("{}", plot_profile(&points, i, j)?);
+            if let Some(f) = output {
+                gut::fs::write_to_file(&f, &table)?;
+                println!("energy profile written into {:?}", f);
+            }
+        }
+        VaspTaskCli::Hessian {
+            vasp,
+            potential,
+            mol,
+            atoms,
+            step,
+            read_pattern,
+            output,
+            export,
+            export_format,
+        } => {
+            use crate::hessian::*;
+            use crate::ipi::{ForceEngine, VaspEngine};
+            use gosh::gchemol::prelude::*;
+            use gosh::gchemol::Molecule;
+
+            let mol = Molecule::from_file(&mol).with_context(|| format!("read structure from {:?}", mol))?;
+            let atoms = match &atoms {
+                Some(s) => crate::vasp::vibration::parse_atom_selection(s, mol.natoms())?,
+                None => vec![],
+            };
+            let opts = HessianOptions { step, atoms };
+            let rt = tokio::runtime::Runtime::new()?;
+            // NOTE: VASP engine spawns its task server in the runtime
+            let _guard = rt.enter();
+            let engine = match (&vasp, potential) {
+                (_, Some(pot)) => ForceEngine::Potential(pot),
+                (Some(program), None) => {
+                    let read_pattern = crate::session::join_read_patterns(&read_pattern)?;
+                    ForceEngine::Vasp(VaspEngine::start(program, &Default::default(), &read_pattern)?)
+                }
+                (None, None) => bail!("either --vasp or --potential is required"),
+            };
+            let modes = rt.block_on(run_hessian(engine, &mol, &opts, output.as_deref()))?;
+            if let Some(f) = &export {
+                let modes: Vec<_> = modes.iter().collect();
+                let s = crate::vasp::vibration::format_vib_modes_as(&mol, &modes, export_format)?;
+                gut::fs::write_to_file(f, &s)?;
+                println!("{} modes exported into {:?}", modes.len(), f);
+            }
+        }
+    }
+
+    Ok(())
+}
+// c9274095 ends here
+
+// [[file:../vasp-tools.note::8a712671][8a712671]]
+/// A fake i-PI driver for testing i-PI clients, sending deterministic
+/// structures as ASE's SocketIOCalculator does and checking the responses.
+#[derive(Debug, StructOpt)]
+struct FakeIpiDriverCli {
+    #[structopt(flatten)]
+    verbose: gut::cli::Verbosity,
+
+    /// The address to bind: "unix:<name or path>" for unix socket, or
+    /// "<host:port>" for TCP socket.
+    #[structopt(short = 'a', long, default_value = "localhost:10244")]
+    address: crate::ipi::Endpoint,
+
+    /// The number of structures to compute
+    #[structopt(short = 'n', long, default_value = "5")]
+    nsteps: usize,
+}
+
+#[tokio::main]
+pub async fn fake_ipi_driver_enter_main() -> Result<()> {
+    let args = FakeIpiDriverCli::parse();
+    args.verbose.setup_logger();
+
+    crate::ipi::fake_ipi_driver(&args.address, args.nsteps).await?;
+
+    Ok(())
+}
+// 8a712671 ends here
@@ -0,0 +1,271 @@
+// [[file:../vasp-tools.note::*docs][docs:1]]
+//! Geometric constraints (fixed bond lengths and angles) applied by
+//! projecting out constrained components of forces for external optimizers
+// docs:1 ends here
+
+// [[file:../vasp-tools.note::040f9ae0][040f9ae0]]
+use super::*;
+
+use gosh::gchemol::Molecule;
+// 040f9ae0 ends here
+
+// [[file:../vasp-tools.note::174f9754][174f9754]]
+/// A geometric constraint over atoms (0-based)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Constraint {
+    /// Fixed distance between two atoms
+    Distance(usize, usize),
+    /// Fixed angle i-j-k with atom j at the vertex
+    Angle(usize, usize, usize),
+}
+
+/// Cartesian coordinates with minimum image convention for periodic
+/// structures
+struct Geometry {
+    coords: Vec<[f64; 3]>,
+    lattice: Option<[[f64; 3]; 3]>,
+}
+
+impl Geometry {
+    fn from_molecule(mol: &Molecule) -> Self {
+        let lattice = mol.get_lattice().map(|lat| lat.vectors());
+        let coords = match lattice {
+            Some(_) => mol.get_scaled_positions().unwrap().collect(),
+            None => mol.positions().collect(),
+        };
+        Self { coords, lattice }
+    }
+
+    /// Return the Cartesian vector from atom `i` to atom `j`.
+    fn vector(&self, i: usize, j: usize) -> [f64; 3] {
+        let mut d = [0.0; 3];
+        for k in 0..3 {
+            d[k] = self.coords[j][k] - self.coords[i][k];
+        }
+        if let Some(vs) = self.lattice {
+            // minimum image in fractional coordinates
+            let df = d.map(|x| x - x.round());
+            d = [0.0; 3];
+            for k in 0..3 {
+                for l in 0..3 {
+                    d[l] += df[k] * vs[k][l];
+                }
+            }
+        }
+        d
+    }
+}
+
+/// Return the Cartesian vector from atom `i` to atom `j` (0-based) in
+/// `mol`, with minimum image convention for periodic structures.
+pub(crate) fn bond_vector(mol: &Molecule, i: usize, j: usize) -> [f64; 3] {
+    Geometry::from_molecule(mol).vector(i, j)
+}
+
+fn dot(a: [f64; 3], b: [f64; 3]) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+impl Constraint {
+    fn atoms(&self) -> Vec<usize> {
+        match *self {
+            Self::Distance(i, j) => vec![i, j],
+            Self::Angle(i, j, k) => vec![i, j, k],
+        }
+    }
+
+    /// Return the current value of constrained distance (in Å) or angle
+    /// (in degree).
+    fn value_in(&self, geom: &Geometry) -> f64 {
+        match *self {
+            Self::Distance(i, j) => dot(geom.vector(i, j), geom.vector(i, j)).sqrt(),
+            Self::Angle(i, j, k) => {
+                let (u, v) = (geom.vector(j, i), geom.vector(j, k));
+                let cos = dot(u, v) / (dot(u, u) * dot(v, v)).sqrt();
+                cos.clamp(-1.0, 1.0).acos().to_degrees()
+            }
+        }
+    }
+
+    /// Return the gradient of the constrained coordinate with respect to
+    /// positions of involved atoms.
+    fn gradient(&self, geom: &Geometry) -> Result<Vec<(usize, [f64; 3])>> {
+        let grad = match *self {
+            Self::Distance(i, j) => {
+                let d = geom.vector(i, j);
+                let r = dot(d, d).sqrt();
+                ensure!(r > 1e-6, "atoms {} and {} overlap", i + 1, j + 1);
+                let g = d.map(|x| x / r);
+                vec![(i, g.map(|x| -x)), (j, g)]
+            }
+            Self::Angle(i, j, k) => {
+                let (u, v) = (geom.vector(j, i), geom.vector(j, k));
+                let (ru, rv) = (dot(u, u).sqrt(), dot(v, v).sqrt());
+                ensure!(ru > 1e-6 && rv > 1e-6, "overlapped atoms in angle {}-{}-{}", i + 1, j + 1, k + 1);
+                let cos = dot(u, v) / (ru * rv);
+                let sin = (1.0 - cos * cos).max(0.0).sqrt();
+                ensure!(sin > 1e-6, "angle {}-{}-{} is linear", i + 1, j + 1, k + 1);
+                let mut gi = [0.0; 3];
+                let mut gk = [0.0; 3];
+                for l in 0..3 {
+                    gi[l] = -(v[l] / (ru * rv) - cos * u[l] / (ru * ru)) / sin;
+                    gk[l] = -(u[l] / (ru * rv) - cos * v[l] / (rv * rv)) / sin;
+                }
+                let gj = [-gi[0] - gk[0], -gi[1] - gk[1], -gi[2] - gk[2]];
+                vec![(i, gi), (j, gj), (k, gk)]
+            }
+        };
+        Ok(grad)
+    }
+}
+
+impl std::fmt::Display for Constraint {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            Self::Distance(i, j) => write!(f, "distance {} {}", i + 1, j + 1),
+            Self::Angle(i, j, k) => write!(f, "angle {} {} {}", i + 1, j + 1, k + 1),
+        }
+    }
+}
+
+/// A set of constraints read from a constraints file, one constraint per
+/// line with 1-based atom indices:
+///
+/// ```text
+/// # fix the C-O bond
+/// distance 1 2
+/// # fix the H-C-O angle
+/// angle 3 1 2
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Constraints {
+    constraints: Vec<Constraint>,
+}
+
+impl std::str::FromStr for Constraints {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut constraints = vec![];
+        for (n, line) in s.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let items: Vec<_> = line.split_whitespace().collect();
+            let atoms: Vec<usize> = items[1..]
+                .iter()
+                .map(|x| x.parse())
+                .collect::<std::result::Result<_, _>>()
+                .with_context(|| format!("invalid atom index in line {}: {:?}", n + 1, line))?;
+            ensure!(atoms.iter().all(|&i| i > 0), "atom index starts from 1 in line {}: {:?}", n + 1, line);
+            let c = match (items[0].to_lowercase().as_str(), atoms.as_slice()) {
+                ("distance" | "bond", &[i, j]) => Constraint::Distance(i - 1, j - 1),
+                ("angle", &[i, j, k]) => Constraint::Angle(i - 1, j - 1, k - 1),
+                _ => bail!("invalid constraint in line {}: {:?}", n + 1, line),
+            };
+            constraints.push(c);
+        }
+        Ok(Self { constraints })
+    }
+}
+
+impl Constraints {
+    /// Read constraints from file `f`.
+    pub fn from_file(f: &Path) -> Result<Self> {
+        let s = gut::fs::read_file(f)?;
+        s.parse().with_context(|| format!("read constraints from {:?}", f))
+    }
+
+    /// Check that all constrained atoms are in `mol`.
+    fn check_atoms(&self, mol: &Molecule) -> Result<()> {
+        let natoms = mol.natoms();
+        for c in &self.constraints {
+            for i in c.atoms() {
+                ensure!(i < natoms, "{}: atom {} out of range 1-{}", c, i + 1, natoms);
+            }
+        }
+        Ok(())
+    }
+
+    /// Return constraints with their current values in `mol`.
+    pub fn values(&self, mol: &Molecule) -> Result<Vec<(Constraint, f64)>> {
+        self.check_atoms(mol)?;
+        let geom = Geometry::from_molecule(mol);
+        Ok(self.constraints.iter().map(|c| (*c, c.value_in(&geom))).collect())
+    }
+
+    /// Project out components of `forces` along the gradients of all
+    /// constrained coordinates in `mol`, so that an optimizer following
+    /// the forces keeps the constrained distances and angles unchanged
+    /// (to first order). As in SHAKE, coupled constraints are handled by
+    /// iterating over them until converged.
+    pub fn project_forces(&self, mol: &Molecule, forces: &mut [[f64; 3]]) -> Result<()> {
+        ensure!(forces.len() == mol.natoms(), "invalid number of forces: {}", forces.len());
+        self.check_atoms(mol)?;
+
+        let geom = Geometry::from_molecule(mol);
+        let gradients: Vec<_> = self.constraints.iter().map(|c| c.gradient(&geom)).collect::<Result<_>>()?;
+        let max_iterations = 1000;
+        for _ in 0..max_iterations {
+            let mut residual = 0f64;
+            for grad in &gradients {
+                let gg: f64 = grad.iter().map(|&(_, g)| dot(g, g)).sum();
+                let fg: f64 = grad.iter().map(|&(i, g)| dot(forces[i], g)).sum();
+                let lambda = fg / gg;
+                for &(i, g) in grad {
+                    for l in 0..3 {
+                        forces[i][l] -= lambda * g[l];
+                    }
+                }
+                residual = residual.max(fg.abs() / gg.sqrt());
+            }
+            if residual < 1e-10 {
+                return Ok(());
+            }
+        }
+        bail!("force projection not converged in {} iterations", max_iterations);
+    }
+}
+// 174f9754 ends here
+
+// [[file:../vasp-tools.note::2a038f9a][2a038f9a]]
+#[test]
+fn test_constraints() -> Result<()> {
+    use gosh::gchemol::Atom;
+
+    let s = "# comment
+distance 1 2
+angle 2 1 3  # H-O-H
+";
+    let constraints: Constraints = s.parse()?;
+    assert_eq!(constraints.constraints.len(), 2);
+    assert!("distance 1".parse::<Constraints>().is_err());
+    assert!("distance 0 1".parse::<Constraints>().is_err());
+    assert!("dihedral 1 2 3 4".parse::<Constraints>().is_err());
+
+    let atoms = vec![
+        Atom::new("O", [0.0, 0.0, 0.0]),
+        Atom::new("H", [1.0, 0.0, 0.0]),
+        Atom::new("H", [0.0, 1.0, 0.0]),
+    ];
+    let mol = Molecule::from_atoms(atoms);
+    let values = constraints.values(&mol)?;
+    assert_relative_eq!(values[0].1, 1.0, epsilon = 1e-8);
+    assert_relative_eq!(values[1].1, 90.0, epsilon = 1e-8);
+
+    let mut forces = vec![[0.1, 0.2, 0.3], [0.5, -0.2, 0.1], [-0.3, 0.4, -0.2]];
+    constraints.project_forces(&mol, &mut forces)?;
+    // no force changes the O-H1 distance or the H1-O-H2 angle
+    let geom = Geometry::from_molecule(&mol);
+    for c in &constraints.constraints {
+        let fg: f64 = c.gradient(&geom)?.iter().map(|&(i, g)| dot(forces[i], g)).sum();
+        assert_relative_eq!(fg, 0.0, epsilon = 1e-8);
+    }
+    // unconstrained z components are unchanged
+    assert_relative_eq!(forces[2][2], -0.2, epsilon = 1e-8);
+    assert!("distance 1 4".parse::<Constraints>()?.values(&mol).is_err());
+
+    Ok(())
+}
+// 2a038f9a ends here
@@ -0,0 +1,332 @@
+// [[file:../vasp-tools.note::*docs][docs:1]]
+//! Saddle point search using the dimer method (min-mode following)
+// docs:1 ends here
+
+// [[file:../vasp-tools.note::84f82cea][84f82cea]]
+use super::*;
+
+use crate::ipi::ForceEngine;
+use crate::optim::{Fire, Lbfgs, OptAlgorithm};
+use gosh::gchemol::prelude::*;
+use gosh::gchemol::Molecule;
+// 84f82cea ends here
+
+// [[file:../vasp-tools.note::423e0da1][423e0da1]]
+/// Options for dimer saddle search
+#[derive(Debug, Clone)]
+pub struct DimerOptions {
+    /// The distance between the center and the end point of dimer (Å)
+    pub separation: f64,
+    /// Skip rotation if the rotational force is below this (eV/Å)
+    pub frot_min: f64,
+    /// The max number of rotations per translation step
+    pub max_rotations: usize,
+    pub algorithm: OptAlgorithm,
+    /// Converged if the max force on free atoms is below this (eV/Å) with
+    /// negative curvature
+    pub fmax: f64,
+    /// The max number of translation steps
+    pub max_steps: usize,
+    /// The max displacement of any atom in one step (Å)
+    pub max_step: f64,
+    /// Write the latest structure into this file in POSCAR format after
+    /// each step
+    pub snapshot: Option<PathBuf>,
+}
+
+impl Default for DimerOptions {
+    fn default() -> Self {
+        Self {
+            separation: 0.01,
+            frot_min: 0.1,
+            max_rotations: 4,
+            algorithm: OptAlgorithm::Fire,
+            fmax: 0.05,
+            max_steps: 200,
+            max_step: 0.1,
+            snapshot: None,
+        }
+    }
+}
+
+/// The final state of dimer saddle search
+#[derive(Debug, Clone)]
+pub struct DimerResult {
+    /// The structure at the dimer center
+    pub mol: Molecule,
+    pub energy: f64,
+    pub fmax: f64,
+    /// The curvature along the dimer (eV/Å^2)
+    pub curvature: f64,
+    /// The lowest mode (flattened, normalized)
+    pub mode: Vec<f64>,
+    pub nsteps: usize,
+    pub converged: bool,
+}
+
+fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+fn normalize(v: &mut [f64]) -> Result<()> {
+    let norm = dot(v, v).sqrt();
+    ensure!(norm > 1e-12, "zero vector for dimer direction");
+    v.iter_mut().for_each(|x| *x /= norm);
+    Ok(())
+}
+
+/// Return the initial dimer direction from atomic `displacements` (e.g.
+/// an imaginary mode parsed from OUTCAR), with fixed coordinates in `mask`
+/// removed.
+pub fn initial_mode(displacements: &[[f64; 3]], mask: &[bool]) -> Result<Vec<f64>> {
+    ensure!(displacements.len() * 3 == mask.len(), "mode has a different number of atoms");
+    let mut mode: Vec<f64> = displacements
+        .iter()
+        .flatten()
+        .zip(mask)
+        .map(|(&d, &free)| if free { d } else { 0.0 })
+        .collect();
+    normalize(&mut mode).context("no displacement of free atoms in initial mode")?;
+    Ok(mode)
+}
+
+/// Return a random dimer direction over free coordinates in `mask`,
+/// generated by xorshift with `seed` for reproducibility.
+pub fn random_mode(mask: &[bool], seed: u64) -> Result<Vec<f64>> {
+    let mut state = seed.max(1);
+    let mut uniform = || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        (state >> 11) as f64 / (1u64 << 53) as f64
+    };
+    let mut mode: Vec<f64> = mask
+        .iter()
+        .map(|&free| {
+            // Box-Muller transform for normal distribution
+            let (u1, u2) = (uniform().max(1e-12), uniform());
+            let x = (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos();
+            if free {
+                x
+            } else {
+                0.0
+            }
+        })
+        .collect();
+    normalize(&mut mode)?;
+    Ok(mode)
+}
+
+/// The dimer with center `x0` along direction `mode`
+struct Dimer<'a> {
+    engine: &'a mut ForceEngine,
+    mol: Molecule,
+    mask: Vec<bool>,
+    separation: f64,
+    mode: Vec<f64>,
+}
+
+impl<'a> Dimer<'a> {
+    /// Compute energy and masked forces (flattened) at positions `x`.
+    async fn compute(&mut self, x: &[f64]) -> Result<(f64, Vec<f64>)> {
+        let positions: Vec<[f64; 3]> = x.chunks(3).map(|p| [p[0], p[1], p[2]]).collect();
+        self.mol.set_positions(positions);
+        let props = self.engine.compute(&self.mol).await?;
+        let energy = props.mp.get_energy().context("no energy")?;
+        let forces = props.mp.get_forces().context("no forces")?;
+        let f = forces
+            .iter()
+            .flatten()
+            .zip(&self.mask)
+            .map(|(&f, &free)| if free { f } else { 0.0 })
+            .collect();
+        Ok((energy, f))
+    }
+
+    /// Compute forces at the end point of dimer along `mode`.
+    async fn end_forces(&mut self, x0: &[f64], mode: &[f64]) -> Result<Vec<f64>> {
+        let x1: Vec<_> = x0.iter().zip(mode).map(|(x, n)| x + self.separation * n).collect();
+        Ok(self.compute(&x1).await?.1)
+    }
+
+    /// Return curvature along `mode` from forces at center `f0` and at end
+    /// point `f1`.
+    fn curvature(&self, f0: &[f64], f1: &[f64], mode: &[f64]) -> f64 {
+        let df: Vec<_> = f0.iter().zip(f1).map(|(a, b)| a - b).collect();
+        dot(&df, mode) / self.separation
+    }
+
+    /// Rotate dimer toward the lowest curvature mode at center `x0` with
+    /// forces `f0`, using the method of Kästner and Sherwood. Return the
+    /// curvature and the total rotation angle in degree.
+    async fn rotate(&mut self, x0: &[f64], f0: &[f64], frot_min: f64, max_rotations: usize) -> Result<(f64, f64)> {
+        let mut f1 = self.end_forces(x0, &self.mode.clone()).await?;
+        let mut c0 = self.curvature(f0, &f1, &self.mode);
+        let mut total = 0.0;
+        for _ in 0..max_rotations {
+            // the rotational force perpendicular to the dimer
+            let df: Vec<_> = f1.iter().zip(f0).map(|(a, b)| 2.0 * (a - b)).collect();
+            let along = dot(&df, &self.mode);
+            let mut theta: Vec<_> = df.iter().zip(&self.mode).map(|(f, n)| f - along * n).collect();
+            let frot = dot(&theta, &theta).sqrt();
+            if frot < frot_min {
+                break;
+            }
+            theta.iter_mut().for_each(|x| *x /= frot);
+
+            let n0 = self.mode.clone();
+            let dc = 2.0 * f0.iter().zip(&f1).zip(&theta).map(|((a, b), t)| (a - b) * t).sum::<f64>() / self.separation;
+            let phi1 = -0.5 * (dc / (2.0 * c0.abs())).atan();
+            if 1.0 - (2.0 * phi1).cos() < 1e-12 {
+                break;
+            }
+            let rotated = |phi: f64| -> Vec<f64> {
+                let (c, s) = (phi.cos(), phi.sin());
+                n0.iter().zip(&theta).map(|(n, t)| n * c + t * s).collect()
+            };
+            let mut n1 = rotated(phi1);
+            normalize(&mut n1)?;
+            let f1_trial = self.end_forces(x0, &n1).await?;
+            let c1 = self.curvature(f0, &f1_trial, &n1);
+
+            // fit curvature as C(φ) = a0/2 + a1 cos(2φ) + b1 sin(2φ)
+            let b1 = 0.5 * dc;
+            let a1 = (c0 - c1 + b1 * (2.0 * phi1).sin()) / (1.0 - (2.0 * phi1).cos());
+            let a0 = 2.0 * (c0 - a1);
+            let mut phi_min = 0.5 * (b1 / a1).atan();
+            let fit = |phi: f64| 0.5 * a0 + a1 * (2.0 * phi).cos() + b1 * (2.0 * phi).sin();
+            if fit(phi_min) > c0 {
+                phi_min += 0.5 * std::f64::consts::PI;
+            }
+            let mut n_min = rotated(phi_min);
+            normalize(&mut n_min)?;
+            self.mode = n_min;
+            total += phi_min.to_degrees();
+            f1 = self.end_forces(x0, &self.mode.clone()).await?;
+            c0 = self.curvature(f0, &f1, &self.mode);
+        }
+        Ok((c0, total))
+    }
+}
+
+/// Search saddle point from `mol` using the dimer method, starting along
+/// `mode` (flattened). The atoms fixed in selective dynamics are not moved.
+pub async fn run_dimer(engine: &mut ForceEngine, mol: &Molecule, mode: Vec<f64>, opts: &DimerOptions) -> Result<DimerResult> {
+    let mask = crate::optim::free_coords_mask(mol);
+    ensure!(mode.len() == mask.len(), "initial mode has a different number of atoms");
+    let mut dimer = Dimer {
+        engine,
+        mol: mol.clone(),
+        mask,
+        separation: opts.separation,
+        mode,
+    };
+
+    let mut x0: Vec<f64> = mol.positions().flatten().collect();
+    let mut lbfgs = Lbfgs::new();
+    let mut fire = Fire::new();
+    for i in 1..=opts.max_steps {
+        let (energy, f0) = dimer.compute(&x0).await?;
+        let (curvature, angle) = dimer.rotate(&x0, &f0, opts.frot_min, opts.max_rotations).await?;
+        let fmax = f0.chunks(3).map(|d| dot(d, d).sqrt()).fold(0.0, f64::max);
+        println!(
+            "{:<6} Energy: {:<18.8} fmax: {:<12.6} curvature: {:<12.6} rotated: {:.2}",
+            i, energy, fmax, curvature, angle
+        );
+        let done = fmax < opts.fmax && curvature < 0.0;
+        if done || i == opts.max_steps {
+            if done {
+                info!("saddle point found in {} steps", i);
+            } else {
+                warn!("dimer search not converged in {} steps", i);
+            }
+            dimer.mol.set_positions(x0.chunks(3).map(|p| [p[0], p[1], p[2]]).collect::<Vec<_>>());
+            return Ok(DimerResult {
+                mol: dimer.mol,
+                energy,
+                fmax,
+                curvature,
+                mode: dimer.mode,
+                nsteps: i,
+                converged: done,
+            });
+        }
+
+        // invert the force along the lowest mode, or only climb up along it
+        // in convex region
+        let n = &dimer.mode;
+        let fn_ = dot(&f0, n);
+        let f_eff: Vec<f64> = if curvature < 0.0 {
+            f0.iter().zip(n).map(|(f, n)| f - 2.0 * fn_ * n).collect()
+        } else {
+            n.iter().map(|n| -fn_ * n).collect()
+        };
+        let mut dr = match opts.algorithm {
+            OptAlgorithm::Lbfgs => lbfgs.step(&x0, &f_eff),
+            OptAlgorithm::Fire => fire.step(&f_eff),
+        };
+        for (d, &free) in dr.iter_mut().zip(&dimer.mask) {
+            if !free {
+                *d = 0.0;
+            }
+        }
+        crate::optim::limit_step(&mut dr, opts.max_step);
+        x0.iter_mut().zip(&dr).for_each(|(x, d)| *x += d);
+        if let Some(f) = &opts.snapshot {
+            let mut mol = dimer.mol.clone();
+            mol.set_positions(x0.chunks(3).map(|p| [p[0], p[1], p[2]]).collect::<Vec<_>>());
+            gut::fs::write_to_file(f, &mol.format_as("vasp/input")?)?;
+        }
+    }
+    bail!("no dimer step to run");
+}
+// 423e0da1 ends here
+
+// [[file:../vasp-tools.note::a3cecf72][a3cecf72]]
+#[test]
+fn test_dimer_mode() -> Result<()> {
+    let mask = [true, true, true, false, false, false];
+    let mode = initial_mode(&[[1.0, 0.0, 0.0], [1.0, 0.0, 0.0]], &mask)?;
+    assert_eq!(mode, vec![1.0, 0.0, 0.0, 0.0, 0.0, 0.0]);
+    assert!(initial_mode(&[[0.0; 3], [1.0, 0.0, 0.0]], &mask).is_err());
+
+    let mode = random_mode(&mask, 42)?;
+    assert_relative_eq!(dot(&mode, &mode), 1.0, epsilon = 1e-10);
+    assert_eq!(&mode[3..], &[0.0; 3]);
+    assert_eq!(mode, random_mode(&mask, 42)?);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dimer_rotation() -> Result<()> {
+    use crate::potential::Potential;
+    use gosh::gchemol::Atom;
+
+    // harmonic dimer at equilibrium: the curvature along the stretching
+    // mode is 2k, and zero along translations
+    let mol = Molecule::from_atoms(vec![Atom::new("H", [0.0; 3]), Atom::new("H", [1.5, 0.0, 0.0])]);
+    let mut engine = ForceEngine::Potential(Potential::Harmonic { k: 1.0, r0: 1.5 });
+    let x0: Vec<f64> = mol.positions().flatten().collect();
+    let mut dimer = Dimer {
+        engine: &mut engine,
+        mol: mol.clone(),
+        mask: vec![true; 6],
+        separation: 0.01,
+        mode: initial_mode(&[[-1.0, 0.0, 0.0], [1.0, 0.0, 0.0]], &[true; 6])?,
+    };
+    let (_, f0) = dimer.compute(&x0).await?;
+    let (c, _) = dimer.rotate(&x0, &f0, 0.1, 0).await?;
+    assert_relative_eq!(c, 2.0, epsilon = 1e-2);
+
+    // rotate away from the stretching mode toward the translation
+    dimer.mode = initial_mode(&[[-1.0, 0.0, 0.0], [1.2, 0.0, 0.0]], &[true; 6])?;
+    let (c0, _) = dimer.rotate(&x0, &f0, 1e-3, 0).await?;
+    let (c1, angle) = dimer.rotate(&x0, &f0, 1e-3, 4).await?;
+    assert!(c1 < c0, "curvature not lowered: {} -> {}", c0, c1);
+    assert!(angle.abs() > 1.0);
+    assert!(c1.abs() < 0.05);
+
+    Ok(())
+}
+// a3cecf72 ends here
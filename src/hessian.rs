@@ -0,0 +1,502 @@
+// [[file:../vasp-tools.note::*docs][docs:1]]
+//! Finite-difference Hessian and normal modes computed by driving a
+//! persistent (interactive) force engine, instead of IBRION=5 in VASP.
+// docs:1 ends here
+
+// [[file:../vasp-tools.note::9c713307][9c713307]]
+use super::*;
+
+use crate::ipi::ForceEngine;
+use crate::vasp::VibMode;
+use gosh::gchemol::Molecule;
+// 9c713307 ends here
+
+// [[file:../vasp-tools.note::c16b1cbf][c16b1cbf]]
+/// Standard atomic weights (in amu) of elements from H to Rn
+const ATOMIC_MASSES: [f64; 86] = [
+    1.008, 4.0026, 6.94, 9.0122, 10.81, 12.011, 14.007, 15.999, 18.998, 20.180, // H-Ne
+    22.990, 24.305, 26.982, 28.085, 30.974, 32.06, 35.45, 39.948, 39.098, 40.078, // Na-Ca
+    44.956, 47.867, 50.942, 51.996, 54.938, 55.845, 58.933, 58.693, 63.546, 65.38, // Sc-Zn
+    69.723, 72.630, 74.922, 78.971, 79.904, 83.798, 85.468, 87.62, 88.906, 91.224, // Ga-Zr
+    92.906, 95.95, 97.907, 101.07, 102.91, 106.42, 107.87, 112.41, 114.82, 118.71, // Nb-Sn
+    121.76, 127.60, 126.90, 131.29, 132.91, 137.33, 138.91, 140.12, 140.91, 144.24, // Sb-Nd
+    144.91, 150.36, 151.96, 157.25, 158.93, 162.50, 164.93, 167.26, 168.93, 173.05, // Pm-Yb
+    174.97, 178.49, 180.95, 183.84, 186.21, 190.23, 192.22, 195.08, 196.97, 200.59, // Lu-Hg
+    204.38, 207.2, 208.98, 208.98, 209.99, 222.02, // Tl-Rn
+];
+
+/// The frequency in cm-1 of a harmonic oscillator with force constant of 1
+/// eV/Å^2 and mass of 1 amu
+const FREQ_CM1_PER_SQRT_EV_A2_AMU: f64 = 521.47090;
+
+/// 1 cm-1 in meV
+const CM1_IN_MEV: f64 = 0.12398419843;
+
+/// Return the mass (amu) of element with atomic number `z`.
+fn atomic_mass(z: usize) -> Result<f64> {
+    ensure!(z >= 1 && z <= ATOMIC_MASSES.len(), "no atomic mass for element {}", z);
+    Ok(ATOMIC_MASSES[z - 1])
+}
+
+/// Options for finite-difference Hessian
+#[derive(Debug, Clone)]
+pub struct HessianOptions {
+    /// The displacement step in Å (POTIM for IBRION=5)
+    pub step: f64,
+    /// Displace these atoms only (1-based) for a partial Hessian. All atoms
+    /// are displaced if empty.
+    pub atoms: Vec<usize>,
+}
+
+impl Default for HessianOptions {
+    fn default() -> Self {
+        Self {
+            step: 0.015,
+            atoms: vec![],
+        }
+    }
+}
+
+/// The (partial) Hessian over displaced atoms
+#[derive(Debug, Clone)]
+pub struct Hessian {
+    /// The displaced atoms (1-based)
+    pub atoms: Vec<usize>,
+    /// The second derivatives of energy in eV/Å^2, with rows and columns
+    /// for the x, y, z of each displaced atom.
+    pub matrix: Vec<Vec<f64>>,
+    /// The reference energy in eV
+    pub energy: f64,
+}
+
+/// Compute forces of `mol` using `engine`.
+async fn compute_forces(engine: &mut ForceEngine, mol: &Molecule) -> Result<(f64, Vec<[f64; 3]>)> {
+    let props = engine.compute(mol).await?;
+    let energy = props.mp.get_energy().context("no energy")?;
+    let forces = props.mp.get_forces().context("no forces")?.to_vec();
+    ensure!(forces.len() == mol.natoms(), "invalid number of forces: {}", forces.len());
+    Ok((energy, forces))
+}
+
+/// Compute Hessian of `mol` by central finite differences of forces, which
+/// are computed by displacing atoms in `engine` step by step. With a
+/// persistent interactive VASP session, each displacement restarts from
+/// the converged wave functions of the previous one.
+pub async fn compute_hessian(engine: &mut ForceEngine, mol: &Molecule, opts: &HessianOptions) -> Result<Hessian> {
+    let natoms = mol.natoms();
+    let atoms: Vec<usize> = if opts.atoms.is_empty() {
+        (1..=natoms).collect()
+    } else {
+        opts.atoms.clone()
+    };
+    for &i in &atoms {
+        ensure!(i >= 1 && i <= natoms, "atom {} out of range 1-{}", i, natoms);
+    }
+    let h = opts.step;
+    ensure!(h > 0.0, "invalid displacement step: {}", h);
+
+    info!("reference calculation ...");
+    let (energy, _) = compute_forces(engine, mol).await?;
+
+    let n = atoms.len() * 3;
+    let mut matrix = vec![vec![0.0; n]; n];
+    let positions: Vec<[f64; 3]> = mol.positions().collect();
+    for (p, (&i, k)) in atoms.iter().flat_map(|i| std::iter::repeat(i).zip(0..3)).enumerate() {
+        info!("displacement {}/{}: atom {} along {}", p + 1, n, i, ["x", "y", "z"][k]);
+        let mut forces = vec![];
+        for sign in [1.0, -1.0] {
+            let mut mol_disp = mol.clone();
+            let mut positions = positions.clone();
+            positions[i - 1][k] += sign * h;
+            mol_disp.set_positions(positions);
+            forces.push(compute_forces(engine, &mol_disp).await?.1);
+        }
+        for (q, (&j, l)) in atoms.iter().flat_map(|j| std::iter::repeat(j).zip(0..3)).enumerate() {
+            matrix[p][q] = -(forces[0][j - 1][l] - forces[1][j - 1][l]) / (2.0 * h);
+        }
+    }
+    // symmetrize
+    for p in 0..n {
+        for q in 0..p {
+            let x = 0.5 * (matrix[p][q] + matrix[q][p]);
+            matrix[p][q] = x;
+            matrix[q][p] = x;
+        }
+    }
+
+    Ok(Hessian { atoms, matrix, energy })
+}
+
+/// Diagonalize symmetric matrix `a` using cyclic Jacobi rotations. Return
+/// eigenvalues and eigenvectors (as columns) in ascending order.
+fn jacobi_eigen(mut a: Vec<Vec<f64>>) -> (Vec<f64>, Vec<Vec<f64>>) {
+    let n = a.len();
+    let mut v: Vec<Vec<f64>> = (0..n).map(|i| (0..n).map(|j| if i == j { 1.0 } else { 0.0 }).collect()).collect();
+    for _ in 0..100 {
+        let off: f64 = (0..n).flat_map(|p| (0..n).filter(move |&q| q != p).map(move |q| (p, q))).map(|(p, q)| a[p][q].powi(2)).sum();
+        if off < 1e-22 {
+            break;
+        }
+        for p in 0..n {
+            for q in p + 1..n {
+                if a[p][q].abs() < 1e-300 {
+                    continue;
+                }
+                let theta = (a[q][q] - a[p][p]) / (2.0 * a[p][q]);
+                let t = theta.signum() / (theta.abs() + (theta * theta + 1.0).sqrt());
+                let c = 1.0 / (t * t + 1.0).sqrt();
+                let s = t * c;
+                for k in 0..n {
+                    let (akp, akq) = (a[k][p], a[k][q]);
+                    a[k][p] = c * akp - s * akq;
+                    a[k][q] = s * akp + c * akq;
+                }
+                for k in 0..n {
+                    let (apk, aqk) = (a[p][k], a[q][k]);
+                    a[p][k] = c * apk - s * aqk;
+                    a[q][k] = s * apk + c * aqk;
+                }
+                for k in 0..n {
+                    let (vkp, vkq) = (v[k][p], v[k][q]);
+                    v[k][p] = c * vkp - s * vkq;
+                    v[k][q] = s * vkp + c * vkq;
+                }
+            }
+        }
+    }
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_by(|&i, &j| a[i][i].total_cmp(&a[j][j]));
+    let values = order.iter().map(|&i| a[i][i]).collect();
+    let vectors = (0..n).map(|r| order.iter().map(|&i| v[r][i]).collect()).collect();
+    (values, vectors)
+}
+
+impl Hessian {
+    /// Compute normal modes of `mol` from mass-weighted Hessian. Modes are
+    /// sorted by frequency in descending order as in OUTCAR, with imaginary
+    /// ones (negative) at last. The displacements of atoms not in Hessian
+    /// are zeros.
+    pub fn normal_modes(&self, mol: &Molecule) -> Result<Vec<VibMode>> {
+        let numbers: Vec<usize> = mol.atoms().map(|(_, a)| a.number()).collect();
+        let masses: Vec<f64> = self
+            .atoms
+            .iter()
+            .map(|&i| atomic_mass(numbers[i - 1]))
+            .collect::<Result<_>>()?;
+        let n = self.matrix.len();
+        let m = |p: usize| masses[p / 3];
+        let mw: Vec<Vec<f64>> = (0..n)
+            .map(|p| (0..n).map(|q| self.matrix[p][q] / (m(p) * m(q)).sqrt()).collect())
+            .collect();
+        let (values, vectors) = jacobi_eigen(mw);
+
+        let positions: Vec<[f64; 3]> = mol.positions().collect();
+        let mut modes = vec![];
+        for (k, &lambda) in values.iter().enumerate().rev() {
+            let frequency = lambda.signum() * FREQ_CM1_PER_SQRT_EV_A2_AMU * lambda.abs().sqrt();
+            // back to Cartesian displacements
+            let mut displacements = vec![[0.0; 3]; positions.len()];
+            for p in 0..n {
+                displacements[self.atoms[p / 3] - 1][p % 3] = vectors[p][k] / m(p).sqrt();
+            }
+            let norm = displacements.iter().flatten().map(|x| x * x).sum::<f64>().sqrt();
+            displacements.iter_mut().flatten().for_each(|x| *x /= norm);
+            modes.push(VibMode {
+                index: modes.len() + 1,
+                frequency,
+                energy: frequency * CM1_IN_MEV,
+                positions: positions.clone(),
+                displacements,
+            });
+        }
+        Ok(modes)
+    }
+
+    /// Format Hessian matrix in eV/Å^2 as plain text, one row per line.
+    pub fn format_matrix(&self) -> String {
+        let mut txt = String::new();
+        for row in &self.matrix {
+            let line: Vec<_> = row.iter().map(|x| format!("{:14.6}", x)).collect();
+            txt += &line.join(" ");
+            txt += "\n";
+        }
+        txt
+    }
+}
+
+/// Compute Hessian and normal modes of `mol` using `engine`, print the
+/// modes, and write Hessian matrix into `output` if not None. The VASP
+/// engine will be terminated at the end.
+pub async fn run_hessian(
+    mut engine: ForceEngine,
+    mol: &Molecule,
+    opts: &HessianOptions,
+    output: Option<&Path>,
+) -> Result<Vec<VibMode>> {
+    let hessian = compute_hessian(&mut engine, mol, opts).await;
+    if let ForceEngine::Vasp(vasp) = &engine {
+        vasp.terminate().await?;
+    }
+    let hessian = hessian?;
+    info!("reference energy: {} eV", hessian.energy);
+    if let Some(f) = output {
+        gut::fs::write_to_file(f, &hessian.format_matrix())?;
+        println!("Hessian matrix (eV/Å^2) written into {:?}", f);
+    }
+    let modes = hessian.normal_modes(mol)?;
+    let modes_ref: Vec<_> = modes.iter().collect();
+    print!("{}", crate::vasp::format_vib_modes(&modes_ref));
+    Ok(modes)
+}
+// c16b1cbf ends here
+
+// [[file:../vasp-tools.note::367eb8ff][367eb8ff]]
+fn default_fd_step() -> f64 {
+    HessianOptions::default().step
+}
+
+fn default_read_pattern() -> String {
+    crate::vasp::stdout::VASP_READ_PATTERN.into()
+}
+
+/// The request of finite-difference gradients evaluated on server side,
+/// over the interactive session of VASP.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct FiniteDiffRequest {
+    /// The scaled positions of reference structure, one atom per line
+    pub positions: String,
+    /// The atoms (1-based) to be displaced. All atoms are displaced if
+    /// empty.
+    #[serde(default)]
+    pub atoms: Vec<usize>,
+    /// The displacement step in Å
+    #[serde(default = "default_fd_step")]
+    pub step: f64,
+    /// Also compute derivatives of dipole moment (LDIPOL or IDIPOL is
+    /// required)
+    #[serde(default)]
+    pub dipole: bool,
+    /// The regex for reading VASP stdout
+    #[serde(default = "default_read_pattern")]
+    pub read_pattern: String,
+}
+
+/// The computed results of one structure in finite differences
+#[derive(Debug, Clone)]
+pub struct FiniteDiffPoint {
+    pub energy: f64,
+    pub forces: Vec<[f64; 3]>,
+    pub dipole: Option<[f64; 3]>,
+}
+
+/// The finite-difference gradients computed on server side
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct FiniteDiffResult {
+    /// The displaced atoms (1-based)
+    pub atoms: Vec<usize>,
+    /// The energy of reference structure in eV
+    pub energy: f64,
+    /// The rows of Hessian in eV/Å^2 for x, y, z of each displaced atom,
+    /// over the Cartesian coordinates of all atoms
+    pub hessian_rows: Vec<Vec<f64>>,
+    /// The derivatives of dipole moment in e for x, y, z of each displaced
+    /// atom
+    pub dipole_derivatives: Option<Vec<[f64; 3]>>,
+}
+
+/// Return the inverse of 3x3 matrix `m`.
+fn inverse3(m: &[[f64; 3]; 3]) -> Result<[[f64; 3]; 3]> {
+    let det = m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1]) - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+        + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0]);
+    ensure!(det.abs() > 1e-12, "singular lattice: {:?}", m);
+    let mut inv = [[0.0; 3]; 3];
+    for (i, row) in inv.iter_mut().enumerate() {
+        for (j, x) in row.iter_mut().enumerate() {
+            // the cofactor of m[j][i]
+            let (r1, r2) = ((j + 1) % 3, (j + 2) % 3);
+            let (c1, c2) = ((i + 1) % 3, (i + 2) % 3);
+            *x = (m[r1][c1] * m[r2][c2] - m[r1][c2] * m[r2][c1]) / det;
+        }
+    }
+    Ok(inv)
+}
+
+impl FiniteDiffRequest {
+    /// Return the atoms to be displaced in structure of `natoms` atoms.
+    pub fn displaced_atoms(&self, natoms: usize) -> Result<Vec<usize>> {
+        ensure!(self.step > 0.0, "invalid displacement step: {}", self.step);
+        if self.atoms.is_empty() {
+            return Ok((1..=natoms).collect());
+        }
+        for &i in &self.atoms {
+            ensure!(i >= 1 && i <= natoms, "atom {} out of range 1-{}", i, natoms);
+        }
+        Ok(self.atoms.clone())
+    }
+
+    /// Return scaled positions of structures displaced by `step` in cell
+    /// with `lattice` vectors: the plus and minus displacements along x, y
+    /// and z of each atom in `atoms`.
+    pub fn displaced_positions(&self, lattice: &[[f64; 3]; 3], atoms: &[usize]) -> Result<Vec<String>> {
+        let fracs = crate::vasp::guard::parse_scaled_positions(&self.positions)?;
+        // NOTE: Cartesian = fractional x lattice, so a Cartesian step along
+        // k moves fractional coordinates by the k-th row of its inverse
+        let inv = inverse3(lattice)?;
+        let mut inputs = vec![];
+        for &i in atoms {
+            for row in inv.iter() {
+                for sign in [1.0, -1.0] {
+                    let mut fracs = fracs.clone();
+                    for (x, d) in fracs[i - 1].iter_mut().zip(row) {
+                        *x += sign * self.step * d;
+                    }
+                    let txt: String = fracs
+                        .iter()
+                        .map(|[x, y, z]| format!("{:19.16} {:19.16} {:19.16}\n", x, y, z))
+                        .collect();
+                    inputs.push(txt);
+                }
+            }
+        }
+        Ok(inputs)
+    }
+}
+
+/// Assemble finite-difference gradients from computed `reference` and
+/// `displaced` structures, in the order of `displaced_positions`.
+pub fn assemble_finite_diff(
+    atoms: Vec<usize>,
+    step: f64,
+    reference: &FiniteDiffPoint,
+    displaced: &[FiniteDiffPoint],
+) -> Result<FiniteDiffResult> {
+    ensure!(
+        displaced.len() == atoms.len() * 6,
+        "expect {} displaced structures, got {}",
+        atoms.len() * 6,
+        displaced.len()
+    );
+    let natoms = reference.forces.len();
+    let mut hessian_rows = vec![];
+    let mut dipole_derivatives = reference.dipole.map(|_| vec![]);
+    for pair in displaced.chunks(2) {
+        let (plus, minus) = (&pair[0], &pair[1]);
+        ensure!(
+            plus.forces.len() == natoms && minus.forces.len() == natoms,
+            "inconsistent number of forces in displaced structures"
+        );
+        let row = plus
+            .forces
+            .iter()
+            .flatten()
+            .zip(minus.forces.iter().flatten())
+            .map(|(fp, fm)| -(fp - fm) / (2.0 * step))
+            .collect();
+        hessian_rows.push(row);
+        if let Some(derivatives) = dipole_derivatives.as_mut() {
+            let (dp, dm) = (plus.dipole.context("no dipole")?, minus.dipole.context("no dipole")?);
+            derivatives.push(std::array::from_fn(|k| (dp[k] - dm[k]) / (2.0 * step)));
+        }
+    }
+    Ok(FiniteDiffResult {
+        atoms,
+        energy: reference.energy,
+        hessian_rows,
+        dipole_derivatives,
+    })
+}
+// 367eb8ff ends here
+
+// [[file:../vasp-tools.note::5e1e2195][5e1e2195]]
+#[test]
+fn test_jacobi_eigen() {
+    let a = vec![vec![2.0, 1.0], vec![1.0, 2.0]];
+    let (values, vectors) = jacobi_eigen(a);
+    assert_relative_eq!(values[0], 1.0, epsilon = 1e-10);
+    assert_relative_eq!(values[1], 3.0, epsilon = 1e-10);
+    assert_relative_eq!(vectors[0][1].abs(), 0.5f64.sqrt(), epsilon = 1e-10);
+    assert_relative_eq!(vectors[0][1], vectors[1][1], epsilon = 1e-10);
+}
+
+#[tokio::test]
+async fn test_hessian_diatomic() -> Result<()> {
+    use crate::potential::Potential;
+    use gosh::gchemol::Atom;
+
+    // H2 on harmonic potential at equilibrium distance
+    let (k, r0) = (1.0, 1.5);
+    let atoms = vec![Atom::new("H", [0.0, 0.0, 0.0]), Atom::new("H", [r0, 0.0, 0.0])];
+    let mol = Molecule::from_atoms(atoms);
+    let mut engine = ForceEngine::Potential(Potential::Harmonic { k, r0 });
+
+    let hessian = compute_hessian(&mut engine, &mol, &HessianOptions::default()).await?;
+    assert_eq!(hessian.matrix.len(), 6);
+    assert_relative_eq!(hessian.matrix[0][0], k, epsilon = 1e-6);
+    assert_relative_eq!(hessian.matrix[0][3], -k, epsilon = 1e-6);
+
+    let modes = hessian.normal_modes(&mol)?;
+    assert_eq!(modes.len(), 6);
+    // stretching mode with reduced mass of m/2
+    let expected = FREQ_CM1_PER_SQRT_EV_A2_AMU * (k / (1.008 / 2.0)).sqrt();
+    assert_relative_eq!(modes[0].frequency, expected, epsilon = 1e-3);
+    assert_relative_eq!(modes[0].displacements[0][0], -modes[0].displacements[1][0], epsilon = 1e-8);
+    // rotations are not exactly zero due to finite displacements
+    assert!(modes[1].frequency.abs() < 10.0);
+
+    // partial Hessian over the second atom only
+    let opts = HessianOptions {
+        atoms: vec![2],
+        ..Default::default()
+    };
+    let hessian = compute_hessian(&mut engine, &mol, &opts).await?;
+    assert_eq!(hessian.matrix.len(), 3);
+    let modes = hessian.normal_modes(&mol)?;
+    assert_eq!(modes[0].displacements[0], [0.0; 3]);
+    let expected = FREQ_CM1_PER_SQRT_EV_A2_AMU * (k / 1.008).sqrt();
+    assert_relative_eq!(modes[0].frequency, expected, epsilon = 1e-3);
+
+    Ok(())
+}
+
+#[test]
+fn test_finite_diff_request() -> Result<()> {
+    let req = FiniteDiffRequest {
+        positions: "0.0 0.0 0.0\n0.15 0.0 0.0\n".into(),
+        atoms: vec![2],
+        step: 0.01,
+        dipole: true,
+        read_pattern: default_read_pattern(),
+    };
+    assert!(req.displaced_atoms(1).is_err());
+    let atoms = req.displaced_atoms(2)?;
+    let lattice = [[10.0, 0.0, 0.0], [0.0, 10.0, 0.0], [0.0, 0.0, 20.0]];
+    let inputs = req.displaced_positions(&lattice, &atoms)?;
+    assert_eq!(inputs.len(), 6);
+    let fracs = crate::vasp::guard::parse_scaled_positions(&inputs[5])?;
+    assert_relative_eq!(fracs[1][2], -0.0005, epsilon = 1e-12);
+    assert_relative_eq!(fracs[1][0], 0.15, epsilon = 1e-12);
+
+    // harmonic bond along x between two atoms 1.5 Å apart
+    let k = 2.0;
+    let point = |dx: [f64; 3]| {
+        let f = -k * dx[0];
+        FiniteDiffPoint {
+            energy: 0.5 * k * dx[0] * dx[0],
+            forces: vec![[-f, 0.0, 0.0], [f, 0.0, 0.0]],
+            dipole: Some([0.5 * dx[0], 0.0, 0.0]),
+        }
+    };
+    let displaced: Vec<_> = (0..3)
+        .flat_map(|k| [1.0, -1.0].map(|s| point(std::array::from_fn(|i| if i == k { s * 0.01 } else { 0.0 }))))
+        .collect();
+    let result = assemble_finite_diff(atoms, 0.01, &point([0.0; 3]), &displaced)?;
+    assert_eq!(result.hessian_rows.len(), 3);
+    assert_relative_eq!(result.hessian_rows[0][3], k, epsilon = 1e-10);
+    assert_relative_eq!(result.hessian_rows[0][0], -k, epsilon = 1e-10);
+    let derivatives = result.dipole_derivatives.unwrap();
+    assert_relative_eq!(derivatives[0][0], 0.5, epsilon = 1e-10);
+    assert!(assemble_finite_diff(vec![1, 2], 0.01, &point([0.0; 3]), &displaced).is_err());
+
+    Ok(())
+}
+// 5e1e2195 ends here
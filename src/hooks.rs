@@ -0,0 +1,412 @@
+// [[file:../vasp-tools.note::*docs][docs:1]]
+//! Notification hooks (shell command or webhook) on completion and failure
+//! of VASP runs, and evaluation hooks streaming each interactive evaluation
+//! to external programs
+// docs:1 ends here
+
+// [[file:../vasp-tools.note::3b8e0d6a][3b8e0d6a]]
+use super::*;
+
+use serde::{Deserialize, Serialize};
+// 3b8e0d6a ends here
+
+// [[file:../vasp-tools.note::c41f92e7][c41f92e7]]
+/// The event triggering notification hooks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HookEvent {
+    /// VASP finished with converged results
+    Converged,
+    /// VASP finished without convergence, or interactive session ended
+    Finished,
+    /// VASP or the interactive server exited unexpectedly
+    Crashed,
+    /// The walltime of the job is nearly exhausted
+    Walltime,
+    /// Spin flip or oscillating magnetic moments found during the run
+    Magnetization,
+}
+
+impl HookEvent {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Converged => "converged",
+            Self::Finished => "finished",
+            Self::Crashed => "crashed",
+            Self::Walltime => "walltime",
+            Self::Magnetization => "magnetization",
+        }
+    }
+}
+
+/// The summary of VASP run passed to hooks
+#[derive(Debug, Clone, Serialize)]
+pub struct RunSummary {
+    pub event: HookEvent,
+    /// The working directory of VASP run
+    pub directory: PathBuf,
+    pub hostname: String,
+    /// The convergence verdict or error message
+    pub message: String,
+    /// The number of ionic steps
+    pub nsteps: usize,
+    /// The energy of last ionic step in eV
+    pub energy: Option<f64>,
+    /// The max force of last ionic step in eV/Å
+    pub fmax: Option<f64>,
+    /// The elapsed time of the job in seconds
+    pub elapsed: f64,
+    /// The time when the event happened in RFC 3339 format
+    pub timestamp: String,
+}
+
+impl RunSummary {
+    /// Collect summary of VASP run in `dir`. If `failure` is set, the event
+    /// will be `Crashed` with it as message, otherwise the event is decided
+    /// by the convergence in OUTCAR.
+    pub fn collect(dir: &Path, failure: Option<String>, elapsed: f64) -> Self {
+        let conv = crate::vasp::report::check_convergence(dir).ok();
+        let (event, message) = match (failure, &conv) {
+            (Some(msg), _) => (HookEvent::Crashed, msg),
+            (None, Some(c)) if c.converged => (HookEvent::Converged, c.verdict.clone()),
+            (None, Some(c)) => (HookEvent::Finished, c.verdict.clone()),
+            (None, None) => (HookEvent::Finished, "no OUTCAR found".into()),
+        };
+        Self {
+            event,
+            directory: dir.canonicalize().unwrap_or_else(|_| dir.to_owned()),
+            hostname: crate::vasp::provenance::hostname(),
+            message,
+            nsteps: conv.as_ref().map_or(0, |c| c.nsteps),
+            energy: conv.as_ref().and_then(|c| c.energy),
+            fmax: conv.as_ref().and_then(|c| c.fmax),
+            elapsed,
+            timestamp: chrono::Local::now().to_rfc3339(),
+        }
+    }
+}
+
+/// Render payload `template` by replacing placeholders like `{{event}}` or
+/// `{{energy}}` with fields of `summary`. Missing values are rendered as
+/// empty string.
+fn render_template(template: &str, summary: &RunSummary) -> Result<String> {
+    let value = serde_json::to_value(summary)?;
+    let fields = value.as_object().context("summary is not an object")?;
+    let mut s = template.to_owned();
+    for (k, v) in fields {
+        let v = match v {
+            serde_json::Value::String(x) => x.to_owned(),
+            serde_json::Value::Null => String::new(),
+            x => x.to_string(),
+        };
+        s = s.replace(&format!("{{{{{}}}}}", k), &v);
+    }
+    Ok(s)
+}
+
+/// Parse walltime in seconds from "HH:MM:SS", "MM:SS" or plain seconds.
+pub fn parse_walltime(s: &str) -> Result<f64> {
+    let mut t = 0.0;
+    for part in s.trim().split(':') {
+        let x: f64 = part.parse().with_context(|| format!("invalid walltime: {:?}", s))?;
+        t = t * 60.0 + x;
+    }
+    Ok(t)
+}
+
+/// Hooks triggered on completion and failure of VASP runs
+#[derive(Debug, Clone, Default)]
+pub struct Hooks {
+    /// The shell command to run with the payload in its stdin. The event
+    /// and directory are also passed in env vars `VASP_HOOK_EVENT` and
+    /// `VASP_HOOK_DIR`.
+    pub command: Option<String>,
+    /// The URL for posting the payload (using curl)
+    pub webhook: Option<String>,
+    /// The template of payload. The default is the summary in JSON.
+    pub template: Option<String>,
+}
+
+impl Hooks {
+    pub fn is_empty(&self) -> bool {
+        self.command.is_none() && self.webhook.is_none()
+    }
+
+    /// Return the payload for `summary`.
+    pub fn payload(&self, summary: &RunSummary) -> Result<String> {
+        match &self.template {
+            Some(t) => render_template(t, summary),
+            None => Ok(serde_json::to_string_pretty(summary)?),
+        }
+    }
+
+    fn try_notify(&self, summary: &RunSummary) -> Result<()> {
+        let payload = self.payload(summary)?;
+        if let Some(cmd) = &self.command {
+            debug!("run hook command: {:?}", cmd);
+            let out = duct::cmd!("sh", "-c", cmd)
+                .env("VASP_HOOK_EVENT", summary.event.name())
+                .env("VASP_HOOK_DIR", &summary.directory)
+                .stdin_bytes(payload.as_bytes())
+                .unchecked()
+                .run()
+                .with_context(|| format!("run hook command {:?}", cmd))?;
+            ensure!(out.status.success(), "hook command {:?} failed: {}", cmd, out.status);
+        }
+        if let Some(url) = &self.webhook {
+            debug!("post payload to webhook: {:?}", url);
+            let content_type = if self.template.is_none() {
+                "Content-Type: application/json"
+            } else {
+                "Content-Type: text/plain"
+            };
+            let out = duct::cmd!("curl", "-sS", "-m", "30", "-X", "POST", "-H", content_type, "--data-binary", "@-", url)
+                .stdin_bytes(payload.as_bytes())
+                .stdout_null()
+                .unchecked()
+                .run()
+                .context("run curl for webhook")?;
+            ensure!(out.status.success(), "post to webhook {:?} failed: {}", url, out.status);
+        }
+        Ok(())
+    }
+
+    /// Trigger hooks with `summary`. Failures of hooks are logged but not
+    /// fatal.
+    pub fn notify(&self, summary: &RunSummary) {
+        if self.is_empty() {
+            return;
+        }
+        info!("trigger hooks on {} event", summary.event.name());
+        if let Err(e) = self.try_notify(summary) {
+            warn!("notification hook failed: {:?}", e);
+        }
+    }
+
+    /// Trigger hooks with `Walltime` event in background when only `margin`
+    /// seconds left for `walltime` in seconds since now.
+    pub fn watch_walltime(&self, walltime: f64, margin: f64) {
+        if self.is_empty() {
+            return;
+        }
+        let hooks = self.clone();
+        let t0 = std::time::Instant::now();
+        let delay = (walltime - margin).max(0.0);
+        std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_secs_f64(delay));
+            let elapsed = t0.elapsed().as_secs_f64();
+            let mut summary = RunSummary::collect(".".as_ref(), None, elapsed);
+            summary.event = HookEvent::Walltime;
+            summary.message = format!("walltime nearly exhausted: {:.0} seconds left", walltime - elapsed);
+            hooks.notify(&summary);
+        });
+    }
+}
+// c41f92e7 ends here
+
+// [[file:../vasp-tools.note::9d27f5b1][9d27f5b1]]
+#[test]
+fn test_hooks() -> Result<()> {
+    assert_eq!(parse_walltime("1:30:00")?, 5400.0);
+    assert_eq!(parse_walltime("90")?, 90.0);
+    assert!(parse_walltime("1h").is_err());
+
+    let tdir = tempfile::tempdir()?;
+    let summary = RunSummary::collect(tdir.path(), Some("VASP exited unexpectedly".into()), 10.0);
+    assert_eq!(summary.event, HookEvent::Crashed);
+    assert_eq!(summary.energy, None);
+
+    let mut hooks = Hooks {
+        template: Some("{{event}} in {{directory}}: {{message}} (E = {{energy}})".into()),
+        ..Default::default()
+    };
+    let s = hooks.payload(&summary)?;
+    assert!(s.starts_with("crashed in /"));
+    assert!(s.ends_with(": VASP exited unexpectedly (E = )"));
+
+    let out = tdir.path().join("hook.out");
+    hooks.command = Some(format!("cat > {:?}; echo $VASP_HOOK_EVENT >> {:?}", out, out));
+    hooks.try_notify(&summary)?;
+    let s = gut::fs::read_file(&out)?;
+    assert!(s.ends_with(")crashed\n"));
+
+    Ok(())
+}
+// 9d27f5b1 ends here
+
+// [[file:../vasp-tools.note::1f7fb16b][1f7fb16b]]
+/// The geometry and computed results of one interactive evaluation, sent to
+/// evaluation hook as one line of JSON
+#[derive(Debug, Clone, Serialize)]
+pub struct EvaluationRecord {
+    /// The working directory of VASP server
+    pub directory: PathBuf,
+    /// The time of evaluation in RFC 3339 format
+    pub timestamp: String,
+    pub symbols: Vec<String>,
+    /// The lattice vectors in Å
+    pub cell: Option<[[f64; 3]; 3]>,
+    /// Cartesian positions in Å
+    pub positions: Vec<[f64; 3]>,
+    /// The energy in eV
+    pub energy: f64,
+    /// The forces in eV/Å
+    pub forces: Vec<[f64; 3]>,
+    /// The stress tensor in kB (XX, YY, ZZ, XY, YZ, ZX) as in OUTCAR
+    pub stress: Option<[f64; 6]>,
+}
+
+impl EvaluationRecord {
+    /// Create record for structure `mol` with computed `props`.
+    pub fn new(mol: &gosh::gchemol::Molecule, props: &crate::bbm::Properties) -> Result<Self> {
+        use gosh::gchemol::prelude::*;
+
+        let dir: &Path = ".".as_ref();
+        let record = Self {
+            directory: dir.canonicalize().unwrap_or_else(|_| dir.to_owned()),
+            timestamp: chrono::Local::now().to_rfc3339(),
+            symbols: mol.symbols().map(|x| x.to_owned()).collect(),
+            cell: mol.get_lattice().map(|lat| lat.vectors()),
+            positions: mol.positions().collect(),
+            energy: props.mp.get_energy().context("no energy")?,
+            forces: props.mp.get_forces().context("no forces")?.clone(),
+            stress: props.stress,
+        };
+        Ok(record)
+    }
+}
+
+/// The optional reply of evaluation hook in JSON, e.g. corrections from a
+/// machine learning model for delta-learning
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct EvaluationFeedback {
+    /// The correction added to computed energy in eV
+    pub energy: Option<f64>,
+    /// The corrections added to computed forces in eV/Å
+    pub forces: Option<Vec<[f64; 3]>>,
+    /// The message for logging, e.g. the uncertainty of ML committee
+    pub message: Option<String>,
+}
+
+impl EvaluationFeedback {
+    /// Add corrections into computed `props`.
+    pub fn apply(&self, props: &mut crate::bbm::Properties) -> Result<()> {
+        if let Some(msg) = &self.message {
+            info!("evaluation hook: {}", msg);
+        }
+        if let Some(de) = self.energy {
+            let energy = props.mp.get_energy().context("no energy")?;
+            props.mp.set_energy(energy + de);
+        }
+        if let Some(df) = &self.forces {
+            let mut forces = props.mp.get_forces().context("no forces")?.clone();
+            ensure!(
+                df.len() == forces.len(),
+                "expect force corrections of {} atoms, got {}",
+                forces.len(),
+                df.len()
+            );
+            for (f, d) in forces.iter_mut().zip(df) {
+                (0..3).for_each(|k| f[k] += d[k]);
+            }
+            props.mp.set_forces(forces);
+        }
+        Ok(())
+    }
+}
+
+/// The hook receiving each interactive evaluation as one line of JSON, for
+/// active learning or delta-learning around the VASP server. The reply is
+/// parsed as `EvaluationFeedback` if not empty.
+#[derive(Debug, Clone)]
+pub enum EvaluationHook {
+    /// The shell command reading the record from stdin and writing reply
+    /// into stdout
+    Command(String),
+    /// The unix socket for sending the record and reading one line of reply
+    Socket(PathBuf),
+}
+
+impl EvaluationHook {
+    fn exchange(&self, line: &str) -> Result<String> {
+        match self {
+            Self::Command(cmd) => {
+                debug!("run evaluation hook command: {:?}", cmd);
+                let out = duct::cmd!("sh", "-c", cmd)
+                    .stdin_bytes(line.as_bytes())
+                    .stdout_capture()
+                    .unchecked()
+                    .run()
+                    .with_context(|| format!("run evaluation hook command {:?}", cmd))?;
+                ensure!(out.status.success(), "evaluation hook command {:?} failed: {}", cmd, out.status);
+                Ok(String::from_utf8_lossy(&out.stdout).into_owned())
+            }
+            Self::Socket(f) => {
+                use std::io::{BufRead, Write};
+
+                debug!("send evaluation record to socket {:?}", f);
+                let mut stream = std::os::unix::net::UnixStream::connect(f)
+                    .with_context(|| format!("connect to evaluation hook socket {:?}", f))?;
+                stream.write_all(line.as_bytes())?;
+                stream.flush()?;
+                let mut reply = String::new();
+                std::io::BufReader::new(stream).read_line(&mut reply)?;
+                Ok(reply)
+            }
+        }
+    }
+
+    /// Send `record` to the hook and return its feedback. Failures of the
+    /// hook are fatal, as the corrections may be required.
+    pub fn call(&self, record: &EvaluationRecord) -> Result<EvaluationFeedback> {
+        let line = serde_json::to_string(record)? + "\n";
+        let reply = self.exchange(&line)?;
+        if reply.trim().is_empty() {
+            Ok(EvaluationFeedback::default())
+        } else {
+            serde_json::from_str(reply.trim()).with_context(|| format!("invalid reply of evaluation hook: {:?}", reply))
+        }
+    }
+}
+// 1f7fb16b ends here
+
+// [[file:../vasp-tools.note::3cfd648e][3cfd648e]]
+#[test]
+fn test_evaluation_hook() -> Result<()> {
+    use gosh::model::ModelProperties;
+
+    let mol = gosh::gchemol::Molecule::from_atoms(vec![
+        gosh::gchemol::Atom::new("H", [0.0; 3]),
+        gosh::gchemol::Atom::new("H", [0.74, 0.0, 0.0]),
+    ]);
+    let mut mp = ModelProperties::default();
+    mp.set_energy(-6.5);
+    mp.set_forces(vec![[0.1, 0.0, 0.0], [-0.1, 0.0, 0.0]]);
+    let mut props: crate::bbm::Properties = mp.into();
+    let record = EvaluationRecord::new(&mol, &props)?;
+    assert_eq!(record.symbols, vec!["H", "H"]);
+    assert_eq!(record.cell, None);
+
+    let tdir = tempfile::tempdir()?;
+    let out = tdir.path().join("record.json");
+    let cmd = format!(
+        r#"cat > {:?}; echo '{{"energy": 0.5, "forces": [[0.1, 0, 0], [0, 0.2, 0]], "message": "std = 0.01"}}'"#,
+        out
+    );
+    let feedback = EvaluationHook::Command(cmd).call(&record)?;
+    let s = gut::fs::read_file(&out)?;
+    assert!(s.ends_with("}\n"));
+    assert!(s.contains(r#""energy":-6.5"#));
+    feedback.apply(&mut props)?;
+    assert_eq!(props.mp.get_energy(), Some(-6.0));
+    assert_eq!(props.mp.get_forces().unwrap()[1], [-0.1, 0.2, 0.0]);
+
+    // no reply
+    let feedback = EvaluationHook::Command("cat > /dev/null".into()).call(&record)?;
+    assert!(feedback.energy.is_none());
+    assert!(EvaluationHook::Command("echo invalid".into()).call(&record).is_err());
+
+    Ok(())
+}
+// 3cfd648e ends here
@@ -4,11 +4,14 @@
 
 // [[file:../vasp-tools.note::0bd38257][0bd38257]]
 use super::*;
-use crate::session::{Session, SessionHandler};
+use crate::process::{ProcessControl, ResourceLimits};
+use crate::session::{ChildExited, Session, SessionHandler};
+use crate::vasp::restart::ReuseOptions;
 
 use std::process::Command;
 use std::sync::Arc;
 use tokio::sync::Notify;
+use tracing::{debug, error, info, trace, warn};
 // 0bd38257 ends here
 
 // [[file:../vasp-tools.note::*base][base:1]]
@@ -16,14 +19,21 @@ use tokio::sync::Notify;
 struct Interaction(String, String);
 
 /// The message sent from client for controlling child process
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 enum Control {
     Quit,
     Pause,
     Resume,
+    Interrupt,
+    /// Stop child process gracefully using STOPCAR, which will be spawned
+    /// again on next interaction. Notify the sender when stopped.
+    Restart(tokio::sync::oneshot::Sender<()>),
+    /// Stop child process gracefully using STOPCAR, and stop serving
+    Cancel,
 }
 
-type InteractionOutput = String;
+// NOTE: child process exited before read pattern found is reported to client
+type InteractionOutput = std::result::Result<String, ChildExited>;
 type RxInteractionOutput = tokio::sync::watch::Receiver<InteractionOutput>;
 type TxInteractionOutput = tokio::sync::watch::Sender<InteractionOutput>;
 type RxInteraction = tokio::sync::mpsc::Receiver<Interaction>;
@@ -44,6 +54,12 @@ pub struct TaskServer {
     notifier: Arc<Notify>,
     // child process
     session: Option<Session>,
+    // for pausing/resuming child process using cgroup or MPI launcher
+    control: ProcessControl,
+    // respawn child process when exited, with WAVECAR/CHGCAR handled by policy
+    respawn: Option<ReuseOptions>,
+    // the working directory of child process
+    workdir: PathBuf,
 }
 
 mod taskserver {
@@ -53,41 +69,236 @@ mod taskserver {
         /// Run child process in new session, and serve requests for interactions.
         pub async fn run_and_serve(&mut self) -> Result<()> {
             let mut session = self.session.as_mut().context("no running session")?;
-            let rx_int = self.rx_int.take().context("no rx_int")?;
-            let rx_ctl = self.rx_ctl.take().context("no rx_ctl")?;
-            let tx_out = self.tx_out.take().context("no tx_out")?;
-            let notifier = self.notifier.clone();
-            handle_interaction(&mut session, rx_int, tx_out, rx_ctl, notifier).await?;
+            let channels = TaskChannels {
+                rx_int: self.rx_int.take().context("no rx_int")?,
+                rx_ctl: self.rx_ctl.take().context("no rx_ctl")?,
+                tx_out: self.tx_out.take().context("no tx_out")?,
+                notifier: self.notifier.clone(),
+            };
+            let control = &self.control;
+            let respawn = self.respawn.as_ref();
+            let workdir = &self.workdir;
+            handle_interaction(&mut session, control, respawn, workdir, channels).await?;
             Ok(())
         }
+
+        /// Respawn VASP when it exited (crashed or NSW exhausted) instead of
+        /// stopping the server. WAVECAR and CHGCAR are handled following
+        /// `reuse` before respawn.
+        pub fn set_respawn(&mut self, reuse: ReuseOptions) {
+            self.respawn = reuse.into();
+        }
+
+        /// Record interactions with VASP into transcript file `f`.
+        pub fn set_transcript(&mut self, f: &Path) {
+            if let Some(session) = self.session.as_mut() {
+                session.set_transcript(f);
+            }
+        }
+    }
+
+    /// The state of child process stopped, for respawning it
+    #[derive(Default)]
+    struct RespawnState {
+        // the child process exited by itself
+        exited: Option<ChildExited>,
+        // the child process stopped on request
+        restarted: bool,
+    }
+
+    /// Spawn child process if not running, and interact with it. If
+    /// `respawn` is set, the child process exited before (in `state`) will
+    /// be respawned: WAVECAR and CHGCAR in `workdir` are handled by policy,
+    /// and positions in `input` are written into POSCAR, which VASP reads
+    /// on start. The same for child process restarted on request.
+    fn interact_session(
+        session: &mut Session,
+        handler: &mut Option<SessionHandler>,
+        state: &mut RespawnState,
+        respawn: Option<&ReuseOptions>,
+        workdir: &Path,
+        input: &str,
+        read_pattern: &str,
+    ) -> Result<InteractionOutput> {
+        let mut input = input;
+        if handler.is_none() {
+            let respawned = match (state.exited.take(), respawn) {
+                (Some(e), Some(reuse)) => {
+                    info!("respawn VASP: {}", e);
+                    crate::vasp::restart::prepare_reuse(workdir, reuse, e.code != Some(0))?;
+                    true
+                }
+                _ => std::mem::take(&mut state.restarted),
+            };
+            if respawned && !input.is_empty() {
+                let poscar = workdir.join("POSCAR");
+                let txt = gut::fs::read_file(&poscar)?;
+                let txt = crate::vasp::poscar::with_scaled_positions(&txt, input)?;
+                gut::fs::write_to_file(&poscar, &txt)?;
+                input = "";
+            }
+            *handler = session.spawn()?.into();
+        }
+        match session.interact(input, read_pattern) {
+            Ok(out) => Ok(Ok(out)),
+            Err(e) => match e.downcast::<ChildExited>() {
+                Ok(e) => {
+                    *handler = None;
+                    state.exited = Some(e.clone());
+                    // VASP exited normally, e.g. NSW exhausted
+                    if e.code != Some(0) {
+                        save_crash_bundle(session, workdir, &e);
+                    }
+                    Ok(Err(e))
+                }
+                Err(e) => Err(e),
+            },
+        }
+    }
+
+    /// Collect diagnostic files of crashed child process `e` in `workdir`
+    /// for reporting bugs, and print the path.
+    fn save_crash_bundle(session: &Session, workdir: &Path, e: &ChildExited) {
+        use crate::vasp::crash::*;
+
+        let (stdout, stderr) = session.output_tails();
+        let info = CrashInfo {
+            input: session.last_input().to_owned(),
+            stdout,
+            stderr,
+            code: e.code,
+            signal: e.signal,
+        };
+        match write_crash_bundle(workdir, &info) {
+            Ok(dir) => eprintln!("{}; diagnostic bundle saved in {:?}", e, dir),
+            Err(err) => warn!("failed to save crash bundle: {:?}", err),
+        }
+    }
+
+    /// The seconds to wait for VASP exiting after STOPCAR written, before it
+    /// is terminated
+    const STOP_TIMEOUT: f64 = 60.0;
+
+
+    /// Stop child process gracefully using STOPCAR: the last input is fed
+    /// again, and VASP aborts at its first electronic step, writing output
+    /// files as in a normal exit. The child process is terminated if not
+    /// exited in `STOP_TIMEOUT` seconds.
+    async fn stop_gracefully(session: &mut Session, handler: &SessionHandler, control: &ProcessControl, workdir: &Path) {
+        use crate::vasp::stopcar::StopMode;
+
+        let input = session.last_input().to_owned();
+        // paused VASP cannot read STOPCAR
+        if let Err(err) = control.resume(handler) {
+            warn!("resume child process error: {:?}", err);
+        }
+        // NOTE: VASP reads STOPCAR only when computing, so there is nothing
+        // to do if it has not read any positions yet
+        if !input.is_empty() {
+            match crate::vasp::stopcar::write(workdir, StopMode::Hard) {
+                Ok(_) => {
+                    // the timer is cancelled when `stopped` dropped
+                    let (stopped, rx) = std::sync::mpsc::channel::<()>();
+                    let h = handler.clone();
+                    std::thread::spawn(move || {
+                        let timeout = std::time::Duration::from_secs_f64(STOP_TIMEOUT);
+                        if let Err(std::sync::mpsc::RecvTimeoutError::Timeout) = rx.recv_timeout(timeout) {
+                            warn!("child process not stopped in {} seconds, terminate it", STOP_TIMEOUT);
+                            h.terminate().ok();
+                        }
+                    });
+                    // the pattern never matches, read stdout until exited
+                    if let Err(err) = crate::process::run_blocking(|| session.interact(&input, r"\z.")) {
+                        info!("child process stopped: {:#}", err);
+                    }
+                    drop(stopped);
+                }
+                Err(err) => error!("stop child process using STOPCAR failed: {:?}", err),
+            }
+        }
+        if let Err(err) = control.terminate(handler).await {
+            error!("terminate child process error: {:?}", err);
+        }
+        session.stop();
+        if let Err(err) = crate::vasp::stopcar::remove(workdir) {
+            warn!("{:?}", err);
+        }
+    }
+
+    /// The channels for serving requests from `TaskClient`
+    struct TaskChannels {
+        rx_int: RxInteraction,
+        rx_ctl: RxControl,
+        tx_out: TxInteractionOutput,
+        notifier: Arc<Notify>,
     }
 
     /// Interact with child process: write stdin with `input` and read in stdout by
     /// `read_pattern`
     async fn handle_interaction(
         session: &mut Session,
-        mut rx_int: RxInteraction,
-        mut tx_out: TxInteractionOutput,
-        mut rx_ctl: RxControl,
-        notifier: Arc<Notify>,
+        control: &ProcessControl,
+        respawn: Option<&ReuseOptions>,
+        workdir: &Path,
+        channels: TaskChannels,
     ) -> Result<()> {
+        let TaskChannels {
+            mut rx_int,
+            mut rx_ctl,
+            tx_out,
+            notifier,
+        } = channels;
         let mut session_handler = session.get_handler();
+        let mut state = RespawnState::default();
         for i in 0.. {
             tokio::select! {
                 Some(int) = rx_int.recv() => {
-                    if session_handler.is_none() {
-                        session_handler = session.spawn()?.into();
-                    }
-                    assert!(session_handler.is_some());
+                    let _span = tracing::debug_span!("task", task = i).entered();
                     let Interaction(input, read_pattern) = int;
-                    let out = session.interact(&input, &read_pattern)?;
+                    let mut out = interact_session(session, &mut session_handler, &mut state, respawn, workdir, &input, &read_pattern)?;
+                    // VASP exited normally, e.g. NSW exhausted: respawn and
+                    // redo the interaction
+                    if respawn.is_some() && matches!(&out, Err(e) if e.code == Some(0)) {
+                        out = interact_session(session, &mut session_handler, &mut state, respawn, workdir, &input, &read_pattern)?;
+                    }
                     debug!("coffee break for computation ... {:?}", i);
+                    let exited = out.is_err();
                     tx_out.send(out).context("send stdout using tx_out")?;
                     &notifier.notify_waiters();
                     debug!("Computation done: sent client {} the result", i);
+                    if exited && respawn.is_none() {
+                        error!("child process exited unexpectedly.");
+                        break;
+                    } else if exited {
+                        error!("child process exited unexpectedly, which will be respawned on next interaction.");
+                    }
                 }
                 Some(ctl) = rx_ctl.recv() => {
-                    match break_control_session(session_handler.as_ref(), ctl) {
+                    // e.g. for reading updated INCAR
+                    if let Control::Restart(done) = ctl {
+                        // NOTE: stop VASP gracefully, so WAVECAR is written
+                        // for the restart
+                        if let Some(h) = session_handler.take() {
+                            info!("stop child process gracefully for restart");
+                            stop_gracefully(session, &h, control, workdir).await;
+                        }
+                        state.restarted = true;
+                        done.send(()).ok();
+                        continue;
+                    }
+                    if let Control::Cancel = ctl {
+                        if let Some(h) = session_handler.take() {
+                            info!("stop child process gracefully for cancellation");
+                            stop_gracefully(session, &h, control, workdir).await;
+                        }
+                        break;
+                    }
+                    // VASP exited, which will be respawned on next interaction
+                    if session_handler.is_none() && state.exited.is_some() && !matches!(ctl, Control::Quit) {
+                        debug!("ignore {:?}: child process not running", ctl);
+                        continue;
+                    }
+                    match break_control_session(session_handler.as_ref(), control, ctl).await {
                         Ok(false) => {},
                         Ok(true) => break,
                         Err(err) => {error!("control session error: {:?}", err); break;}
@@ -102,14 +313,16 @@ mod taskserver {
         Ok(())
     }
 
-    fn break_control_session(s: Option<&SessionHandler>, ctl: Control) -> Result<bool> {
+    async fn break_control_session(s: Option<&SessionHandler>, control: &ProcessControl, ctl: Control) -> Result<bool> {
         let s = s.as_ref().ok_or(format_err!("control error: session not started!"))?;
 
         match ctl {
-            Control::Pause => s.pause()?,
-            Control::Resume => s.resume()?,
+            Control::Pause => control.pause(s)?,
+            Control::Resume => control.resume(s)?,
+            Control::Interrupt => control.interrupt(s)?,
+            Control::Restart(_) | Control::Cancel => unreachable!("{:?} is handled with session", ctl),
             Control::Quit => {
-                s.terminate()?;
+                control.terminate(s).await?;
                 return Ok(true);
             }
         }
@@ -135,6 +348,9 @@ mod taskclient {
     use super::*;
 
     impl TaskClient {
+        /// Write `input` into stdin of child process, and read its stdout
+        /// until a line matching `read_pattern` (regex). Return
+        /// `ChildExited` error if child process exited before that.
         pub async fn interact(&mut self, input: &str, read_pattern: &str) -> Result<String> {
             self.tx_int.send(Interaction(input.into(), read_pattern.into())).await?;
             let out = self.recv_stdout().await?;
@@ -153,6 +369,34 @@ mod taskclient {
             Ok(())
         }
 
+        /// Relay SIGINT to child process, letting VASP finish the current
+        /// SCF step cleanly.
+        pub async fn interrupt(&self) -> Result<()> {
+            trace!("send interrupt task msg");
+            self.tx_ctl.send(Control::Interrupt).await?;
+            Ok(())
+        }
+
+        /// Stop child process gracefully using STOPCAR, which will be
+        /// spawned again on next interaction with its positions written
+        /// into POSCAR, e.g. for reading updated INCAR. Return after child
+        /// process stopped.
+        pub async fn restart(&self) -> Result<()> {
+            trace!("send restart task msg");
+            let (tx, rx) = tokio::sync::oneshot::channel();
+            self.tx_ctl.send(Control::Restart(tx)).await?;
+            rx.await.context("task server stopped before restart")?;
+            Ok(())
+        }
+
+        /// Stop child process gracefully using STOPCAR after the current
+        /// interaction, and stop serving.
+        pub async fn cancel(&self) -> Result<()> {
+            trace!("send cancel task msg");
+            self.tx_ctl.send(Control::Cancel).await?;
+            Ok(())
+        }
+
         pub async fn terminate(&self) -> Result<()> {
             trace!("send quit task msg");
             self.tx_ctl.send(Control::Quit).await?;
@@ -165,8 +409,8 @@ mod taskclient {
             self.notifier.notified().await;
             // read stdout from the channel
             self.rx_out.changed().await?;
-            let out = &*self.rx_out.borrow();
-            Ok(out.to_string())
+            let out = self.rx_out.borrow().clone()?;
+            Ok(out)
         }
     }
 }
@@ -176,11 +420,33 @@ mod taskclient {
 /// Create task server and client. The client can be cloned and used in
 /// concurrent environment
 pub fn new_interactive_task(program: &Path) -> (TaskServer, TaskClient) {
-    let command = Command::new(program);
+    new_task(Command::new(program), ".".as_ref(), ProcessControl::default())
+}
+
+/// Create task server and client as `new_interactive_task`, with resource
+/// `limits` applied to the child process. Return error if cgroup limits
+/// cannot be applied.
+pub fn new_interactive_task_with_limits(program: &Path, limits: &ResourceLimits) -> Result<(TaskServer, TaskClient)> {
+    new_interactive_task_in(program, ".".as_ref(), limits)
+}
+
+/// Create task server and client as `new_interactive_task_with_limits`,
+/// with the child process running in working directory `dir`.
+pub fn new_interactive_task_in(
+    program: &Path,
+    dir: &Path,
+    limits: &ResourceLimits,
+) -> Result<(TaskServer, TaskClient)> {
+    let mut command = Command::new(program);
+    command.current_dir(dir);
+    let control = ProcessControl::prepare(&mut command, limits)?;
+    Ok(new_task(command, dir, control))
+}
 
+fn new_task(command: Command, dir: &Path, control: ProcessControl) -> (TaskServer, TaskClient) {
     let (tx_int, rx_int) = tokio::sync::mpsc::channel(1);
     let (tx_ctl, rx_ctl) = tokio::sync::mpsc::channel(1);
-    let (tx_out, rx_out) = tokio::sync::watch::channel("".into());
+    let (tx_out, rx_out) = tokio::sync::watch::channel(Ok("".into()));
 
     let notify1 = Arc::new(Notify::new());
     let notify2 = notify1.clone();
@@ -192,6 +458,9 @@ pub fn new_interactive_task(program: &Path) -> (TaskServer, TaskClient) {
         tx_out: tx_out.into(),
         session: session.into(),
         notifier: notify1,
+        control,
+        respawn: None,
+        workdir: dir.to_owned(),
     };
 
     let client = TaskClient {
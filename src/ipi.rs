@@ -1,16 +1,23 @@
 // [[file:../vasp-tools.note::*imports][imports:1]]
-use crate::common::*;
+use super::*;
 
 use gosh::gchemol::Molecule;
+use gosh::model::ModelProperties;
+use serde::{Deserialize, Serialize};
 // imports:1 ends here
 
 // [[file:../vasp-tools.note::*mods][mods:1]]
 mod codec;
+mod engine;
+mod fake;
+
+pub use engine::{ForceEngine, VaspEngine};
+pub use fake::fake_ipi_driver;
 // mods:1 ends here
 
 // [[file:../vasp-tools.note::*base][base:1]]
 /// The Message type sent from client side (the computation engine)
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum ClientStatus {
     /// The client code needs initializing data.
     NeedInit,
@@ -49,7 +56,54 @@ pub enum ClientMessage {
     Status(ClientStatus),
 }
 
-#[derive(Debug, Clone)]
+/// Additional properties carried in the EXTRA field of FORCEREADY message as
+/// JSON string.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ExtraData {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dipole: Option<[f64; 3]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub magnetization: Option<f64>,
+    /// The number of SCF iterations
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nscf: Option<usize>,
+    /// Total charges on each atom
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub charges: Option<Vec<f64>>,
+}
+
+impl ExtraData {
+    /// Read extra properties from VASP OUTCAR `f`. Missing properties will be
+    /// ignored.
+    pub fn from_vasp_outcar(f: &Path) -> Result<Self> {
+        use crate::vasp::outcar::*;
+
+        let extra = Self {
+            dipole: None,
+            magnetization: parse_last_magnetization(f)?,
+            nscf: parse_last_nscf(f)?,
+            charges: parse_last_charges(f)?,
+        };
+        Ok(extra)
+    }
+}
+
+#[test]
+fn test_ipi_extra_json() -> Result<()> {
+    let extra = ExtraData {
+        magnetization: Some(2.0),
+        nscf: Some(12),
+        ..Default::default()
+    };
+    let s = serde_json::to_string(&extra)?;
+    assert_eq!(s, r#"{"magnetization":2.0,"nscf":12}"#);
+    let x: ExtraData = serde_json::from_str(&s)?;
+    assert_eq!(x, extra);
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Computed {
     energy: f64,
     forces: Vec<[f64; 3]>,
@@ -58,20 +112,108 @@ pub struct Computed {
 }
 
 impl Computed {
-    fn from_model_properties(mp: &gosh::model::ModelProperties) -> Self {
-        let energy = dbg!(mp.get_energy().unwrap());
+    /// The computed energy in eV
+    pub fn energy(&self) -> f64 {
+        self.energy
+    }
+
+    /// The computed forces in eV/Å
+    pub fn forces(&self) -> &[[f64; 3]] {
+        &self.forces
+    }
+
+    /// The virial tensor in eV (row major)
+    pub fn virial(&self) -> [f64; 9] {
+        self.virial
+    }
+
+    /// The extra string sent from client
+    pub fn extra(&self) -> &str {
+        &self.extra
+    }
+
+    /// Parse the extra string as JSON data. Return None if the client sent
+    /// nothing or something not in JSON.
+    pub fn extra_data(&self) -> Option<ExtraData> {
+        serde_json::from_str(&self.extra).ok()
+    }
+
+    /// Set extra data as JSON string
+    pub fn set_extra_data(&mut self, extra: &ExtraData) -> Result<()> {
+        self.extra = serde_json::to_string(extra).context("serialize extra data")?;
+        Ok(())
+    }
+
+    /// Convert into `ModelProperties` for output
+    pub fn to_model_properties(&self, mol: &Molecule) -> ModelProperties {
+        let mut mp = ModelProperties::default();
+        mp.set_energy(self.energy);
+        mp.set_forces(self.forces.clone());
+        mp.set_molecule(mol.clone());
+        mp
+    }
+
+    /// Set virial from VASP stress tensor `stress` in kB (XX, YY, ZZ, XY, YZ,
+    /// ZX as in OUTCAR) and cell `volume` in Å^3.
+    pub fn set_virial_from_vasp_stress(&mut self, stress: [f64; 6], volume: f64) {
+        self.virial = vasp_stress_to_virial(stress, volume);
+    }
+
+    fn from_model_properties(mp: &gosh::model::ModelProperties) -> Result<Self> {
+        let energy = mp.get_energy().unwrap();
         let forces = mp.get_forces().unwrap().clone();
-        Self {
+        let mut computed = Self {
             energy,
             forces,
-            // TODO: we have no support for stress tensor, so set virial as
-            // zeros
+            // NOTE: ModelProperties has no stress tensor, so set virial as
+            // zeros. See `set_virial_from_vasp_stress`.
             virial: [0.0; 9],
             extra: "".into(),
+        };
+        if let Some(dipole) = mp.get_dipole() {
+            let extra = ExtraData {
+                dipole: Some(dipole),
+                ..Default::default()
+            };
+            computed.set_extra_data(&extra)?;
         }
+        Ok(computed)
     }
 }
 
+/// Convert VASP stress in kB to virial tensor in eV (row major) following
+/// i-PI convention.
+///
+/// VASP reports the stress with positive sign for compressive stress (the
+/// cell wants to expand), so the virial is simply `stress * volume`.
+pub fn vasp_stress_to_virial(stress: [f64; 6], volume: f64) -> [f64; 9] {
+    let [xx, yy, zz, xy, yz, zx] = stress.map(|x| crate::units::kbar_to_ev_per_a3(x) * volume);
+    [xx, xy, zx, xy, yy, yz, zx, yz, zz]
+}
+
+#[test]
+fn test_computed_serde() -> Result<()> {
+    let mut mp = ModelProperties::default();
+    mp.set_energy(-1.0);
+    mp.set_forces(vec![[0.1, 0.2, 0.3]]);
+    let mut computed = Computed::from_model_properties(&mp)?;
+    computed.set_virial_from_vasp_stress([1.0; 6], 10.0);
+    let s = serde_json::to_string(&computed)?;
+    let x: Computed = serde_json::from_str(&s)?;
+    assert_eq!(x, computed);
+
+    Ok(())
+}
+
+#[test]
+fn test_vasp_stress_to_virial() {
+    let v = vasp_stress_to_virial([crate::units::EV_PER_A3_IN_KB, 0.0, 0.0, crate::units::EV_PER_A3_IN_KB, 0.0, 0.0], 2.0);
+    assert_relative_eq!(v[0], 2.0, epsilon = 1e-8);
+    assert_relative_eq!(v[1], 2.0, epsilon = 1e-8);
+    assert_relative_eq!(v[3], 2.0, epsilon = 1e-8);
+    assert_relative_eq!(v[8], 0.0, epsilon = 1e-8);
+}
+
 #[derive(Debug, Clone)]
 pub struct InitData {
     ibead: usize,
@@ -87,19 +229,389 @@ impl InitData {
             init: init.into(),
         }
     }
+
+    /// Create init data carrying element symbols as space-separated list.
+    pub fn with_symbols<'a>(ibead: usize, symbols: impl IntoIterator<Item = &'a str>) -> Self {
+        let init = symbols.into_iter().collect::<Vec<_>>().join(" ");
+        Self::new(ibead, &init)
+    }
+
+    /// The bead index assigned by i-PI server.
+    pub fn ibead(&self) -> usize {
+        self.ibead
+    }
+
+    /// Parse element symbols from init string. The init string could be a
+    /// JSON object with "symbols", "species" or "elements" key, a JSON array,
+    /// or space-separated element symbols. Return None if no symbols found.
+    pub fn symbols(&self) -> Option<Vec<String>> {
+        parse_symbols_from_init(&self.init)
+    }
+}
+
+fn parse_symbols_from_init(init: &str) -> Option<Vec<String>> {
+    use serde_json::Value;
+
+    let init = init.trim();
+    if init.is_empty() {
+        return None;
+    }
+    let symbols = match serde_json::from_str::<Value>(init) {
+        Ok(Value::Array(arr)) => arr.iter().map(|x| x.as_str().map(|s| s.to_string())).collect::<Option<Vec<_>>>()?,
+        Ok(Value::Object(map)) => {
+            let arr = ["symbols", "species", "elements"].iter().find_map(|k| map.get(*k))?.as_array()?;
+            arr.iter().map(|x| x.as_str().map(|s| s.to_string())).collect::<Option<Vec<_>>>()?
+        }
+        Ok(_) => return None,
+        Err(_) => init.split_whitespace().map(|s| s.to_string()).collect(),
+    };
+    // element symbols: "C", "Fe", ...
+    let valid = symbols.iter().all(|s| {
+        let mut chars = s.chars();
+        chars.next().map_or(false, |c| c.is_ascii_uppercase()) && chars.all(|c| c.is_ascii_lowercase())
+    });
+    if valid && !symbols.is_empty() {
+        Some(symbols)
+    } else {
+        None
+    }
+}
+
+#[test]
+fn test_ipi_init_symbols() {
+    let symbols = parse_symbols_from_init("C H H O").unwrap();
+    assert_eq!(symbols, ["C", "H", "H", "O"]);
+    let symbols = parse_symbols_from_init(r#"["Fe", "O"]"#).unwrap();
+    assert_eq!(symbols, ["Fe", "O"]);
+    let symbols = parse_symbols_from_init(r#"{"species": ["Pt", "C"], "foo": 1}"#).unwrap();
+    assert_eq!(symbols, ["Pt", "C"]);
+    assert!(parse_symbols_from_init("").is_none());
+    assert!(parse_symbols_from_init("bead=1 nm").is_none());
 }
 // base:1 ends here
 
+// [[file:../vasp-tools.note::2830e76a][2830e76a]]
+/// The socket address for i-PI communication, as in i-PI's `<ffsocket
+/// mode="unix|inet">`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Endpoint {
+    /// Path to unix domain socket file
+    Unix(PathBuf),
+    /// host:port for TCP socket
+    Inet(String),
+}
+
+impl Endpoint {
+    /// i-PI creates the unix socket file as `/tmp/ipi_<address>`
+    pub fn from_ipi_unix_address(address: &str) -> Self {
+        Self::Unix(format!("/tmp/ipi_{}", address).into())
+    }
+}
+
+impl std::str::FromStr for Endpoint {
+    type Err = Error;
+
+    /// Parse endpoint from "unix:<path>", "inet:<host:port>" or "<host:port>".
+    /// A string containing "/" is treated as the path to unix socket file.
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.contains('/') {
+                Ok(Self::Unix(path.into()))
+            } else {
+                Ok(Self::from_ipi_unix_address(path))
+            }
+        } else if let Some(addr) = s.strip_prefix("inet:") {
+            Ok(Self::Inet(addr.into()))
+        } else if s.contains('/') {
+            Ok(Self::Unix(s.into()))
+        } else if s.contains(':') {
+            Ok(Self::Inet(s.into()))
+        } else {
+            bail!("invalid i-PI endpoint: {:?}", s);
+        }
+    }
+}
+
+impl std::fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+            Self::Inet(addr) => write!(f, "inet:{}", addr),
+        }
+    }
+}
+
+#[test]
+fn test_ipi_endpoint() -> Result<()> {
+    let e: Endpoint = "localhost:10244".parse()?;
+    assert_eq!(e, Endpoint::Inet("localhost:10244".into()));
+    let e: Endpoint = "unix:vasp".parse()?;
+    assert_eq!(e, Endpoint::Unix("/tmp/ipi_vasp".into()));
+    let e: Endpoint = "./vasp.sock".parse()?;
+    assert_eq!(e, Endpoint::Unix("./vasp.sock".into()));
+    assert!("vasp".parse::<Endpoint>().is_err());
+
+    Ok(())
+}
+// 2830e76a ends here
+
 // [[file:../vasp-tools.note::*pub/as client][pub/as client:1]]
-use gosh::model::*;
+use crate::bbm::BbmOptions;
 
-pub async fn bbm_as_ipi_client(mut bbm: BlackBoxModel, mol_ini: Molecule, sock: &Path) -> Result<()> {
-    use futures::SinkExt;
-    use futures::StreamExt;
-    use tokio::net::UnixStream;
-    use tokio_util::codec::{FramedRead, FramedWrite};
+/// Options for reconnecting to i-PI server when the connection is lost.
+#[derive(Debug, Clone)]
+pub struct ReconnectOptions {
+    /// Max number of retries before giving up. Zero for no reconnection.
+    pub max_retries: usize,
+    /// Keep the engine (and the interactive VASP session it drives) alive
+    /// across reconnections to preserve the wavefunction. Otherwise the
+    /// engine will be recreated after reconnection.
+    pub keep_session: bool,
+}
 
-    // FIXME: temp solution: write flame yaml input
+impl Default for ReconnectOptions {
+    fn default() -> Self {
+        Self {
+            max_retries: 10,
+            keep_session: true,
+        }
+    }
+}
+
+/// Options of i-PI client for connecting to server and computing with the
+/// engine.
+#[derive(Debug, Clone)]
+pub struct IpiClientOptions {
+    /// The address of i-PI server
+    pub endpoint: Endpoint,
+    /// Read the stress tensor and extra properties (magnetization, SCF
+    /// iterations, atomic charges) from this OUTCAR after each computation,
+    /// e.g. the OUTCAR of interactive VASP server called by BBM.
+    pub outcar: Option<PathBuf>,
+    pub reconnect: ReconnectOptions,
+    /// The retries, scratch directories and checkpoint of BBM calculations
+    pub bbm: BbmOptions,
+}
+
+impl IpiClientOptions {
+    /// Default options for connecting to `endpoint`.
+    pub fn new(endpoint: Endpoint) -> Self {
+        Self {
+            endpoint,
+            outcar: None,
+            reconnect: ReconnectOptions::default(),
+            bbm: BbmOptions::default(),
+        }
+    }
+}
+
+/// Options for running VASP as the engine of i-PI client
+#[derive(Debug, Clone)]
+pub struct VaspEngineOptions {
+    pub limits: crate::process::ResourceLimits,
+    /// The pattern (regex) in VASP stdout when computation is done
+    pub read_pattern: String,
+    /// The initial velocities written into POSCAR
+    pub velocities: Option<Vec<[f64; 3]>>,
+}
+
+impl Default for VaspEngineOptions {
+    fn default() -> Self {
+        Self {
+            limits: Default::default(),
+            read_pattern: crate::vasp::stdout::VASP_READ_PATTERN.into(),
+            velocities: None,
+        }
+    }
+}
+
+/// How the connection with i-PI server ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConnectionEnd {
+    /// The server asked for exit
+    Exit,
+    /// The connection is closed by server without EXIT message
+    Disconnected,
+}
+
+/// Connect to i-PI server and compute with BBM in `bbm_dir` on request,
+/// following `opts`. The connection will be re-established with backoff if
+/// the server restarts.
+///
+/// The element symbols of received structures are taken from `mol_ini` if
+/// provided, otherwise from the INIT message sent by the server.
+pub async fn bbm_as_ipi_client(bbm_dir: &Path, mol_ini: Option<Molecule>, opts: &IpiClientOptions) -> Result<()> {
+    if let Some(mol_ini) = &mol_ini {
+        print_flame_yaml(mol_ini);
+    }
+    let new_engine = || -> Result<_> { Ok(ForceEngine::Bbm(opts.bbm.build_driver(bbm_dir)?)) };
+    run_ipi_client("ipi-client", new_engine, mol_ini.as_ref(), opts).await
+}
+
+/// Connect to i-PI server and compute with VASP `program` run in
+/// interactive mode in current directory. Positions received from i-PI
+/// server are passed to VASP stdin directly, without the overhead of calling
+/// BBM scripts in each step.
+pub async fn vasp_as_ipi_client(
+    program: &Path,
+    vasp: &VaspEngineOptions,
+    mol_ini: Option<Molecule>,
+    opts: &IpiClientOptions,
+) -> Result<()> {
+    let new_engine = || -> Result<_> {
+        let mut engine = VaspEngine::start(program, &vasp.limits, &vasp.read_pattern)?;
+        if let Some(velocities) = &vasp.velocities {
+            engine.set_velocities(velocities.clone());
+        }
+        Ok(ForceEngine::Vasp(engine))
+    };
+    run_ipi_client("ipi-vasp", new_engine, mol_ini.as_ref(), opts).await
+}
+
+/// Connect to i-PI server and compute with analytical potential `pot`, for
+/// testing i-PI stack and optimizers without VASP. The OUTCAR in `opts` is
+/// ignored.
+pub async fn potential_as_ipi_client(
+    pot: crate::potential::Potential,
+    mol_ini: Option<Molecule>,
+    opts: &IpiClientOptions,
+) -> Result<()> {
+    let opts = IpiClientOptions {
+        outcar: None,
+        ..opts.clone()
+    };
+    let new_engine = || -> Result<_> { Ok(ForceEngine::Potential(pot)) };
+    run_ipi_client("ipi-potential", new_engine, mol_ini.as_ref(), &opts).await
+}
+
+/// Run a pool of i-PI clients for path-integral simulations, one for each BBM
+/// directory in `bbm_dirs`. Each client runs in its own thread with its own
+/// interactive VASP session, and i-PI server assigns beads to connected
+/// clients. The bead index will be reported from the INIT message.
+///
+/// If OUTCAR in `opts` is a relative path, it is resolved against each BBM
+/// directory.
+pub fn bbm_pool_as_ipi_client(bbm_dirs: &[PathBuf], mol_ini: Option<Molecule>, opts: &IpiClientOptions) -> Result<()> {
+    ensure!(!bbm_dirs.is_empty(), "no BBM directory for i-PI clients");
+    ensure!(
+        !opts.bbm.is_exclusive(),
+        "keeping scratch directories or checkpoint is not supported for multiple clients"
+    );
+    if let Some(mol_ini) = &mol_ini {
+        print_flame_yaml(mol_ini);
+    }
+
+    info!("start {} i-PI clients", bbm_dirs.len());
+    std::thread::scope(|s| {
+        let handles: Vec<_> = bbm_dirs
+            .iter()
+            .enumerate()
+            .map(|(i, bbm_dir)| {
+                let label = format!("ipi-client-{}", i);
+                let opts = IpiClientOptions {
+                    outcar: opts.outcar.as_ref().map(|f| bbm_dir.join(f)),
+                    ..opts.clone()
+                };
+                let mol_ini = mol_ini.as_ref();
+                s.spawn(move || -> Result<()> {
+                    // NOTE: BBM computation is blocking, so each client has its
+                    // own runtime in a separate thread
+                    let rt = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+                    let new_engine = || -> Result<_> { Ok(ForceEngine::Bbm(opts.bbm.build_driver(bbm_dir)?)) };
+                    rt.block_on(run_ipi_client(&label, new_engine, mol_ini, &opts))
+                        .with_context(|| format!("{} failed", label))
+                })
+            })
+            .collect();
+
+        let mut nfailed = 0;
+        for h in handles {
+            match h.join() {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => {
+                    error!("{:?}", e);
+                    nfailed += 1;
+                }
+                Err(_) => {
+                    error!("i-PI client thread panicked");
+                    nfailed += 1;
+                }
+            }
+        }
+        ensure!(nfailed == 0, "{} of {} i-PI clients failed", nfailed, bbm_dirs.len());
+        Ok(())
+    })
+}
+
+async fn run_ipi_client(
+    label: &str,
+    new_engine: impl Fn() -> Result<ForceEngine>,
+    mol_ini: Option<&Molecule>,
+    opts: &IpiClientOptions,
+) -> Result<()> {
+    let (endpoint, outcar, reconnect) = (&opts.endpoint, opts.outcar.as_deref(), &opts.reconnect);
+    let mut engine: Option<ForceEngine> = None;
+    let mut retries = 0;
+    loop {
+        if engine.is_none() {
+            engine = new_engine()?.into();
+        }
+        let engine_ = engine.as_mut().expect("engine");
+        match connect_and_serve(label, endpoint, engine_, mol_ini, outcar, &mut retries).await {
+            Ok(ConnectionEnd::Exit) => break,
+            Ok(ConnectionEnd::Disconnected) => warn!("{}: i-PI server closed the connection.", label),
+            Err(e) => warn!("{}: i-PI client error: {:?}", label, e),
+        }
+        if retries >= reconnect.max_retries {
+            bail!("i-PI server at {} is not available after {} retries", endpoint, retries);
+        }
+        if !reconnect.keep_session {
+            if let Some(ForceEngine::Vasp(vasp)) = engine.take() {
+                vasp.terminate().await?;
+            }
+        }
+        // exponential backoff: 1, 2, 4, ..., 64 seconds
+        let delay = 1 << retries.min(6);
+        retries += 1;
+        info!("{}: reconnect to i-PI server in {} seconds (retry {})", label, delay, retries);
+        tokio::time::sleep(std::time::Duration::from_secs(delay)).await;
+    }
+    if let Some(ForceEngine::Vasp(vasp)) = engine {
+        vasp.terminate().await?;
+    }
+
+    Ok(())
+}
+
+async fn connect_and_serve(
+    label: &str,
+    endpoint: &Endpoint,
+    engine: &mut ForceEngine,
+    mol_ini: Option<&Molecule>,
+    outcar: Option<&Path>,
+    retries: &mut usize,
+) -> Result<ConnectionEnd> {
+    use tokio::net::{TcpStream, UnixStream};
+
+    info!("{}: connect to i-PI server at {}", label, endpoint);
+    match endpoint {
+        Endpoint::Unix(path) => {
+            let stream = UnixStream::connect(path)
+                .await
+                .with_context(|| format!("connect to unix socket {:?}", path))?;
+            serve_as_ipi_client(label, stream, engine, mol_ini, outcar, retries).await
+        }
+        Endpoint::Inet(addr) => {
+            let stream = TcpStream::connect(addr)
+                .await
+                .with_context(|| format!("connect to host {:?}", addr))?;
+            serve_as_ipi_client(label, stream, engine, mol_ini, outcar, retries).await
+        }
+    }
+}
+
+// FIXME: temp solution: write flame yaml input
+fn print_flame_yaml(mol_ini: &Molecule) {
     let [va, vb, vc] = mol_ini.get_lattice().as_ref().unwrap().vectors();
     println!("---");
     println!("conf:");
@@ -116,19 +628,32 @@ pub async fn bbm_as_ipi_client(mut bbm: BlackBoxModel, mol_ini: Molecule, sock:
         let fff: String = a.freezing().iter().map(|&x| if x { "T" } else { "F" }).collect();
         println!("  - [{:10.4}, {:10.4}, {:10.4}, {}, {}]", x, y, z, a.symbol(), fff);
     }
+}
 
-    // let mut stream = UnixStream::connect(sock).context("connect to unix socket").await?;
-    let mut stream = tokio::net::TcpStream::connect("127.0.0.1:10244")
-        .await
-        .context("connect to host")?;
-    let (read, write) = stream.split();
+async fn serve_as_ipi_client<S>(
+    label: &str,
+    stream: S,
+    engine: &mut ForceEngine,
+    mol_ini: Option<&Molecule>,
+    outcar: Option<&Path>,
+    retries: &mut usize,
+) -> Result<ConnectionEnd>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite,
+{
+    use futures::SinkExt;
+    use futures::StreamExt;
+    use tokio_util::codec::{FramedRead, FramedWrite};
 
+    let (read, write) = tokio::io::split(stream);
     // the message we received from the server (the driver)
-    let mut server_read = FramedRead::new(read, codec::ServerCodec);
+    let mut server_read = FramedRead::new(read, codec::ServerCodec::default());
     // the message we sent to the server (the driver)
     let mut client_write = FramedWrite::new(write, codec::ClientCodec);
 
     let mut mol_to_compute: Option<Molecule> = None;
+    // NOTE: i-PI requires client to report NEEDINIT on (re)connection
+    let mut initialized = false;
     // NOTE: There is no async for loop for stream in current version of Rust,
     // so we use while loop instead
     while let Some(stream) = server_read.next().await {
@@ -136,7 +661,9 @@ pub async fn bbm_as_ipi_client(mut bbm: BlackBoxModel, mol_ini: Molecule, sock:
         match stream {
             ServerMessage::Status => {
                 debug!("server ask for client status");
-                if mol_to_compute.is_none() {
+                if !initialized {
+                    client_write.send(ClientMessage::Status(ClientStatus::NeedInit)).await?;
+                } else if mol_to_compute.is_none() {
                     client_write.send(ClientMessage::Status(ClientStatus::Ready)).await?;
                 } else {
                     client_write.send(ClientMessage::Status(ClientStatus::HaveData)).await?;
@@ -145,13 +672,32 @@ pub async fn bbm_as_ipi_client(mut bbm: BlackBoxModel, mol_ini: Molecule, sock:
             ServerMessage::GetForce => {
                 debug!("server ask for forces");
                 if let Some(mol) = mol_to_compute.as_mut() {
-                    assert_eq!(mol.natoms(), mol_ini.natoms());
-                    // NOTE: reset element symbols from mol_ini
-                    mol.set_symbols(mol_ini.symbols());
-                    let mp = bbm.compute(&mol)?;
-                    let computed = Computed::from_model_properties(&mp);
+                    if let Some(mol_ini) = mol_ini {
+                        ensure!(mol.natoms() == mol_ini.natoms(), "inconsistent number of atoms");
+                        // NOTE: reset element symbols from mol_ini
+                        mol.set_symbols(mol_ini.symbols());
+                    }
+                    let props = engine.compute(&mol).await?;
+                    let mut computed = Computed::from_model_properties(&props.mp)?;
+                    let volume = mol.get_lattice().map(|lat| lat.volume()).unwrap_or(0.0);
+                    if let Some(stress) = props.stress {
+                        computed.set_virial_from_vasp_stress(stress, volume);
+                    }
+                    if let Some(f) = outcar {
+                        if props.stress.is_none() {
+                            let stress = crate::vasp::outcar::parse_last_stress(f)?;
+                            computed.set_virial_from_vasp_stress(stress, volume);
+                        }
+                        let mut extra = ExtraData::from_vasp_outcar(f)?;
+                        extra.dipole = computed.extra_data().and_then(|x| x.dipole);
+                        computed.set_extra_data(&extra)?;
+                    }
                     client_write.send(ClientMessage::ForceReady(computed)).await?;
                     mol_to_compute = None;
+                    // NOTE: reset only after forces delivered, so a server
+                    // accepting then dropping connections still exhausts
+                    // the retries
+                    *retries = 0;
                 } else {
                     bail!("not mol to compute!");
                 }
@@ -162,62 +708,106 @@ pub async fn bbm_as_ipi_client(mut bbm: BlackBoxModel, mol_ini: Molecule, sock:
             }
             ServerMessage::Init(data) => {
                 debug!("server sent init data: {:?}", data);
+                info!("{}: assigned to bead {}", label, data.ibead());
+                initialized = true;
             }
             ServerMessage::Exit => {
                 debug!("server ask exit");
-                break;
+                return Ok(ConnectionEnd::Exit);
             }
         }
     }
 
-    Ok(())
+    Ok(ConnectionEnd::Disconnected)
 }
 // pub/as client:1 ends here
 
 // [[file:../vasp-tools.note::*pub/as driver][pub/as driver:1]]
-async fn ipi_driver(sock: &Path, mol: &Molecule) -> Result<()> {
+/// Bind i-PI socket at `endpoint`, and drive the first connected client to
+/// compute `mols` one by one. `on_computed` will be called for each computed
+/// structure with its index.
+pub async fn ipi_driver<I, F>(endpoint: &Endpoint, mols: I, on_computed: F) -> Result<()>
+where
+    I: IntoIterator<Item = Molecule>,
+    F: FnMut(usize, &Molecule, &Computed) -> Result<()>,
+{
+    use tokio::net::{TcpListener, UnixListener};
+
+    info!("i-PI driver: listening on {}", endpoint);
+    match endpoint {
+        Endpoint::Unix(path) => {
+            let listener = UnixListener::bind(path).with_context(|| format!("bind unix socket {:?}", path))?;
+            let (stream, _) = listener.accept().await.context("accept new unix socket client")?;
+            let r = drive_ipi_client(stream, mols, on_computed).await;
+            let _ = std::fs::remove_file(path);
+            r
+        }
+        Endpoint::Inet(addr) => {
+            let listener = TcpListener::bind(addr).await.with_context(|| format!("bind {:?}", addr))?;
+            let (stream, peer) = listener.accept().await.context("accept new tcp client")?;
+            debug!("i-PI client connected from {:?}", peer);
+            drive_ipi_client(stream, mols, on_computed).await
+        }
+    }
+}
+
+async fn drive_ipi_client<S, I, F>(stream: S, mols: I, mut on_computed: F) -> Result<()>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite,
+    I: IntoIterator<Item = Molecule>,
+    F: FnMut(usize, &Molecule, &Computed) -> Result<()>,
+{
     use futures::SinkExt;
     use futures::StreamExt;
-    use tokio::net::UnixListener;
     use tokio_util::codec::{FramedRead, FramedWrite};
 
-    let mut listener = UnixListener::bind(sock).context("bind unix socket")?;
-    let (mut stream, _) = listener.accept().await.context("accept new unix socket client")?;
-    let (read, write) = stream.split();
-    
+    let (read, write) = tokio::io::split(stream);
     // the message we received from the client code (VASP, SIESTA, ...)
     let mut client_read = FramedRead::new(read, codec::ClientCodec);
     // the message we sent to the client
-    let mut server_write = FramedWrite::new(write, codec::ServerCodec);
+    let mut server_write = FramedWrite::new(write, codec::ServerCodec::default());
 
-    loop {
-        // ask for client status
-        server_write.send(ServerMessage::Status).await?;
-        // read the message
-        if let Some(stream) = client_read.next().await {
-            let stream = stream?;
-            match stream {
+    for (i, mol) in mols.into_iter().enumerate() {
+        debug!("i-PI driver: compute structure {}", i);
+        let mut sent = false;
+        loop {
+            // ask for client status
+            server_write.send(ServerMessage::Status).await?;
+            // read the message
+            let msg = client_read.next().await.ok_or(format_err!("i-PI client disconnected"))??;
+            match msg {
                 // we are ready to send structure to compute
                 ClientMessage::Status(status) => match status {
                     ClientStatus::Ready => {
+                        ensure!(!sent, "i-PI client lost the structure sent");
                         server_write.send(ServerMessage::PosData(mol.clone())).await?;
+                        sent = true;
                     }
                     ClientStatus::NeedInit => {
-                        let init = InitData::new(0, "");
+                        // inform the client the element symbols
+                        let init = InitData::with_symbols(0, mol.symbols());
                         server_write.send(ServerMessage::Init(init)).await?;
                     }
                     ClientStatus::HaveData => {
                         server_write.send(ServerMessage::GetForce).await?;
+                        // the computation is done, and we got the results
+                        let msg = client_read.next().await.ok_or(format_err!("i-PI client disconnected"))??;
+                        match msg {
+                            ClientMessage::ForceReady(computed) => {
+                                on_computed(i, &mol, &computed)?;
+                                break;
+                            }
+                            _ => bail!("unexpected message from i-PI client: {:?}", msg),
+                        }
                     }
                 },
-                // the computation is done, and we got the results
-                ClientMessage::ForceReady(computed) => {
-                    dbg!(computed);
-                    break;
-                }
+                ClientMessage::ForceReady(_) => bail!("unexpected FORCEREADY message from i-PI client"),
             }
         }
     }
+    info!("i-PI driver: all structures computed, ask client to exit.");
+    server_write.send(ServerMessage::Exit).await?;
+
     Ok(())
 }
 // pub/as driver:1 ends here
@@ -9,25 +9,62 @@ type EncodedResult = Result<(), std::io::Error>;
 
 const HEADER_SIZE: usize = 12;
 
-use gosh::gchemol::units::{Bohr, Hartree};
+use crate::units::{BOHR, HARTREE};
 // imports:1 ends here
 
 // [[file:../../vasp-tools.note::*utils][utils:1]]
+/// Errors in i-PI communication
+#[derive(Debug)]
+pub enum ProtocolError {
+    Io(std::io::Error),
+    /// Unknown message header received from peer
+    InvalidHeader(String),
+    /// Malformed message body
+    InvalidMessage(String),
+}
+
+impl std::fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "i-PI io error: {}", e),
+            Self::InvalidHeader(h) => write!(f, "i-PI protocol error: invalid header {:?}", h),
+            Self::InvalidMessage(m) => write!(f, "i-PI protocol error: {}", m),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<std::io::Error> for ProtocolError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
 // A wrapper for Ok(None), so we can early return using question mark (?)
 #[derive(Debug)]
 enum DecodeError {
-    IoError(std::io::Error),
+    Protocol(ProtocolError),
     // but a frame isn’t fully available yet, then Ok(None) is returned
     NotEnoughData,
 }
 
-fn fix_decode_err<T>(e: DecodeError) -> Result<Option<T>, std::io::Error> {
+fn fix_decode_err<T>(e: DecodeError) -> Result<Option<T>, ProtocolError> {
     match e {
-        DecodeError::IoError(e) => Err(e),
+        DecodeError::Protocol(e) => Err(e),
         DecodeError::NotEnoughData => Ok(None),
     }
 }
 
+fn invalid_message(msg: impl Into<String>) -> DecodeError {
+    DecodeError::Protocol(ProtocolError::InvalidMessage(msg.into()))
+}
+
+// Max number of atoms or bytes we accept, to avoid waiting forever for
+// garbage length headers.
+const MAX_NATOMS: usize = 10_000_000;
+const MAX_NBYTES: usize = 1 << 30;
+
 fn try_to_string(bytes: &[u8]) -> Result<String, std::io::Error> {
     let bytes: Bytes = bytes.into_iter().cloned().collect();
     String::from_utf8(bytes.to_vec()).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
@@ -45,8 +82,24 @@ fn try_decode_message_header(src: &BytesMut, nheader: usize) -> Result<String, D
         return Err(DecodeError::NotEnoughData);
     }
 
-    let s = try_to_string(&src[..nheader]).map_err(|e| into_decode_error(e))?;
-    Ok(s.trim_end().to_string())
+    let header = &src[..nheader];
+    if !header.is_ascii() {
+        return Err(DecodeError::Protocol(ProtocolError::InvalidHeader(
+            String::from_utf8_lossy(header).into(),
+        )));
+    }
+    let s = try_to_string(header).map_err(|e| into_decode_error(e))?;
+    // NOTE: some implementations pad header with null bytes
+    Ok(s.trim_end_matches(|c| c == ' ' || c == '\0').to_string())
+}
+
+/// Return error if message header is not `expected`
+fn check_message_header(src: &BytesMut, expected: &str) -> Result<(), DecodeError> {
+    let msg = try_decode_message_header(src, HEADER_SIZE)?;
+    if msg != expected {
+        return Err(DecodeError::Protocol(ProtocolError::InvalidHeader(msg)));
+    }
+    Ok(())
 }
 
 /// Try to decode length header
@@ -56,6 +109,9 @@ fn try_decode_length_header_u32(src: &BytesMut, offset: usize) -> Result<usize,
         return Err(DecodeError::NotEnoughData);
     }
     let n = to_u32(&src[offset..nheader]) as usize;
+    if n > MAX_NBYTES {
+        return Err(invalid_message(format!("length header too large: {}", n)));
+    }
     if src.len() < nheader + n {
         return Err(DecodeError::NotEnoughData);
     }
@@ -73,7 +129,7 @@ fn try_decode_nbytes(src: &BytesMut, nbytes: usize) -> Result<(), DecodeError> {
 }
 
 fn into_decode_error(e: std::io::Error) -> DecodeError {
-    DecodeError::IoError(e)
+    DecodeError::Protocol(e.into())
 }
 
 fn format_header(code: &str) -> String {
@@ -106,10 +162,10 @@ fn encode_client_status(dest: &mut BytesMut, status: &ClientStatus) -> EncodedRe
 fn decode_client_status(src: &BytesMut) -> Result<ClientStatus, DecodeError> {
     let msg = try_decode_message_header(src, 12)?;
     let status = match msg.as_str() {
-        "NEEDINT" => ClientStatus::NeedInit,
+        "NEEDINIT" => ClientStatus::NeedInit,
         "READY" => ClientStatus::Ready,
         "HAVEDATA" => ClientStatus::HaveData,
-        _ => panic!("invalid message: {:?}", msg),
+        _ => return Err(DecodeError::Protocol(ProtocolError::InvalidHeader(msg))),
     };
     Ok(status)
 }
@@ -130,17 +186,15 @@ fn test_ipi_status() {
 /// [12] [4]    [4(?)] [s...]
 /// INIT ibead  nbytes  ...
 fn decode_init(src: &mut BytesMut) -> Result<InitData, DecodeError> {
-    let msg = try_decode_message_header(src, 12)?;
-    assert_eq!(msg, "INIT");
-    let nbytes = try_decode_length_header_u32(src, 12 + 4)?;
-    let n_expected = 12 + 4 + 4 + nbytes;
+    check_message_header(src, "INIT")?;
+    let _nbytes = try_decode_length_header_u32(src, 12 + 4)?;
 
     src.advance(12);
     let ibead = src.get_u32_le();
     let nbytes = src.get_u32_le();
     let init = src.copy_to_bytes(nbytes as usize);
     let init = try_to_string(&init).map_err(|e| into_decode_error(e))?;
-    Ok(InitData::new(0, &init))
+    Ok(InitData::new(ibead as usize, &init))
 }
 
 fn encode_init(dest: &mut BytesMut, init: InitData) -> EncodedResult {
@@ -173,12 +227,14 @@ fn is_periodic(cell: [f64; 9]) -> bool {
 
 fn decode_posdata(src: &mut BytesMut) -> Result<Molecule, DecodeError> {
     // 0. try to decode no advance, until we have enough data
-    let msg = try_decode_message_header(src, 12)?;
-    assert_eq!(msg, "POSDATA");
+    check_message_header(src, "POSDATA")?;
 
     let nbytes_cell = 9 * 8 * 2; // cell matrix and the inverse of cell matrix
     let nbytes_expected = 12 + nbytes_cell;
     let natoms = try_decode_length_header_u32(&src, nbytes_expected)?;
+    if natoms > MAX_NATOMS {
+        return Err(invalid_message(format!("too many atoms in POSDATA: {}", natoms)));
+    }
 
     let nbytes_cart_coords = 3 * 8 * natoms;
     let nbytes_expected = nbytes_expected + 4 + nbytes_cart_coords;
@@ -189,7 +245,7 @@ fn decode_posdata(src: &mut BytesMut) -> Result<Molecule, DecodeError> {
     let mut cell = [0f64; 9];
     // nine floats for the cell vector matrix
     for i in 0..9 {
-        cell[i] = src.get_f64_le() * Bohr;
+        cell[i] = src.get_f64_le() * BOHR;
     }
 
     // read inverse matrix of the cell
@@ -197,19 +253,24 @@ fn decode_posdata(src: &mut BytesMut) -> Result<Molecule, DecodeError> {
     // nine floats for the inverse matrix
     let mut _icell = [0f64; 9];
     for i in 0..9 {
-        _icell[i] = src.get_f64_le() * Bohr;
+        _icell[i] = src.get_f64_le() * BOHR;
     }
 
     let natoms = src.get_u32_le() as usize;
     let mut coords = vec![[0f64; 3]; natoms];
     for i in 0..natoms {
-        let x = src.get_f64_le() * Bohr;
-        let y = src.get_f64_le() * Bohr;
-        let z = src.get_f64_le() * Bohr;
+        let x = src.get_f64_le() * BOHR;
+        let y = src.get_f64_le() * BOHR;
+        let z = src.get_f64_le() * BOHR;
         coords[i] = [x, y, z];
     }
 
-    // FIXME: how to determinate element symbols?
+    if !cell.iter().chain(coords.iter().flatten()).all(|x| x.is_finite()) {
+        return Err(invalid_message("non-finite numbers in POSDATA"));
+    }
+
+    // NOTE: element symbols are not transfered in POSDATA. They will be reset
+    // in `ServerCodec` from INIT message if available.
     let atoms: Vec<_> = coords.into_iter().map(|p| Atom::new("C", p)).collect();
     let mut mol = Molecule::from_atoms(atoms);
 
@@ -236,19 +297,19 @@ fn encode_posdata(dest: &mut BytesMut, mol: &Molecule) -> EncodedResult {
 
     // I-PI assumes row major order for cell matrix
     for v in cell.transpose().as_slice() {
-        dest.put_f64_le(*v / Bohr);
+        dest.put_f64_le(*v / BOHR);
     }
     // I-PI assumes row major order for cell matrix
     for v in icell.transpose().as_slice() {
-        dest.put_f64_le(*v * Bohr);
+        dest.put_f64_le(*v * BOHR);
     }
 
     // write Cartesian coordinates
     dest.put_u32_le(mol.natoms() as u32);
     for [x, y, z] in mol.positions() {
-        dest.put_f64_le(x / Bohr);
-        dest.put_f64_le(y / Bohr);
-        dest.put_f64_le(z / Bohr);
+        dest.put_f64_le(x / BOHR);
+        dest.put_f64_le(y / BOHR);
+        dest.put_f64_le(z / BOHR);
     }
 
     Ok(())
@@ -285,17 +346,17 @@ fn test_decode_posdata() {
 fn encode_client_computed(dst: &mut BytesMut, computed: &Computed) -> EncodedResult {
     let s = format_header("FORCEREADY");
     dst.put_slice(s.as_bytes());
-    dst.put_f64_le(computed.energy / Hartree);
+    dst.put_f64_le(computed.energy / HARTREE);
     let n = computed.forces.len();
     dst.put_u32_le(n as u32);
-    let f = Bohr / Hartree;
+    let f = BOHR / HARTREE;
     for i in 0..n {
         dst.put_f64_le(computed.forces[i][0] * f);
         dst.put_f64_le(computed.forces[i][1] * f);
         dst.put_f64_le(computed.forces[i][2] * f);
     }
     for i in 0..9 {
-        dst.put_f64_le(computed.virial[i] * Hartree);
+        dst.put_f64_le(computed.virial[i] / HARTREE);
     }
     let n = computed.extra.len();
     dst.put_u32_le(n as u32);
@@ -306,12 +367,14 @@ fn encode_client_computed(dst: &mut BytesMut, computed: &Computed) -> EncodedRes
 
 fn decode_client_computed(src: &mut BytesMut) -> Result<Computed, DecodeError> {
     let nheader = 12;
-    let msg = try_decode_message_header(src, nheader)?;
-    assert_eq!(msg, "FORCEREADY");
+    check_message_header(src, "FORCEREADY")?;
 
     // try to read natoms
     let nenergy = 8;
     let natoms = try_decode_length_header_u32(src, nheader + nenergy)?;
+    if natoms > MAX_NATOMS {
+        return Err(invalid_message(format!("too many atoms in FORCEREADY: {}", natoms)));
+    }
     let nforces = 3 * natoms * 8;
     let nviral = 9 * 8; // nine float numbers (f64)
     let nbytes_expected = 12 + 8 + 4 + nforces + nviral;
@@ -320,17 +383,17 @@ fn decode_client_computed(src: &mut BytesMut) -> Result<Computed, DecodeError> {
 
     // start reading message now
     src.advance(nheader);
-    let energy = src.get_f64_le() * Hartree;
+    let energy = src.get_f64_le() * HARTREE;
     let natoms = src.get_u32_le() as usize;
     let mut forces = vec![[0.0; 3]; natoms];
     for i in 0..natoms {
         for j in 0..3 {
-            forces[i][j] = src.get_f64_le() * Hartree / Bohr;
+            forces[i][j] = src.get_f64_le() * HARTREE / BOHR;
         }
     }
     let mut virial = [0.0; 9];
     for i in 0..9 {
-        virial[i] = src.get_f64_le() * Hartree;
+        virial[i] = src.get_f64_le() * HARTREE;
     }
     let nextra = src.get_u32_le();
     let bytes = src.copy_to_bytes(nextra as usize);
@@ -345,6 +408,22 @@ fn decode_client_computed(src: &mut BytesMut) -> Result<Computed, DecodeError> {
 
     Ok(computed.into())
 }
+
+#[test]
+fn test_ipi_computed() {
+    let computed = Computed {
+        energy: -1.5,
+        forces: vec![[0.1, 0.2, 0.3], [-0.1, -0.2, -0.3]],
+        virial: [0.1, 0.0, 0.0, 0.0, 0.2, 0.0, 0.0, 0.0, 0.3],
+        extra: "".into(),
+    };
+    let mut dest = BytesMut::new();
+    encode_client_computed(&mut dest, &computed).unwrap();
+    let decoded = decode_client_computed(&mut dest).unwrap();
+    assert_relative_eq!(decoded.energy, computed.energy, epsilon = 1e-8);
+    assert_relative_eq!(decoded.forces[1][2], computed.forces[1][2], epsilon = 1e-8);
+    assert_relative_eq!(decoded.virial[8], computed.virial[8], epsilon = 1e-8);
+}
 // client/compute done:1 ends here
 
 // [[file:../../vasp-tools.note::*pub/client][pub/client:1]]
@@ -352,7 +431,7 @@ pub struct ClientCodec;
 
 impl Decoder for ClientCodec {
     type Item = ClientMessage;
-    type Error = std::io::Error;
+    type Error = ProtocolError;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
         match try_decode_message_header(src, 12) {
@@ -365,7 +444,7 @@ impl Decoder for ClientCodec {
                     src.advance(12);
                     Ok(Some(ClientMessage::Status(ClientStatus::Ready)))
                 }
-                "HAVADATA" => {
+                "HAVEDATA" => {
                     src.advance(12);
                     Ok(Some(ClientMessage::Status(ClientStatus::HaveData)))
                 }
@@ -375,7 +454,7 @@ impl Decoder for ClientCodec {
                 },
                 _ => {
                     error!("invalid header: {}", header_str);
-                    todo!();
+                    Err(ProtocolError::InvalidHeader(header_str))
                 }
             },
             Err(e) => fix_decode_err(e),
@@ -384,22 +463,46 @@ impl Decoder for ClientCodec {
 }
 
 impl Encoder<ClientMessage> for ClientCodec {
-    type Error = std::io::Error;
+    type Error = ProtocolError;
 
     fn encode(&mut self, item: ClientMessage, dest: &mut BytesMut) -> Result<(), Self::Error> {
         match item {
-            ClientMessage::Status(status) => encode_client_status(dest, &status),
-            ClientMessage::ForceReady(computed) => encode_client_computed(dest, &computed),
+            ClientMessage::Status(status) => encode_client_status(dest, &status)?,
+            ClientMessage::ForceReady(computed) => encode_client_computed(dest, &computed)?,
         }
+        Ok(())
     }
 }
 // pub/client:1 ends here
 
 // [[file:../../vasp-tools.note::*pub/server][pub/server:1]]
-pub struct ServerCodec;
+/// The codec for messages sent from the server (driver). The element symbols
+/// parsed from INIT message will be applied for received structures.
+#[derive(Debug, Default)]
+pub struct ServerCodec {
+    symbols: Option<Vec<String>>,
+}
+
+impl ServerCodec {
+    /// Apply element `symbols` for structures received in POSDATA
+    pub fn with_symbols(symbols: Vec<String>) -> Self {
+        Self { symbols: symbols.into() }
+    }
+
+    fn apply_symbols(&self, mol: &mut Molecule) {
+        if let Some(symbols) = &self.symbols {
+            if symbols.len() == mol.natoms() {
+                mol.set_symbols(symbols.iter().map(|s| s.as_str()));
+            } else {
+                warn!("ignore element symbols: expect {} atoms, got {}", symbols.len(), mol.natoms());
+            }
+        }
+    }
+}
+
 impl Decoder for ServerCodec {
     type Item = ServerMessage;
-    type Error = std::io::Error;
+    type Error = ProtocolError;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
         match try_decode_message_header(src, 12) {
@@ -412,21 +515,32 @@ impl Decoder for ServerCodec {
                     src.advance(12);
                     Ok(Some(ServerMessage::GetForce))
                 }
-                "EXIT" | "STOP" => {
+                // different i-PI versions or drivers (ASE) send different
+                // messages for exit
+                "EXIT" | "STOP" | "QUIT" => {
                     src.advance(12);
                     Ok(Some(ServerMessage::Exit))
                 }
                 "INIT" => match decode_init(src) {
                     Err(e) => fix_decode_err(e),
-                    Ok(init_data) => Ok(Some(ServerMessage::Init(init_data))),
+                    Ok(init_data) => {
+                        if let Some(symbols) = init_data.symbols() {
+                            debug!("got {} element symbols from INIT", symbols.len());
+                            self.symbols = symbols.into();
+                        }
+                        Ok(Some(ServerMessage::Init(init_data)))
+                    }
                 },
                 "POSDATA" => match decode_posdata(src) {
                     Err(e) => fix_decode_err(e),
-                    Ok(mol) => Ok(Some(ServerMessage::PosData(mol))),
+                    Ok(mut mol) => {
+                        self.apply_symbols(&mut mol);
+                        Ok(Some(ServerMessage::PosData(mol)))
+                    }
                 },
                 _ => {
                     error!("invalid header: {}", header_str);
-                    todo!();
+                    Err(ProtocolError::InvalidHeader(header_str))
                 }
             },
             Err(e) => fix_decode_err(e),
@@ -435,16 +549,167 @@ impl Decoder for ServerCodec {
 }
 
 impl Encoder<ServerMessage> for ServerCodec {
-    type Error = std::io::Error;
+    type Error = ProtocolError;
 
     fn encode(&mut self, msg: ServerMessage, dest: &mut BytesMut) -> Result<(), Self::Error> {
         match msg {
-            ServerMessage::Status => encode_header(dest, "STATUS"),
-            ServerMessage::GetForce => encode_header(dest, "GETFORCE"),
-            ServerMessage::Exit => encode_header(dest, "EXIT"),
-            ServerMessage::Init(data) => encode_init(dest, data),
-            ServerMessage::PosData(mol) => encode_posdata(dest, &mol),
+            ServerMessage::Status => encode_header(dest, "STATUS")?,
+            ServerMessage::GetForce => encode_header(dest, "GETFORCE")?,
+            ServerMessage::Exit => encode_header(dest, "EXIT")?,
+            ServerMessage::Init(data) => encode_init(dest, data)?,
+            ServerMessage::PosData(mol) => encode_posdata(dest, &mol)?,
         }
+        Ok(())
     }
 }
 // pub/server:1 ends here
+
+// [[file:../../vasp-tools.note::5a9af1d1][5a9af1d1]]
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_molecule() -> Molecule {
+        let atoms = vec![Atom::new("C", [0.0, 0.0, 0.0]), Atom::new("O", [1.2, 0.1, 0.3])];
+        let mut mol = Molecule::from_atoms(atoms);
+        let mat = Matrix3f::from_row_slice(&[10.0, 0.0, 0.0, 0.0, 11.0, 0.0, 0.0, 0.0, 12.0]);
+        mol.set_lattice(Lattice::from_matrix(mat));
+        mol
+    }
+
+    fn server_messages() -> Vec<ServerMessage> {
+        vec![
+            ServerMessage::Status,
+            ServerMessage::GetForce,
+            ServerMessage::Exit,
+            ServerMessage::Init(InitData::new(1, "C O")),
+            ServerMessage::PosData(test_molecule()),
+        ]
+    }
+
+    fn client_messages() -> Vec<ClientMessage> {
+        let computed = Computed {
+            energy: -1.0,
+            forces: vec![[0.1, 0.2, 0.3], [0.3, 0.2, 0.1]],
+            virial: [0.0; 9],
+            extra: r#"{"nscf":3}"#.into(),
+        };
+        vec![
+            ClientMessage::Status(ClientStatus::NeedInit),
+            ClientMessage::Status(ClientStatus::Ready),
+            ClientMessage::Status(ClientStatus::HaveData),
+            ClientMessage::ForceReady(computed),
+        ]
+    }
+
+    #[test]
+    fn test_ipi_server_messages_roundtrip() {
+        for msg in server_messages() {
+            let mut buf = BytesMut::new();
+            ServerCodec::default().encode(msg.clone(), &mut buf).unwrap();
+            // incomplete frame should not be decoded
+            for n in 0..buf.len() {
+                let mut partial = BytesMut::from(&buf[..n]);
+                let decoded = ServerCodec::default().decode(&mut partial).unwrap();
+                assert!(decoded.is_none(), "{:?}: {}", msg, n);
+            }
+            let decoded = ServerCodec::default().decode(&mut buf).unwrap().unwrap();
+            assert!(buf.is_empty());
+            match (&msg, &decoded) {
+                (ServerMessage::Status, ServerMessage::Status) => {}
+                (ServerMessage::GetForce, ServerMessage::GetForce) => {}
+                (ServerMessage::Exit, ServerMessage::Exit) => {}
+                (ServerMessage::Init(a), ServerMessage::Init(b)) => {
+                    assert_eq!(a.ibead, b.ibead);
+                    assert_eq!(a.init, b.init);
+                }
+                (ServerMessage::PosData(a), ServerMessage::PosData(b)) => {
+                    assert_eq!(a.natoms(), b.natoms());
+                }
+                _ => panic!("message mismatch: {:?} vs {:?}", msg, decoded),
+            }
+        }
+    }
+
+    #[test]
+    fn test_ipi_client_messages_roundtrip() {
+        for msg in client_messages() {
+            let mut buf = BytesMut::new();
+            ClientCodec.encode(msg.clone(), &mut buf).unwrap();
+            for n in 0..buf.len() {
+                let mut partial = BytesMut::from(&buf[..n]);
+                assert!(ClientCodec.decode(&mut partial).unwrap().is_none());
+            }
+            let decoded = ClientCodec.decode(&mut buf).unwrap().unwrap();
+            assert!(buf.is_empty());
+            match (&msg, &decoded) {
+                (ClientMessage::Status(a), ClientMessage::Status(b)) => assert_eq!(a, b),
+                (ClientMessage::ForceReady(a), ClientMessage::ForceReady(b)) => {
+                    assert_eq!(a.forces.len(), b.forces.len());
+                    assert_eq!(a.extra, b.extra);
+                }
+                _ => panic!("message mismatch: {:?} vs {:?}", msg, decoded),
+            }
+        }
+    }
+
+    #[test]
+    fn test_ipi_exit_variants() {
+        for header in ["EXIT", "STOP", "QUIT"] {
+            let mut buf = BytesMut::new();
+            encode_header(&mut buf, header).unwrap();
+            let decoded = ServerCodec::default().decode(&mut buf).unwrap().unwrap();
+            assert!(matches!(decoded, ServerMessage::Exit));
+        }
+        // null padded header
+        let mut buf = BytesMut::from(&b"EXIT\0\0\0\0\0\0\0\0"[..]);
+        let decoded = ServerCodec::default().decode(&mut buf).unwrap().unwrap();
+        assert!(matches!(decoded, ServerMessage::Exit));
+    }
+
+    #[test]
+    fn test_ipi_invalid_header() {
+        let mut buf = BytesMut::from(&b"HELLO WORLD!"[..]);
+        assert!(ServerCodec::default().decode(&mut buf).is_err());
+        let mut buf = BytesMut::from(&b"HAVADATA    "[..]);
+        assert!(ClientCodec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_ipi_codec_fuzz() {
+        // simple deterministic pseudo random generator
+        let mut seed = 42_u64;
+        let mut rand = move || {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (seed >> 33) as u8
+        };
+
+        let mut frames: Vec<Vec<u8>> = vec![];
+        for msg in server_messages() {
+            let mut buf = BytesMut::new();
+            ServerCodec::default().encode(msg, &mut buf).unwrap();
+            frames.push(buf.to_vec());
+        }
+        for msg in client_messages() {
+            let mut buf = BytesMut::new();
+            ClientCodec.encode(msg, &mut buf).unwrap();
+            frames.push(buf.to_vec());
+        }
+
+        // corrupt valid frames randomly: decoders should never panic
+        for _ in 0..2000 {
+            let i = rand() as usize % frames.len();
+            let mut frame = frames[i].clone();
+            let n = 1 + rand() as usize % 4;
+            for _ in 0..n {
+                let j = (rand() as usize * 7919 + rand() as usize) % frame.len();
+                frame[j] = rand();
+            }
+            let mut buf = BytesMut::from(&frame[..]);
+            let _ = ServerCodec::default().decode(&mut buf);
+            let mut buf = BytesMut::from(&frame[..]);
+            let _ = ClientCodec.decode(&mut buf);
+        }
+    }
+}
+// 5a9af1d1 ends here
@@ -0,0 +1,107 @@
+// [[file:../../vasp-tools.note::fe582f7e][fe582f7e]]
+use super::*;
+
+use crate::bbm::{BbmDriver, Properties};
+use crate::interactive::{new_interactive_task_with_limits, TaskClient};
+use crate::process::ResourceLimits;
+use gosh::gchemol::prelude::*;
+// fe582f7e ends here
+
+// [[file:../../vasp-tools.note::83d91a35][83d91a35]]
+/// Interactive VASP calculation driven directly by `TaskClient`: positions
+/// are written into stdin of VASP, and energy and forces are parsed from its
+/// stdout, without calling BBM scripts for each step.
+pub struct VaspEngine {
+    task: TaskClient,
+    // the structure VASP started with, for checking structures of later
+    // steps
+    mol_ref: Option<Molecule>,
+    // the initial ionic velocities written into POSCAR
+    velocities: Option<Vec<[f64; 3]>>,
+    // the regex for reading VASP stdout
+    read_pattern: String,
+}
+
+impl VaspEngine {
+    /// Start VASP `program` in interactive mode in current directory, which
+    /// should contain INCAR, KPOINTS and POTCAR files. VASP stdout will be
+    /// read until a line matching `read_pattern` (regex).
+    pub fn start(program: &Path, limits: &ResourceLimits, read_pattern: &str) -> Result<Self> {
+        use crate::vasp::VaspTask;
+
+        crate::vasp::update_incar_for_bbm(&VaspTask::Interactive)?;
+        let (mut server, task) = new_interactive_task_with_limits(program, limits)?;
+        tokio::spawn(async move {
+            if let Err(e) = server.run_and_serve().await {
+                error!("interactive VASP server error: {:?}", e);
+            }
+        });
+
+        Ok(Self {
+            task,
+            mol_ref: None,
+            velocities: None,
+            read_pattern: read_pattern.into(),
+        })
+    }
+
+    /// Set initial ionic velocities (Cartesian, in Å/fs) for restarting MD
+    /// of VASP, which will be written into POSCAR for the first
+    /// calculation.
+    pub fn set_velocities(&mut self, velocities: Vec<[f64; 3]>) {
+        self.velocities = velocities.into();
+    }
+
+    /// Compute energy and forces of `mol` using running VASP. The stress
+    /// tensor will be read from OUTCAR if available.
+    pub async fn compute(&mut self, mol: &Molecule) -> Result<Properties> {
+        let input = if let Some(mol_ref) = &self.mol_ref {
+            crate::vasp::stdin::check_structure_consistency(mol, mol_ref)?;
+            crate::vasp::stdin::format_scaled_positions(mol)?
+        } else {
+            // for the first time run, VASP reads coordinates from POSCAR
+            debug!("Write complete POSCAR file for initial calculation.");
+            let mut txt = mol.format_as("vasp/input")?;
+            if let Some(velocities) = &self.velocities {
+                txt = crate::vasp::poscar::with_velocities(&txt, velocities)?;
+            }
+            gut::fs::write_to_file("POSCAR", &txt)?;
+            self.mol_ref = mol.clone().into();
+            String::new()
+        };
+        let out = self.task.interact(&input, &self.read_pattern).await?;
+
+        // NOTE: for larger system, there may have no energy/forces information in
+        // stdout
+        let (props, source) = crate::vasp::results::parse_last_results(&out, ".".as_ref(), Some(mol.natoms())).await?;
+        debug!("VASP results read from {:?}", source);
+
+        Ok(props)
+    }
+
+    /// Terminate running VASP.
+    pub async fn terminate(&self) -> Result<()> {
+        self.task.terminate().await
+    }
+}
+
+/// The engine computing energy and forces for i-PI client
+pub enum ForceEngine {
+    /// Calculation using BBM scripts
+    Bbm(BbmDriver),
+    /// Interactive VASP calculation
+    Vasp(VaspEngine),
+    /// Analytical potential for testing
+    Potential(crate::potential::Potential),
+}
+
+impl ForceEngine {
+    pub async fn compute(&mut self, mol: &Molecule) -> Result<Properties> {
+        match self {
+            Self::Bbm(bbm) => bbm.compute(mol).await,
+            Self::Vasp(vasp) => vasp.compute(mol).await,
+            Self::Potential(pot) => pot.compute(mol),
+        }
+    }
+}
+// 83d91a35 ends here
@@ -0,0 +1,208 @@
+// [[file:../../vasp-tools.note::f94ca889][f94ca889]]
+use super::*;
+
+use futures::{SinkExt, StreamExt};
+use gosh::gchemol::{Atom, Lattice};
+use tokio_util::codec::{FramedRead, FramedWrite};
+use vecfx::*;
+// f94ca889 ends here
+
+// [[file:../../vasp-tools.note::959f970d][959f970d]]
+/// Deterministic structures sent by fake i-PI driver: a CO molecule in a
+/// orthorhombic box, stretched a bit in each step.
+pub fn fake_structures(nsteps: usize) -> Vec<Molecule> {
+    (0..nsteps)
+        .map(|i| {
+            let d = 0.01 * i as f64;
+            let atoms = vec![Atom::new("C", [1.0, 2.0, 3.0]), Atom::new("O", [2.1 + d, 2.1, 3.2])];
+            let mut mol = Molecule::from_atoms(atoms);
+            let mat = Matrix3f::from_row_slice(&[10.0, 0.0, 0.0, 0.0, 11.0, 0.0, 0.0, 0.0, 12.0]);
+            mol.set_lattice(Lattice::from_matrix(mat));
+            mol
+        })
+        .collect()
+}
+
+/// Check the FORCEREADY data sent by client for `mol`.
+fn check_computed(mol: &Molecule, computed: &Computed) -> Result<()> {
+    ensure!(computed.energy().is_finite(), "invalid energy: {}", computed.energy());
+    let forces = computed.forces();
+    ensure!(
+        forces.len() == mol.natoms(),
+        "expect forces of {} atoms, got {}",
+        mol.natoms(),
+        forces.len()
+    );
+    ensure!(
+        forces.iter().flatten().all(|x| x.is_finite()),
+        "invalid forces: {:?}",
+        forces
+    );
+    ensure!(computed.virial().iter().all(|x| x.is_finite()), "invalid virial");
+    // ASE accepts anything in extra bytes, but we expect empty or JSON
+    let extra = computed.extra().trim_matches(|c: char| c == '\0' || c.is_whitespace());
+    if !extra.is_empty() {
+        serde_json::from_str::<serde_json::Value>(extra)
+            .with_context(|| format!("extra string is not in JSON: {:?}", extra))?;
+    }
+    Ok(())
+}
+
+/// Drive i-PI client over `stream` as ASE's SocketIOCalculator does: for each
+/// structure, ask for status, send INIT if required, send POSDATA, and fetch
+/// the forces when client reports HAVEDATA. EXIT will be sent at the end.
+/// Return error if client responds unexpectedly.
+async fn drive_as_ase<S>(stream: S, mols: &[Molecule]) -> Result<Vec<Computed>>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite,
+{
+    let (read, write) = tokio::io::split(stream);
+    let mut client_read = FramedRead::new(read, codec::ClientCodec);
+    let mut server_write = FramedWrite::new(write, codec::ServerCodec::default());
+
+    let mut all_computed = vec![];
+    for (i, mol) in mols.iter().enumerate() {
+        info!("fake i-PI driver: step {}", i);
+        let mut status = ask_status(&mut server_write, &mut client_read).await?;
+        if status == ClientStatus::NeedInit {
+            // NOTE: ASE sends one NUL byte as the init string
+            server_write.send(ServerMessage::Init(InitData::new(0, "\0"))).await?;
+            status = ask_status(&mut server_write, &mut client_read).await?;
+        }
+        ensure!(status == ClientStatus::Ready, "step {}: expect READY, got {:?}", i, status);
+        server_write.send(ServerMessage::PosData(mol.clone())).await?;
+        let status = ask_status(&mut server_write, &mut client_read).await?;
+        ensure!(status == ClientStatus::HaveData, "step {}: expect HAVEDATA, got {:?}", i, status);
+        server_write.send(ServerMessage::GetForce).await?;
+        match next_message(&mut client_read).await? {
+            ClientMessage::ForceReady(computed) => {
+                check_computed(mol, &computed).with_context(|| format!("step {}", i))?;
+                println!("step {:4}: energy = {:-18.8} eV", i, computed.energy());
+                all_computed.push(computed);
+            }
+            msg => bail!("step {}: expect FORCEREADY, got {:?}", i, msg),
+        }
+    }
+    server_write.send(ServerMessage::Exit).await?;
+
+    Ok(all_computed)
+}
+
+async fn next_message<R>(client_read: &mut FramedRead<R, codec::ClientCodec>) -> Result<ClientMessage>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    let msg = client_read.next().await.ok_or(format_err!("i-PI client disconnected"))??;
+    Ok(msg)
+}
+
+async fn ask_status<R, W>(
+    server_write: &mut FramedWrite<W, codec::ServerCodec>,
+    client_read: &mut FramedRead<R, codec::ClientCodec>,
+) -> Result<ClientStatus>
+where
+    R: tokio::io::AsyncRead + Unpin,
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    server_write.send(ServerMessage::Status).await?;
+    match next_message(client_read).await? {
+        ClientMessage::Status(status) => Ok(status),
+        msg => bail!("expect client status, got {:?}", msg),
+    }
+}
+
+/// Run a fake i-PI driver at `endpoint` sending `nsteps` deterministic
+/// structures to the first connected client, and check its responses. This
+/// is useful for testing i-PI clients without a real i-PI or ASE installation.
+pub async fn fake_ipi_driver(endpoint: &Endpoint, nsteps: usize) -> Result<()> {
+    use tokio::net::{TcpListener, UnixListener};
+
+    let mols = fake_structures(nsteps);
+    info!("fake i-PI driver: listening on {}", endpoint);
+    match endpoint {
+        Endpoint::Unix(path) => {
+            let listener = UnixListener::bind(path).with_context(|| format!("bind unix socket {:?}", path))?;
+            let (stream, _) = listener.accept().await.context("accept new unix socket client")?;
+            let r = drive_as_ase(stream, &mols).await;
+            let _ = std::fs::remove_file(path);
+            r?;
+        }
+        Endpoint::Inet(addr) => {
+            let listener = TcpListener::bind(addr).await.with_context(|| format!("bind {:?}", addr))?;
+            let (stream, _) = listener.accept().await.context("accept new tcp client")?;
+            drive_as_ase(stream, &mols).await?;
+        }
+    }
+    info!("fake i-PI driver: all {} steps passed.", nsteps);
+
+    Ok(())
+}
+// 959f970d ends here
+
+// [[file:../../vasp-tools.note::c8a5657a][c8a5657a]]
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // A minimal i-PI client: the energy is the x coordinate of the last atom
+    // in Å, which checks the unit conversion in both directions.
+    async fn serve_fake_client<S>(stream: S) -> Result<usize>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite,
+    {
+        let (read, write) = tokio::io::split(stream);
+        let mut server_read = FramedRead::new(read, codec::ServerCodec::default());
+        let mut client_write = FramedWrite::new(write, codec::ClientCodec);
+
+        let mut initialized = false;
+        let mut mol_to_compute: Option<Molecule> = None;
+        let mut ncomputed = 0;
+        while let Some(msg) = server_read.next().await {
+            match msg? {
+                ServerMessage::Status => {
+                    let status = if !initialized {
+                        ClientStatus::NeedInit
+                    } else if mol_to_compute.is_none() {
+                        ClientStatus::Ready
+                    } else {
+                        ClientStatus::HaveData
+                    };
+                    client_write.send(ClientMessage::Status(status)).await?;
+                }
+                ServerMessage::Init(_) => initialized = true,
+                ServerMessage::PosData(mol) => mol_to_compute = Some(mol),
+                ServerMessage::GetForce => {
+                    let mol = mol_to_compute.take().context("no mol")?;
+                    let [x, _, _] = mol.positions().last().unwrap();
+                    let computed = Computed {
+                        energy: x,
+                        forces: vec![[0.1, 0.2, 0.3]; mol.natoms()],
+                        virial: [0.0; 9],
+                        extra: "".into(),
+                    };
+                    client_write.send(ClientMessage::ForceReady(computed)).await?;
+                    ncomputed += 1;
+                }
+                ServerMessage::Exit => break,
+            }
+        }
+        Ok(ncomputed)
+    }
+
+    #[tokio::test]
+    async fn test_fake_ipi_driver() -> Result<()> {
+        let (server, client) = tokio::io::duplex(1024);
+        let mols = fake_structures(3);
+        let h = tokio::spawn(serve_fake_client(client));
+        let all_computed = drive_as_ase(server, &mols).await?;
+        assert_eq!(h.await??, 3);
+        for (mol, computed) in mols.iter().zip(&all_computed) {
+            let [x, _, _] = mol.positions().last().unwrap();
+            assert_relative_eq!(computed.energy(), x, epsilon = 1e-8);
+            assert_relative_eq!(computed.forces()[0][2], 0.3, epsilon = 1e-8);
+        }
+
+        Ok(())
+    }
+}
+// c8a5657a ends here
@@ -0,0 +1,341 @@
+// [[file:../vasp-tools.note::*docs][docs:1]]
+//! JSON-RPC 2.0 transport of the interactive VASP server over TCP, for
+//! clients in other languages (e.g. Python workflow tools) without
+//! implementing the binary codec of unix socket. Available with the
+//! `jsonrpc` cargo feature.
+//!
+//! Each request or response is one line of JSON. Available methods:
+//!
+//! * interact: `{"input": "...", "read_pattern": "...", "engine": "..."}`,
+//!   returns `{"output": "..."}`. `read_pattern` defaults to the VASP one,
+//!   and `engine` defaults to the default engine. Set `"stdout_only": true`
+//!   if results are read only from output, so the server may pre-compute
+//!   the next geometry with `--speculate`.
+//! * interact_batch: `{"inputs": ["...", "..."], "read_pattern": "...",
+//!   "engine": "..."}`, runs the interactions back-to-back and returns
+//!   `{"outputs": ["...", "..."]}`
+//! * finite_diff: `{"positions": "...", "atoms": [1, 2], "step": 0.015,
+//!   "dipole": false, "engine": "..."}`, displaces atoms of the structure in
+//!   scaled `positions` on server side, returns `{"atoms": [...], "energy":
+//!   ..., "hessian_rows": [[...]], "dipole_derivatives": null}`
+//! * control: `{"signal": "pause" | "resume" | "interrupt" | "quit", "engine": "..."}`
+//! * set_profile: `{"profile": "fast" | "accurate", "engine": "..."}`,
+//!   updates INCAR with tags of the profile and restarts VASP, which takes
+//!   effect from the next interaction
+//! * upload: `{"name": "POSCAR", "content": "...", "size": 123, "sha256":
+//!   "...", "engine": "..."}`, writes input file into working directory of
+//!   engine after verifying its size and checksum, returns `{"sha256": "..."}`
+//! * download: `{"name": "OUTCAR", "engine": "..."}`, returns `{"name": "...",
+//!   "content": "...", "size": 123, "sha256": "..."}`
+//! * cancel: stops VASP gracefully using STOPCAR after the current
+//!   interaction and shuts down the server, as touching a `CANCEL` file in
+//!   working directory
+//! * status: returns server state, counters and hosted engines
+//!
+//! ```text
+//! $ echo '{"jsonrpc": "2.0", "id": 1, "method": "status"}' | nc -q 1 localhost 9200
+//! ```
+//!
+//! The server only binds to loopback addresses unless a token is set, which
+//! every request then carries as a top-level `"token"` member. Lines longer
+//! than `MAX_LINE_BYTES` are rejected, closing the connection.
+// docs:1 ends here
+
+// [[file:../vasp-tools.note::370dafab][370dafab]]
+use super::*;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+// 370dafab ends here
+
+// [[file:../vasp-tools.note::f93e7049][f93e7049]]
+/// Invalid JSON was received
+pub const PARSE_ERROR: i64 = -32700;
+/// The JSON sent is not a valid request object
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+/// The interaction is rejected as the server queue is full
+pub const SERVER_BUSY: i64 = -32000;
+/// VASP exited or failed during interaction
+pub const INTERACTION_FAILED: i64 = -32001;
+/// The file is not allowed, missing or corrupted in transfer
+pub const TRANSFER_FAILED: i64 = -32002;
+/// The positions are rejected as unphysical geometry
+pub const INVALID_GEOMETRY: i64 = -32003;
+/// The server is cancelled and shutting down
+pub const CANCELLED: i64 = -32004;
+/// The request carries no token or a wrong one
+pub const UNAUTHORIZED: i64 = -32005;
+
+/// The max length of one request line in bytes
+pub const MAX_LINE_BYTES: usize = 16 << 20;
+
+/// The request object. Notification (request without id) gets no
+/// response.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Request {
+    pub jsonrpc: String,
+    #[serde(default)]
+    pub id: Option<Value>,
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+    /// The token required by server (an extension to JSON-RPC 2.0)
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+/// The response object
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Response {
+    pub jsonrpc: String,
+    pub id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<RpcError>,
+}
+
+impl Response {
+    pub fn result(id: Value, result: Value) -> Self {
+        Self {
+            jsonrpc: "2.0".into(),
+            id,
+            result: result.into(),
+            error: None,
+        }
+    }
+
+    pub fn error(id: Value, code: i64, message: impl Into<String>) -> Self {
+        let error = RpcError {
+            code,
+            message: message.into(),
+        };
+        Self {
+            jsonrpc: "2.0".into(),
+            id,
+            result: None,
+            error: error.into(),
+        }
+    }
+
+    /// Encode as one line of JSON.
+    pub fn to_line(&self) -> String {
+        let mut line = serde_json::to_string(self).expect("response json");
+        line.push('\n');
+        line
+    }
+}
+
+/// Parse request from one `line` of JSON. Return the error response if
+/// invalid.
+pub fn parse_request(line: &str) -> std::result::Result<Request, Response> {
+    let value: Value = serde_json::from_str(line).map_err(|e| Response::error(Value::Null, PARSE_ERROR, e.to_string()))?;
+    let id = value.get("id").cloned().unwrap_or(Value::Null);
+    let req: Request =
+        serde_json::from_value(value).map_err(|e| Response::error(id.clone(), INVALID_REQUEST, e.to_string()))?;
+    if req.jsonrpc != "2.0" {
+        return Err(Response::error(id, INVALID_REQUEST, "only JSON-RPC 2.0 is supported"));
+    }
+    Ok(req)
+}
+
+/// Check `token` of `req` against the one required by server. Return the
+/// error response if not matched.
+pub fn check_token(req: &Request, token: Option<&str>) -> std::result::Result<(), Response> {
+    match token {
+        Some(token) if req.token.as_deref() != Some(token) => {
+            let id = req.id.clone().unwrap_or_default();
+            Err(Response::error(id, UNAUTHORIZED, "missing or invalid token"))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Refuse to serve at `addr` accessible from other hosts, unless requests
+/// are authenticated with a token.
+pub fn check_bind_addr(addr: &std::net::SocketAddr, token: Option<&str>) -> Result<()> {
+    // NOTE: unlike the socket file, TCP port is not protected by file
+    // permissions
+    ensure!(
+        addr.ip().is_loopback() || token.is_some(),
+        "refuse to serve JSON-RPC at {} accessible from other hosts without a token",
+        addr
+    );
+    Ok(())
+}
+
+/// Read one line from `reader` of at most `limit` bytes, including the
+/// trailing newline. Return None at the end of stream.
+pub async fn read_line<R>(reader: &mut R, limit: usize) -> Result<Option<String>>
+where
+    R: tokio::io::AsyncBufRead + std::marker::Unpin,
+{
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt};
+
+    let mut buf = vec![];
+    let n = reader.take(limit as u64).read_until(b'\n', &mut buf).await?;
+    if n == 0 {
+        return Ok(None);
+    }
+    ensure!(n < limit || buf.ends_with(b"\n"), "request line longer than {} bytes", limit);
+    let line = String::from_utf8(buf).context("request line is not UTF-8")?;
+    Ok(Some(line))
+}
+
+/// Parse `params` of method into `T`, or return the error response for
+/// request `id`.
+pub fn parse_params<T: serde::de::DeserializeOwned>(id: &Value, params: &Value) -> std::result::Result<T, Response> {
+    // NOTE: omitted params are treated as empty object
+    let params = if params.is_null() { serde_json::json!({}) } else { params.clone() };
+    serde_json::from_value(params).map_err(|e| Response::error(id.clone(), INVALID_PARAMS, e.to_string()))
+}
+
+fn default_read_pattern() -> String {
+    crate::vasp::stdout::VASP_READ_PATTERN.into()
+}
+
+/// The parameters of "interact" method
+#[derive(Debug, Clone, Deserialize)]
+pub struct InteractParams {
+    /// The text written into stdin of VASP
+    #[serde(default)]
+    pub input: String,
+    /// The regex for reading VASP stdout
+    #[serde(default = "default_read_pattern")]
+    pub read_pattern: String,
+    /// The routing key of engine
+    pub engine: Option<String>,
+    /// Results are read only from `output`, never from files in working
+    /// directory of engine, which allows speculative pre-computation
+    #[serde(default)]
+    pub stdout_only: bool,
+}
+
+/// The parameters of "interact_batch" method
+#[derive(Debug, Clone, Deserialize)]
+pub struct InteractBatchParams {
+    /// The text written into stdin of VASP for each interaction
+    pub inputs: Vec<String>,
+    /// The regex for reading VASP stdout
+    #[serde(default = "default_read_pattern")]
+    pub read_pattern: String,
+    /// The routing key of engine
+    pub engine: Option<String>,
+}
+
+/// The parameters of "finite_diff" method
+#[derive(Debug, Clone, Deserialize)]
+pub struct FiniteDiffParams {
+    #[serde(flatten)]
+    pub request: crate::hessian::FiniteDiffRequest,
+    /// The routing key of engine
+    pub engine: Option<String>,
+}
+
+/// The parameters of "control" method
+#[derive(Debug, Clone, Deserialize)]
+pub struct ControlParams {
+    /// pause, resume, interrupt or quit
+    pub signal: String,
+    /// The routing key of engine
+    pub engine: Option<String>,
+}
+
+/// The parameters of "set_profile" method
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProfileParams {
+    /// fast or accurate
+    pub profile: String,
+    /// The routing key of engine
+    pub engine: Option<String>,
+}
+
+/// The parameters of "upload" method
+#[derive(Debug, Clone, Deserialize)]
+pub struct UploadParams {
+    /// The file name in working directory, e.g. POSCAR or INCAR
+    pub name: String,
+    /// The text of file
+    pub content: String,
+    /// The size of file in bytes
+    pub size: u64,
+    /// The SHA-256 checksum of file in hex
+    pub sha256: String,
+    /// The routing key of engine
+    pub engine: Option<String>,
+}
+
+/// The parameters of "download" method
+#[derive(Debug, Clone, Deserialize)]
+pub struct DownloadParams {
+    /// The file name in working directory, e.g. OUTCAR or CONTCAR
+    pub name: String,
+    /// The routing key of engine
+    pub engine: Option<String>,
+}
+// f93e7049 ends here
+
+// [[file:../vasp-tools.note::c23adee6][c23adee6]]
+#[test]
+fn test_jsonrpc() -> Result<()> {
+    let req = parse_request(r#"{"jsonrpc": "2.0", "id": 1, "method": "interact", "params": {"input": "0 0 0\n"}}"#).unwrap();
+    assert_eq!(req.method, "interact");
+    assert_eq!(req.id, Some(1.into()));
+    let params: InteractParams = parse_params(&1.into(), &req.params).unwrap();
+    assert_eq!(params.input, "0 0 0\n");
+    assert_eq!(params.read_pattern, crate::vasp::stdout::VASP_READ_PATTERN);
+    assert_eq!(params.engine, None);
+
+    let req = parse_request(r#"{"jsonrpc": "2.0", "method": "status"}"#).unwrap();
+    assert_eq!(req.id, None);
+    let resp = parse_request(r#"{"jsonrpc": "2.0", "id": 3}"#).unwrap_err();
+    assert_eq!(resp.id, 3.into());
+    assert_eq!(resp.error.unwrap().code, INVALID_REQUEST);
+    let resp = parse_request("{").unwrap_err();
+    assert_eq!(resp.error.unwrap().code, PARSE_ERROR);
+    let resp = parse_params::<ControlParams>(&2.into(), &Value::Null).unwrap_err();
+    assert_eq!(resp.error.unwrap().code, INVALID_PARAMS);
+    let params = serde_json::json!({"name": "POSCAR", "content": "H2O\n", "size": 4, "sha256": "ab"});
+    let params: UploadParams = parse_params(&4.into(), &params).unwrap();
+    assert_eq!(params.size, 4);
+    let params = serde_json::json!({"name": "POSCAR", "content": "H2O\n"});
+    assert!(parse_params::<UploadParams>(&4.into(), &params).is_err());
+
+    let line = Response::result(1.into(), serde_json::json!({"output": "ok"})).to_line();
+    assert_eq!(line, "{\"jsonrpc\":\"2.0\",\"id\":1,\"result\":{\"output\":\"ok\"}}\n");
+
+    // authentication
+    let req = parse_request(r#"{"jsonrpc": "2.0", "id": 5, "method": "status", "token": "s3cret"}"#).unwrap();
+    assert!(check_token(&req, None).is_ok());
+    assert!(check_token(&req, Some("s3cret")).is_ok());
+    let resp = check_token(&req, Some("other")).unwrap_err();
+    assert_eq!(resp.error.unwrap().code, UNAUTHORIZED);
+    assert!(check_bind_addr(&"127.0.0.1:9200".parse()?, None).is_ok());
+    assert!(check_bind_addr(&"0.0.0.0:9200".parse()?, None).is_err());
+    assert!(check_bind_addr(&"0.0.0.0:9200".parse()?, Some("s3cret")).is_ok());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_jsonrpc_read_line() -> Result<()> {
+    let mut r = "short\nvery long line\nlast".as_bytes();
+    assert_eq!(read_line(&mut r, 8).await?.as_deref(), Some("short\n"));
+    assert!(read_line(&mut r, 8).await.is_err());
+
+    let mut r = "short\nlast".as_bytes();
+    read_line(&mut r, 8).await?;
+    assert_eq!(read_line(&mut r, 8).await?.as_deref(), Some("last"));
+    assert_eq!(read_line(&mut r, 8).await?, None);
+
+    Ok(())
+}
+// c23adee6 ends here
@@ -7,76 +7,76 @@ extern crate approx; // For the macro relative_eq!
                      // imports:1 ends here
 
 // [[file:../vasp-tools.note::a397a097][a397a097]]
+mod bbm;
+pub mod capi;
 pub mod cli;
+mod constraint;
+mod dimer;
+mod hessian;
+mod hooks;
 mod interactive;
+mod ipi;
+#[cfg(feature = "jsonrpc")]
+mod jsonrpc;
+mod logging;
+mod metrics;
+mod neb;
+mod optim;
 mod plot;
+mod pool;
+mod potential;
+mod process;
+mod registry;
+mod scan;
+mod scheduler;
+mod session;
 mod socket;
+mod speculate;
+mod systemd;
+mod trajectory;
+pub mod units;
+pub mod utils;
 mod vasp;
-
-mod session {
-    use super::*;
-
-    pub use gosh::runner::interactive::InteractiveSession as Session;
-    pub use gosh::runner::process::SessionHandler;
-
-    #[test]
-    fn test_interactive_vasp() -> Result<()> {
-        let read_pattern = "POSITIONS: reading from stdin";
-
-        // the input for writing into stdin
-        let positions = include_str!("../tests/files/interactive_positions.txt");
-
-        let vasp = std::process::Command::new("fake-vasp");
-        let mut s = Session::new(vasp);
-        let h = s.spawn()?;
-
-        let o = s.interact("", read_pattern)?;
-        let _ = crate::vasp::stdout::parse_energy_and_forces(&o)?;
-        let o = s.interact(&positions, read_pattern)?;
-        let (energy2, _forces2) = crate::vasp::stdout::parse_energy_and_forces(&o)?;
-        assert_eq!(energy2, 2.0);
-        let o = s.interact(&positions, read_pattern)?;
-        let (energy3, _forces3) = crate::vasp::stdout::parse_energy_and_forces(&o)?;
-        assert_eq!(energy3, 3.0);
-
-        h.terminate()?;
-
-        Ok(())
-    }
-}
 // a397a097 ends here
 
 // [[file:../vasp-tools.note::57018756][57018756]]
 use gut::prelude::*;
 
 use crate::session::*;
+use crate::utils::wait_file;
 use crate::vasp::VaspOutcar;
+// 57018756 ends here
 
-/// Wait until file `f` available for max time of `timeout`.
+// [[file:../vasp-tools.note::4da92fbf][4da92fbf]]
+/// The public API for embedding vasp-tools as a library, e.g. driving
+/// interactive VASP from an optimizer without calling the binaries.
 ///
-/// # Parameters
-/// * timeout: timeout in seconds
-/// * f: the file to wait for available
-fn wait_file(f: &Path, timeout: usize) -> Result<()> {
-    use gut::utils::sleep;
-
-    // wait a moment for socke file ready
-    let interval = 0.1;
-    let mut t = 0.0;
-    loop {
-        if f.exists() {
-            trace!("Elapsed time during waiting: {:.2} seconds ", t);
-            return Ok(());
-        }
-        t += interval;
-        sleep(interval);
-
-        if t > timeout as f64 {
-            bail!("file {:?} doest exist for {} seconds", f, timeout);
-        }
-    }
+/// # Example
+///
+/// ```ignore
+/// use vasp_tools::prelude::*;
+///
+/// let (mut server, mut client) = new_interactive_task("vasp".as_ref());
+/// tokio::spawn(async move { server.run_and_serve().await });
+/// let out = client.interact("", VASP_READ_PATTERN).await?;
+/// let (props, source) = parse_last_results(&out, ".".as_ref(), None).await?;
+/// ```
+pub mod prelude {
+    pub use crate::bbm::{BbmDriver, Properties};
+    pub use crate::interactive::{new_interactive_task, new_interactive_task_with_limits, TaskClient, TaskServer};
+    pub use crate::ipi::{vasp_as_ipi_client, Endpoint, ForceEngine, IpiClientOptions, ReconnectOptions};
+    pub use crate::ipi::{VaspEngine, VaspEngineOptions};
+    pub use crate::process::ResourceLimits;
+    pub use crate::session::{join_read_patterns, ChildExited, Session, SessionHandler};
+    pub use crate::socket::{Client, Server, ServerBusy};
+    pub use crate::units;
+    pub use crate::vasp::results::{parse_last_results, ParseResultsError, ResultsSource};
+    pub use crate::vasp::stdin::format_scaled_positions;
+    pub use crate::vasp::outcar::{Energies, EnergyKind};
+    pub use crate::vasp::stdout::{parse_energies_and_forces, parse_energy_and_forces, VASP_READ_PATTERN};
+    pub use crate::vasp::VaspOutcar;
 }
-// 57018756 ends here
+// 4da92fbf ends here
 
 // [[file:../vasp-tools.note::242ad86a][242ad86a]]
 #[cfg(feature = "adhoc")]
@@ -90,10 +90,13 @@ pub mod docs {
         };
     }
 
+    export_doc!(bbm);
     export_doc!(interactive);
+    export_doc!(ipi);
     export_doc!(session);
     export_doc!(socket);
     export_doc!(vasp);
     export_doc!(plot);
+    export_doc!(process);
 }
 // 242ad86a ends here
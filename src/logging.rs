@@ -0,0 +1,49 @@
+// [[file:../vasp-tools.note::*docs][docs:1]]
+//! Structured logs of tracing spans (client, interaction, task) in JSON
+// docs:1 ends here
+
+// [[file:../vasp-tools.note::52a80ea1][52a80ea1]]
+use super::*;
+// 52a80ea1 ends here
+
+// [[file:../vasp-tools.note::0c7de09a][0c7de09a]]
+/// Write tracing events with their spans as JSON lines into `file`, in
+/// addition to the usual logs in terminal. Each line carries the ids of
+/// client, interaction job and task, so logs of multiple clients can be
+/// filtered without correlating interleaved text lines.
+///
+/// Records from `log` macros are bridged into tracing events, so this
+/// replaces the terminal logger, with the level set by RUST_LOG (info by
+/// default).
+pub fn setup_json_log(file: &Path) -> Result<()> {
+    use tracing_subscriber::filter::LevelFilter;
+    use tracing_subscriber::prelude::*;
+
+    let fp = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(file)
+        .with_context(|| format!("open log file {:?}", file))?;
+    let level = std::env::var("RUST_LOG")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(LevelFilter::INFO);
+    let terminal = tracing_subscriber::fmt::layer()
+        .with_writer(std::io::stderr)
+        .with_filter(level);
+    let json = tracing_subscriber::fmt::layer()
+        .json()
+        .with_current_span(true)
+        .with_span_list(true)
+        .with_writer(std::sync::Mutex::new(fp))
+        .with_filter(LevelFilter::DEBUG);
+    // NOTE: `try_init` also installs `LogTracer` for `log` records
+    tracing_subscriber::registry()
+        .with(terminal)
+        .with(json)
+        .try_init()
+        .context("set global tracing subscriber")?;
+
+    Ok(())
+}
+// 0c7de09a ends here
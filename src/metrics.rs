@@ -0,0 +1,255 @@
+// [[file:../vasp-tools.note::*docs][docs:1]]
+//! Prometheus metrics of the interactive VASP server
+// docs:1 ends here
+
+// [[file:../vasp-tools.note::8b421c32][8b421c32]]
+use super::*;
+
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::Mutex;
+// 8b421c32 ends here
+
+// [[file:../vasp-tools.note::ff9cb6d1][ff9cb6d1]]
+/// The upper bounds (in seconds) of histogram buckets for step durations
+const DURATION_BUCKETS: &[f64] = &[1.0, 10.0, 60.0, 300.0, 1800.0, 3600.0, 14400.0];
+
+/// The state of the interactive server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerState {
+    Idle = 0,
+    Running = 1,
+    Paused = 2,
+    Stopped = 3,
+}
+
+impl ServerState {
+    const ALL: [ServerState; 4] = [Self::Idle, Self::Running, Self::Paused, Self::Stopped];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Idle => "idle",
+            Self::Running => "running",
+            Self::Paused => "paused",
+            Self::Stopped => "stopped",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Durations {
+    // cumulative counts for each bucket in `DURATION_BUCKETS`
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+    last: f64,
+}
+
+/// The current state and counters of the interactive server
+#[cfg(feature = "jsonrpc")]
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct MetricsStatus {
+    /// idle, running, paused or stopped
+    pub state: &'static str,
+    pub interactions: u64,
+    pub failures: u64,
+    pub rejections: u64,
+    pub child_exits: u64,
+    /// The duration of last interaction in seconds
+    pub last_duration: f64,
+}
+
+/// Counters and gauges of the interactive server, shared by all clients.
+/// If a textfile is set, metrics will be written into it on each update,
+/// for the textfile collector of node exporter.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    interactions: AtomicU64,
+    failures: AtomicU64,
+    rejections: AtomicU64,
+    child_exits: AtomicU64,
+    state: AtomicU8,
+    durations: Mutex<Durations>,
+    textfile: Option<PathBuf>,
+}
+
+impl Metrics {
+    pub fn new(textfile: Option<PathBuf>) -> Self {
+        Self {
+            textfile,
+            ..Default::default()
+        }
+    }
+
+    /// Record an interaction finished in `secs` seconds. If `ok` is false,
+    /// the child process exited during the interaction.
+    pub fn interaction_done(&self, secs: f64, ok: bool) {
+        self.interactions.fetch_add(1, Ordering::SeqCst);
+        if ok {
+            let mut d = self.durations.lock().unwrap();
+            if d.buckets.is_empty() {
+                d.buckets = vec![0; DURATION_BUCKETS.len()];
+            }
+            for (i, &le) in DURATION_BUCKETS.iter().enumerate() {
+                if secs <= le {
+                    d.buckets[i] += 1;
+                }
+            }
+            d.sum += secs;
+            d.count += 1;
+            d.last = secs;
+        } else {
+            self.failures.fetch_add(1, Ordering::SeqCst);
+            self.child_exits.fetch_add(1, Ordering::SeqCst);
+        }
+        self.set_state(if ok { ServerState::Idle } else { ServerState::Stopped });
+    }
+
+    /// Record an interaction rejected as server is busy
+    pub fn interaction_rejected(&self) {
+        self.rejections.fetch_add(1, Ordering::SeqCst);
+        self.update_textfile();
+    }
+
+    pub fn set_state(&self, state: ServerState) {
+        self.state.store(state as u8, Ordering::SeqCst);
+        self.update_textfile();
+    }
+
+    /// The number of unexpected exits of VASP process
+    pub fn child_exits(&self) -> u64 {
+        self.child_exits.load(Ordering::SeqCst)
+    }
+
+    fn state(&self) -> ServerState {
+        let s = self.state.load(Ordering::SeqCst);
+        ServerState::ALL.into_iter().find(|x| *x as u8 == s).unwrap_or(ServerState::Idle)
+    }
+
+    /// Return current state and counters, e.g. for status queries of
+    /// clients
+    #[cfg(feature = "jsonrpc")]
+    pub fn status(&self) -> MetricsStatus {
+        MetricsStatus {
+            state: self.state().name(),
+            interactions: self.interactions.load(Ordering::SeqCst),
+            failures: self.failures.load(Ordering::SeqCst),
+            rejections: self.rejections.load(Ordering::SeqCst),
+            child_exits: self.child_exits.load(Ordering::SeqCst),
+            last_duration: self.durations.lock().unwrap().last,
+        }
+    }
+
+    /// Render metrics in Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut txt = String::new();
+        let mut counter = |name: &str, help: &str, v: u64| {
+            txt += &format!("# HELP {name} {help}\n# TYPE {name} counter\n{name} {v}\n");
+        };
+        counter(
+            "vasp_tools_interactions_total",
+            "Interactions served.",
+            self.interactions.load(Ordering::SeqCst),
+        );
+        counter(
+            "vasp_tools_interaction_failures_total",
+            "Interactions failed.",
+            self.failures.load(Ordering::SeqCst),
+        );
+        counter(
+            "vasp_tools_interaction_rejections_total",
+            "Interactions rejected as server busy.",
+            self.rejections.load(Ordering::SeqCst),
+        );
+        counter(
+            "vasp_tools_child_exits_total",
+            "Unexpected exits of VASP process.",
+            self.child_exits.load(Ordering::SeqCst),
+        );
+
+        let state = self.state();
+        txt += "# HELP vasp_tools_state Current state of the server.\n# TYPE vasp_tools_state gauge\n";
+        for s in ServerState::ALL {
+            txt += &format!("vasp_tools_state{{state=\"{}\"}} {}\n", s.name(), (s == state) as u8);
+        }
+
+        let d = self.durations.lock().unwrap();
+        let name = "vasp_tools_step_duration_seconds";
+        txt += &format!("# HELP {name} Durations of interactions.\n# TYPE {name} histogram\n");
+        for (i, le) in DURATION_BUCKETS.iter().enumerate() {
+            let n = d.buckets.get(i).copied().unwrap_or(0);
+            txt += &format!("{name}_bucket{{le=\"{le}\"}} {n}\n");
+        }
+        txt += &format!("{name}_bucket{{le=\"+Inf\"}} {}\n", d.count);
+        txt += &format!("{name}_sum {}\n{name}_count {}\n", d.sum, d.count);
+        let name = "vasp_tools_last_step_duration_seconds";
+        txt += &format!("# HELP {name} Duration of last interaction.\n# TYPE {name} gauge\n{name} {}\n", d.last);
+
+        txt
+    }
+
+    /// Write metrics into textfile atomically.
+    fn update_textfile(&self) {
+        if let Some(f) = &self.textfile {
+            let tmp = f.with_extension("prom.tmp");
+            let res = gut::fs::write_to_file(&tmp, &self.render()).and_then(|_| Ok(std::fs::rename(&tmp, f)?));
+            if let Err(e) = res {
+                warn!("failed to write metrics into {:?}: {:?}", f, e);
+            }
+        }
+    }
+}
+
+/// Serve `metrics` over HTTP at `addr` (e.g. "0.0.0.0:9100") for
+/// Prometheus scraping. Any request path returns the metrics.
+pub async fn serve_metrics(metrics: std::sync::Arc<Metrics>, addr: &str) -> Result<()> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("bind metrics endpoint {:?}", addr))?;
+    info!("serve metrics at http://{}/metrics", addr);
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            // the request is not important: read it and discard
+            let mut buf = vec![0u8; 1024];
+            let _ = stream.read(&mut buf).await;
+            let body = metrics.render();
+            let resp = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            let _ = stream.write_all(resp.as_bytes()).await;
+        });
+    }
+}
+// ff9cb6d1 ends here
+
+// [[file:../vasp-tools.note::c7688c98][c7688c98]]
+#[test]
+fn test_metrics() -> Result<()> {
+    let tdir = tempfile::tempdir()?;
+    let f = tdir.path().join("vasp.prom");
+    let metrics = Metrics::new(f.clone().into());
+    metrics.set_state(ServerState::Running);
+    metrics.interaction_done(5.0, true);
+    metrics.interaction_done(100.0, true);
+    metrics.interaction_rejected();
+    metrics.interaction_done(1.0, false);
+
+    let txt = gut::fs::read_file(&f)?;
+    assert!(txt.contains("vasp_tools_interactions_total 3\n"));
+    assert!(txt.contains("vasp_tools_interaction_failures_total 1\n"));
+    assert!(txt.contains("vasp_tools_interaction_rejections_total 1\n"));
+    assert!(txt.contains("vasp_tools_state{state=\"stopped\"} 1\n"));
+    assert!(txt.contains("vasp_tools_state{state=\"idle\"} 0\n"));
+    assert!(txt.contains("vasp_tools_step_duration_seconds_bucket{le=\"10\"} 1\n"));
+    assert!(txt.contains("vasp_tools_step_duration_seconds_bucket{le=\"300\"} 2\n"));
+    assert!(txt.contains("vasp_tools_step_duration_seconds_count 2\n"));
+    assert!(txt.contains("vasp_tools_last_step_duration_seconds 100\n"));
+
+    Ok(())
+}
+// c7688c98 ends here
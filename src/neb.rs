@@ -0,0 +1,330 @@
+// [[file:../vasp-tools.note::*docs][docs:1]]
+//! Nudged elastic band (NEB) with optional climbing image, evaluating all
+//! images concurrently over a pool of force engines
+// docs:1 ends here
+
+// [[file:../vasp-tools.note::0d3c13d9][0d3c13d9]]
+use super::*;
+
+use crate::optim::{Fire, Lbfgs, OptAlgorithm};
+use crate::pool::EnginePool;
+use crate::vasp::compare::displacements;
+use gosh::gchemol::prelude::*;
+use gosh::gchemol::Molecule;
+// 0d3c13d9 ends here
+
+// [[file:../vasp-tools.note::369a2cb7][369a2cb7]]
+/// Options for NEB calculation
+#[derive(Debug, Clone)]
+pub struct NebOptions {
+    /// The spring constant between neighboring images (eV/Å^2)
+    pub spring: f64,
+    /// Use climbing image for the highest energy image
+    pub climb: bool,
+    pub algorithm: OptAlgorithm,
+    /// Converged if the max NEB force on free atoms is below this (eV/Å)
+    pub fmax: f64,
+    /// The max number of NEB iterations
+    pub max_steps: usize,
+    /// The max displacement of any atom in one step (Å)
+    pub max_step: f64,
+    /// Write images into sub-directories (00, 01, ...) of this directory
+    /// in POSCAR format after each iteration
+    pub output_dir: Option<PathBuf>,
+    /// Append the energy profile of each iteration into this file
+    pub profile: Option<PathBuf>,
+}
+
+impl Default for NebOptions {
+    fn default() -> Self {
+        Self {
+            spring: 5.0,
+            climb: false,
+            algorithm: OptAlgorithm::Fire,
+            fmax: 0.05,
+            max_steps: 200,
+            max_step: 0.2,
+            output_dir: None,
+            profile: None,
+        }
+    }
+}
+
+/// The final state of NEB calculation
+#[derive(Debug, Clone)]
+pub struct NebResult {
+    /// All images including the end points
+    pub images: Vec<Molecule>,
+    /// The energies in eV of all images
+    pub energies: Vec<f64>,
+    /// The max NEB force on free atoms of intermediate images
+    pub fmax: f64,
+    /// The number of NEB iterations
+    pub nsteps: usize,
+    pub converged: bool,
+}
+
+/// Generate `n` intermediate images between `initial` and `final_` by
+/// linear interpolation. Return all images including the end points.
+pub fn interpolate(initial: &Molecule, final_: &Molecule, n: usize) -> Result<Vec<Molecule>> {
+    ensure!(initial.natoms() == final_.natoms(), "end points have different number of atoms");
+    ensure!(
+        initial.symbols().zip(final_.symbols()).all(|(a, b)| a == b),
+        "end points have different element symbols"
+    );
+    let d = displacements(initial, final_);
+    let positions: Vec<[f64; 3]> = initial.positions().collect();
+    let mut images = vec![initial.clone()];
+    for i in 1..=n {
+        let t = i as f64 / (n + 1) as f64;
+        let mut mol = initial.clone();
+        let p: Vec<[f64; 3]> = positions
+            .iter()
+            .zip(&d)
+            .map(|(p, d)| [p[0] + t * d[0], p[1] + t * d[1], p[2] + t * d[2]])
+            .collect();
+        mol.set_positions(p);
+        images.push(mol);
+    }
+    images.push(final_.clone());
+    Ok(images)
+}
+
+fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+/// Return the flattened displacement from image `a` to image `b`.
+fn image_vector(a: &Molecule, b: &Molecule) -> Vec<f64> {
+    displacements(a, b).into_iter().flatten().collect()
+}
+
+/// Return the tangent at image `i` using the improved tangent estimate
+/// of Henkelman and Jónsson.
+fn tangent(images: &[Molecule], energies: &[f64], i: usize) -> Vec<f64> {
+    let tp = image_vector(&images[i], &images[i + 1]);
+    let tm = image_vector(&images[i - 1], &images[i]);
+    let (ep, e0, em) = (energies[i + 1], energies[i], energies[i - 1]);
+    let mut tau: Vec<f64> = if ep > e0 && e0 > em {
+        tp
+    } else if ep < e0 && e0 < em {
+        tm
+    } else {
+        let de_max = (ep - e0).abs().max((em - e0).abs());
+        let de_min = (ep - e0).abs().min((em - e0).abs());
+        let (wp, wm) = if ep > em { (de_max, de_min) } else { (de_min, de_max) };
+        tp.iter().zip(&tm).map(|(p, m)| p * wp + m * wm).collect()
+    };
+    let norm = dot(&tau, &tau).sqrt();
+    if norm > 0.0 {
+        tau.iter_mut().for_each(|x| *x /= norm);
+    }
+    tau
+}
+
+/// Compute NEB forces (flattened) of intermediate images from true
+/// `forces`. The image with the highest energy climbs if `climb` is true.
+fn neb_forces(images: &[Molecule], energies: &[f64], forces: &[Vec<f64>], spring: f64, climb: bool) -> Vec<Vec<f64>> {
+    let n = images.len();
+    let imax = (1..n - 1).max_by(|&a, &b| energies[a].total_cmp(&energies[b]));
+    (1..n - 1)
+        .map(|i| {
+            let tau = tangent(images, energies, i);
+            let f = &forces[i - 1];
+            let ft = dot(f, &tau);
+            if climb && Some(i) == imax {
+                f.iter().zip(&tau).map(|(f, t)| f - 2.0 * ft * t).collect()
+            } else {
+                let dp = image_vector(&images[i], &images[i + 1]);
+                let dm = image_vector(&images[i - 1], &images[i]);
+                let fs = spring * (dot(&dp, &dp).sqrt() - dot(&dm, &dm).sqrt());
+                f.iter().zip(&tau).map(|(f, t)| f - ft * t + fs * t).collect()
+            }
+        })
+        .collect()
+}
+
+/// Return the reaction coordinates (accumulated distances in Å) of all
+/// images.
+fn reaction_coordinates(images: &[Molecule]) -> Vec<f64> {
+    let mut s = vec![0.0];
+    for w in images.windows(2) {
+        let d = image_vector(&w[0], &w[1]);
+        s.push(s.last().unwrap() + dot(&d, &d).sqrt());
+    }
+    s
+}
+
+/// Format energy profile of `images` as a table, with energies relative to
+/// the initial image.
+pub fn format_mep_profile(images: &[Molecule], energies: &[f64]) -> String {
+    let s = reaction_coordinates(images);
+    let mut txt = format!("{:>6} {:>12} {:>18} {:>14}\n", "image", "coord (Å)", "energy (eV)", "relative (eV)");
+    for (i, (s, e)) in s.iter().zip(energies).enumerate() {
+        txt += &format!("{:>6} {:>12.4} {:>18.8} {:>14.6}\n", i, s, e, e - energies[0]);
+    }
+    txt
+}
+
+/// Plot energy profile of `images` as ASCII text.
+pub fn plot_mep_profile(images: &[Molecule], energies: &[f64]) -> Result<String> {
+    use crate::plot::AsciiPlot;
+
+    let mut plot = AsciiPlot::new();
+    plot.set_title("Minimum energy path");
+    plot.set_xlabel("reaction coordinate (Å)");
+    plot.set_ylabel("relative energy (eV)");
+    let x = reaction_coordinates(images);
+    let y: Vec<_> = energies.iter().map(|e| e - energies[0]).collect();
+    plot.plot(&x, &y)
+}
+
+fn write_images(dir: &Path, images: &[Molecule], intermediate_only: bool) -> Result<()> {
+    let n = images.len();
+    for (i, mol) in images.iter().enumerate() {
+        if intermediate_only && (i == 0 || i == n - 1) {
+            continue;
+        }
+        let d = dir.join(format!("{:02}", i));
+        std::fs::create_dir_all(&d)?;
+        gut::fs::write_to_file(d.join("POSCAR"), &mol.format_as("vasp/input")?)?;
+    }
+    Ok(())
+}
+
+fn append_profile(f: &Path, iteration: usize, images: &[Molecule], energies: &[f64]) -> Result<()> {
+    use std::io::Write;
+
+    let mut fp = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(f)
+        .with_context(|| format!("open profile file {:?}", f))?;
+    writeln!(fp, "# iteration {}", iteration)?;
+    write!(fp, "{}", format_mep_profile(images, energies))?;
+    writeln!(fp)?;
+    Ok(())
+}
+
+/// Run NEB calculation for `images` (including end points), evaluating
+/// intermediate images concurrently using engines in `pool`. The atoms
+/// fixed in selective dynamics of the initial image are not moved.
+pub fn run_neb(pool: &EnginePool, images: Vec<Molecule>, opts: &NebOptions) -> Result<NebResult> {
+    let n = images.len();
+    ensure!(n >= 3, "NEB requires at least one intermediate image");
+    let mut images = images;
+    let natoms = images[0].natoms();
+    let mask: Vec<bool> = crate::optim::free_coords_mask(&images[0]).repeat(n - 2);
+
+    info!("evaluate end points ...");
+    let ends = pool.evaluate(&[images[0].clone(), images[n - 1].clone()])?;
+    let e_ini = ends[0].mp.get_energy().context("no energy")?;
+    let e_fin = ends[1].mp.get_energy().context("no energy")?;
+    if let Some(d) = &opts.output_dir {
+        write_images(d, &images, false)?;
+    }
+
+    let mut lbfgs = Lbfgs::new();
+    let mut fire = Fire::new();
+    for step in 1..=opts.max_steps {
+        let all = pool.evaluate(&images[1..n - 1])?;
+        let mut energies = vec![e_ini];
+        let mut forces = vec![];
+        for props in &all {
+            energies.push(props.mp.get_energy().context("no energy")?);
+            let f = props.mp.get_forces().context("no forces")?;
+            ensure!(f.len() == natoms, "invalid number of forces: {}", f.len());
+            forces.push(f.iter().flatten().copied().collect::<Vec<_>>());
+        }
+        energies.push(e_fin);
+
+        let f: Vec<f64> = neb_forces(&images, &energies, &forces, opts.spring, opts.climb)
+            .into_iter()
+            .flatten()
+            .zip(&mask)
+            .map(|(f, &free)| if free { f } else { 0.0 })
+            .collect();
+        let fmax = f.chunks(3).map(|d| dot(d, d).sqrt()).fold(0.0, f64::max);
+        let barrier = energies.iter().fold(f64::NEG_INFINITY, |a, &b| a.max(b)) - e_ini;
+        println!("{:<6} barrier: {:<14.6} fmax: {:<12.6}", step, barrier, fmax);
+        if let Some(f) = &opts.profile {
+            append_profile(f, step, &images, &energies)?;
+        }
+
+        let done = fmax < opts.fmax;
+        if done || step == opts.max_steps {
+            if done {
+                info!("NEB converged in {} iterations", step);
+            } else {
+                warn!("NEB not converged in {} iterations", step);
+            }
+            return Ok(NebResult {
+                images,
+                energies,
+                fmax,
+                nsteps: step,
+                converged: done,
+            });
+        }
+
+        let x: Vec<f64> = images[1..n - 1].iter().flat_map(|m| m.positions().flatten()).collect();
+        let mut dr = match opts.algorithm {
+            OptAlgorithm::Lbfgs => lbfgs.step(&x, &f),
+            OptAlgorithm::Fire => fire.step(&f),
+        };
+        for (d, &free) in dr.iter_mut().zip(&mask) {
+            if !free {
+                *d = 0.0;
+            }
+        }
+        crate::optim::limit_step(&mut dr, opts.max_step);
+        for (k, mol) in images[1..n - 1].iter_mut().enumerate() {
+            let offset = k * natoms * 3;
+            let positions: Vec<[f64; 3]> = x[offset..offset + natoms * 3]
+                .chunks(3)
+                .zip(dr[offset..offset + natoms * 3].chunks(3))
+                .map(|(x, d)| [x[0] + d[0], x[1] + d[1], x[2] + d[2]])
+                .collect();
+            mol.set_positions(positions);
+        }
+        if let Some(d) = &opts.output_dir {
+            write_images(d, &images, true)?;
+        }
+    }
+    bail!("no NEB iteration to run");
+}
+// 369a2cb7 ends here
+
+// [[file:../vasp-tools.note::2f3b6c41][2f3b6c41]]
+#[test]
+fn test_neb_rotation() -> Result<()> {
+    use crate::ipi::ForceEngine;
+    use crate::potential::Potential;
+    use gosh::gchemol::Atom;
+
+    // rotation of a harmonic dimer has no barrier, but the linearly
+    // interpolated path compresses the bond
+    let initial = Molecule::from_atoms(vec![Atom::new("H", [0.0; 3]), Atom::new("H", [1.5, 0.0, 0.0])]);
+    let final_ = Molecule::from_atoms(vec![Atom::new("H", [0.0; 3]), Atom::new("H", [0.0, 1.5, 0.0])]);
+    let images = interpolate(&initial, &final_, 3)?;
+    assert_eq!(images.len(), 5);
+    let p: Vec<_> = images[2].positions().collect();
+    assert_relative_eq!(p[1][0], 0.75, epsilon = 1e-8);
+    assert_relative_eq!(p[1][1], 0.75, epsilon = 1e-8);
+
+    let pool = EnginePool::new(2, |_| Ok(ForceEngine::Potential(Potential::Harmonic { k: 1.0, r0: 1.5 })))?;
+    let opts = NebOptions {
+        climb: true,
+        max_steps: 500,
+        ..Default::default()
+    };
+    let r = run_neb(&pool, images, &opts)?;
+    assert!(r.converged);
+    let barrier = r.energies.iter().fold(f64::NEG_INFINITY, |a, &b| a.max(b));
+    assert!(barrier < 0.01, "barrier: {}", barrier);
+    let s = format_mep_profile(&r.images, &r.energies);
+    assert_eq!(s.lines().count(), 6);
+
+    Ok(())
+}
+// 2f3b6c41 ends here
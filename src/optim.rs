@@ -0,0 +1,330 @@
+// [[file:../vasp-tools.note::*docs][docs:1]]
+//! Simple built-in geometry optimizers (LBFGS and FIRE) driving the force
+//! engines, for small relaxations without gosh-optim or ASE
+// docs:1 ends here
+
+// [[file:../vasp-tools.note::58f2fd40][58f2fd40]]
+use super::*;
+
+use crate::ipi::ForceEngine;
+use gosh::gchemol::prelude::*;
+use gosh::gchemol::Molecule;
+// 58f2fd40 ends here
+
+// [[file:../vasp-tools.note::a68ee854][a68ee854]]
+/// The optimization algorithm
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OptAlgorithm {
+    Lbfgs,
+    /// Fast inertial relaxation engine
+    Fire,
+}
+
+impl std::str::FromStr for OptAlgorithm {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "lbfgs" => Ok(Self::Lbfgs),
+            "fire" => Ok(Self::Fire),
+            _ => bail!("unsupported optimization algorithm: {:?}", s),
+        }
+    }
+}
+
+/// Options for geometry optimization
+#[derive(Debug, Clone)]
+pub struct OptOptions {
+    pub algorithm: OptAlgorithm,
+    /// Converged if the max force on free atoms is below this (eV/Å)
+    pub fmax: f64,
+    /// The max number of optimization steps
+    pub max_steps: usize,
+    /// The max displacement of any atom in one step (Å)
+    pub max_step: f64,
+    /// Write the latest structure into this file in POSCAR format after
+    /// each step
+    pub snapshot: Option<PathBuf>,
+    /// Append each evaluated structure into this extxyz trajectory
+    pub trajectory: Option<PathBuf>,
+}
+
+impl Default for OptOptions {
+    fn default() -> Self {
+        Self {
+            algorithm: OptAlgorithm::Lbfgs,
+            fmax: 0.05,
+            max_steps: 200,
+            max_step: 0.2,
+            snapshot: None,
+            trajectory: None,
+        }
+    }
+}
+
+/// The final state of geometry optimization
+#[derive(Debug, Clone)]
+pub struct OptResult {
+    /// The optimized structure
+    pub mol: Molecule,
+    /// The energy in eV of the final structure
+    pub energy: f64,
+    /// The max force on free atoms in the final structure
+    pub fmax: f64,
+    /// The number of force evaluations
+    pub nsteps: usize,
+    pub converged: bool,
+}
+
+fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+/// Scale step `dr` (flattened) so that no atom moves more than `max_step`.
+pub(crate) fn limit_step(dr: &mut [f64], max_step: f64) {
+    let longest = dr.chunks(3).map(|d| dot(d, d).sqrt()).fold(0.0, f64::max);
+    if longest > max_step {
+        dr.iter_mut().for_each(|x| *x *= max_step / longest);
+    }
+}
+
+/// Limited-memory BFGS without line search, as in ASE.
+pub(crate) struct Lbfgs {
+    memory: usize,
+    // the initial inverse Hessian (Å^2/eV)
+    h0: f64,
+    s: Vec<Vec<f64>>,
+    y: Vec<Vec<f64>>,
+    rho: Vec<f64>,
+    // positions and forces of previous step
+    last: Option<(Vec<f64>, Vec<f64>)>,
+}
+
+impl Lbfgs {
+    pub(crate) fn new() -> Self {
+        Self {
+            memory: 100,
+            h0: 1.0 / 70.0,
+            s: vec![],
+            y: vec![],
+            rho: vec![],
+            last: None,
+        }
+    }
+
+    pub(crate) fn step(&mut self, x: &[f64], f: &[f64]) -> Vec<f64> {
+        if let Some((x0, f0)) = self.last.take() {
+            let s: Vec<_> = x.iter().zip(&x0).map(|(a, b)| a - b).collect();
+            let y: Vec<_> = f0.iter().zip(f).map(|(a, b)| a - b).collect();
+            let ys = dot(&y, &s);
+            // skip the update that breaks positive definiteness
+            if ys > 1e-12 {
+                self.s.push(s);
+                self.y.push(y);
+                self.rho.push(1.0 / ys);
+                if self.s.len() > self.memory {
+                    self.s.remove(0);
+                    self.y.remove(0);
+                    self.rho.remove(0);
+                }
+            }
+        }
+        self.last = Some((x.to_vec(), f.to_vec()));
+
+        // two-loop recursion for -H g with gradient g = -f
+        let mut q: Vec<_> = f.iter().map(|x| -x).collect();
+        let n = self.s.len();
+        let mut alpha = vec![0.0; n];
+        for i in (0..n).rev() {
+            alpha[i] = self.rho[i] * dot(&self.s[i], &q);
+            q.iter_mut().zip(&self.y[i]).for_each(|(q, y)| *q -= alpha[i] * y);
+        }
+        let mut z: Vec<_> = q.iter().map(|x| self.h0 * x).collect();
+        for i in 0..n {
+            let beta = self.rho[i] * dot(&self.y[i], &z);
+            z.iter_mut().zip(&self.s[i]).for_each(|(z, s)| *z += s * (alpha[i] - beta));
+        }
+        z.iter().map(|x| -x).collect()
+    }
+}
+
+/// FIRE with the default parameters of ASE.
+pub(crate) struct Fire {
+    dt: f64,
+    a: f64,
+    nsteps: usize,
+    v: Option<Vec<f64>>,
+}
+
+impl Fire {
+    const DT_MAX: f64 = 1.0;
+    const N_MIN: usize = 5;
+    const F_INC: f64 = 1.1;
+    const F_DEC: f64 = 0.5;
+    const A_START: f64 = 0.1;
+    const F_A: f64 = 0.99;
+
+    pub(crate) fn new() -> Self {
+        Self {
+            dt: 0.1,
+            a: Self::A_START,
+            nsteps: 0,
+            v: None,
+        }
+    }
+
+    pub(crate) fn step(&mut self, f: &[f64]) -> Vec<f64> {
+        let mut v = match self.v.take() {
+            None => vec![0.0; f.len()],
+            Some(mut v) => {
+                if dot(f, &v) > 0.0 {
+                    let (fnorm, vnorm) = (dot(f, f).sqrt(), dot(&v, &v).sqrt());
+                    v.iter_mut()
+                        .zip(f)
+                        .for_each(|(v, f)| *v = (1.0 - self.a) * *v + self.a * f / fnorm * vnorm);
+                    if self.nsteps > Self::N_MIN {
+                        self.dt = (self.dt * Self::F_INC).min(Self::DT_MAX);
+                        self.a *= Self::F_A;
+                    }
+                    self.nsteps += 1;
+                } else {
+                    v.iter_mut().for_each(|v| *v = 0.0);
+                    self.a = Self::A_START;
+                    self.dt *= Self::F_DEC;
+                    self.nsteps = 0;
+                }
+                v
+            }
+        };
+        v.iter_mut().zip(f).for_each(|(v, f)| *v += self.dt * f);
+        let dr = v.iter().map(|v| self.dt * v).collect();
+        self.v = Some(v);
+        dr
+    }
+}
+
+/// Return mask of free Cartesian coordinates (flattened) of `mol` from
+/// selective dynamics flags.
+pub(crate) fn free_coords_mask(mol: &Molecule) -> Vec<bool> {
+    mol.atoms().flat_map(|(_, a)| a.freezing().map(|frozen| !frozen)).collect()
+}
+
+/// Optimize `mol` using forces computed by `engine`. The atoms fixed in
+/// selective dynamics are not moved.
+pub async fn optimize(engine: &mut ForceEngine, mol: &Molecule, opts: &OptOptions) -> Result<OptResult> {
+    let mask = free_coords_mask(mol);
+    let nfixed = mask.iter().filter(|&&x| !x).count();
+    if nfixed > 0 {
+        info!("{} coordinates fixed by selective dynamics", nfixed);
+    }
+    optimize_with_mask(engine, mol, &mask, opts).await
+}
+
+async fn optimize_with_mask(engine: &mut ForceEngine, mol: &Molecule, mask: &[bool], opts: &OptOptions) -> Result<OptResult> {
+    ensure!(mask.len() == mol.natoms() * 3, "invalid mask of free coordinates");
+    let mut mol = mol.clone();
+    let mut lbfgs = Lbfgs::new();
+    let mut fire = Fire::new();
+    for i in 1..=opts.max_steps {
+        let props = engine.compute(&mol).await?;
+        if let Some(f) = &opts.trajectory {
+            crate::trajectory::append_extxyz(f, &mol, &props)?;
+        }
+        let energy = props.mp.get_energy().context("no energy")?;
+        let forces = props.mp.get_forces().context("no forces")?;
+        let f: Vec<f64> = forces
+            .iter()
+            .flatten()
+            .zip(mask)
+            .map(|(&f, &free)| if free { f } else { 0.0 })
+            .collect();
+        let fmax = f.chunks(3).map(|d| dot(d, d).sqrt()).fold(0.0, f64::max);
+        println!("{:<6} Energy: {:<18.8} fmax: {:<12.6}", i, energy, fmax);
+        if fmax < opts.fmax {
+            info!("optimization converged in {} steps", i);
+            return Ok(OptResult {
+                mol,
+                energy,
+                fmax,
+                nsteps: i,
+                converged: true,
+            });
+        }
+        if i == opts.max_steps {
+            warn!("optimization not converged in {} steps", i);
+            return Ok(OptResult {
+                mol,
+                energy,
+                fmax,
+                nsteps: i,
+                converged: false,
+            });
+        }
+
+        let x: Vec<f64> = mol.positions().flatten().collect();
+        let mut dr = match opts.algorithm {
+            OptAlgorithm::Lbfgs => lbfgs.step(&x, &f),
+            OptAlgorithm::Fire => fire.step(&f),
+        };
+        for (d, &free) in dr.iter_mut().zip(mask) {
+            if !free {
+                *d = 0.0;
+            }
+        }
+        limit_step(&mut dr, opts.max_step);
+        let positions: Vec<[f64; 3]> = x
+            .chunks(3)
+            .zip(dr.chunks(3))
+            .map(|(x, d)| [x[0] + d[0], x[1] + d[1], x[2] + d[2]])
+            .collect();
+        mol.set_positions(positions);
+        if let Some(f) = &opts.snapshot {
+            let txt = mol.format_as("vasp/input")?;
+            gut::fs::write_to_file(f, &txt)?;
+        }
+    }
+    bail!("no optimization step to run");
+}
+// a68ee854 ends here
+
+// [[file:../vasp-tools.note::ad4ae476][ad4ae476]]
+#[tokio::test]
+async fn test_optimize_harmonic() -> Result<()> {
+    use crate::potential::Potential;
+    use gosh::gchemol::Atom;
+
+    let atoms = vec![Atom::new("H", [0.0, 0.0, 0.0]), Atom::new("H", [1.2, 0.1, 0.0])];
+    let mol = Molecule::from_atoms(atoms);
+    let mut engine = ForceEngine::Potential(Potential::Harmonic { k: 1.0, r0: 1.5 });
+    let distance = |mol: &Molecule| {
+        let p: Vec<_> = mol.positions().collect();
+        (0..3).map(|k| (p[1][k] - p[0][k]).powi(2)).sum::<f64>().sqrt()
+    };
+
+    for algorithm in [OptAlgorithm::Lbfgs, OptAlgorithm::Fire] {
+        let opts = OptOptions {
+            algorithm,
+            fmax: 1e-3,
+            max_steps: 1000,
+            ..Default::default()
+        };
+        let r = optimize(&mut engine, &mol, &opts).await?;
+        assert!(r.converged, "{:?} not converged", algorithm);
+        assert_relative_eq!(distance(&r.mol), 1.5, epsilon = 1e-2);
+    }
+
+    // the first atom is fixed
+    let mask = [false, false, false, true, true, true];
+    let r = optimize_with_mask(&mut engine, &mol, &mask, &OptOptions::default()).await?;
+    assert_eq!(r.mol.positions().next(), Some([0.0, 0.0, 0.0]));
+    assert!(r.fmax < 0.05);
+
+    let opts = OptOptions {
+        max_steps: 1,
+        ..Default::default()
+    };
+    assert!(!optimize(&mut engine, &mol, &opts).await?.converged);
+
+    Ok(())
+}
+// ad4ae476 ends here
@@ -33,7 +33,19 @@ impl AsciiPlot {
         self.title = title.into();
     }
 
+    /// Plot `y` against `x` as ASCII text. gnuplot will be used if
+    /// available, otherwise fall back to the native renderer.
     pub fn plot(&self, x: &[f64], y: &[f64]) -> Result<String> {
+        ensure!(x.len() == y.len(), "x and y have different lengths");
+        if gnuplot_available() {
+            self.plot_with_gnuplot(x, y)
+        } else {
+            debug!("gnuplot not found, use native ASCII plotting.");
+            Ok(self.plot_native(x, y))
+        }
+    }
+
+    fn plot_with_gnuplot(&self, x: &[f64], y: &[f64]) -> Result<String> {
         // data file for gnuplot input
         let data_file = "plot.dat";
 
@@ -61,8 +73,375 @@ impl AsciiPlot {
         Ok(output)
     }
 }
+
+/// Return true if gnuplot program can be found in PATH
+fn gnuplot_available() -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    std::env::var_os("PATH").map_or(false, |paths| {
+        std::env::split_paths(&paths).any(|dir| {
+            dir.join("gnuplot")
+                .metadata()
+                .map_or(false, |m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+        })
+    })
+}
 // 5e88e23c ends here
 
+// [[file:../vasp-tools.note::f60d6ab7][f60d6ab7]]
+// the size of plotting area in characters, similar to gnuplot dumb terminal
+const CANVAS_WIDTH: usize = 64;
+const CANVAS_HEIGHT: usize = 18;
+
+/// A character canvas for rendering data points in pure Rust
+struct Canvas {
+    width: usize,
+    height: usize,
+    xrange: (f64, f64),
+    yrange: (f64, f64),
+    cells: Vec<Vec<char>>,
+}
+
+/// Return the range of `values` ignoring non-finite values, expanded a bit if
+/// all values are the same.
+fn data_range(values: &[f64]) -> (f64, f64) {
+    let (lo, hi) = values
+        .iter()
+        .filter(|v| v.is_finite())
+        .fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), &v| (lo.min(v), hi.max(v)));
+    if lo > hi {
+        (0.0, 1.0)
+    } else if (hi - lo).abs() < 1e-12 {
+        let d = if lo.abs() > 1e-12 { lo.abs() * 0.01 } else { 1.0 };
+        (lo - d, hi + d)
+    } else {
+        (lo, hi)
+    }
+}
+
+impl Canvas {
+    fn new(width: usize, height: usize, xrange: (f64, f64), yrange: (f64, f64)) -> Self {
+        Self {
+            width,
+            height,
+            xrange,
+            yrange,
+            cells: vec![vec![' '; width]; height],
+        }
+    }
+
+    // map data point into (row, col) in canvas
+    fn locate(&self, x: f64, y: f64) -> Option<(usize, usize)> {
+        if !x.is_finite() || !y.is_finite() {
+            return None;
+        }
+        let (x0, x1) = self.xrange;
+        let (y0, y1) = self.yrange;
+        let col = ((x - x0) / (x1 - x0) * (self.width - 1) as f64).round();
+        let row = ((y1 - y) / (y1 - y0) * (self.height - 1) as f64).round();
+        if col < 0.0 || row < 0.0 || col > (self.width - 1) as f64 || row > (self.height - 1) as f64 {
+            return None;
+        }
+        Some((row as usize, col as usize))
+    }
+
+    /// Draw data points marked with `marker`, connected with dots.
+    fn line(&mut self, x: &[f64], y: &[f64], marker: char) {
+        let points: Vec<_> = x.iter().zip(y).filter_map(|(&x, &y)| self.locate(x, y)).collect();
+        for w in points.windows(2) {
+            let (r0, c0) = (w[0].0 as f64, w[0].1 as f64);
+            let (r1, c1) = (w[1].0 as f64, w[1].1 as f64);
+            let n = (r1 - r0).abs().max((c1 - c0).abs()) as usize;
+            for k in 1..n {
+                let t = k as f64 / n as f64;
+                let r = (r0 + t * (r1 - r0)).round() as usize;
+                let c = (c0 + t * (c1 - c0)).round() as usize;
+                if self.cells[r][c] == ' ' {
+                    self.cells[r][c] = '.';
+                }
+            }
+        }
+        for (r, c) in points {
+            self.cells[r][c] = marker;
+        }
+    }
+
+    /// Draw vertical bars from the bottom up to data points, which will not
+    /// overwrite drawn points.
+    fn bars(&mut self, x: &[f64], y: &[f64], ch: char) {
+        for (&xi, &yi) in x.iter().zip(y) {
+            if let Some((row, col)) = self.locate(xi, yi) {
+                for r in row..self.height {
+                    if self.cells[r][col] == ' ' {
+                        self.cells[r][col] = ch;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Render the canvas with y tick labels on the left and x tick labels at
+    /// the bottom.
+    fn render(&self) -> String {
+        self.render_with_right_axis(None)
+    }
+
+    /// Render the canvas as `render`, with tick labels of secondary y axis in
+    /// `yrange2` on the right.
+    fn render_with_right_axis(&self, yrange2: Option<(f64, f64)>) -> String {
+        let (x0, x1) = self.xrange;
+        let (y0, y1) = self.yrange;
+        let nyticks = 5;
+        let ytick_rows: Vec<_> = (0..nyticks).map(|i| i * (self.height - 1) / (nyticks - 1)).collect();
+        let ylabels: Vec<_> = ytick_rows
+            .iter()
+            .map(|&r| format!("{:.2}", y1 - (y1 - y0) * r as f64 / (self.height - 1) as f64))
+            .collect();
+        let margin = ylabels.iter().map(|s| s.len()).max().unwrap_or(0);
+
+        let mut lines = vec![];
+        for (r, row) in self.cells.iter().enumerate() {
+            let label = match ytick_rows.iter().position(|&t| t == r) {
+                Some(i) => ylabels[i].as_str(),
+                None => "",
+            };
+            let tick = if label.is_empty() { '|' } else { '+' };
+            let row: String = row.iter().collect();
+            let line = match yrange2 {
+                Some((v0, v1)) => {
+                    let rlabel = match ytick_rows.iter().position(|&t| t == r) {
+                        Some(_) => format!("+ {}", format_tick(v1 - (v1 - v0) * r as f64 / (self.height - 1) as f64)),
+                        None => "|".into(),
+                    };
+                    format!("{:>margin$} {}{}{}", label, tick, row, rlabel, margin = margin)
+                }
+                None => format!("{:>margin$} {}{}", label, tick, row.trim_end(), margin = margin),
+            };
+            lines.push(line);
+        }
+        lines.push(format!("{:>margin$} +{}", "", "-".repeat(self.width), margin = margin));
+
+        // x tick labels: min, middle and max
+        let mut xticks = vec![' '; self.width + 8];
+        for (col, v) in [(0, x0), (self.width / 2, (x0 + x1) / 2.0), (self.width - 1, x1)] {
+            let s = format_tick(v);
+            let start = col.saturating_sub(s.len() / 2);
+            for (i, ch) in s.chars().enumerate() {
+                if let Some(c) = xticks.get_mut(start + i) {
+                    *c = ch;
+                }
+            }
+        }
+        let xticks: String = xticks.into_iter().collect();
+        lines.push(format!("{:>margin$}  {}", "", xticks.trim_end(), margin = margin));
+
+        lines.join("\n")
+    }
+}
+
+impl Canvas {
+    /// Fill empty cells with those drawn in `other`.
+    fn overlay(&mut self, other: &Canvas) {
+        for (row, other_row) in self.cells.iter_mut().zip(&other.cells) {
+            for (c, &o) in row.iter_mut().zip(other_row) {
+                if *c == ' ' {
+                    *c = o;
+                }
+            }
+        }
+    }
+}
+
+/// A panel in stacked plots, with a data series drawn as points and an
+/// optional overlay drawn as bars on secondary y axis.
+#[derive(Debug, Clone)]
+pub struct Panel {
+    ylabel: String,
+    x: Vec<f64>,
+    y: Vec<f64>,
+    bars: Option<(String, Vec<f64>)>,
+}
+
+impl Panel {
+    pub fn new(ylabel: &str, x: &[f64], y: &[f64]) -> Self {
+        Self {
+            ylabel: ylabel.into(),
+            x: x.to_vec(),
+            y: y.to_vec(),
+            bars: None,
+        }
+    }
+
+    /// Overlay `y` values as bars on secondary y axis labeled as `label`.
+    pub fn with_bars(mut self, label: &str, y: &[f64]) -> Self {
+        self.bars = Some((label.into(), y.to_vec()));
+        self
+    }
+}
+
+fn format_tick(v: f64) -> String {
+    if v.fract().abs() < 1e-8 {
+        format!("{}", v as i64)
+    } else {
+        format!("{:.2}", v)
+    }
+}
+
+fn center(s: &str, width: usize) -> String {
+    format!("{:^width$}", s, width = width).trim_end().to_string()
+}
+
+impl AsciiPlot {
+    /// Plot multiple `panels` stacked vertically sharing the same x axis.
+    /// Always rendered natively.
+    pub fn plot_panels(&self, panels: &[Panel]) -> String {
+        let all_x: Vec<f64> = panels.iter().flat_map(|p| p.x.iter().copied()).collect();
+        let xrange = data_range(&all_x);
+        let height = (CANVAS_HEIGHT / panels.len().max(1)).max(8);
+        let width = CANVAS_WIDTH + 10;
+
+        let mut s = String::new();
+        s.push_str(&center(&self.title, width));
+        s.push_str("\n");
+        for panel in panels {
+            let mut canvas = Canvas::new(CANVAS_WIDTH, height, xrange, data_range(&panel.y));
+            let mut header = format!("{} (*)", panel.ylabel);
+            let rendered = match &panel.bars {
+                Some((label, bars)) => {
+                    // bars use the secondary y axis starting from zero
+                    let (_, hi) = data_range(bars);
+                    let yrange2 = (0.0, hi.max(1.0));
+                    let mut bar_canvas = Canvas::new(CANVAS_WIDTH, height, xrange, yrange2);
+                    bar_canvas.bars(&panel.x, bars, '#');
+                    canvas.line(&panel.x, &panel.y, '*');
+                    canvas.overlay(&bar_canvas);
+                    header = format!("{:<w$}{} (#)", header, label, w = CANVAS_WIDTH);
+                    canvas.render_with_right_axis(yrange2.into())
+                }
+                None => {
+                    canvas.line(&panel.x, &panel.y, '*');
+                    canvas.render()
+                }
+            };
+            s.push_str(&header);
+            s.push_str("\n");
+            s.push_str(&rendered);
+            s.push_str("\n");
+        }
+        s.push_str(&center(&self.xlabel, width));
+        s.push_str("\n");
+        s
+    }
+
+    /// Plot `y` against `x` in pure Rust without calling gnuplot.
+    pub fn plot_native(&self, x: &[f64], y: &[f64]) -> String {
+        let mut canvas = Canvas::new(CANVAS_WIDTH, CANVAS_HEIGHT, data_range(x), data_range(y));
+        canvas.line(x, y, '*');
+        let width = CANVAS_WIDTH + 10;
+
+        let mut s = String::new();
+        s.push_str(&center(&self.title, width));
+        s.push_str("\n");
+        s.push_str(&self.ylabel);
+        s.push_str("\n");
+        s.push_str(&canvas.render());
+        s.push_str("\n");
+        s.push_str(&center(&self.xlabel, width));
+        s.push_str("\n");
+        s
+    }
+}
+// f60d6ab7 ends here
+
+// [[file:../vasp-tools.note::e7bd3a84][e7bd3a84]]
+/// Escape special characters in `s` for XML (or HTML) text.
+pub(crate) fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+impl AsciiPlot {
+    /// Plot `y` against `x` as a standalone SVG image, for embedding in
+    /// reports. Non-finite values are skipped.
+    pub fn plot_svg(&self, x: &[f64], y: &[f64]) -> String {
+        let (width, height) = (640.0, 320.0);
+        let (left, right, top, bottom) = (70.0, 20.0, 30.0, 45.0);
+        let (pw, ph) = (width - left - right, height - top - bottom);
+        let (xlo, xhi) = data_range(x);
+        let (ylo, yhi) = data_range(y);
+        let sx = |v: f64| left + (v - xlo) / (xhi - xlo) * pw;
+        let sy = |v: f64| top + (yhi - v) / (yhi - ylo) * ph;
+
+        let mut svg = format!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}" font-family="sans-serif" font-size="12">"#,
+            w = width,
+            h = height
+        );
+        svg.push('\n');
+        let mut push = |s: String| {
+            svg.push_str(&s);
+            svg.push('\n');
+        };
+        push(format!(r#"<rect x="0" y="0" width="{}" height="{}" fill="white"/>"#, width, height));
+        push(format!(
+            r#"<text x="{}" y="18" text-anchor="middle" font-size="14">{}</text>"#,
+            width / 2.0,
+            escape_xml(&self.title)
+        ));
+        push(format!(
+            r#"<rect x="{}" y="{}" width="{}" height="{}" fill="none" stroke="black"/>"#,
+            left, top, pw, ph
+        ));
+        // ticks on both ends of axes
+        for (v, anchor) in [(xlo, "start"), (xhi, "end")] {
+            push(format!(
+                r#"<text x="{:.1}" y="{:.1}" text-anchor="{}">{}</text>"#,
+                sx(v),
+                top + ph + 15.0,
+                anchor,
+                format_tick(v)
+            ));
+        }
+        for v in [ylo, yhi] {
+            push(format!(
+                r#"<text x="{:.1}" y="{:.1}" text-anchor="end">{}</text>"#,
+                left - 5.0,
+                sy(v) + 4.0,
+                format_tick(v)
+            ));
+        }
+        push(format!(
+            r#"<text x="{:.1}" y="{:.1}" text-anchor="middle">{}</text>"#,
+            left + pw / 2.0,
+            height - 8.0,
+            escape_xml(&self.xlabel)
+        ));
+        push(format!(
+            r#"<text x="15" y="{:.1}" text-anchor="middle" transform="rotate(-90 15 {:.1})">{}</text>"#,
+            top + ph / 2.0,
+            top + ph / 2.0,
+            escape_xml(&self.ylabel)
+        ));
+
+        let points: Vec<_> = x
+            .iter()
+            .zip(y)
+            .filter(|(a, b)| a.is_finite() && b.is_finite())
+            .map(|(&a, &b)| (sx(a), sy(b)))
+            .collect();
+        let line: Vec<_> = points.iter().map(|(a, b)| format!("{:.1},{:.1}", a, b)).collect();
+        push(format!(
+            r#"<polyline points="{}" fill="none" stroke="steelblue" stroke-width="1.5"/>"#,
+            line.join(" ")
+        ));
+        for (a, b) in points {
+            push(format!(r#"<circle cx="{:.1}" cy="{:.1}" r="2.5" fill="steelblue"/>"#, a, b));
+        }
+        svg.push_str("</svg>\n");
+        svg
+    }
+}
+// e7bd3a84 ends here
+
 // [[file:../vasp-tools.note::ac52b11c][ac52b11c]]
 #[test]
 fn test_gnuplot_ascii_plot() {
@@ -95,3 +474,66 @@ fn test_gnuplot_ascii_plot() {
     println!("{}", s);
 }
 // ac52b11c ends here
+
+// [[file:../vasp-tools.note::c6505758][c6505758]]
+#[test]
+fn test_native_ascii_plot() {
+    let mut ascii_plot = AsciiPlot::new();
+    ascii_plot.set_title("Geometry optimization");
+    ascii_plot.set_xlabel("opt. step");
+    ascii_plot.set_ylabel("energy (eV)");
+
+    let y = vec![-1386.3788, -1386.1684, -1384.9314, -1385.2560, -1385.3613];
+    let x: Vec<_> = (0..y.len()).map(|x| x as f64).collect();
+    let s = ascii_plot.plot_native(&x, &y);
+    println!("{}", s);
+    assert!(s.contains("Geometry optimization"));
+    assert!(s.contains("opt. step"));
+    assert!(s.contains("-1384.93"));
+    assert_eq!(s.matches('*').count(), y.len());
+
+    // constant values and NaN should not panic
+    let s = ascii_plot.plot_native(&[0.0, 1.0, 2.0], &[1.0, f64::NAN, 1.0]);
+    assert_eq!(s.matches('*').count(), 2);
+}
+// c6505758 ends here
+
+// [[file:../vasp-tools.note::e7db22d3][e7db22d3]]
+#[test]
+fn test_ascii_plot_panels() {
+    let mut ascii_plot = AsciiPlot::new();
+    ascii_plot.set_title("Geometry optimization");
+    ascii_plot.set_xlabel("opt. step");
+
+    let energy = vec![-1386.3788, -1386.1684, -1386.4314, -1386.4560, -1386.4613];
+    let fmax = vec![1.2, 0.8, 0.3, 0.1, 0.04];
+    let nscf = vec![24.0, 12.0, 10.0, 8.0, 5.0];
+    let x: Vec<_> = (0..energy.len()).map(|x| x as f64).collect();
+    let lg_fmax: Vec<_> = fmax.iter().map(|f: &f64| f.log10()).collect();
+    let panels = [
+        Panel::new("energy (eV)", &x, &energy).with_bars("SCF", &nscf),
+        Panel::new("log10(fmax)", &x, &lg_fmax),
+    ];
+    let s = ascii_plot.plot_panels(&panels);
+    println!("{}", s);
+    assert!(s.contains("log10(fmax)"));
+    assert!(s.contains("SCF (#)"));
+    assert!(s.contains('#'));
+    assert_eq!(s.matches('*').count(), 2 * energy.len() + 2);
+}
+// e7db22d3 ends here
+
+// [[file:../vasp-tools.note::19785a42][19785a42]]
+#[test]
+fn test_svg_plot() {
+    let mut plot = AsciiPlot::new();
+    plot.set_title("energy & forces");
+    let x = [0.0, 1.0, 2.0, 3.0];
+    let y = [1.0, f64::NAN, 0.5, 0.25];
+    let svg = plot.plot_svg(&x, &y);
+    assert!(svg.starts_with("<svg"));
+    assert!(svg.trim_end().ends_with("</svg>"));
+    assert!(svg.contains("energy &amp; forces"));
+    assert_eq!(svg.matches("<circle").count(), 3);
+}
+// 19785a42 ends here
@@ -0,0 +1,139 @@
+// [[file:../vasp-tools.note::*docs][docs:1]]
+//! A pool of persistent force engines (e.g. BBM drivers each with its own
+//! interactive VASP session) evaluating structures concurrently
+// docs:1 ends here
+
+// [[file:../vasp-tools.note::0a72e71d][0a72e71d]]
+use super::*;
+
+use crate::bbm::Properties;
+use crate::ipi::ForceEngine;
+use gosh::gchemol::Molecule;
+use std::sync::mpsc::{channel, Sender};
+// 0a72e71d ends here
+
+// [[file:../vasp-tools.note::0c623911][0c623911]]
+type Computed = (usize, Result<Properties>);
+
+struct Worker {
+    jobs: Sender<(usize, Molecule, Sender<Computed>)>,
+    handle: std::thread::JoinHandle<()>,
+}
+
+/// A pool of force engines, each living in its own thread across
+/// evaluations, so that interactive VASP sessions are kept alive.
+pub struct EnginePool {
+    workers: Vec<Worker>,
+}
+
+impl EnginePool {
+    /// Create a pool of `n` workers. The engine of worker `i` is created
+    /// by `new_engine(i)` in its thread on first evaluation.
+    pub fn new<F>(n: usize, new_engine: F) -> Result<Self>
+    where
+        F: Fn(usize) -> Result<ForceEngine> + Send + Clone + 'static,
+    {
+        ensure!(n > 0, "no engine in pool");
+        let workers = (0..n)
+            .map(|i| {
+                let (tx, rx) = channel::<(usize, Molecule, Sender<Computed>)>();
+                let new_engine = new_engine.clone();
+                let handle = std::thread::spawn(move || {
+                    // NOTE: BBM computation is blocking, so each worker has its
+                    // own runtime in a separate thread
+                    let rt = match tokio::runtime::Builder::new_current_thread().enable_all().build() {
+                        Ok(rt) => rt,
+                        Err(e) => {
+                            error!("worker {}: failed to create runtime: {:?}", i, e);
+                            return;
+                        }
+                    };
+                    // NOTE: VASP engine spawns its task server in the runtime
+                    let _guard = rt.enter();
+                    let mut engine: Option<ForceEngine> = None;
+                    for (k, mol, results) in rx {
+                        if engine.is_none() {
+                            match new_engine(i) {
+                                Ok(e) => engine = Some(e),
+                                Err(e) => {
+                                    let _ = results.send((k, Err(e)));
+                                    continue;
+                                }
+                            }
+                        }
+                        let engine_ = engine.as_mut().expect("engine");
+                        let computed = rt.block_on(engine_.compute(&mol));
+                        let _ = results.send((k, computed));
+                    }
+                    if let Some(ForceEngine::Vasp(vasp)) = engine {
+                        if let Err(e) = rt.block_on(vasp.terminate()) {
+                            warn!("worker {}: failed to terminate VASP: {:?}", i, e);
+                        }
+                    }
+                });
+                Worker { jobs: tx, handle }
+            })
+            .collect();
+        Ok(Self { workers })
+    }
+
+    /// Evaluate `mols` concurrently. Structure `k` is always assigned to
+    /// engine `k % n`, so that each engine restarts from wave functions of
+    /// the same structure in the previous evaluation.
+    pub fn evaluate(&self, mols: &[Molecule]) -> Result<Vec<Properties>> {
+        let n = self.workers.len();
+        let (tx, rx) = channel();
+        for (k, mol) in mols.iter().enumerate() {
+            self.workers[k % n]
+                .jobs
+                .send((k, mol.clone(), tx.clone()))
+                .map_err(|_| format_err!("worker {} of engine pool exited", k % n))?;
+        }
+        drop(tx);
+
+        let mut all: Vec<Option<Properties>> = mols.iter().map(|_| None).collect();
+        for (k, computed) in rx {
+            let props = computed.with_context(|| format!("evaluation of structure {} failed", k + 1))?;
+            all[k] = Some(props);
+        }
+        all.into_iter()
+            .enumerate()
+            .map(|(k, x)| x.with_context(|| format!("structure {} not evaluated", k + 1)))
+            .collect()
+    }
+}
+
+impl Drop for EnginePool {
+    fn drop(&mut self) {
+        for w in self.workers.drain(..) {
+            drop(w.jobs);
+            if w.handle.join().is_err() {
+                error!("worker thread of engine pool panicked");
+            }
+        }
+    }
+}
+// 0c623911 ends here
+
+// [[file:../vasp-tools.note::cb8e7e84][cb8e7e84]]
+#[test]
+fn test_engine_pool() -> Result<()> {
+    use crate::potential::Potential;
+    use gosh::gchemol::Atom;
+
+    let pool = EnginePool::new(2, |_| Ok(ForceEngine::Potential(Potential::Harmonic { k: 1.0, r0: 1.5 })))?;
+    let mols: Vec<_> = [1.3, 1.4, 1.5, 1.6, 1.7]
+        .iter()
+        .map(|&r| Molecule::from_atoms(vec![Atom::new("H", [0.0; 3]), Atom::new("H", [r, 0.0, 0.0])]))
+        .collect();
+    let all = pool.evaluate(&mols)?;
+    assert_eq!(all.len(), 5);
+    assert_relative_eq!(all[1].mp.get_energy().unwrap(), 0.005, epsilon = 1e-8);
+    assert_relative_eq!(all[4].mp.get_energy().unwrap(), 0.02, epsilon = 1e-8);
+
+    let pool = EnginePool::new(1, |_| bail!("no engine"))?;
+    assert!(pool.evaluate(&mols).is_err());
+
+    Ok(())
+}
+// cb8e7e84 ends here
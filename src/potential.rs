@@ -0,0 +1,219 @@
+// [[file:../vasp-tools.note::*docs][docs:1]]
+//! Simple analytical potentials for testing without VASP
+// docs:1 ends here
+
+// [[file:../vasp-tools.note::22abd803][22abd803]]
+use super::*;
+
+use crate::bbm::Properties;
+use gosh::gchemol::Molecule;
+use gosh::model::ModelProperties;
+// 22abd803 ends here
+
+// [[file:../vasp-tools.note::9175c259][9175c259]]
+/// Pair potentials computing energy and forces over all atom pairs. For
+/// periodic structures, the minimum image convention is applied.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Potential {
+    /// E = k/2 (r - r0)^2 in eV, with `k` in eV/Å^2 and `r0` in Å
+    Harmonic { k: f64, r0: f64 },
+    /// E = 4ε ((σ/r)^12 - (σ/r)^6) in eV, with `epsilon` in eV and `sigma`
+    /// in Å
+    LennardJones { epsilon: f64, sigma: f64 },
+}
+
+impl std::str::FromStr for Potential {
+    type Err = Error;
+
+    /// Parse potential with default parameters from "harmonic" or "lj"
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "harmonic" => Ok(Self::Harmonic { k: 1.0, r0: 1.5 }),
+            "lj" | "lennard-jones" => Ok(Self::LennardJones {
+                epsilon: 0.1,
+                sigma: 2.5,
+            }),
+            _ => bail!("unsupported potential: {:?}", s),
+        }
+    }
+}
+
+/// Return displacement vectors from atom i to atom j for all pairs (i < j)
+/// in `mol`.
+fn pair_vectors(mol: &Molecule) -> Vec<(usize, usize, [f64; 3])> {
+    let lattice = mol.get_lattice().map(|lat| lat.vectors());
+    let coords: Vec<[f64; 3]> = match lattice {
+        Some(_) => mol.get_scaled_positions().unwrap().collect(),
+        None => mol.positions().collect(),
+    };
+    let n = coords.len();
+    let mut pairs = vec![];
+    for i in 0..n {
+        for j in i + 1..n {
+            let mut d = [0.0; 3];
+            for k in 0..3 {
+                d[k] = coords[j][k] - coords[i][k];
+            }
+            if let Some(vs) = lattice {
+                // minimum image in fractional coordinates
+                let df = d.map(|x| x - x.round());
+                d = [0.0; 3];
+                for k in 0..3 {
+                    for l in 0..3 {
+                        d[l] += df[k] * vs[k][l];
+                    }
+                }
+            }
+            pairs.push((i, j, d));
+        }
+    }
+    pairs
+}
+
+impl Potential {
+    /// Return pair energy and its derivative at distance `r`
+    fn pair_energy(&self, r: f64) -> (f64, f64) {
+        match *self {
+            Self::Harmonic { k, r0 } => (0.5 * k * (r - r0).powi(2), k * (r - r0)),
+            Self::LennardJones { epsilon, sigma } => {
+                let s6 = (sigma / r).powi(6);
+                let e = 4.0 * epsilon * (s6 * s6 - s6);
+                let de = 4.0 * epsilon * (-12.0 * s6 * s6 + 6.0 * s6) / r;
+                (e, de)
+            }
+        }
+    }
+
+    /// Compute energy and forces of `mol`.
+    pub fn compute(&self, mol: &Molecule) -> Result<Properties> {
+        let natoms = mol.natoms();
+        ensure!(natoms > 0, "no atoms");
+        let mut energy = 0.0;
+        let mut forces = vec![[0.0; 3]; natoms];
+        for (i, j, d) in pair_vectors(mol) {
+            let r = (d[0] * d[0] + d[1] * d[1] + d[2] * d[2]).sqrt();
+            ensure!(r > 1e-6, "atoms {} and {} overlap", i + 1, j + 1);
+            let (e, de) = self.pair_energy(r);
+            energy += e;
+            for k in 0..3 {
+                let f = de * d[k] / r;
+                forces[i][k] += f;
+                forces[j][k] -= f;
+            }
+        }
+
+        let mut mp = ModelProperties::default();
+        mp.set_energy(energy);
+        mp.set_forces(forces);
+        Ok(mp.into())
+    }
+}
+
+/// Simulate interactive VASP calculation using `Potential`: the structure
+/// is read from POSCAR, and updated using scaled positions from stdin. The
+/// results are printed as VASP stdout, and written into vasprun.xml.
+pub struct PotentialVasp {
+    pot: Potential,
+    mol: Molecule,
+}
+
+impl PotentialVasp {
+    pub fn from_poscar(pot: Potential, poscar: &Path) -> Result<Self> {
+        use gosh::gchemol::prelude::*;
+
+        let mol = Molecule::from_file(poscar).with_context(|| format!("read {:?}", poscar))?;
+        ensure!(mol.get_lattice().is_some(), "no lattice in {:?}", poscar);
+        Ok(Self { pot, mol })
+    }
+
+    pub fn natoms(&self) -> usize {
+        self.mol.natoms()
+    }
+
+    /// Update structure with scaled positions in text `s`, one atom per line.
+    pub fn update_scaled_positions(&mut self, s: &str) -> Result<()> {
+        let [va, vb, vc] = self.mol.get_lattice().unwrap().vectors();
+        let positions: Vec<[f64; 3]> = s
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                let f: Vec<f64> = line
+                    .split_whitespace()
+                    .take(3)
+                    .map(|x| x.parse().with_context(|| format!("invalid position: {:?}", line)))
+                    .collect::<Result<_>>()?;
+                ensure!(f.len() == 3, "invalid position: {:?}", line);
+                let mut p = [0.0; 3];
+                for k in 0..3 {
+                    p[k] = f[0] * va[k] + f[1] * vb[k] + f[2] * vc[k];
+                }
+                Ok(p)
+            })
+            .collect::<Result<_>>()?;
+        ensure!(
+            positions.len() == self.natoms(),
+            "expect {} positions, got {}",
+            self.natoms(),
+            positions.len()
+        );
+        self.mol.set_positions(positions);
+        Ok(())
+    }
+
+    /// Compute ionic step `i`, print results in the format of VASP stdout,
+    /// and append them into vasprun.xml.
+    pub fn compute_step(&self, i: usize) -> Result<()> {
+        use std::io::Write;
+
+        let props = self.pot.compute(&self.mol)?;
+        let energy = props.mp.get_energy().unwrap();
+        println!("FORCES:");
+        for [x, y, z] in props.mp.get_forces().unwrap() {
+            println!("{:14.7}{:14.7}{:14.7}", x, y, z);
+        }
+        println!("{:4} F= {:.8E} E0= {:.8E}  d E =0.000000E+00  mag=     0.0000", i, energy, energy);
+
+        let mut fp = std::fs::OpenOptions::new()
+            .create(true)
+            .append(i > 1)
+            .write(true)
+            .truncate(i == 1)
+            .open("vasprun.xml")?;
+        if i == 1 {
+            writeln!(fp, "<?xml version=\"1.0\" encoding=\"ISO-8859-1\"?>\n<modeling>")?;
+        }
+        fp.write_all(crate::vasp::vasprun::format_calculation(&props).as_bytes())?;
+        Ok(())
+    }
+}
+// 9175c259 ends here
+
+// [[file:../vasp-tools.note::2cac70da][2cac70da]]
+#[test]
+fn test_potential() -> Result<()> {
+    use gosh::gchemol::Atom;
+
+    let atoms = vec![Atom::new("H", [0.0, 0.0, 0.0]), Atom::new("H", [2.0, 0.0, 0.0])];
+    let mol = Molecule::from_atoms(atoms);
+    let pot: Potential = "harmonic".parse()?;
+    let props = pot.compute(&mol)?;
+    assert_relative_eq!(props.mp.get_energy().unwrap(), 0.125, epsilon = 1e-8);
+    let forces = props.mp.get_forces().unwrap();
+    // stretched bond: atoms are pulled together
+    assert_relative_eq!(forces[0][0], 0.5, epsilon = 1e-8);
+    assert_relative_eq!(forces[1][0], -0.5, epsilon = 1e-8);
+
+    // check forces against finite difference
+    let pot: Potential = "lj".parse()?;
+    let e = |x: f64| -> Result<f64> {
+        let atoms = vec![Atom::new("Ar", [0.0, 0.0, 0.0]), Atom::new("Ar", [x, 0.0, 0.0])];
+        Ok(pot.compute(&Molecule::from_atoms(atoms))?.mp.get_energy().unwrap())
+    };
+    let atoms = vec![Atom::new("Ar", [0.0, 0.0, 0.0]), Atom::new("Ar", [3.0, 0.0, 0.0])];
+    let props = pot.compute(&Molecule::from_atoms(atoms))?;
+    let fd = -(e(3.0 + 1e-5)? - e(3.0 - 1e-5)?) / 2e-5;
+    assert_relative_eq!(props.mp.get_forces().unwrap()[1][0], fd, epsilon = 1e-6);
+
+    Ok(())
+}
+// 2cac70da ends here
@@ -0,0 +1,440 @@
+// [[file:../vasp-tools.note::*docs][docs:1]]
+//! Resource control for the spawned VASP child process
+// docs:1 ends here
+
+// [[file:../vasp-tools.note::e2cebc11][e2cebc11]]
+use super::*;
+
+use std::process::Command;
+// e2cebc11 ends here
+
+// [[file:../vasp-tools.note::*mods][mods:1]]
+mod cgroup;
+mod mpi;
+mod ps;
+// mods:1 ends here
+
+// [[file:../vasp-tools.note::*pub][pub:1]]
+pub use cgroup::{try_create_cgroup, Cgroup};
+pub use mpi::{MpiControl, MpiLauncher};
+pub use ps::{list_descendants, list_processes, show_vasp_processes, ProcessInfo};
+// pub:1 ends here
+
+// [[file:../vasp-tools.note::86f16a6b][86f16a6b]]
+/// The max number of CPUs in `libc::cpu_set_t`
+const CPU_SETSIZE: usize = libc::CPU_SETSIZE as usize;
+
+/// Resource limits applied to the child process before `exec`
+#[derive(Debug, Clone, Default)]
+pub struct ResourceLimits {
+    /// Pin the child process to these CPU ids
+    pub cpu_set: Option<Vec<usize>>,
+    /// The niceness of the child process
+    pub nice: Option<i32>,
+    /// Max size of virtual memory in bytes (RLIMIT_AS)
+    pub max_memory: Option<u64>,
+    /// Max CPU time in seconds (RLIMIT_CPU)
+    pub max_cpu_time: Option<u64>,
+    /// Run the child process in a dedicated cgroup (v2) if possible, which
+    /// will be frozen/thawed for pause/resume instead of signals.
+    pub use_cgroup: bool,
+    /// Max memory in bytes for the cgroup (memory.max)
+    pub cgroup_memory_max: Option<u64>,
+    /// Max number of CPUs for the cgroup (cpu.max)
+    pub cgroup_cpu_max: Option<f64>,
+    /// The MPI launcher for signaling ranks on all nodes.
+    pub mpi: Option<MpiLauncher>,
+    /// The signal for pausing child process instead of SIGSTOP, e.g.
+    /// SIGTSTP for MPI stacks misbehaving under SIGSTOP
+    pub pause_signal: Option<UnixSignal>,
+    /// The signal for resuming child process instead of SIGCONT
+    pub resume_signal: Option<UnixSignal>,
+}
+
+impl ResourceLimits {
+    /// Return true if no limit is set
+    pub fn is_empty(&self) -> bool {
+        self.cpu_set.is_none() && self.nice.is_none() && self.max_memory.is_none() && self.max_cpu_time.is_none()
+    }
+
+    /// Read limits from env file `f`. Recognized keys:
+    ///
+    /// * VASP_CPU_SET: cpu list, e.g. "0-3,8"
+    /// * VASP_NICE: niceness, e.g. "10"
+    /// * VASP_MAX_MEMORY: max virtual memory in MB
+    /// * VASP_MAX_CPU_TIME: max CPU time in seconds
+    pub fn from_env_file(f: &Path) -> Result<Self> {
+        let env = envfile::EnvFile::new(f).with_context(|| format!("read env file {:?}", f))?;
+
+        let mut limits = Self::default();
+        if let Some(s) = env.get("VASP_CPU_SET") {
+            limits.cpu_set = parse_cpu_list(s)?.into();
+        }
+        if let Some(s) = env.get("VASP_NICE") {
+            limits.nice = s.trim().parse::<i32>().context("invalid VASP_NICE")?.into();
+        }
+        if let Some(s) = env.get("VASP_MAX_MEMORY") {
+            let mb: u64 = s.trim().parse().context("invalid VASP_MAX_MEMORY")?;
+            limits.max_memory = megabytes_to_bytes(mb).context("invalid VASP_MAX_MEMORY")?.into();
+        }
+        if let Some(s) = env.get("VASP_MAX_CPU_TIME") {
+            limits.max_cpu_time = s.trim().parse::<u64>().context("invalid VASP_MAX_CPU_TIME")?.into();
+        }
+
+        Ok(limits)
+    }
+
+    /// Update limits with values in `other` if they are set.
+    pub fn merge(&mut self, other: Self) {
+        if other.cpu_set.is_some() {
+            self.cpu_set = other.cpu_set;
+        }
+        if other.nice.is_some() {
+            self.nice = other.nice;
+        }
+        if other.max_memory.is_some() {
+            self.max_memory = other.max_memory;
+        }
+        if other.max_cpu_time.is_some() {
+            self.max_cpu_time = other.max_cpu_time;
+        }
+        self.use_cgroup |= other.use_cgroup;
+        if other.cgroup_memory_max.is_some() {
+            self.cgroup_memory_max = other.cgroup_memory_max;
+        }
+        if other.cgroup_cpu_max.is_some() {
+            self.cgroup_cpu_max = other.cgroup_cpu_max;
+        }
+        if other.mpi.is_some() {
+            self.mpi = other.mpi;
+        }
+        if other.pause_signal.is_some() {
+            self.pause_signal = other.pause_signal;
+        }
+        if other.resume_signal.is_some() {
+            self.resume_signal = other.resume_signal;
+        }
+    }
+}
+
+/// Convert memory size `mb` in MB into bytes.
+pub fn megabytes_to_bytes(mb: u64) -> Result<u64> {
+    mb.checked_mul(1024 * 1024)
+        .with_context(|| format!("memory size too large: {} MB", mb))
+}
+
+/// Parse cpu list in taskset style, e.g. "0-3,8,10-11". The cpu ids must be
+/// less than CPU_SETSIZE.
+pub fn parse_cpu_list(s: &str) -> Result<Vec<usize>> {
+    let mut cpus = vec![];
+    for part in s.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+        if let Some((a, b)) = part.split_once('-') {
+            let a: usize = a.trim().parse().with_context(|| format!("invalid cpu list: {:?}", s))?;
+            let b: usize = b.trim().parse().with_context(|| format!("invalid cpu list: {:?}", s))?;
+            ensure!(a <= b, "invalid cpu range: {:?}", part);
+            ensure!(b < CPU_SETSIZE, "cpu id {} out of range (max {})", b, CPU_SETSIZE - 1);
+            cpus.extend(a..=b);
+        } else {
+            let cpu: usize = part.parse().with_context(|| format!("invalid cpu list: {:?}", s))?;
+            ensure!(cpu < CPU_SETSIZE, "cpu id {} out of range (max {})", cpu, CPU_SETSIZE - 1);
+            cpus.push(cpu);
+        }
+    }
+    ensure!(!cpus.is_empty(), "empty cpu list");
+    Ok(cpus)
+}
+
+#[test]
+fn test_parse_cpu_list() -> Result<()> {
+    let cpus = parse_cpu_list("0-3,8, 10-11")?;
+    assert_eq!(cpus, vec![0, 1, 2, 3, 8, 10, 11]);
+    assert!(parse_cpu_list("3-1").is_err());
+    assert!(parse_cpu_list("").is_err());
+    assert!(parse_cpu_list("1024").is_err());
+    assert!(parse_cpu_list("0-4096").is_err());
+    assert_eq!(megabytes_to_bytes(2)?, 2 * 1024 * 1024);
+    assert!(megabytes_to_bytes(u64::MAX).is_err());
+
+    Ok(())
+}
+// 86f16a6b ends here
+
+// [[file:../vasp-tools.note::594c7101][594c7101]]
+/// The signals allowed for controlling child process: (name without "SIG"
+/// prefix, number)
+const UNIX_SIGNALS: &[(&str, libc::c_int)] = &[
+    ("STOP", libc::SIGSTOP),
+    ("TSTP", libc::SIGTSTP),
+    ("CONT", libc::SIGCONT),
+    ("INT", libc::SIGINT),
+    ("TERM", libc::SIGTERM),
+    ("USR1", libc::SIGUSR1),
+    ("USR2", libc::SIGUSR2),
+];
+
+/// A signal for controlling child process
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnixSignal {
+    name: &'static str,
+    number: libc::c_int,
+}
+
+impl UnixSignal {
+    pub const STOP: Self = Self {
+        name: "STOP",
+        number: libc::SIGSTOP,
+    };
+    pub const CONT: Self = Self {
+        name: "CONT",
+        number: libc::SIGCONT,
+    };
+    pub const INT: Self = Self {
+        name: "INT",
+        number: libc::SIGINT,
+    };
+
+    /// The signal name without "SIG" prefix, e.g. "STOP"
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// The signal number
+    pub fn number(&self) -> libc::c_int {
+        self.number
+    }
+}
+
+impl std::str::FromStr for UnixSignal {
+    type Err = Error;
+
+    /// Parse from signal name with or without "SIG" prefix, e.g. "SIGTSTP"
+    /// or "tstp"
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim().to_uppercase();
+        let name = s.strip_prefix("SIG").unwrap_or(&s);
+        UNIX_SIGNALS
+            .iter()
+            .find(|(x, _)| *x == name)
+            .map(|&(name, number)| Self { name, number })
+            .with_context(|| {
+                let names: Vec<_> = UNIX_SIGNALS.iter().map(|(x, _)| *x).collect();
+                format!("invalid signal {:?}, expect one of {:?}", s, names)
+            })
+    }
+}
+
+#[test]
+fn test_unix_signal() -> Result<()> {
+    let sig: UnixSignal = "SIGTSTP".parse()?;
+    assert_eq!(sig.name(), "TSTP");
+    assert_eq!(sig.number(), libc::SIGTSTP);
+    assert_eq!("stop".parse::<UnixSignal>()?, UnixSignal::STOP);
+    assert!("KILL".parse::<UnixSignal>().is_err());
+
+    Ok(())
+}
+// 594c7101 ends here
+
+// [[file:../vasp-tools.note::7e2ba4eb][7e2ba4eb]]
+/// Extension for controlling the process (group) to be spawned
+pub trait ProcessGroupExt {
+    /// Apply CPU affinity, niceness and rlimits in child process before exec.
+    fn apply_resource_limits(&mut self, limits: &ResourceLimits) -> &mut Self;
+
+    /// Move child process into `cgroup` before exec.
+    fn join_cgroup(&mut self, cgroup: &Cgroup) -> Result<&mut Self>;
+}
+
+impl ProcessGroupExt for Command {
+    fn join_cgroup(&mut self, cgroup: &Cgroup) -> Result<&mut Self> {
+        cgroup::join_cgroup_before_exec(self, cgroup)?;
+        Ok(self)
+    }
+
+    fn apply_resource_limits(&mut self, limits: &ResourceLimits) -> &mut Self {
+        use std::os::unix::process::CommandExt;
+
+        if limits.is_empty() {
+            return self;
+        }
+        debug!("apply resource limits for child process: {:?}", limits);
+
+        // NOTE: prepare cpu set outside the closure: no allocation is allowed
+        // after fork
+        let cpu_set = limits.cpu_set.as_ref().map(|cpus| {
+            let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+            // NOTE: CPU_SET panics for cpu ids out of range, which are
+            // rejected in `parse_cpu_list`
+            for &cpu in cpus.iter().filter(|&&cpu| cpu < CPU_SETSIZE) {
+                unsafe { libc::CPU_SET(cpu, &mut set) };
+            }
+            set
+        });
+        let nice = limits.nice;
+        let max_memory = limits.max_memory;
+        let max_cpu_time = limits.max_cpu_time;
+
+        unsafe {
+            self.pre_exec(move || {
+                if let Some(set) = cpu_set.as_ref() {
+                    if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), set) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                if let Some(nice) = nice {
+                    if libc::setpriority(libc::PRIO_PROCESS, 0, nice) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                if let Some(n) = max_memory {
+                    set_rlimit(libc::RLIMIT_AS, n)?;
+                }
+                if let Some(n) = max_cpu_time {
+                    set_rlimit(libc::RLIMIT_CPU, n)?;
+                }
+                Ok(())
+            });
+        }
+        self
+    }
+}
+
+fn set_rlimit(resource: libc::__rlimit_resource_t, n: u64) -> std::io::Result<()> {
+    let lim = libc::rlimit {
+        rlim_cur: n as libc::rlim_t,
+        rlim_max: n as libc::rlim_t,
+    };
+    if unsafe { libc::setrlimit(resource, &lim) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+// 7e2ba4eb ends here
+
+// [[file:../vasp-tools.note::31fe1d2a][31fe1d2a]]
+use crate::session::SessionHandler;
+
+/// Run blocking `f` without stalling other tasks of async runtime, which
+/// is possible only in multi-threaded runtime.
+pub(crate) fn run_blocking<T>(f: impl FnOnce() -> T) -> T {
+    use tokio::runtime::{Handle, RuntimeFlavor};
+
+    match Handle::try_current().map(|h| h.runtime_flavor()) {
+        Ok(RuntimeFlavor::MultiThread) => tokio::task::block_in_place(f),
+        _ => f(),
+    }
+}
+
+/// Pause/resume/terminate the child process, using cgroup freezer or MPI
+/// launcher if available, and fall back to signaling the session.
+#[derive(Debug, Default)]
+pub struct ProcessControl {
+    cgroup: Option<Cgroup>,
+    mpi: Option<MpiControl>,
+    // signals for pause/resume, SIGSTOP/SIGCONT if not set
+    pause_signal: Option<UnixSignal>,
+    resume_signal: Option<UnixSignal>,
+}
+
+impl ProcessControl {
+    /// Prepare `command` to be spawned with resource `limits`. Return error
+    /// if cgroup limits requested cannot be applied.
+    pub fn prepare(command: &mut Command, limits: &ResourceLimits) -> Result<Self> {
+        command.apply_resource_limits(limits);
+        let cgroup = if limits.use_cgroup {
+            try_create_cgroup(limits)?.and_then(|cg| match command.join_cgroup(&cg) {
+                Ok(_) => Some(cg),
+                Err(e) => {
+                    warn!("cannot join cgroup, fall back to signals: {:?}", e);
+                    None
+                }
+            })
+        } else {
+            None
+        };
+        let mpi = limits.mpi.map(|l| {
+            let mpi = MpiControl::new(l);
+            mpi.prepare(command);
+            mpi
+        });
+
+        Ok(Self {
+            cgroup,
+            mpi,
+            pause_signal: limits.pause_signal,
+            resume_signal: limits.resume_signal,
+        })
+    }
+
+    pub fn pause(&self, s: &SessionHandler) -> Result<()> {
+        let sig = self.pause_signal.unwrap_or(UnixSignal::STOP);
+        if let Some(cg) = &self.cgroup {
+            match cg.freeze() {
+                Ok(_) => return Ok(()),
+                Err(e) => warn!("freeze cgroup failed, fall back to signal: {:?}", e),
+            }
+        }
+        if let Some(mpi) = &self.mpi {
+            match mpi.signal(sig.name()) {
+                Ok(_) => return Ok(()),
+                Err(e) => warn!("signal MPI ranks failed, fall back to session: {:?}", e),
+            }
+        }
+        s.signal(sig.number())?;
+        Ok(())
+    }
+
+    pub fn resume(&self, s: &SessionHandler) -> Result<()> {
+        let sig = self.resume_signal.unwrap_or(UnixSignal::CONT);
+        if let Some(cg) = &self.cgroup {
+            match cg.thaw() {
+                Ok(_) => return Ok(()),
+                Err(e) => warn!("thaw cgroup failed, fall back to signal: {:?}", e),
+            }
+        }
+        if let Some(mpi) = &self.mpi {
+            match mpi.signal(sig.name()) {
+                Ok(_) => return Ok(()),
+                Err(e) => warn!("signal MPI ranks failed, fall back to session: {:?}", e),
+            }
+        }
+        s.signal(sig.number())?;
+        Ok(())
+    }
+
+    /// Relay SIGINT to child process, letting VASP finish the current SCF
+    /// step cleanly, e.g. before pause.
+    pub fn interrupt(&self, s: &SessionHandler) -> Result<()> {
+        if let Some(mpi) = &self.mpi {
+            match mpi.signal(UnixSignal::INT.name()) {
+                Ok(_) => return Ok(()),
+                Err(e) => warn!("signal MPI ranks failed, fall back to session: {:?}", e),
+            }
+        }
+        s.interrupt()?;
+        Ok(())
+    }
+
+    pub async fn terminate(&self, s: &SessionHandler) -> Result<()> {
+        // frozen processes cannot handle the termination signal
+        if let Some(cg) = &self.cgroup {
+            cg.thaw().ok();
+        }
+        if let Some(mpi) = &self.mpi {
+            // wait at most 30 seconds for all ranks to exit
+            if let Err(e) = mpi.terminate(30.0).await {
+                error!("terminate MPI ranks failed: {:?}", e);
+            }
+        }
+        s.terminate()?;
+        Ok(())
+    }
+
+    /// Terminate child process as `terminate`, blocking until done, for
+    /// callers outside of async runtime.
+    pub fn terminate_blocking(&self, s: &SessionHandler) -> Result<()> {
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+        rt.block_on(self.terminate(s))
+    }
+}
+// 31fe1d2a ends here
@@ -0,0 +1,315 @@
+// [[file:../../vasp-tools.note::50d554c6][50d554c6]]
+use super::*;
+
+use std::ffi::CString;
+// 50d554c6 ends here
+
+// [[file:../../vasp-tools.note::df373ab5][df373ab5]]
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// A cgroup (v2) created for child process. The cgroup directory will be
+/// removed when dropped.
+#[derive(Debug)]
+pub struct Cgroup {
+    path: PathBuf,
+}
+
+/// How long to wait for processes in cgroup being frozen
+const FREEZE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// Return the cgroup v2 directory under `root` from the content of
+/// /proc/self/cgroup.
+fn cgroup_dir_from(proc_cgroup: &str, root: &Path) -> Result<PathBuf> {
+    // 0::/user.slice/user-1000.slice/user@1000.service/app.slice/xx.scope
+    let rel = proc_cgroup
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .ok_or(format_err!("no cgroup v2 hierarchy found"))?;
+    Ok(root.join(rel.trim().trim_start_matches('/')))
+}
+
+/// Return the cgroup v2 directory of current process
+fn current_cgroup_dir() -> Result<PathBuf> {
+    let s = gut::fs::read_file("/proc/self/cgroup")?;
+    let dir = cgroup_dir_from(&s, CGROUP_ROOT.as_ref())?;
+    ensure!(dir.join("cgroup.procs").exists(), "invalid cgroup dir: {:?}", dir);
+    Ok(dir)
+}
+
+/// The leaf cgroup for current process, when its cgroup is used as parent
+/// of VASP cgroups with controllers enabled
+const SERVER_LEAF: &str = "server";
+
+/// The cgroup current process was in, before it moved into `SERVER_LEAF`
+static DELEGATED_DIR: std::sync::OnceLock<PathBuf> = std::sync::OnceLock::new();
+
+/// Move current process out of cgroup `dir` into its leaf child cgroup
+/// `SERVER_LEAF`. Controllers can be enabled only in a cgroup without
+/// processes (the no-internal-process rule of cgroup v2).
+fn move_into_leaf(dir: &Path) -> Result<()> {
+    let leaf = dir.join(SERVER_LEAF);
+    if !leaf.exists() {
+        std::fs::create_dir(&leaf).with_context(|| format!("create cgroup {:?}", leaf))?;
+    }
+    let f = leaf.join("cgroup.procs");
+    std::fs::write(&f, std::process::id().to_string()).with_context(|| format!("move into cgroup {:?}", leaf))?;
+    debug!("moved into cgroup {:?}", leaf);
+    Ok(())
+}
+
+/// Return the parent cgroup directory for VASP cgroups with `controllers`
+/// enabled: the cgroup of current process, which moves into a leaf cgroup
+/// first if any controller required.
+fn parent_cgroup_dir(controllers: &[&str]) -> Result<PathBuf> {
+    if let Some(dir) = DELEGATED_DIR.get() {
+        return Ok(dir.clone());
+    }
+    let dir = current_cgroup_dir()?;
+    if controllers.is_empty() {
+        return Ok(dir);
+    }
+    move_into_leaf(&dir)?;
+    Ok(DELEGATED_DIR.get_or_init(|| dir).clone())
+}
+
+/// Enable `controllers` (e.g. "memory") for child cgroups of `parent` in
+/// its cgroup.subtree_control, without which the interface files such as
+/// memory.max are missing.
+fn enable_controllers(parent: &Path, controllers: &[&str]) -> Result<()> {
+    let available = gut::fs::read_file(parent.join("cgroup.controllers"))?;
+    let enabled = gut::fs::read_file(parent.join("cgroup.subtree_control")).unwrap_or_default();
+    for c in controllers {
+        ensure!(
+            available.split_whitespace().any(|x| x == *c),
+            "controller {:?} is not available in {:?}",
+            c,
+            parent
+        );
+        if enabled.split_whitespace().any(|x| x == *c) {
+            continue;
+        }
+        let f = parent.join("cgroup.subtree_control");
+        std::fs::write(&f, format!("+{}", c)).with_context(|| format!("enable controller {:?} in {:?}", c, f))?;
+    }
+    Ok(())
+}
+
+/// Return true if cgroup.events reports the cgroup is frozen.
+fn is_frozen(events: &str) -> bool {
+    events.lines().any(|line| line.split_whitespace().eq(["frozen", "1"]))
+}
+
+impl Cgroup {
+    /// Create a new cgroup under the cgroup of current process. This requires
+    /// the user has a delegated cgroup, for example, from `systemd-run --user
+    /// --scope -p Delegate=yes`.
+    pub fn create(name: &str) -> Result<Self> {
+        Self::create_in(&current_cgroup_dir()?, name)
+    }
+
+    /// Create a new cgroup `name` under `parent` cgroup directory.
+    fn create_in(parent: &Path, name: &str) -> Result<Self> {
+        let path = parent.join(name);
+        std::fs::create_dir(&path).with_context(|| format!("create cgroup {:?}", path))?;
+        debug!("created cgroup {:?}", path);
+
+        Ok(Self { path })
+    }
+
+    /// The cgroup directory
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Freeze all processes in this cgroup, and wait until they are all
+    /// frozen.
+    pub fn freeze(&self) -> Result<()> {
+        debug!("freeze cgroup {:?}", self.path);
+        self.write("cgroup.freeze", "1")?;
+        self.wait_frozen(FREEZE_TIMEOUT)
+    }
+
+    /// Wait at most `timeout` for cgroup.events reporting "frozen 1",
+    /// without stalling other tasks of async runtime.
+    fn wait_frozen(&self, timeout: std::time::Duration) -> Result<()> {
+        let f = self.path.join("cgroup.events");
+        let t = std::time::Instant::now();
+        super::run_blocking(|| loop {
+            if is_frozen(&gut::fs::read_file(&f)?) {
+                return Ok(());
+            }
+            ensure!(t.elapsed() < timeout, "cgroup {:?} not frozen in {:?}", self.path, timeout);
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        })
+    }
+
+    /// Thaw all processes in this cgroup
+    pub fn thaw(&self) -> Result<()> {
+        debug!("thaw cgroup {:?}", self.path);
+        self.write("cgroup.freeze", "0")
+    }
+
+    /// Set max memory usage in bytes (memory.max)
+    pub fn set_memory_max(&self, bytes: u64) -> Result<()> {
+        self.write("memory.max", &bytes.to_string())
+    }
+
+    /// Limit CPU bandwidth in number of CPUs (cpu.max)
+    pub fn set_cpu_max(&self, ncpus: f64) -> Result<()> {
+        let period = 100000;
+        let quota = (ncpus * period as f64) as u64;
+        self.write("cpu.max", &format!("{} {}", quota, period))
+    }
+
+    fn write(&self, key: &str, value: &str) -> Result<()> {
+        let f = self.path.join(key);
+        std::fs::write(&f, value).with_context(|| format!("write {:?} to {:?}", value, f))?;
+        Ok(())
+    }
+
+    /// Return the path to `cgroup.procs` for moving child process into this
+    /// cgroup before exec.
+    fn procs_file(&self) -> Result<CString> {
+        let f = self.path.join("cgroup.procs");
+        let s = CString::new(f.to_string_lossy().as_bytes())?;
+        Ok(s)
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        // NOTE: it will fail if there are processes still alive in it
+        if let Err(e) = std::fs::remove_dir(&self.path) {
+            debug!("failed to remove cgroup {:?}: {:?}", self.path, e);
+        }
+    }
+}
+// df373ab5 ends here
+
+// [[file:../../vasp-tools.note::0b4c05ae][0b4c05ae]]
+/// The cgroup controllers required by `limits`
+fn required_controllers(limits: &ResourceLimits) -> Vec<&'static str> {
+    let mut controllers = vec![];
+    if limits.cgroup_memory_max.is_some() {
+        controllers.push("memory");
+    }
+    if limits.cgroup_cpu_max.is_some() {
+        controllers.push("cpu");
+    }
+    controllers
+}
+
+/// Create a cgroup for the VASP process to be spawned, with optional memory and
+/// cpu limits. Return None if cgroup is not available, or error if the limits
+/// requested cannot be applied.
+pub fn try_create_cgroup(limits: &ResourceLimits) -> Result<Option<Cgroup>> {
+    let controllers = required_controllers(limits);
+    match parent_cgroup_dir(&controllers) {
+        Ok(parent) => try_create_cgroup_in(&parent, limits),
+        Err(e) if controllers.is_empty() => {
+            warn!("cgroup is not available, fall back to signals: {:?}", e);
+            Ok(None)
+        }
+        Err(e) => Err(e.context("cgroup limits requested, but no delegated cgroup available")),
+    }
+}
+
+/// Create a cgroup under `parent` as `try_create_cgroup`, with
+/// controllers enabled in `parent`, which has no processes in it.
+fn try_create_cgroup_in(parent: &Path, limits: &ResourceLimits) -> Result<Option<Cgroup>> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // NOTE: one cgroup for each VASP launched in this process
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let name = format!("vasp-tools-{}-{}", std::process::id(), COUNTER.fetch_add(1, Ordering::SeqCst));
+    let controllers = required_controllers(limits);
+    if controllers.is_empty() {
+        return match Cgroup::create_in(parent, &name) {
+            Ok(cg) => Ok(Some(cg)),
+            Err(e) => {
+                warn!("cgroup is not available, fall back to signals: {:?}", e);
+                Ok(None)
+            }
+        };
+    }
+    enable_controllers(parent, &controllers)
+        .with_context(|| format!("cannot enable cgroup controllers {:?}", controllers))?;
+    let cg = Cgroup::create_in(parent, &name)?;
+    if let Some(n) = limits.cgroup_memory_max {
+        cg.set_memory_max(n).context("cannot set cgroup memory limit")?;
+    }
+    if let Some(n) = limits.cgroup_cpu_max {
+        cg.set_cpu_max(n).context("cannot set cgroup cpu limit")?;
+    }
+    Ok(Some(cg))
+}
+
+/// Move the process to be spawned into `cgroup`. All its descendants (MPI
+/// ranks on the same node) will be in the same cgroup.
+pub(super) fn join_cgroup_before_exec(command: &mut Command, cgroup: &Cgroup) -> Result<()> {
+    use std::os::unix::process::CommandExt;
+
+    let procs = cgroup.procs_file()?;
+    unsafe {
+        command.pre_exec(move || {
+            let fd = libc::open(procs.as_ptr(), libc::O_WRONLY);
+            if fd < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            // writing "0" moves the writing process itself
+            let n = libc::write(fd, b"0".as_ptr() as *const libc::c_void, 1);
+            libc::close(fd);
+            if n < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    Ok(())
+}
+// 0b4c05ae ends here
+
+// [[file:../../vasp-tools.note::0004524a][0004524a]]
+#[test]
+fn test_cgroup() -> Result<()> {
+    let s = "0::/user.slice/user-1000.slice/app.slice/run.scope\n";
+    let dir = cgroup_dir_from(s, "/sys/fs/cgroup".as_ref())?;
+    assert_eq!(dir, Path::new("/sys/fs/cgroup/user.slice/user-1000.slice/app.slice/run.scope"));
+    // cgroup v1 only
+    assert!(cgroup_dir_from("12:cpu,cpuacct:/user.slice\n", "/sys/fs/cgroup".as_ref()).is_err());
+    assert!(is_frozen("populated 1\nfrozen 1\n"));
+    assert!(!is_frozen("populated 1\nfrozen 0\n"));
+
+    // fall back to signals when cgroup is not available, but limits
+    // requested must be applied
+    let limits = ResourceLimits {
+        cgroup_memory_max: Some(1 << 30),
+        ..Default::default()
+    };
+    assert!(try_create_cgroup_in("/nonexistent/cgroup".as_ref(), &Default::default())?.is_none());
+    assert!(try_create_cgroup_in("/nonexistent/cgroup".as_ref(), &limits).is_err());
+
+    // a fake cgroup hierarchy in plain directory
+    let tdir = tempfile::tempdir()?;
+    let parent = tdir.path();
+    gut::fs::write_to_file(parent.join("cgroup.controllers"), "cpu memory pids\n")?;
+    gut::fs::write_to_file(parent.join("cgroup.subtree_control"), "")?;
+    gut::fs::write_to_file(parent.join("cgroup.procs"), "")?;
+    // the server moves into a leaf cgroup, leaving the parent empty
+    move_into_leaf(parent)?;
+    assert_eq!(gut::fs::read_file(parent.join(SERVER_LEAF).join("cgroup.procs"))?, std::process::id().to_string());
+    let cg = try_create_cgroup_in(parent, &limits)?.expect("cgroup");
+    assert_ne!(try_create_cgroup_in(parent, &Default::default())?.expect("cgroup").path(), cg.path());
+    assert_eq!(gut::fs::read_file(parent.join("cgroup.subtree_control"))?, "+memory");
+    assert_eq!(gut::fs::read_file(cg.path().join("memory.max"))?, "1073741824");
+    assert!(enable_controllers(parent, &["io"]).is_err());
+
+    gut::fs::write_to_file(cg.path().join("cgroup.events"), "populated 1\nfrozen 0\n")?;
+    assert!(cg.wait_frozen(std::time::Duration::from_millis(50)).is_err());
+    gut::fs::write_to_file(cg.path().join("cgroup.events"), "populated 1\nfrozen 1\n")?;
+    cg.freeze()?;
+    assert_eq!(gut::fs::read_file(cg.path().join("cgroup.freeze"))?, "1");
+
+    Ok(())
+}
+// 0004524a ends here
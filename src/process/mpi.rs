@@ -0,0 +1,221 @@
+// [[file:../../vasp-tools.note::24a62e32][24a62e32]]
+use super::*;
+// 24a62e32 ends here
+
+// [[file:../../vasp-tools.note::869c3f44][869c3f44]]
+/// The env var for the pid file of mpirun. The script for running VASP should
+/// call `mpirun --report-pid $VASP_TOOLS_MPI_PIDFILE vasp_std` for OpenMPI.
+pub const MPI_PIDFILE_ENV: &str = "VASP_TOOLS_MPI_PIDFILE";
+
+/// The env var for the file recording the SLURM step id of srun. The script
+/// for running VASP should write it from the first task, e.g. `srun bash -c
+/// '[ $SLURM_PROCID = 0 ] && echo $SLURM_STEP_ID > $VASP_TOOLS_MPI_STEPFILE;
+/// exec vasp_std'`.
+pub const MPI_STEPFILE_ENV: &str = "VASP_TOOLS_MPI_STEPFILE";
+
+/// The MPI launcher used for running VASP
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MpiLauncher {
+    /// OpenMPI's mpirun, which forwards signals to all ranks
+    OpenMpi,
+    /// SLURM's srun, which could be signaled using scancel
+    Srun,
+}
+
+impl std::str::FromStr for MpiLauncher {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "openmpi" | "mpirun" => Ok(Self::OpenMpi),
+            "srun" | "slurm" => Ok(Self::Srun),
+            _ => bail!("unsupported MPI launcher: {:?}", s),
+        }
+    }
+}
+
+impl MpiLauncher {
+    /// Guess the MPI launcher from the script for running VASP
+    pub fn detect(program: &Path) -> Option<Self> {
+        let s = std::fs::read(program).ok()?;
+        let s = String::from_utf8_lossy(&s);
+        if s.contains("srun ") && std::env::var("SLURM_JOB_ID").is_ok() {
+            Some(Self::Srun)
+        } else if s.contains("mpirun ") || s.contains("mpiexec ") {
+            Some(Self::OpenMpi)
+        } else {
+            None
+        }
+    }
+}
+// 869c3f44 ends here
+
+// [[file:../../vasp-tools.note::e1086e1c][e1086e1c]]
+/// Signal all MPI ranks (possibly on remote nodes) using launcher specific
+/// mechanism.
+#[derive(Debug)]
+pub struct MpiControl {
+    launcher: MpiLauncher,
+    // the file for mpirun pid or srun step id, unique for each control as
+    // several VASP could be launched in one process
+    pidfile: PathBuf,
+}
+
+impl MpiControl {
+    pub fn new(launcher: MpiLauncher) -> Self {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let n = COUNTER.fetch_add(1, Ordering::SeqCst);
+        let name = match launcher {
+            MpiLauncher::OpenMpi => "mpirun",
+            MpiLauncher::Srun => "srun",
+        };
+        let pidfile = std::env::temp_dir().join(format!("vasp-tools-{}-{}-{}.pid", name, std::process::id(), n));
+        Self { launcher, pidfile }
+    }
+
+    /// Set env var for the command to be spawned, so that mpirun could report
+    /// its pid, or srun its step id.
+    pub fn prepare(&self, command: &mut Command) {
+        debug!("MPI launcher: {:?}", self.launcher);
+        // NOTE: remove the file left by previous run
+        let _ = std::fs::remove_file(&self.pidfile);
+        match self.launcher {
+            MpiLauncher::OpenMpi => command.env(MPI_PIDFILE_ENV, &self.pidfile),
+            MpiLauncher::Srun => command.env(MPI_STEPFILE_ENV, &self.pidfile),
+        };
+    }
+
+    fn mpirun_pid(&self) -> Result<libc::pid_t> {
+        let s = gut::fs::read_file(&self.pidfile).with_context(|| {
+            format!(
+                "no mpirun pid file found. Call `mpirun --report-pid ${}` in your script.",
+                MPI_PIDFILE_ENV
+            )
+        })?;
+        let pid = s.trim().parse().with_context(|| format!("invalid pid: {:?}", s))?;
+        Ok(pid)
+    }
+
+    fn slurm_job_id() -> Result<String> {
+        std::env::var("SLURM_JOB_ID").context("SLURM_JOB_ID not set")
+    }
+
+    /// The SLURM step launched by srun, in "jobid.stepid" format.
+    fn slurm_step(&self) -> Result<String> {
+        let job_id = Self::slurm_job_id()?;
+        let s = gut::fs::read_file(&self.pidfile).with_context(|| {
+            format!(
+                "no srun step file found. Write $SLURM_STEP_ID into ${} in your script.",
+                MPI_STEPFILE_ENV
+            )
+        })?;
+        let step_id: u32 = s.trim().parse().with_context(|| format!("invalid step id: {:?}", s))?;
+        Ok(format!("{}.{}", job_id, step_id))
+    }
+
+    /// Send `signal` to all ranks. `signal` is the signal name without "SIG"
+    /// prefix, such as "STOP", "CONT" or "TERM".
+    pub fn signal(&self, signal: &str) -> Result<()> {
+        match self.launcher {
+            MpiLauncher::Srun => {
+                // NOTE: only the step of this VASP, not other steps in the job
+                let step = self.slurm_step()?;
+                debug!("scancel --signal={} {}", signal, step);
+                duct::cmd!("scancel", format!("--signal={}", signal), &step)
+                    .run()
+                    .context("scancel failure")?;
+            }
+            MpiLauncher::OpenMpi => {
+                let pid = self.mpirun_pid()?;
+                // NOTE: mpirun forwards SIGTSTP as SIGSTOP to all ranks
+                let sig = match signal {
+                    "STOP" | "TSTP" => libc::SIGTSTP,
+                    "CONT" => libc::SIGCONT,
+                    "USR1" => libc::SIGUSR1,
+                    "USR2" => libc::SIGUSR2,
+                    "TERM" => libc::SIGTERM,
+                    "KILL" => libc::SIGKILL,
+                    "INT" => libc::SIGINT,
+                    _ => bail!("unsupported signal: {}", signal),
+                };
+                debug!("send signal {} to mpirun {}", signal, pid);
+                if unsafe { libc::kill(pid, sig) } != 0 {
+                    bail!("kill mpirun failed: {:?}", std::io::Error::last_os_error());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Return true if all MPI ranks exited
+    pub fn ranks_exited(&self) -> Result<bool> {
+        match self.launcher {
+            MpiLauncher::Srun => {
+                let step = self.slurm_step()?;
+                let out = duct::cmd!("squeue", "-h", "-s", "-j", Self::slurm_job_id()?, "-o", "%i").read()?;
+                Ok(!step_running(&out, &step))
+            }
+            MpiLauncher::OpenMpi => {
+                // mpirun exits only after all ranks exited
+                let pid = self.mpirun_pid()?;
+                Ok(unsafe { libc::kill(pid, 0) } != 0)
+            }
+        }
+    }
+
+    /// Terminate all ranks, and wait until they exited for max `timeout`
+    /// seconds.
+    pub async fn terminate(&self, timeout: f64) -> Result<()> {
+        let interval = std::time::Duration::from_secs_f64(0.5);
+
+        self.signal("TERM")?;
+        let mut t = 0.0;
+        while t < timeout {
+            if self.ranks_exited()? {
+                info!("All MPI ranks exited.");
+                return Ok(());
+            }
+            tokio::time::sleep(interval).await;
+            t += interval.as_secs_f64();
+        }
+        warn!("MPI ranks still alive after {} seconds, send SIGKILL.", timeout);
+        self.signal("KILL")?;
+        tokio::time::sleep(interval).await;
+        ensure!(self.ranks_exited()?, "MPI ranks still alive");
+
+        Ok(())
+    }
+}
+
+/// Return true if `step` ("jobid.stepid") is listed in output of `squeue -s
+/// -o %i`.
+fn step_running(squeue: &str, step: &str) -> bool {
+    squeue.lines().any(|x| x.trim() == step)
+}
+
+impl Drop for MpiControl {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.pidfile);
+    }
+}
+
+#[test]
+fn test_mpi_launcher() {
+    let l: MpiLauncher = "OpenMPI".parse().unwrap();
+    assert_eq!(l, MpiLauncher::OpenMpi);
+    let l: MpiLauncher = "srun".parse().unwrap();
+    assert_eq!(l, MpiLauncher::Srun);
+    assert!("mvapich".parse::<MpiLauncher>().is_err());
+
+    // each control has its own pid file
+    let a = MpiControl::new(MpiLauncher::OpenMpi);
+    let b = MpiControl::new(MpiLauncher::OpenMpi);
+    assert_ne!(a.pidfile, b.pidfile);
+
+    let squeue = "1234.batch\n1234.0\n1234.12\n";
+    assert!(step_running(squeue, "1234.12"));
+    assert!(!step_running(squeue, "1234.1"));
+}
+// e1086e1c ends here
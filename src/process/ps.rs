@@ -0,0 +1,147 @@
+// [[file:../../vasp-tools.note::953f89c0][953f89c0]]
+use super::*;
+// 953f89c0 ends here
+
+// [[file:../../vasp-tools.note::511ec2ac][511ec2ac]]
+/// Brief information of a running process read from /proc
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ProcessInfo {
+    pub pid: u32,
+    pub ppid: u32,
+    pub pgid: u32,
+    /// session id, which is the pid of session leader
+    pub sid: u32,
+    pub cmdline: String,
+    /// The working directory of the process
+    pub cwd: Option<PathBuf>,
+}
+
+fn is_alive(pid: u32) -> bool {
+    Path::new("/proc").join(pid.to_string()).exists()
+}
+
+// 12345 (vasp_std) S 12340 12340 12340 0 -1 ...
+fn parse_proc_stat(s: &str) -> Option<(u32, u32, u32)> {
+    // NOTE: the command name may contain spaces or parentheses
+    let (_, rest) = s.rsplit_once(')')?;
+    let attrs: Vec<_> = rest.split_whitespace().collect();
+    if attrs.len() < 4 {
+        return None;
+    }
+    let ppid = attrs[1].parse().ok()?;
+    let pgid = attrs[2].parse().ok()?;
+    let sid = attrs[3].parse().ok()?;
+    Some((ppid, pgid, sid))
+}
+
+impl ProcessInfo {
+    fn from_pid(pid: u32) -> Option<Self> {
+        let dir = Path::new("/proc").join(pid.to_string());
+        let stat = std::fs::read_to_string(dir.join("stat")).ok()?;
+        let (ppid, pgid, sid) = parse_proc_stat(&stat)?;
+        let cmdline = std::fs::read(dir.join("cmdline")).ok()?;
+        let cmdline = String::from_utf8_lossy(&cmdline).replace('\0', " ").trim().to_string();
+        let cwd = std::fs::read_link(dir.join("cwd")).ok();
+        Some(Self {
+            pid,
+            ppid,
+            pgid,
+            sid,
+            cmdline,
+            cwd,
+        })
+    }
+
+    /// Return true if the session leader has gone, or the process has been
+    /// re-parented to init.
+    pub fn is_orphan(&self) -> bool {
+        self.ppid == 1 || (self.sid != self.pid && !is_alive(self.sid))
+    }
+
+    /// Send `signal` to this process.
+    pub fn kill(&self, signal: i32) -> Result<()> {
+        if unsafe { libc::kill(self.pid as libc::pid_t, signal) } != 0 {
+            let e = std::io::Error::last_os_error();
+            bail!("kill process {} failed: {:?}", self.pid, e);
+        }
+        Ok(())
+    }
+}
+
+/// List processes owned by current user with command line containing
+/// `pattern`.
+pub fn list_processes(pattern: &str) -> Result<Vec<ProcessInfo>> {
+    use std::os::unix::fs::MetadataExt;
+
+    let uid = unsafe { libc::getuid() };
+    let this = std::process::id();
+    let mut procs = vec![];
+    for entry in std::fs::read_dir("/proc")? {
+        let entry = entry?;
+        let pid: u32 = match entry.file_name().to_string_lossy().parse() {
+            Ok(pid) => pid,
+            Err(_) => continue,
+        };
+        if pid == this {
+            continue;
+        }
+        // the process could exit at any time
+        match entry.metadata() {
+            Ok(m) if m.uid() == uid => {}
+            _ => continue,
+        }
+        if let Some(p) = ProcessInfo::from_pid(pid) {
+            if p.cmdline.contains(pattern) {
+                procs.push(p);
+            }
+        }
+    }
+    procs.sort_by_key(|p| p.pid);
+
+    Ok(procs)
+}
+
+/// List all descendant processes of process `pid` owned by current user.
+pub fn list_descendants(pid: u32) -> Result<Vec<ProcessInfo>> {
+    let procs = list_processes("")?;
+    let mut parents = vec![pid];
+    let mut descendants = vec![];
+    while let Some(ppid) = parents.pop() {
+        for p in procs.iter().filter(|p| p.ppid == ppid) {
+            parents.push(p.pid);
+            descendants.push(p.clone());
+        }
+    }
+    Ok(descendants)
+}
+
+/// Show VASP processes of current user. Orphaned processes will be terminated
+/// if `kill` is true.
+pub fn show_vasp_processes(pattern: &str, orphans_only: bool, kill: bool, force: bool) -> Result<()> {
+    let procs = list_processes(pattern)?;
+    println!("{:>8} {:>8} {:>8} {:>7} {:<40} {}", "PID", "PPID", "SID", "ORPHAN", "CWD", "CMD");
+    for p in procs.iter().filter(|p| !orphans_only || p.is_orphan()) {
+        let cwd = p.cwd.as_ref().map(|d| d.display().to_string()).unwrap_or("--".into());
+        let orphan = if p.is_orphan() { "yes" } else { "no" };
+        println!("{:>8} {:>8} {:>8} {:>7} {:<40} {}", p.pid, p.ppid, p.sid, orphan, cwd, p.cmdline);
+        if kill && p.is_orphan() {
+            let signal = if force { libc::SIGKILL } else { libc::SIGTERM };
+            match p.kill(signal) {
+                Ok(_) => info!("sent signal {} to process {}", signal, p.pid),
+                Err(e) => error!("{:?}", e),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_parse_proc_stat() {
+    let s = "12345 (vasp std) S 1 12340 12339 0 -1 4194560";
+    let (ppid, pgid, sid) = parse_proc_stat(s).unwrap();
+    assert_eq!(ppid, 1);
+    assert_eq!(pgid, 12340);
+    assert_eq!(sid, 12339);
+}
+// 511ec2ac ends here
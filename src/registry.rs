@@ -0,0 +1,193 @@
+// [[file:../vasp-tools.note::*docs][docs:1]]
+//! Registry of running interactive VASP servers for discovery by name
+// docs:1 ends here
+
+// [[file:../vasp-tools.note::0318ca0b][0318ca0b]]
+use super::*;
+
+use serde::{Deserialize, Serialize};
+// 0318ca0b ends here
+
+// [[file:../vasp-tools.note::d09b0d2f][d09b0d2f]]
+/// The metadata of a running interactive server
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerEntry {
+    pub name: String,
+    /// The absolute path to the socket file
+    pub socket_file: PathBuf,
+    /// The working directory of VASP
+    pub wrk_dir: PathBuf,
+    pub pid: u32,
+    /// The start time in RFC 3339 format
+    pub start_time: String,
+}
+
+impl ServerEntry {
+    /// Create entry for current process serving at `socket_file`
+    pub fn new(name: &str, socket_file: &Path) -> Result<Self> {
+        let wrk_dir = std::env::current_dir()?;
+        let socket_file = if socket_file.is_absolute() {
+            socket_file.to_owned()
+        } else {
+            wrk_dir.join(socket_file)
+        };
+        let entry = Self {
+            name: name.into(),
+            socket_file,
+            wrk_dir,
+            pid: std::process::id(),
+            start_time: chrono::Local::now().to_rfc3339(),
+        };
+        Ok(entry)
+    }
+
+    /// Return true if the server process is still running
+    pub fn is_alive(&self) -> bool {
+        if unsafe { libc::kill(self.pid as libc::pid_t, 0) } == 0 {
+            return true;
+        }
+        std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+    }
+}
+
+/// The registry is a directory of metadata files (`<name>.json`), one for
+/// each running server.
+#[derive(Debug, Clone)]
+pub struct Registry {
+    dir: PathBuf,
+}
+
+/// Remove the metadata file from registry when dropped, e.g. on server
+/// exit.
+#[derive(Debug)]
+pub struct Registration {
+    file: PathBuf,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.file);
+    }
+}
+
+impl Registry {
+    pub fn new(dir: &Path) -> Result<Self> {
+        use std::os::unix::fs::PermissionsExt;
+
+        if !dir.exists() {
+            std::fs::create_dir_all(dir).with_context(|| format!("create registry dir {:?}", dir))?;
+            std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700))?;
+        }
+        Ok(Self { dir: dir.to_owned() })
+    }
+
+    /// Open the registry of current user: `vasp-tools/servers` in
+    /// XDG_RUNTIME_DIR if available, otherwise in temporary directory
+    /// suffixed with user id.
+    pub fn open_default() -> Result<Self> {
+        let dir = match std::env::var_os("XDG_RUNTIME_DIR") {
+            Some(d) if Path::new(&d).is_dir() => PathBuf::from(d).join("vasp-tools"),
+            _ => std::env::temp_dir().join(format!("vasp-tools-{}", unsafe { libc::geteuid() })),
+        };
+        Self::new(&dir.join("servers"))
+    }
+
+    fn entry_file(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", name))
+    }
+
+    /// Record `entry` in registry. Return error if a running server with
+    /// the same name already registered.
+    pub fn register(&self, entry: &ServerEntry) -> Result<Registration> {
+        ensure!(
+            !entry.name.is_empty() && !entry.name.contains('/'),
+            "invalid server name: {:?}",
+            entry.name
+        );
+        if let Ok(old) = self.find(&entry.name) {
+            bail!(
+                "server {:?} already running in {:?} (pid {})",
+                old.name,
+                old.wrk_dir,
+                old.pid
+            );
+        }
+        let file = self.entry_file(&entry.name);
+        let json = serde_json::to_string_pretty(entry)?;
+        gut::fs::write_to_file(&file, &json)?;
+        debug!("registered server {:?} in {:?}", entry.name, file);
+        Ok(Registration { file })
+    }
+
+    /// List running servers sorted by name. Entries of dead servers are
+    /// removed.
+    pub fn list(&self) -> Result<Vec<ServerEntry>> {
+        let mut entries = vec![];
+        for f in std::fs::read_dir(&self.dir)? {
+            let path = f?.path();
+            if path.extension().map(|x| x == "json") != Some(true) {
+                continue;
+            }
+            let entry: ServerEntry = match gut::fs::read_file(&path).map(|s| serde_json::from_str(&s)) {
+                Ok(Ok(entry)) => entry,
+                _ => {
+                    warn!("ignore invalid registry file: {:?}", path);
+                    continue;
+                }
+            };
+            if entry.is_alive() {
+                entries.push(entry);
+            } else {
+                debug!("remove stale registry entry {:?}", path);
+                let _ = std::fs::remove_file(&path);
+            }
+        }
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(entries)
+    }
+
+    /// Find running server by `name`.
+    pub fn find(&self, name: &str) -> Result<ServerEntry> {
+        self.list()?
+            .into_iter()
+            .find(|e| e.name == name)
+            .with_context(|| format!("no running server named {:?}", name))
+    }
+}
+
+/// Format `entries` as a table for display
+pub fn format_server_table(entries: &[ServerEntry]) -> String {
+    let mut txt = format!("{:20} {:>8} {:26} {}\n", "NAME", "PID", "STARTED", "WORK DIR");
+    for e in entries {
+        txt += &format!("{:20} {:>8} {:26} {}\n", e.name, e.pid, e.start_time, e.wrk_dir.display());
+    }
+    txt
+}
+// d09b0d2f ends here
+
+// [[file:../vasp-tools.note::2fa3e549][2fa3e549]]
+#[test]
+fn test_registry() -> Result<()> {
+    let tdir = tempfile::tempdir()?;
+    let registry = Registry::new(&tdir.path().join("servers"))?;
+
+    let entry = ServerEntry::new("opt1", "vasp.sock".as_ref())?;
+    assert!(entry.socket_file.is_absolute());
+    let reg = registry.register(&entry)?;
+    assert!(registry.register(&entry).is_err());
+    assert_eq!(registry.find("opt1")?, entry);
+    assert!(registry.find("opt2").is_err());
+
+    // stale entry of dead process
+    let mut dead = ServerEntry::new("dead", "x.sock".as_ref())?;
+    dead.pid = i32::MAX as u32;
+    gut::fs::write_to_file(tdir.path().join("servers/dead.json"), &serde_json::to_string(&dead)?)?;
+    assert_eq!(registry.list()?.len(), 1);
+    assert!(!tdir.path().join("servers/dead.json").exists());
+
+    drop(reg);
+    assert!(registry.list()?.is_empty());
+
+    Ok(())
+}
+// 2fa3e549 ends here
@@ -0,0 +1,226 @@
+// [[file:../vasp-tools.note::*docs][docs:1]]
+//! Rigid scan of the distance between two atoms, with energies evaluated
+//! by interactive VASP, BBM drivers or analytical potentials
+// docs:1 ends here
+
+// [[file:../vasp-tools.note::64f745e2][64f745e2]]
+use super::*;
+
+use crate::bbm::{BbmOptions, Properties};
+use crate::ipi::ForceEngine;
+use crate::pool::EnginePool;
+use gosh::gchemol::Molecule;
+// 64f745e2 ends here
+
+// [[file:../vasp-tools.note::32474795][32474795]]
+/// The range of scanned distances in Å, in the form of "start:stop:step".
+/// The stop value is included if it is on the grid.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScanRange {
+    pub start: f64,
+    pub stop: f64,
+    pub step: f64,
+}
+
+impl std::str::FromStr for ScanRange {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let items: Vec<f64> = s
+            .split(':')
+            .map(|x| x.trim().parse())
+            .collect::<std::result::Result<_, _>>()
+            .with_context(|| format!("invalid scan range {:?}, expect start:stop:step", s))?;
+        let (start, stop, step) = match items[..] {
+            [start, stop, step] => (start, stop, step),
+            _ => bail!("invalid scan range {:?}, expect start:stop:step", s),
+        };
+        ensure!(step > 0.0, "scan step should be positive: {}", step);
+        ensure!(start > 0.0 && stop > 0.0, "scanned distance should be positive: {:?}", s);
+        Ok(Self { start, stop, step })
+    }
+}
+
+impl ScanRange {
+    /// Return all distances in the range. The scan goes backward if stop
+    /// is less than start.
+    pub fn values(&self) -> Vec<f64> {
+        let n = ((self.stop - self.start).abs() / self.step + 1e-6).floor() as usize;
+        let sign = if self.stop < self.start { -1.0 } else { 1.0 };
+        (0..=n).map(|k| self.start + sign * k as f64 * self.step).collect()
+    }
+}
+
+/// Generate structures with distance between atoms `i` and `j` (1-based)
+/// set to each of `distances`, by moving atoms in `moving` (1-based, atom
+/// `j` only if empty) along the direction from `i` to `j`.
+pub fn scan_structures(mol: &Molecule, i: usize, j: usize, moving: &[usize], distances: &[f64]) -> Result<Vec<Molecule>> {
+    let natoms = mol.natoms();
+    ensure!(i != j, "scan requires two different atoms");
+    for &k in [i, j].iter().chain(moving) {
+        ensure!(k >= 1 && k <= natoms, "atom {} out of range 1-{}", k, natoms);
+    }
+    let moving = if moving.is_empty() { vec![j] } else { moving.to_vec() };
+    ensure!(!moving.contains(&i), "atom {} cannot be moved in scan", i);
+
+    let d = crate::constraint::bond_vector(mol, i - 1, j - 1);
+    let r0 = (d[0] * d[0] + d[1] * d[1] + d[2] * d[2]).sqrt();
+    ensure!(r0 > 1e-6, "atoms {} and {} overlap", i, j);
+    let positions: Vec<[f64; 3]> = mol.positions().collect();
+    let mols = distances
+        .iter()
+        .map(|&r| {
+            let mut mol = mol.clone();
+            let mut positions = positions.clone();
+            for &k in &moving {
+                for l in 0..3 {
+                    positions[k - 1][l] += (r - r0) * d[l] / r0;
+                }
+            }
+            mol.set_positions(positions);
+            mol
+        })
+        .collect();
+    Ok(mols)
+}
+
+/// Evaluate `mols` one by one using `engine`, so that the interactive VASP
+/// session restarts from the wave functions of the previous point.
+pub async fn evaluate_in_sequence(engine: &mut ForceEngine, mols: &[Molecule]) -> Result<Vec<Properties>> {
+    let mut all = vec![];
+    for (k, mol) in mols.iter().enumerate() {
+        info!("scan point {}/{}", k + 1, mols.len());
+        all.push(engine.compute(mol).await?);
+    }
+    Ok(all)
+}
+
+/// Evaluate `mols` in parallel using a pool of BBM drivers, one for each
+/// of `bbm_dirs` (each with its own interactive VASP session). Points are
+/// assigned to drivers in round-robin order.
+pub fn evaluate_with_bbm_pool(bbm_dirs: &[PathBuf], bbm_opts: &BbmOptions, mols: &[Molecule]) -> Result<Vec<Properties>> {
+    ensure!(!bbm_dirs.is_empty(), "no BBM directory for evaluation");
+    ensure!(
+        bbm_dirs.len() == 1 || !bbm_opts.is_exclusive(),
+        "keeping scratch directories or checkpoint is not supported for multiple BBM drivers"
+    );
+
+    info!("evaluate {} points with {} BBM drivers", mols.len(), bbm_dirs.len());
+    let bbm_dirs = bbm_dirs.to_vec();
+    let bbm_opts = bbm_opts.clone();
+    let pool = EnginePool::new(bbm_dirs.len(), move |i| Ok(ForceEngine::Bbm(bbm_opts.build_driver(&bbm_dirs[i])?)))?;
+    pool.evaluate(mols)
+}
+
+/// One point of scanned energy profile
+#[derive(Debug, Clone)]
+pub struct ScanPoint {
+    /// The scanned distance in Å
+    pub distance: f64,
+    /// The energy in eV
+    pub energy: f64,
+}
+
+/// Collect energy profile from computed results `all` of `mols`, and append
+/// them into extxyz `trajectory` if not None.
+pub fn collect_profile(
+    distances: &[f64],
+    mols: &[Molecule],
+    all: &[Properties],
+    trajectory: Option<&Path>,
+) -> Result<Vec<ScanPoint>> {
+    ensure!(
+        distances.len() == all.len() && mols.len() == all.len(),
+        "inconsistent number of scan points"
+    );
+    let mut points = vec![];
+    for ((&distance, mol), props) in distances.iter().zip(mols).zip(all) {
+        if let Some(f) = trajectory {
+            crate::trajectory::append_extxyz(f, mol, props)?;
+        }
+        let energy = props.mp.get_energy().context("no energy")?;
+        points.push(ScanPoint { distance, energy });
+    }
+    Ok(points)
+}
+
+/// Format scanned energy profile as a table, with energies relative to the
+/// lowest one.
+pub fn format_profile(points: &[ScanPoint]) -> String {
+    let emin = points.iter().map(|p| p.energy).fold(f64::INFINITY, f64::min);
+    let mut txt = format!("{:>6} {:>12} {:>18} {:>14}\n", "point", "distance (Å)", "energy (eV)", "relative (eV)");
+    for (k, p) in points.iter().enumerate() {
+        txt += &format!(
+            "{:>6} {:>12.4} {:>18.8} {:>14.6}\n",
+            k + 1,
+            p.distance,
+            p.energy,
+            p.energy - emin
+        );
+    }
+    txt
+}
+
+/// Plot scanned energy profile as ASCII text.
+pub fn plot_profile(points: &[ScanPoint], i: usize, j: usize) -> Result<String> {
+    use crate::plot::AsciiPlot;
+
+    let mut plot = AsciiPlot::new();
+    plot.set_title(&format!("Scan of distance {}-{}", i, j));
+    plot.set_xlabel("distance (Å)");
+    plot.set_ylabel("energy (eV)");
+    let x: Vec<_> = points.iter().map(|p| p.distance).collect();
+    let y: Vec<_> = points.iter().map(|p| p.energy).collect();
+    plot.plot(&x, &y)
+}
+// 32474795 ends here
+
+// [[file:../vasp-tools.note::52863f3e][52863f3e]]
+#[test]
+fn test_scan_range() -> Result<()> {
+    let r: ScanRange = "1.0:1.5:0.1".parse()?;
+    let values = r.values();
+    assert_eq!(values.len(), 6);
+    assert_relative_eq!(values[5], 1.5, epsilon = 1e-8);
+    let r: ScanRange = "2.0:1.0:0.5".parse()?;
+    assert_eq!(r.values(), vec![2.0, 1.5, 1.0]);
+    assert!("1.0:2.0".parse::<ScanRange>().is_err());
+    assert!("1.0:2.0:0".parse::<ScanRange>().is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_scan_harmonic() -> Result<()> {
+    use crate::potential::Potential;
+    use gosh::gchemol::Atom;
+
+    let atoms = vec![
+        Atom::new("H", [0.0, 0.0, 0.0]),
+        Atom::new("H", [1.0, 0.0, 0.0]),
+        Atom::new("H", [0.0, 5.0, 0.0]),
+    ];
+    let mol = Molecule::from_atoms(atoms);
+    let distances = "1.2:1.8:0.3".parse::<ScanRange>()?.values();
+    let mols = scan_structures(&mol, 1, 2, &[], &distances)?;
+    assert_eq!(mols.len(), 3);
+    let p: Vec<_> = mols[2].positions().collect();
+    assert_relative_eq!(p[1][0], 1.8, epsilon = 1e-8);
+    assert_eq!(p[2], [0.0, 5.0, 0.0]);
+    assert!(scan_structures(&mol, 1, 2, &[1], &distances).is_err());
+
+    // only the pair of atoms 1 and 2 matters with a short-range harmonic
+    // potential centered at 1.5 Å
+    let mol = Molecule::from_atoms(vec![Atom::new("H", [0.0, 0.0, 0.0]), Atom::new("H", [1.0, 0.0, 0.0])]);
+    let mols = scan_structures(&mol, 1, 2, &[], &distances)?;
+    let mut engine = ForceEngine::Potential(Potential::Harmonic { k: 1.0, r0: 1.5 });
+    let all = evaluate_in_sequence(&mut engine, &mols).await?;
+    let points = collect_profile(&distances, &mols, &all, None)?;
+    assert_relative_eq!(points[0].energy, 0.045, epsilon = 1e-8);
+    assert_relative_eq!(points[1].energy, 0.0, epsilon = 1e-8);
+    let s = format_profile(&points);
+    assert_eq!(s.lines().count(), 4);
+
+    Ok(())
+}
+// 52863f3e ends here
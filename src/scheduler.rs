@@ -0,0 +1,242 @@
+// [[file:../vasp-tools.note::*docs][docs:1]]
+//! Integration with batch schedulers (SLURM and PBS): remaining walltime of
+//! current job, requeue before timeout, and submission scripts
+// docs:1 ends here
+
+// [[file:../vasp-tools.note::e9f64052][e9f64052]]
+use super::*;
+// e9f64052 ends here
+
+// [[file:../vasp-tools.note::fe0539f0][fe0539f0]]
+/// The batch scheduler
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scheduler {
+    Slurm,
+    Pbs,
+}
+
+impl std::str::FromStr for Scheduler {
+    type Err = Error;
+
+    /// Parse from "slurm" or "pbs"
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "slurm" => Ok(Self::Slurm),
+            "pbs" | "torque" => Ok(Self::Pbs),
+            _ => bail!("invalid scheduler: {:?}", s),
+        }
+    }
+}
+
+/// Parse time in SLURM format in seconds: "days-hours:minutes:seconds",
+/// "days-hours[:minutes]", "hours:minutes:seconds", "minutes:seconds" or
+/// "minutes", as in squeue output or `sbatch --time`.
+pub fn parse_slurm_time(s: &str) -> Result<f64> {
+    let s = s.trim();
+    let invalid = || format!("invalid SLURM time: {:?}", s);
+    let (days, rest) = match s.split_once('-') {
+        Some((d, rest)) => (Some(d.parse::<f64>().with_context(invalid)?), rest),
+        None => (None, s),
+    };
+    let parts: Vec<f64> = rest
+        .split(':')
+        .map(|x| x.parse())
+        .collect::<std::result::Result<_, _>>()
+        .with_context(invalid)?;
+    let (h, m, sec) = match (days, parts.as_slice()) {
+        (Some(_), [h]) => (*h, 0.0, 0.0),
+        (Some(_), [h, m]) => (*h, *m, 0.0),
+        (None, [m]) => (0.0, *m, 0.0),
+        (None, [m, sec]) => (0.0, *m, *sec),
+        (_, [h, m, sec]) => (*h, *m, *sec),
+        _ => bail!(invalid()),
+    };
+    Ok(days.unwrap_or(0.0) * 86400.0 + h * 3600.0 + m * 60.0 + sec)
+}
+
+/// Format `seconds` as "HH:MM:SS" for job submission.
+pub fn format_walltime(seconds: f64) -> String {
+    let t = seconds.max(0.0).round() as u64;
+    format!("{:02}:{:02}:{:02}", t / 3600, t / 60 % 60, t % 60)
+}
+
+/// Return the remaining walltime in seconds from output of `qstat -f`:
+/// "Walltime.Remaining" (Torque), or the difference between requested and
+/// used walltime.
+fn parse_pbs_remaining(s: &str) -> Option<f64> {
+    let get = |key: &str| {
+        s.lines()
+            .filter_map(|l| l.split_once('='))
+            .find(|(k, _)| k.trim() == key)
+            .map(|(_, v)| v.trim())
+    };
+    if let Some(t) = get("Walltime.Remaining").and_then(|v| v.parse().ok()) {
+        return Some(t);
+    }
+    let requested = crate::hooks::parse_walltime(get("Resource_List.walltime")?).ok()?;
+    let used = get("resources_used.walltime").map_or(Some(0.0), |v| crate::hooks::parse_walltime(v).ok())?;
+    Some(requested - used)
+}
+
+impl Scheduler {
+    /// Detect the scheduler running current job from env vars.
+    pub fn detect() -> Option<Self> {
+        if std::env::var_os("SLURM_JOB_ID").is_some() {
+            Some(Self::Slurm)
+        } else if std::env::var_os("PBS_JOBID").is_some() {
+            Some(Self::Pbs)
+        } else {
+            None
+        }
+    }
+
+    /// The id of current job
+    pub fn job_id(&self) -> Result<String> {
+        match self {
+            Self::Slurm => std::env::var("SLURM_JOB_ID").context("SLURM_JOB_ID not set"),
+            Self::Pbs => std::env::var("PBS_JOBID").context("PBS_JOBID not set"),
+        }
+    }
+
+    /// Query the remaining walltime in seconds of current job.
+    pub fn remaining_walltime(&self) -> Result<f64> {
+        let job_id = self.job_id()?;
+        match self {
+            Self::Slurm => {
+                let out = duct::cmd!("squeue", "-h", "-j", &job_id, "-o", "%L")
+                    .read()
+                    .context("squeue failure")?;
+                parse_slurm_time(&out).with_context(|| format!("no time limit for job {}", job_id))
+            }
+            Self::Pbs => {
+                let out = duct::cmd!("qstat", "-f", &job_id).read().context("qstat failure")?;
+                parse_pbs_remaining(&out).with_context(|| format!("no walltime found for job {}", job_id))
+            }
+        }
+    }
+
+    /// Requeue current job, which will be killed and started again later.
+    pub fn requeue(&self) -> Result<()> {
+        let job_id = self.job_id()?;
+        info!("requeue job {}", job_id);
+        match self {
+            Self::Slurm => duct::cmd!("scontrol", "requeue", &job_id).run().context("scontrol requeue failure")?,
+            Self::Pbs => duct::cmd!("qrerun", &job_id).run().context("qrerun failure")?,
+        };
+        Ok(())
+    }
+
+    /// Requeue current job in background when only `margin` seconds left
+    /// for `walltime` in seconds since now, so the job starts again instead
+    /// of being killed on timeout.
+    pub fn watch_requeue(&self, walltime: f64, margin: f64) {
+        let scheduler = *self;
+        let delay = (walltime - margin).max(0.0);
+        info!("job will be requeued in {:.0} seconds", delay);
+        std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_secs_f64(delay));
+            if let Err(e) = scheduler.requeue() {
+                error!("requeue job failed: {:?}", e);
+            }
+        });
+    }
+}
+// fe0539f0 ends here
+
+// [[file:../vasp-tools.note::e339bf11][e339bf11]]
+/// Options for the job script running interactive VASP server
+#[derive(Debug, Clone)]
+pub struct SubmitOptions {
+    pub scheduler: Scheduler,
+    pub job_name: String,
+    pub nodes: usize,
+    /// The number of MPI tasks per node
+    pub ntasks_per_node: usize,
+    /// The walltime in seconds
+    pub walltime: f64,
+    /// The partition (SLURM) or queue (PBS)
+    pub partition: Option<String>,
+    /// The command for running VASP, e.g. "mpirun vasp_std"
+    pub program: String,
+    /// Requeue the job before timeout
+    pub requeue: bool,
+}
+
+/// Format the job script running interactive VASP server with `opts`. The
+/// walltime budget of server is read from the scheduler on start.
+pub fn format_submit_script(opts: &SubmitOptions) -> String {
+    let time = format_walltime(opts.walltime);
+    let mut txt = String::from("#!/bin/bash\n");
+    match opts.scheduler {
+        Scheduler::Slurm => {
+            txt += &format!("#SBATCH --job-name={}\n", opts.job_name);
+            txt += &format!("#SBATCH --nodes={}\n", opts.nodes);
+            txt += &format!("#SBATCH --ntasks-per-node={}\n", opts.ntasks_per_node);
+            txt += &format!("#SBATCH --time={}\n", time);
+            if let Some(p) = &opts.partition {
+                txt += &format!("#SBATCH --partition={}\n", p);
+            }
+            if opts.requeue {
+                txt += "#SBATCH --requeue\n#SBATCH --open-mode=append\n";
+            }
+        }
+        Scheduler::Pbs => {
+            txt += &format!("#PBS -N {}\n", opts.job_name);
+            txt += &format!("#PBS -l nodes={}:ppn={}\n", opts.nodes, opts.ntasks_per_node);
+            txt += &format!("#PBS -l walltime={}\n", time);
+            if let Some(q) = &opts.partition {
+                txt += &format!("#PBS -q {}\n", q);
+            }
+            if opts.requeue {
+                txt += "#PBS -r y\n";
+            }
+            txt += "\ncd \"$PBS_O_WORKDIR\"\n";
+        }
+    }
+    let requeue = if opts.requeue { " --requeue" } else { "" };
+    txt += &format!(
+        "\n# the walltime budget is read from the scheduler\nrun-vasp --interactive --walltime auto{} -x \"{}\"\n",
+        requeue, opts.program
+    );
+    txt
+}
+// e339bf11 ends here
+
+// [[file:../vasp-tools.note::f94cd05e][f94cd05e]]
+#[test]
+fn test_scheduler() -> Result<()> {
+    assert_eq!(parse_slurm_time("1-02:03:04")?, 93784.0);
+    assert_eq!(parse_slurm_time("2:03:04")?, 7384.0);
+    assert_eq!(parse_slurm_time("03:04")?, 184.0);
+    assert_eq!(parse_slurm_time("30")?, 1800.0);
+    assert_eq!(parse_slurm_time("1-12")?, 129600.0);
+    assert!(parse_slurm_time("UNLIMITED").is_err());
+    assert_eq!(format_walltime(93784.0), "26:03:04");
+
+    let qstat = "Job Id: 123.server\n    Resource_List.walltime = 02:00:00\n    resources_used.walltime = 00:30:00\n";
+    assert_eq!(parse_pbs_remaining(qstat), Some(5400.0));
+    assert_eq!(parse_pbs_remaining("    Walltime.Remaining = 3550\n"), Some(3550.0));
+    assert_eq!(parse_pbs_remaining("Job Id: 123.server\n"), None);
+
+    let mut opts = SubmitOptions {
+        scheduler: Scheduler::Slurm,
+        job_name: "vasp".into(),
+        nodes: 2,
+        ntasks_per_node: 32,
+        walltime: 5400.0,
+        partition: Some("normal".into()),
+        program: "srun vasp_std".into(),
+        requeue: true,
+    };
+    let txt = format_submit_script(&opts);
+    assert!(txt.contains("#SBATCH --time=01:30:00\n"), "{}", txt);
+    assert!(txt.contains("#SBATCH --requeue\n"));
+    assert!(txt.contains("--requeue -x \"srun vasp_std\""));
+    opts.scheduler = "pbs".parse()?;
+    let txt = format_submit_script(&opts);
+    assert!(txt.contains("#PBS -l walltime=01:30:00\n"), "{}", txt);
+    assert!(txt.contains("#PBS -q normal\n"));
+
+    Ok(())
+}
+// f94cd05e ends here
@@ -0,0 +1,422 @@
+// [[file:../vasp-tools.note::*docs][docs:1]]
+//! Interactive session with a child process over its stdin/stdout
+// docs:1 ends here
+
+// [[file:../vasp-tools.note::5d5e528e][5d5e528e]]
+use super::*;
+
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::{Arc, Mutex};
+// 5d5e528e ends here
+
+// [[file:../vasp-tools.note::d8f5cf5c][d8f5cf5c]]
+/// The error when child process exited before any read pattern found in its
+/// stdout, e.g. VASP crashed.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ChildExited {
+    /// The exit code of child process, None if killed by signal
+    pub code: Option<i32>,
+    /// The signal killed child process
+    #[serde(default)]
+    pub signal: Option<i32>,
+    /// The text read from stdout before exit
+    pub output: String,
+}
+
+impl std::fmt::Display for ChildExited {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match (self.code, self.signal) {
+            (Some(code), _) => write!(f, "child process exited with code {} before read pattern found", code),
+            (None, Some(sig)) => write!(f, "child process killed by signal {} before read pattern found", sig),
+            (None, None) => write!(f, "child process killed before read pattern found"),
+        }
+    }
+}
+
+impl std::error::Error for ChildExited {}
+
+/// Combine multiple acceptable read `patterns` (regex) into one, which
+/// matches a line of stdout if any of them matches.
+pub fn join_read_patterns(patterns: &[String]) -> Result<String> {
+    ensure!(!patterns.is_empty(), "no read pattern");
+    for p in patterns {
+        regex::Regex::new(p).with_context(|| format!("invalid read pattern: {:?}", p))?;
+    }
+    if let [p] = patterns {
+        Ok(p.to_owned())
+    } else {
+        let patterns: Vec<_> = patterns.iter().map(|p| format!("(?:{})", p)).collect();
+        Ok(patterns.join("|"))
+    }
+}
+
+/// Handler for signaling the process group of child process
+#[derive(Debug, Clone)]
+pub struct SessionHandler {
+    pgid: i32,
+}
+
+impl SessionHandler {
+    /// Create handler for process group `pgid`, e.g. a child spawned with
+    /// `process_group(0)` outside of `Session`.
+    pub(crate) fn for_process_group(pgid: u32) -> Self {
+        Self { pgid: pgid as i32 }
+    }
+
+    /// Send signal `sig` to the process group of child process.
+    pub fn signal(&self, sig: libc::c_int) -> Result<()> {
+        if unsafe { libc::killpg(self.pgid, sig) } != 0 {
+            let err = std::io::Error::last_os_error();
+            // the processes already exited
+            if err.raw_os_error() != Some(libc::ESRCH) {
+                bail!("signal process group {} failed: {:?}", self.pgid, err);
+            }
+        }
+        Ok(())
+    }
+
+    /// The process group id of child process
+    pub fn id(&self) -> u32 {
+        self.pgid as u32
+    }
+
+    pub fn pause(&self) -> Result<()> {
+        self.signal(libc::SIGSTOP)
+    }
+
+    pub fn resume(&self) -> Result<()> {
+        self.signal(libc::SIGCONT)
+    }
+
+    pub fn interrupt(&self) -> Result<()> {
+        self.signal(libc::SIGINT)
+    }
+
+    pub fn terminate(&self) -> Result<()> {
+        // stopped processes cannot handle SIGTERM
+        self.signal(libc::SIGCONT)?;
+        self.signal(libc::SIGTERM)
+    }
+}
+
+/// The number of last lines of stdout/stderr kept for diagnosing crashes
+const TAIL_LINES: usize = 200;
+
+/// Append `line` into `tail`, keeping the last `TAIL_LINES` lines only.
+fn push_tail(tail: &mut VecDeque<String>, line: &str) {
+    if tail.len() == TAIL_LINES {
+        tail.pop_front();
+    }
+    tail.push_back(line.trim_end_matches('\n').to_owned());
+}
+
+/// Interactive session with child process: write input into its stdin, and
+/// read its stdout until a line matching read pattern found.
+pub struct Session {
+    command: Command,
+    child: Option<Child>,
+    stdin: Option<ChildStdin>,
+    stdout: Option<BufReader<ChildStdout>>,
+    handler: Option<SessionHandler>,
+    // the file for recording interactions, and the number recorded
+    transcript: Option<PathBuf>,
+    nrecorded: usize,
+    // the last non-empty input, and last lines of stdout/stderr of child
+    // process, for diagnosing crashes
+    last_input: String,
+    stdout_tail: VecDeque<String>,
+    stderr_tail: Arc<Mutex<VecDeque<String>>>,
+    // the compiled read pattern of last interaction
+    read_re: Option<regex::Regex>,
+}
+
+impl Session {
+    pub fn new(command: Command) -> Self {
+        Self {
+            command,
+            child: None,
+            stdin: None,
+            stdout: None,
+            handler: None,
+            transcript: None,
+            nrecorded: 0,
+            last_input: String::new(),
+            stdout_tail: VecDeque::new(),
+            stderr_tail: Default::default(),
+            read_re: None,
+        }
+    }
+
+    /// Return the last non-empty input written into stdin of child process.
+    pub fn last_input(&self) -> &str {
+        &self.last_input
+    }
+
+    /// Return the last lines of stdout and stderr of child process.
+    pub fn output_tails(&self) -> (Vec<String>, Vec<String>) {
+        let stdout = self.stdout_tail.iter().cloned().collect();
+        let stderr = self.stderr_tail.lock().map(|x| x.iter().cloned().collect()).unwrap_or_default();
+        (stdout, stderr)
+    }
+
+    /// Record each interaction (input and stdout) into transcript file `f`
+    /// in JSON lines, which can be replayed by `fake-vasp` later.
+    pub fn set_transcript(&mut self, f: &Path) {
+        self.transcript = f.to_owned().into();
+    }
+
+    fn record(&mut self, input: &str, output: &str, exited: Option<Option<i32>>) {
+        if let Some(f) = &self.transcript {
+            self.nrecorded += 1;
+            let entry = TranscriptEntry {
+                step: self.nrecorded,
+                input: input.into(),
+                output: output.into(),
+                exited: exited.is_some(),
+                code: exited.flatten(),
+            };
+            if let Err(e) = append_transcript(f, &entry) {
+                tracing::warn!("record transcript failed: {:?}", e);
+            }
+        }
+    }
+
+    /// Spawn child process in a new process group.
+    pub fn spawn(&mut self) -> Result<SessionHandler> {
+        use std::os::unix::process::CommandExt;
+
+        let mut child = self
+            .command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .process_group(0)
+            .spawn()
+            .with_context(|| format!("spawn {:?}", self.command))?;
+        self.stdin = child.stdin.take();
+        self.stdout = child.stdout.take().map(BufReader::new);
+        self.stdout_tail.clear();
+        // NOTE: stderr is passed through, keeping its last lines
+        let stderr_tail: Arc<Mutex<VecDeque<String>>> = Default::default();
+        if let Some(stderr) = child.stderr.take() {
+            let tail = stderr_tail.clone();
+            std::thread::spawn(move || {
+                for line in BufReader::new(stderr).lines().map_while(|l| l.ok()) {
+                    eprintln!("{}", line);
+                    if let Ok(mut tail) = tail.lock() {
+                        push_tail(&mut tail, &line);
+                    }
+                }
+            });
+        }
+        self.stderr_tail = stderr_tail;
+        let handler = SessionHandler { pgid: child.id() as i32 };
+        self.child = child.into();
+        self.handler = handler.clone().into();
+        Ok(handler)
+    }
+
+    /// Stop child process if running, and wait for it to exit. The child
+    /// process can be spawned again later.
+    pub fn stop(&mut self) {
+        if let Some(mut child) = self.terminate_child() {
+            let _ = child.wait();
+        }
+    }
+
+    /// Terminate child process if running. Return it for reaping.
+    fn terminate_child(&mut self) -> Option<Child> {
+        self.stdin = None;
+        self.stdout = None;
+        let handler = self.handler.take();
+        let mut child = self.child.take()?;
+        match child.try_wait() {
+            Ok(None) => {
+                if let Some(h) = handler {
+                    let _ = h.terminate();
+                }
+                Some(child)
+            }
+            _ => None,
+        }
+    }
+
+    /// Return the handler of spawned child process.
+    pub fn get_handler(&self) -> Option<SessionHandler> {
+        self.handler.clone()
+    }
+
+    /// Write `input` into stdin of child process, and return text read from
+    /// its stdout until a line matching `read_pattern` (regex). Return
+    /// `ChildExited` error if child process exited before that.
+    #[tracing::instrument(level = "debug", skip(self, input), fields(input_len = input.len()))]
+    pub fn interact(&mut self, input: &str, read_pattern: &str) -> Result<String> {
+        // NOTE: the same pattern is used for all interactions usually
+        let re = match self.read_re.take() {
+            Some(re) if re.as_str() == read_pattern => re,
+            _ => regex::Regex::new(read_pattern).with_context(|| format!("invalid read pattern: {:?}", read_pattern))?,
+        };
+        // cloning compiled regex is cheap
+        self.read_re = Some(re.clone());
+        if !input.is_empty() {
+            self.last_input = input.to_owned();
+            let stdin = self.stdin.as_mut().context("session not started")?;
+            stdin.write_all(input.as_bytes()).context("write stdin")?;
+            stdin.flush()?;
+        }
+
+        let stdout = self.stdout.as_mut().context("session not started")?;
+        let mut txt = String::new();
+        let mut buf = vec![];
+        loop {
+            buf.clear();
+            // NOTE: VASP may print invalid UTF-8 characters
+            if stdout.read_until(b'\n', &mut buf).context("read stdout")? == 0 {
+                use std::os::unix::process::ExitStatusExt;
+
+                let status = self.child.as_mut().and_then(|c| c.wait().ok());
+                let code = status.and_then(|s| s.code());
+                let signal = status.and_then(|s| s.signal());
+                tracing::warn!(?code, ?signal, "child exited before read pattern found");
+                self.record(input, &txt, Some(code));
+                return Err(ChildExited { code, signal, output: txt }.into());
+            }
+            let line = String::from_utf8_lossy(&buf);
+            push_tail(&mut self.stdout_tail, &line);
+            txt.push_str(&line);
+            if re.is_match(&line) {
+                tracing::debug!(nbytes = txt.len(), "read pattern found");
+                self.record(input, &txt, None);
+                return Ok(txt);
+            }
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        // NOTE: do not block on waiting child process exit, which could be
+        // dropped in async server
+        if let Some(mut child) = self.terminate_child() {
+            std::thread::spawn(move || child.wait());
+        }
+    }
+}
+// d8f5cf5c ends here
+
+// [[file:../vasp-tools.note::a6658e61][a6658e61]]
+/// One recorded interaction with child process
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct TranscriptEntry {
+    /// The interaction index (starting from 1)
+    pub step: usize,
+    /// The text written into stdin
+    pub input: String,
+    /// The text read from stdout until read pattern found or child exited
+    pub output: String,
+    /// True if child process exited during this interaction
+    #[serde(default)]
+    pub exited: bool,
+    /// The exit code of child process, None if killed by signal
+    #[serde(default)]
+    pub code: Option<i32>,
+}
+
+fn append_transcript(f: &Path, entry: &TranscriptEntry) -> Result<()> {
+    let mut fp = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(f)
+        .with_context(|| format!("open transcript file {:?}", f))?;
+    writeln!(fp, "{}", serde_json::to_string(entry)?)?;
+    Ok(())
+}
+
+/// Read recorded interactions from transcript file `f`.
+pub fn read_transcript(f: &Path) -> Result<Vec<TranscriptEntry>> {
+    let s = gut::fs::read_file(f)?;
+    s.lines()
+        .filter(|l| !l.trim().is_empty())
+        .enumerate()
+        .map(|(i, l)| serde_json::from_str(l).with_context(|| format!("invalid line {} in transcript {:?}", i + 1, f)))
+        .collect()
+}
+// a6658e61 ends here
+
+// [[file:../vasp-tools.note::a031d02a][a031d02a]]
+#[test]
+fn test_interactive_session() -> Result<()> {
+    let mut cmd = Command::new("sh");
+    cmd.args(["-c", "echo hello; read x; echo got $x; echo 'READY>'; read y; exit 3"]);
+    let mut s = Session::new(cmd);
+    s.spawn()?;
+
+    let read_pattern = join_read_patterns(&["^READY>".into(), "^hello".into()])?;
+    let o = s.interact("", &read_pattern)?;
+    assert_eq!(o, "hello\n");
+    let o = s.interact("abc\n", &read_pattern)?;
+    assert_eq!(o, "got abc\nREADY>\n");
+    let e = s.interact("y\n", &read_pattern).unwrap_err();
+    let e = e.downcast_ref::<ChildExited>().unwrap();
+    assert_eq!(e.code, Some(3));
+    assert_eq!(e.signal, None);
+    assert_eq!(s.last_input(), "y\n");
+    assert_eq!(s.output_tails().0, vec!["hello", "got abc", "READY>"]);
+    // stop running child process and spawn again
+    s.spawn()?;
+    assert_eq!(s.interact("", &read_pattern)?, "hello\n");
+    s.stop();
+    assert!(s.get_handler().is_none());
+    s.spawn()?;
+    assert_eq!(s.interact("", &read_pattern)?, "hello\n");
+
+    assert!(join_read_patterns(&["(".into()]).is_err());
+
+    // record transcript
+    let tdir = tempfile::tempdir()?;
+    let f = tdir.path().join("transcript.jsonl");
+    let mut cmd = Command::new("sh");
+    cmd.args(["-c", "echo 'READY>'; read x; echo got $x; exit 2"]);
+    let mut s = Session::new(cmd);
+    s.set_transcript(&f);
+    s.spawn()?;
+    s.interact("", "^READY>")?;
+    assert!(s.interact("abc\n", "^READY>").is_err());
+    let entries = read_transcript(&f)?;
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].output, "READY>\n");
+    assert!(!entries[0].exited);
+    assert_eq!(entries[1].input, "abc\n");
+    assert_eq!(entries[1].output, "got abc\n");
+    assert_eq!(entries[1].code, Some(2));
+
+    Ok(())
+}
+
+#[test]
+fn test_interactive_vasp() -> Result<()> {
+    let read_pattern = "POSITIONS: reading from stdin";
+
+    // the input for writing into stdin
+    let positions = include_str!("../tests/files/interactive_positions.txt");
+
+    let vasp = std::process::Command::new("fake-vasp");
+    let mut s = Session::new(vasp);
+    let h = s.spawn()?;
+
+    let o = s.interact("", read_pattern)?;
+    let _ = crate::vasp::stdout::parse_energy_and_forces(&o)?;
+    let o = s.interact(&positions, read_pattern)?;
+    let (energy2, _forces2) = crate::vasp::stdout::parse_energy_and_forces(&o)?;
+    assert_eq!(energy2, 2.0);
+    let o = s.interact(&positions, read_pattern)?;
+    let (energy3, _forces3) = crate::vasp::stdout::parse_energy_and_forces(&o)?;
+    assert_eq!(energy3, 3.0);
+
+    h.terminate()?;
+
+    Ok(())
+}
+// a031d02a ends here
@@ -9,17 +9,176 @@ use std::process::Command;
 mod codec {
     use super::*;
     use bytes::{Buf, BufMut, Bytes};
+    use serde::{Deserialize, Serialize};
     use std::io::{Read, Write};
     use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
     use tokio::net::UnixStream;
 
-    /// The request from client side
+    /// The version of socket protocol. Version 1 has no handshake. Version
+    /// 3 adds routing to multiple engines. Version 4 adds file transfer.
+    pub const PROTOCOL_VERSION: u32 = 4;
+    /// The min protocol version of server required by client
+    pub const MIN_PROTOCOL_VERSION: u32 = 2;
+    /// The capability declared by client reading results only from output
+    /// of interactions, never from files in working directory of engine.
+    /// Server starts speculative pre-computation only for such clients.
+    pub const STDOUT_ONLY: &str = "stdout-only";
+
+    /// The handshake message exchanged on connection
+    #[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+    pub struct Handshake {
+        /// The version of vasp-tools
+        pub crate_version: String,
+        pub protocol_version: u32,
+        /// The supported operations, e.g. "interact", "control"
+        pub capabilities: Vec<String>,
+        /// The engines hosted by server, selectable by routing key
+        #[serde(default)]
+        pub engines: Vec<EngineInfo>,
+    }
+
+    /// The interactive engine hosted by server
+    #[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+    pub struct EngineInfo {
+        /// The routing key
+        pub name: String,
+        /// The working directory of engine
+        pub directory: PathBuf,
+        /// The number of atoms in POSCAR VASP started with
+        #[serde(default)]
+        pub natoms: Option<usize>,
+        /// The species of atoms in POSCAR order, e.g. "Ti2 O4"
+        #[serde(default)]
+        pub species: Option<String>,
+        /// The number of interactions done by engine. Zero means VASP is
+        /// waiting for the initial structure. None for old server.
+        #[serde(default)]
+        pub nsteps: Option<usize>,
+    }
+
+    impl Handshake {
+        /// The handshake of this build
+        pub fn current() -> Self {
+            Self {
+                crate_version: env!("CARGO_PKG_VERSION").into(),
+                protocol_version: PROTOCOL_VERSION,
+                capabilities: vec![
+                    "interact".into(),
+                    "control".into(),
+                    "route".into(),
+                    "transfer".into(),
+                    "interrupt".into(),
+                    "batch".into(),
+                    "finite-diff".into(),
+                    "profile".into(),
+                    "cancel".into(),
+                ],
+                engines: vec![],
+            }
+        }
+
+        fn encode(&self, buf: &mut Vec<u8>) -> Result<()> {
+            let json = serde_json::to_string(self).context("serialize handshake")?;
+            encode(buf, &json);
+            Ok(())
+        }
+
+        async fn decode<R: AsyncRead + std::marker::Unpin>(r: &mut R) -> Result<Self> {
+            let json = decode(r).await?;
+            let hs = serde_json::from_slice(&json).context("invalid handshake")?;
+            Ok(hs)
+        }
+    }
+
+    /// The input files allowed to be uploaded into working directory of
+    /// server
+    pub const UPLOAD_FILES: &[&str] = &["POSCAR", "INCAR", "KPOINTS"];
+    /// The output files allowed to be downloaded from working directory of
+    /// server
+    pub const DOWNLOAD_FILES: &[&str] = &["OUTCAR", "CONTCAR", "OSZICAR", "vasprun.xml"];
+
+    /// A file transferred over socket, verified with its size and SHA-256
+    /// checksum on receiving
     #[derive(Debug, Eq, PartialEq, Clone)]
+    pub struct FileData {
+        /// The file name in working directory of server
+        pub name: String,
+        pub size: u64,
+        /// The SHA-256 checksum in hex
+        pub sha256: String,
+        pub data: Vec<u8>,
+    }
+
+    impl FileData {
+        pub fn new(name: &str, data: Vec<u8>) -> Self {
+            use sha2::{Digest, Sha256};
+
+            Self {
+                name: name.into(),
+                size: data.len() as u64,
+                sha256: format!("{:x}", Sha256::digest(&data)),
+                data,
+            }
+        }
+
+        /// Check received data against its size and checksum.
+        pub fn verify(&self) -> Result<()> {
+            use sha2::{Digest, Sha256};
+
+            ensure!(
+                self.data.len() as u64 == self.size,
+                "size mismatch of {:?}: expect {} bytes, received {}",
+                self.name,
+                self.size,
+                self.data.len()
+            );
+            let sha256 = format!("{:x}", Sha256::digest(&self.data));
+            ensure!(sha256 == self.sha256, "checksum mismatch of {:?}: {} != {}", self.name, sha256, self.sha256);
+            Ok(())
+        }
+
+        fn encode(&self, buf: &mut Vec<u8>) {
+            encode(&mut *buf, &self.name);
+            buf.put_u64(self.size);
+            encode(&mut *buf, &self.sha256);
+            encode_bytes(&mut *buf, &self.data);
+        }
+
+        async fn decode<R: AsyncRead + std::marker::Unpin>(r: &mut R) -> Result<Self> {
+            let name = String::from_utf8_lossy(&decode(r).await?).to_string();
+            let size = r.read_u64().await?;
+            let sha256 = String::from_utf8_lossy(&decode(r).await?).to_string();
+            let data = decode(r).await?;
+            Ok(Self { name, size, sha256, data })
+        }
+    }
+
+    /// The request from client side
+    #[derive(Debug, PartialEq, Clone)]
     pub enum ServerOp {
-        /// Control server process: pause/resume/quit
+        /// Exchange versions and capabilities on connection
+        Hello(Handshake),
+        /// Control server process: pause/resume/interrupt/quit
         Control(Signal),
         /// Interact with server process with input for stdin and read-pattern for stdout.
         Interact((String, String)),
+        /// Interact with server process for each input back-to-back, with
+        /// the same read-pattern.
+        InteractBatch((Vec<String>, String)),
+        /// Evaluate finite-difference gradients of a structure
+        FiniteDiff(crate::hessian::FiniteDiffRequest),
+        /// Switch INCAR profile of engine ("fast" or "accurate") by
+        /// restarting VASP
+        Profile(String),
+        /// Stop VASP gracefully using STOPCAR and shut down the server
+        Cancel,
+        /// Route following operations on this connection to the engine
+        /// with this name
+        Route(String),
+        /// Upload file into working directory of engine
+        Upload(FileData),
+        /// Download file with this name from working directory of engine
+        Download(String),
     }
 
     #[derive(Debug, Eq, PartialEq, Clone)]
@@ -27,21 +186,24 @@ mod codec {
         Quit,
         Resume,
         Pause,
+        /// Relay SIGINT to VASP
+        Interrupt,
     }
 
     impl ServerOp {
         /// Encode message ready for sent over UnixStream
-        pub fn encode(&self) -> Vec<u8> {
+        pub fn encode(&self) -> Result<Vec<u8>> {
             use ServerOp::*;
 
             let mut buf = vec![];
-            match self {
+            let buf = match self {
                 Control(sig) => {
                     buf.put_u8(b'X');
                     let sig = match sig {
                         Signal::Quit => "SIGTERM",
                         Signal::Resume => "SIGCONT",
                         Signal::Pause => "SIGSTOP",
+                        Signal::Interrupt => "SIGINT",
                     };
                     encode(&mut buf, sig);
                     buf
@@ -52,10 +214,46 @@ mod codec {
                     encode(&mut buf, pattern);
                     buf
                 }
-                _ => {
-                    todo!();
+                InteractBatch((inputs, pattern)) => {
+                    buf.put_u8(b'M');
+                    encode_list(&mut buf, inputs);
+                    encode(&mut buf, pattern);
+                    buf
                 }
-            }
+                FiniteDiff(req) => {
+                    buf.put_u8(b'G');
+                    let json = serde_json::to_string(req).context("serialize finite-diff request")?;
+                    encode(&mut buf, &json);
+                    buf
+                }
+                Hello(hs) => {
+                    buf.put_u8(b'H');
+                    hs.encode(&mut buf)?;
+                    buf
+                }
+                Route(key) => {
+                    buf.put_u8(b'R');
+                    encode(&mut buf, key);
+                    buf
+                }
+                Upload(file) => {
+                    buf.put_u8(b'U');
+                    file.encode(&mut buf);
+                    buf
+                }
+                Download(name) => {
+                    buf.put_u8(b'D');
+                    encode(&mut buf, name);
+                    buf
+                }
+                Profile(name) => {
+                    buf.put_u8(b'P');
+                    encode(&mut buf, name);
+                    buf
+                }
+                Cancel => vec![b'C'],
+            };
+            Ok(buf)
         }
 
         /// Read and decode raw data as operation for server
@@ -70,27 +268,145 @@ mod codec {
                     let pattern = String::from_utf8_lossy(&decode(r).await?).to_string();
                     ServerOp::Interact((input, pattern))
                 }
+                b'M' => {
+                    let inputs = decode_list(r).await?;
+                    let pattern = String::from_utf8_lossy(&decode(r).await?).to_string();
+                    ServerOp::InteractBatch((inputs, pattern))
+                }
+                b'G' => {
+                    let req = serde_json::from_slice(&decode(r).await?).context("invalid finite-diff request")?;
+                    ServerOp::FiniteDiff(req)
+                }
                 b'X' => {
                     let sig = String::from_utf8_lossy(&decode(r).await?).to_string();
                     let sig = match sig.as_str() {
                         "SIGTERM" => Signal::Quit,
                         "SIGCONT" => Signal::Resume,
                         "SIGSTOP" => Signal::Pause,
-                        _ => todo!(),
+                        "SIGINT" => Signal::Interrupt,
+                        _ => bail!("invalid control signal: {:?}", sig),
                     };
                     ServerOp::Control(sig)
                 }
-                _ => {
-                    todo!();
-                }
+                b'H' => ServerOp::Hello(Handshake::decode(r).await?),
+                b'R' => ServerOp::Route(String::from_utf8_lossy(&decode(r).await?).to_string()),
+                b'U' => ServerOp::Upload(FileData::decode(r).await?),
+                b'D' => ServerOp::Download(String::from_utf8_lossy(&decode(r).await?).to_string()),
+                b'P' => ServerOp::Profile(String::from_utf8_lossy(&decode(r).await?).to_string()),
+                b'C' => ServerOp::Cancel,
+                x => bail!("invalid server op tag: {:?}", x),
             };
             Ok(op)
         }
     }
 
-    fn encode<B: BufMut>(mut buf: B, msg: &str) {
+    /// The reply from server side for interaction
+    #[derive(Debug, PartialEq, Clone)]
+    pub enum ServerReply {
+        /// The text read from stdout of server process
+        Output(String),
+        /// The text read from stdout for each input of batch interaction
+        Outputs(Vec<String>),
+        /// The finite-difference gradients
+        FiniteDiff(crate::hessian::FiniteDiffResult),
+        /// The request is rejected as the server queue is full
+        Busy(String),
+        /// The handshake of server
+        Hello(Handshake),
+        /// The downloaded file
+        File(FileData),
+        /// The file operation is done, with the checksum of file written
+        Done(String),
+        /// The file operation failed with error message
+        Failed(String),
+    }
+
+    impl ServerReply {
+        /// Encode message ready for sent over UnixStream
+        pub fn encode(&self) -> Result<Vec<u8>> {
+            let mut buf = vec![];
+            match self {
+                ServerReply::Output(txt) => {
+                    buf.put_u8(b'0');
+                    encode(&mut buf, txt);
+                }
+                ServerReply::Outputs(txts) => {
+                    buf.put_u8(b'M');
+                    encode_list(&mut buf, txts);
+                }
+                ServerReply::FiniteDiff(result) => {
+                    buf.put_u8(b'G');
+                    let json = serde_json::to_string(result).context("serialize finite-diff result")?;
+                    encode(&mut buf, &json);
+                }
+                ServerReply::Busy(msg) => {
+                    buf.put_u8(b'B');
+                    encode(&mut buf, msg);
+                }
+                ServerReply::Hello(hs) => {
+                    buf.put_u8(b'H');
+                    hs.encode(&mut buf)?;
+                }
+                ServerReply::File(file) => {
+                    buf.put_u8(b'F');
+                    file.encode(&mut buf);
+                }
+                ServerReply::Done(sha256) => {
+                    buf.put_u8(b'D');
+                    encode(&mut buf, sha256);
+                }
+                ServerReply::Failed(msg) => {
+                    buf.put_u8(b'E');
+                    encode(&mut buf, msg);
+                }
+            }
+            Ok(buf)
+        }
+
+        /// Read and decode raw data as reply from server
+        pub async fn decode<R: AsyncRead + std::marker::Unpin>(r: &mut R) -> Result<Self> {
+            let mut buf = vec![0_u8; 1];
+            r.read_exact(&mut buf).await?;
+            let reply = match buf[0] {
+                b'0' => ServerReply::Output(String::from_utf8_lossy(&decode(r).await?).to_string()),
+                b'M' => ServerReply::Outputs(decode_list(r).await?),
+                b'G' => ServerReply::FiniteDiff(
+                    serde_json::from_slice(&decode(r).await?).context("invalid finite-diff result")?,
+                ),
+                b'B' => ServerReply::Busy(String::from_utf8_lossy(&decode(r).await?).to_string()),
+                b'H' => ServerReply::Hello(Handshake::decode(r).await?),
+                b'F' => ServerReply::File(FileData::decode(r).await?),
+                b'D' => ServerReply::Done(String::from_utf8_lossy(&decode(r).await?).to_string()),
+                b'E' => ServerReply::Failed(String::from_utf8_lossy(&decode(r).await?).to_string()),
+                x => bail!("invalid server reply tag: {:?}", x),
+            };
+            Ok(reply)
+        }
+    }
+
+    fn encode<B: BufMut>(buf: B, msg: &str) {
+        encode_bytes(buf, msg.as_bytes());
+    }
+
+    fn encode_bytes<B: BufMut>(mut buf: B, msg: &[u8]) {
         buf.put_u32(msg.len() as u32);
-        buf.put(msg.as_bytes());
+        buf.put(msg);
+    }
+
+    fn encode_list(buf: &mut Vec<u8>, msgs: &[String]) {
+        buf.put_u32(msgs.len() as u32);
+        for msg in msgs {
+            encode(&mut *buf, msg);
+        }
+    }
+
+    async fn decode_list<R: AsyncRead + std::marker::Unpin>(r: &mut R) -> Result<Vec<String>> {
+        let n = r.read_u32().await? as usize;
+        let mut msgs = Vec::with_capacity(n.min(1024));
+        for _ in 0..n {
+            msgs.push(String::from_utf8_lossy(&decode(r).await?).to_string());
+        }
+        Ok(msgs)
     }
 
     async fn decode<R: AsyncRead + std::marker::Unpin>(r: &mut R) -> Result<Vec<u8>> {
@@ -109,47 +425,320 @@ mod codec {
         Ok(())
     }
 
-    pub async fn send_msg_encode(stream: &mut UnixStream, msg: &str) -> Result<()> {
-        let mut buf = vec![];
-
-        encode(&mut buf, msg);
-        send_msg(stream, &buf).await?;
-
-        Ok(())
-    }
-
-    pub async fn recv_msg_decode(stream: &mut UnixStream) -> Result<String> {
-        let msg = String::from_utf8_lossy(&decode(stream).await?).to_string();
-        Ok(msg)
+    /// Encode `reply` and send it over `stream`
+    pub async fn send_reply(stream: &mut UnixStream, reply: &ServerReply) -> Result<()> {
+        send_msg(stream, &reply.encode()?).await
     }
 
     #[tokio::test]
     async fn test_async_codec() -> Result<()> {
         let op = ServerOp::Control(Signal::Quit);
-        let d = op.encode();
+        let d = op.encode()?;
+        let decoded_op = ServerOp::decode(&mut d.as_slice()).await?;
+        assert_eq!(decoded_op, op);
+        let op = ServerOp::Control(Signal::Interrupt);
+        let d = op.encode()?;
         let decoded_op = ServerOp::decode(&mut d.as_slice()).await?;
         assert_eq!(decoded_op, op);
 
         let input = "hello world\ngood night\n".to_string();
         let pattern = "POSITIONS: reading from stdin".to_string();
-        let op = ServerOp::Interact((input, pattern));
-        let d = op.encode();
+        let op = ServerOp::Interact((input.clone(), pattern.clone()));
+        let d = op.encode()?;
+        let decoded_op = ServerOp::decode(&mut d.as_slice()).await?;
+        assert_eq!(decoded_op, op);
+        let op = ServerOp::InteractBatch((vec![input.clone(), "".into(), input.clone()], pattern.clone()));
+        let d = op.encode()?;
+        let decoded_op = ServerOp::decode(&mut d.as_slice()).await?;
+        assert_eq!(decoded_op, op);
+        let req = crate::hessian::FiniteDiffRequest {
+            positions: "0.0 0.0 0.0\n".into(),
+            atoms: vec![1],
+            step: 0.015,
+            dipole: false,
+            read_pattern: pattern,
+        };
+        let op = ServerOp::FiniteDiff(req);
+        let d = op.encode()?;
+        let decoded_op = ServerOp::decode(&mut d.as_slice()).await?;
+        assert_eq!(decoded_op, op);
+
+        let op = ServerOp::Hello(Handshake::current());
+        let d = op.encode()?;
+        let decoded_op = ServerOp::decode(&mut d.as_slice()).await?;
+        assert_eq!(decoded_op, op);
+        let op = ServerOp::Route("ml".into());
+        let d = op.encode()?;
+        let decoded_op = ServerOp::decode(&mut d.as_slice()).await?;
+        assert_eq!(decoded_op, op);
+        let op = ServerOp::Upload(FileData::new("POSCAR", b"H2O\n1.0\n".to_vec()));
+        let d = op.encode()?;
         let decoded_op = ServerOp::decode(&mut d.as_slice()).await?;
         assert_eq!(decoded_op, op);
+        let op = ServerOp::Download("OUTCAR".into());
+        let d = op.encode()?;
+        let decoded_op = ServerOp::decode(&mut d.as_slice()).await?;
+        assert_eq!(decoded_op, op);
+        let op = ServerOp::Profile("fast".into());
+        let d = op.encode()?;
+        let decoded_op = ServerOp::decode(&mut d.as_slice()).await?;
+        assert_eq!(decoded_op, op);
+        let d = ServerOp::Cancel.encode()?;
+        assert_eq!(ServerOp::decode(&mut d.as_slice()).await?, ServerOp::Cancel);
+        // handshake from server without engines
+        let hs: Handshake = serde_json::from_str(r#"{"crate_version":"0.0.17","protocol_version":2,"capabilities":[]}"#)?;
+        assert!(hs.engines.is_empty());
+        assert!(ServerOp::decode(&mut b"?".as_slice()).await.is_err());
+        let mut file = FileData::new("INCAR", b"ENCUT = 400\n".to_vec());
+        file.verify()?;
+        file.data[0] = b'e';
+        assert!(file.verify().is_err());
+        file.data.pop();
+        assert!(file.verify().unwrap_err().to_string().contains("size mismatch"));
+
+        for reply in [
+            ServerReply::Output("abc\n".into()),
+            ServerReply::Outputs(vec!["abc\n".into(), "def\n".into()]),
+            ServerReply::Busy("full".into()),
+            ServerReply::Hello(Handshake::current()),
+            ServerReply::File(FileData::new("CONTCAR", vec![0, 1, 2])),
+            ServerReply::Done("e3b0c442".into()),
+            ServerReply::Failed("not allowed".into()),
+        ] {
+            let d = reply.encode()?;
+            let decoded = ServerReply::decode(&mut d.as_slice()).await?;
+            assert_eq!(decoded, reply);
+        }
 
         Ok(())
     }
 }
 // codec:1 ends here
 
+// [[file:../vasp-tools.note::7f3a5c8d][7f3a5c8d]]
+/// Socket file location and permissions for multi-user safety
+mod permission {
+    use super::*;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    /// The permissions applied to socket file after binding
+    #[derive(Debug, Clone)]
+    pub struct SocketPermissions {
+        /// The file mode, 0o600 by default (owner only)
+        pub mode: u32,
+        /// The group owning the socket file, for sharing with group members
+        pub group: Option<String>,
+    }
+
+    impl Default for SocketPermissions {
+        fn default() -> Self {
+            Self { mode: 0o600, group: None }
+        }
+    }
+
+    /// Parse file mode in octal, e.g. "600" or "0o660"
+    pub fn parse_file_mode(s: &str) -> Result<u32> {
+        let s = s.trim();
+        let s = s.strip_prefix("0o").unwrap_or(s);
+        let mode = u32::from_str_radix(s, 8).with_context(|| format!("invalid file mode: {:?}", s))?;
+        ensure!(mode <= 0o777, "invalid file mode: {:o}", mode);
+        Ok(mode)
+    }
+
+    fn lookup_group(name: &str) -> Result<libc::gid_t> {
+        let cname = std::ffi::CString::new(name)?;
+        let grp = unsafe { libc::getgrnam(cname.as_ptr()) };
+        ensure!(!grp.is_null(), "group not found: {:?}", name);
+        Ok(unsafe { (*grp).gr_gid })
+    }
+
+    /// Apply `perms` to `socket_file`.
+    pub fn apply_socket_permissions(socket_file: &Path, perms: &SocketPermissions) -> Result<()> {
+        if let Some(group) = &perms.group {
+            let gid = lookup_group(group)?;
+            let path = std::ffi::CString::new(socket_file.as_os_str().as_bytes())?;
+            // NOTE: -1 (as uid_t) for keeping the owner unchanged
+            if unsafe { libc::chown(path.as_ptr(), libc::uid_t::MAX, gid) } != 0 {
+                let err = std::io::Error::last_os_error();
+                bail!("change group of {:?} to {:?} failed: {:?}", socket_file, group, err);
+            }
+        }
+        std::fs::set_permissions(socket_file, std::fs::Permissions::from_mode(perms.mode))
+            .with_context(|| format!("set mode {:o} for {:?}", perms.mode, socket_file))?;
+
+        Ok(())
+    }
+
+    /// Bind `socket_file` under a restrictive umask, so that the socket is
+    /// never accessible by other users before `apply_socket_permissions`.
+    pub fn bind_private(socket_file: &Path) -> Result<std::os::unix::net::UnixListener> {
+        // NOTE: umask is process wide, restore it immediately after binding
+        let old = unsafe { libc::umask(0o177) };
+        let listener = std::os::unix::net::UnixListener::bind(socket_file);
+        unsafe { libc::umask(old) };
+        listener.with_context(|| format!("bind socket {:?}", socket_file))
+    }
+
+    /// The supplementary groups and effective group of current process
+    fn current_groups() -> Vec<libc::gid_t> {
+        let n = unsafe { libc::getgroups(0, std::ptr::null_mut()) };
+        let mut groups = vec![0; n.max(0) as usize];
+        let n = unsafe { libc::getgroups(groups.len() as libc::c_int, groups.as_mut_ptr()) };
+        groups.truncate(n.max(0) as usize);
+        groups.push(unsafe { libc::getegid() });
+        groups
+    }
+
+    /// Return true if socket file with owner `owner`, group `group` and
+    /// file `mode` can be used by user `uid` in `groups`: owned by the
+    /// user, or shared with one of the user's groups with read and write
+    /// access.
+    fn socket_accessible(owner: u32, group: u32, mode: u32, uid: u32, groups: &[u32]) -> bool {
+        owner == uid || (groups.contains(&group) && mode & 0o060 == 0o060)
+    }
+
+    /// Refuse to use `socket_file` owned by other users, which could be a
+    /// server started by someone else on shared login nodes, unless it is
+    /// explicitly shared with one of our groups.
+    pub fn check_socket_owner(socket_file: &Path) -> Result<()> {
+        let meta = std::fs::metadata(socket_file).with_context(|| format!("stat {:?}", socket_file))?;
+        let uid = unsafe { libc::geteuid() };
+        ensure!(
+            socket_accessible(meta.uid(), meta.gid(), meta.mode(), uid, &current_groups()),
+            "refuse to connect to {:?}: owned by uid {} (gid {}, mode {:o}) and not shared with current user ({})",
+            socket_file,
+            meta.uid(),
+            meta.gid(),
+            meta.mode() & 0o777,
+            uid
+        );
+        Ok(())
+    }
+
+    /// The default socket file for VASP server running in current
+    /// directory. If XDG_RUNTIME_DIR is available, the socket file is placed
+    /// in its `vasp-tools` subdirectory (private to current user), named by
+    /// the hash of current directory, so that the client started in the
+    /// same directory can find it. Otherwise "vasp.sock" in current
+    /// directory.
+    pub fn default_socket_file() -> Result<PathBuf> {
+        use sha2::{Digest, Sha256};
+
+        let runtime_dir = match std::env::var_os("XDG_RUNTIME_DIR") {
+            Some(d) if Path::new(&d).is_dir() => PathBuf::from(d),
+            _ => return Ok("vasp.sock".into()),
+        };
+        let dir = runtime_dir.join("vasp-tools");
+        if !dir.exists() {
+            std::fs::create_dir_all(&dir).with_context(|| format!("create {:?}", dir))?;
+            std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700))?;
+        }
+        let cwd = std::env::current_dir()?.canonicalize()?;
+        let hash = Sha256::digest(cwd.as_os_str().as_bytes());
+        let hash: String = hash.iter().take(8).map(|b| format!("{:02x}", b)).collect();
+        Ok(dir.join(format!("vasp-{}.sock", hash)))
+    }
+
+    #[test]
+    fn test_socket_permissions() -> Result<()> {
+        assert_eq!(parse_file_mode("600")?, 0o600);
+        assert_eq!(parse_file_mode("0o660")?, 0o660);
+        assert!(parse_file_mode("800").is_err());
+        assert!(parse_file_mode("1777").is_err());
+
+        let tdir = tempfile::tempdir()?;
+        let f = tdir.path().join("test.sock");
+        gut::fs::write_to_file(&f, "")?;
+        let perms = SocketPermissions {
+            mode: 0o640,
+            ..Default::default()
+        };
+        apply_socket_permissions(&f, &perms)?;
+        assert_eq!(std::fs::metadata(&f)?.permissions().mode() & 0o777, 0o640);
+        check_socket_owner(&f)?;
+
+        // group shared sockets
+        assert!(socket_accessible(1000, 100, 0o600, 1000, &[]));
+        assert!(socket_accessible(1001, 100, 0o660, 1000, &[10, 100]));
+        assert!(!socket_accessible(1001, 100, 0o640, 1000, &[100]));
+        assert!(!socket_accessible(1001, 100, 0o666, 1000, &[10]));
+
+        let f = tdir.path().join("bind.sock");
+        let _listener = bind_private(&f)?;
+        assert_eq!(std::fs::metadata(&f)?.permissions().mode() & 0o777, 0o600);
+
+        Ok(())
+    }
+}
+// 7f3a5c8d ends here
+
 // [[file:../vasp-tools.note::*server][server:1]]
 mod server {
     use super::*;
-    use crate::interactive::new_interactive_task;
+    use crate::interactive::new_interactive_task_in;
     use crate::interactive::TaskClient;
+    use crate::metrics::{Metrics, ServerState};
+    use crate::process::ResourceLimits;
+    use crate::speculate::{SpeculateOptions, Speculator};
+    use crate::vasp::guard::GeometryGuard;
+    use crate::vasp::profile::{IncarProfiles, Profile};
+    use crate::vasp::restart::ReuseOptions;
+    use crate::vasp::snapshot::SnapshotMode;
+    use permission::SocketPermissions;
 
     use gut::fs::*;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
     use tokio::net::{UnixListener, UnixStream};
+    use tracing::{debug, error, info, warn, Instrument};
+
+    /// The routing key of the engine running in server directory, used by
+    /// clients without selecting any engine.
+    pub const DEFAULT_ENGINE: &str = "default";
+
+    /// The sentinel file in working directory of any engine for cancelling
+    /// the server, for users who can only touch the filesystem
+    pub const CANCEL_FILE: &str = "CANCEL";
+
+    /// How often to check the `CANCEL_FILE` when idle
+    const CANCEL_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(2);
+
+    /// An additional interactive engine hosted by server, e.g. VASP with a
+    /// different functional, or a fast ML potential. Clients select it by
+    /// its name as routing key.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Engine {
+        /// The routing key
+        pub name: String,
+        /// The working directory with its own input files
+        pub dir: PathBuf,
+        /// The program to run. The same as the default engine if None.
+        pub program: Option<PathBuf>,
+    }
+
+    impl std::str::FromStr for Engine {
+        type Err = Error;
+
+        /// Parse engine from "name:dir[:program]".
+        fn from_str(s: &str) -> Result<Self> {
+            let mut parts = s.splitn(3, ':');
+            let name = parts.next().unwrap_or_default().trim();
+            let dir = parts.next().unwrap_or_default().trim();
+            ensure!(
+                !name.is_empty() && !dir.is_empty(),
+                "invalid engine {:?}, expect name:dir[:program]",
+                s
+            );
+            let program = parts.next().map(|x| x.trim()).filter(|x| !x.is_empty());
+            Ok(Self {
+                name: name.into(),
+                dir: dir.into(),
+                program: program.map(|x| x.into()),
+            })
+        }
+    }
 
     /// Computation server backended by unix domain socket
     #[derive(Debug)]
@@ -157,6 +746,32 @@ mod server {
         socket_file: PathBuf,
         listener: UnixListener,
         stream: Option<UnixStream>,
+        limits: ResourceLimits,
+        snapshot: Option<SnapshotMode>,
+        max_queue: Option<usize>,
+        // textfile and HTTP address for exporting metrics
+        metrics_file: Option<PathBuf>,
+        metrics_addr: Option<String>,
+        respawn: Option<ReuseOptions>,
+        transcript: Option<PathBuf>,
+        // additional engines hosted besides the default one
+        engines: Vec<Engine>,
+        // TCP address for JSON-RPC transport
+        #[cfg(feature = "jsonrpc")]
+        jsonrpc_addr: Option<String>,
+        #[cfg(feature = "jsonrpc")]
+        jsonrpc_token: Option<String>,
+        // reject unphysical geometries before feeding them to VASP
+        guard: Option<GeometryGuard>,
+        // pre-compute the extrapolated next geometry while idle
+        speculate: Option<SpeculateOptions>,
+        // the INCAR tags of profiles switched at runtime
+        profiles: IncarProfiles,
+        // additional directories to look for `CANCEL_FILE`
+        cancel_dirs: Vec<PathBuf>,
+        // the listening socket is inherited from systemd, which owns the
+        // socket file
+        inherited: bool,
     }
 
     fn remove_socket_file(s: &Path) -> Result<()> {
@@ -178,7 +793,9 @@ mod server {
     impl Drop for Server {
         // clean up existing unix domain socket file
         fn drop(&mut self) {
-            let _ = remove_socket_file(&self.socket_file);
+            if !self.inherited {
+                let _ = remove_socket_file(&self.socket_file);
+            }
         }
     }
 
@@ -190,126 +807,1633 @@ mod server {
                 bail!("Socket server already started: {:?}!", socket_file);
             }
 
-            let listener = UnixListener::bind(&socket_file).context("bind socket")?;
+            let listener = permission::bind_private(&socket_file)?;
+            listener.set_nonblocking(true)?;
+            let listener = UnixListener::from_std(listener).context("bind socket")?;
+            // accessible by current user only unless changed explicitly
+            permission::apply_socket_permissions(&socket_file, &SocketPermissions::default())?;
             debug!("serve socket {:?}", socket_file);
 
-            Ok(Server {
+            Ok(Self::with_listener(listener, socket_file, false))
+        }
+
+        /// Create a new socket server using listening socket `fd` inherited
+        /// from systemd socket activation. The socket file is managed by
+        /// systemd, and will not be removed on exit.
+        pub fn from_listen_fd(fd: std::os::unix::io::RawFd) -> Result<Self> {
+            use std::os::unix::io::FromRawFd;
+
+            let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+            listener.set_nonblocking(true)?;
+            let socket_file = listener
+                .local_addr()?
+                .as_pathname()
+                .map(|p| p.to_owned())
+                .with_context(|| format!("inherited fd {} is not a unix socket with path", fd))?;
+            let listener = UnixListener::from_std(listener).context("inherited socket")?;
+            info!("serve socket {:?} inherited from systemd", socket_file);
+
+            Ok(Self::with_listener(listener, socket_file, true))
+        }
+
+        fn with_listener(listener: UnixListener, socket_file: PathBuf, inherited: bool) -> Self {
+            Server {
                 listener,
                 socket_file,
                 stream: None,
-            })
+                limits: ResourceLimits::default(),
+                snapshot: None,
+                max_queue: None,
+                metrics_file: None,
+                metrics_addr: None,
+                respawn: None,
+                transcript: None,
+                engines: vec![],
+                #[cfg(feature = "jsonrpc")]
+                jsonrpc_addr: None,
+                #[cfg(feature = "jsonrpc")]
+                jsonrpc_token: None,
+                guard: GeometryGuard::default().into(),
+                speculate: None,
+                profiles: IncarProfiles::default(),
+                cancel_dirs: vec![],
+                inherited,
+            }
+        }
+
+        /// The path to the socket file being served
+        pub fn socket_file(&self) -> &Path {
+            &self.socket_file
+        }
+
+        /// Change the mode and group of socket file, e.g. for sharing the
+        /// server with group members.
+        pub fn set_socket_permissions(&self, perms: &SocketPermissions) -> Result<()> {
+            permission::apply_socket_permissions(&self.socket_file, perms)
+        }
+
+        /// Set resource limits for the child process to be spawned.
+        pub fn set_resource_limits(&mut self, limits: ResourceLimits) {
+            self.limits = limits;
+        }
+
+        /// Save VASP output files into `step_%04d/` after each interaction
+        /// using `mode`.
+        pub fn set_snapshot_mode(&mut self, mode: SnapshotMode) {
+            self.snapshot = mode.into();
+        }
+
+        /// Also look for `CANCEL_FILE` in `dir`, e.g. the submit directory
+        /// when VASP runs in a scratch directory.
+        pub fn add_cancel_dir(&mut self, dir: &Path) {
+            self.cancel_dirs.push(dir.to_owned());
+        }
+
+        /// Reject interactions with BUSY reply when there are already `n`
+        /// interactions pending (including the running one), instead of
+        /// queueing them unboundedly.
+        pub fn set_max_queue(&mut self, n: usize) {
+            self.max_queue = n.into();
+        }
+
+        /// Respawn VASP when it exited (crashed or NSW exhausted), with
+        /// WAVECAR and CHGCAR handled following `reuse`.
+        pub fn set_respawn(&mut self, reuse: ReuseOptions) {
+            self.respawn = reuse.into();
+        }
+
+        /// Host an additional `engine` besides the default one, which
+        /// clients can select by its name.
+        pub fn add_engine(&mut self, engine: Engine) -> Result<()> {
+            ensure!(
+                engine.name != DEFAULT_ENGINE && self.engines.iter().all(|e| e.name != engine.name),
+                "duplicate engine name: {:?}",
+                engine.name
+            );
+            ensure!(engine.dir.is_dir(), "invalid working directory for engine: {:?}", engine.dir);
+            self.engines.push(engine);
+            Ok(())
+        }
+
+        /// Record interactions with VASP (the default engine) into
+        /// transcript file `f`, for replaying using `fake-vasp` without VASP.
+        pub fn set_transcript(&mut self, f: &Path) {
+            self.transcript = f.to_owned().into();
+        }
+
+        /// Export Prometheus metrics into textfile `file` (for node
+        /// exporter) and/or over HTTP at `addr`.
+        pub fn set_metrics_exporter(&mut self, file: Option<PathBuf>, addr: Option<String>) {
+            self.metrics_file = file;
+            self.metrics_addr = addr;
+        }
+
+        /// Also serve clients with JSON-RPC over TCP at `addr`, e.g.
+        /// "127.0.0.1:9200". Address accessible from other hosts is refused
+        /// unless requests are authenticated with `token`.
+        #[cfg(feature = "jsonrpc")]
+        pub fn set_jsonrpc_addr(&mut self, addr: &str, token: Option<&str>) -> Result<()> {
+            use std::net::ToSocketAddrs;
+
+            let addrs = addr.to_socket_addrs().with_context(|| format!("invalid JSON-RPC address {:?}", addr))?;
+            for a in addrs {
+                crate::jsonrpc::check_bind_addr(&a, token)?;
+            }
+            self.jsonrpc_addr = addr.to_owned().into();
+            self.jsonrpc_token = token.map(|x| x.to_owned());
+            Ok(())
+        }
+
+        /// Check positions of each interaction using `guard`, rejecting
+        /// unphysical geometries with an error reply. Set None to disable
+        /// the check, which is enabled by default.
+        pub fn set_geometry_guard(&mut self, guard: Option<GeometryGuard>) {
+            self.guard = guard;
+        }
+
+        /// Pre-compute the next geometry extrapolated from previous steps
+        /// when VASP is idle, and answer the matching request from client
+        /// with its results. Note that the speculative steps are also
+        /// recorded in OUTCAR and vasprun.xml.
+        pub fn set_speculation(&mut self, opts: SpeculateOptions) {
+            self.speculate = opts.into();
+        }
+
+        /// Set INCAR tags of the "fast" and "accurate" profiles, which
+        /// clients can switch to at runtime.
+        pub fn set_incar_profiles(&mut self, profiles: IncarProfiles) {
+            self.profiles = profiles;
         }
 
-        /// Run the `program` backgroundly and serve the client interactions with it
+        /// Run the `program` backgroundly and serve the client interactions with
+        /// it. Return error if VASP or the task server exited unexpectedly.
         pub async fn run_and_serve(&mut self, program: &Path) -> Result<()> {
             // watch for user interruption
             let ctrl_c = tokio::signal::ctrl_c();
 
             // state will be shared with different tasks
-            let (mut server, client) = new_interactive_task(program);
-            let h = server.run_and_serve();
+            let mut engines = vec![(DEFAULT_ENGINE.to_owned(), PathBuf::from("."), program.to_owned())];
+            for e in &self.engines {
+                let program = e.program.clone().unwrap_or_else(|| program.to_owned());
+                engines.push((e.name.clone(), e.dir.clone(), program));
+            }
+            let mut servers = vec![];
+            let mut tasks = HashMap::new();
+            let mut hello = codec::Handshake::current();
+            let mut served = HashMap::new();
+            let mut speculators = HashMap::new();
+            for (name, dir, program) in engines {
+                info!("engine {:?}: run {:?} in {:?}", name, program, dir);
+                let (mut server, client) = new_interactive_task_in(&program, &dir, &self.limits)?;
+                if let Some(reuse) = &self.respawn {
+                    server.set_respawn(reuse.clone());
+                }
+                if let (Some(f), DEFAULT_ENGINE) = (&self.transcript, name.as_str()) {
+                    server.set_transcript(f);
+                }
+                let directory = dir.canonicalize().with_context(|| format!("invalid engine directory: {:?}", dir))?;
+                hello.engines.push(codec::EngineInfo {
+                    name: name.clone(),
+                    directory,
+                    natoms: None,
+                    species: None,
+                    nsteps: None,
+                });
+                served.insert(name.clone(), Arc::new(AtomicUsize::new(0)));
+                if let Some(opts) = self.speculate {
+                    let speculator = Speculator::new(opts, &dir);
+                    speculators.insert(name.clone(), Arc::new(tokio::sync::Mutex::new(speculator)));
+                }
+                tasks.insert(name, (client, dir));
+                servers.push(server);
+            }
+            let routes = Routes {
+                tasks,
+                hello,
+                served,
+                speculators,
+                armed: None,
+                stdout_only: false,
+                profiles: self.profiles.clone(),
+                cancel: Cancellation {
+                    dirs: self.cancel_dirs.clone(),
+                    ..Default::default()
+                }
+                .into(),
+            };
+            // NOTE: the server stops when any engine stopped
+            let h = futures::future::select_all(servers.iter_mut().map(|s| Box::pin(s.run_and_serve())));
             tokio::pin!(h);
+            let metrics = Arc::new(Metrics::new(self.metrics_file.clone()));
+            let ctx = ServeCtx {
+                queue: ClientQueue {
+                    client_id: 0,
+                    // the number of pending interactions from all clients
+                    pending: Arc::new(AtomicUsize::new(0)),
+                    max_queue: self.max_queue,
+                },
+                snapshot: self.snapshot,
+                step: Arc::new(AtomicUsize::new(0)),
+                metrics: metrics.clone(),
+                guard: self.guard,
+            };
+            metrics.set_state(ServerState::Idle);
+            if let Some(addr) = self.metrics_addr.clone() {
+                let metrics = metrics.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = crate::metrics::serve_metrics(metrics, &addr).await {
+                        error!("metrics endpoint error: {:?}", e);
+                    }
+                });
+            }
+            #[cfg(feature = "jsonrpc")]
+            if let Some(addr) = self.jsonrpc_addr.clone() {
+                let (routes, ctx, token) = (routes.clone(), ctx.clone(), self.jsonrpc_token.clone());
+                tokio::spawn(async move {
+                    let res = serve_jsonrpc(&addr, token, routes, ctx).await;
+                    if let Err(e) = res {
+                        error!("JSON-RPC endpoint error: {:?}", e);
+                    }
+                });
+            }
 
+            // the reason why server crashed
+            let mut crashed = None;
             tokio::select! {
                 _ = ctrl_c => {
                     info!("User interrupted. Shutting down ...");
                     // It is hard to exit VASP cleanly
                     // crate::vasp::stopcar::write()?;
                 },
-                res = &mut h => {
+                _ = routes.wait_for_cancel() => {
+                    info!("Cancelled. Stopping VASP gracefully ...");
+                    metrics.set_state(ServerState::Stopped);
+                    for (task, _) in routes.tasks.values() {
+                        task.cancel().await.ok();
+                    }
+                    // wait for all engines stopped
+                    let (res, _, rest) = (&mut h).await;
+                    for res in std::iter::once(res).chain(futures::future::join_all(rest).await) {
+                        if let Err(e) = res {
+                            error!("Task server error: {:?}", e);
+                        }
+                    }
+                },
+                (res, _, _) = &mut h => {
                     if let Err(e) = res {
                         error!("Task server error: {:?}", e);
+                        crashed = format!("task server error: {:?}", e).into();
+                    } else if metrics.child_exits() > 0 {
+                        crashed = "VASP exited unexpectedly".to_owned().into();
                     }
+                    metrics.set_state(ServerState::Stopped);
                 },
                 _ = async {
                     info!("server: start main loop ...");
+                    crate::systemd::notify_ready();
+                    tokio::spawn(crate::systemd::keep_watchdog_alive());
                     for i in 0.. {
                         // wait for client requests
                         let mut client_stream = self.wait_for_client_stream().await.unwrap();
-                        debug!("new incoming connection {}", i);
-                        let task = client.clone();
+                        info!("new incoming connection: client {}", i);
+                        let routes = routes.clone();
                         // spawn a new task for each client
-                        tokio::spawn(async move { handle_client_requests(client_stream, task).await });
+                        let span = tracing::info_span!("client", client = i);
+                        tokio::spawn(handle_client_requests(client_stream, routes, ctx.for_client(i)).instrument(span));
                     }
                 } => {
                     info!("main loop done?");
                 }
             }
 
+            if let Some(msg) = crashed {
+                bail!(msg);
+            }
             Ok(())
         }
     }
 
-    async fn handle_client_requests(mut client_stream: UnixStream, mut task: TaskClient) {
-        use codec::ServerOp;
+    /// The engines available for a client connection
+    #[derive(Clone)]
+    struct Routes {
+        // the task client and working directory of engine by routing key
+        tasks: HashMap<String, (TaskClient, PathBuf)>,
+        // the handshake replied to client
+        hello: codec::Handshake,
+        // the number of interactions done by engine
+        served: HashMap<String, Arc<AtomicUsize>>,
+        // the speculative pre-computation state by engine, if enabled
+        speculators: HashMap<String, Arc<tokio::sync::Mutex<Speculator>>>,
+        // the engine and read-pattern of speculation to start once the
+        // reply is sent to client
+        armed: Option<(String, String)>,
+        // client reads results only from output of interactions, so the
+        // engine may run speculatively once replied
+        stdout_only: bool,
+        // the INCAR tags of profiles
+        profiles: IncarProfiles,
+        // cooperative cancellation of server
+        cancel: Arc<Cancellation>,
+    }
 
-        while let Ok(op) = ServerOp::decode(&mut client_stream).await {
-            match op {
-                ServerOp::Interact((input, pattern)) => {
-                    debug!("client asked for interaction with input and read-pattern");
-                    match task.interact(&input, &pattern).await {
-                        Ok(txt) => {
-                            debug!("sending client text read from stdout");
-                            codec::send_msg_encode(&mut client_stream, &txt).await.unwrap();
-                        }
-                        Err(err) => {
-                            error!("interaction error: {:?}", err);
-                        }
-                    }
-                }
-                ServerOp::Control(sig) => {
-                    debug!("client sent control signal {:?}", sig);
-                    match sig {
-                        codec::Signal::Quit => task.terminate().await.ok(),
-                        codec::Signal::Pause => task.pause().await.ok(),
-                        codec::Signal::Resume => task.resume().await.ok(),
-                    };
-                }
-                _ => {
-                    todo!();
+    /// The cancellation requested by client or by `CANCEL_FILE`
+    #[derive(Default)]
+    struct Cancellation {
+        requested: AtomicBool,
+        notify: tokio::sync::Notify,
+        // the directories to look for `CANCEL_FILE` besides working
+        // directories of engines
+        dirs: Vec<PathBuf>,
+    }
+
+    impl Routes {
+        /// The handshake with current state of engines: the structure VASP
+        /// started with and the number of interactions done, so client need
+        /// not guess from files in working directory.
+        fn current_hello(&self) -> codec::Handshake {
+            let mut hello = self.hello.clone();
+            for engine in hello.engines.iter_mut() {
+                if let Ok((natoms, species)) = crate::vasp::stdin::summarize_poscar(&engine.directory.join("POSCAR")) {
+                    engine.natoms = natoms.into();
+                    engine.species = species.into();
                 }
+                engine.nsteps = self.served.get(&engine.name).map(|n| n.load(Ordering::SeqCst));
             }
+            hello
         }
-    }
-}
-// server:1 ends here
-
-// [[file:../vasp-tools.note::*client][client:1]]
-mod client {
-    use super::*;
-    use gut::fs::*;
-    use std::io::{Read, Write};
-    use tokio::net::UnixStream;
-
-    /// Client of Unix domain socket
-    pub struct Client {
-        stream: UnixStream,
-    }
 
-    impl Client {
-        /// Make connection to unix domain socket server
-        pub async fn connect(socket_file: &Path) -> Result<Self> {
-            debug!("Connect to socket server: {:?}", socket_file);
-            let stream = UnixStream::connect(socket_file)
-                .await
-                .with_context(|| format!("connect to socket file failure: {:?}", socket_file))?;
+        /// Return true if engine `name` has done any interaction, i.e. VASP
+        /// has read the initial structure.
+        fn engine_started(&self, name: &str) -> bool {
+            self.served.get(name).is_some_and(|n| n.load(Ordering::SeqCst) > 0)
+        }
 
-            let client = Self { stream };
-            Ok(client)
+        /// Wait for the speculative pre-computation of engine `name` to
+        /// finish, before it is used for anything other than single
+        /// interaction.
+        async fn settle_speculation(&self, name: &str) {
+            if let Some(speculator) = self.speculators.get(name) {
+                speculator.lock().await.settle().await;
+            }
         }
 
-        /// Interact with background server using `input` for stdin and
+        /// Arm the next speculation of engine `name` after interaction
+        /// `reply`. Only for client declared `codec::STDOUT_ONLY`, and only
+        /// output with forces in stdout is considered, as the speculative
+        /// step appends to OUTCAR and vasprun.xml while client is working
+        /// on the reply.
+        fn arm_speculation(&mut self, name: &str, reply: Option<&codec::ServerReply>, pattern: &str) {
+            self.armed = None;
+            let Some(codec::ServerReply::Output(txt)) = reply else {
+                return;
+            };
+            if !self.stdout_only || !self.speculators.contains_key(name) {
+                return;
+            }
+            if crate::vasp::stdout::parse_energies_and_forces(txt).is_err() {
+                debug!("no speculation without forces in stdout");
+                return;
+            }
+            self.armed = Some((name.to_owned(), pattern.to_owned()));
+        }
+
+        /// Start the armed speculation if any. This is called only after the
+        /// reply is sent to client.
+        async fn start_speculation(&mut self, guard: Option<&GeometryGuard>) {
+            let Some((name, pattern)) = self.armed.take() else {
+                return;
+            };
+            if let (Some(speculator), Some((task, _))) = (self.speculators.get(&name), self.tasks.get(&name)) {
+                speculator.lock().await.start(task, &pattern, guard);
+            }
+        }
+
+        /// Switch engine `name` to INCAR `profile`: VASP is stopped
+        /// gracefully, writing WAVECAR to restart from, then INCAR is
+        /// updated, which takes effect from the next interaction.
+        async fn switch_profile(&self, name: &str, profile: Profile) -> Result<()> {
+            let (task, dir) = self.tasks.get(name).with_context(|| format!("unknown engine: {:?}", name))?;
+            self.settle_speculation(name).await;
+            task.restart().await?;
+            self.profiles.apply(dir, profile)?;
+            Ok(())
+        }
+
+        /// Request cancellation of server: VASP will be stopped gracefully
+        /// after the current interaction.
+        fn request_cancel(&self) {
+            self.cancel.requested.store(true, Ordering::SeqCst);
+            self.cancel.notify.notify_one();
+        }
+
+        /// Return true if cancellation of server has been requested by
+        /// client or by `CANCEL_FILE` in working directory of any engine or
+        /// in additional directories, which is removed when found.
+        fn cancel_requested(&self) -> bool {
+            if self.cancel.requested.load(Ordering::SeqCst) {
+                return true;
+            }
+            let dirs = self.tasks.values().map(|(_, dir)| dir).chain(&self.cancel.dirs);
+            let Some(f) = dirs.map(|dir| dir.join(CANCEL_FILE)).find(|f| f.exists()) else {
+                return false;
+            };
+            info!("found {:?}: cancel the server", f);
+            if let Err(e) = std::fs::remove_file(&f) {
+                warn!("remove {:?} failed: {:?}", f, e);
+            }
+            self.request_cancel();
+            true
+        }
+
+        /// Wait until cancellation of server requested, checking
+        /// `CANCEL_FILE` periodically.
+        async fn wait_for_cancel(&self) {
+            while !self.cancel_requested() {
+                tokio::select! {
+                    _ = self.cancel.notify.notified() => {},
+                    _ = tokio::time::sleep(CANCEL_POLL_INTERVAL) => {},
+                }
+            }
+        }
+
+        /// Record interactions done by engine `name` if `reply` has output.
+        fn record_served(&self, name: &str, reply: Option<&codec::ServerReply>) {
+            let done = match reply {
+                Some(codec::ServerReply::Output(_)) => 1,
+                Some(codec::ServerReply::Outputs(txts)) => txts.len(),
+                // the reference and two displacements for each coordinate
+                Some(codec::ServerReply::FiniteDiff(r)) => 1 + r.hessian_rows.len() * 2,
+                _ => return,
+            };
+            if let Some(n) = self.served.get(name) {
+                n.fetch_add(done, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// Check positions in `input` for engine running in `dir` using
+    /// `guard`. Return the error message if rejected.
+    fn check_geometry(guard: Option<&GeometryGuard>, dir: &Path, input: &str, job: usize) -> Option<String> {
+        let guard = guard?;
+        // NOTE: empty input for reading results only
+        if input.trim().is_empty() {
+            return None;
+        }
+        let e = guard.check_input_in_dir(dir, input).err()?;
+        let msg = format!("unphysical geometry rejected: {:#}", e);
+        warn!("interaction {}: {}", job, msg);
+        Some(msg)
+    }
+
+    /// The shared queue of pending interactions seen by a client
+    #[derive(Clone)]
+    struct ClientQueue {
+        // the id of connected client, for logging
+        client_id: usize,
+        // the number of pending interactions from all clients
+        pending: Arc<AtomicUsize>,
+        max_queue: Option<usize>,
+    }
+
+    impl ClientQueue {
+        /// Try to enter the queue. Return the number of interactions
+        /// already pending if the queue is full.
+        fn try_enter(&self) -> std::result::Result<(), usize> {
+            let n = self.pending.fetch_add(1, Ordering::SeqCst);
+            match self.max_queue {
+                Some(m) if n >= m => {
+                    self.pending.fetch_sub(1, Ordering::SeqCst);
+                    Err(n)
+                }
+                _ => Ok(()),
+            }
+        }
+
+        fn leave(&self) {
+            self.pending.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /// The context for serving interactions of a client
+    #[derive(Clone)]
+    struct ServeCtx {
+        queue: ClientQueue,
+        // save VASP output files after each interaction
+        snapshot: Option<SnapshotMode>,
+        // the interaction counter shared by all clients
+        step: Arc<AtomicUsize>,
+        metrics: Arc<Metrics>,
+        // for checking positions before interaction
+        guard: Option<GeometryGuard>,
+    }
+
+    impl ServeCtx {
+        /// The context for client `id`, sharing the queue, interaction
+        /// counter and metrics with other clients.
+        fn for_client(&self, id: usize) -> Self {
+            let mut ctx = self.clone();
+            ctx.queue.client_id = id;
+            ctx
+        }
+
+        /// The job id of next interaction, shared by all clients, also used
+        /// for snapshot
+        fn next_job(&self) -> usize {
+            self.step.fetch_add(1, Ordering::SeqCst) + 1
+        }
+
+        /// Enter the interaction queue, or return the `Busy` reply for
+        /// client if the queue is full.
+        fn enter_queue(&self) -> std::result::Result<(), codec::ServerReply> {
+            self.queue.try_enter().map_err(|n| {
+                let msg = format!("server busy: {} interactions pending, reject client {}", n, self.queue.client_id);
+                warn!("{}", msg);
+                self.metrics.interaction_rejected();
+                codec::ServerReply::Busy(msg)
+            })
+        }
+    }
+
+    /// Serve one interaction request of client for `job` using engine
+    /// running in `dir`. Return None on interaction error.
+    async fn serve_interaction(
+        task: &mut TaskClient,
+        dir: &Path,
+        input: &str,
+        pattern: &str,
+        job: usize,
+        ctx: &ServeCtx,
+    ) -> Option<codec::ServerReply> {
+        debug!("client {} asked for interaction with input and read-pattern", ctx.queue.client_id);
+        if let Err(reply) = ctx.enter_queue() {
+            return reply.into();
+        }
+        let out = run_interaction(task, dir, input, pattern, job, ctx).await;
+        ctx.queue.leave();
+        codec::ServerReply::Output(out?).into()
+    }
+
+    /// Run one interaction for `job` using engine running in `dir`, for
+    /// client already in the queue. Return None on interaction error.
+    async fn run_interaction(
+        task: &mut TaskClient,
+        dir: &Path,
+        input: &str,
+        pattern: &str,
+        job: usize,
+        ctx: &ServeCtx,
+    ) -> Option<String> {
+        let metrics = &ctx.metrics;
+        let t = std::time::Instant::now();
+        metrics.set_state(ServerState::Running);
+        let out = task.interact(input, pattern).await;
+        metrics.interaction_done(t.elapsed().as_secs_f64(), out.is_ok());
+        match out {
+            Ok(txt) => {
+                info!(elapsed = t.elapsed().as_secs_f64(), "interaction {} done", job);
+                if let Some(mode) = ctx.snapshot {
+                    if let Err(e) = crate::vasp::snapshot::save_step_snapshot(dir, job, mode) {
+                        warn!("failed to save snapshot of step {}: {:?}", job, e);
+                    }
+                }
+                Some(txt)
+            }
+            Err(err) => {
+                error!("interaction error: {:?}", err);
+                None
+            }
+        }
+    }
+
+    /// Serve one interaction as `serve_interaction`, answered by the
+    /// speculative pre-computation of `speculator` if matched. The next
+    /// speculation is started by `Routes::start_speculation` after the
+    /// reply is sent.
+    async fn serve_speculated(
+        speculator: &tokio::sync::Mutex<Speculator>,
+        task: &mut TaskClient,
+        dir: &Path,
+        input: &str,
+        pattern: &str,
+        job: usize,
+        ctx: &ServeCtx,
+    ) -> Option<codec::ServerReply> {
+        let mut speculator = speculator.lock().await;
+        let reply = match speculator.take_matched(input, pattern).await {
+            Some(txt) => {
+                info!("interaction {} answered by speculation", job);
+                codec::ServerReply::Output(txt).into()
+            }
+            None => serve_interaction(task, dir, input, pattern, job, ctx).await,
+        };
+        if let Some(codec::ServerReply::Output(_)) = &reply {
+            speculator.record(input);
+        }
+        reply
+    }
+
+    /// Serve a batch of interactions for `inputs` back-to-back using engine
+    /// running in `dir`, without round trips to client in between. All
+    /// inputs are checked by `guard` before the first one is sent, and the
+    /// queue is entered once for the whole batch, so that no computed
+    /// output is thrown away by a busy server. Return None on interaction
+    /// error.
+    async fn serve_batch(
+        task: &mut TaskClient,
+        dir: &Path,
+        inputs: &[String],
+        pattern: &str,
+        ctx: &ServeCtx,
+    ) -> Option<codec::ServerReply> {
+        use codec::ServerReply;
+
+        debug!("client {} asked for {} interactions in batch", ctx.queue.client_id, inputs.len());
+        let next = ctx.step.load(Ordering::SeqCst);
+        for (i, input) in inputs.iter().enumerate() {
+            if let Some(msg) = check_geometry(ctx.guard.as_ref(), dir, input, next + i + 1) {
+                return ServerReply::Failed(format!("structure {} in batch: {}", i + 1, msg)).into();
+            }
+        }
+        if let Err(reply) = ctx.enter_queue() {
+            return reply.into();
+        }
+        let mut outputs = Vec::with_capacity(inputs.len());
+        for input in inputs {
+            let job = ctx.next_job();
+            let span = tracing::info_span!("interaction", job);
+            match run_interaction(task, dir, input, pattern, job, ctx).instrument(span).await {
+                Some(txt) => outputs.push(txt),
+                None => break,
+            }
+        }
+        ctx.queue.leave();
+        if outputs.len() < inputs.len() {
+            return None;
+        }
+        ServerReply::Outputs(outputs).into()
+    }
+
+    /// Read the computed results of one structure in finite differences
+    /// for engine running in `dir`.
+    async fn read_finite_diff_point(
+        stdout: &str,
+        dir: &Path,
+        natoms: usize,
+        dipole: bool,
+    ) -> Result<crate::hessian::FiniteDiffPoint> {
+        let (props, _) = crate::vasp::results::parse_last_results(stdout, dir, Some(natoms)).await?;
+        let energy = props.mp.get_energy().context("no energy")?;
+        let forces = props.mp.get_forces().context("no forces")?.to_vec();
+        let dipole = if dipole {
+            Some(crate::vasp::outcar::parse_last_dipole(&dir.join("OUTCAR"))?)
+        } else {
+            None
+        };
+        Ok(crate::hessian::FiniteDiffPoint { energy, forces, dipole })
+    }
+
+    /// Evaluate finite-difference gradients for `req` using engine running
+    /// in `dir`. The reference and all displaced structures are computed
+    /// back-to-back in the interactive session, each one restarting from
+    /// wave functions of the previous one, entering the queue once as
+    /// `serve_batch` does. Return None on interaction error.
+    async fn serve_finite_diff(
+        task: &mut TaskClient,
+        dir: &Path,
+        req: &crate::hessian::FiniteDiffRequest,
+        ctx: &ServeCtx,
+    ) -> Option<codec::ServerReply> {
+        use codec::ServerReply;
+
+        let prepared = || -> Result<_> {
+            let s = gut::fs::read_file(dir.join("POSCAR"))?;
+            let lines: Vec<_> = s.lines().collect();
+            let lattice = crate::vasp::poscar::read_lattice_vectors(&lines)?;
+            let natoms = crate::vasp::guard::parse_scaled_positions(&req.positions)?.len();
+            let atoms = req.displaced_atoms(natoms)?;
+            let displaced = req.displaced_positions(&lattice, &atoms)?;
+            Ok((natoms, atoms, displaced))
+        };
+        let (natoms, atoms, displaced) = match prepared() {
+            Ok(x) => x,
+            Err(e) => return ServerReply::Failed(format!("invalid finite-difference request: {:#}", e)).into(),
+        };
+        info!("client {}: finite differences of {} displacements", ctx.queue.client_id, displaced.len());
+        let inputs: Vec<_> = std::iter::once(req.positions.clone()).chain(displaced).collect();
+        let next = ctx.step.load(Ordering::SeqCst);
+        for (i, input) in inputs.iter().enumerate() {
+            if let Some(msg) = check_geometry(ctx.guard.as_ref(), dir, input, next + i + 1) {
+                return ServerReply::Failed(format!("structure {} in finite differences: {}", i + 1, msg)).into();
+            }
+        }
+
+        if let Err(reply) = ctx.enter_queue() {
+            return reply.into();
+        }
+        let mut points = vec![];
+        let mut failed = None;
+        for input in &inputs {
+            let job = ctx.next_job();
+            let span = tracing::info_span!("interaction", job);
+            let txt = match run_interaction(task, dir, input, &req.read_pattern, job, ctx).instrument(span).await {
+                Some(txt) => txt,
+                None => break,
+            };
+            match read_finite_diff_point(&txt, dir, natoms, req.dipole).await {
+                Ok(point) => points.push(point),
+                Err(e) => {
+                    failed = Some(ServerReply::Failed(format!("interaction {}: {:#}", job, e)));
+                    break;
+                }
+            }
+        }
+        ctx.queue.leave();
+        if failed.is_some() {
+            return failed;
+        }
+        if points.len() < inputs.len() {
+            return None;
+        }
+        let reply = match crate::hessian::assemble_finite_diff(atoms, req.step, &points[0], &points[1..]) {
+            Ok(result) => ServerReply::FiniteDiff(result),
+            Err(e) => ServerReply::Failed(format!("{:#}", e)),
+        };
+        reply.into()
+    }
+
+    /// Check that `name` is one of `allowed` files. These are plain file
+    /// names, so clients can not access files outside working directory of
+    /// engine.
+    fn check_transfer_file(name: &str, allowed: &[&str]) -> Result<()> {
+        ensure!(allowed.contains(&name), "transfer of {:?} is not allowed, expect one of {:?}", name, allowed);
+        Ok(())
+    }
+
+    /// Write uploaded `file` into `dir` after verification. Return its
+    /// checksum.
+    fn receive_upload(dir: &Path, file: &codec::FileData) -> Result<String> {
+        check_transfer_file(&file.name, codec::UPLOAD_FILES)?;
+        file.verify()?;
+        // NOTE: write into a temporary file first, so VASP never reads a
+        // partial file
+        let tmp = dir.join(format!(".{}.upload", file.name));
+        std::fs::write(&tmp, &file.data).with_context(|| format!("write {:?}", tmp))?;
+        std::fs::rename(&tmp, dir.join(&file.name))?;
+        Ok(file.sha256.clone())
+    }
+
+    /// Read file `name` in `dir` for downloading.
+    fn read_download(dir: &Path, name: &str) -> Result<codec::FileData> {
+        check_transfer_file(name, codec::DOWNLOAD_FILES)?;
+        let path = dir.join(name);
+        let data = std::fs::read(&path).with_context(|| format!("read {:?}", path))?;
+        Ok(codec::FileData::new(name, data))
+    }
+
+    async fn handle_client_requests(
+        mut client_stream: UnixStream,
+        mut routes: Routes,
+        ctx: ServeCtx,
+    ) {
+        use codec::{ServerOp, ServerReply};
+
+        let id = ctx.queue.client_id;
+        let metrics = &ctx.metrics;
+        // the engine selected by client
+        let mut current = DEFAULT_ENGINE.to_owned();
+        loop {
+            let op = match ServerOp::decode(&mut client_stream).await {
+                Ok(op) => op,
+                Err(e) => {
+                    // NOTE: UnexpectedEof when client closed connection normally
+                    debug!("client {} disconnected: {:?}", id, e);
+                    break;
+                }
+            };
+            // NOTE: no more interactions once cancelled
+            let interaction = matches!(
+                op,
+                ServerOp::Interact(_) | ServerOp::InteractBatch(_) | ServerOp::FiniteDiff(_)
+            );
+            if interaction && routes.cancel_requested() {
+                let reply = ServerReply::Failed("server cancelled, shutting down".into());
+                if codec::send_reply(&mut client_stream, &reply).await.is_err() {
+                    break;
+                }
+                continue;
+            }
+            match op {
+                ServerOp::Hello(hs) => {
+                    info!(
+                        "client {}: vasp-tools {}, protocol version {}",
+                        id, hs.crate_version, hs.protocol_version
+                    );
+                    routes.stdout_only = hs.capabilities.iter().any(|x| x == codec::STDOUT_ONLY);
+                    let reply = ServerReply::Hello(routes.current_hello());
+                    if codec::send_reply(&mut client_stream, &reply).await.is_err() {
+                        break;
+                    }
+                }
+                ServerOp::Route(key) => {
+                    if !routes.tasks.contains_key(&key) {
+                        // NOTE: client checks engines in handshake before routing
+                        error!("client {} asked for unknown engine {:?}", id, key);
+                        break;
+                    }
+                    info!("client {} routed to engine {:?}", id, key);
+                    current = key;
+                }
+                ServerOp::Interact((input, pattern)) => {
+                    let (task, dir) = routes.tasks.get_mut(&current).expect("selected engine");
+                    let job = ctx.next_job();
+                    let span = tracing::info_span!("interaction", job, engine = current.as_str());
+                    let checked = check_geometry(ctx.guard.as_ref(), dir, &input, job);
+                    let reply = match (checked, routes.speculators.get(&current)) {
+                        (Some(msg), _) => Some(ServerReply::Failed(msg)),
+                        (None, Some(speculator)) => {
+                            serve_speculated(speculator, task, dir, &input, &pattern, job, &ctx)
+                                .instrument(span)
+                                .await
+                        }
+                        (None, None) => {
+                            serve_interaction(task, dir, &input, &pattern, job, &ctx).instrument(span).await
+                        }
+                    };
+                    routes.record_served(&current, reply.as_ref());
+                    routes.arm_speculation(&current, reply.as_ref(), &pattern);
+                    // NOTE: close the connection on interaction error, so
+                    // client will not wait for the output forever
+                    let Some(reply) = reply else { break };
+                    if let Err(e) = codec::send_reply(&mut client_stream, &reply).await {
+                        error!("send reply to client {} failed: {:?}", id, e);
+                        break;
+                    }
+                    routes.start_speculation(ctx.guard.as_ref()).await;
+                }
+                ServerOp::InteractBatch((inputs, pattern)) => {
+                    routes.settle_speculation(&current).await;
+                    let (task, dir) = routes.tasks.get_mut(&current).expect("selected engine");
+                    let span = tracing::info_span!("batch", engine = current.as_str());
+                    let reply = serve_batch(task, dir, &inputs, &pattern, &ctx).instrument(span).await;
+                    routes.record_served(&current, reply.as_ref());
+                    let Some(reply) = reply else { break };
+                    if let Err(e) = codec::send_reply(&mut client_stream, &reply).await {
+                        error!("send reply to client {} failed: {:?}", id, e);
+                        break;
+                    }
+                }
+                ServerOp::FiniteDiff(req) => {
+                    let reply = if !routes.engine_started(&current) {
+                        ServerReply::Failed("VASP has not started, send the initial structure first".into()).into()
+                    } else {
+                        routes.settle_speculation(&current).await;
+                        let (task, dir) = routes.tasks.get_mut(&current).expect("selected engine");
+                        let span = tracing::info_span!("finite_diff", engine = current.as_str());
+                        serve_finite_diff(task, dir, &req, &ctx).instrument(span).await
+                    };
+                    routes.record_served(&current, reply.as_ref());
+                    let Some(reply) = reply else { break };
+                    if let Err(e) = codec::send_reply(&mut client_stream, &reply).await {
+                        error!("send reply to client {} failed: {:?}", id, e);
+                        break;
+                    }
+                }
+                ServerOp::Upload(file) => {
+                    // NOTE: VASP may read uploaded files in the speculative step
+                    routes.settle_speculation(&current).await;
+                    let (_, dir) = routes.tasks.get(&current).expect("selected engine");
+                    let reply = match receive_upload(dir, &file) {
+                        Ok(sha256) => {
+                            info!("client {} uploaded {:?} ({} bytes)", id, file.name, file.size);
+                            ServerReply::Done(sha256)
+                        }
+                        Err(e) => {
+                            warn!("client {} upload failed: {:?}", id, e);
+                            ServerReply::Failed(format!("{:#}", e))
+                        }
+                    };
+                    if codec::send_reply(&mut client_stream, &reply).await.is_err() {
+                        break;
+                    }
+                }
+                ServerOp::Download(name) => {
+                    // NOTE: VASP is writing output files in the speculative step
+                    routes.settle_speculation(&current).await;
+                    let (_, dir) = routes.tasks.get(&current).expect("selected engine");
+                    let reply = match read_download(dir, &name) {
+                        Ok(file) => {
+                            info!("client {} downloading {:?} ({} bytes)", id, name, file.size);
+                            ServerReply::File(file)
+                        }
+                        Err(e) => {
+                            warn!("client {} download failed: {:?}", id, e);
+                            ServerReply::Failed(format!("{:#}", e))
+                        }
+                    };
+                    if codec::send_reply(&mut client_stream, &reply).await.is_err() {
+                        break;
+                    }
+                }
+                ServerOp::Profile(name) => {
+                    let res = match name.parse() {
+                        Ok(profile) => routes.switch_profile(&current, profile).await,
+                        Err(e) => Err(e),
+                    };
+                    let reply = match res {
+                        Ok(_) => {
+                            info!("client {} switched engine {:?} to {} profile", id, current, name);
+                            ServerReply::Done(name)
+                        }
+                        Err(e) => {
+                            warn!("client {} switch profile failed: {:?}", id, e);
+                            ServerReply::Failed(format!("{:#}", e))
+                        }
+                    };
+                    if codec::send_reply(&mut client_stream, &reply).await.is_err() {
+                        break;
+                    }
+                }
+                ServerOp::Cancel => {
+                    info!("client {} cancelled the server", id);
+                    routes.request_cancel();
+                    let reply = ServerReply::Done("cancelled".into());
+                    if codec::send_reply(&mut client_stream, &reply).await.is_err() {
+                        break;
+                    }
+                }
+                ServerOp::Control(sig) => {
+                    debug!("client {} sent control signal {:?}", id, sig);
+                    routes.settle_speculation(&current).await;
+                    let (task, _) = routes.tasks.get(&current).expect("selected engine");
+                    match sig {
+                        // stop all engines
+                        codec::Signal::Quit => {
+                            metrics.set_state(ServerState::Stopped);
+                            for (task, _) in routes.tasks.values() {
+                                task.terminate().await.ok();
+                            }
+                            Some(())
+                        }
+                        codec::Signal::Pause => {
+                            metrics.set_state(ServerState::Paused);
+                            task.pause().await.ok()
+                        }
+                        codec::Signal::Resume => {
+                            metrics.set_state(ServerState::Idle);
+                            task.resume().await.ok()
+                        }
+                        codec::Signal::Interrupt => task.interrupt().await.ok(),
+                    };
+                }
+            }
+        }
+    }
+
+    /// Serve clients with JSON-RPC over TCP at `addr`, sharing engines
+    /// and queue with unix socket clients. Requests must carry `token` if
+    /// set.
+    #[cfg(feature = "jsonrpc")]
+    async fn serve_jsonrpc(addr: &str, token: Option<String>, routes: Routes, ctx: ServeCtx) -> Result<()> {
+        let listener = tokio::net::TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("bind JSON-RPC endpoint {:?}", addr))?;
+        crate::jsonrpc::check_bind_addr(&listener.local_addr()?, token.as_deref())?;
+        info!("serve JSON-RPC at {}", addr);
+        let token: Option<Arc<str>> = token.map(|x| x.into());
+        for i in 0.. {
+            let (stream, peer) = listener.accept().await?;
+            info!("new JSON-RPC connection from {}: client {}", peer, i);
+            let span = tracing::info_span!("jsonrpc", client = i);
+            let fut = handle_jsonrpc_client(stream, token.clone(), routes.clone(), ctx.for_client(i));
+            tokio::spawn(fut.instrument(span));
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "jsonrpc")]
+    async fn handle_jsonrpc_client(
+        stream: tokio::net::TcpStream,
+        token: Option<Arc<str>>,
+        mut routes: Routes,
+        ctx: ServeCtx,
+    ) {
+        use crate::jsonrpc::MAX_LINE_BYTES;
+        use tokio::io::AsyncWriteExt;
+
+        let (rd, mut wr) = stream.into_split();
+        let mut rd = tokio::io::BufReader::new(rd);
+        loop {
+            let line = match crate::jsonrpc::read_line(&mut rd, MAX_LINE_BYTES).await {
+                Ok(Some(line)) => line,
+                Ok(None) => break,
+                Err(e) => {
+                    // NOTE: no way to find the start of next request
+                    warn!("close JSON-RPC client {}: {:?}", ctx.queue.client_id, e);
+                    let msg = format!("{:#}", e);
+                    let resp = crate::jsonrpc::Response::error(Default::default(), crate::jsonrpc::INVALID_REQUEST, msg);
+                    wr.write_all(resp.to_line().as_bytes()).await.ok();
+                    break;
+                }
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            let req = crate::jsonrpc::parse_request(&line)
+                .and_then(|req| crate::jsonrpc::check_token(&req, token.as_deref()).map(|_| req));
+            let resp = match req {
+                Ok(req) => {
+                    let id = req.id.clone();
+                    let resp = dispatch_jsonrpc(req, &mut routes, &ctx).await;
+                    // no response for notification
+                    id.map(|_| resp)
+                }
+                Err(resp) => Some(resp),
+            };
+            if let Some(resp) = resp {
+                if let Err(e) = wr.write_all(resp.to_line().as_bytes()).await {
+                    error!("send JSON-RPC response to client {} failed: {:?}", ctx.queue.client_id, e);
+                    break;
+                }
+            }
+            routes.start_speculation(ctx.guard.as_ref()).await;
+        }
+        debug!("JSON-RPC client {} disconnected", ctx.queue.client_id);
+    }
+
+    #[cfg(feature = "jsonrpc")]
+    async fn dispatch_jsonrpc(req: crate::jsonrpc::Request, routes: &mut Routes, ctx: &ServeCtx) -> crate::jsonrpc::Response {
+        use crate::jsonrpc::*;
+        use serde_json::json;
+
+        let metrics = &ctx.metrics;
+        let id = req.id.clone().unwrap_or_default();
+        let unknown_engine = |name: &str| Response::error(id.clone(), INVALID_PARAMS, format!("unknown engine: {:?}", name));
+        // NOTE: no more interactions once cancelled
+        let interaction = matches!(req.method.as_str(), "interact" | "interact_batch" | "finite_diff");
+        if interaction && routes.cancel_requested() {
+            return Response::error(id, CANCELLED, "server cancelled, shutting down");
+        }
+        match req.method.as_str() {
+            "interact" => {
+                let params: InteractParams = match parse_params(&id, &req.params) {
+                    Ok(p) => p,
+                    Err(resp) => return resp,
+                };
+                let name = params.engine.as_deref().unwrap_or(DEFAULT_ENGINE);
+                let Some((task, dir)) = routes.tasks.get_mut(name) else {
+                    return unknown_engine(name);
+                };
+                let job = ctx.next_job();
+                if let Some(msg) = check_geometry(ctx.guard.as_ref(), dir, &params.input, job) {
+                    return Response::error(id, INVALID_GEOMETRY, msg);
+                }
+                let span = tracing::info_span!("interaction", job, engine = name);
+                let (input, pattern) = (&params.input, &params.read_pattern);
+                let reply = match routes.speculators.get(name) {
+                    Some(speculator) => {
+                        serve_speculated(speculator, task, dir, input, pattern, job, ctx)
+                            .instrument(span)
+                            .await
+                    }
+                    None => serve_interaction(task, dir, input, pattern, job, ctx).instrument(span).await,
+                };
+                routes.record_served(name, reply.as_ref());
+                routes.stdout_only = params.stdout_only;
+                routes.arm_speculation(name, reply.as_ref(), pattern);
+                match reply {
+                    Some(codec::ServerReply::Output(txt)) => Response::result(id, json!({ "output": txt })),
+                    Some(codec::ServerReply::Busy(msg)) => Response::error(id, SERVER_BUSY, msg),
+                    _ => Response::error(id, INTERACTION_FAILED, "interaction failed: VASP exited?"),
+                }
+            }
+            "interact_batch" => {
+                let params: InteractBatchParams = match parse_params(&id, &req.params) {
+                    Ok(p) => p,
+                    Err(resp) => return resp,
+                };
+                let name = params.engine.as_deref().unwrap_or(DEFAULT_ENGINE);
+                routes.settle_speculation(name).await;
+                let Some((task, dir)) = routes.tasks.get_mut(name) else {
+                    return unknown_engine(name);
+                };
+                let span = tracing::info_span!("batch", engine = name);
+                let reply = serve_batch(task, dir, &params.inputs, &params.read_pattern, ctx)
+                    .instrument(span)
+                    .await;
+                routes.record_served(name, reply.as_ref());
+                match reply {
+                    Some(codec::ServerReply::Outputs(txts)) => Response::result(id, json!({ "outputs": txts })),
+                    Some(codec::ServerReply::Busy(msg)) => Response::error(id, SERVER_BUSY, msg),
+                    Some(codec::ServerReply::Failed(msg)) => Response::error(id, INVALID_GEOMETRY, msg),
+                    _ => Response::error(id, INTERACTION_FAILED, "interaction failed: VASP exited?"),
+                }
+            }
+            "finite_diff" => {
+                let params: FiniteDiffParams = match parse_params(&id, &req.params) {
+                    Ok(p) => p,
+                    Err(resp) => return resp,
+                };
+                let name = params.engine.as_deref().unwrap_or(DEFAULT_ENGINE);
+                if !routes.tasks.contains_key(name) {
+                    return unknown_engine(name);
+                }
+                if !routes.engine_started(name) {
+                    return Response::error(id, INTERACTION_FAILED, "VASP has not started, send the initial structure first");
+                }
+                routes.settle_speculation(name).await;
+                let (task, dir) = routes.tasks.get_mut(name).expect("engine");
+                let span = tracing::info_span!("finite_diff", engine = name);
+                let reply = serve_finite_diff(task, dir, &params.request, ctx).instrument(span).await;
+                routes.record_served(name, reply.as_ref());
+                match reply {
+                    Some(codec::ServerReply::FiniteDiff(result)) => Response::result(id, json!(result)),
+                    Some(codec::ServerReply::Busy(msg)) => Response::error(id, SERVER_BUSY, msg),
+                    Some(codec::ServerReply::Failed(msg)) => Response::error(id, INVALID_PARAMS, msg),
+                    _ => Response::error(id, INTERACTION_FAILED, "interaction failed: VASP exited?"),
+                }
+            }
+            "control" => {
+                let params: ControlParams = match parse_params(&id, &req.params) {
+                    Ok(p) => p,
+                    Err(resp) => return resp,
+                };
+                let name = params.engine.as_deref().unwrap_or(DEFAULT_ENGINE);
+                routes.settle_speculation(name).await;
+                let Some((task, _)) = routes.tasks.get(name) else {
+                    return unknown_engine(name);
+                };
+                let res = match params.signal.as_str() {
+                    "pause" => {
+                        metrics.set_state(ServerState::Paused);
+                        task.pause().await
+                    }
+                    "resume" => {
+                        metrics.set_state(ServerState::Idle);
+                        task.resume().await
+                    }
+                    "interrupt" => task.interrupt().await,
+                    // stop all engines
+                    "quit" => {
+                        metrics.set_state(ServerState::Stopped);
+                        for (task, _) in routes.tasks.values() {
+                            task.terminate().await.ok();
+                        }
+                        Ok(())
+                    }
+                    s => return Response::error(id, INVALID_PARAMS, format!("invalid signal: {:?}", s)),
+                };
+                match res {
+                    Ok(_) => Response::result(id, json!(true)),
+                    Err(e) => Response::error(id, INTERACTION_FAILED, format!("{:?}", e)),
+                }
+            }
+            "upload" => {
+                let params: UploadParams = match parse_params(&id, &req.params) {
+                    Ok(p) => p,
+                    Err(resp) => return resp,
+                };
+                let name = params.engine.as_deref().unwrap_or(DEFAULT_ENGINE);
+                routes.settle_speculation(name).await;
+                let Some((_, dir)) = routes.tasks.get(name) else {
+                    return unknown_engine(name);
+                };
+                let file = codec::FileData {
+                    name: params.name,
+                    size: params.size,
+                    sha256: params.sha256,
+                    data: params.content.into_bytes(),
+                };
+                match receive_upload(dir, &file) {
+                    Ok(sha256) => Response::result(id, json!({ "sha256": sha256 })),
+                    Err(e) => Response::error(id, TRANSFER_FAILED, format!("{:#}", e)),
+                }
+            }
+            "download" => {
+                let params: DownloadParams = match parse_params(&id, &req.params) {
+                    Ok(p) => p,
+                    Err(resp) => return resp,
+                };
+                let name = params.engine.as_deref().unwrap_or(DEFAULT_ENGINE);
+                routes.settle_speculation(name).await;
+                let Some((_, dir)) = routes.tasks.get(name) else {
+                    return unknown_engine(name);
+                };
+                // NOTE: only text files can be transferred as JSON string
+                let res = read_download(dir, &params.name).and_then(|file| {
+                    let content = String::from_utf8(file.data).context("not a text file")?;
+                    Ok(json!({ "name": file.name, "content": content, "size": file.size, "sha256": file.sha256 }))
+                });
+                match res {
+                    Ok(result) => Response::result(id, result),
+                    Err(e) => Response::error(id, TRANSFER_FAILED, format!("{:#}", e)),
+                }
+            }
+            "set_profile" => {
+                let params: ProfileParams = match parse_params(&id, &req.params) {
+                    Ok(p) => p,
+                    Err(resp) => return resp,
+                };
+                let name = params.engine.as_deref().unwrap_or(DEFAULT_ENGINE);
+                if !routes.tasks.contains_key(name) {
+                    return unknown_engine(name);
+                }
+                let profile = match params.profile.parse() {
+                    Ok(p) => p,
+                    Err(e) => return Response::error(id, INVALID_PARAMS, format!("{:#}", e)),
+                };
+                match routes.switch_profile(name, profile).await {
+                    Ok(_) => Response::result(id, json!(true)),
+                    Err(e) => Response::error(id, INTERACTION_FAILED, format!("{:#}", e)),
+                }
+            }
+            "cancel" => {
+                info!("JSON-RPC client {} cancelled the server", ctx.queue.client_id);
+                routes.request_cancel();
+                Response::result(id, json!(true))
+            }
+            "status" => {
+                let hello = routes.current_hello();
+                let status = json!({
+                    "crate_version": hello.crate_version,
+                    "engines": hello.engines,
+                    "metrics": metrics.status(),
+                });
+                Response::result(id, status)
+            }
+            m => Response::error(id, METHOD_NOT_FOUND, format!("method not found: {:?}", m)),
+        }
+    }
+
+    #[test]
+    fn test_engine_spec() -> Result<()> {
+        let e: Engine = "ml:../ml:/opt/bin/ml-engine".parse()?;
+        assert_eq!(e.name, "ml");
+        assert_eq!(e.dir, Path::new("../ml"));
+        assert_eq!(e.program.as_deref(), Some(Path::new("/opt/bin/ml-engine")));
+        let e: Engine = "pbe0:hybrid".parse()?;
+        assert_eq!(e.program, None);
+        assert!("pbe0".parse::<Engine>().is_err());
+        assert!(":hybrid".parse::<Engine>().is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cancel_requested() -> Result<()> {
+        let tdir = tempfile::tempdir()?;
+        let cancel = Cancellation {
+            dirs: vec![tdir.path().to_owned()],
+            ..Default::default()
+        };
+        let routes = Routes {
+            tasks: HashMap::new(),
+            hello: codec::Handshake::current(),
+            served: HashMap::new(),
+            speculators: HashMap::new(),
+            armed: None,
+            stdout_only: false,
+            profiles: IncarProfiles::default(),
+            cancel: cancel.into(),
+        };
+        assert!(!routes.cancel_requested());
+        // by sentinel file, which is removed when found
+        let f = tdir.path().join(CANCEL_FILE);
+        gut::fs::write_to_file(&f, "")?;
+        assert!(routes.cancel_requested());
+        assert!(!f.exists());
+        assert!(routes.cancel_requested());
+        routes.wait_for_cancel().await;
+
+        // by client on another connection
+        let routes = Routes {
+            cancel: Default::default(),
+            ..routes
+        };
+        assert!(!routes.cancel_requested());
+        let other = routes.clone();
+        let wait = tokio::spawn(async move { other.wait_for_cancel().await });
+        routes.request_cancel();
+        tokio::time::timeout(std::time::Duration::from_secs(1), wait).await??;
+        assert!(routes.cancel_requested());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_arm_speculation() -> Result<()> {
+        let tdir = tempfile::tempdir()?;
+        let speculator = Speculator::new(Default::default(), tdir.path());
+        let mut routes = Routes {
+            tasks: HashMap::new(),
+            hello: codec::Handshake::current(),
+            served: HashMap::new(),
+            speculators: [(DEFAULT_ENGINE.to_owned(), Arc::new(speculator.into()))].into(),
+            armed: None,
+            stdout_only: false,
+            profiles: IncarProfiles::default(),
+            cancel: Default::default(),
+        };
+        let txt = gut::fs::read_file("./tests/files/interactive.txt")?;
+        let reply = codec::ServerReply::Output(txt);
+        // client may read OUTCAR or vasprun.xml after the reply
+        routes.arm_speculation(DEFAULT_ENGINE, Some(&reply), ".");
+        assert!(routes.armed.is_none());
+        routes.stdout_only = true;
+        routes.arm_speculation(DEFAULT_ENGINE, Some(&reply), ".");
+        assert!(routes.armed.is_some());
+        // no forces in stdout
+        let reply = codec::ServerReply::Output("POSITIONS: reading from stdin\n".into());
+        routes.arm_speculation(DEFAULT_ENGINE, Some(&reply), ".");
+        assert!(routes.armed.is_none());
+
+        Ok(())
+    }
+}
+// server:1 ends here
+
+// [[file:../vasp-tools.note::*client][client:1]]
+mod client {
+    use super::*;
+    use gut::fs::*;
+    use std::io::{Read, Write};
+    use tokio::net::UnixStream;
+
+    /// The error when the server rejected interaction as its queue is full,
+    /// e.g. several optimizers mistakenly connected to the same server.
+    #[derive(Debug, Clone)]
+    pub struct ServerBusy(pub String);
+
+    impl std::fmt::Display for ServerBusy {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "{}", self.0)
+        }
+    }
+
+    impl std::error::Error for ServerBusy {}
+
+    /// Client of Unix domain socket
+    pub struct Client {
+        stream: UnixStream,
+        // the handshake from server
+        server: codec::Handshake,
+        // the opt-in cache of interaction results
+        cache: Option<ResponseCache>,
+        // the engine selected for interactions
+        engine: String,
+    }
+
+    /// Exchange handshake with server. Return error if the server is too
+    /// old to understand the handshake or incompatible.
+    async fn handshake(stream: &mut UnixStream) -> Result<codec::Handshake> {
+        use codec::{Handshake, ServerOp, ServerReply, MIN_PROTOCOL_VERSION};
+
+        let op = ServerOp::Hello(Handshake::current());
+        codec::send_msg(stream, &op.encode()?).await?;
+        // NOTE: server without handshake support closes the connection
+        let timeout = std::time::Duration::from_secs(5);
+        let reply = tokio::time::timeout(timeout, ServerReply::decode(stream)).await;
+        let hs = match reply {
+            Ok(Ok(ServerReply::Hello(hs))) => hs,
+            Ok(Ok(reply)) => bail!("unexpected handshake reply from server: {:?}", reply),
+            _ => bail!(
+                "no handshake reply from server: the server is too old (protocol version < {}), please restart it with this version of vasp-tools",
+                MIN_PROTOCOL_VERSION
+            ),
+        };
+        ensure!(
+            hs.protocol_version >= MIN_PROTOCOL_VERSION,
+            "server (vasp-tools {}) is too old: protocol version {}, required >= {}",
+            hs.crate_version,
+            hs.protocol_version,
+            MIN_PROTOCOL_VERSION
+        );
+        debug!("server handshake: {:?}", hs);
+
+        Ok(hs)
+    }
+
+    impl Client {
+        /// Make connection to unix domain socket server
+        pub async fn connect(socket_file: &Path) -> Result<Self> {
+            debug!("Connect to socket server: {:?}", socket_file);
+            permission::check_socket_owner(socket_file)?;
+            let mut stream = UnixStream::connect(socket_file)
+                .await
+                .with_context(|| format!("connect to socket file failure: {:?}", socket_file))?;
+            let server = handshake(&mut stream).await?;
+
+            let client = Self {
+                stream,
+                server,
+                cache: None,
+                engine: server::DEFAULT_ENGINE.into(),
+            };
+            Ok(client)
+        }
+
+        /// Return the working directory of selected engine reported by
+        /// server, or None for old server without engines.
+        pub fn engine_dir(&self) -> Option<&Path> {
+            let engine = self.server.engines.iter().find(|e| e.name == self.engine)?;
+            Some(&engine.directory)
+        }
+
+        /// Return the number of interactions done by selected engine, or
+        /// None for old server not reporting engine state.
+        pub fn engine_steps(&self) -> Option<usize> {
+            self.server.engines.iter().find(|e| e.name == self.engine)?.nsteps
+        }
+
+        /// Return the number of atoms and species (e.g. "Ti2 O4") of
+        /// structure selected engine started with, if reported by server.
+        pub fn engine_structure(&self) -> Option<(usize, &str)> {
+            let engine = self.server.engines.iter().find(|e| e.name == self.engine)?;
+            Some((engine.natoms?, engine.species.as_deref()?))
+        }
+
+        /// Route following interactions and control signals on this
+        /// connection to the engine named `name`. Return the working
+        /// directory of the engine.
+        pub async fn select_engine(&mut self, name: &str) -> Result<PathBuf> {
+            ensure!(self.server_supports("route"), "server does not support multiple engines");
+            let engine = self.server.engines.iter().find(|e| e.name == name).with_context(|| {
+                let names: Vec<_> = self.server.engines.iter().map(|e| e.name.as_str()).collect();
+                format!("no engine {:?} on server, available engines: {:?}", name, names)
+            })?;
+            let dir = engine.directory.clone();
+            self.send_op(codec::ServerOp::Route(name.into())).await?;
+            self.engine = name.into();
+            Ok(dir)
+        }
+
+        /// Cache interaction results in file `f`, keyed by the hash of input
+        /// and read pattern on selected engine. Identical interactions will
+        /// be answered from the cache without asking the server.
+        pub fn enable_cache(&mut self, f: &Path) -> Result<()> {
+            self.cache = ResponseCache::open(f)?.into();
+            Ok(())
+        }
+
+        /// Declare that results are read only from output of interactions,
+        /// never from OUTCAR or vasprun.xml in working directory of engine,
+        /// so server may run the next geometry speculatively (`run-vasp
+        /// --speculate`) while client is working on the reply.
+        pub async fn declare_stdout_only(&mut self) -> Result<()> {
+            let mut hello = codec::Handshake::current();
+            hello.capabilities.push(codec::STDOUT_ONLY.into());
+            self.send_op(codec::ServerOp::Hello(hello)).await?;
+            match codec::ServerReply::decode(&mut self.stream).await? {
+                codec::ServerReply::Hello(hs) => self.server = hs,
+                reply => bail!("unexpected reply from server: {:?}", reply),
+            }
+            Ok(())
+        }
+
+        /// Return the cache key of interaction with `input` and
+        /// `read_pattern` on selected engine, identified by its working
+        /// directory with INCAR and POSCAR there.
+        fn cache_key(&self, input: &str, read_pattern: &str) -> String {
+            let scope = self.engine_dir().map(ResponseCache::engine_scope).unwrap_or_default();
+            ResponseCache::key(&[input, read_pattern, &self.engine, &scope])
+        }
+
+        /// Return true if server supports operation `op`, e.g. "control"
+        pub fn server_supports(&self, op: &str) -> bool {
+            self.server.capabilities.iter().any(|x| x == op)
+        }
+
+        /// Interact with background server using `input` for stdin and
         /// `read_pattern` for reading stdout.
         pub async fn interact(&mut self, input: &str, read_pattern: &str) -> Result<String> {
+            let key = self.cache_key(input, read_pattern);
+            if let Some(out) = self.cache.as_ref().and_then(|c| c.get(&key)) {
+                debug!("found cached interaction result");
+                return Ok(String::from_utf8_lossy(out).into_owned());
+            }
+
             debug!("Interact with server process ...");
             let op = codec::ServerOp::Interact((input.to_string(), read_pattern.to_string()));
             self.send_op(op).await?;
 
             debug!("receiving output");
-            let txt = codec::recv_msg_decode(&mut self.stream).await?;
-            debug!("got {} bytes", txt.len());
+            match codec::ServerReply::decode(&mut self.stream).await? {
+                codec::ServerReply::Output(txt) => {
+                    debug!("got {} bytes", txt.len());
+                    if let Some(cache) = self.cache.as_mut() {
+                        cache.insert(&key, txt.as_bytes())?;
+                    }
+                    Ok(txt)
+                }
+                codec::ServerReply::Busy(msg) => Err(ServerBusy(msg).into()),
+                codec::ServerReply::Failed(msg) => bail!("interaction rejected by server: {}", msg),
+                reply => bail!("unexpected reply from server: {:?}", reply),
+            }
+        }
+
+        /// Interact with background server for each of `inputs` in one
+        /// round trip, using `read_pattern` for reading stdout. The server
+        /// runs them back-to-back, e.g. for finite-difference displacements
+        /// or NEB images. Return the outputs in the same order. Old server
+        /// without batch support is asked one by one.
+        pub async fn interact_batch(&mut self, inputs: &[String], read_pattern: &str) -> Result<Vec<String>> {
+            if !self.server_supports("batch") {
+                let mut outputs = vec![];
+                for input in inputs {
+                    outputs.push(self.interact(input, read_pattern).await?);
+                }
+                return Ok(outputs);
+            }
 
-            Ok(txt)
+            let keys: Vec<_> = inputs
+                .iter()
+                .map(|input| self.cache_key(input, read_pattern))
+                .collect();
+            let mut outputs: Vec<Option<String>> = keys
+                .iter()
+                .map(|key| {
+                    let out = self.cache.as_ref()?.get(key)?;
+                    Some(String::from_utf8_lossy(out).into_owned())
+                })
+                .collect();
+            // only ask server for uncached ones
+            let pending: Vec<_> = (0..inputs.len()).filter(|&i| outputs[i].is_none()).collect();
+            if !pending.is_empty() {
+                debug!("Interact with server process for {} inputs in batch ...", pending.len());
+                let batch = pending.iter().map(|&i| inputs[i].clone()).collect();
+                self.send_op(codec::ServerOp::InteractBatch((batch, read_pattern.to_string())))
+                    .await?;
+                let txts = match codec::ServerReply::decode(&mut self.stream).await? {
+                    codec::ServerReply::Outputs(txts) => txts,
+                    codec::ServerReply::Busy(msg) => return Err(ServerBusy(msg).into()),
+                    codec::ServerReply::Failed(msg) => bail!("interaction rejected by server: {}", msg),
+                    reply => bail!("unexpected reply from server: {:?}", reply),
+                };
+                ensure!(
+                    txts.len() == pending.len(),
+                    "expect {} outputs from server, got {}",
+                    pending.len(),
+                    txts.len()
+                );
+                for (i, txt) in pending.into_iter().zip(txts) {
+                    if let Some(cache) = self.cache.as_mut() {
+                        cache.insert(&keys[i], txt.as_bytes())?;
+                    }
+                    outputs[i] = Some(txt);
+                }
+            }
+            Ok(outputs.into_iter().flatten().collect())
+        }
+
+        /// Ask server to evaluate finite-difference gradients for `req`. All
+        /// displaced structures are computed on server side in one round
+        /// trip.
+        pub async fn finite_diff(
+            &mut self,
+            req: &crate::hessian::FiniteDiffRequest,
+        ) -> Result<crate::hessian::FiniteDiffResult> {
+            ensure!(self.server_supports("finite-diff"), "server does not support finite differences");
+            self.send_op(codec::ServerOp::FiniteDiff(req.clone())).await?;
+            match codec::ServerReply::decode(&mut self.stream).await? {
+                codec::ServerReply::FiniteDiff(result) => Ok(result),
+                codec::ServerReply::Busy(msg) => Err(ServerBusy(msg).into()),
+                codec::ServerReply::Failed(msg) => bail!("finite differences failed on server: {}", msg),
+                reply => bail!("unexpected reply from server: {:?}", reply),
+            }
+        }
+
+        /// Upload local file `f` into working directory of selected engine
+        /// on server as `name`, e.g. "POSCAR" or "INCAR".
+        pub async fn upload_file(&mut self, f: &Path, name: &str) -> Result<()> {
+            ensure!(self.server_supports("transfer"), "server does not support file transfer");
+            let data = std::fs::read(f).with_context(|| format!("read {:?}", f))?;
+            let file = codec::FileData::new(name, data);
+            let sha256 = file.sha256.clone();
+            self.send_op(codec::ServerOp::Upload(file)).await?;
+            match codec::ServerReply::decode(&mut self.stream).await? {
+                codec::ServerReply::Done(x) => {
+                    ensure!(x == sha256, "checksum mismatch of uploaded {:?}: {} != {}", name, x, sha256);
+                    Ok(())
+                }
+                codec::ServerReply::Failed(msg) => bail!("upload {:?} failed: {}", name, msg),
+                reply => bail!("unexpected reply from server: {:?}", reply),
+            }
+        }
+
+        /// Download file `name` from working directory of selected engine
+        /// on server, e.g. "OUTCAR" or "CONTCAR", into local file `f`.
+        pub async fn download_file(&mut self, name: &str, f: &Path) -> Result<()> {
+            ensure!(self.server_supports("transfer"), "server does not support file transfer");
+            self.send_op(codec::ServerOp::Download(name.into())).await?;
+            match codec::ServerReply::decode(&mut self.stream).await? {
+                codec::ServerReply::File(file) => {
+                    ensure!(file.name == name, "expect file {:?}, received {:?}", name, file.name);
+                    file.verify()?;
+                    std::fs::write(f, &file.data).with_context(|| format!("write {:?}", f))?;
+                    Ok(())
+                }
+                codec::ServerReply::Failed(msg) => bail!("download {:?} failed: {}", name, msg),
+                reply => bail!("unexpected reply from server: {:?}", reply),
+            }
         }
 
         /// Try to tell the background computation to stop
@@ -333,9 +2457,43 @@ mod client {
             Ok(())
         }
 
+        /// Try to interrupt the background computation with SIGINT, letting
+        /// VASP finish the current SCF step cleanly, e.g. before pause.
+        pub async fn try_interrupt(&mut self) -> Result<()> {
+            ensure!(self.server_supports("interrupt"), "server does not support interrupt");
+            self.send_op_control(codec::Signal::Interrupt).await?;
+
+            Ok(())
+        }
+
+        /// Switch INCAR profile of selected engine to `profile` ("fast" or
+        /// "accurate"). VASP is restarted with updated INCAR on next
+        /// interaction.
+        pub async fn set_profile(&mut self, profile: &str) -> Result<()> {
+            ensure!(self.server_supports("profile"), "server does not support INCAR profiles");
+            self.send_op(codec::ServerOp::Profile(profile.into())).await?;
+            match codec::ServerReply::decode(&mut self.stream).await? {
+                codec::ServerReply::Done(_) => Ok(()),
+                codec::ServerReply::Failed(msg) => bail!("switch profile failed on server: {}", msg),
+                reply => bail!("unexpected reply from server: {:?}", reply),
+            }
+        }
+
+        /// Cancel the server: VASP is stopped gracefully using STOPCAR after
+        /// the current interaction, and the server shuts down.
+        pub async fn cancel(&mut self) -> Result<()> {
+            ensure!(self.server_supports("cancel"), "server does not support cancellation");
+            self.send_op(codec::ServerOp::Cancel).await?;
+            match codec::ServerReply::decode(&mut self.stream).await? {
+                codec::ServerReply::Done(_) => Ok(()),
+                reply => bail!("unexpected reply from server: {:?}", reply),
+            }
+        }
+
         /// Send control signal to server
         async fn send_op_control(&mut self, sig: codec::Signal) -> Result<()> {
             debug!("Send control signal {:?}", sig);
+            ensure!(self.server_supports("control"), "server does not support control operation");
             let op = codec::ServerOp::Control(sig);
             self.send_op(op).await?;
 
@@ -345,7 +2503,7 @@ mod client {
         async fn send_op(&mut self, op: codec::ServerOp) -> Result<()> {
             use tokio::io::AsyncWriteExt;
 
-            self.stream.write_all(&op.encode()).await?;
+            self.stream.write_all(&op.encode()?).await?;
             self.stream.flush().await?;
 
             Ok(())
@@ -354,7 +2512,169 @@ mod client {
 }
 // client:1 ends here
 
+// [[file:../vasp-tools.note::038510c9][038510c9]]
+/// Local cache of interaction results on client side
+mod cache {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+    use std::collections::HashMap;
+    use std::io::Write;
+
+    /// One record in cache file
+    #[derive(Debug, Serialize, Deserialize)]
+    struct CacheRecord {
+        key: String,
+        value: Vec<u8>,
+    }
+
+    /// The cache of interaction results stored in a local file, as
+    /// appended msgpack records, so that repeated evaluations in re-run
+    /// scripts need not hit the server.
+    ///
+    /// NOTE: a plain append-only file is used instead of an embedded
+    /// database like sled or sqlite: the cache is written by one client
+    /// process at a time and read whole on start, so a database would only
+    /// add a heavy dependency. A record torn by a killed client is cut off
+    /// when the file is opened again.
+    #[derive(Debug)]
+    pub struct ResponseCache {
+        file: PathBuf,
+        entries: HashMap<String, Vec<u8>>,
+    }
+
+    impl ResponseCache {
+        /// Open cache file `f`, which will be created on first insertion.
+        /// An invalid record at the end (e.g. killed while writing) is
+        /// removed from the file, so new records are appended after the
+        /// last valid one.
+        pub fn open(f: &Path) -> Result<Self> {
+            let mut entries = HashMap::new();
+            if f.exists() {
+                let data = std::fs::read(f).with_context(|| format!("read cache file {:?}", f))?;
+                let mut rd = data.as_slice();
+                while !rd.is_empty() {
+                    let valid = data.len() - rd.len();
+                    match rmp_serde::from_read::<_, CacheRecord>(&mut rd) {
+                        Ok(r) => {
+                            entries.insert(r.key, r.value);
+                        }
+                        Err(e) => {
+                            warn!("remove invalid records from cache file {:?}: {:?}", f, e);
+                            std::fs::OpenOptions::new()
+                                .write(true)
+                                .open(f)
+                                .and_then(|fp| fp.set_len(valid as u64))
+                                .with_context(|| format!("truncate cache file {:?}", f))?;
+                            break;
+                        }
+                    }
+                }
+                debug!("{} cached results loaded from {:?}", entries.len(), f);
+            }
+            Ok(Self {
+                file: f.to_owned(),
+                entries,
+            })
+        }
+
+        /// Return the cache key for input payload `parts`: the hex of
+        /// their SHA256 hash.
+        pub fn key(parts: &[&str]) -> String {
+            use sha2::{Digest, Sha256};
+
+            let mut hasher = Sha256::new();
+            for part in parts {
+                hasher.update(part.as_bytes());
+                // NOTE: separate parts to avoid ambiguity, e.g. ["ab", "c"] vs ["a", "bc"]
+                hasher.update(b"\0");
+            }
+            format!("{:x}", hasher.finalize())
+        }
+
+        /// Return the identity of engine in working directory `dir` for
+        /// cache keys: the hash of the directory with INCAR and POSCAR in
+        /// it, so results of another server or calculation setup are never
+        /// reused.
+        pub fn engine_scope(dir: &Path) -> String {
+            let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap_or_default();
+            let dir = dir.canonicalize().unwrap_or_else(|_| dir.to_owned());
+            Self::key(&[&dir.to_string_lossy(), &read("INCAR"), &read("POSCAR")])
+        }
+
+        /// Return the cached result for `key`.
+        pub fn get(&self, key: &str) -> Option<&[u8]> {
+            self.entries.get(key).map(|v| v.as_slice())
+        }
+
+        /// Cache result `value` for `key`, and append it into cache file.
+        pub fn insert(&mut self, key: &str, value: &[u8]) -> Result<()> {
+            let record = CacheRecord {
+                key: key.into(),
+                value: value.to_vec(),
+            };
+            let mut fp = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.file)
+                .with_context(|| format!("open cache file {:?}", self.file))?;
+            fp.write_all(&rmp_serde::to_vec(&record)?)?;
+            self.entries.insert(record.key, record.value);
+            Ok(())
+        }
+
+        /// Return the number of cached results.
+        pub fn len(&self) -> usize {
+            self.entries.len()
+        }
+
+        /// Return true if nothing cached.
+        pub fn is_empty(&self) -> bool {
+            self.entries.is_empty()
+        }
+    }
+
+    #[test]
+    fn test_response_cache() -> Result<()> {
+        let tdir = tempfile::tempdir()?;
+        let f = tdir.path().join("cache.db");
+        let k1 = ResponseCache::key(&["ab", "c"]);
+        let k2 = ResponseCache::key(&["a", "bc"]);
+        assert_ne!(k1, k2);
+
+        let mut cache = ResponseCache::open(&f)?;
+        assert!(cache.is_empty());
+        cache.insert(&k1, b"energy = -1.0")?;
+        cache.insert(&k2, &[0xc0, 0xff])?;
+        assert_eq!(cache.get(&k1), Some(&b"energy = -1.0"[..]));
+
+        // truncated record at the end
+        let mut fp = std::fs::OpenOptions::new().append(true).open(&f)?;
+        fp.write_all(&[0x92, 0xa3])?;
+        let mut cache = ResponseCache::open(&f)?;
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&k2), Some(&[0xc0, 0xff][..]));
+        assert_eq!(cache.get("xx"), None);
+        // records inserted after the truncated one are kept
+        let k3 = ResponseCache::key(&["abc"]);
+        cache.insert(&k3, b"energy = -2.0")?;
+        let cache = ResponseCache::open(&f)?;
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.get(&k3), Some(&b"energy = -2.0"[..]));
+
+        // the same input in another directory or with another INCAR
+        let scope = ResponseCache::engine_scope(tdir.path());
+        assert_ne!(scope, ResponseCache::engine_scope(&tdir.path().join("ml")));
+        gut::fs::write_to_file(tdir.path().join("INCAR"), "ENCUT = 400\n")?;
+        assert_ne!(scope, ResponseCache::engine_scope(tdir.path()));
+
+        Ok(())
+    }
+}
+// 038510c9 ends here
+
 // [[file:../vasp-tools.note::*pub][pub:1]]
-pub use client::Client;
-pub use server::Server;
+pub use cache::ResponseCache;
+pub use client::{Client, ServerBusy};
+pub use permission::{default_socket_file, parse_file_mode, SocketPermissions};
+pub use server::{Engine, Server};
 // pub:1 ends here
@@ -0,0 +1,301 @@
+// [[file:../vasp-tools.note::*docs][docs:1]]
+//! Speculative pre-computation of the next geometry: while the interactive
+//! VASP is idle, extrapolate the next expected structure from previous
+//! steps and run it in advance, so a matching request from client is
+//! answered instantly. The answer is computed for the extrapolated
+//! structure, not the requested one, which differs by up to the tolerance.
+// docs:1 ends here
+
+// [[file:../vasp-tools.note::490e3d8b][490e3d8b]]
+use super::*;
+
+use crate::interactive::TaskClient;
+use crate::vasp::guard::GeometryGuard;
+use std::collections::VecDeque;
+// 490e3d8b ends here
+
+// [[file:../vasp-tools.note::62533352][62533352]]
+/// Options for speculative pre-computation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpeculateOptions {
+    /// The order of extrapolation: 1 for linear (from the last two steps),
+    /// 2 for quadratic (from the last three steps)
+    pub order: usize,
+    /// The max deviation in Å of any atom from the extrapolated structure
+    /// for reusing its results
+    pub tolerance: f64,
+}
+
+impl Default for SpeculateOptions {
+    fn default() -> Self {
+        Self {
+            order: 1,
+            tolerance: 0.005,
+        }
+    }
+}
+
+impl std::str::FromStr for SpeculateOptions {
+    type Err = Error;
+
+    /// Parse extrapolation "linear" or "quadratic"
+    fn from_str(s: &str) -> Result<Self> {
+        let order = match s.trim().to_lowercase().as_str() {
+            "linear" => 1,
+            "quadratic" => 2,
+            _ => bail!("invalid extrapolation: {:?}, expect linear or quadratic", s),
+        };
+        Ok(Self {
+            order,
+            ..Default::default()
+        })
+    }
+}
+
+/// The difference of fractional coordinates with minimum image convention
+fn wrapped(x: f64) -> f64 {
+    x - x.round()
+}
+
+/// Extrapolate the next structure from scaled positions in `history` (the
+/// oldest first) with polynomial of `order`. Return None if there are not
+/// enough steps, or the number of atoms changed.
+pub fn extrapolate(history: &[Vec<[f64; 3]>], order: usize) -> Option<Vec<[f64; 3]>> {
+    let n = history.len();
+    if n < order + 1 {
+        return None;
+    }
+    let last = &history[n - 1];
+    if history[n - order - 1..].iter().any(|x| x.len() != last.len()) {
+        return None;
+    }
+    let mut next = last.clone();
+    for (i, p) in next.iter_mut().enumerate() {
+        for k in 0..3 {
+            let d1 = wrapped(last[i][k] - history[n - 2][i][k]);
+            p[k] += match order {
+                1 => d1,
+                // x(n+1) - x(n) = 2 (x(n) - x(n-1)) - (x(n-1) - x(n-2))
+                _ => 2.0 * d1 - wrapped(history[n - 2][i][k] - history[n - 3][i][k]),
+            };
+        }
+    }
+    Some(next)
+}
+
+/// Return the max distance in Å between atoms in scaled positions `a` and
+/// `b` in cell with `lattice` vectors. Return None if the number of atoms
+/// differs.
+pub fn max_deviation(a: &[[f64; 3]], b: &[[f64; 3]], lattice: &[[f64; 3]; 3]) -> Option<f64> {
+    if a.len() != b.len() {
+        return None;
+    }
+    let dmax = a
+        .iter()
+        .zip(b)
+        .map(|(pa, pb)| {
+            let d: [f64; 3] = std::array::from_fn(|k| wrapped(pa[k] - pb[k]));
+            let cart: [f64; 3] = std::array::from_fn(|k| (0..3).map(|j| d[j] * lattice[j][k]).sum());
+            cart.iter().map(|x| x * x).sum::<f64>().sqrt()
+        })
+        .fold(0.0, f64::max);
+    Some(dmax)
+}
+
+fn format_positions(positions: &[[f64; 3]]) -> String {
+    positions
+        .iter()
+        .map(|[x, y, z]| format!("{:19.16} {:19.16} {:19.16}\n", x, y, z))
+        .collect()
+}
+
+/// The speculative interaction running in background
+struct Pending {
+    positions: Vec<[f64; 3]>,
+    read_pattern: String,
+    handle: tokio::task::JoinHandle<Result<String>>,
+}
+
+/// The state of speculative pre-computation for one engine
+pub struct Speculator {
+    opts: SpeculateOptions,
+    // the working directory of engine
+    dir: PathBuf,
+    // the scaled positions of recent steps, the oldest first
+    history: VecDeque<Vec<[f64; 3]>>,
+    pending: Option<Pending>,
+    // the number of requests answered by speculation, and the total
+    hits: usize,
+    total: usize,
+}
+
+impl Speculator {
+    pub fn new(opts: SpeculateOptions, dir: &Path) -> Self {
+        Self {
+            opts,
+            dir: dir.to_owned(),
+            history: VecDeque::new(),
+            pending: None,
+            hits: 0,
+            total: 0,
+        }
+    }
+
+    fn lattice(&self) -> Result<[[f64; 3]; 3]> {
+        let s = gut::fs::read_file(self.dir.join("POSCAR"))?;
+        let lines: Vec<_> = s.lines().collect();
+        crate::vasp::poscar::read_lattice_vectors(&lines)
+    }
+
+    /// Wait for the speculative interaction if any. Return its output if
+    /// it was run for a structure matching `input` within tolerance, with
+    /// the same `read_pattern`. The output is for the extrapolated
+    /// positions, not those in `input`. Otherwise the output is discarded,
+    /// as VASP must finish it before reading the next input anyway.
+    pub async fn take_matched(&mut self, input: &str, read_pattern: &str) -> Option<String> {
+        let pending = self.pending.take()?;
+        self.total += 1;
+        let deviation = crate::vasp::guard::parse_scaled_positions(input)
+            .ok()
+            .zip(self.lattice().ok())
+            .and_then(|(positions, lattice)| max_deviation(&positions, &pending.positions, &lattice));
+        let matched = pending.read_pattern == read_pattern && deviation.is_some_and(|d| d <= self.opts.tolerance);
+        let out = match pending.handle.await {
+            Ok(Ok(out)) => out,
+            Ok(Err(e)) => {
+                warn!("speculative interaction failed: {:?}", e);
+                return None;
+            }
+            Err(e) => {
+                warn!("speculative interaction aborted: {:?}", e);
+                return None;
+            }
+        };
+        if matched {
+            self.hits += 1;
+            info!(
+                "speculation hit ({}/{}): deviation {:.4} Å",
+                self.hits,
+                self.total,
+                deviation.unwrap_or_default()
+            );
+            Some(out)
+        } else {
+            info!("speculation missed ({}/{}): deviation {:?} Å", self.hits, self.total, deviation);
+            None
+        }
+    }
+
+    /// Wait for the speculative interaction if any and discard its output,
+    /// before the engine is used otherwise, e.g. for batch interactions.
+    pub async fn settle(&mut self) {
+        if let Some(pending) = self.pending.take() {
+            debug!("discard speculative interaction");
+            pending.handle.await.ok();
+        }
+        self.history.clear();
+    }
+
+    /// Record positions in `input` of an interaction done. The history is
+    /// reset if `input` has no positions, e.g. the initial one.
+    pub fn record(&mut self, input: &str) {
+        match crate::vasp::guard::parse_scaled_positions(input) {
+            Ok(positions) if !positions.is_empty() => {
+                self.history.push_back(positions);
+                while self.history.len() > self.opts.order + 1 {
+                    self.history.pop_front();
+                }
+            }
+            _ => self.history.clear(),
+        }
+    }
+
+    /// Run the extrapolated next structure in background using `task`, if
+    /// enough steps recorded and it passes `guard`.
+    pub fn start(&mut self, task: &TaskClient, read_pattern: &str, guard: Option<&GeometryGuard>) {
+        let history: Vec<_> = self.history.iter().cloned().collect();
+        let Some(positions) = extrapolate(&history, self.opts.order) else {
+            return;
+        };
+        let input = format_positions(&positions);
+        if let Some(guard) = guard {
+            if let Err(e) = guard.check_input_in_dir(&self.dir, &input) {
+                debug!("skip speculation for unphysical geometry: {:?}", e);
+                return;
+            }
+        }
+        debug!("start speculative interaction");
+        let mut task = task.clone();
+        let pattern = read_pattern.to_owned();
+        let handle = tokio::spawn(async move { task.interact(&input, &pattern).await });
+        self.pending = Some(Pending {
+            positions,
+            read_pattern: read_pattern.into(),
+            handle,
+        });
+    }
+}
+// 62533352 ends here
+
+// [[file:../vasp-tools.note::7701568d][7701568d]]
+#[test]
+fn test_speculate_extrapolate() {
+    let history = vec![
+        vec![[0.10, 0.5, 0.98]],
+        vec![[0.12, 0.5, 0.99]],
+        vec![[0.15, 0.5, 0.00]],
+    ];
+    // across the cell boundary
+    let next = extrapolate(&history, 1).unwrap();
+    assert_relative_eq!(next[0][0], 0.18, epsilon = 1e-12);
+    assert_relative_eq!(next[0][2], 0.01, epsilon = 1e-12);
+    let next = extrapolate(&history, 2).unwrap();
+    assert_relative_eq!(next[0][0], 0.19, epsilon = 1e-12);
+    assert!(extrapolate(&history[1..], 2).is_none());
+
+    let lattice = [[10.0, 0.0, 0.0], [0.0, 10.0, 0.0], [0.0, 0.0, 10.0]];
+    let d = max_deviation(&[[0.0, 0.0, 0.999]], &[[0.0, 0.0, 0.0]], &lattice).unwrap();
+    assert_relative_eq!(d, 0.01, epsilon = 1e-9);
+    assert!(max_deviation(&[[0.0; 3]], &[], &lattice).is_none());
+
+    let opts: SpeculateOptions = "quadratic".parse().unwrap();
+    assert_eq!(opts.order, 2);
+    assert!("cubic".parse::<SpeculateOptions>().is_err());
+}
+
+#[tokio::test]
+async fn test_speculator_take_matched() -> Result<()> {
+    let tdir = tempfile::tempdir()?;
+    let dir = tdir.path();
+    let poscar = "test\n1.0\n10.0 0.0 0.0\n0.0 10.0 0.0\n0.0 0.0 10.0\nH\n1\nDirect\n0.1 0.5 0.5\n";
+    gut::fs::write_to_file(dir.join("POSCAR"), poscar)?;
+    // `cat` echoes the speculative input as its output
+    let (mut server, client) = crate::interactive::new_interactive_task_in("cat".as_ref(), dir, &Default::default())?;
+    tokio::spawn(async move { server.run_and_serve().await });
+
+    let mut speculator = Speculator::new(SpeculateOptions::default(), dir);
+    // nothing to take before any speculation
+    assert!(speculator.take_matched("0.14 0.5 0.5\n", ".").await.is_none());
+    speculator.record("0.10 0.5 0.5\n");
+    speculator.record("0.12 0.5 0.5\n");
+    // hit: deviates 0.002 Å from the extrapolated position, and the output
+    // is for the extrapolated position, not the requested one
+    speculator.start(&client, ".", None);
+    let out = speculator.take_matched("0.1402 0.5 0.5\n", ".").await.expect("speculation hit");
+    let positions = crate::vasp::guard::parse_scaled_positions(&out)?;
+    assert_relative_eq!(positions[0][0], 0.14, epsilon = 1e-12);
+    assert!((positions[0][0] - 0.1402).abs() > 1e-5);
+    // miss: too far away, or with different read-pattern
+    speculator.start(&client, ".", None);
+    assert!(speculator.take_matched("0.20 0.5 0.5\n", ".").await.is_none());
+    speculator.start(&client, ".", None);
+    assert!(speculator.take_matched("0.14 0.5 0.5\n", "TOTEN").await.is_none());
+    assert_eq!((speculator.hits, speculator.total), (1, 3));
+    // no speculation after history reset
+    speculator.settle().await;
+    speculator.start(&client, ".", None);
+    assert!(speculator.pending.is_none());
+
+    Ok(())
+}
+// 7701568d ends here
@@ -0,0 +1,178 @@
+// [[file:../vasp-tools.note::*docs][docs:1]]
+//! Running the socket server as systemd service: socket activation,
+//! readiness/watchdog notifications, and unit file generation
+// docs:1 ends here
+
+// [[file:../vasp-tools.note::0333ede6][0333ede6]]
+use super::*;
+
+use std::os::unix::io::RawFd;
+// 0333ede6 ends here
+
+// [[file:../vasp-tools.note::1a549fe8][1a549fe8]]
+/// The first file descriptor passed by systemd (SD_LISTEN_FDS_START)
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Return the file descriptors passed for process `pid` from values of env
+/// vars LISTEN_PID and LISTEN_FDS.
+fn parse_listen_fds(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> Result<Vec<RawFd>> {
+    let (Some(listen_pid), Some(listen_fds)) = (listen_pid, listen_fds) else {
+        return Ok(vec![]);
+    };
+    let listen_pid: u32 = listen_pid.trim().parse().context("invalid LISTEN_PID")?;
+    // the sockets are passed for another process, e.g. our parent
+    if listen_pid != pid {
+        return Ok(vec![]);
+    }
+    let n: RawFd = listen_fds.trim().parse().context("invalid LISTEN_FDS")?;
+    Ok((LISTEN_FDS_START..LISTEN_FDS_START + n).collect())
+}
+
+/// Return the listening sockets passed by systemd socket activation as in
+/// `sd_listen_fds(1)`: env vars are unset so that child processes do not
+/// inherit them, and the sockets are marked close-on-exec.
+pub fn listen_fds() -> Result<Vec<RawFd>> {
+    let listen_pid = std::env::var("LISTEN_PID").ok();
+    let listen_fds = std::env::var("LISTEN_FDS").ok();
+    let fds = parse_listen_fds(listen_pid.as_deref(), listen_fds.as_deref(), std::process::id())?;
+    for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(var);
+    }
+    for &fd in &fds {
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } != 0 {
+            bail!("set close-on-exec for fd {} failed: {:?}", fd, std::io::Error::last_os_error());
+        }
+    }
+    Ok(fds)
+}
+
+/// Send `state` (e.g. "READY=1") to systemd as in `sd_notify`. Return false
+/// if not running under systemd (NOTIFY_SOCKET not set).
+pub fn notify(state: &str) -> Result<bool> {
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return Ok(false);
+    };
+    let path = path.to_string_lossy().to_string();
+    let addr = match path.strip_prefix('@') {
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            SocketAddr::from_abstract_name(name)?
+        }
+        None => SocketAddr::from_pathname(&path)?,
+    };
+    let sock = UnixDatagram::unbound()?;
+    sock.send_to_addr(state.as_bytes(), &addr)
+        .with_context(|| format!("notify systemd at {:?}", path))?;
+    Ok(true)
+}
+
+/// Tell systemd that the service is ready. Failure is not fatal.
+pub fn notify_ready() {
+    match notify("READY=1") {
+        Ok(true) => info!("notified systemd: ready"),
+        Ok(false) => {}
+        Err(e) => warn!("notify systemd failed: {:?}", e),
+    }
+}
+
+/// Return the watchdog interval configured in systemd (WatchdogSec).
+pub fn watchdog_interval() -> Option<std::time::Duration> {
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    if let Some(pid) = std::env::var("WATCHDOG_PID").ok().and_then(|x| x.parse::<u32>().ok()) {
+        if pid != std::process::id() {
+            return None;
+        }
+    }
+    Some(std::time::Duration::from_micros(usec))
+}
+
+/// Keep sending watchdog notifications at half of the interval configured
+/// in systemd. Return immediately if watchdog is not enabled.
+pub async fn keep_watchdog_alive() {
+    let Some(interval) = watchdog_interval() else {
+        return;
+    };
+    info!("systemd watchdog enabled: {:?}", interval);
+    loop {
+        tokio::time::sleep(interval / 2).await;
+        if let Err(e) = notify("WATCHDOG=1") {
+            warn!("watchdog notification failed: {:?}", e);
+        }
+    }
+}
+// 1a549fe8 ends here
+
+// [[file:../vasp-tools.note::9927c3fe][9927c3fe]]
+/// Options for generating systemd unit files
+#[derive(Debug, Clone)]
+pub struct UnitOptions {
+    /// The name of service, used in unit names and server registry
+    pub name: String,
+    /// The working directory of VASP
+    pub workdir: PathBuf,
+    /// The path to run-vasp program
+    pub run_vasp: PathBuf,
+    /// The command for running VASP
+    pub program: String,
+    /// The socket file. The default is in user runtime directory.
+    pub socket_file: Option<PathBuf>,
+    /// The watchdog timeout in seconds
+    pub watchdog: Option<u64>,
+}
+
+/// Generate systemd unit files for interactive VASP service with socket
+/// activation. Return (file name, content) of socket and service units.
+pub fn format_unit_files(opts: &UnitOptions) -> [(String, String); 2] {
+    let unit = format!("vasp-{}", opts.name);
+    let socket_file = match &opts.socket_file {
+        Some(f) => f.display().to_string(),
+        None => format!("%t/vasp-tools/{}.sock", opts.name),
+    };
+    let socket = format!(
+        "[Unit]\nDescription=Interactive VASP server socket ({name})\n\n\
+         [Socket]\nListenStream={socket_file}\nSocketMode=0600\nRemoveOnStop=yes\n\n\
+         [Install]\nWantedBy=sockets.target\n",
+        name = opts.name,
+    );
+    let watchdog = opts.watchdog.map(|t| format!("WatchdogSec={}\n", t)).unwrap_or_default();
+    let service = format!(
+        "[Unit]\nDescription=Interactive VASP server ({name})\nRequires={unit}.socket\nAfter={unit}.socket\n\n\
+         [Service]\nType=notify\nNotifyAccess=main\nWorkingDirectory={workdir}\n\
+         ExecStart={run_vasp} --interactive --name {name} -x \"{program}\"\n{watchdog}Restart=on-failure\n\n\
+         [Install]\nWantedBy=default.target\n",
+        name = opts.name,
+        workdir = opts.workdir.display(),
+        run_vasp = opts.run_vasp.display(),
+        program = opts.program,
+    );
+    [(format!("{}.socket", unit), socket), (format!("{}.service", unit), service)]
+}
+
+#[test]
+fn test_systemd() -> Result<()> {
+    assert!(parse_listen_fds(None, None, 100)?.is_empty());
+    assert!(parse_listen_fds(Some("99"), Some("1"), 100)?.is_empty());
+    assert_eq!(parse_listen_fds(Some("100"), Some("2"), 100)?, vec![3, 4]);
+    assert!(parse_listen_fds(Some("x"), Some("1"), 100).is_err());
+
+    let opts = UnitOptions {
+        name: "h2o".into(),
+        workdir: "/data/h2o".into(),
+        run_vasp: "/usr/local/bin/run-vasp".into(),
+        program: "mpirun vasp_std".into(),
+        socket_file: None,
+        watchdog: Some(60),
+    };
+    let [(fsocket, socket), (fservice, service)] = format_unit_files(&opts);
+    assert_eq!(fsocket, "vasp-h2o.socket");
+    assert_eq!(fservice, "vasp-h2o.service");
+    assert!(socket.contains("ListenStream=%t/vasp-tools/h2o.sock\n"), "{}", socket);
+    assert!(service.contains("Type=notify\n"), "{}", service);
+    assert!(service.contains("WatchdogSec=60\n"));
+    assert!(service.contains("ExecStart=/usr/local/bin/run-vasp --interactive --name h2o -x \"mpirun vasp_std\"\n"));
+
+    Ok(())
+}
+// 9927c3fe ends here
@@ -0,0 +1,110 @@
+// [[file:../vasp-tools.note::*docs][docs:1]]
+//! Write evaluated structures with computed results as extxyz trajectory
+// docs:1 ends here
+
+// [[file:../vasp-tools.note::95e4a249][95e4a249]]
+use super::*;
+
+use crate::bbm::Properties;
+use gosh::gchemol::Molecule;
+// 95e4a249 ends here
+
+// [[file:../vasp-tools.note::5acf0693][5acf0693]]
+/// Format `mol` with computed `props` as one frame of extended XYZ format.
+/// Energy is in eV, and forces in eV/Å. The stress is converted from VASP
+/// (kB, positive for compressive stress) to eV/Å^3 following ASE
+/// convention.
+pub fn format_extxyz_frame(mol: &Molecule, props: &Properties) -> Result<String> {
+    let energy = props.mp.get_energy().context("no energy")?;
+    let forces = props.mp.get_forces().context("no forces")?;
+    let natoms = mol.natoms();
+    ensure!(
+        forces.len() == natoms,
+        "expect forces of {} atoms, got {}",
+        natoms,
+        forces.len()
+    );
+
+    let mut comment = String::new();
+    if let Some(lat) = mol.get_lattice() {
+        let vectors: Vec<_> = lat.vectors().iter().flatten().map(|x| format!("{:.8}", x)).collect();
+        comment += &format!("Lattice=\"{}\" ", vectors.join(" "));
+    }
+    comment += "Properties=species:S:1:pos:R:3:forces:R:3";
+    comment += &format!(" energy={:.8}", energy);
+    if let Some(stress) = props.stress {
+        let [xx, yy, zz, xy, yz, zx] = stress.map(|x| -crate::units::kbar_to_ev_per_a3(x));
+        let stress: Vec<_> = [xx, xy, zx, xy, yy, yz, zx, yz, zz]
+            .iter()
+            .map(|x| format!("{:.8}", x))
+            .collect();
+        comment += &format!(" stress=\"{}\"", stress.join(" "));
+    }
+    let pbc = if mol.get_lattice().is_some() { "T T T" } else { "F F F" };
+    comment += &format!(" pbc=\"{}\"", pbc);
+
+    let mut txt = format!("{}\n{}\n", natoms, comment);
+    for ((symbol, [x, y, z]), [fx, fy, fz]) in mol.symbols().zip(mol.positions()).zip(forces) {
+        txt += &format!(
+            "{:3} {:15.8} {:15.8} {:15.8} {:15.8} {:15.8} {:15.8}\n",
+            symbol, x, y, z, fx, fy, fz
+        );
+    }
+    Ok(txt)
+}
+
+/// Append `mol` with computed `props` as a new frame into extxyz trajectory
+/// file `f`, which will be created if not exists.
+pub fn append_extxyz(f: &Path, mol: &Molecule, props: &Properties) -> Result<()> {
+    use std::io::Write;
+
+    let txt = format_extxyz_frame(mol, props)?;
+    let mut fp = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(f)
+        .with_context(|| format!("open trajectory file {:?}", f))?;
+    fp.write_all(txt.as_bytes())?;
+    debug!("appended frame of {} atoms into {:?}", mol.natoms(), f);
+
+    Ok(())
+}
+// 5acf0693 ends here
+
+// [[file:../vasp-tools.note::eda0c08a][eda0c08a]]
+#[test]
+fn test_extxyz_frame() -> Result<()> {
+    use gosh::gchemol::{Atom, Lattice};
+    use gosh::model::ModelProperties;
+
+    let atoms = vec![Atom::new("H", [0.0, 0.0, 0.0]), Atom::new("O", [1.0, 0.0, 0.0])];
+    let mut mol = Molecule::from_atoms(atoms);
+    let mut mp = ModelProperties::default();
+    mp.set_energy(-1.5);
+    mp.set_forces(vec![[0.1, 0.0, 0.0], [-0.1, 0.0, 0.0]]);
+    let mut props: Properties = mp.into();
+
+    let s = format_extxyz_frame(&mol, &props)?;
+    let lines: Vec<_> = s.lines().collect();
+    assert_eq!(lines.len(), 4);
+    assert_eq!(lines[0], "2");
+    assert!(lines[1].contains("energy=-1.50000000"));
+    assert!(lines[1].contains("pbc=\"F F F\""));
+    assert!(!lines[1].contains("Lattice"));
+    assert!(lines[3].starts_with("O "));
+
+    mol.set_lattice(Lattice::new([[5.0, 0.0, 0.0], [0.0, 5.0, 0.0], [0.0, 0.0, 5.0]]));
+    props.stress = Some([crate::units::EV_PER_A3_IN_KB, 0.0, 0.0, 0.0, 0.0, 0.0]);
+    let s = format_extxyz_frame(&mol, &props)?;
+    assert!(s.contains("Lattice=\"5.00000000 0.00000000"));
+    assert!(s.contains("stress=\"-1.00000000 "));
+
+    let tdir = tempfile::tempdir()?;
+    let f = tdir.path().join("traj.xyz");
+    append_extxyz(&f, &mol, &props)?;
+    append_extxyz(&f, &mol, &props)?;
+    assert_eq!(gut::fs::read_file(&f)?.lines().count(), 8);
+
+    Ok(())
+}
+// eda0c08a ends here
@@ -0,0 +1,83 @@
+// [[file:../vasp-tools.note::*docs][docs:1]]
+//! Unit conversions between VASP units (eV, Å, kB) and atomic units
+//! (Hartree, Bohr) or GPa, using CODATA 2014 constants.
+// docs:1 ends here
+
+// [[file:../vasp-tools.note::24f3bcf2][24f3bcf2]]
+/// 1 Bohr in Å
+pub const BOHR: f64 = 0.52917721067;
+/// 1 Hartree in eV
+pub const HARTREE: f64 = 27.21138602;
+/// 1 eV/Å^3 in kB
+pub const EV_PER_A3_IN_KB: f64 = 1602.1766208;
+/// 1 kB in GPa
+pub const KB_IN_GPA: f64 = 0.1;
+/// 1 eV/Å^2 in J/m^2
+pub const EV_PER_A2_IN_J_PER_M2: f64 = 16.021766208;
+
+/// Convert energy from eV to Hartree
+pub fn ev_to_hartree(e: f64) -> f64 {
+    e / HARTREE
+}
+
+/// Convert energy from Hartree to eV
+pub fn hartree_to_ev(e: f64) -> f64 {
+    e * HARTREE
+}
+
+/// Convert length from Å to Bohr
+pub fn angstrom_to_bohr(x: f64) -> f64 {
+    x / BOHR
+}
+
+/// Convert length from Bohr to Å
+pub fn bohr_to_angstrom(x: f64) -> f64 {
+    x * BOHR
+}
+
+/// Convert force from eV/Å to Hartree/Bohr
+pub fn force_ev_to_au(f: f64) -> f64 {
+    f * BOHR / HARTREE
+}
+
+/// Convert force from Hartree/Bohr to eV/Å
+pub fn force_au_to_ev(f: f64) -> f64 {
+    f * HARTREE / BOHR
+}
+
+/// Convert pressure from kB (as in VASP OUTCAR) to GPa
+pub fn kbar_to_gpa(p: f64) -> f64 {
+    p * KB_IN_GPA
+}
+
+/// Convert pressure from GPa to kB
+pub fn gpa_to_kbar(p: f64) -> f64 {
+    p / KB_IN_GPA
+}
+
+/// Convert pressure from kB to eV/Å^3
+pub fn kbar_to_ev_per_a3(p: f64) -> f64 {
+    p / EV_PER_A3_IN_KB
+}
+
+/// Convert pressure from eV/Å^3 to kB
+pub fn ev_per_a3_to_kbar(p: f64) -> f64 {
+    p * EV_PER_A3_IN_KB
+}
+// 24f3bcf2 ends here
+
+// [[file:../vasp-tools.note::86928962][86928962]]
+#[test]
+fn test_units() {
+    assert_relative_eq!(hartree_to_ev(ev_to_hartree(1.5)), 1.5, epsilon = 1e-12);
+    assert_relative_eq!(bohr_to_angstrom(1.0), 0.52917721067, epsilon = 1e-12);
+    assert_relative_eq!(angstrom_to_bohr(1.0), 1.8897261254578281, epsilon = 1e-9);
+    assert_relative_eq!(force_au_to_ev(force_ev_to_au(0.3)), 0.3, epsilon = 1e-12);
+    assert_relative_eq!(force_au_to_ev(1.0), 51.42206707191191, epsilon = 1e-6);
+    assert_relative_eq!(kbar_to_gpa(10.0), 1.0, epsilon = 1e-12);
+    assert_relative_eq!(gpa_to_kbar(1.0), 10.0, epsilon = 1e-12);
+    // 1 eV/Å^3 = 160.21766208 GPa
+    assert_relative_eq!(kbar_to_gpa(ev_per_a3_to_kbar(1.0)), 160.21766208, epsilon = 1e-8);
+    assert_relative_eq!(kbar_to_ev_per_a3(ev_per_a3_to_kbar(2.0)), 2.0, epsilon = 1e-12);
+}
+// 86928962 ends here
@@ -0,0 +1,99 @@
+// [[file:../vasp-tools.note::*docs][docs:1]]
+//! Small utilities shared by different commands
+// docs:1 ends here
+
+// [[file:../vasp-tools.note::7a389942][7a389942]]
+use super::*;
+
+use std::time::Duration;
+// 7a389942 ends here
+
+// [[file:../vasp-tools.note::ad66b133][ad66b133]]
+/// Wait until file `f` available for max time of `timeout` in seconds. The
+/// file system events are watched using inotify, and it will fall back to
+/// polling if inotify is not available (e.g. on some network file systems).
+pub async fn wait_file(f: &Path, timeout: f64) -> Result<()> {
+    use notify::{RecursiveMode, Watcher};
+
+    if f.exists() {
+        return Ok(());
+    }
+
+    let dir = match f.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d.to_owned(),
+        _ => PathBuf::from("."),
+    };
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+        if res.is_ok() {
+            let _ = tx.send(());
+        }
+    });
+    let mut watcher = match watcher {
+        Ok(w) => w,
+        Err(e) => {
+            debug!("inotify not available: {:?}", e);
+            return poll_file(f, timeout).await;
+        }
+    };
+    if let Err(e) = watcher.watch(&dir, RecursiveMode::NonRecursive) {
+        debug!("cannot watch {:?}: {:?}", dir, e);
+        return poll_file(f, timeout).await;
+    }
+
+    let wait = async {
+        // NOTE: the file could be created before the watcher is ready
+        while !f.exists() {
+            if rx.recv().await.is_none() {
+                break;
+            }
+        }
+    };
+    let _ = tokio::time::timeout(Duration::from_secs_f64(timeout), wait).await;
+    if !f.exists() {
+        bail!("file {:?} doest exist for {} seconds", f, timeout);
+    }
+
+    Ok(())
+}
+
+/// Wait for file `f` by polling every 0.1 second.
+async fn poll_file(f: &Path, timeout: f64) -> Result<()> {
+    let interval = 0.1;
+    let mut t = 0.0;
+    loop {
+        if f.exists() {
+            trace!("Elapsed time during waiting: {:.2} seconds ", t);
+            return Ok(());
+        }
+        t += interval;
+        tokio::time::sleep(Duration::from_secs_f64(interval)).await;
+
+        if t > timeout {
+            bail!("file {:?} doest exist for {} seconds", f, timeout);
+        }
+    }
+}
+
+/// Blocking version of `wait_file` for use outside async context.
+pub fn wait_file_blocking(f: &Path, timeout: f64) -> Result<()> {
+    let rt = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+    rt.block_on(wait_file(f, timeout))
+}
+
+#[tokio::test]
+async fn test_wait_file() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let f = dir.path().join("OUTCAR");
+    assert!(wait_file(&f, 0.2).await.is_err());
+
+    let f_ = f.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_secs_f64(0.1)).await;
+        gut::fs::write_to_file(&f_, "").unwrap();
+    });
+    wait_file(&f, 2.0).await?;
+
+    Ok(())
+}
+// ad66b133 ends here
@@ -8,10 +8,40 @@ use super::*;
 
 // [[file:../vasp-tools.note::*mods][mods:1]]
 mod freq;
+pub mod archive;
+pub mod batch;
+pub mod clean;
+pub mod compare;
+pub mod converge;
+pub mod crash;
+pub mod dataset;
+pub mod deepmd;
+pub mod defect;
+pub mod eads;
+pub mod eos;
+pub mod forces;
+pub mod grep;
+pub mod guard;
+pub mod magnetization;
+pub mod neighbors;
+pub mod profile;
+pub mod provenance;
+pub mod report;
+pub mod restart;
+pub mod results;
+pub mod scratch;
+pub mod snapshot;
+pub mod surface;
+pub mod symmetry;
+pub mod template;
+pub mod vasprun;
+pub mod vibration;
+pub mod watch;
 // mods:1 ends here
 
 // [[file:../vasp-tools.note::*pub][pub:1]]
 pub use freq::VaspOutcar;
+pub use freq::{format_vib_modes, select_mode_by_index, select_modes_in_range, VibMode};
 // pub:1 ends here
 
 // [[file:../vasp-tools.note::*update params][update params:1]]
@@ -58,6 +88,42 @@ pub mod incar {
         Ok(txt)
     }
 
+    /// Parse tags in INCAR file `path`. The tag names are in upper case, and
+    /// the values are with comments and redundant spaces removed.
+    pub fn parse_tags(path: &Path) -> Result<std::collections::BTreeMap<String, String>> {
+        let bytes = std::fs::read(path).with_context(|| format!("read {:?} file failure", path))?;
+        Ok(parse_tags_from_str(&String::from_utf8_lossy(&bytes)))
+    }
+
+    fn parse_tags_from_str(s: &str) -> std::collections::BTreeMap<String, String> {
+        let mut tags = std::collections::BTreeMap::new();
+        for line in s.lines() {
+            // remove comments
+            let line = line.split(|c| c == '#' || c == '!').next().unwrap_or("");
+            // multiple tags could be in one line separated by semicolon
+            for item in line.split(';') {
+                if let Some((tag, value)) = item.split_once('=') {
+                    let tag = tag.trim().to_uppercase();
+                    if !tag.is_empty() {
+                        tags.insert(tag, value.split_whitespace().collect::<Vec<_>>().join(" "));
+                    }
+                }
+            }
+        }
+        tags
+    }
+
+    #[test]
+    fn test_parse_incar_tags() {
+        let s = "SYSTEM = test run\n  encut=400 # cutoff\nISPIN = 2; MAGMOM = 2*1.0\n! NSW = 100\n";
+        let tags = parse_tags_from_str(s);
+        assert_eq!(tags.len(), 4);
+        assert_eq!(tags["SYSTEM"], "test run");
+        assert_eq!(tags["ENCUT"], "400");
+        assert_eq!(tags["MAGMOM"], "2*1.0");
+        assert!(!tags.contains_key("NSW"));
+    }
+
     #[test]
     #[ignore]
     fn test_update_incar() -> Result<()> {
@@ -79,20 +145,39 @@ pub mod incar {
 // update params:1 ends here
 
 // [[file:../vasp-tools.note::57803ca9][57803ca9]]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub enum VaspTask {
     Interactive,
     SinglePoint,
     Frequency,
 }
 
+impl std::str::FromStr for VaspTask {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().replace('_', "-").as_str() {
+            "interactive" => Ok(Self::Interactive),
+            "single-point" => Ok(Self::SinglePoint),
+            "frequency" => Ok(Self::Frequency),
+            _ => bail!("unsupported VASP task: {:?}", s),
+        }
+    }
+}
+
 /// Update INCAR file in current directory for BBM calculation
 pub fn update_incar_for_bbm(task: &VaspTask) -> Result<()> {
-    debug!("Update INCAR for VASP calculation: task = {:?}", task);
+    update_incar_in_dir(".".as_ref(), task)
+}
 
+/// Update INCAR file in directory `dir` for VASP calculation of `task`.
+pub fn update_incar_in_dir(dir: &Path, task: &VaspTask) -> Result<()> {
+    debug!("Update INCAR in {:?} for VASP calculation: task = {:?}", dir, task);
+
+    let incar = dir.join("INCAR");
     let mandatory_params = task.mandatory_params();
-    let updated_incar = crate::vasp::incar::update_with_mandatory_params("INCAR".as_ref(), &mandatory_params)?;
-    gut::fs::write_to_file("INCAR", &updated_incar)?;
+    let updated_incar = crate::vasp::incar::update_with_mandatory_params(&incar, &mandatory_params)?;
+    gut::fs::write_to_file(&incar, &updated_incar)?;
 
     Ok(())
 }
@@ -164,6 +249,121 @@ pub mod poscar {
         Ok(positions)
     }
 
+    pub(crate) use vasp_parsers::poscar::{locate_positions, read_lattice_vectors};
+
+    /// Read ionic velocities (Cartesian, in Å/fs) from the optional block
+    /// after atomic positions in POSCAR/CONTCAR text `s`. Return None if no
+    /// velocities found.
+    pub fn read_velocities(s: &str) -> Result<Option<Vec<[f64; 3]>>> {
+        let lines: Vec<_> = s.lines().collect();
+        let (i, natoms) = locate_positions(&lines)?;
+        let mut rest = lines[i + natoms..].iter().copied().skip_while(|l| l.trim().is_empty()).peekable();
+        // VASP writes velocities in Cartesian coordinates by default
+        let mut direct = false;
+        match rest.peek() {
+            None => return Ok(None),
+            Some(l) if l.trim_start().to_uppercase().starts_with("LATTICE") => {
+                bail!("lattice velocities in POSCAR are not supported")
+            }
+            Some(l) if l.trim_start().to_uppercase().starts_with('D') => {
+                direct = true;
+                rest.next();
+            }
+            Some(l) if l.trim_start().to_uppercase().starts_with(['C', 'K']) => {
+                rest.next();
+            }
+            _ => {}
+        }
+        let mut velocities: Vec<[f64; 3]> = rest
+            .take(natoms)
+            .map(|line| {
+                let v: Vec<f64> = line
+                    .split_whitespace()
+                    .take(3)
+                    .map(|x| x.parse().with_context(|| format!("invalid velocity: {:?}", line)))
+                    .collect::<Result<_>>()?;
+                ensure!(v.len() == 3, "invalid velocity: {:?}", line);
+                Ok([v[0], v[1], v[2]])
+            })
+            .collect::<Result<_>>()?;
+        ensure!(
+            velocities.len() == natoms,
+            "expect velocities of {} atoms, got {}",
+            natoms,
+            velocities.len()
+        );
+        if direct {
+            let [va, vb, vc] = read_lattice_vectors(&lines)?;
+            for v in velocities.iter_mut() {
+                let f = *v;
+                for k in 0..3 {
+                    v[k] = f[0] * va[k] + f[1] * vb[k] + f[2] * vc[k];
+                }
+            }
+        }
+        Ok(Some(velocities))
+    }
+
+    /// Replace the velocities block in POSCAR text `s` with `velocities`
+    /// (Cartesian, in Å/fs), for restarting MD with consistent initial
+    /// velocities.
+    pub fn with_velocities(s: &str, velocities: &[[f64; 3]]) -> Result<String> {
+        let lines: Vec<_> = s.lines().collect();
+        let (i, natoms) = locate_positions(&lines)?;
+        ensure!(
+            velocities.len() == natoms,
+            "expect velocities of {} atoms, got {}",
+            natoms,
+            velocities.len()
+        );
+        let mut txt = lines[..i + natoms].join("\n");
+        txt += "\n\nCartesian\n";
+        for [x, y, z] in velocities {
+            txt += &format!("{:16.8E} {:16.8E} {:16.8E}\n", x, y, z);
+        }
+        Ok(txt)
+    }
+
+    #[test]
+    fn test_poscar_velocities() -> Result<()> {
+        let poscar = gut::fs::read_file("./tests/files/live-vasp/POSCAR")?;
+        assert_eq!(read_velocities(&poscar)?, None);
+
+        let velocities: Vec<_> = (0..25).map(|i| [i as f64 * 0.001, 0.0, -0.002]).collect();
+        let s = with_velocities(&poscar, &velocities)?;
+        let v = read_velocities(&s)?.unwrap();
+        assert_eq!(v.len(), 25);
+        assert_eq!(v[3], velocities[3]);
+        // replace existing velocities
+        let s = with_velocities(&s, &vec![[0.0; 3]; 25])?;
+        assert_eq!(read_velocities(&s)?.unwrap()[3], [0.0; 3]);
+        assert!(with_velocities(&s, &velocities[..3]).is_err());
+
+        Ok(())
+    }
+
+    /// Replace atomic positions in POSCAR text `s` with scaled `positions`
+    /// (one atom per line, as the input of interactive VASP). Selective
+    /// dynamics flags and velocities are kept.
+    pub fn with_scaled_positions(s: &str, positions: &str) -> Result<String> {
+        let mut lines: Vec<_> = s.lines().map(|l| l.to_owned()).collect();
+        let refs: Vec<_> = lines.iter().map(|l| l.as_str()).collect();
+        let (i, natoms) = locate_positions(&refs)?;
+        let positions: Vec<_> = positions.lines().filter(|l| !l.trim().is_empty()).collect();
+        ensure!(
+            positions.len() == natoms,
+            "expect positions of {} atoms, got {}",
+            natoms,
+            positions.len()
+        );
+        lines[i - 1] = "Direct".into();
+        for (line, p) in lines[i..i + natoms].iter_mut().zip(positions) {
+            let flags: Vec<_> = line.split_whitespace().skip(3).collect();
+            *line = format!("{} {}", p.trim_end(), flags.join(" ")).trim_end().to_owned();
+        }
+        Ok(lines.join("\n") + "\n")
+    }
+
     #[test]
     fn test_poscar_positions() -> Result<()> {
         let poscar = "./tests/files/live-vasp/POSCAR";
@@ -171,6 +371,13 @@ pub mod poscar {
         let s = get_scaled_positions_from_poscar(poscar.as_ref())?;
         assert_eq!(s.lines().count(), 25);
 
+        let positions: String = (0..25).map(|i| format!("{:.4} 0.5 0.5\n", i as f64 / 25.0)).collect();
+        let txt = with_scaled_positions(&gut::fs::read_file(poscar)?, &positions)?;
+        let lines: Vec<_> = txt.lines().collect();
+        assert_eq!(lines[8], "Direct");
+        assert_eq!(lines[10], "0.0400 0.5 0.5 T T T");
+        assert!(with_scaled_positions(&txt, "0.0 0.0 0.0\n").is_err());
+
         Ok(())
     }
 }
@@ -181,9 +388,62 @@ pub mod poscar {
 pub mod stopcar {
     use super::*;
 
-    pub fn write(wrk_dir: &Path) -> Result<()> {
-        debug!("Writing STOPCAR ...");
-        gut::fs::write_to_file(wrk_dir.join("STOPCAR"), "LABORT = .TRUE.\n").context("write STOPCAR")?;
+    /// How VASP should stop
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+    pub enum StopMode {
+        /// Stop after current ionic step finished (LSTOP)
+        Soft,
+        /// Stop at the next electronic step immediately (LABORT)
+        Hard,
+    }
+
+    impl std::str::FromStr for StopMode {
+        type Err = Error;
+
+        fn from_str(s: &str) -> Result<Self> {
+            match s.trim().to_lowercase().as_str() {
+                "soft" => Ok(Self::Soft),
+                "hard" => Ok(Self::Hard),
+                _ => bail!("invalid stop mode: {:?}, expect soft or hard", s),
+            }
+        }
+    }
+
+    pub fn write(wrk_dir: &Path, mode: StopMode) -> Result<()> {
+        debug!("Writing STOPCAR ({:?}) ...", mode);
+        let txt = match mode {
+            StopMode::Soft => "LSTOP = .TRUE.\n",
+            StopMode::Hard => "LABORT = .TRUE.\n",
+        };
+        gut::fs::write_to_file(wrk_dir.join("STOPCAR"), txt).context("write STOPCAR")?;
+
+        Ok(())
+    }
+
+    /// Remove STOPCAR left in `wrk_dir`, which could stop next VASP run
+    /// unexpectedly.
+    pub fn remove(wrk_dir: &Path) -> Result<()> {
+        let f = wrk_dir.join("STOPCAR");
+        if f.exists() {
+            debug!("Removing STOPCAR ...");
+            std::fs::remove_file(&f).with_context(|| format!("remove {:?}", f))?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_stopcar() -> Result<()> {
+        let tdir = tempfile::tempdir()?;
+        write(tdir.path(), StopMode::Soft)?;
+        let s = gut::fs::read_file(tdir.path().join("STOPCAR"))?;
+        assert_eq!(s.trim(), "LSTOP = .TRUE.");
+        remove(tdir.path())?;
+        assert!(!tdir.path().join("STOPCAR").exists());
+        // removing again is fine
+        remove(tdir.path())?;
+        assert_eq!("Soft".parse::<StopMode>()?, StopMode::Soft);
+        assert!("abort".parse::<StopMode>().is_err());
 
         Ok(())
     }
@@ -195,11 +455,64 @@ pub mod stopcar {
 pub mod stdin {
     use super::*;
 
-    fn get_scaled_positions_from_poscar_str(s: &str) -> Result<String> {
+    use gosh::gchemol::Molecule;
+
+    /// Summarize `symbols` in the order of VASP POSCAR, e.g. "Ti2 O4"
+    pub(crate) fn summarize_symbols<'a>(symbols: impl IntoIterator<Item = &'a str>) -> String {
+        let mut groups: Vec<(&str, usize)> = vec![];
+        for s in symbols {
+            match groups.last_mut() {
+                Some((last, n)) if *last == s => *n += 1,
+                _ => groups.push((s, 1)),
+            }
+        }
+        let groups: Vec<_> = groups.iter().map(|(s, n)| format!("{}{}", s, n)).collect();
+        groups.join(" ")
+    }
+
+    /// Check that `mol` has the same number of atoms and the same species
+    /// order as `reference`, the structure VASP started with. VASP reads
+    /// positions from stdin without any element info, so re-ordered atoms
+    /// will silently produce garbage results.
+    pub fn check_structure_consistency(mol: &Molecule, reference: &Molecule) -> Result<()> {
+        let symbols: Vec<_> = mol.symbols().collect();
+        let symbols_ref: Vec<_> = reference.symbols().collect();
+        ensure!(
+            symbols.len() == symbols_ref.len(),
+            "structure mismatch: expect {} atoms ({}) as in POSCAR, got {} atoms ({})",
+            symbols_ref.len(),
+            summarize_symbols(symbols_ref.iter().copied()),
+            symbols.len(),
+            summarize_symbols(symbols.iter().copied()),
+        );
+        if let Some(i) = symbols.iter().zip(&symbols_ref).position(|(a, b)| a != b) {
+            bail!(
+                "structure mismatch: atom {} is {}, but {} in POSCAR; expect species order {}, got {}",
+                i + 1,
+                symbols[i],
+                symbols_ref[i],
+                summarize_symbols(symbols_ref.iter().copied()),
+                summarize_symbols(symbols.iter().copied()),
+            );
+        }
+        Ok(())
+    }
+
+    /// Parse structure from POSCAR text `s`, check it against `poscar`
+    /// which VASP started with, and return its scaled positions.
+    pub fn get_scaled_positions_from_poscar_str(s: &str, poscar: &Path) -> Result<String> {
         use gosh::gchemol::prelude::*;
-        use gosh::gchemol::Molecule;
 
-        let frac_coords: String = Molecule::from_str(s, "vasp/input")?
+        let mol = Molecule::from_str(s, "vasp/input")?;
+        let mol_ref = Molecule::from_file(poscar).with_context(|| format!("read {:?}", poscar))?;
+        check_structure_consistency(&mol, &mol_ref)?;
+        format_scaled_positions(&mol)
+    }
+
+    /// Format scaled positions of `mol` as the input of VASP interactive
+    /// calculation.
+    pub fn format_scaled_positions(mol: &gosh::gchemol::Molecule) -> Result<String> {
+        let frac_coords: String = mol
             .get_scaled_positions()
             .ok_or(format_err!("non-periodic structure?"))?
             .map(|[x, y, z]| format!("{:19.16} {:19.16} {:19.16}\n", x, y, z))
@@ -208,10 +521,13 @@ pub mod stdin {
         Ok(frac_coords)
     }
 
-    /// Read scaled positions from current process's standard input
-    pub fn get_scaled_positions_from_stdin() -> Result<String> {
-        let txt = read_txt_from_stdin()?;
-        get_scaled_positions_from_poscar_str(&txt)
+    /// Return the number of atoms and summarized species (e.g. "Ti2 O4") of
+    /// structure in `poscar`.
+    pub fn summarize_poscar(poscar: &Path) -> Result<(usize, String)> {
+        use gosh::gchemol::prelude::*;
+
+        let mol = Molecule::from_file(poscar).with_context(|| format!("read {:?}", poscar))?;
+        Ok((mol.natoms(), summarize_symbols(mol.symbols())))
     }
 
     /// Read text from current process's standard input
@@ -223,6 +539,183 @@ pub mod stdin {
         stdin.read_to_string(&mut buffer)?;
         Ok(buffer)
     }
+
+    /// The header line of versioned stdin protocol, followed by key=value
+    /// fields, e.g. "#vasp-tools-positions v2 mode=update natoms=3". Input
+    /// without this header is a complete POSCAR (v1).
+    pub const POSITIONS_HEADER: &str = "#vasp-tools-positions";
+
+    /// What the client asks VASP to do with the structure from stdin
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum PositionsMode {
+        /// Start VASP with a complete POSCAR
+        Start,
+        /// Update positions of running VASP
+        Update,
+    }
+
+    /// The structure read from stdin in protocol v2
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct PositionsInput {
+        pub mode: PositionsMode,
+        pub natoms: usize,
+        /// The lattice vectors expected by client
+        pub lattice: Option<[[f64; 3]; 3]>,
+        /// A complete POSCAR in `Start` mode, or scaled positions (one
+        /// atom per line) in `Update` mode
+        pub payload: String,
+    }
+
+    /// Format the header line of protocol v2.
+    pub fn format_positions_header(mode: PositionsMode, natoms: usize, lattice: Option<&[[f64; 3]; 3]>) -> String {
+        let mode = match mode {
+            PositionsMode::Start => "start",
+            PositionsMode::Update => "update",
+        };
+        let mut line = format!("{} v2 mode={} natoms={}", POSITIONS_HEADER, mode, natoms);
+        if let Some(lattice) = lattice {
+            let values: Vec<_> = lattice.iter().flatten().map(|x| x.to_string()).collect();
+            line += &format!(" lattice={}", values.join(","));
+        }
+        line + "\n"
+    }
+
+    /// Parse scaled positions of `natoms` atoms, one atom per line. Columns
+    /// after coordinates (e.g. element symbols) are ignored.
+    fn parse_positions_payload(s: &str, natoms: usize) -> Result<String> {
+        let positions = guard::parse_scaled_positions(s)?;
+        ensure!(
+            positions.len() == natoms,
+            "expect positions of {} atoms as in header, got {} lines",
+            natoms,
+            positions.len()
+        );
+        let positions = positions
+            .iter()
+            .map(|[x, y, z]| format!("{:19.16} {:19.16} {:19.16}\n", x, y, z))
+            .collect();
+        Ok(positions)
+    }
+
+    /// Check that `lattice` is the same as `expected` within 1e-4 Å.
+    pub fn check_lattice(lattice: &[[f64; 3]; 3], expected: &[[f64; 3]; 3]) -> Result<()> {
+        let same = lattice.iter().flatten().zip(expected.iter().flatten()).all(|(a, b)| (a - b).abs() < 1e-4);
+        ensure!(
+            same,
+            "lattice mismatch: expect {:?} as in POSCAR, got {:?}; the cell can not be changed in interactive VASP",
+            expected,
+            lattice
+        );
+        Ok(())
+    }
+
+    /// Parse structure text `s` from stdin in protocol v2. Return None if
+    /// no v2 header found, i.e. a complete POSCAR in protocol v1.
+    pub fn parse_positions_input(s: &str) -> Result<Option<PositionsInput>> {
+        let (header, rest) = s.split_once('\n').unwrap_or((s, ""));
+        let mut fields = header.split_whitespace();
+        if fields.next() != Some(POSITIONS_HEADER) {
+            return Ok(None);
+        }
+        let version = fields.next().unwrap_or_default();
+        ensure!(version == "v2", "unsupported stdin protocol version: {:?}", version);
+
+        let (mut mode, mut natoms, mut lattice) = (None, None, None);
+        for field in fields {
+            let (key, value) = field.split_once('=').with_context(|| format!("invalid header field: {:?}", field))?;
+            match key {
+                "mode" => {
+                    mode = match value {
+                        "start" => PositionsMode::Start,
+                        "update" => PositionsMode::Update,
+                        _ => bail!("invalid mode {:?}, expect start or update", value),
+                    }
+                    .into()
+                }
+                "natoms" => natoms = Some(value.parse::<usize>().with_context(|| format!("invalid natoms: {:?}", value))?),
+                "lattice" => {
+                    let v: Vec<f64> = value
+                        .split(',')
+                        .map(|x| x.parse())
+                        .collect::<std::result::Result<_, _>>()
+                        .with_context(|| format!("invalid lattice: {:?}", value))?;
+                    ensure!(v.len() == 9, "expect 9 numbers for lattice, got {:?}", value);
+                    lattice = Some([[v[0], v[1], v[2]], [v[3], v[4], v[5]], [v[6], v[7], v[8]]]);
+                }
+                _ => bail!("unknown header field: {:?}", field),
+            }
+        }
+        let mode = mode.context("no mode in header")?;
+        let natoms = natoms.context("no natoms in header")?;
+
+        let payload = match mode {
+            PositionsMode::Start => {
+                let lines: Vec<_> = rest.lines().collect();
+                let (_, n) = poscar::locate_positions(&lines).context("expect a complete POSCAR after header")?;
+                ensure!(n == natoms, "expect {} atoms as in header, got {} atoms in POSCAR", natoms, n);
+                if let Some(lattice) = &lattice {
+                    check_lattice(lattice, &poscar::read_lattice_vectors(&lines)?)?;
+                }
+                rest.to_owned()
+            }
+            PositionsMode::Update => parse_positions_payload(rest, natoms)?,
+        };
+        Ok(Some(PositionsInput {
+            mode,
+            natoms,
+            lattice,
+            payload,
+        }))
+    }
+
+    #[test]
+    fn test_positions_input() -> Result<()> {
+        assert_eq!(parse_positions_input("H2O\n1.0\n")?, None);
+
+        let lattice = [[10.0, 0.0, 0.0], [0.0, 10.0, 0.0], [0.0, 0.0, 10.0]];
+        let header = format_positions_header(PositionsMode::Update, 2, Some(&lattice));
+        assert_eq!(
+            header,
+            "#vasp-tools-positions v2 mode=update natoms=2 lattice=10,0,0,0,10,0,0,0,10\n"
+        );
+        let input = parse_positions_input(&format!("{}0.1 0.2 0.3 O\n0.4 0.5 0.6 H\n", header))?.unwrap();
+        assert_eq!(input.mode, PositionsMode::Update);
+        assert_eq!(input.lattice, Some(lattice));
+        assert_eq!(input.payload.lines().count(), 2);
+        assert!(input.payload.starts_with(" 0.1000000000000000"));
+
+        // atom count mismatch
+        assert!(parse_positions_input(&format!("{}0.1 0.2 0.3\n", header)).is_err());
+        // unsupported version or mode
+        assert!(parse_positions_input("#vasp-tools-positions v3 mode=update natoms=1\n0 0 0\n").is_err());
+        assert!(parse_positions_input("#vasp-tools-positions v2 mode=reset natoms=1\n0 0 0\n").is_err());
+        assert!(parse_positions_input("#vasp-tools-positions v2 natoms=1\n0 0 0\n").is_err());
+        // NaN coordinates
+        assert!(parse_positions_input("#vasp-tools-positions v2 mode=update natoms=1\nNaN 0 0\n").is_err());
+
+        assert!(check_lattice(&lattice, &lattice).is_ok());
+        assert!(check_lattice(&[[10.1, 0.0, 0.0], lattice[1], lattice[2]], &lattice).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_structure_consistency() -> Result<()> {
+        use gosh::gchemol::Atom;
+
+        let atoms = |symbols: &[&str]| {
+            let atoms = symbols.iter().enumerate().map(|(i, s)| Atom::new(*s, [i as f64, 0.0, 0.0]));
+            Molecule::from_atoms(atoms)
+        };
+        let mol_ref = atoms(&["Ti", "Ti", "O", "O", "O", "O"]);
+        check_structure_consistency(&atoms(&["Ti", "Ti", "O", "O", "O", "O"]), &mol_ref)?;
+        let e = check_structure_consistency(&atoms(&["Ti", "O", "Ti", "O", "O", "O"]), &mol_ref).unwrap_err();
+        assert!(format!("{}", e).contains("atom 2 is O"));
+        assert!(format!("{}", e).contains("Ti1 O1 Ti1 O3"));
+        assert!(check_structure_consistency(&atoms(&["Ti", "Ti", "O"]), &mol_ref).is_err());
+
+        Ok(())
+    }
 }
 // stdin:1 ends here
 
@@ -233,6 +726,10 @@ pub mod stdout {
     use std::io::prelude::*;
     use text_parser::parsers::*;
 
+    /// The default pattern (regex) in VASP stdout when it is ready for
+    /// reading positions from stdin in interactive mode
+    pub const VASP_READ_PATTERN: &str = "POSITIONS: reading from stdin";
+
     fn parse_vasp_energy(s: &str) -> Option<f64> {
         if s.len() < 42 {
             None
@@ -244,7 +741,8 @@ pub mod stdout {
     #[test]
     fn test_parse_vasp_energy() {
         let s = "   1 F= -.84780990E+02 E0= -.84775142E+02  d E =-.847810E+02  mag=     3.2666";
-        let (_, e) = read_energy(s).unwrap();
+        let (_, (f, e)) = read_energy(s).unwrap();
+        assert_eq!(f, -0.84780990E+02);
         assert_eq!(e, -0.84775142E+02);
     }
 
@@ -278,18 +776,19 @@ pub mod stdout {
     //    1 F= -.85097948E+02 E0= -.85096866E+02  d E =-.850979E+02  mag=     2.9646
     //    2 F= -.85086257E+02 E0= -.85082618E+02  d E =-.850863E+02  mag=     2.9772
     // POSITIONS: reading from stdin
-    fn read_energy(s: &str) -> IResult<&str, f64> {
+    // return free energy (F) and energy(sigma->0) (E0)
+    fn read_energy(s: &str) -> IResult<&str, (f64, f64)> {
         let tag_nf = tag("F=");
         let tag_e0 = tag("E0=");
         do_parse!(
             s,
-            space0 >> digit1 >> space1 >> tag_nf >> space0 >> double >>  // 1 F= ...
+            space0 >> digit1 >> space1 >> tag_nf >> space0 >> free: double >>  // 1 F= ...
             space0 >> tag_e0 >> space0 >> energy: double >> read_line >> // E0= ...
-            (energy)
+            ((free, energy))
         )
     }
 
-    fn read_energy_and_forces(s: &str) -> IResult<&str, (f64, Vec<[f64; 3]>)> {
+    fn read_energy_and_forces(s: &str) -> IResult<&str, ((f64, f64), Vec<[f64; 3]>)> {
         let jump = take_until("FORCES:\n");
         do_parse!(
             s,
@@ -300,10 +799,55 @@ pub mod stdout {
         )
     }
 
-    /// Parse energy and forces from stdout of VASP interactive calculation
+    /// Parse energy (sigma -> 0) and forces from stdout of VASP interactive
+    /// calculation
     pub fn parse_energy_and_forces(s: &str) -> Result<(f64, Vec<[f64; 3]>)> {
-        let (_, values) = read_energy_and_forces(s).unwrap();
-        Ok(values)
+        let (energies, forces) = parse_energies_and_forces(s)?;
+        Ok((energies.sigma0.expect("E0 in stdout"), forces))
+    }
+
+    /// Parse energies and forces from stdout of VASP interactive
+    /// calculation. Energy without entropy is not available in stdout.
+    pub fn parse_energies_and_forces(s: &str) -> Result<(outcar::Energies, Vec<[f64; 3]>)> {
+        let (_, ((free, e0), forces)) =
+            read_energy_and_forces(s).map_err(|e| format_err!("parse energy/forces from VASP stdout: {:?}", e))?;
+        let energies = outcar::Energies {
+            free,
+            without_entropy: None,
+            sigma0: e0.into(),
+        };
+        Ok((energies, forces))
+    }
+
+    /// Parse energies and total magnetization from the last ionic step line
+    /// in VASP stdout or OSZICAR text `s`, which is much cheaper than
+    /// parsing OUTCAR. Return None if not found.
+    pub fn parse_last_energy_line(s: &str) -> Option<(outcar::Energies, Option<f64>)> {
+        //    1 F= -.85097948E+02 E0= -.85096866E+02  d E =-.850979E+02  mag=     2.9646
+        let value_after = |line: &str, key: &str| -> Option<f64> { line.split_once(key)?.1.split_whitespace().next()?.parse().ok() };
+        let line = s.lines().rev().find(|line| {
+            let line = line.trim_start();
+            line.starts_with(|c: char| c.is_ascii_digit()) && line.contains(" F=")
+        })?;
+        let energies = outcar::Energies {
+            free: value_after(line, " F=")?,
+            without_entropy: None,
+            sigma0: value_after(line, " E0="),
+        };
+        Some((energies, value_after(line, " mag=")))
+    }
+
+    #[test]
+    fn test_parse_last_energy_line() {
+        let s = "DAV:   2    -0.850979E+02\n   1 F= -.85097948E+02 E0= -.85096866E+02  d E =-.850979E+02  mag=     2.9646\n";
+        let (energies, mag) = parse_last_energy_line(s).unwrap();
+        assert_eq!(energies.free, -0.85097948E+02);
+        assert_eq!(energies.sigma0, Some(-0.85096866E+02));
+        assert_eq!(mag, Some(2.9646));
+        // non spin-polarized
+        let (_, mag) = parse_last_energy_line("   2 F= -.85E+02 E0= -.85E+02  d E =-.1E-02\n").unwrap();
+        assert_eq!(mag, None);
+        assert!(parse_last_energy_line("POSITIONS: reading from stdin\n").is_none());
     }
 
     #[test]
@@ -328,21 +872,83 @@ pub mod outcar {
     use gosh::gchemol;
     use text_parser::TextReader;
 
-    #[derive(Debug, Default, Clone)]
-    struct OptIter {
-        i: usize,
-        energy: Option<f64>,
+    #[derive(Debug, Default, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+    pub(crate) struct OptIter {
+        pub(crate) i: usize,
+        pub(crate) energy: Option<f64>,
         // number of SCF for this opt step
-        nscf: Option<usize>,
-        volume: Option<f64>,
-        mag: Option<f64>,
-        fmax: Option<f64>,
+        pub(crate) nscf: Option<usize>,
+        pub(crate) volume: Option<f64>,
+        pub(crate) mag: Option<f64>,
+        pub(crate) fmax: Option<f64>,
+    }
+
+    /// Parse OUTCAR file. Forces of atoms fixed in selective dynamics are
+    /// excluded from fmax if `mask` is true.
+    pub fn summarize_outcar(f: &Path, plot: bool, mask: bool) -> Result<()> {
+        let collected_parts = parse_opt_iters(f, mask)?;
+        if plot {
+            println!("{}", plot_opt_iters(&collected_parts)?);
+        } else {
+            for part in collected_parts {
+                show_iter(&part);
+            }
+        }
+        Ok(())
     }
 
-    /// Parse OUTCAR file
-    pub fn summarize_outcar(f: &Path, plot: bool) -> Result<()> {
-        use std::io::BufRead;
+    /// Redraw the plot of optimization in OUTCAR `f` in terminal when new
+    /// ionic steps are appended, checking for updates every `interval`
+    /// seconds. Return when VASP finished writing OUTCAR.
+    pub fn follow_outcar(f: &Path, interval: f64, mask: bool) -> Result<()> {
+        let mut last_size = 0;
+        let mut last_nsteps = None;
+        loop {
+            let size = f.metadata().with_context(|| format!("read metadata of {:?}", f))?.len();
+            // NOTE: only re-parse when OUTCAR grows
+            if size != last_size {
+                last_size = size;
+                // OUTCAR could be incomplete at the beginning
+                let parts = parse_opt_iters(f, mask).unwrap_or_else(|e| {
+                    debug!("parse OUTCAR failed: {:?}", e);
+                    vec![]
+                });
+                if Some(parts.len()) != last_nsteps && !parts.is_empty() {
+                    last_nsteps = parts.len().into();
+                    let s = plot_opt_iters(&parts)?;
+                    // clear screen and move cursor to top left
+                    print!("\x1b[2J\x1b[H");
+                    println!("{}", s);
+                    if let Some(part) = parts.last() {
+                        show_iter(part);
+                    }
+                }
+                if vasp_finished(f)? {
+                    info!("VASP finished.");
+                    break;
+                }
+            }
+            gut::utils::sleep(interval);
+        }
+        Ok(())
+    }
 
+    // VASP writes timing information at the end of OUTCAR
+    pub(crate) fn vasp_finished(f: &Path) -> Result<bool> {
+        use std::io::{Read, Seek, SeekFrom};
+
+        let mut fp = std::fs::File::open(f)?;
+        let size = fp.metadata()?.len();
+        fp.seek(SeekFrom::Start(size.saturating_sub(8192)))?;
+        let mut buf = vec![];
+        fp.read_to_end(&mut buf)?;
+        Ok(String::from_utf8_lossy(&buf).contains("General timing and accounting informations"))
+    }
+
+    /// Parse ionic steps in OUTCAR `f`. If `mask` is true, forces of atoms
+    /// fixed in selective dynamics (read from POSCAR or CONTCAR) are
+    /// excluded from fmax.
+    pub(crate) fn parse_opt_iters(f: &Path, mask: bool) -> Result<Vec<OptIter>> {
         let r = TextReader::from_path(f)?;
         let mut parts = r.partitions_preceded(|line| line.contains("FREE ENERGIE OF THE ION-ELECTRON SYSTEM"));
 
@@ -356,6 +962,8 @@ pub mod outcar {
         } else {
             bail!("no POSCAR of CONTCAR");
         };
+        let frozen = crate::vasp::forces::frozen_flags(&mol);
+        let frozen = mask.then_some(frozen.as_slice());
 
         let mut old_partition = parts.next().ok_or(format_err!("OUTCAR has no partition"))?;
         let mut collected_parts = vec![];
@@ -369,7 +977,7 @@ pub mod outcar {
             // energy  without entropy=     -402.84358808  energy(sigma->0) =     -402.84008979
             let mut part = OptIter::default();
             part.i = i;
-            part.fmax = read_forces_and_fmax(&old_partition, &mol);
+            part.fmax = read_forces_and_fmax(&old_partition, mol.natoms(), frozen);
             let mut nscf = 0;
             for line in p.lines() {
                 if line.contains("free  energy   TOTEN  =") {
@@ -398,47 +1006,52 @@ pub mod outcar {
             // show_iter(&part);
             collected_parts.push(part);
         }
-        if plot {
-            use crate::plot::AsciiPlot;
-            let mut ascii_plot = AsciiPlot::new();
-
-            ascii_plot.set_title("Geometry optimization");
-            ascii_plot.set_xlabel("opt. step");
-            ascii_plot.set_ylabel("energy (eV)");
-            let x = collected_parts.iter().map(|o| o.i as f64).collect_vec();
-            let y = collected_parts.iter().map(|o| o.energy.unwrap() as f64).collect_vec();
-            let s = ascii_plot.plot(&x, &y)?;
-            println!("{}", s);
+        Ok(collected_parts)
+    }
+
+    fn plot_opt_iters(collected_parts: &[OptIter]) -> Result<String> {
+        use crate::plot::{AsciiPlot, Panel};
+
+        let mut ascii_plot = AsciiPlot::new();
+        ascii_plot.set_title("Geometry optimization");
+        ascii_plot.set_xlabel("opt. step");
+        ascii_plot.set_ylabel("energy (eV)");
+        let x = collected_parts.iter().map(|o| o.i as f64).collect_vec();
+        let y = collected_parts.iter().map(|o| o.energy.unwrap_or(f64::NAN)).collect_vec();
+        // show energy only if we have no forces
+        if collected_parts.iter().all(|o| o.fmax.is_none()) {
+            ascii_plot.plot(&x, &y)
         } else {
-            for part in collected_parts {
-                show_iter(&part);
-            }
+            // energy with SCF count as bars, and log(fmax) for convergence
+            let nscf = collected_parts.iter().map(|o| o.nscf.map_or(0.0, |n| n as f64)).collect_vec();
+            let lg_fmax = collected_parts
+                .iter()
+                .map(|o| o.fmax.map_or(f64::NAN, |f| f.log10()))
+                .collect_vec();
+            let panels = [
+                Panel::new("energy (eV)", &x, &y).with_bars("SCF", &nscf),
+                Panel::new("log10(fmax) (eV/Å)", &x, &lg_fmax),
+            ];
+            Ok(ascii_plot.plot_panels(&panels))
         }
-        Ok(())
     }
 
-    fn read_forces_and_fmax(s: &str, mol: &Molecule) -> Option<f64> {
-        use vecfx::*;
-
+    fn read_forces_and_fmax(s: &str, natoms: usize, frozen: Option<&[[bool; 3]]>) -> Option<f64> {
         let token = "TOTAL-FORCE (eV/Angst)";
-        let natoms = mol.natoms();
         let mut r = TextReader::from_str(s);
         let _ = r.seek_line(|line| line.contains(token));
         let mut lines = r.lines().take(natoms + 2);
         let first_line = lines.next()?;
         if first_line.contains(token) {
             //      -0.04844      0.25073      4.19570         0.005351      0.001537     -0.846521
-            let forces: Vec<f64> = lines
+            let forces: Vec<[f64; 3]> = lines
                 .skip(1)
-                .flat_map(|line| {
-                    let f3: Vec<_> = line.split_whitespace().skip(3).map(|x| x.parse().unwrap()).collect();
-                    f3
+                .map(|line| {
+                    let f3: Vec<f64> = line.split_whitespace().skip(3).map(|x| x.parse().unwrap()).collect();
+                    [f3[0], f3[1], f3[2]]
                 })
                 .collect();
-            let mask = mol.freezing_coords_mask();
-            let forces_masked = mask.apply(&forces);
-            let fmax = forces_masked.as_3d().iter().map(|x| x.vec2norm()).float_max();
-            fmax.into()
+            crate::vasp::forces::fmax(&forces, frozen).into()
         } else {
             None
         }
@@ -455,10 +1068,194 @@ pub mod outcar {
         );
     }
 
+    /// Parse the stress tensor (XX, YY, ZZ, XY, YZ, ZX) in kB from the last
+    /// "FORCE on cell" block in OUTCAR `f`. ISIF >= 1 is required.
+    pub fn parse_last_stress(f: &Path) -> Result<[f64; 6]> {
+        let s = gut::fs::read_file(f)?;
+        let line = s
+            .lines()
+            .rev()
+            .find(|line| line.trim_start().starts_with("in kB"))
+            .ok_or(format_err!("no stress found in {:?}", f))?;
+        parse_stress_line(line)
+    }
+
+    pub(crate) use vasp_parsers::outcar::parse_stress_line;
+    pub use vasp_parsers::outcar::{Energies, EnergyKind};
+
+    /// Parse free energy, energy without entropy and energy(sigma->0) of
+    /// the last ionic step in OUTCAR `f`.
+    pub fn parse_last_energies(f: &Path) -> Result<Option<Energies>> {
+        let s = gut::fs::read_file(f)?;
+        Ok(vasp_parsers::outcar::parse_last_energies(&s))
+    }
+
+    /// Parse total magnetization from the last " number of electron" line in
+    /// OUTCAR `f`.
+    pub fn parse_last_magnetization(f: &Path) -> Result<Option<f64>> {
+        let s = gut::fs::read_file(f)?;
+        Ok(vasp_parsers::outcar::parse_last_magnetization(&s))
+    }
+
+    /// Parse the dipole moment (e·Å) from the last "dipolmoment" line in
+    /// OUTCAR `f`. LDIPOL or IDIPOL is required.
+    pub fn parse_last_dipole(f: &Path) -> Result<[f64; 3]> {
+        //  dipolmoment           0.000000     -0.000000      0.279564 electrons x Angstroem
+        let s = gut::fs::read_file(f)?;
+        let line = s
+            .lines()
+            .rev()
+            .find(|line| line.trim_start().starts_with("dipolmoment"))
+            .ok_or(format_err!("no dipole moment found in {:?}", f))?;
+        let v: Vec<f64> = line
+            .split_whitespace()
+            .skip(1)
+            .take(3)
+            .map(|x| x.parse())
+            .collect::<std::result::Result<_, _>>()
+            .with_context(|| format!("invalid dipole moment: {:?}", line))?;
+        ensure!(v.len() == 3, "invalid dipole moment: {:?}", line);
+        Ok([v[0], v[1], v[2]])
+    }
+
+    /// Parse number of SCF iterations of the last ionic step in OUTCAR `f`.
+    pub fn parse_last_nscf(f: &Path) -> Result<Option<usize>> {
+        let s = gut::fs::read_file(f)?;
+        Ok(vasp_parsers::outcar::parse_last_nscf(&s))
+    }
+
+    /// Parse number of SCF iterations of each ionic step in OUTCAR `f`.
+    pub fn parse_scf_counts(f: &Path) -> Result<Vec<usize>> {
+        let s = gut::fs::read_file(f)?;
+        Ok(vasp_parsers::outcar::parse_scf_counts(&s))
+    }
+
+    /// Parse total charges on each atom from the last "total charge" block in
+    /// OUTCAR `f` (LORBIT = 11 is required).
+    pub fn parse_last_charges(f: &Path) -> Result<Option<Vec<f64>>> {
+        let s = gut::fs::read_file(f)?;
+        // total charge
+        //
+        // # of ion       s       p       d       tot
+        // ------------------------------------------
+        //     1        0.498   0.458   0.000   0.956
+        // --------------------------------------------------
+        // tot          ...
+        let block = match s.rsplit_once(" total charge ") {
+            Some((_, block)) => block,
+            None => return Ok(None),
+        };
+        let charges: Option<Vec<f64>> = block
+            .lines()
+            .skip_while(|line| !line.starts_with("# of ion"))
+            .skip(2)
+            .take_while(|line| !line.starts_with("---"))
+            .map(|line| line.split_whitespace().last().and_then(|x| x.parse().ok()))
+            .collect();
+        Ok(charges.filter(|x| !x.is_empty()))
+    }
+
+    /// The electrostatic corrections for charged system in OUTCAR
+    /// (IDIPOL and LMONO)
+    #[derive(Debug, Default, Clone, PartialEq)]
+    pub struct ChargeCorrection {
+        /// The dipole moment in electrons x Å
+        pub dipole: Option<[f64; 3]>,
+        /// The trace of quadrupole moment in electrons x Å^2
+        pub quadrupole: Option<f64>,
+        /// The energy correction for charged system in eV
+        pub energy: Option<f64>,
+    }
+
+    /// Parse the last electrostatic corrections for charged system from
+    /// OUTCAR `f`.
+    pub fn parse_charge_correction(f: &Path) -> Result<ChargeCorrection> {
+        let s = gut::fs::read_file(f)?;
+        Ok(parse_charge_correction_str(&s))
+    }
+
+    //  dipolmoment           0.000012     -0.000003      0.000204 electrons x Angstroem
+    //  Tr[quadrupol]    -25.627618
+    //
+    //  energy correction for charged system           0.171553 eV
+    fn parse_charge_correction_str(s: &str) -> ChargeCorrection {
+        let mut corr = ChargeCorrection::default();
+        for line in s.lines() {
+            let line = line.trim_start();
+            if line.starts_with("dipolmoment") {
+                let x: Vec<f64> = line.split_whitespace().skip(1).take(3).filter_map(|x| x.parse().ok()).collect();
+                if x.len() == 3 {
+                    corr.dipole = Some([x[0], x[1], x[2]]);
+                }
+            } else if line.starts_with("Tr[quadrupol]") {
+                corr.quadrupole = line.split_whitespace().nth(1).and_then(|x| x.parse().ok());
+            } else if line.starts_with("energy correction for charged system") {
+                corr.energy = line.split_whitespace().nth(5).and_then(|x| x.parse().ok());
+            }
+        }
+        corr
+    }
+
+    /// Return the net charge of the system in OUTCAR `f`: the total valence
+    /// from POTCAR (ZVAL) minus NELECT. Positive for electron deficiency.
+    pub fn parse_system_charge(f: &Path) -> Result<f64> {
+        let s = gut::fs::read_file(f)?;
+        parse_system_charge_str(&s)
+    }
+
+    //    ions per type =              24   1
+    //    ZVAL   =   6.00  4.00
+    //    NELECT =     147.0000    total number of electrons
+    fn parse_system_charge_str(s: &str) -> Result<f64> {
+        let values_after = |key: &str| -> Option<Vec<f64>> {
+            let line = s.lines().find(|line| line.trim_start().starts_with(key))?;
+            let (_, values) = line.split_once('=')?;
+            values.split_whitespace().map_while(|x| x.parse().ok()).collect::<Vec<_>>().into()
+        };
+        let ions = values_after("ions per type").context("no ions per type in OUTCAR")?;
+        let zval = values_after("ZVAL").context("no ZVAL in OUTCAR")?;
+        ensure!(ions.len() == zval.len(), "inconsistent ions per type and ZVAL: {:?} vs {:?}", ions, zval);
+        let nelect = values_after("NELECT")
+            .and_then(|x| x.first().copied())
+            .context("no NELECT in OUTCAR")?;
+        let valence: f64 = ions.iter().zip(&zval).map(|(n, z)| n * z).sum();
+        Ok(valence - nelect)
+    }
+
+    #[test]
+    fn test_parse_charge_correction() -> Result<()> {
+        let s = "   ions per type =              24   1
+   NELECT =     147.0000    total number of electrons
+   ZVAL   =   6.00  4.00
+ dipolmoment           0.000012     -0.000003      0.000204 electrons x Angstroem
+ Tr[quadrupol]    -25.627618
+
+ energy correction for charged system           0.171553 eV
+";
+        let corr = parse_charge_correction_str(s);
+        assert_eq!(corr.dipole, Some([0.000012, -0.000003, 0.000204]));
+        assert_eq!(corr.quadrupole, Some(-25.627618));
+        assert_eq!(corr.energy, Some(0.171553));
+        assert_relative_eq!(parse_system_charge_str(s)?, 1.0, epsilon = 1e-8);
+        assert_eq!(parse_charge_correction_str(""), ChargeCorrection::default());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_stress_line() -> Result<()> {
+        let line = "  in kB      -5.33553    -5.33553    -5.01808     0.10000     0.20000     0.30000";
+        let stress = parse_stress_line(line)?;
+        assert_eq!(stress[2], -5.01808);
+        assert_eq!(stress[5], 0.3);
+
+        Ok(())
+    }
+
     #[test]
     #[ignore]
     fn test_outcar_parser() {
-        summarize_outcar("tests/files/OUTCAR".as_ref(), false);
+        summarize_outcar("tests/files/OUTCAR".as_ref(), false, true);
     }
 }
 // 0cf24c08 ends here
@@ -0,0 +1,138 @@
+// [[file:../../vasp-tools.note::de84dc61][de84dc61]]
+use super::*;
+
+use sha2::{Digest, Sha256};
+// de84dc61 ends here
+
+// [[file:../../vasp-tools.note::9cf78de4][9cf78de4]]
+/// Important input/output files to archive
+const ARCHIVE_FILES: &[&str] = &["INCAR", "KPOINTS", "POSCAR", "CONTCAR", "OUTCAR", "OSZICAR", "vasprun.xml"];
+
+/// Large files skipped by default
+const LARGE_FILES: &[&str] = &["WAVECAR", "CHG", "CHGCAR"];
+
+/// The name of checksum manifest in archive, which can be checked using
+/// `sha256sum -c` after extraction.
+const MANIFEST_FILE: &str = "MANIFEST.sha256";
+
+/// Options for archiving a VASP run directory
+#[derive(Debug, Clone, Default)]
+pub struct ArchiveOptions {
+    /// Store OUTCAR as OUTCAR.zst with high compression level
+    pub compress_outcar: bool,
+    /// Include WAVECAR, CHG and CHGCAR
+    pub include_large: bool,
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+fn sha256_file(f: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    let mut fp = std::fs::File::open(f).with_context(|| format!("open {:?}", f))?;
+    std::io::copy(&mut fp, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn new_tar_header(size: u64, mtime: u64) -> tar::Header {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(size);
+    header.set_mode(0o644);
+    header.set_mtime(mtime);
+    header.set_cksum();
+    header
+}
+
+/// Archive important files in VASP run directory `dir` into `output` in
+/// tar.zst format. All files are put in a top directory named after `dir`,
+/// together with a manifest of SHA-256 checksums. Return archived entries
+/// and their checksums.
+pub fn archive_run_dir(dir: &Path, output: &Path, opts: &ArchiveOptions) -> Result<Vec<(String, String)>> {
+    let dir = dir.canonicalize().with_context(|| format!("invalid run directory: {:?}", dir))?;
+    let top = dir.file_name().and_then(|x| x.to_str()).unwrap_or("run").to_owned();
+
+    let mut names: Vec<&str> = ARCHIVE_FILES.to_vec();
+    if opts.include_large {
+        names.extend(LARGE_FILES);
+    }
+
+    let fp = std::fs::File::create(output).with_context(|| format!("create archive {:?}", output))?;
+    let encoder = zstd::Encoder::new(fp, 0)?;
+    let mut builder = tar::Builder::new(encoder);
+    let mut manifest = vec![];
+    for name in names {
+        let path = dir.join(name);
+        if !path.is_file() {
+            warn!("{:?} not found, skipped.", path);
+            continue;
+        }
+        let mtime = path
+            .metadata()?
+            .modified()?
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        if name == "OUTCAR" && opts.compress_outcar {
+            let name = "OUTCAR.zst";
+            info!("archive {:?} as {}", path, name);
+            let data = zstd::encode_all(std::fs::File::open(&path)?, 19)?;
+            let mut header = new_tar_header(data.len() as u64, mtime);
+            builder.append_data(&mut header, format!("{}/{}", top, name), data.as_slice())?;
+            manifest.push((name.to_owned(), sha256_hex(&data)));
+        } else {
+            info!("archive {:?}", path);
+            builder.append_path_with_name(&path, format!("{}/{}", top, name))?;
+            manifest.push((name.to_owned(), sha256_file(&path)?));
+        }
+    }
+    ensure!(!manifest.is_empty(), "no files to archive in {:?}", dir);
+
+    let txt: String = manifest.iter().map(|(name, sum)| format!("{}  {}\n", sum, name)).collect();
+    let mut header = new_tar_header(txt.len() as u64, 0);
+    builder.append_data(&mut header, format!("{}/{}", top, MANIFEST_FILE), txt.as_bytes())?;
+    builder.into_inner()?.finish()?;
+
+    Ok(manifest)
+}
+// 9cf78de4 ends here
+
+// [[file:../../vasp-tools.note::12f553ce][12f553ce]]
+#[test]
+fn test_archive_run_dir() -> Result<()> {
+    use std::io::Read;
+
+    let tdir = tempfile::tempdir()?;
+    let run = tdir.path().join("run1");
+    std::fs::create_dir(&run)?;
+    gut::fs::write_to_file(run.join("INCAR"), "ISTART = 0\n")?;
+    gut::fs::write_to_file(run.join("OUTCAR"), "outcar\n")?;
+    gut::fs::write_to_file(run.join("WAVECAR"), "wavecar")?;
+
+    let output = tdir.path().join("run1.tar.zst");
+    let opts = ArchiveOptions {
+        compress_outcar: true,
+        ..Default::default()
+    };
+    let manifest = archive_run_dir(&run, &output, &opts)?;
+    let names: Vec<_> = manifest.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(names, ["INCAR", "OUTCAR.zst"]);
+
+    let decoder = zstd::Decoder::new(std::fs::File::open(&output)?)?;
+    let mut archive = tar::Archive::new(decoder);
+    let mut entries = vec![];
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_string_lossy().into_owned();
+        let mut data = vec![];
+        entry.read_to_end(&mut data)?;
+        if path == "run1/INCAR" {
+            assert_eq!(sha256_hex(&data), manifest[0].1);
+        }
+        entries.push(path);
+    }
+    assert_eq!(entries, ["run1/INCAR", "run1/OUTCAR.zst", "run1/MANIFEST.sha256"]);
+
+    Ok(())
+}
+// 12f553ce ends here
@@ -0,0 +1,418 @@
+// [[file:../../vasp-tools.note::0967114e][0967114e]]
+use super::*;
+
+use crate::bbm::RetryPolicy;
+use crate::process::{ProcessControl, ResourceLimits};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+// 0967114e ends here
+
+// [[file:../../vasp-tools.note::d048326b][d048326b]]
+/// The log file of VASP stdout/stderr in each run directory
+const BATCH_LOG_FILE: &str = "vasp.log";
+
+/// Match `name` against wildcard `pattern` with `*` and `?`.
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let s: Vec<char> = name.chars().collect();
+    // the positions of last `*` in pattern and matched char in name
+    let (mut i, mut j) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while j < s.len() {
+        if i < p.len() && (p[i] == '?' || p[i] == s[j]) {
+            i += 1;
+            j += 1;
+        } else if i < p.len() && p[i] == '*' {
+            star = Some((i, j));
+            i += 1;
+        } else if let Some((si, sj)) = star {
+            i = si + 1;
+            j = sj + 1;
+            star = Some((si, sj + 1));
+        } else {
+            return false;
+        }
+    }
+    p[i..].iter().all(|&c| c == '*')
+}
+
+/// Expand `pattern` with wildcards (`*` and `?`) in path components into
+/// existing directories. Hidden directories are matched only if the
+/// pattern component starts with a dot.
+fn expand_glob(pattern: &str) -> Vec<PathBuf> {
+    if !pattern.contains(['*', '?']) {
+        return vec![pattern.into()];
+    }
+    let mut paths = vec![if pattern.starts_with('/') { PathBuf::from("/") } else { PathBuf::new() }];
+    for part in pattern.split('/').filter(|x| !x.is_empty()) {
+        if !part.contains(['*', '?']) {
+            paths.iter_mut().for_each(|p| p.push(part));
+            continue;
+        }
+        let mut matched = vec![];
+        for p in &paths {
+            let dir = if p.as_os_str().is_empty() { Path::new(".") } else { p.as_path() };
+            let mut names: Vec<_> = match std::fs::read_dir(dir) {
+                Ok(entries) => entries
+                    .filter_map(|e| e.ok())
+                    .filter(|e| e.file_type().map_or(false, |t| t.is_dir()))
+                    .filter_map(|e| e.file_name().into_string().ok())
+                    .filter(|x| !x.starts_with('.') || part.starts_with('.'))
+                    .filter(|x| wildcard_match(part, x))
+                    .collect(),
+                Err(_) => continue,
+            };
+            names.sort();
+            matched.extend(names.into_iter().map(|x| p.join(x)));
+        }
+        paths = matched;
+    }
+    paths
+}
+
+/// Collect run directories from `patterns` (with wildcards) and from the
+/// list file `list` (one directory per line). Duplicates are removed.
+pub fn collect_run_dirs(patterns: &[String], list: Option<&Path>) -> Result<Vec<PathBuf>> {
+    let mut patterns = patterns.to_vec();
+    if let Some(f) = list {
+        let s = gut::fs::read_file(f).with_context(|| format!("read directory list {:?}", f))?;
+        patterns.extend(s.lines().map(|x| x.trim()).filter(|x| !x.is_empty() && !x.starts_with('#')).map(String::from));
+    }
+
+    let mut dirs: Vec<PathBuf> = vec![];
+    for pattern in &patterns {
+        let expanded = expand_glob(pattern);
+        if expanded.is_empty() {
+            warn!("no directory matches {:?}", pattern);
+        }
+        for d in expanded {
+            ensure!(d.is_dir(), "not a directory: {:?}", d);
+            if !dirs.contains(&d) {
+                dirs.push(d);
+            }
+        }
+    }
+    Ok(dirs)
+}
+
+/// The classified status of a batch job
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum JobStatus {
+    /// Converged relaxation or finished single point calculation
+    Converged,
+    /// Finished molecular dynamics
+    Finished,
+    /// Ionic relaxation stopped without reaching required accuracy
+    NotConverged,
+    /// SCF of the last ionic step reached NELM
+    ScfNotConverged,
+    /// VASP exited without timing information in OUTCAR
+    Crashed,
+    /// Killed for exceeding the time limit
+    TimedOut,
+    /// Failed to prepare or start VASP
+    Failed,
+}
+
+impl JobStatus {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Converged => "converged",
+            Self::Finished => "finished",
+            Self::NotConverged => "not-converged",
+            Self::ScfNotConverged => "scf-not-converged",
+            Self::Crashed => "crashed",
+            Self::TimedOut => "timed-out",
+            Self::Failed => "failed",
+        }
+    }
+
+    /// Classify the status of finished job in `dir`.
+    fn classify(dir: &Path, timed_out: bool) -> (Self, Option<super::report::Convergence>) {
+        use super::report::Verdict;
+
+        if timed_out {
+            return (Self::TimedOut, super::report::check_convergence(dir).ok());
+        }
+        let conv = match super::report::check_convergence(dir) {
+            Ok(conv) => conv,
+            Err(_) => return (Self::Crashed, None),
+        };
+        let status = match conv.outcome {
+            Verdict::Unfinished => Self::Crashed,
+            Verdict::ScfNotConverged => Self::ScfNotConverged,
+            Verdict::Converged => Self::Converged,
+            Verdict::Finished => Self::Finished,
+            Verdict::NotConverged => Self::NotConverged,
+        };
+        (status, conv.into())
+    }
+}
+
+/// The result of one batch job
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct JobResult {
+    pub directory: PathBuf,
+    pub status: JobStatus,
+    /// The number of runs including retries
+    pub attempts: usize,
+    pub nsteps: usize,
+    pub energy: Option<f64>,
+    pub fmax: Option<f64>,
+    /// The wall time of the job in seconds
+    pub elapsed: f64,
+    /// The convergence verdict or error message
+    pub message: String,
+}
+
+/// Options for running VASP in many directories
+#[derive(Debug, Clone)]
+pub struct BatchOptions {
+    /// The max number of jobs running at the same time
+    pub jobs: usize,
+    /// Update INCAR for this task before running VASP
+    pub task: Option<VaspTask>,
+    /// Retry crashed jobs (e.g. for node or filesystem failures). Timeout
+    /// applies to each run of VASP.
+    pub retry: RetryPolicy,
+    pub limits: ResourceLimits,
+}
+
+impl Default for BatchOptions {
+    fn default() -> Self {
+        Self {
+            jobs: 1,
+            task: None,
+            retry: RetryPolicy::default(),
+            limits: ResourceLimits::default(),
+        }
+    }
+}
+
+/// Run `program` once in `dir`, and return true if killed for timeout.
+fn run_vasp_once(program: &Path, dir: &Path, opts: &BatchOptions) -> Result<bool> {
+    use crate::session::SessionHandler;
+    use std::os::unix::process::CommandExt;
+
+    let log = std::fs::File::create(dir.join(BATCH_LOG_FILE))?;
+    let mut command = Command::new(program);
+    command
+        .current_dir(dir)
+        .stdin(std::process::Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log)
+        .process_group(0);
+    let control = ProcessControl::prepare(&mut command, &opts.limits)?;
+    let mut child = command.spawn().with_context(|| format!("spawn {:?} in {:?}", program, dir))?;
+    let handler = SessionHandler::for_process_group(child.id());
+
+    let t0 = std::time::Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            debug!("VASP in {:?} exited with {}", dir, status);
+            return Ok(false);
+        }
+        if opts.retry.timeout.map_or(false, |t| t0.elapsed().as_secs_f64() > t) {
+            warn!("VASP in {:?} exceeds time limit, terminating ...", dir);
+            control.terminate_blocking(&handler)?;
+            gut::utils::sleep(5.0);
+            if child.try_wait()?.is_none() {
+                child.kill()?;
+            }
+            child.wait()?;
+            return Ok(true);
+        }
+        gut::utils::sleep(1.0);
+    }
+}
+
+/// Prepare and run VASP job in `dir`, retrying crashed runs following
+/// retry policy in `opts`.
+fn run_job(program: &Path, dir: &Path, opts: &BatchOptions) -> JobResult {
+    let t0 = std::time::Instant::now();
+    let mut result = JobResult {
+        directory: dir.to_owned(),
+        status: JobStatus::Failed,
+        attempts: 0,
+        nsteps: 0,
+        energy: None,
+        fmax: None,
+        elapsed: 0.0,
+        message: String::new(),
+    };
+    if let Some(task) = &opts.task {
+        if let Err(e) = update_incar_in_dir(dir, task) {
+            result.message = format!("update INCAR failed: {:?}", e);
+            return result;
+        }
+    }
+
+    let mut delay = opts.retry.backoff;
+    loop {
+        result.attempts += 1;
+        match run_vasp_once(program, dir, opts) {
+            Ok(timed_out) => {
+                let (status, conv) = JobStatus::classify(dir, timed_out);
+                result.status = status;
+                if let Some(conv) = conv {
+                    result.nsteps = conv.nsteps;
+                    result.energy = conv.energy;
+                    result.fmax = conv.fmax;
+                    result.message = conv.verdict;
+                } else {
+                    result.message = "no OUTCAR".into();
+                }
+            }
+            Err(e) => {
+                result.status = JobStatus::Failed;
+                result.message = format!("{:?}", e);
+            }
+        }
+        // only crashed runs are worth retrying
+        if result.status != JobStatus::Crashed || result.attempts > opts.retry.max_retries {
+            break;
+        }
+        warn!("VASP crashed in {:?}, retry in {} seconds", dir, delay);
+        gut::utils::sleep(delay);
+        delay *= 2.0;
+    }
+    if result.status == JobStatus::TimedOut {
+        result.message = format!("killed after {} seconds", opts.retry.timeout.unwrap_or_default());
+    }
+    result.elapsed = t0.elapsed().as_secs_f64();
+    info!("{:?}: {} ({})", dir, result.status.name(), result.message);
+    result
+}
+
+/// Run `program` in each of `dirs` with at most `opts.jobs` jobs in
+/// parallel. Return results in the order of `dirs`.
+pub fn run_batch(program: &Path, dirs: &[PathBuf], opts: &BatchOptions) -> Result<Vec<JobResult>> {
+    // NOTE: VASP runs in each directory: relative path to program should be
+    // resolved first
+    let program = if program.components().count() > 1 && program.is_relative() {
+        program.canonicalize().with_context(|| format!("invalid program path: {:?}", program))?
+    } else {
+        program.to_owned()
+    };
+    let njobs = opts.jobs.max(1).min(dirs.len());
+    info!("run VASP in {} directories with {} parallel jobs", dirs.len(), njobs);
+
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(vec![None; dirs.len()]);
+    std::thread::scope(|s| {
+        for _ in 0..njobs {
+            s.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                if i >= dirs.len() {
+                    break;
+                }
+                let r = run_job(&program, &dirs[i], opts);
+                results.lock().unwrap()[i] = Some(r);
+            });
+        }
+    });
+
+    let results = results.into_inner().unwrap().into_iter().flatten().collect();
+    Ok(results)
+}
+
+/// Format batch `results` as a plain text table.
+pub fn format_results_table(results: &[JobResult]) -> String {
+    let fmt = |x: Option<f64>| x.map(|x| format!("{:.6}", x)).unwrap_or("--".into());
+    let mut txt = format!(
+        "{:<30} {:<18} {:>6} {:>18} {:>12} {:>10}\n",
+        "directory", "status", "steps", "energy (eV)", "fmax (eV/Å)", "time (s)"
+    );
+    for r in results {
+        txt += &format!(
+            "{:<30} {:<18} {:>6} {:>18} {:>12} {:>10.1}\n",
+            r.directory.display(),
+            r.status.name(),
+            r.nsteps,
+            fmt(r.energy),
+            fmt(r.fmax),
+            r.elapsed
+        );
+    }
+    let nok = results
+        .iter()
+        .filter(|r| matches!(r.status, JobStatus::Converged | JobStatus::Finished))
+        .count();
+    txt += &format!("{} of {} jobs succeeded\n", nok, results.len());
+    txt
+}
+
+/// Write batch `results` into CSV file `f`.
+pub fn write_results_csv(f: &Path, results: &[JobResult]) -> Result<()> {
+    use super::watch::csv_field;
+
+    let fmt = |x: Option<f64>| x.map(|x| x.to_string()).unwrap_or_default();
+    let mut txt = String::from("directory,status,attempts,nsteps,energy,fmax,elapsed,message\n");
+    for r in results {
+        let line = [
+            csv_field(&r.directory.to_string_lossy()),
+            r.status.name().to_owned(),
+            r.attempts.to_string(),
+            r.nsteps.to_string(),
+            fmt(r.energy),
+            fmt(r.fmax),
+            format!("{:.1}", r.elapsed),
+            csv_field(&r.message),
+        ];
+        txt += &line.join(",");
+        txt += "\n";
+    }
+    gut::fs::write_to_file(f, &txt)?;
+    Ok(())
+}
+// d048326b ends here
+
+// [[file:../../vasp-tools.note::150ff179][150ff179]]
+#[test]
+fn test_batch_run() -> Result<()> {
+    assert!(wildcard_match("run-*", "run-01"));
+    assert!(wildcard_match("r?n*1", "run-01"));
+    assert!(wildcard_match("*", ""));
+    assert!(!wildcard_match("run-*", "ru"));
+    assert!(!wildcard_match("*x", "run"));
+
+    let tdir = tempfile::tempdir()?;
+    let root = tdir.path();
+    for d in ["run-1", "run-2", "other"] {
+        std::fs::create_dir(root.join(d))?;
+    }
+    let dirs = collect_run_dirs(&[format!("{}/run-*", root.display())], None)?;
+    assert_eq!(dirs, vec![root.join("run-1"), root.join("run-2")]);
+
+    // a fake VASP: finished in run-1, crashed in run-2
+    let program = root.join("fake-vasp");
+    let script = "#!/bin/sh\nif [ $(basename $PWD) = run-1 ]; then\n  echo ' General timing and accounting informations' > OUTCAR\nelse\n  echo ' running' >> OUTCAR; exit 1\nfi\n";
+    gut::fs::write_to_file(&program, script)?;
+    std::fs::set_permissions(&program, std::os::unix::fs::PermissionsExt::from_mode(0o755))?;
+
+    let opts = BatchOptions {
+        jobs: 2,
+        retry: RetryPolicy {
+            max_retries: 1,
+            backoff: 0.1,
+            timeout: None,
+        },
+        ..Default::default()
+    };
+    let results = run_batch(&program, &dirs, &opts)?;
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[0].status, JobStatus::Converged);
+    assert_eq!(results[0].attempts, 1);
+    assert_eq!(results[1].status, JobStatus::Crashed);
+    assert_eq!(results[1].attempts, 2);
+    assert!(format_results_table(&results).ends_with("1 of 2 jobs succeeded\n"));
+
+    let f = root.join("results.csv");
+    write_results_csv(&f, &results)?;
+    assert_eq!(gut::fs::read_file(&f)?.lines().count(), 3);
+
+    Ok(())
+}
+// 150ff179 ends here
@@ -0,0 +1,115 @@
+// [[file:../../vasp-tools.note::7c4ee21b][7c4ee21b]]
+use super::*;
+// 7c4ee21b ends here
+
+// [[file:../../vasp-tools.note::086a225b][086a225b]]
+/// Large files safe to remove after VASP calculation finished
+const LARGE_FILES: &[&str] = &["WAVECAR", "CHG", "CHGCAR", "PROCAR"];
+
+/// Format `bytes` in human readable form.
+pub(crate) fn format_size(bytes: u64) -> String {
+    let units = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut i = 0;
+    while size >= 1024.0 && i < units.len() - 1 {
+        size /= 1024.0;
+        i += 1;
+    }
+    if i == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, units[i])
+    }
+}
+
+/// Find large files no less than `min_size` bytes in directory `dir`, and in
+/// its sub-directories if `recursive` is true. Symbolic links are ignored.
+fn find_large_files(dir: &Path, min_size: u64, recursive: bool, found: &mut Vec<(PathBuf, u64)>) -> Result<()> {
+    let mut entries: Vec<_> = std::fs::read_dir(dir)
+        .with_context(|| format!("read directory {:?}", dir))?
+        .filter_map(|e| e.ok())
+        .collect();
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let path = entry.path();
+        let ft = entry.file_type()?;
+        if ft.is_dir() {
+            if recursive {
+                // NOTE: do not abort for unreadable sub-directories
+                if let Err(e) = find_large_files(&path, min_size, recursive, found) {
+                    warn!("{:?}", e);
+                }
+            }
+        } else if ft.is_file() {
+            let is_large = entry.file_name().to_str().map_or(false, |x| LARGE_FILES.contains(&x));
+            let size = entry.metadata()?.len();
+            if is_large && size >= min_size {
+                found.push((path, size));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Remove WAVECAR, CHG, CHGCAR and PROCAR files no less than `min_size`
+/// bytes in `dirs`. If `dry_run` is true, only list the files to be removed.
+/// Return the total size of files (to be) removed.
+pub fn clean_run_dirs(dirs: &[PathBuf], min_size: u64, recursive: bool, dry_run: bool) -> Result<u64> {
+    let mut found = vec![];
+    for dir in dirs {
+        find_large_files(dir, min_size, recursive, &mut found)?;
+    }
+
+    let mut total = 0;
+    let mut nremoved = 0;
+    for (path, size) in found {
+        if dry_run {
+            println!("would remove {:?} ({})", path, format_size(size));
+        } else {
+            match std::fs::remove_file(&path) {
+                Ok(_) => println!("removed {:?} ({})", path, format_size(size)),
+                Err(e) => {
+                    warn!("failed to remove {:?}: {:?}", path, e);
+                    continue;
+                }
+            }
+        }
+        total += size;
+        nremoved += 1;
+    }
+    let verb = if dry_run { "could be reclaimed" } else { "reclaimed" };
+    println!("{} files, {} {}.", nremoved, format_size(total), verb);
+
+    Ok(total)
+}
+// 086a225b ends here
+
+// [[file:../../vasp-tools.note::f8726d78][f8726d78]]
+#[test]
+fn test_clean_run_dirs() -> Result<()> {
+    assert_eq!(format_size(100), "100 B");
+    assert_eq!(format_size(1536), "1.5 KB");
+
+    let tdir = tempfile::tempdir()?;
+    let sub = tdir.path().join("sub");
+    std::fs::create_dir(&sub)?;
+    gut::fs::write_to_file(tdir.path().join("WAVECAR"), &"x".repeat(100))?;
+    gut::fs::write_to_file(tdir.path().join("OUTCAR"), &"x".repeat(100))?;
+    gut::fs::write_to_file(tdir.path().join("CHG"), "x")?;
+    gut::fs::write_to_file(sub.join("CHGCAR"), &"x".repeat(100))?;
+
+    let dirs = vec![tdir.path().to_owned()];
+    let n = clean_run_dirs(&dirs, 10, false, true)?;
+    assert_eq!(n, 100);
+    assert!(tdir.path().join("WAVECAR").exists());
+
+    let n = clean_run_dirs(&dirs, 10, true, false)?;
+    assert_eq!(n, 200);
+    assert!(!tdir.path().join("WAVECAR").exists());
+    assert!(!sub.join("CHGCAR").exists());
+    assert!(tdir.path().join("CHG").exists());
+    assert!(tdir.path().join("OUTCAR").exists());
+
+    Ok(())
+}
+// f8726d78 ends here
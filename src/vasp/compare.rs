@@ -0,0 +1,235 @@
+// [[file:../../vasp-tools.note::719f59a6][719f59a6]]
+use super::*;
+
+use gosh::gchemol::prelude::*;
+use gosh::gchemol::Molecule;
+use gosh::model::ModelProperties;
+use std::collections::BTreeMap;
+// 719f59a6 ends here
+
+// [[file:../../vasp-tools.note::6f76beda][6f76beda]]
+/// Data collected from a VASP run directory for comparison
+struct RunData {
+    incar: BTreeMap<String, String>,
+    mp: Option<ModelProperties>,
+    /// The final structure from CONTCAR, or POSCAR if not available
+    mol: Option<Molecule>,
+    /// Number of SCF iterations in each ionic step
+    scf_counts: Vec<usize>,
+}
+
+impl RunData {
+    fn load(dir: &Path) -> Result<Self> {
+        use gosh::adaptor::ModelAdaptor;
+
+        ensure!(dir.is_dir(), "invalid run directory: {:?}", dir);
+        let incar = dir.join("INCAR");
+        let incar = if incar.exists() { incar::parse_tags(&incar)? } else { BTreeMap::new() };
+        let outcar = dir.join("OUTCAR");
+        let (mp, scf_counts) = if outcar.exists() {
+            let mp = gosh::adaptor::Vasp()
+                .parse_last(&outcar)
+                .map_err(|e| warn!("parse {:?} failed: {:?}", outcar, e))
+                .ok();
+            (mp, outcar::parse_scf_counts(&outcar)?)
+        } else {
+            warn!("no OUTCAR in {:?}", dir);
+            (None, vec![])
+        };
+        let mol = ["CONTCAR", "POSCAR"]
+            .iter()
+            .map(|x| dir.join(x))
+            .filter(|f| f.exists())
+            .find_map(|f| Molecule::from_file(&f).ok());
+
+        Ok(Self {
+            incar,
+            mp,
+            mol,
+            scf_counts,
+        })
+    }
+}
+
+/// Return INCAR tags with different values in `a` and `b`.
+fn diff_incar(
+    a: &BTreeMap<String, String>,
+    b: &BTreeMap<String, String>,
+) -> Vec<(String, Option<String>, Option<String>)> {
+    let mut tags: Vec<_> = a.keys().chain(b.keys()).collect();
+    tags.sort();
+    tags.dedup();
+    tags.into_iter()
+        .filter_map(|tag| {
+            let (va, vb) = (a.get(tag), b.get(tag));
+            // NOTE: VASP tags are case insensitive, e.g. .TRUE. vs .true.
+            let same = va.map(|x| x.to_uppercase()) == vb.map(|x| x.to_uppercase());
+            (!same).then(|| (tag.to_owned(), va.cloned(), vb.cloned()))
+        })
+        .collect()
+}
+
+/// Return displacements in Å of atoms from `mol1` to `mol2`. For periodic
+/// structures, the minimum image convention is applied using the lattice
+/// of `mol1`.
+pub(crate) fn displacements(mol1: &Molecule, mol2: &Molecule) -> Vec<[f64; 3]> {
+    match (mol1.get_lattice(), mol2.get_scaled_positions()) {
+        (Some(lat), Some(fracs2)) => {
+            let [va, vb, vc] = lat.vectors();
+            let fracs1 = mol1.get_scaled_positions().unwrap();
+            fracs1
+                .zip(fracs2)
+                .map(|(p1, p2)| {
+                    let mut d = [0.0; 3];
+                    for i in 0..3 {
+                        let df = p2[i] - p1[i];
+                        let df = df - df.round();
+                        for j in 0..3 {
+                            d[j] += df * [va, vb, vc][i][j];
+                        }
+                    }
+                    d
+                })
+                .collect()
+        }
+        _ => mol1
+            .positions()
+            .zip(mol2.positions())
+            .map(|(p1, p2)| [p2[0] - p1[0], p2[1] - p1[1], p2[2] - p1[2]])
+            .collect(),
+    }
+}
+
+/// Return the RMSD in Å between positions of `mol1` and `mol2` without
+/// alignment. For periodic structures, the minimum image convention is
+/// applied using the lattice of `mol1`.
+fn rmsd(mol1: &Molecule, mol2: &Molecule) -> Result<f64> {
+    ensure!(mol1.natoms() == mol2.natoms(), "different number of atoms");
+    ensure!(mol1.natoms() > 0, "no atoms");
+    ensure!(
+        mol1.symbols().zip(mol2.symbols()).all(|(a, b)| a == b),
+        "different element symbols"
+    );
+
+    let displacements = displacements(mol1, mol2);
+    let msd = displacements.iter().flatten().map(|x| x * x).sum::<f64>() / displacements.len() as f64;
+    Ok(msd.sqrt())
+}
+
+fn format_opt<T: std::fmt::Display>(x: Option<T>) -> String {
+    x.map(|x| x.to_string()).unwrap_or("--".into())
+}
+
+/// Compare two VASP run directories `dir_a` and `dir_b`, and print a concise
+/// report of differences in INCAR, final energy/forces, final structure and
+/// SCF behaviour.
+pub fn compare_runs(dir_a: &Path, dir_b: &Path) -> Result<()> {
+    let a = RunData::load(dir_a)?;
+    let b = RunData::load(dir_b)?;
+
+    println!("A: {:?}", dir_a);
+    println!("B: {:?}", dir_b);
+
+    println!("\n== INCAR ==");
+    let diffs = diff_incar(&a.incar, &b.incar);
+    if diffs.is_empty() {
+        println!("identical");
+    }
+    for (tag, va, vb) in diffs {
+        println!("{:<12} A: {:<20} B: {}", tag, format_opt(va), format_opt(vb));
+    }
+
+    println!("\n== Final energy and forces ==");
+    let ea = a.mp.as_ref().and_then(|mp| mp.get_energy());
+    let eb = b.mp.as_ref().and_then(|mp| mp.get_energy());
+    println!(
+        "energy (eV)  A: {:<20} B: {}",
+        format_opt(ea.map(|e| format!("{:.6}", e))),
+        format_opt(eb.map(|e| format!("{:.6}", e)))
+    );
+    if let (Some(ea), Some(eb)) = (ea, eb) {
+        println!("energy difference (B - A): {:.6} eV", eb - ea);
+    }
+    let fa = a.mp.as_ref().and_then(|mp| mp.get_forces());
+    let fb = b.mp.as_ref().and_then(|mp| mp.get_forces());
+    // NOTE: exclude atoms fixed in selective dynamics as VASP does
+    let frozen_a = a.mol.as_ref().map(forces::frozen_flags);
+    let frozen_b = b.mol.as_ref().map(forces::frozen_flags);
+    println!(
+        "fmax (eV/Å)  A: {:<20} B: {}",
+        format_opt(fa.map(|f| format!("{:.6}", forces::fmax(f, frozen_a.as_deref())))),
+        format_opt(fb.map(|f| format!("{:.6}", forces::fmax(f, frozen_b.as_deref()))))
+    );
+    match (fa, fb) {
+        (Some(fa), Some(fb)) if fa.len() == fb.len() => {
+            let df: Vec<_> = fa
+                .iter()
+                .zip(fb)
+                .map(|(x, y)| [y[0] - x[0], y[1] - x[1], y[2] - x[2]])
+                .collect();
+            println!("max force difference: {:.6} eV/Å", forces::fmax(&df, None));
+        }
+        (Some(_), Some(_)) => println!("forces not comparable: different number of atoms"),
+        _ => {}
+    }
+
+    println!("\n== Final structure ==");
+    match (&a.mol, &b.mol) {
+        (Some(ma), Some(mb)) => match rmsd(ma, mb) {
+            Ok(x) => println!("RMSD: {:.4} Å ({} atoms)", x, ma.natoms()),
+            Err(e) => println!("structures not comparable: {}", e),
+        },
+        _ => println!("no CONTCAR or POSCAR found"),
+    }
+
+    println!("\n== SCF ==");
+    for (label, run) in [("A", &a), ("B", &b)] {
+        let n = run.scf_counts.len();
+        let total: usize = run.scf_counts.iter().sum();
+        let max = run.scf_counts.iter().max().copied();
+        let nelm = run.incar.get("NELM").and_then(|x| x.parse::<usize>().ok()).unwrap_or(60);
+        let nhit = run.scf_counts.iter().filter(|&&x| x >= nelm).count();
+        println!(
+            "{}: ionic steps: {:<4} total SCF: {:<6} max SCF: {:<4} steps reaching NELM ({}): {}",
+            label,
+            n,
+            total,
+            format_opt(max),
+            nelm,
+            nhit
+        );
+    }
+
+    Ok(())
+}
+// 6f76beda ends here
+
+// [[file:../../vasp-tools.note::b1dd5fd0][b1dd5fd0]]
+#[test]
+fn test_compare_runs() -> Result<()> {
+    use gosh::gchemol::{Atom, Lattice};
+
+    let a: BTreeMap<_, _> = [("ENCUT", "400"), ("LREAL", ".FALSE."), ("ISPIN", "2")]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    let b: BTreeMap<_, _> = [("ENCUT", "500"), ("LREAL", ".false."), ("NELM", "100")]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    let diffs = diff_incar(&a, &b);
+    let tags: Vec<_> = diffs.iter().map(|(tag, _, _)| tag.as_str()).collect();
+    assert_eq!(tags, ["ENCUT", "ISPIN", "NELM"]);
+
+    let mut mol1 = Molecule::from_atoms(vec![Atom::new("C", [0.1, 0.0, 0.0]), Atom::new("O", [1.0, 1.0, 1.0])]);
+    let mut mol2 = Molecule::from_atoms(vec![Atom::new("C", [9.9, 0.0, 0.0]), Atom::new("O", [1.0, 1.0, 1.0])]);
+    assert_relative_eq!(rmsd(&mol1, &mol2)?, (9.8f64.powi(2) / 2.0).sqrt(), epsilon = 1e-6);
+    // minimum image convention
+    let lat = Lattice::new([[10.0, 0.0, 0.0], [0.0, 10.0, 0.0], [0.0, 0.0, 10.0]]);
+    mol1.set_lattice(lat.clone());
+    mol2.set_lattice(lat);
+    assert_relative_eq!(rmsd(&mol1, &mol2)?, (0.2f64.powi(2) / 2.0).sqrt(), epsilon = 1e-6);
+
+    Ok(())
+}
+// b1dd5fd0 ends here
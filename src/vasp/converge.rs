@@ -0,0 +1,316 @@
+// [[file:../../vasp-tools.note::664645df][664645df]]
+use super::*;
+
+use super::batch::{run_batch, BatchOptions, JobResult, JobStatus};
+// 664645df ends here
+
+// [[file:../../vasp-tools.note::51beaab5][51beaab5]]
+/// The input files copied from template directory into each run directory
+const INPUT_FILES: &[&str] = &["INCAR", "POSCAR", "POTCAR", "KPOINTS"];
+
+/// Return the Gamma-centered k-mesh for lattice `vectors` (Å), following
+/// the fully automatic scheme of VASP with length parameter `length` (Å):
+/// N_i = max(1, int(length * |b_i| + 0.5)).
+pub fn kmesh_from_length(vectors: &[[f64; 3]; 3], length: f64) -> [usize; 3] {
+    let cross = |a: &[f64; 3], b: &[f64; 3]| {
+        [
+            a[1] * b[2] - a[2] * b[1],
+            a[2] * b[0] - a[0] * b[2],
+            a[0] * b[1] - a[1] * b[0],
+        ]
+    };
+    let norm = |v: [f64; 3]| (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt();
+    let [a, b, c] = vectors;
+    let bc = cross(b, c);
+    let volume = (a[0] * bc[0] + a[1] * bc[1] + a[2] * bc[2]).abs();
+    // the lengths of reciprocal lattice vectors without the factor of 2π
+    let widths = [norm(bc), norm(cross(c, a)), norm(cross(a, b))];
+    widths.map(|w| ((length * w / volume + 0.5).floor() as usize).max(1))
+}
+
+/// Format Gamma-centered k-mesh `mesh` as KPOINTS file.
+pub fn format_kpoints(mesh: [usize; 3]) -> String {
+    format!(
+        "Automatic mesh generated by vasp-tools\n0\nGamma\n{} {} {}\n0 0 0\n",
+        mesh[0], mesh[1], mesh[2]
+    )
+}
+
+/// Prepare run directory `dir` with input files from `template`, replacing
+/// ENCUT in INCAR and the k-mesh in KPOINTS if not None.
+pub(crate) fn prepare_variant(template: &Path, dir: &Path, encut: Option<f64>, kmesh: Option<[usize; 3]>) -> Result<()> {
+    std::fs::create_dir_all(dir).with_context(|| format!("create directory {:?}", dir))?;
+    for name in INPUT_FILES {
+        let src = template.join(name);
+        if src.exists() {
+            let dst = dir.join(name);
+            std::fs::copy(&src, &dst).with_context(|| format!("copy {:?} to {:?}", src, dst))?;
+        } else {
+            // KPOINTS is optional when KSPACING is set in INCAR
+            ensure!(*name == "KPOINTS", "missing input file {:?}", src);
+        }
+    }
+    if let Some(encut) = encut {
+        let incar = dir.join("INCAR");
+        let param = format!("ENCUT = {}", encut);
+        let txt = incar::update_with_mandatory_params(&incar, &[param.as_str()])?;
+        gut::fs::write_to_file(&incar, &txt)?;
+    }
+    if let Some(mesh) = kmesh {
+        gut::fs::write_to_file(dir.join("KPOINTS"), &format_kpoints(mesh))?;
+    }
+    Ok(())
+}
+
+/// One tested setting in convergence test
+#[derive(Debug, Clone)]
+pub struct ConvergePoint {
+    /// The tested setting, e.g. "ENCUT = 400" or "KPOINTS = 4x4x4"
+    pub setting: String,
+    /// The value for plotting: ENCUT in eV or the number of k-points
+    pub value: f64,
+    /// The energy in eV, None if the calculation failed
+    pub energy: Option<f64>,
+    pub fmax: Option<f64>,
+}
+
+impl ConvergePoint {
+    fn new(setting: String, value: f64, result: &JobResult) -> Self {
+        let ok = matches!(result.status, JobStatus::Converged | JobStatus::Finished);
+        if !ok {
+            warn!("{}: {:?} ({})", setting, result.status, result.message);
+        }
+        Self {
+            setting,
+            value,
+            energy: result.energy.filter(|_| ok),
+            fmax: result.fmax.filter(|_| ok),
+        }
+    }
+}
+
+/// Return the index of the first point from which all points agree with
+/// the last one (the most accurate setting) within `energy_tol` in eV/atom,
+/// and within `force_tol` in eV/Å for max force if not None. Return None if
+/// less than two points or any later point failed.
+pub fn find_converged(points: &[ConvergePoint], natoms: usize, energy_tol: f64, force_tol: Option<f64>) -> Option<usize> {
+    if points.len() < 2 {
+        return None;
+    }
+    let last = points.last()?;
+    let (eref, fref) = (last.energy?, last.fmax);
+    let within = |p: &ConvergePoint| -> bool {
+        let energy_ok = p.energy.map_or(false, |e| (e - eref).abs() / natoms as f64 <= energy_tol);
+        let force_ok = match (force_tol, p.fmax, fref) {
+            (None, _, _) => true,
+            (Some(tol), Some(f), Some(fref)) => (f - fref).abs() <= tol,
+            _ => false,
+        };
+        energy_ok && force_ok
+    };
+    let mut converged = points.len() - 1;
+    while converged > 0 && within(&points[converged - 1]) {
+        converged -= 1;
+    }
+    Some(converged)
+}
+
+/// Options for convergence test of ENCUT and k-mesh
+#[derive(Debug, Clone)]
+pub struct ConvergeOptions {
+    /// The tested ENCUT values in eV, in increasing order
+    pub encuts: Vec<f64>,
+    /// The tested length parameters (Å) for k-mesh, in increasing order
+    pub kpoints_lengths: Vec<f64>,
+    /// The energy tolerance in eV/atom
+    pub energy_tol: f64,
+    /// The tolerance of max force in eV/Å
+    pub force_tol: Option<f64>,
+    /// Run calculations in sub-directories of this directory
+    pub output_dir: PathBuf,
+    pub batch: BatchOptions,
+}
+
+impl Default for ConvergeOptions {
+    fn default() -> Self {
+        Self {
+            encuts: vec![],
+            kpoints_lengths: vec![],
+            energy_tol: 1e-3,
+            force_tol: None,
+            output_dir: "converge".into(),
+            batch: BatchOptions {
+                task: Some(VaspTask::SinglePoint),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+/// The results of convergence test
+#[derive(Debug, Clone, Default)]
+pub struct ConvergeReport {
+    pub natoms: usize,
+    pub encut_points: Vec<ConvergePoint>,
+    /// The converged ENCUT in eV
+    pub encut: Option<f64>,
+    pub kmesh_points: Vec<ConvergePoint>,
+    /// The converged k-mesh
+    pub kmesh: Option<[usize; 3]>,
+}
+
+/// Run single-point calculations of `program` at increasing ENCUT, and
+/// then at increasing k-mesh densities with the converged ENCUT, using
+/// input files in `template` directory.
+pub fn run_convergence(program: &Path, template: &Path, opts: &ConvergeOptions) -> Result<ConvergeReport> {
+    ensure!(
+        !opts.encuts.is_empty() || !opts.kpoints_lengths.is_empty(),
+        "no ENCUT or k-mesh to test"
+    );
+    let poscar = gut::fs::read_file(template.join("POSCAR"))?;
+    let lines: Vec<_> = poscar.lines().collect();
+    let (_, natoms) = poscar::locate_positions(&lines)?;
+    let mut report = ConvergeReport {
+        natoms,
+        ..Default::default()
+    };
+
+    let mut encut = None;
+    if !opts.encuts.is_empty() {
+        info!("test convergence of ENCUT: {:?}", opts.encuts);
+        let dirs: Vec<PathBuf> = opts.encuts.iter().map(|e| opts.output_dir.join(format!("encut-{}", e))).collect();
+        for (dir, &e) in dirs.iter().zip(&opts.encuts) {
+            prepare_variant(template, dir, Some(e), None)?;
+        }
+        let results = run_batch(program, &dirs, &opts.batch)?;
+        report.encut_points = opts
+            .encuts
+            .iter()
+            .zip(&results)
+            .map(|(&e, r)| ConvergePoint::new(format!("ENCUT = {}", e), e, r))
+            .collect();
+        report.encut = find_converged(&report.encut_points, natoms, opts.energy_tol, opts.force_tol).map(|i| opts.encuts[i]);
+        encut = report.encut.or_else(|| {
+            warn!("ENCUT not converged, use the largest one for k-mesh test");
+            opts.encuts.last().copied()
+        });
+    }
+
+    if !opts.kpoints_lengths.is_empty() {
+        let vectors = poscar::read_lattice_vectors(&lines)?;
+        // different lengths may lead to the same mesh
+        let mut meshes: Vec<[usize; 3]> = vec![];
+        for &l in &opts.kpoints_lengths {
+            let mesh = kmesh_from_length(&vectors, l);
+            if !meshes.contains(&mesh) {
+                meshes.push(mesh);
+            }
+        }
+        info!("test convergence of k-mesh: {:?}", meshes);
+        let label = |m: &[usize; 3]| format!("{}x{}x{}", m[0], m[1], m[2]);
+        let dirs: Vec<PathBuf> = meshes.iter().map(|m| opts.output_dir.join(format!("kmesh-{}", label(m)))).collect();
+        for (dir, &m) in dirs.iter().zip(&meshes) {
+            prepare_variant(template, dir, encut, Some(m))?;
+        }
+        let results = run_batch(program, &dirs, &opts.batch)?;
+        report.kmesh_points = meshes
+            .iter()
+            .zip(&results)
+            .map(|(m, r)| ConvergePoint::new(format!("KPOINTS = {}", label(m)), (m[0] * m[1] * m[2]) as f64, r))
+            .collect();
+        report.kmesh = find_converged(&report.kmesh_points, natoms, opts.energy_tol, opts.force_tol).map(|i| meshes[i]);
+    }
+
+    Ok(report)
+}
+
+/// Format convergence test `points` as a table, with energy differences
+/// per atom relative to the last point. The `converged` point is marked.
+pub fn format_convergence(points: &[ConvergePoint], natoms: usize, converged: Option<usize>) -> String {
+    let eref = points.last().and_then(|p| p.energy);
+    let fmt = |x: Option<f64>, prec: usize| x.map(|x| format!("{:.*}", prec, x)).unwrap_or("--".into());
+    let mut txt = format!(
+        "{:<22} {:>18} {:>16} {:>12}\n",
+        "setting", "energy (eV)", "dE (meV/atom)", "fmax (eV/Å)"
+    );
+    for (i, p) in points.iter().enumerate() {
+        let de = p.energy.zip(eref).map(|(e, eref)| (e - eref) / natoms as f64 * 1000.0);
+        let mark = if Some(i) == converged { " *" } else { "" };
+        txt += &format!(
+            "{:<22} {:>18} {:>16} {:>12}{}\n",
+            p.setting,
+            fmt(p.energy, 8),
+            fmt(de, 3),
+            fmt(p.fmax, 6),
+            mark
+        );
+    }
+    txt
+}
+
+/// Plot energy differences per atom of convergence test `points` as ASCII
+/// text.
+pub fn plot_convergence(points: &[ConvergePoint], natoms: usize, title: &str, xlabel: &str) -> Result<String> {
+    use crate::plot::AsciiPlot;
+
+    let eref = points.last().and_then(|p| p.energy).context("the last calculation failed")?;
+    let (x, y): (Vec<_>, Vec<_>) = points
+        .iter()
+        .filter_map(|p| Some((p.value, (p.energy? - eref) / natoms as f64 * 1000.0)))
+        .unzip();
+    let mut plot = AsciiPlot::new();
+    plot.set_title(title);
+    plot.set_xlabel(xlabel);
+    plot.set_ylabel("dE (meV/atom)");
+    plot.plot(&x, &y)
+}
+// 51beaab5 ends here
+
+// [[file:../../vasp-tools.note::4b4e3be7][4b4e3be7]]
+#[test]
+fn test_converge_kmesh() -> Result<()> {
+    let cubic = [[4.0, 0.0, 0.0], [0.0, 4.0, 0.0], [0.0, 0.0, 8.0]];
+    assert_eq!(kmesh_from_length(&cubic, 20.0), [5, 5, 3]);
+    assert_eq!(kmesh_from_length(&cubic, 1.0), [1, 1, 1]);
+    assert!(format_kpoints([5, 5, 3]).contains("\nGamma\n5 5 3\n"));
+
+    let tdir = tempfile::tempdir()?;
+    let template = tdir.path().join("template");
+    std::fs::create_dir(&template)?;
+    for name in ["INCAR", "POSCAR", "POTCAR"] {
+        gut::fs::write_to_file(template.join(name), "ENCUT = 300\nISMEAR = 0\n")?;
+    }
+    let dir = tdir.path().join("encut-400");
+    prepare_variant(&template, &dir, Some(400.0), Some([2, 2, 1]))?;
+    let tags = incar::parse_tags(&dir.join("INCAR"))?;
+    assert_eq!(tags["ENCUT"], "400");
+    assert_eq!(tags["ISMEAR"], "0");
+    assert!(gut::fs::read_file(dir.join("KPOINTS"))?.contains("2 2 1"));
+    std::fs::remove_file(template.join("POTCAR"))?;
+    assert!(prepare_variant(&template, &dir, None, None).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_converge_find() {
+    let points: Vec<_> = [-10.0, -10.2, -10.205, -10.206]
+        .iter()
+        .map(|&e| ConvergePoint {
+            setting: String::new(),
+            value: 0.0,
+            energy: Some(e),
+            fmax: Some(0.1),
+        })
+        .collect();
+    // 2 atoms: 0.003 eV/atom for the second point
+    assert_eq!(find_converged(&points, 2, 1e-3, None), Some(2));
+    assert_eq!(find_converged(&points, 2, 5e-3, None), Some(1));
+    assert_eq!(find_converged(&points, 2, 1e-3, Some(0.01)), Some(2));
+    assert_eq!(find_converged(&points[..1], 2, 1e-3, None), None);
+
+    let s = format_convergence(&points, 2, Some(2));
+    assert_eq!(s.lines().count(), 5);
+    assert!(s.lines().nth(3).unwrap().ends_with('*'));
+}
+// 4b4e3be7 ends here
@@ -0,0 +1,106 @@
+// [[file:../../vasp-tools.note::dee6bb91][dee6bb91]]
+use super::*;
+// dee6bb91 ends here
+
+// [[file:../../vasp-tools.note::f4bfddd7][f4bfddd7]]
+/// The number of last lines of OSZICAR kept in crash bundle
+const OSZICAR_TAIL_LINES: usize = 50;
+
+/// What is known about a crashed VASP process
+#[derive(Debug, Clone, Default)]
+pub struct CrashInfo {
+    /// The last payload written into stdin
+    pub input: String,
+    /// The last lines of stdout
+    pub stdout: Vec<String>,
+    /// The last lines of stderr
+    pub stderr: Vec<String>,
+    /// The exit code, None if killed by signal
+    pub code: Option<i32>,
+    /// The signal killed VASP
+    pub signal: Option<i32>,
+}
+
+impl CrashInfo {
+    fn exit_status(&self) -> String {
+        let fmt = |x: Option<i32>| x.map(|x| x.to_string()).unwrap_or("--".into());
+        format!(
+            "time: {}\nexit code: {}\nsignal: {}\n",
+            chrono::Local::now().to_rfc3339(),
+            fmt(self.code),
+            fmt(self.signal)
+        )
+    }
+}
+
+/// Collect diagnostic files of VASP crashed in `wrk_dir` into a timestamped
+/// directory `crash_%Y%m%d_%H%M%S` in it: the last stdin payload, the last
+/// lines of stdout/stderr, exit status, INCAR and the tail of OSZICAR.
+/// Missing input files are skipped. Return the bundle directory.
+pub fn write_crash_bundle(wrk_dir: &Path, info: &CrashInfo) -> Result<PathBuf> {
+    let stamp = chrono::Local::now().format("crash_%Y%m%d_%H%M%S").to_string();
+    // NOTE: VASP could crash more than once in one second when respawned
+    let dir = (0..)
+        .map(|i| match i {
+            0 => wrk_dir.join(&stamp),
+            _ => wrk_dir.join(format!("{}_{}", stamp, i)),
+        })
+        .find(|d| !d.exists())
+        .unwrap();
+    std::fs::create_dir_all(&dir).with_context(|| format!("create crash bundle dir {:?}", dir))?;
+
+    let join_lines = |lines: &[String]| lines.iter().map(|l| format!("{}\n", l)).collect::<String>();
+    gut::fs::write_to_file(dir.join("stdin.txt"), &info.input)?;
+    gut::fs::write_to_file(dir.join("stdout.txt"), &join_lines(&info.stdout))?;
+    gut::fs::write_to_file(dir.join("stderr.txt"), &join_lines(&info.stderr))?;
+    gut::fs::write_to_file(dir.join("exit_status.txt"), &info.exit_status())?;
+
+    let incar = wrk_dir.join("INCAR");
+    if incar.exists() {
+        std::fs::copy(&incar, dir.join("INCAR")).with_context(|| format!("copy {:?}", incar))?;
+    }
+    let oszicar = wrk_dir.join("OSZICAR");
+    if oszicar.exists() {
+        let s = gut::fs::read_file(&oszicar)?;
+        let lines: Vec<_> = s.lines().collect();
+        let tail = &lines[lines.len().saturating_sub(OSZICAR_TAIL_LINES)..];
+        gut::fs::write_to_file(dir.join("OSZICAR.tail"), &format!("{}\n", tail.join("\n")))?;
+    }
+
+    Ok(dir)
+}
+// f4bfddd7 ends here
+
+// [[file:../../vasp-tools.note::e8a01799][e8a01799]]
+#[test]
+fn test_crash_bundle() -> Result<()> {
+    let tdir = tempfile::tempdir()?;
+    let wrk = tdir.path();
+    gut::fs::write_to_file(wrk.join("INCAR"), "ENCUT = 400\n")?;
+    let oszicar: String = (1..=60).map(|i| format!("line {}\n", i)).collect();
+    gut::fs::write_to_file(wrk.join("OSZICAR"), &oszicar)?;
+
+    let info = CrashInfo {
+        input: "0.0 0.0 0.0\n".into(),
+        stdout: vec!["POSITIONS: reading from stdin".into()],
+        stderr: vec!["forrtl: severe (174): SIGSEGV".into()],
+        code: None,
+        signal: Some(11),
+    };
+    let dir = write_crash_bundle(wrk, &info)?;
+    assert!(dir.file_name().unwrap().to_string_lossy().starts_with("crash_"));
+    assert_eq!(gut::fs::read_file(dir.join("stdin.txt"))?, info.input);
+    assert_eq!(gut::fs::read_file(dir.join("INCAR"))?, "ENCUT = 400\n");
+    assert!(gut::fs::read_file(dir.join("stderr.txt"))?.contains("SIGSEGV"));
+    assert!(gut::fs::read_file(dir.join("exit_status.txt"))?.contains("signal: 11"));
+    let tail = gut::fs::read_file(dir.join("OSZICAR.tail"))?;
+    assert_eq!(tail.lines().count(), 50);
+    assert_eq!(tail.lines().next(), Some("line 11"));
+
+    // another crash in the same second
+    let dir2 = write_crash_bundle(wrk, &info)?;
+    assert_ne!(dir, dir2);
+
+    Ok(())
+}
+// e8a01799 ends here
@@ -0,0 +1,359 @@
+// [[file:../../vasp-tools.note::6d4b91cd][6d4b91cd]]
+use super::*;
+
+use gosh::gchemol::prelude::*;
+use gosh::gchemol::{Lattice, Molecule};
+use gosh::model::ModelProperties;
+// 6d4b91cd ends here
+
+// [[file:../../vasp-tools.note::f4e49fc2][f4e49fc2]]
+/// One ionic step of MD run from OUTCAR
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct MdFrame {
+    /// The ionic step (starting from 1)
+    pub step: usize,
+    /// The lattice vectors in Å
+    pub cell: Option<[[f64; 3]; 3]>,
+    /// Cartesian positions in Å
+    pub positions: Vec<[f64; 3]>,
+    /// Forces in eV/Å
+    pub forces: Vec<[f64; 3]>,
+    /// The free energy (TOTEN) in eV
+    pub energy: f64,
+    /// The stress tensor in kB (XX, YY, ZZ, XY, YZ, ZX) as in OUTCAR, if
+    /// available (ISIF >= 1)
+    pub stress: Option<[f64; 6]>,
+}
+
+fn parse_xyz(line: &str, skip: usize) -> Option<[f64; 3]> {
+    let v: Vec<f64> = line.split_whitespace().skip(skip).take(3).map(|x| x.parse().ok()).collect::<Option<_>>()?;
+    if v.len() == 3 {
+        Some([v[0], v[1], v[2]])
+    } else {
+        None
+    }
+}
+
+/// Return the inverse of 3x3 matrix `m`, or None if singular.
+fn inverse3(m: &[[f64; 3]; 3]) -> Option<[[f64; 3]; 3]> {
+    let [a, b, c] = m;
+    let det = a[0] * (b[1] * c[2] - b[2] * c[1]) - a[1] * (b[0] * c[2] - b[2] * c[0]) + a[2] * (b[0] * c[1] - b[1] * c[0]);
+    if det.abs() < 1e-12 {
+        return None;
+    }
+    let mut inv = [[0.0; 3]; 3];
+    for i in 0..3 {
+        for j in 0..3 {
+            // cofactor of element (j, i)
+            let (r1, r2) = ((j + 1) % 3, (j + 2) % 3);
+            let (c1, c2) = ((i + 1) % 3, (i + 2) % 3);
+            inv[i][j] = (m[r1][c1] * m[r2][c2] - m[r1][c2] * m[r2][c1]) / det;
+        }
+    }
+    Some(inv)
+}
+
+/// Parse positions, forces and energy of all ionic steps in OUTCAR text
+/// `s`. Incomplete last step is ignored.
+fn parse_md_frames_str(s: &str) -> Result<Vec<MdFrame>> {
+    let mut frames = vec![];
+    let mut cell = None;
+    let mut stress = None;
+    let mut pending: Option<(Vec<[f64; 3]>, Vec<[f64; 3]>, Option<[f64; 6]>)> = None;
+    let mut lines = s.lines();
+    while let Some(line) = lines.next() {
+        if line.contains("direct lattice vectors") {
+            let v: Vec<_> = lines.by_ref().take(3).filter_map(|l| parse_xyz(l, 0)).collect();
+            ensure!(v.len() == 3, "invalid lattice vectors in OUTCAR");
+            cell = Some([v[0], v[1], v[2]]);
+        } else if line.trim_start().starts_with("in kB") {
+            // the stress block comes before positions and forces
+            stress = Some(outcar::parse_stress_line(line)?);
+        } else if line.contains("POSITION") && line.contains("TOTAL-FORCE") {
+            // skip the line of dashes
+            lines.next();
+            let (mut positions, mut forces) = (vec![], vec![]);
+            for l in lines.by_ref().take_while(|l| !l.trim_start().starts_with("---")) {
+                let p = parse_xyz(l, 0).with_context(|| format!("invalid position line: {:?}", l))?;
+                let f = parse_xyz(l, 3).with_context(|| format!("invalid force line: {:?}", l))?;
+                positions.push(p);
+                forces.push(f);
+            }
+            pending = Some((positions, forces, stress.take()));
+        } else if line.contains("free  energy   TOTEN  =") {
+            if let Some((positions, forces, stress)) = pending.take() {
+                let energy = line
+                    .split_whitespace()
+                    .nth(4)
+                    .and_then(|x| x.parse().ok())
+                    .with_context(|| format!("invalid energy line: {:?}", line))?;
+                frames.push(MdFrame {
+                    step: frames.len() + 1,
+                    cell,
+                    positions,
+                    forces,
+                    energy,
+                    stress,
+                });
+            }
+        }
+    }
+    Ok(frames)
+}
+
+/// Parse all ionic steps of MD run in OUTCAR `f`.
+pub fn parse_md_frames(f: &Path) -> Result<Vec<MdFrame>> {
+    let s = gut::fs::read_file(f)?;
+    parse_md_frames_str(&s).with_context(|| format!("parse MD frames from {:?}", f))
+}
+
+/// Return the descriptor of `frame`: the histogram of interatomic
+/// distances below `cutoff` (Å) with minimum image convention, normalized
+/// by the number of atoms.
+fn distance_histogram(frame: &MdFrame, cutoff: f64, nbins: usize) -> Vec<f64> {
+    let mut hist = vec![0.0; nbins];
+    let n = frame.positions.len();
+    // Cartesian r = f0 a + f1 b + f2 c for lattice vectors a, b, c
+    let cell = frame.cell.and_then(|m| Some((m, inverse3(&m)?)));
+    for i in 0..n {
+        for j in i + 1..n {
+            let [xi, yi, zi] = frame.positions[i];
+            let [xj, yj, zj] = frame.positions[j];
+            let mut d = [xj - xi, yj - yi, zj - zi];
+            if let Some((m, inv)) = &cell {
+                let f: Vec<_> = (0..3)
+                    .map(|k| (0..3).map(|l| d[l] * inv[l][k]).sum::<f64>())
+                    .map(|x| x - x.round())
+                    .collect();
+                d = [0, 1, 2].map(|k| (0..3).map(|l| f[l] * m[l][k]).sum());
+            }
+            let r = (d[0] * d[0] + d[1] * d[1] + d[2] * d[2]).sqrt();
+            if r < cutoff {
+                hist[((r / cutoff) * nbins as f64) as usize] += 1.0;
+            }
+        }
+    }
+    hist.iter_mut().for_each(|x| *x /= n.max(1) as f64);
+    hist
+}
+
+/// Select `n` frames from `descriptors` by farthest point sampling,
+/// starting from the first one. Return indices in ascending order.
+pub fn farthest_point_sampling(descriptors: &[Vec<f64>], n: usize) -> Vec<usize> {
+    let distance = |a: &[f64], b: &[f64]| a.iter().zip(b).map(|(x, y)| (x - y).powi(2)).sum::<f64>().sqrt();
+    if descriptors.is_empty() || n == 0 {
+        return vec![];
+    }
+    let mut selected = vec![0];
+    let mut dmin: Vec<_> = descriptors.iter().map(|d| distance(d, &descriptors[0])).collect();
+    while selected.len() < n.min(descriptors.len()) {
+        let (k, _) = dmin
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(b.1))
+            .expect("non-empty descriptors");
+        selected.push(k);
+        for (d, x) in dmin.iter_mut().zip(descriptors) {
+            *d = d.min(distance(x, &descriptors[k]));
+        }
+    }
+    selected.sort_unstable();
+    selected
+}
+
+/// How frames are sampled
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Sampling {
+    /// Every n-th frame
+    Stride(usize),
+    /// Farthest point sampling of n frames on distance histograms
+    Fps(usize),
+}
+
+/// Options for sampling MD frames
+#[derive(Debug, Clone)]
+pub struct SampleOptions {
+    /// Skip the first frames for equilibration
+    pub skip: usize,
+    /// Only keep frames with energy (eV) in this window
+    pub energy_window: (Option<f64>, Option<f64>),
+    pub sampling: Sampling,
+    /// The cutoff of distance histogram for farthest point sampling (Å)
+    pub cutoff: f64,
+}
+
+impl Default for SampleOptions {
+    fn default() -> Self {
+        Self {
+            skip: 0,
+            energy_window: (None, None),
+            sampling: Sampling::Stride(1),
+            cutoff: 6.0,
+        }
+    }
+}
+
+/// Parse energy window "min:max" in eV, where either bound can be omitted.
+pub fn parse_energy_window(s: &str) -> Result<(Option<f64>, Option<f64>)> {
+    let (a, b) = s.split_once(':').with_context(|| format!("invalid energy window {:?}, expect min:max", s))?;
+    let parse = |x: &str| -> Result<Option<f64>> {
+        let x = x.trim();
+        if x.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(x.parse().with_context(|| format!("invalid energy window: {:?}", s))?))
+        }
+    };
+    Ok((parse(a)?, parse(b)?))
+}
+
+/// Sample `frames` following `opts`.
+pub fn sample_frames<'a>(frames: &'a [MdFrame], opts: &SampleOptions) -> Vec<&'a MdFrame> {
+    let (emin, emax) = opts.energy_window;
+    let candidates: Vec<_> = frames
+        .iter()
+        .skip(opts.skip)
+        .filter(|f| emin.map_or(true, |e| f.energy >= e) && emax.map_or(true, |e| f.energy <= e))
+        .collect();
+    match opts.sampling {
+        Sampling::Stride(n) => candidates.into_iter().step_by(n.max(1)).collect(),
+        Sampling::Fps(n) => {
+            let descriptors: Vec<_> = candidates.iter().map(|f| distance_histogram(f, opts.cutoff, 60)).collect();
+            farthest_point_sampling(&descriptors, n)
+                .into_iter()
+                .map(|i| candidates[i])
+                .collect()
+        }
+    }
+}
+
+/// The format of exported dataset
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DatasetFormat {
+    /// Extended XYZ, e.g. for NequIP or MACE
+    Extxyz,
+    /// JSON list of frames with symbols, cell, positions, forces and energy
+    Json,
+}
+
+impl std::str::FromStr for DatasetFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "extxyz" | "xyz" => Ok(Self::Extxyz),
+            "json" => Ok(Self::Json),
+            _ => bail!("unsupported dataset format: {:?}", s),
+        }
+    }
+}
+
+/// Format `frames` as dataset for ML training. Element symbols are taken
+/// from `mol`.
+pub fn format_dataset(mol: &Molecule, frames: &[&MdFrame], format: DatasetFormat) -> Result<String> {
+    let symbols: Vec<_> = mol.symbols().map(|x| x.to_owned()).collect();
+    for f in frames {
+        ensure!(
+            f.positions.len() == symbols.len(),
+            "frame {} has {} atoms, but the structure has {}",
+            f.step,
+            f.positions.len(),
+            symbols.len()
+        );
+    }
+    match format {
+        DatasetFormat::Extxyz => {
+            let mut txt = String::new();
+            for f in frames {
+                let mut mol = mol.clone();
+                mol.set_positions(f.positions.clone());
+                if let Some(cell) = f.cell {
+                    mol.set_lattice(Lattice::new(cell));
+                }
+                let mut mp = ModelProperties::default();
+                mp.set_energy(f.energy);
+                mp.set_forces(f.forces.clone());
+                txt += &crate::trajectory::format_extxyz_frame(&mol, &mp.into())?;
+            }
+            Ok(txt)
+        }
+        DatasetFormat::Json => {
+            #[derive(serde::Serialize)]
+            struct Record<'a> {
+                symbols: &'a [String],
+                #[serde(flatten)]
+                frame: &'a MdFrame,
+            }
+            let records: Vec<_> = frames.iter().map(|&frame| Record { symbols: &symbols, frame }).collect();
+            Ok(serde_json::to_string_pretty(&records)?)
+        }
+    }
+}
+// f4e49fc2 ends here
+
+// [[file:../../vasp-tools.note::fce09292][fce09292]]
+#[test]
+fn test_md_dataset() -> Result<()> {
+    let step = |x: f64, e: f64| {
+        format!(
+            "      direct lattice vectors                 reciprocal lattice vectors
+    10.000000000  0.000000000  0.000000000     0.100000000  0.000000000  0.000000000
+     0.000000000 10.000000000  0.000000000     0.000000000  0.100000000  0.000000000
+     0.000000000  0.000000000 10.000000000     0.000000000  0.000000000  0.100000000
+ POSITION                                       TOTAL-FORCE (eV/Angst)
+ -----------------------------------------------------------------------------------
+      0.00000      0.00000      0.00000         0.100000      0.000000      0.000000
+      {:.5}      0.00000      0.00000        -0.100000      0.000000      0.000000
+ -----------------------------------------------------------------------------------
+  free  energy   TOTEN  =       {:.8} eV
+",
+            x, e
+        )
+    };
+    let s: String = [(0.7, -1.0), (0.8, -2.0), (0.9, -3.0), (9.5, -4.0)]
+        .iter()
+        .map(|&(x, e)| step(x, e))
+        .collect();
+    let frames = parse_md_frames_str(&s)?;
+    assert_eq!(frames.len(), 4);
+    assert_eq!(frames[1].positions[1], [0.8, 0.0, 0.0]);
+    assert_eq!(frames[1].forces[0], [0.1, 0.0, 0.0]);
+    assert_eq!(frames[3].energy, -4.0);
+    assert_eq!(frames[3].cell.unwrap()[2][2], 10.0);
+
+    let opts = SampleOptions {
+        skip: 1,
+        sampling: Sampling::Stride(2),
+        ..Default::default()
+    };
+    let steps: Vec<_> = sample_frames(&frames, &opts).iter().map(|f| f.step).collect();
+    assert_eq!(steps, vec![2, 4]);
+    let opts = SampleOptions {
+        energy_window: parse_energy_window("-3.5:")?,
+        ..Default::default()
+    };
+    assert_eq!(sample_frames(&frames, &opts).len(), 3);
+
+    let opts = SampleOptions {
+        sampling: Sampling::Fps(2),
+        ..Default::default()
+    };
+    let selected = sample_frames(&frames, &opts);
+    assert_eq!(selected.len(), 2);
+    assert_eq!(selected[0].step, 1);
+    let descriptors = vec![vec![0.0], vec![0.1], vec![1.0], vec![0.5]];
+    assert_eq!(farthest_point_sampling(&descriptors, 3), vec![0, 2, 3]);
+    // minimum image convention
+    assert_eq!(distance_histogram(&frames[3], 1.0, 3), vec![0.0, 0.5, 0.0]);
+
+    let mol = Molecule::from_atoms(vec![gosh::gchemol::Atom::new("H", [0.0; 3]), gosh::gchemol::Atom::new("H", [1.0, 0.0, 0.0])]);
+    let selected: Vec<_> = frames.iter().collect();
+    let s = format_dataset(&mol, &selected, DatasetFormat::Extxyz)?;
+    assert_eq!(s.lines().count(), 16);
+    let s = format_dataset(&mol, &selected[..1], DatasetFormat::Json)?;
+    assert!(s.contains("\"symbols\""));
+    assert!(s.contains("\"energy\": -1.0"));
+
+    Ok(())
+}
+// fce09292 ends here
@@ -0,0 +1,317 @@
+// [[file:../../vasp-tools.note::92278a6f][92278a6f]]
+use super::*;
+
+use super::dataset::MdFrame;
+use std::collections::HashMap;
+// 92278a6f ends here
+
+// [[file:../../vasp-tools.note::24161adf][24161adf]]
+/// Frames of the same atoms in the same order, as one DeePMD system
+#[derive(Debug, Clone)]
+pub struct DpSystem {
+    /// The element symbols of atoms
+    pub symbols: Vec<String>,
+    pub frames: Vec<MdFrame>,
+}
+
+/// Parse key=value pairs in comment line of extxyz, with values optionally
+/// quoted.
+fn parse_extxyz_comment(line: &str) -> HashMap<String, String> {
+    let mut pairs = HashMap::new();
+    let mut rest = line.trim();
+    while let Some((key, tail)) = rest.split_once('=') {
+        let key = key.trim().to_owned();
+        let (value, tail) = if let Some(tail) = tail.strip_prefix('"') {
+            tail.split_once('"').unwrap_or((tail, ""))
+        } else {
+            tail.split_once(char::is_whitespace).unwrap_or((tail, ""))
+        };
+        pairs.insert(key, value.to_owned());
+        rest = tail.trim_start();
+    }
+    pairs
+}
+
+fn parse_floats(s: &str) -> Result<Vec<f64>> {
+    s.split_whitespace()
+        .map(|x| x.parse().with_context(|| format!("invalid float number: {:?}", x)))
+        .collect()
+}
+
+/// Parse frames in extxyz text `s` as written by `format_extxyz_frame`:
+/// species, positions and forces with energy, lattice and stress in the
+/// comment line.
+fn parse_extxyz_frames_str(s: &str) -> Result<Vec<(Vec<String>, MdFrame)>> {
+    let mut frames = vec![];
+    let mut lines = s.lines().filter(|l| !l.trim().is_empty());
+    while let Some(line) = lines.next() {
+        let natoms: usize = line.trim().parse().with_context(|| format!("invalid number of atoms: {:?}", line))?;
+        let comment = lines.next().context("no comment line in extxyz")?;
+        let pairs = parse_extxyz_comment(comment);
+        ensure!(
+            pairs.get("Properties").map(|x| x.as_str()) == Some("species:S:1:pos:R:3:forces:R:3"),
+            "unsupported extxyz properties: {:?}",
+            comment
+        );
+        let energy: f64 = pairs
+            .get("energy")
+            .context("no energy in extxyz")?
+            .parse()
+            .with_context(|| format!("invalid energy: {:?}", comment))?;
+        let cell = match pairs.get("Lattice") {
+            Some(v) => {
+                let v = parse_floats(v)?;
+                ensure!(v.len() == 9, "invalid lattice: {:?}", comment);
+                Some([[v[0], v[1], v[2]], [v[3], v[4], v[5]], [v[6], v[7], v[8]]])
+            }
+            None => None,
+        };
+        // convert back from ASE convention in eV/Å^3 to VASP stress in kB
+        let stress = match pairs.get("stress") {
+            Some(v) => {
+                let v = parse_floats(v)?;
+                ensure!(v.len() == 9, "invalid stress: {:?}", comment);
+                Some([v[0], v[4], v[8], v[1], v[5], v[2]].map(|x| -crate::units::ev_per_a3_to_kbar(x)))
+            }
+            None => None,
+        };
+
+        let (mut symbols, mut positions, mut forces) = (vec![], vec![], vec![]);
+        for _ in 0..natoms {
+            let l = lines.next().context("incomplete extxyz frame")?;
+            let symbol = l.split_whitespace().next().context("empty atom line")?;
+            let v = parse_floats(&l.trim_start()[symbol.len()..])?;
+            ensure!(v.len() == 6, "invalid atom line: {:?}", l);
+            symbols.push(symbol.to_owned());
+            positions.push([v[0], v[1], v[2]]);
+            forces.push([v[3], v[4], v[5]]);
+        }
+        let frame = MdFrame {
+            step: frames.len() + 1,
+            cell,
+            positions,
+            forces,
+            energy,
+            stress,
+        };
+        frames.push((symbols, frame));
+    }
+    Ok(frames)
+}
+
+/// Read all frames with element symbols from `f`: an extxyz trajectory
+/// (.xyz or .extxyz) written in interactive mode, or OUTCAR with POSCAR or
+/// CONTCAR next to it.
+pub fn read_frames(f: &Path) -> Result<Vec<(Vec<String>, MdFrame)>> {
+    use gosh::gchemol::prelude::*;
+
+    let ext = f.extension().and_then(|x| x.to_str()).unwrap_or_default();
+    if ext == "xyz" || ext == "extxyz" {
+        let s = gut::fs::read_file(f)?;
+        parse_extxyz_frames_str(&s).with_context(|| format!("parse extxyz frames from {:?}", f))
+    } else {
+        let mol = super::vibration::read_structure_for_outcar(f)?;
+        let symbols: Vec<_> = mol.symbols().map(|x| x.to_owned()).collect();
+        let frames = super::dataset::parse_md_frames(f)?;
+        Ok(frames.into_iter().map(|frame| (symbols.clone(), frame)).collect())
+    }
+}
+
+/// Group `frames` into systems of the same atoms, in order of first
+/// appearance.
+pub fn collect_systems(frames: Vec<(Vec<String>, MdFrame)>) -> Vec<DpSystem> {
+    let mut systems: Vec<DpSystem> = vec![];
+    for (symbols, frame) in frames {
+        match systems.iter_mut().find(|s| s.symbols == symbols) {
+            Some(s) => s.frames.push(frame),
+            None => systems.push(DpSystem {
+                symbols,
+                frames: vec![frame],
+            }),
+        }
+    }
+    systems
+}
+
+/// Return the type map shared by all `systems`: element symbols in order
+/// of first appearance, as in POSCAR species line.
+pub fn default_type_map(systems: &[DpSystem]) -> Vec<String> {
+    let mut type_map: Vec<String> = vec![];
+    for s in systems.iter().flat_map(|s| &s.symbols) {
+        if !type_map.contains(s) {
+            type_map.push(s.to_owned());
+        }
+    }
+    type_map
+}
+
+/// Format `data` of `shape` as NPY file (version 1.0) of little endian f64.
+fn format_npy(shape: &[usize], data: &[f64]) -> Vec<u8> {
+    assert_eq!(shape.iter().product::<usize>(), data.len(), "invalid npy shape");
+    let shape = match shape {
+        [n] => format!("({},)", n),
+        _ => format!("({})", shape.iter().map(|x| x.to_string()).collect::<Vec<_>>().join(", ")),
+    };
+    let mut header = format!("{{'descr': '<f8', 'fortran_order': False, 'shape': {}, }}", shape);
+    // the header is padded with spaces and ends with newline, so that the
+    // data is 64-byte aligned
+    let total = 10 + header.len() + 1;
+    header += &" ".repeat((64 - total % 64) % 64);
+    header += "\n";
+
+    let mut bytes = b"\x93NUMPY\x01\x00".to_vec();
+    bytes.extend((header.len() as u16).to_le_bytes());
+    bytes.extend(header.as_bytes());
+    for x in data {
+        bytes.extend(x.to_le_bytes());
+    }
+    bytes
+}
+
+fn format_raw(rows: &[Vec<f64>]) -> String {
+    rows.iter()
+        .map(|row| row.iter().map(|x| format!("{:.10e}", x)).collect::<Vec<_>>().join(" ") + "\n")
+        .collect()
+}
+
+/// Write `system` into directory `dir` in DeePMD-kit layout: type.raw,
+/// type_map.raw, box/coord/energy/force/virial in raw text, and in npy
+/// files of set.000, set.001, ... with at most `set_size` frames each.
+/// Virial is written only if stress is available in all frames.
+pub fn write_system(dir: &Path, system: &DpSystem, type_map: &[String], set_size: usize) -> Result<()> {
+    let types = system
+        .symbols
+        .iter()
+        .map(|s| {
+            type_map
+                .iter()
+                .position(|t| t == s)
+                .with_context(|| format!("element {} not found in type map {:?}", s, type_map))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let natoms = types.len();
+
+    let (mut boxes, mut coords, mut energies, mut forces, mut virials) = (vec![], vec![], vec![], vec![], vec![]);
+    for f in &system.frames {
+        ensure!(f.positions.len() == natoms, "frame {} has {} atoms, expect {}", f.step, f.positions.len(), natoms);
+        let cell = f.cell.with_context(|| format!("no lattice in frame {}, which is required by DeePMD", f.step))?;
+        boxes.push(cell.iter().flatten().copied().collect::<Vec<_>>());
+        coords.push(f.positions.iter().flatten().copied().collect::<Vec<_>>());
+        forces.push(f.forces.iter().flatten().copied().collect::<Vec<_>>());
+        energies.push(vec![f.energy]);
+        if let Some(stress) = f.stress {
+            let volume = super::eos::cell_volume(&cell);
+            virials.push(crate::ipi::vasp_stress_to_virial(stress, volume).to_vec());
+        }
+    }
+    let has_virial = virials.len() == system.frames.len();
+    if !has_virial && !virials.is_empty() {
+        warn!("stress is missing in some frames, virial will not be written");
+    }
+
+    std::fs::create_dir_all(dir).with_context(|| format!("create directory {:?}", dir))?;
+    let types_txt: String = types.iter().map(|t| format!("{}\n", t)).collect();
+    gut::fs::write_to_file(dir.join("type.raw"), &types_txt)?;
+    gut::fs::write_to_file(dir.join("type_map.raw"), &(type_map.join("\n") + "\n"))?;
+    let mut data = vec![
+        ("box", 9, &boxes),
+        ("coord", natoms * 3, &coords),
+        ("energy", 1, &energies),
+        ("force", natoms * 3, &forces),
+    ];
+    if has_virial {
+        data.push(("virial", 9, &virials));
+    }
+    for &(name, _, rows) in &data {
+        gut::fs::write_to_file(dir.join(format!("{}.raw", name)), &format_raw(rows))?;
+    }
+    for (i, k) in (0..system.frames.len()).step_by(set_size.max(1)).enumerate() {
+        let set_dir = dir.join(format!("set.{:03}", i));
+        std::fs::create_dir_all(&set_dir)?;
+        for &(name, ncols, rows) in &data {
+            let rows = &rows[k..(k + set_size.max(1)).min(rows.len())];
+            let values: Vec<_> = rows.iter().flatten().copied().collect();
+            let shape = if name == "energy" { vec![rows.len()] } else { vec![rows.len(), ncols] };
+            let f = set_dir.join(format!("{}.npy", name));
+            std::fs::write(&f, format_npy(&shape, &values)).with_context(|| format!("write {:?}", f))?;
+        }
+    }
+    Ok(())
+}
+
+/// Export frames in trajectory `files` (OUTCAR or extxyz) into `outdir`
+/// as DeePMD-kit systems sys.000, sys.001, ..., one for each set of atoms.
+/// The type map is derived from element symbols if `type_map` is empty.
+/// Return the systems written.
+pub fn export_deepmd(files: &[PathBuf], outdir: &Path, type_map: &[String], set_size: usize) -> Result<Vec<DpSystem>> {
+    let mut frames = vec![];
+    for f in files {
+        let new = read_frames(f)?;
+        info!("read {} frames from {:?}", new.len(), f);
+        frames.extend(new);
+    }
+    ensure!(!frames.is_empty(), "no frame found in {:?}", files);
+    let systems = collect_systems(frames);
+    let type_map = if type_map.is_empty() {
+        default_type_map(&systems)
+    } else {
+        type_map.to_vec()
+    };
+    for (i, s) in systems.iter().enumerate() {
+        write_system(&outdir.join(format!("sys.{:03}", i)), s, &type_map, set_size)?;
+    }
+    Ok(systems)
+}
+// 24161adf ends here
+
+// [[file:../../vasp-tools.note::97991d0f][97991d0f]]
+#[test]
+fn test_deepmd_export() -> Result<()> {
+    let npy = format_npy(&[2], &[1.0, 2.0]);
+    assert_eq!(&npy[..8], b"\x93NUMPY\x01\x00");
+    assert_eq!(npy.len(), 128 + 16);
+    assert_eq!(npy[127], b'\n');
+    assert_eq!(&npy[128..136], &1.0f64.to_le_bytes());
+    let header = String::from_utf8_lossy(&npy[10..128]).to_string();
+    assert!(header.contains("'shape': (2,)"));
+
+    let xyz = r#"2
+Lattice="10.0 0.0 0.0 0.0 10.0 0.0 0.0 0.0 10.0" Properties=species:S:1:pos:R:3:forces:R:3 energy=-3.50000000 stress="-0.1 0.0 0.0 0.0 -0.1 0.0 0.0 0.0 -0.1" pbc="T T T"
+O     0.0 0.0 0.0   0.1 0.0 0.0
+H     1.0 0.0 0.0  -0.1 0.0 0.0
+2
+Lattice="10.0 0.0 0.0 0.0 10.0 0.0 0.0 0.0 10.0" Properties=species:S:1:pos:R:3:forces:R:3 energy=-3.60000000 pbc="T T T"
+H     0.0 0.0 0.0   0.1 0.0 0.0
+H     1.0 0.0 0.0  -0.1 0.0 0.0
+"#;
+    let frames = parse_extxyz_frames_str(xyz)?;
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[0].0, vec!["O", "H"]);
+    assert_eq!(frames[1].1.energy, -3.6);
+    assert_eq!(frames[1].1.stress, None);
+    // -0.1 eV/Å^3 in ASE convention is 160.2 kB in VASP
+    let stress = frames[0].1.stress.unwrap();
+    assert_relative_eq!(stress[0], 160.21766208, epsilon = 1e-6);
+
+    let systems = collect_systems(frames);
+    assert_eq!(systems.len(), 2);
+    let type_map = default_type_map(&systems);
+    assert_eq!(type_map, vec!["O", "H"]);
+
+    let tdir = tempfile::tempdir()?;
+    write_system(tdir.path(), &systems[0], &type_map, 5000)?;
+    assert_eq!(gut::fs::read_file(tdir.path().join("type.raw"))?, "0\n1\n");
+    assert_eq!(gut::fs::read_file(tdir.path().join("type_map.raw"))?, "O\nH\n");
+    // virial = stress * volume = 0.1 eV/Å^3 * 1000 Å^3
+    let virial = gut::fs::read_file(tdir.path().join("virial.raw"))?;
+    let virial = parse_floats(&virial)?;
+    assert_relative_eq!(virial[0], 100.0, epsilon = 1e-6);
+    assert_relative_eq!(virial[4], 100.0, epsilon = 1e-6);
+    for name in ["box", "coord", "energy", "force", "virial"] {
+        assert!(tdir.path().join("set.000").join(format!("{}.npy", name)).exists());
+    }
+    assert!(write_system(tdir.path(), &systems[1], &["O".into()], 5000).is_err());
+
+    Ok(())
+}
+// 97991d0f ends here
@@ -0,0 +1,254 @@
+// [[file:../../vasp-tools.note::31bb7528][31bb7528]]
+use super::*;
+
+use super::outcar::ChargeCorrection;
+// 31bb7528 ends here
+
+// [[file:../../vasp-tools.note::e9794672][e9794672]]
+/// The local potential on real space grid from LOCPOT
+#[derive(Debug, Clone)]
+pub struct Locpot {
+    /// The lattice vectors in Å
+    pub vectors: [[f64; 3]; 3],
+    /// The number of grid points along each lattice vector
+    pub grid: [usize; 3],
+    /// The potential in eV, with the first index running fastest
+    pub values: Vec<f64>,
+}
+
+impl Locpot {
+    /// Read LOCPOT file `f`. Only the first block is read for spin
+    /// polarized calculation.
+    pub fn from_file(f: &Path) -> Result<Self> {
+        let s = gut::fs::read_file(f)?;
+        Self::from_str_(&s).with_context(|| format!("read LOCPOT from {:?}", f))
+    }
+
+    fn from_str_(s: &str) -> Result<Self> {
+        let lines: Vec<_> = s.lines().collect();
+        let (i, natoms) = poscar::locate_positions(&lines)?;
+        let vectors = poscar::read_lattice_vectors(&lines)?;
+        let mut rest = lines[i + natoms..].iter().skip_while(|line| line.trim().is_empty());
+        let line = rest.next().context("no grid dimensions")?;
+        let grid: Vec<usize> = line
+            .split_whitespace()
+            .map(|x| x.parse().with_context(|| format!("invalid grid dimensions: {:?}", line)))
+            .collect::<Result<_>>()?;
+        ensure!(grid.len() == 3, "invalid grid dimensions: {:?}", line);
+        let n = grid[0] * grid[1] * grid[2];
+        let values: Vec<f64> = rest
+            .flat_map(|line| line.split_whitespace())
+            .take(n)
+            .map(|x| x.parse().with_context(|| format!("invalid potential value: {:?}", x)))
+            .collect::<Result<_>>()?;
+        ensure!(values.len() == n, "expect {} grid values, got {}", n, values.len());
+        Ok(Self {
+            vectors,
+            grid: [grid[0], grid[1], grid[2]],
+            values,
+        })
+    }
+
+    /// Return the planar average of potential along lattice vector `axis`
+    /// (0, 1 or 2).
+    pub fn planar_average(&self, axis: usize) -> Vec<f64> {
+        let [nx, ny, _] = self.grid;
+        let mut avg = vec![0.0; self.grid[axis]];
+        for (k, v) in self.values.iter().enumerate() {
+            let idx = [k % nx, (k / nx) % ny, k / (nx * ny)];
+            avg[idx[axis]] += v;
+        }
+        let m = (self.values.len() / self.grid[axis]) as f64;
+        avg.iter_mut().for_each(|x| *x /= m);
+        avg
+    }
+}
+
+/// Parse lattice vector along which the potential is averaged: x, y or z
+/// (or a, b, c).
+pub fn parse_axis(s: &str) -> Result<usize> {
+    match s.to_lowercase().as_str() {
+        "x" | "a" => Ok(0),
+        "y" | "b" => Ok(1),
+        "z" | "c" => Ok(2),
+        _ => bail!("invalid axis: {:?}", s),
+    }
+}
+
+/// Parse the region "start:end" in fractional coordinates for potential
+/// alignment. The region wraps around the cell if start > end.
+pub fn parse_region(s: &str) -> Result<(f64, f64)> {
+    let (a, b) = s.split_once(':').with_context(|| format!("invalid region {:?}, expect start:end", s))?;
+    let a: f64 = a.trim().parse().with_context(|| format!("invalid region: {:?}", s))?;
+    let b: f64 = b.trim().parse().with_context(|| format!("invalid region: {:?}", s))?;
+    ensure!(
+        (0.0..=1.0).contains(&a) && (0.0..=1.0).contains(&b),
+        "region should be in fractional coordinates: {:?}",
+        s
+    );
+    Ok((a, b))
+}
+
+/// Return the potential alignment in eV between `defect` and `bulk`
+/// supercells: the planar averaged potential difference along `axis`,
+/// averaged over `region` (fractional) far from the defect.
+pub fn potential_alignment(defect: &Locpot, bulk: &Locpot, axis: usize, region: (f64, f64)) -> Result<f64> {
+    ensure!(
+        defect.grid == bulk.grid,
+        "different FFT grids of LOCPOT: {:?} vs {:?}",
+        defect.grid,
+        bulk.grid
+    );
+    let avg_d = defect.planar_average(axis);
+    let avg_b = bulk.planar_average(axis);
+    let n = avg_d.len();
+    let (a, b) = region;
+    let inside = |k: usize| {
+        let x = k as f64 / n as f64;
+        if a <= b {
+            (a..=b).contains(&x)
+        } else {
+            x >= a || x <= b
+        }
+    };
+    let diffs: Vec<_> = (0..n).filter(|&k| inside(k)).map(|k| avg_d[k] - avg_b[k]).collect();
+    ensure!(!diffs.is_empty(), "no grid point in region {:?}", region);
+    Ok(diffs.iter().sum::<f64>() / diffs.len() as f64)
+}
+
+/// Options for defect report
+#[derive(Debug, Clone)]
+pub struct DefectOptions {
+    /// The valence band maximum of bulk in eV
+    pub vbm: Option<f64>,
+    /// The lattice vector for potential alignment
+    pub axis: usize,
+    /// The fractional region along `axis` for potential alignment
+    pub region: (f64, f64),
+    /// Add the energy correction for charged system in OUTCAR to formation
+    /// energy, if not included in total energy
+    pub add_correction: bool,
+}
+
+impl Default for DefectOptions {
+    fn default() -> Self {
+        Self {
+            vbm: None,
+            axis: 2,
+            region: (0.4, 0.6),
+            add_correction: false,
+        }
+    }
+}
+
+/// The energy terms of charged defect
+#[derive(Debug, Clone)]
+pub struct DefectReport {
+    /// The net charge of defect supercell
+    pub charge: f64,
+    pub e_defect: f64,
+    pub e_bulk: f64,
+    pub correction: ChargeCorrection,
+    /// The potential alignment in eV from LOCPOT
+    pub alignment: Option<f64>,
+    /// The formation energy at Fermi level of VBM, without chemical
+    /// potentials of added or removed atoms
+    pub formation: Option<f64>,
+}
+
+/// Collect energy terms for charged defect formation energy from run
+/// directories of `defect` and `bulk` supercells.
+pub fn defect_report(defect: &Path, bulk: &Path, opts: &DefectOptions) -> Result<DefectReport> {
+    let energy_of = |dir: &Path| -> Result<f64> {
+        let conv = report::check_convergence(dir)?;
+        if !conv.converged {
+            warn!("run in {:?}: {}", dir, conv.verdict);
+        }
+        conv.energy.with_context(|| format!("no energy in {:?}", dir))
+    };
+    let e_defect = energy_of(defect)?;
+    let e_bulk = energy_of(bulk)?;
+    let outcar = defect.join("OUTCAR");
+    let charge = outcar::parse_system_charge(&outcar)?;
+    let correction = outcar::parse_charge_correction(&outcar)?;
+
+    let (locpot_d, locpot_b) = (defect.join("LOCPOT"), bulk.join("LOCPOT"));
+    let alignment = if locpot_d.exists() && locpot_b.exists() {
+        let (d, b) = (Locpot::from_file(&locpot_d)?, Locpot::from_file(&locpot_b)?);
+        Some(potential_alignment(&d, &b, opts.axis, opts.region)?)
+    } else {
+        warn!("no LOCPOT for potential alignment");
+        None
+    };
+
+    let mut formation = e_defect - e_bulk;
+    if opts.add_correction {
+        formation += correction.energy.unwrap_or_default();
+    }
+    let formation = if charge.abs() < 1e-6 {
+        Some(formation)
+    } else {
+        opts.vbm.map(|vbm| formation + charge * (vbm + alignment.unwrap_or_default()))
+    };
+    Ok(DefectReport {
+        charge,
+        e_defect,
+        e_bulk,
+        correction,
+        alignment,
+        formation,
+    })
+}
+
+/// Format energy terms in defect `report`.
+pub fn format_defect_report(report: &DefectReport) -> String {
+    let fmt = |x: Option<f64>| x.map(|x| format!("{:.6}", x)).unwrap_or("--".into());
+    let mut txt = String::new();
+    txt += &format!("charge q                    = {:+.2}\n", report.charge);
+    txt += &format!("E(defect)                   = {:.6} eV\n", report.e_defect);
+    txt += &format!("E(bulk)                     = {:.6} eV\n", report.e_bulk);
+    txt += &format!("E(defect) - E(bulk)         = {:.6} eV\n", report.e_defect - report.e_bulk);
+    if let Some([x, y, z]) = report.correction.dipole {
+        txt += &format!("dipole moment               = {:.6} {:.6} {:.6} e*Å\n", x, y, z);
+    }
+    txt += &format!("Tr[quadrupole]              = {}\n", fmt(report.correction.quadrupole));
+    txt += &format!("charge correction           = {} eV\n", fmt(report.correction.energy));
+    txt += &format!("potential alignment ΔV      = {} eV\n", fmt(report.alignment));
+    txt += &format!("formation energy (E_F=VBM)  = {} eV (without chemical potentials)\n", fmt(report.formation));
+    txt
+}
+// e9794672 ends here
+
+// [[file:../../vasp-tools.note::1e0b7180][1e0b7180]]
+#[test]
+fn test_locpot_alignment() -> Result<()> {
+    let header = "locpot
+1.0
+4.0 0.0 0.0
+0.0 4.0 0.0
+0.0 0.0 8.0
+H
+1
+Direct
+0.0 0.0 0.0
+
+1 2 4
+";
+    let bulk = format!("{}0.0 0.0 0.0 0.0 0.0\n0.0 0.0 0.0\n", header);
+    let defect = format!("{}1.0 1.0 0.5 0.5 0.2\n0.2 0.4 0.4\n", header);
+    let bulk = Locpot::from_str_(&bulk)?;
+    let defect = Locpot::from_str_(&defect)?;
+    assert_eq!(defect.grid, [1, 2, 4]);
+    assert_eq!(defect.planar_average(2), vec![1.0, 0.5, 0.2, 0.4]);
+    assert_relative_eq!(defect.planar_average(1)[1], 0.525, epsilon = 1e-8);
+
+    let dv = potential_alignment(&defect, &bulk, 2, parse_region("0.4:0.6")?)?;
+    assert_relative_eq!(dv, 0.2, epsilon = 1e-8);
+    let dv = potential_alignment(&defect, &bulk, 2, parse_region("0.7:0.1")?)?;
+    assert_relative_eq!(dv, 0.7, epsilon = 1e-8);
+    assert!(parse_region("0.4").is_err());
+    assert_eq!(parse_axis("Z")?, 2);
+
+    Ok(())
+}
+// 1e0b7180 ends here
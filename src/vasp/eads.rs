@@ -0,0 +1,181 @@
+// [[file:../../vasp-tools.note::b46b74f6][b46b74f6]]
+use super::*;
+
+use std::collections::BTreeMap;
+// b46b74f6 ends here
+
+// [[file:../../vasp-tools.note::b98c0746][b98c0746]]
+/// INCAR tags that should be the same in calculations for energy
+/// differences: basis set and functional settings
+const CONSISTENT_TAGS: &[&str] = &[
+    "ENCUT", "PREC", "GGA", "METAGGA", "IVDW", "LUSE_VDW", "LHFCALC", "HFSCREEN", "AEXX", "LDAU", "LASPH", "ISPIN",
+];
+
+/// The energy of one run directory for adsorption energy
+#[derive(Debug, Clone)]
+pub struct EadsTerm {
+    /// The role of the run: slab, molecule or total
+    pub label: &'static str,
+    pub dir: PathBuf,
+    /// The energy of last ionic step in eV
+    pub energy: Option<f64>,
+    pub converged: bool,
+    pub verdict: String,
+}
+
+impl EadsTerm {
+    fn load(label: &'static str, dir: &Path) -> Result<Self> {
+        let conv = report::check_convergence(dir).with_context(|| format!("check {} run in {:?}", label, dir))?;
+        Ok(Self {
+            label,
+            dir: dir.to_owned(),
+            energy: conv.energy,
+            converged: conv.converged,
+            verdict: conv.verdict,
+        })
+    }
+}
+
+/// The adsorption energy computed from three run directories
+#[derive(Debug, Clone)]
+pub struct Eads {
+    /// The slab, molecule and total (slab with adsorbate) runs
+    pub terms: [EadsTerm; 3],
+    /// E(total) - E(slab) - E(molecule) in eV
+    pub energy: Option<f64>,
+    /// Warnings about convergence and inconsistent settings
+    pub warnings: Vec<String>,
+}
+
+/// Return the k-mesh setting in `dir`: the KPOINTS file with comment line
+/// removed and spaces normalized, or KSPACING in INCAR if no KPOINTS.
+fn read_kmesh(dir: &Path, tags: &BTreeMap<String, String>) -> Result<String> {
+    let kpoints = dir.join("KPOINTS");
+    if kpoints.exists() {
+        let s = gut::fs::read_file(&kpoints)?;
+        let lines: Vec<_> = s
+            .lines()
+            .skip(1)
+            .map(|x| x.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase())
+            .filter(|x| !x.is_empty())
+            .collect();
+        Ok(lines.join("; "))
+    } else {
+        Ok(format!("KSPACING = {}", tags.get("KSPACING").map_or("--", |x| x.as_str())))
+    }
+}
+
+/// Check settings in run directories `runs` (label, dir) for energy
+/// differences. INCAR tags are compared among all runs, while k-mesh is
+/// compared between periodic runs in `kmesh_runs` only, since gas phase
+/// molecule is usually computed at Gamma point. Return warnings found.
+fn check_consistency(runs: &[(&str, &Path)], kmesh_runs: &[usize]) -> Result<Vec<String>> {
+    let mut all_tags = vec![];
+    for (_, dir) in runs {
+        let incar = dir.join("INCAR");
+        all_tags.push(if incar.exists() { incar::parse_tags(&incar)? } else { BTreeMap::new() });
+    }
+
+    let mut warnings = vec![];
+    for &tag in CONSISTENT_TAGS {
+        // NOTE: VASP tags are case insensitive, e.g. .TRUE. vs .true.
+        let values: Vec<_> = all_tags.iter().map(|t| t.get(tag).map(|x| x.to_uppercase())).collect();
+        if values.iter().any(|x| x != &values[0]) {
+            let s: Vec<_> = runs
+                .iter()
+                .zip(&values)
+                .map(|((label, _), v)| format!("{}: {}", label, v.as_deref().unwrap_or("--")))
+                .collect();
+            warnings.push(format!("inconsistent {} ({})", tag, s.join(", ")));
+        }
+    }
+
+    let kmeshes = kmesh_runs
+        .iter()
+        .map(|&i| read_kmesh(runs[i].1, &all_tags[i]))
+        .collect::<Result<Vec<_>>>()?;
+    if kmeshes.iter().any(|x| x != &kmeshes[0]) {
+        let s: Vec<_> = kmesh_runs
+            .iter()
+            .zip(&kmeshes)
+            .map(|(&i, k)| format!("{}: {}", runs[i].0, k))
+            .collect();
+        warnings.push(format!("inconsistent k-mesh ({})", s.join(", ")));
+    }
+    Ok(warnings)
+}
+
+/// Compute adsorption energy from converged runs of clean `slab`, gas
+/// phase molecule `mol` and slab with adsorbate `total`.
+pub fn adsorption_energy(slab: &Path, mol: &Path, total: &Path) -> Result<Eads> {
+    let terms = [
+        EadsTerm::load("slab", slab)?,
+        EadsTerm::load("molecule", mol)?,
+        EadsTerm::load("total", total)?,
+    ];
+    let mut warnings = vec![];
+    for t in &terms {
+        if !t.converged {
+            warnings.push(format!("{} run in {:?}: {}", t.label, t.dir, t.verdict));
+        }
+    }
+    let runs: Vec<_> = terms.iter().map(|t| (t.label, t.dir.as_path())).collect();
+    warnings.extend(check_consistency(&runs, &[0, 2])?);
+
+    let energy = match (terms[0].energy, terms[1].energy, terms[2].energy) {
+        (Some(e_slab), Some(e_mol), Some(e_total)) => Some(e_total - e_slab - e_mol),
+        _ => None,
+    };
+    Ok(Eads {
+        terms,
+        energy,
+        warnings,
+    })
+}
+
+/// Format adsorption energy `eads` with energies of each run.
+pub fn format_eads(eads: &Eads) -> String {
+    let fmt = |x: Option<f64>| x.map(|x| format!("{:.6}", x)).unwrap_or("--".into());
+    let mut txt = format!("{:<10} {:<30} {:>18} {}\n", "run", "directory", "energy (eV)", "verdict");
+    for t in &eads.terms {
+        txt += &format!(
+            "{:<10} {:<30} {:>18} {}\n",
+            t.label,
+            t.dir.display(),
+            fmt(t.energy),
+            t.verdict
+        );
+    }
+    txt += &format!("Eads = E(total) - E(slab) - E(molecule) = {} eV\n", fmt(eads.energy));
+    for w in &eads.warnings {
+        txt += &format!("WARNING: {}\n", w);
+    }
+    txt
+}
+// b98c0746 ends here
+
+// [[file:../../vasp-tools.note::81155645][81155645]]
+#[test]
+fn test_eads_consistency() -> Result<()> {
+    let tdir = tempfile::tempdir()?;
+    let dirs: Vec<_> = ["slab", "mol", "total"].iter().map(|x| tdir.path().join(x)).collect();
+    for d in &dirs {
+        std::fs::create_dir(d)?;
+        gut::fs::write_to_file(d.join("INCAR"), "ENCUT = 400\nGGA = PE\nLASPH = .TRUE.\n")?;
+        gut::fs::write_to_file(d.join("KPOINTS"), "mesh\n0\nGamma\n 4  4 1\n0 0 0\n")?;
+    }
+    // Gamma point only for molecule
+    gut::fs::write_to_file(dirs[1].join("KPOINTS"), "mesh\n0\nGamma\n1 1 1\n0 0 0\n")?;
+    let runs: Vec<_> = ["slab", "molecule", "total"].iter().copied().zip(dirs.iter().map(|d| d.as_path())).collect();
+    assert!(check_consistency(&runs, &[0, 2])?.is_empty());
+
+    gut::fs::write_to_file(dirs[1].join("INCAR"), "ENCUT = 450\nGGA = PE\nLASPH = .true.\n")?;
+    gut::fs::write_to_file(dirs[2].join("KPOINTS"), "mesh\n0\nGamma\n3 3 1\n0 0 0\n")?;
+    let warnings = check_consistency(&runs, &[0, 2])?;
+    assert_eq!(warnings.len(), 2);
+    assert!(warnings[0].starts_with("inconsistent ENCUT"));
+    assert!(warnings[1].contains("total: 0; gamma; 3 3 1; 0 0 0"));
+
+    Ok(())
+}
+// 81155645 ends here
@@ -0,0 +1,288 @@
+// [[file:../../vasp-tools.note::3a4353c7][3a4353c7]]
+use super::*;
+
+use super::batch::{run_batch, BatchOptions, JobStatus};
+// 3a4353c7 ends here
+
+// [[file:../../vasp-tools.note::24c5dfe5][24c5dfe5]]
+/// Return the volume of cell with lattice `vectors`.
+pub(crate) fn cell_volume(vectors: &[[f64; 3]; 3]) -> f64 {
+    let [a, b, c] = vectors;
+    let bc = [
+        b[1] * c[2] - b[2] * c[1],
+        b[2] * c[0] - b[0] * c[2],
+        b[0] * c[1] - b[1] * c[0],
+    ];
+    (a[0] * bc[0] + a[1] * bc[1] + a[2] * bc[2]).abs()
+}
+
+/// Return POSCAR text `s` with cell volume scaled by `factor`, by updating
+/// the scaling factor (the second line), so that atoms in Cartesian
+/// coordinates are scaled too. Return the new volume together.
+pub fn scale_poscar_volume(s: &str, factor: f64) -> Result<(String, f64)> {
+    ensure!(factor > 0.0, "invalid volume scaling factor: {}", factor);
+    let mut lines: Vec<_> = s.lines().map(String::from).collect();
+    let vectors = {
+        let lines: Vec<_> = lines.iter().map(|x| x.as_str()).collect();
+        poscar::locate_positions(&lines)?;
+        poscar::read_lattice_vectors(&lines)?
+    };
+    let scale: f64 = lines[1].split_whitespace().next().context("no scaling factor")?.parse()?;
+    lines[1] = format!("{:.10}", scale * factor.cbrt());
+    let mut txt = lines.join("\n");
+    txt += "\n";
+    Ok((txt, cell_volume(&vectors) * factor))
+}
+
+/// Solve linear equations `a x = b` using Gaussian elimination with
+/// partial pivoting.
+fn solve_linear<const N: usize>(mut a: [[f64; N]; N], mut b: [f64; N]) -> Result<[f64; N]> {
+    for k in 0..N {
+        let p = (k..N).max_by(|&i, &j| a[i][k].abs().total_cmp(&a[j][k].abs())).unwrap_or(k);
+        ensure!(a[p][k].abs() > 1e-14, "singular linear equations");
+        a.swap(k, p);
+        b.swap(k, p);
+        for i in k + 1..N {
+            let r = a[i][k] / a[k][k];
+            for j in k..N {
+                a[i][j] -= r * a[k][j];
+            }
+            b[i] -= r * b[k];
+        }
+    }
+    let mut x = [0.0; N];
+    for k in (0..N).rev() {
+        let s: f64 = (k + 1..N).map(|j| a[k][j] * x[j]).sum();
+        x[k] = (b[k] - s) / a[k][k];
+    }
+    Ok(x)
+}
+
+/// The fitted parameters of Birch-Murnaghan equation of state
+#[derive(Debug, Clone, Copy)]
+pub struct EosFit {
+    /// The minimum energy in eV
+    pub e0: f64,
+    /// The equilibrium volume in Å^3
+    pub v0: f64,
+    /// The bulk modulus in eV/Å^3
+    pub b0: f64,
+    /// The pressure derivative of bulk modulus
+    pub b0_prime: f64,
+}
+
+impl EosFit {
+    /// Return the energy at volume `v` from the third-order
+    /// Birch-Murnaghan equation.
+    pub fn energy(&self, v: f64) -> f64 {
+        let eta = (self.v0 / v).powf(2.0 / 3.0) - 1.0;
+        self.e0 + 9.0 * self.v0 * self.b0 / 16.0 * (eta.powi(3) * self.b0_prime + eta.powi(2) * (6.0 - 4.0 * (eta + 1.0)))
+    }
+
+    /// Return the bulk modulus in GPa.
+    pub fn b0_in_gpa(&self) -> f64 {
+        crate::units::kbar_to_gpa(crate::units::ev_per_a3_to_kbar(self.b0))
+    }
+}
+
+/// Fit `energies` (eV) at `volumes` (Å^3) to the third-order
+/// Birch-Murnaghan equation of state. The energy is a cubic polynomial in
+/// x = V^(-2/3), so the fit is done by linear least squares.
+pub fn fit_birch_murnaghan(volumes: &[f64], energies: &[f64]) -> Result<EosFit> {
+    ensure!(volumes.len() == energies.len(), "inconsistent number of volumes and energies");
+    ensure!(volumes.len() >= 4, "at least 4 points are required for EOS fitting");
+    // use reduced volume u = V/Vm and polynomial in t = x - 1 for numerical
+    // stability
+    let vm = volumes.iter().sum::<f64>() / volumes.len() as f64;
+    let mut a = [[0.0; 4]; 4];
+    let mut b = [0.0; 4];
+    for (&v, &e) in volumes.iter().zip(energies) {
+        let t = (v / vm).powf(-2.0 / 3.0) - 1.0;
+        let p = [1.0, t, t * t, t * t * t];
+        for (i, pi) in p.iter().enumerate() {
+            for (j, pj) in p.iter().enumerate() {
+                a[i][j] += pi * pj;
+            }
+            b[i] += pi * e;
+        }
+    }
+    let [c0, c1, c2, c3] = solve_linear(a, b)?;
+
+    // the minimum of E(t): c1 + 2 c2 t + 3 c3 t^2 = 0 with positive second
+    // derivative
+    let t0 = if c3.abs() < 1e-12 {
+        -c1 / (2.0 * c2)
+    } else {
+        let disc = 4.0 * c2 * c2 - 12.0 * c1 * c3;
+        ensure!(disc > 0.0, "no energy minimum found in EOS fitting");
+        (-2.0 * c2 + disc.sqrt()) / (6.0 * c3)
+    };
+    let (f2, f3) = (2.0 * c2 + 6.0 * c3 * t0, 6.0 * c3);
+    let x0 = 1.0 + t0;
+    ensure!(x0 > 0.0 && f2 > 0.0, "no energy minimum found in EOS fitting");
+    let e0 = c0 + c1 * t0 + c2 * t0 * t0 + c3 * t0 * t0 * t0;
+
+    // derivatives of E with respect to reduced volume u at the minimum
+    let u0 = x0.powf(-1.5);
+    let (dx, ddx) = (-2.0 / 3.0 * u0.powf(-5.0 / 3.0), 10.0 / 9.0 * u0.powf(-8.0 / 3.0));
+    let e_uu = f2 * dx * dx;
+    let e_uuu = f3 * dx.powi(3) + 3.0 * f2 * dx * ddx;
+    let v0 = u0 * vm;
+    let b0 = u0 * e_uu / vm;
+    let b0_prime = -1.0 - u0 * e_uuu / e_uu;
+    Ok(EosFit { e0, v0, b0, b0_prime })
+}
+
+/// One point of equation of state
+#[derive(Debug, Clone)]
+pub struct EosPoint {
+    /// The volume scaling factor
+    pub scale: f64,
+    /// The cell volume in Å^3
+    pub volume: f64,
+    /// The energy in eV, None if the calculation failed
+    pub energy: Option<f64>,
+}
+
+/// Options for equation of state calculation
+#[derive(Debug, Clone)]
+pub struct EosOptions {
+    /// The volume scaling factors relative to template POSCAR
+    pub scales: Vec<f64>,
+    /// Run calculations in sub-directories of this directory
+    pub output_dir: PathBuf,
+    pub batch: BatchOptions,
+}
+
+impl Default for EosOptions {
+    fn default() -> Self {
+        Self {
+            scales: vec![0.94, 0.96, 0.98, 1.0, 1.02, 1.04, 1.06],
+            output_dir: "eos".into(),
+            batch: BatchOptions {
+                task: Some(VaspTask::SinglePoint),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+/// Run single-point calculations of `program` for POSCAR in `template`
+/// directory with scaled volumes.
+pub fn run_eos(program: &Path, template: &Path, opts: &EosOptions) -> Result<Vec<EosPoint>> {
+    ensure!(!opts.scales.is_empty(), "no volume to calculate");
+    let poscar = gut::fs::read_file(template.join("POSCAR"))?;
+    let mut dirs = vec![];
+    let mut volumes = vec![];
+    for &scale in &opts.scales {
+        let dir = opts.output_dir.join(format!("scale-{:.3}", scale));
+        super::converge::prepare_variant(template, &dir, None, None)?;
+        let (txt, volume) = scale_poscar_volume(&poscar, scale)?;
+        gut::fs::write_to_file(dir.join("POSCAR"), &txt)?;
+        dirs.push(dir);
+        volumes.push(volume);
+    }
+
+    info!("calculate {} volumes for EOS", dirs.len());
+    let results = run_batch(program, &dirs, &opts.batch)?;
+    let points = opts
+        .scales
+        .iter()
+        .zip(volumes)
+        .zip(&results)
+        .map(|((&scale, volume), r)| {
+            let ok = matches!(r.status, JobStatus::Converged | JobStatus::Finished);
+            if !ok {
+                warn!("{:?}: {:?} ({})", r.directory, r.status, r.message);
+            }
+            EosPoint {
+                scale,
+                volume,
+                energy: r.energy.filter(|_| ok),
+            }
+        })
+        .collect();
+    Ok(points)
+}
+
+/// Fit successfully calculated `points` to Birch-Murnaghan equation.
+pub fn fit_eos_points(points: &[EosPoint]) -> Result<EosFit> {
+    let (volumes, energies): (Vec<_>, Vec<_>) = points.iter().filter_map(|p| Some((p.volume, p.energy?))).unzip();
+    fit_birch_murnaghan(&volumes, &energies)
+}
+
+/// Format EOS `points` as a table, with fitted energies if `fit` is not
+/// None.
+pub fn format_eos(points: &[EosPoint], fit: Option<&EosFit>) -> String {
+    let fmt = |x: Option<f64>| x.map(|x| format!("{:.8}", x)).unwrap_or("--".into());
+    let mut txt = format!(
+        "{:>8} {:>14} {:>18} {:>18}\n",
+        "scale", "volume (Å^3)", "energy (eV)", "fitted (eV)"
+    );
+    for p in points {
+        let fitted = fit.map(|f| f.energy(p.volume));
+        txt += &format!(
+            "{:>8.4} {:>14.4} {:>18} {:>18}\n",
+            p.scale,
+            p.volume,
+            fmt(p.energy),
+            fmt(fitted)
+        );
+    }
+    if let Some(f) = fit {
+        txt += &format!("E0  = {:.8} eV\n", f.e0);
+        txt += &format!("V0  = {:.4} Å^3\n", f.v0);
+        txt += &format!("B0  = {:.6} eV/Å^3 ({:.2} GPa)\n", f.b0, f.b0_in_gpa());
+        txt += &format!("B0' = {:.4}\n", f.b0_prime);
+    }
+    txt
+}
+
+/// Plot EOS `points` as ASCII text.
+pub fn plot_eos(points: &[EosPoint]) -> Result<String> {
+    use crate::plot::AsciiPlot;
+
+    let (x, y): (Vec<_>, Vec<_>) = points.iter().filter_map(|p| Some((p.volume, p.energy?))).unzip();
+    let mut plot = AsciiPlot::new();
+    plot.set_title("Equation of state");
+    plot.set_xlabel("volume (Å^3)");
+    plot.set_ylabel("energy (eV)");
+    plot.plot(&x, &y)
+}
+// 24c5dfe5 ends here
+
+// [[file:../../vasp-tools.note::4477b9ed][4477b9ed]]
+#[test]
+fn test_eos_fit() -> Result<()> {
+    let expected = EosFit {
+        e0: -10.0,
+        v0: 20.0,
+        b0: 0.5,
+        b0_prime: 4.5,
+    };
+    let volumes: Vec<_> = (0..7).map(|i| 17.0 + i as f64).collect();
+    let energies: Vec<_> = volumes.iter().map(|&v| expected.energy(v)).collect();
+    let fit = fit_birch_murnaghan(&volumes, &energies)?;
+    assert_relative_eq!(fit.e0, expected.e0, epsilon = 1e-6);
+    assert_relative_eq!(fit.v0, expected.v0, epsilon = 1e-6);
+    assert_relative_eq!(fit.b0, expected.b0, epsilon = 1e-6);
+    assert_relative_eq!(fit.b0_prime, expected.b0_prime, epsilon = 1e-4);
+    assert_relative_eq!(fit.b0_in_gpa(), 80.1088, epsilon = 1e-3);
+    assert!(fit_birch_murnaghan(&volumes[..3], &energies[..3]).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_eos_scale_poscar() -> Result<()> {
+    let poscar = gut::fs::read_file("./tests/files/live-vasp/POSCAR")?;
+    let (_, v1) = scale_poscar_volume(&poscar, 1.0)?;
+    let (txt, v2) = scale_poscar_volume(&poscar, 1.05)?;
+    assert_relative_eq!(v2 / v1, 1.05, epsilon = 1e-8);
+    let (_, v3) = scale_poscar_volume(&txt, 1.0)?;
+    assert_relative_eq!(v3, v2, epsilon = 1e-6);
+    assert_eq!(txt.lines().count(), poscar.lines().count());
+
+    Ok(())
+}
+// 4477b9ed ends here
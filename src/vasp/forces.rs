@@ -0,0 +1,153 @@
+// [[file:../../vasp-tools.note::58910529][58910529]]
+use super::*;
+
+use gosh::gchemol::prelude::*;
+use gosh::gchemol::Molecule;
+use serde::Serialize;
+// 58910529 ends here
+
+// [[file:../../vasp-tools.note::09467567][09467567]]
+/// The force on one atom
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AtomForce {
+    /// The atom index (starting from 1) as in POSCAR
+    pub index: usize,
+    pub element: String,
+    /// The selective dynamics flags (true for fixed) of x, y, z
+    pub frozen: [bool; 3],
+    /// The force vector in eV/Å
+    pub force: [f64; 3],
+    /// The norm of force vector in eV/Å
+    pub norm: f64,
+}
+
+impl AtomForce {
+    /// The frozen status: "fixed" if all coordinates fixed, "partial" if
+    /// some of them fixed, or "free".
+    pub fn status(&self) -> &'static str {
+        match self.frozen.iter().filter(|&&x| x).count() {
+            0 => "free",
+            3 => "fixed",
+            _ => "partial",
+        }
+    }
+}
+
+/// Return the selective dynamics flags (true for fixed) of x, y, z of atoms
+/// in `mol`.
+pub fn frozen_flags(mol: &Molecule) -> Vec<[bool; 3]> {
+    mol.atoms().map(|(_, a)| a.freezing()).collect()
+}
+
+/// Return the max norm of `forces` in eV/Å. Components fixed in selective
+/// dynamics `frozen` flags are excluded as VASP does for EDIFFG, so that
+/// fmax reported by different tools agrees.
+pub fn fmax(forces: &[[f64; 3]], frozen: Option<&[[bool; 3]]>) -> f64 {
+    forces
+        .iter()
+        .enumerate()
+        .map(|(i, f)| {
+            let fixed = frozen.and_then(|x| x.get(i)).copied().unwrap_or_default();
+            (0..3).filter(|&k| !fixed[k]).map(|k| f[k] * f[k]).sum::<f64>().sqrt()
+        })
+        .fold(0.0, f64::max)
+}
+
+/// Build force table for atoms in `mol` with `forces`, sorted by norm of
+/// forces in descending order.
+fn build_force_table(mol: &Molecule, forces: &[[f64; 3]]) -> Result<Vec<AtomForce>> {
+    ensure!(
+        forces.len() == mol.natoms(),
+        "expect forces of {} atoms, got {}",
+        mol.natoms(),
+        forces.len()
+    );
+    let mut table: Vec<_> = mol
+        .atoms()
+        .zip(forces)
+        .map(|((i, a), &f)| AtomForce {
+            index: i,
+            element: a.symbol().to_owned(),
+            frozen: a.freezing(),
+            force: f,
+            norm: (f[0] * f[0] + f[1] * f[1] + f[2] * f[2]).sqrt(),
+        })
+        .collect();
+    table.sort_by(|a, b| b.norm.total_cmp(&a.norm));
+    Ok(table)
+}
+
+/// Read the final forces from OUTCAR `f`, with elements and selective
+/// dynamics flags from POSCAR or CONTCAR in the same directory. Return the
+/// force table sorted by norm of forces in descending order.
+pub fn read_force_table(f: &Path) -> Result<Vec<AtomForce>> {
+    let s = gut::fs::read_file(f)?;
+    let forces = vasp_parsers::outcar::parse_last_forces(&s).with_context(|| format!("no forces found in {:?}", f))?;
+    let fposcar = f.with_file_name("POSCAR");
+    let fcontcar = f.with_file_name("CONTCAR");
+    let mol = if fposcar.exists() {
+        Molecule::from_file(&fposcar)?
+    } else if fcontcar.exists() {
+        Molecule::from_file(&fcontcar)?
+    } else {
+        bail!("no POSCAR or CONTCAR found for {:?}", f);
+    };
+    build_force_table(&mol, &forces)
+}
+
+/// Format the first `top` atoms (all if None) in force `table` as text.
+pub fn format_force_table(table: &[AtomForce], top: Option<usize>) -> String {
+    let mut txt = format!(
+        "{:>6} {:>4} {:>8} {:>12} {:>12} {:>12} {:>12}\n",
+        "atom", "elem", "status", "fx", "fy", "fz", "|F| (eV/Å)"
+    );
+    for a in table.iter().take(top.unwrap_or(table.len())) {
+        let [fx, fy, fz] = a.force;
+        txt += &format!(
+            "{:>6} {:>4} {:>8} {:>12.6} {:>12.6} {:>12.6} {:>12.6}\n",
+            a.index,
+            a.element,
+            a.status(),
+            fx,
+            fy,
+            fz,
+            a.norm
+        );
+    }
+    txt
+}
+// 09467567 ends here
+
+// [[file:../../vasp-tools.note::d0b0786b][d0b0786b]]
+#[test]
+fn test_force_table() -> Result<()> {
+    let mut mol = Molecule::from_atoms(vec![
+        ("H", [0.0, 0.0, 0.0]),
+        ("O", [0.0, 0.0, 1.0]),
+        ("H", [0.0, 0.0, 2.0]),
+    ]);
+    mol.get_atom_mut(1).expect("atom 1").set_freezing([true; 3]);
+    let forces = [[0.1, 0.0, 0.0], [0.0, 0.3, -0.4], [0.0, 0.0, 0.2]];
+    let table = build_force_table(&mol, &forces)?;
+    let indices: Vec<_> = table.iter().map(|a| a.index).collect();
+    assert_eq!(indices, vec![2, 3, 1]);
+    assert_eq!(table[0].element, "O");
+    assert!((table[0].norm - 0.5).abs() < 1e-8);
+    assert_eq!(table[0].status(), "free");
+    assert_eq!(table[2].status(), "fixed");
+    assert!(build_force_table(&mol, &forces[..2]).is_err());
+
+    let txt = format_force_table(&table, Some(2));
+    assert_eq!(txt.lines().count(), 3);
+
+    let frozen = frozen_flags(&mol);
+    assert_eq!(frozen[0], [true; 3]);
+    let forces = [[0.0, 0.0, 0.6], [0.0, 0.3, -0.4], [0.0, 0.0, 0.2]];
+    assert!((fmax(&forces, None) - 0.6).abs() < 1e-8);
+    assert!((fmax(&forces, Some(&frozen)) - 0.5).abs() < 1e-8);
+    let frozen = [[false, false, true]; 3];
+    assert!((fmax(&forces, Some(&frozen)) - 0.3).abs() < 1e-8);
+
+    Ok(())
+}
+// d0b0786b ends here
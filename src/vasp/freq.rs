@@ -7,7 +7,7 @@ use text_parser::TextReader;
 
 // [[file:../../vasp-tools.note::*base][base:1]]
 /// Represent a VASP produced OUTCAR file
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Default, Clone, serde::Serialize, serde::Deserialize)]
 pub struct VaspOutcar {
     natoms: Option<usize>,
     vibrational_mode: Option<Vec<[f64; 3]>>,
@@ -50,6 +50,60 @@ impl VaspOutcar {
 }
 // afdf75b7 ends here
 
+// [[file:../../vasp-tools.note::4e0a1bed][4e0a1bed]]
+pub use vasp_parsers::freq::VibMode;
+
+impl VaspOutcar {
+    /// Parse all vibrational modes from OUTCAR `f`, sorted by the mode
+    /// index. If eigenvectors are printed more than once (e.g. with and
+    /// without division by SQRT(mass)), the last ones are taken.
+    pub fn parse_vib_modes_from(f: &Path) -> Result<Vec<VibMode>> {
+        let mut reader = GrepReader::try_from_path(f)?;
+        let mut s = String::new();
+        reader.read_lines(1, &mut s)?;
+        ensure!(parse::is_vasp_outcar_file(&s), "not a valid OUTCAR file: {:?}", f);
+
+        vasp_parsers::freq::parse_vib_modes(&gut::fs::read_file(f)?)
+    }
+}
+
+/// Select modes by VASP index in `modes`.
+pub fn select_mode_by_index(modes: &[VibMode], index: usize) -> Result<&VibMode> {
+    modes
+        .iter()
+        .find(|m| m.index == index)
+        .with_context(|| format!("no mode {} in {} modes", index, modes.len()))
+}
+
+/// Select modes with frequency (cm-1, negative for imaginary) in window
+/// `range`, e.g. "-500:0", "3000:" or ":100".
+pub fn select_modes_in_range<'a>(modes: &'a [VibMode], range: &str) -> Result<Vec<&'a VibMode>> {
+    let (lo, hi) = range
+        .split_once(':')
+        .with_context(|| format!("invalid frequency range {:?}, expect min:max", range))?;
+    let parse = |x: &str, default: f64| -> Result<f64> {
+        if x.trim().is_empty() {
+            Ok(default)
+        } else {
+            x.trim().parse().with_context(|| format!("invalid frequency range: {:?}", range))
+        }
+    };
+    let lo = parse(lo, f64::NEG_INFINITY)?;
+    let hi = parse(hi, f64::INFINITY)?;
+    Ok(modes.iter().filter(|m| m.frequency >= lo && m.frequency <= hi).collect())
+}
+
+/// Format `modes` as a table of frequencies.
+pub fn format_vib_modes(modes: &[&VibMode]) -> String {
+    let mut txt = format!("{:>6} {:>14} {:>14}\n", "mode", "freq (cm-1)", "energy (meV)");
+    for m in modes {
+        let flag = if m.is_imaginary() { " (imaginary)" } else { "" };
+        txt += &format!("{:>6} {:>14.6} {:>14.6}{}\n", m.index, m.frequency, m.energy, flag);
+    }
+    txt
+}
+// 4e0a1bed ends here
+
 // [[file:../../vasp-tools.note::*parse][parse:1]]
 mod parse {
     use super::*;
@@ -124,4 +178,36 @@ fn test_grep_outcar() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_vib_modes() -> Result<()> {
+    let s = " vasp.5.3.5 31Mar14 (build Aug 17 2020 07:42:27) complex
+   number of dos      NEDOS =    301   number of ions     NIONS =      2
+   1 f  =   10.478975 THz    65.841344 2PiTHz  349.540982 cm-1    43.337574 meV
+             X         Y         Z           dx          dy          dz
+      0.000000  0.000000  0.000000            0.5           0           0
+      1.000000  0.000000  0.000000           -0.5           0           0
+   2 f/i=    1.000000 THz     6.283185 2PiTHz   33.356410 cm-1     4.135667 meV
+             X         Y         Z           dx          dy          dz
+      0.000000  0.000000  0.000000            0           0.5         0
+      1.000000  0.000000  0.000000            0          -0.5         0
+";
+    let tdir = tempfile::tempdir()?;
+    let f = tdir.path().join("OUTCAR");
+    gut::fs::write_to_file(&f, s)?;
+
+    let modes = VaspOutcar::parse_vib_modes_from(&f)?;
+    assert_eq!(modes.len(), 2);
+    assert_eq!(modes[0].displacements[1], [-0.5, 0.0, 0.0]);
+    assert!(modes[1].is_imaginary());
+    assert_eq!(modes[1].frequency, -33.356410);
+    assert_eq!(select_mode_by_index(&modes, 2)?.index, 2);
+    assert!(select_mode_by_index(&modes, 3).is_err());
+    assert_eq!(select_modes_in_range(&modes, ":0")?.len(), 1);
+    assert_eq!(select_modes_in_range(&modes, "100:")?[0].index, 1);
+    assert_eq!(select_modes_in_range(&modes, "-100:1000")?.len(), 2);
+    assert!(select_modes_in_range(&modes, "100").is_err());
+
+    Ok(())
+}
 // 5a5ce2fe ends here
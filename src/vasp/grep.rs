@@ -0,0 +1,190 @@
+// [[file:../../vasp-tools.note::3b5b2f33][3b5b2f33]]
+use super::*;
+
+use text_parser::GrepReader;
+// 3b5b2f33 ends here
+
+// [[file:../../vasp-tools.note::23592032][23592032]]
+/// The number of lines to read for a block, counted from the marked line.
+#[derive(Debug, Clone, Copy)]
+enum BlockLines {
+    Fixed(usize),
+    /// The number of atoms (NIONS) plus extra lines
+    Atoms(usize),
+    /// The number of bands (NBANDS) plus extra lines
+    Bands(usize),
+}
+
+/// Predefined block in OUTCAR located by a regex pattern
+struct BlockSpec {
+    name: &'static str,
+    pattern: &'static str,
+    lines: BlockLines,
+    help: &'static str,
+}
+
+const PREDEFINED_BLOCKS: &[BlockSpec] = &[
+    BlockSpec {
+        name: "energy",
+        pattern: r"free  energy   TOTEN",
+        lines: BlockLines::Fixed(1),
+        help: "free energy TOTEN of each ionic step",
+    },
+    BlockSpec {
+        name: "forces",
+        pattern: r"POSITION\s+TOTAL-FORCE",
+        lines: BlockLines::Atoms(2),
+        help: "positions and total forces of each ionic step",
+    },
+    BlockSpec {
+        name: "stress",
+        pattern: r"^\s+in kB\s",
+        lines: BlockLines::Fixed(2),
+        help: "stress tensor (kB) and external pressure",
+    },
+    BlockSpec {
+        name: "magnetization",
+        pattern: r"magnetization \(x\)",
+        lines: BlockLines::Atoms(6),
+        help: "site projected magnetization",
+    },
+    BlockSpec {
+        name: "eigenvalues",
+        pattern: r"band No\.\s+band energies\s+occupation",
+        lines: BlockLines::Bands(1),
+        help: "band energies and occupations of each k-point",
+    },
+    BlockSpec {
+        name: "frequency",
+        pattern: r"^\s*\d+\s+f(/i)?\s*=",
+        lines: BlockLines::Atoms(2),
+        help: "vibrational frequencies and eigenvectors",
+    },
+];
+
+/// Return names and descriptions of predefined blocks.
+pub fn predefined_blocks() -> Vec<(&'static str, &'static str)> {
+    PREDEFINED_BLOCKS.iter().map(|b| (b.name, b.help)).collect()
+}
+
+/// A block of text extracted from VASP output
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct GrepBlock {
+    /// The block name or the pattern
+    pub name: String,
+    /// The index of the block in all matches
+    pub index: usize,
+    pub text: String,
+}
+
+/// Read a number following `key` (e.g. "NIONS =") in the first line
+/// matching `pattern`.
+fn grep_header_number(reader: &mut GrepReader, pattern: &str, key: &str) -> Result<usize> {
+    let n = reader.mark(pattern, 1)?;
+    ensure!(n > 0, "no line matching {:?}", pattern);
+    reader.goto_next_marker()?;
+    let mut s = String::new();
+    reader.read_lines(1, &mut s)?;
+    let re = regex::Regex::new(&format!(r"{}\s*=?\s*(\d+)", regex::escape(key)))?;
+    let n = re
+        .captures(&s)
+        .and_then(|c| c[1].parse().ok())
+        .with_context(|| format!("no {:?} in line: {:?}", key, s))?;
+    Ok(n)
+}
+
+/// Extract blocks of `nlines` lines starting at lines matching `pattern`
+/// (regex) in file `f`. If `last` is true, only the last block is returned.
+pub fn grep_blocks(f: &Path, name: &str, pattern: &str, nlines: usize, last: bool) -> Result<Vec<GrepBlock>> {
+    let mut reader = GrepReader::try_from_path(f)?;
+    let n = reader.mark(pattern, None)?;
+    debug!("found {} lines matching {:?} in {:?}", n, pattern, f);
+    let skip = if last { n.saturating_sub(1) } else { 0 };
+    let mut blocks = vec![];
+    for i in 0..n {
+        reader.goto_next_marker()?;
+        if i < skip {
+            continue;
+        }
+        let mut text = String::new();
+        reader.read_lines(nlines.max(1), &mut text)?;
+        blocks.push(GrepBlock {
+            name: name.to_owned(),
+            index: i,
+            text,
+        });
+    }
+    Ok(blocks)
+}
+
+/// Extract predefined block `name` from OUTCAR `f`. The number of lines of
+/// each block can be overridden by `context`.
+pub fn grep_named_blocks(f: &Path, name: &str, context: Option<usize>, last: bool) -> Result<Vec<GrepBlock>> {
+    let spec = PREDEFINED_BLOCKS.iter().find(|b| b.name == name).with_context(|| {
+        let names: Vec<_> = PREDEFINED_BLOCKS.iter().map(|b| b.name).collect();
+        format!("unknown block {:?}, available: {}", name, names.join(", "))
+    })?;
+    let nlines = match (context, spec.lines) {
+        (Some(n), _) => n,
+        (None, BlockLines::Fixed(n)) => n,
+        (None, BlockLines::Atoms(n)) => {
+            let mut reader = GrepReader::try_from_path(f)?;
+            grep_header_number(&mut reader, r"number of ions\s+NIONS =", "NIONS")? + n
+        }
+        (None, BlockLines::Bands(n)) => {
+            let mut reader = GrepReader::try_from_path(f)?;
+            grep_header_number(&mut reader, r"number of bands\s+NBANDS=", "NBANDS")? + n
+        }
+    };
+    grep_blocks(f, spec.name, spec.pattern, nlines, last)
+}
+
+/// Format `blocks` as plain text separated by "--" as grep does.
+pub fn format_blocks(blocks: &[GrepBlock]) -> String {
+    let blocks: Vec<_> = blocks.iter().map(|b| b.text.trim_end()).collect();
+    let mut txt = blocks.join("\n--\n");
+    if !txt.is_empty() {
+        txt.push('\n');
+    }
+    txt
+}
+// 23592032 ends here
+
+// [[file:../../vasp-tools.note::3c775615][3c775615]]
+#[test]
+fn test_grep_blocks() -> Result<()> {
+    let s = " vasp.6.3.0 20Jan22 (build Mar 11 2022 13:48:16) complex
+   k-points           NKPTS =      1   k-points in BZ     NKDIM =      1   number of bands    NBANDS=      2
+   number of dos      NEDOS =    301   number of ions     NIONS =      2
+ POSITION                                       TOTAL-FORCE (eV/Angst)
+ -----------------------------------------------------------------------------------
+      0.00000      0.00000      0.00000         0.100000      0.000000      0.000000
+      1.00000      0.00000      0.00000        -0.100000      0.000000      0.000000
+  free  energy   TOTEN  =        -1.50000000 eV
+ POSITION                                       TOTAL-FORCE (eV/Angst)
+ -----------------------------------------------------------------------------------
+      0.00000      0.00000      0.00000         0.050000      0.000000      0.000000
+      1.00000      0.00000      0.00000        -0.050000      0.000000      0.000000
+  free  energy   TOTEN  =        -1.60000000 eV
+";
+    let tdir = tempfile::tempdir()?;
+    let f = tdir.path().join("OUTCAR");
+    gut::fs::write_to_file(&f, s)?;
+
+    let blocks = grep_named_blocks(&f, "energy", None, false)?;
+    assert_eq!(blocks.len(), 2);
+    assert!(blocks[1].text.contains("-1.60000000"));
+
+    let blocks = grep_named_blocks(&f, "forces", None, true)?;
+    assert_eq!(blocks.len(), 1);
+    assert_eq!(blocks[0].index, 1);
+    assert_eq!(blocks[0].text.lines().count(), 4);
+    assert!(blocks[0].text.contains("-0.050000"));
+
+    let blocks = grep_blocks(&f, "pattern", "TOTEN", 1, false)?;
+    assert_eq!(format_blocks(&blocks).lines().count(), 3);
+    assert!(grep_named_blocks(&f, "xxx", None, false).is_err());
+
+    Ok(())
+}
+// 3c775615 ends here
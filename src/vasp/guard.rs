@@ -0,0 +1,171 @@
+// [[file:../../vasp-tools.note::6740b87e][6740b87e]]
+use super::*;
+// 6740b87e ends here
+
+// [[file:../../vasp-tools.note::0c26600f][0c26600f]]
+/// Guard against exploded or unphysical geometries sent to interactive
+/// VASP, which otherwise ends up with SCF explosion after a long wait.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GeometryGuard {
+    /// The min interatomic distance in Å
+    pub min_distance: f64,
+    /// The max distance in Å of atoms outside the cell
+    pub max_escape: f64,
+}
+
+impl Default for GeometryGuard {
+    fn default() -> Self {
+        Self {
+            min_distance: 0.5,
+            max_escape: 5.0,
+        }
+    }
+}
+
+fn cross(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+fn norm(a: [f64; 3]) -> f64 {
+    (a[0] * a[0] + a[1] * a[1] + a[2] * a[2]).sqrt()
+}
+
+/// Parse scaled positions in `input` of interactive VASP (one atom per
+/// line).
+pub(crate) fn parse_scaled_positions(input: &str) -> Result<Vec<[f64; 3]>> {
+    input
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .enumerate()
+        .map(|(i, line)| {
+            let p: Vec<f64> = line
+                .split_whitespace()
+                .take(3)
+                .map(|x| x.parse())
+                .collect::<std::result::Result<_, _>>()
+                .with_context(|| format!("atom {}: invalid position {:?}", i + 1, line))?;
+            ensure!(p.len() == 3, "atom {}: invalid position {:?}", i + 1, line);
+            ensure!(
+                p.iter().all(|x| x.is_finite()),
+                "atom {}: position is not finite: {:?}",
+                i + 1,
+                line.trim()
+            );
+            Ok([p[0], p[1], p[2]])
+        })
+        .collect()
+}
+
+impl GeometryGuard {
+    /// Check scaled `positions` (one atom per line, as the input of
+    /// interactive VASP) in cell with lattice `vectors`. Return error
+    /// describing the first problem found: NaN or infinite coordinates,
+    /// atoms escaping the cell farther than `max_escape`, or atoms closer
+    /// than `min_distance` (with periodic images). Only coordinates are
+    /// checked if `vectors` is None.
+    pub fn check_scaled_positions(&self, vectors: Option<&[[f64; 3]; 3]>, positions: &str) -> Result<()> {
+        let fracs = parse_scaled_positions(positions)?;
+        let Some(&[a, b, c]) = vectors else {
+            return Ok(());
+        };
+        let volume = cross(a, b).iter().zip(c).map(|(x, y)| x * y).sum::<f64>().abs();
+        ensure!(volume > 1e-6, "invalid lattice: {:?}", [a, b, c]);
+
+        // the distance between opposite faces of the cell
+        let spacings = [
+            volume / norm(cross(b, c)),
+            volume / norm(cross(c, a)),
+            volume / norm(cross(a, b)),
+        ];
+        for (i, f) in fracs.iter().enumerate() {
+            for k in 0..3 {
+                let outside = (-f[k]).max(f[k] - 1.0).max(0.0) * spacings[k];
+                ensure!(
+                    outside <= self.max_escape,
+                    "atom {} escaped the cell by {:.2} Å along lattice vector {} (max {} Å)",
+                    i + 1,
+                    outside,
+                    ["a", "b", "c"][k],
+                    self.max_escape
+                );
+            }
+        }
+
+        let to_cart = |f: [f64; 3]| -> [f64; 3] { std::array::from_fn(|k| f[0] * a[k] + f[1] * b[k] + f[2] * c[k]) };
+        for i in 0..fracs.len() {
+            for j in i + 1..fracs.len() {
+                let d: [f64; 3] = std::array::from_fn(|k| {
+                    let x = fracs[j][k] - fracs[i][k];
+                    x - x.round()
+                });
+                // NOTE: the nearest image could be in a neighboring cell
+                // for skewed lattice
+                let mut dmin = f64::MAX;
+                for n in 0..27 {
+                    let shift = [(n / 9) as f64 - 1.0, (n / 3 % 3) as f64 - 1.0, (n % 3) as f64 - 1.0];
+                    let dist = norm(to_cart(std::array::from_fn(|k| d[k] + shift[k])));
+                    dmin = dmin.min(dist);
+                }
+                ensure!(
+                    dmin >= self.min_distance,
+                    "atoms {} and {} are too close: {:.3} Å (min {} Å)",
+                    i + 1,
+                    j + 1,
+                    dmin,
+                    self.min_distance
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Check scaled `positions` to be sent to interactive VASP running in
+    /// `dir`, with lattice read from POSCAR in `dir`. See also
+    /// `check_scaled_positions`.
+    pub fn check_input_in_dir(&self, dir: &Path, positions: &str) -> Result<()> {
+        let vectors = gut::fs::read_file(dir.join("POSCAR")).and_then(|s| {
+            let lines: Vec<_> = s.lines().collect();
+            poscar::read_lattice_vectors(&lines)
+        });
+        match vectors {
+            Ok(vectors) => self.check_scaled_positions(Some(&vectors), positions),
+            Err(e) => {
+                warn!("no lattice for checking geometry: {:?}", e);
+                self.check_scaled_positions(None, positions)
+            }
+        }
+    }
+}
+// 0c26600f ends here
+
+// [[file:../../vasp-tools.note::b66bc486][b66bc486]]
+#[test]
+fn test_geometry_guard() -> Result<()> {
+    let guard = GeometryGuard::default();
+    let vectors = [[10.0, 0.0, 0.0], [0.0, 10.0, 0.0], [0.0, 0.0, 10.0]];
+    let ok = "0.0 0.0 0.0\n0.0 0.0 0.1\n0.95 0.0 0.0\n";
+    guard.check_scaled_positions(Some(&vectors), ok)?;
+    guard.check_scaled_positions(None, ok)?;
+
+    // NaN coordinates
+    let e = guard.check_scaled_positions(None, "0.0 0.0 0.0\nNaN 0.0 0.0\n").unwrap_err();
+    assert!(format!("{:#}", e).contains("atom 2"), "{:#}", e);
+    // atoms escaped the cell
+    let e = guard
+        .check_scaled_positions(Some(&vectors), "0.0 0.0 0.0\n0.5 0.5 1.8\n")
+        .unwrap_err();
+    assert!(format!("{:#}", e).contains("escaped"), "{:#}", e);
+    guard.check_scaled_positions(Some(&vectors), "0.0 0.0 0.0\n0.5 0.5 1.3\n")?;
+    // too close through periodic images
+    let e = guard
+        .check_scaled_positions(Some(&vectors), "0.0 0.0 0.0\n0.99 0.0 0.0\n")
+        .unwrap_err();
+    assert!(format!("{:#}", e).contains("atoms 1 and 2"), "{:#}", e);
+
+    Ok(())
+}
+// b66bc486 ends here
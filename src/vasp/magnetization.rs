@@ -0,0 +1,291 @@
+// [[file:../../vasp-tools.note::85d3e5d5][85d3e5d5]]
+use super::*;
+
+use serde::Serialize;
+// 85d3e5d5 ends here
+
+// [[file:../../vasp-tools.note::5462df25][5462df25]]
+/// The magnetization of one ionic step
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct MagStep {
+    /// The ionic step (starting from 1)
+    pub step: usize,
+    /// The total magnetization in μB
+    pub total: Option<f64>,
+    /// The magnetic moments of atoms in μB from "magnetization (x)" block
+    /// in OUTCAR, only available with LORBIT set
+    pub moments: Vec<f64>,
+}
+
+/// Parse magnetization of each ionic step in OUTCAR text `s`.
+fn parse_outcar_str(s: &str) -> Vec<MagStep> {
+    let mut steps = vec![];
+    let mut total = None;
+    let mut moments = vec![];
+    let mut lines = s.lines();
+    while let Some(line) = lines.next() {
+        if line.starts_with(" number of electron") {
+            //  number of electron     699.9999451 magnetization     114.0418239
+            total = line.split_whitespace().nth(5).and_then(|x| x.parse().ok());
+        } else if line.trim() == "magnetization (x)" {
+            // # of ion       s       p       d       tot
+            // ------------------------------------------
+            //     1       -0.000  -0.000   0.000  -0.000
+            moments = lines
+                .by_ref()
+                .skip_while(|l| !l.starts_with("---"))
+                .skip(1)
+                .take_while(|l| !l.starts_with("---"))
+                .filter_map(|l| l.split_whitespace().last()?.parse().ok())
+                .collect();
+        } else if line.contains("free  energy   TOTEN  =") {
+            steps.push(MagStep {
+                step: steps.len() + 1,
+                total,
+                moments: std::mem::take(&mut moments),
+            });
+        }
+    }
+    steps
+}
+
+/// Parse total magnetization of each ionic step in OSZICAR text `s`.
+fn parse_oszicar_str(s: &str) -> Vec<MagStep> {
+    vasp_parsers::oszicar::parse_oszicar(s)
+        .into_iter()
+        .map(|x| MagStep {
+            step: x.step,
+            total: x.mag,
+            moments: vec![],
+        })
+        .collect()
+}
+
+/// Read magnetization of each ionic step from OUTCAR or OSZICAR `f`. The
+/// magnetic moments of atoms are only available in OUTCAR.
+pub fn read_mag_steps(f: &Path) -> Result<Vec<MagStep>> {
+    let s = gut::fs::read_file(f)?;
+    let name = f.file_name().map(|x| x.to_string_lossy()).unwrap_or_default();
+    if name.contains("OSZICAR") {
+        Ok(parse_oszicar_str(&s))
+    } else {
+        Ok(parse_outcar_str(&s))
+    }
+}
+
+/// The kind of magnetization event
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MagEventKind {
+    /// The magnetic moment changed its sign
+    SpinFlip,
+    /// The magnetic moment oscillates up and down in successive steps
+    Oscillation,
+}
+
+/// A suspicious change of magnetization, which usually indicates a bad
+/// MAGMOM starting guess
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MagEvent {
+    /// The ionic step when found
+    pub step: usize,
+    /// The atom index (starting from 1), or None for total magnetization
+    pub atom: Option<usize>,
+    pub kind: MagEventKind,
+    pub message: String,
+}
+
+/// Options for checking magnetization
+#[derive(Debug, Clone)]
+pub struct MagCheckOptions {
+    /// Only moments larger than this (μB) are considered for spin flips
+    pub threshold: f64,
+    /// Changes smaller than this (μB) are ignored for oscillation
+    pub amplitude: f64,
+    /// The number of steps moving up and down alternately for oscillation
+    pub window: usize,
+}
+
+impl Default for MagCheckOptions {
+    fn default() -> Self {
+        Self {
+            threshold: 0.5,
+            amplitude: 0.1,
+            window: 6,
+        }
+    }
+}
+
+/// Check `series` of (step, moment) of `atom` for spin flips and
+/// oscillation.
+fn check_series(series: &[(usize, f64)], atom: Option<usize>, opts: &MagCheckOptions) -> Vec<MagEvent> {
+    let label = atom.map_or("total magnetization".to_owned(), |i| format!("atom {}", i));
+    let mut events = vec![];
+    // the number of successive changes with alternating signs
+    let mut nalt = 0;
+    let mut last_delta = 0.0;
+    for w in series.windows(2) {
+        let ((_, m0), (step, m1)) = (w[0], w[1]);
+        if m0.abs() >= opts.threshold && m1.abs() >= opts.threshold && m0.signum() != m1.signum() {
+            events.push(MagEvent {
+                step,
+                atom,
+                kind: MagEventKind::SpinFlip,
+                message: format!("{}: spin flip from {:.3} to {:.3} μB", label, m0, m1),
+            });
+        }
+        let delta = m1 - m0;
+        if delta.abs() < opts.amplitude {
+            nalt = 0;
+        } else if nalt > 0 && delta.signum() != last_delta.signum() {
+            nalt += 1;
+        } else {
+            nalt = 1;
+        }
+        last_delta = delta;
+        // NOTE: report only once for each period of oscillation
+        if nalt + 1 == opts.window {
+            events.push(MagEvent {
+                step,
+                atom,
+                kind: MagEventKind::Oscillation,
+                message: format!("{}: moment oscillates in last {} steps, now {:.3} μB", label, opts.window, m1),
+            });
+        }
+    }
+    events
+}
+
+/// Check magnetization in ionic `steps` for spin flips and oscillating
+/// moments. Return events in order of steps.
+pub fn check_magnetization(steps: &[MagStep], opts: &MagCheckOptions) -> Vec<MagEvent> {
+    let series: Vec<_> = steps.iter().filter_map(|s| Some((s.step, s.total?))).collect();
+    let mut events = check_series(&series, None, opts);
+    let natoms = steps.iter().map(|s| s.moments.len()).max().unwrap_or(0);
+    for i in 0..natoms {
+        let series: Vec<_> = steps.iter().filter_map(|s| Some((s.step, *s.moments.get(i)?))).collect();
+        events.extend(check_series(&series, Some(i + 1), opts));
+    }
+    events.sort_by_key(|e| e.step);
+    events
+}
+
+/// Format magnetization in `steps` as a table with `events` found.
+/// Magnetic atoms are those with moments larger than `threshold` (μB).
+pub fn format_magnetization(steps: &[MagStep], events: &[MagEvent], threshold: f64) -> String {
+    let fmt = |x: Option<f64>| x.map(|x| format!("{:.4}", x)).unwrap_or("--".into());
+    let mut txt = format!(
+        "{:>6} {:>12} {:>12} {:>12} {:>10}\n",
+        "step", "total (μB)", "Σ|m| (μB)", "max |m|", "magnetic"
+    );
+    for s in steps {
+        let (sum, max) = if s.moments.is_empty() {
+            (None, None)
+        } else {
+            let sum: f64 = s.moments.iter().map(|x| x.abs()).sum();
+            let max = s.moments.iter().map(|x| x.abs()).fold(0.0, f64::max);
+            (Some(sum), Some(max))
+        };
+        let nmag = s.moments.iter().filter(|x| x.abs() >= threshold).count();
+        txt += &format!(
+            "{:>6} {:>12} {:>12} {:>12} {:>10}\n",
+            s.step,
+            fmt(s.total),
+            fmt(sum),
+            fmt(max),
+            nmag
+        );
+    }
+    for e in events {
+        txt += &format!("WARNING: step {}: {}\n", e.step, e.message);
+    }
+    txt
+}
+
+/// Monitor magnetization in OUTCAR or OSZICAR `f`, checking for updates
+/// every `interval` seconds, and call `on_event` for each new event found.
+/// Return when VASP finished.
+pub fn monitor_magnetization(
+    f: &Path,
+    opts: &MagCheckOptions,
+    interval: f64,
+    mut on_event: impl FnMut(&MagEvent) -> Result<()>,
+) -> Result<()> {
+    let outcar = f.with_file_name("OUTCAR");
+    let mut last_size = 0;
+    let mut nreported = 0;
+    loop {
+        let size = f.metadata().with_context(|| format!("read metadata of {:?}", f))?.len();
+        if size != last_size {
+            last_size = size;
+            let steps = read_mag_steps(f)?;
+            // NOTE: events depend only on previous steps, so new events are
+            // always appended
+            let events = check_magnetization(&steps, opts);
+            for e in &events[nreported.min(events.len())..] {
+                on_event(e)?;
+            }
+            nreported = events.len();
+            debug!("{} ionic steps checked, {} events found", steps.len(), events.len());
+        }
+        if outcar.exists() && outcar::vasp_finished(&outcar)? {
+            info!("VASP finished.");
+            break;
+        }
+        gut::utils::sleep(interval);
+    }
+    Ok(())
+}
+// 5462df25 ends here
+
+// [[file:../../vasp-tools.note::909d7f98][909d7f98]]
+#[test]
+fn test_magnetization_monitor() -> Result<()> {
+    let step = |total: f64, m1: f64, m2: f64| {
+        format!(
+            " number of electron      16.0000000 magnetization       {:.7}
+ magnetization (x)
+
+# of ion       s       p       d       tot
+------------------------------------------
+    1        0.010   0.020   0.000   {:.3}
+    2        0.010   0.020   0.000   {:.3}
+--------------------------------------------------
+tot          0.020   0.040   0.000   {:.3}
+
+  free  energy   TOTEN  =       -10.00000000 eV
+",
+            total,
+            m1,
+            m2,
+            m1 + m2
+        )
+    };
+    let moments = [(2.0, 2.0), (2.0, 1.9), (2.0, -1.8), (2.0, -1.8)];
+    let s: String = moments.iter().map(|&(m1, m2)| step(m1 + m2, m1, m2)).collect();
+    let steps = parse_outcar_str(&s);
+    assert_eq!(steps.len(), 4);
+    assert_eq!(steps[1].total, Some(3.9));
+    assert_eq!(steps[2].moments, vec![2.0, -1.8]);
+
+    let opts = MagCheckOptions::default();
+    let events = check_magnetization(&steps, &opts);
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].step, 3);
+    assert_eq!(events[0].atom, Some(2));
+    assert_eq!(events[0].kind, MagEventKind::SpinFlip);
+    let s = format_magnetization(&steps, &events, opts.threshold);
+    assert!(s.contains("WARNING: step 3: atom 2: spin flip"));
+
+    let series: Vec<_> = [1.0, 1.5, 1.0, 1.5, 1.0, 1.5, 1.0, 1.5].iter().copied().enumerate().collect();
+    let events = check_series(&series, Some(1), &opts);
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].kind, MagEventKind::Oscillation);
+    assert_eq!(events[0].step, 5);
+
+    let s = "   1 F= -.40841292E+03 E0= -.40840842E+03  d E =-.408413E+03  mag=    18.0000\n";
+    assert_eq!(parse_oszicar_str(s)[0].total, Some(18.0));
+
+    Ok(())
+}
+// 909d7f98 ends here
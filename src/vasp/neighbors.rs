@@ -0,0 +1,339 @@
+// [[file:../../vasp-tools.note::d15b9c2d][d15b9c2d]]
+use super::*;
+
+use gosh::gchemol::prelude::*;
+use gosh::gchemol::Molecule;
+use serde::Serialize;
+// d15b9c2d ends here
+
+// [[file:../../vasp-tools.note::2e9a483a][2e9a483a]]
+/// Covalent radii in Å (Cordero et al., Dalton Trans. 2008, 2832)
+#[rustfmt::skip]
+const COVALENT_RADII: &[(&str, f64)] = &[
+    ("H", 0.31), ("He", 0.28), ("Li", 1.28), ("Be", 0.96), ("B", 0.84), ("C", 0.76), ("N", 0.71),
+    ("O", 0.66), ("F", 0.57), ("Ne", 0.58), ("Na", 1.66), ("Mg", 1.41), ("Al", 1.21), ("Si", 1.11),
+    ("P", 1.07), ("S", 1.05), ("Cl", 1.02), ("Ar", 1.06), ("K", 2.03), ("Ca", 1.76), ("Sc", 1.70),
+    ("Ti", 1.60), ("V", 1.53), ("Cr", 1.39), ("Mn", 1.39), ("Fe", 1.32), ("Co", 1.26), ("Ni", 1.24),
+    ("Cu", 1.32), ("Zn", 1.22), ("Ga", 1.22), ("Ge", 1.20), ("As", 1.19), ("Se", 1.20), ("Br", 1.20),
+    ("Kr", 1.16), ("Rb", 2.20), ("Sr", 1.95), ("Y", 1.90), ("Zr", 1.75), ("Nb", 1.64), ("Mo", 1.54),
+    ("Tc", 1.47), ("Ru", 1.46), ("Rh", 1.42), ("Pd", 1.39), ("Ag", 1.45), ("Cd", 1.44), ("In", 1.42),
+    ("Sn", 1.39), ("Sb", 1.39), ("Te", 1.38), ("I", 1.39), ("Xe", 1.40), ("Cs", 2.44), ("Ba", 2.15),
+    ("La", 2.07), ("Ce", 2.04), ("Pr", 2.03), ("Nd", 2.01), ("Pm", 1.99), ("Sm", 1.98), ("Eu", 1.98),
+    ("Gd", 1.96), ("Tb", 1.94), ("Dy", 1.92), ("Ho", 1.92), ("Er", 1.89), ("Tm", 1.90), ("Yb", 1.87),
+    ("Lu", 1.87), ("Hf", 1.75), ("Ta", 1.70), ("W", 1.62), ("Re", 1.51), ("Os", 1.44), ("Ir", 1.41),
+    ("Pt", 1.36), ("Au", 1.36), ("Hg", 1.32), ("Tl", 1.45), ("Pb", 1.46), ("Bi", 1.48), ("Po", 1.40),
+    ("At", 1.50), ("Rn", 1.50), ("Fr", 2.60), ("Ra", 2.21), ("Ac", 2.15), ("Th", 2.06), ("Pa", 2.00),
+    ("U", 1.96), ("Np", 1.90), ("Pu", 1.87), ("Am", 1.80), ("Cm", 1.69),
+];
+
+fn covalent_radius(symbol: &str) -> Result<f64> {
+    COVALENT_RADII
+        .iter()
+        .find(|(s, _)| *s == symbol)
+        .map(|(_, r)| *r)
+        .with_context(|| format!("no covalent radius for element {:?}", symbol))
+}
+
+/// Options for neighbor analysis
+#[derive(Debug, Clone)]
+pub struct NeighborOptions {
+    /// Atoms within this distance in Å are neighbors. If None, the cutoff
+    /// of a pair is `scale` times the sum of their covalent radii.
+    pub cutoff: Option<f64>,
+    pub scale: f64,
+    /// Atoms closer than this ratio of the sum of their covalent radii are
+    /// flagged as unphysical close contacts
+    pub close_ratio: f64,
+}
+
+impl Default for NeighborOptions {
+    fn default() -> Self {
+        Self {
+            cutoff: None,
+            scale: 1.2,
+            close_ratio: 0.6,
+        }
+    }
+}
+
+/// The coordination environment of one atom
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AtomEnvironment {
+    /// The atom index (starting from 1)
+    pub index: usize,
+    pub element: String,
+    /// The neighbors (atom index starting from 1, distance in Å) sorted by
+    /// distance. Periodic images of the same atom are listed separately.
+    pub neighbors: Vec<(usize, f64)>,
+    /// The nearest atom (index starting from 1, distance in Å), including
+    /// those beyond cutoff and periodic images
+    pub nearest: Option<(usize, f64)>,
+}
+
+impl AtomEnvironment {
+    /// The coordination number
+    pub fn coordination(&self) -> usize {
+        self.neighbors.len()
+    }
+}
+
+/// A pair of atoms too close to each other
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CloseContact {
+    /// The atom indices (starting from 1)
+    pub pair: (usize, usize),
+    /// The distance in Å
+    pub distance: f64,
+    /// The minimum physical distance in Å
+    pub threshold: f64,
+}
+
+/// The result of neighbor analysis of one structure
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct NeighborAnalysis {
+    pub atoms: Vec<AtomEnvironment>,
+    pub close_contacts: Vec<CloseContact>,
+}
+
+impl NeighborAnalysis {
+    /// Return the shortest interatomic distance in Å with the atom pair.
+    pub fn shortest(&self) -> Option<(f64, usize, usize)> {
+        self.atoms
+            .iter()
+            .filter_map(|a| a.nearest.map(|(j, d)| (d, a.index, j)))
+            .min_by(|a, b| a.0.total_cmp(&b.0))
+    }
+}
+
+/// Return the number of periodic images along each lattice vector in
+/// `lattice` needed for searching neighbors within `cutoff`.
+fn image_range(lattice: &[[f64; 3]; 3], cutoff: f64) -> [i32; 3] {
+    let cross = |a: &[f64; 3], b: &[f64; 3]| {
+        [
+            a[1] * b[2] - a[2] * b[1],
+            a[2] * b[0] - a[0] * b[2],
+            a[0] * b[1] - a[1] * b[0],
+        ]
+    };
+    let norm = |a: &[f64; 3]| (a[0] * a[0] + a[1] * a[1] + a[2] * a[2]).sqrt();
+    let [va, vb, vc] = lattice;
+    let bc = cross(vb, vc);
+    let volume = (va[0] * bc[0] + va[1] * bc[1] + va[2] * bc[2]).abs();
+    // the spacing between lattice planes
+    let spacing = [bc, cross(vc, va), cross(va, vb)].map(|x| volume / norm(&x));
+    spacing.map(|h| (cutoff / h).ceil() as i32 + 1)
+}
+
+/// Analyze neighbors of atoms with element `symbols` at Cartesian
+/// `positions` in Å, under periodic boundary conditions if `lattice` is
+/// set. Return coordination environments of all atoms and unphysical close
+/// contacts.
+pub fn analyze_neighbors(
+    lattice: Option<&[[f64; 3]; 3]>,
+    symbols: &[String],
+    positions: &[[f64; 3]],
+    opts: &NeighborOptions,
+) -> Result<NeighborAnalysis> {
+    ensure!(symbols.len() == positions.len(), "symbols and positions mismatch");
+    let radii: Vec<f64> = symbols.iter().map(|s| covalent_radius(s)).collect::<Result<_>>()?;
+    let rmax = radii.iter().copied().fold(0.0, f64::max);
+    let cutoff_max = opts.cutoff.unwrap_or(2.0 * rmax * opts.scale);
+
+    let images: Vec<[f64; 3]> = match lattice {
+        Some(lat) => {
+            let [na, nb, nc] = image_range(lat, cutoff_max);
+            let mut images = vec![];
+            for i in -na..=na {
+                for j in -nb..=nb {
+                    for k in -nc..=nc {
+                        let n = [i as f64, j as f64, k as f64];
+                        images.push([0, 1, 2].map(|x| n[0] * lat[0][x] + n[1] * lat[1][x] + n[2] * lat[2][x]));
+                    }
+                }
+            }
+            images
+        }
+        None => vec![[0.0; 3]],
+    };
+
+    let natoms = symbols.len();
+    let mut atoms: Vec<_> = (0..natoms)
+        .map(|i| AtomEnvironment {
+            index: i + 1,
+            element: symbols[i].clone(),
+            neighbors: vec![],
+            nearest: None,
+        })
+        .collect();
+    let mut close_contacts = vec![];
+    for i in 0..natoms {
+        for j in i..natoms {
+            let cutoff = opts.cutoff.unwrap_or(opts.scale * (radii[i] + radii[j]));
+            let threshold = opts.close_ratio * (radii[i] + radii[j]);
+            for (n, image) in images.iter().enumerate() {
+                let d = [0, 1, 2].map(|x| positions[j][x] + image[x] - positions[i][x]);
+                let d = (d[0] * d[0] + d[1] * d[1] + d[2] * d[2]).sqrt();
+                // NOTE: for self images, both n and -n are found
+                if i == j && d < 1e-8 {
+                    continue;
+                }
+                for (k, other) in [(i, j), (j, i)] {
+                    if !matches!(atoms[k].nearest, Some((_, x)) if x <= d) {
+                        atoms[k].nearest = Some((other + 1, d));
+                    }
+                }
+                if d < cutoff {
+                    atoms[i].neighbors.push((j + 1, d));
+                    if i != j {
+                        atoms[j].neighbors.push((i + 1, d));
+                    }
+                }
+                // count self images only once
+                if d < threshold && (i != j || n > images.len() / 2) {
+                    close_contacts.push(CloseContact {
+                        pair: (i + 1, j + 1),
+                        distance: d,
+                        threshold,
+                    });
+                }
+            }
+        }
+    }
+    for a in atoms.iter_mut() {
+        a.neighbors.sort_by(|x, y| x.1.total_cmp(&y.1));
+    }
+
+    Ok(NeighborAnalysis { atoms, close_contacts })
+}
+
+/// Analyze neighbors of atoms in `mol`. See `analyze_neighbors`.
+pub fn analyze_molecule(mol: &Molecule, opts: &NeighborOptions) -> Result<NeighborAnalysis> {
+    let lattice = mol.get_lattice().map(|lat| lat.vectors());
+    let symbols: Vec<_> = mol.symbols().map(|x| x.to_owned()).collect();
+    let positions: Vec<_> = mol.positions().collect();
+    analyze_neighbors(lattice.as_ref(), &symbols, &positions, opts)
+}
+
+/// Analyze neighbors of each frame in XDATCAR `f`.
+pub fn analyze_xdatcar(f: &Path, opts: &NeighborOptions) -> Result<Vec<NeighborAnalysis>> {
+    let s = gut::fs::read_file(f)?;
+    let frames = vasp_parsers::xdatcar::parse_xdatcar(&s).with_context(|| format!("parse {:?}", f))?;
+    frames
+        .iter()
+        .map(|frame| {
+            let lat = &frame.lattice;
+            let positions: Vec<_> = frame
+                .fracs
+                .iter()
+                .map(|x| [0, 1, 2].map(|k| x[0] * lat[0][k] + x[1] * lat[1][k] + x[2] * lat[2][k]))
+                .collect();
+            analyze_neighbors(Some(lat), &frame.symbols, &positions, opts)
+        })
+        .collect()
+}
+
+fn format_close_contacts(analysis: &NeighborAnalysis) -> String {
+    let mut txt = String::new();
+    for c in &analysis.close_contacts {
+        let (i, j) = c.pair;
+        txt += &format!(
+            "WARNING: close contact between atom {} ({}) and atom {} ({}): {:.4} Å < {:.4} Å\n",
+            i,
+            analysis.atoms[i - 1].element,
+            j,
+            analysis.atoms[j - 1].element,
+            c.distance,
+            c.threshold
+        );
+    }
+    txt
+}
+
+/// Format coordination number, nearest distance and neighbor elements of
+/// each atom in `analysis`, with close contacts found.
+pub fn format_neighbor_analysis(analysis: &NeighborAnalysis) -> String {
+    let mut txt = format!("{:>6} {:>4} {:>4} {:>12}  {}\n", "atom", "elem", "CN", "nearest (Å)", "neighbors");
+    for a in &analysis.atoms {
+        // count neighbors by element, e.g. "O2 H1"
+        let mut counts: Vec<(&str, usize)> = vec![];
+        for &(j, _) in &a.neighbors {
+            let e = analysis.atoms[j - 1].element.as_str();
+            match counts.iter_mut().find(|(x, _)| *x == e) {
+                Some((_, n)) => *n += 1,
+                None => counts.push((e, 1)),
+            }
+        }
+        let neighbors: Vec<_> = counts.iter().map(|(e, n)| format!("{}{}", e, n)).collect();
+        let nearest = a.nearest.map(|(_, d)| format!("{:.4}", d)).unwrap_or("--".into());
+        txt += &format!(
+            "{:>6} {:>4} {:>4} {:>12}  {}\n",
+            a.index,
+            a.element,
+            a.coordination(),
+            nearest,
+            neighbors.join(" ")
+        );
+    }
+    txt += &format_close_contacts(analysis);
+    txt
+}
+
+/// Format the shortest distance and the number of close contacts of each
+/// frame in `frames`, with close contacts found.
+pub fn format_frames_summary(frames: &[NeighborAnalysis]) -> String {
+    let mut txt = format!("{:>6} {:>14} {:>14} {:>8}\n", "frame", "shortest (Å)", "pair", "close");
+    for (i, f) in frames.iter().enumerate() {
+        let (d, pair) = match f.shortest() {
+            Some((d, a, b)) => (format!("{:.4}", d), format!("{}-{}", a, b)),
+            None => ("--".into(), "--".into()),
+        };
+        txt += &format!("{:>6} {:>14} {:>14} {:>8}\n", i + 1, d, pair, f.close_contacts.len());
+    }
+    for (i, f) in frames.iter().enumerate().filter(|(_, f)| !f.close_contacts.is_empty()) {
+        txt += &format!("frame {}:\n{}", i + 1, format_close_contacts(f));
+    }
+    txt
+}
+// 2e9a483a ends here
+
+// [[file:../../vasp-tools.note::046d5ac8][046d5ac8]]
+#[test]
+fn test_neighbor_analysis() -> Result<()> {
+    let symbols: Vec<String> = ["O", "H", "H"].iter().map(|x| x.to_string()).collect();
+    let water = [[0.0, 0.0, 0.0], [0.9572, 0.0, 0.0], [-0.24, 0.9266, 0.0]];
+    let opts = NeighborOptions::default();
+    let analysis = analyze_neighbors(None, &symbols, &water, &opts)?;
+    assert_eq!(analysis.atoms[0].coordination(), 2);
+    assert_eq!(analysis.atoms[1].coordination(), 1);
+    assert_eq!(analysis.atoms[1].neighbors[0].0, 1);
+    assert_eq!(analysis.atoms[1].nearest, Some((1, 0.9572)));
+    assert!(analysis.close_contacts.is_empty());
+    let txt = format_neighbor_analysis(&analysis);
+    assert!(txt.lines().nth(1).unwrap().ends_with("H2"), "{}", txt);
+
+    // simple cubic Po with 6 neighbors from periodic images
+    let lat = [[3.35, 0.0, 0.0], [0.0, 3.35, 0.0], [0.0, 0.0, 3.35]];
+    let opts = NeighborOptions {
+        cutoff: Some(3.4),
+        ..Default::default()
+    };
+    let analysis = analyze_neighbors(Some(&lat), &["Po".to_string()], &[[0.0; 3]], &opts)?;
+    assert_eq!(analysis.atoms[0].coordination(), 6);
+    assert_eq!(analysis.atoms[0].nearest, Some((1, 3.35)));
+
+    // close contact across cell boundary
+    let lat = [[10.0, 0.0, 0.0], [0.0, 10.0, 0.0], [0.0, 0.0, 10.0]];
+    let positions = [[0.1, 0.0, 0.0], [9.8, 0.0, 0.0], [5.0, 5.0, 5.0]];
+    let analysis = analyze_neighbors(Some(&lat), &symbols, &positions, &NeighborOptions::default())?;
+    assert_eq!(analysis.close_contacts.len(), 1);
+    assert_eq!(analysis.close_contacts[0].pair, (1, 2));
+    assert!((analysis.close_contacts[0].distance - 0.3).abs() < 1e-8);
+    let (d, _, _) = analysis.shortest().unwrap();
+    assert!((d - 0.3).abs() < 1e-8);
+    assert!(format_frames_summary(&[analysis]).contains("WARNING: close contact between atom 1 (O)"));
+
+    assert!(analyze_neighbors(None, &["Xx".to_string()], &[[0.0; 3]], &opts).is_err());
+
+    Ok(())
+}
+// 046d5ac8 ends here
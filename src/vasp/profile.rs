@@ -0,0 +1,123 @@
+// [[file:../../vasp-tools.note::4a06b064][4a06b064]]
+use super::*;
+// 4a06b064 ends here
+
+// [[file:../../vasp-tools.note::40bf604b][40bf604b]]
+/// The INCAR profile of interactive VASP, trading accuracy for speed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Profile {
+    /// Loose settings for cheap early steps of optimization
+    Fast,
+    /// Tight settings for final steps
+    Accurate,
+}
+
+impl std::str::FromStr for Profile {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "fast" => Ok(Self::Fast),
+            "accurate" => Ok(Self::Accurate),
+            _ => bail!("invalid INCAR profile: {:?}, expect fast or accurate", s),
+        }
+    }
+}
+
+impl std::fmt::Display for Profile {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Fast => write!(f, "fast"),
+            Self::Accurate => write!(f, "accurate"),
+        }
+    }
+}
+
+/// The INCAR tags of each profile, as "TAG = value"
+#[derive(Debug, Clone, PartialEq)]
+pub struct IncarProfiles {
+    pub fast: Vec<String>,
+    pub accurate: Vec<String>,
+}
+
+impl Default for IncarProfiles {
+    fn default() -> Self {
+        Self {
+            fast: vec!["PREC = Normal".into(), "EDIFF = 1E-4".into()],
+            accurate: vec!["PREC = Accurate".into(), "EDIFF = 1E-6".into()],
+        }
+    }
+}
+
+/// Parse INCAR tags separated by semicolon or newline, e.g. "PREC=Normal;
+/// EDIFF=1E-4", into "TAG = value" items.
+pub fn parse_incar_params(s: &str) -> Result<Vec<String>> {
+    s.split([';', '\n'])
+        .filter(|x| !x.trim().is_empty())
+        .map(|item| {
+            let (tag, value) = item
+                .split_once('=')
+                .with_context(|| format!("invalid INCAR tag: {:?}", item.trim()))?;
+            let (tag, value) = (tag.trim().to_uppercase(), value.trim());
+            ensure!(!tag.is_empty() && !value.is_empty(), "invalid INCAR tag: {:?}", item.trim());
+            Ok(format!("{} = {}", tag, value))
+        })
+        .collect()
+}
+
+impl IncarProfiles {
+    /// The INCAR tags of `profile`
+    pub fn params(&self, profile: Profile) -> &[String] {
+        match profile {
+            Profile::Fast => &self.fast,
+            Profile::Accurate => &self.accurate,
+        }
+    }
+
+    /// Update INCAR in `dir` with tags of `profile` for VASP to be
+    /// restarted, after VASP stopped and WAVECAR written. WAVECAR left in
+    /// `dir` is kept and read on restart (ISTART = 1) if not empty. Return
+    /// the tags written.
+    pub fn apply(&self, dir: &Path, profile: Profile) -> Result<Vec<String>> {
+        let mut params: Vec<&str> = self.params(profile).iter().map(|x| x.as_str()).collect();
+        let wavecar = dir.join("WAVECAR");
+        if wavecar.metadata().map(|m| m.len() > 0).unwrap_or(false) {
+            params.push("ISTART = 1");
+        }
+        let incar = dir.join("INCAR");
+        let txt = incar::update_with_mandatory_params(&incar, &params)?;
+        gut::fs::write_to_file(&incar, &txt)?;
+        info!("switch to {} profile: {:?}", profile, params);
+        Ok(params.into_iter().map(|x| x.to_owned()).collect())
+    }
+}
+// 40bf604b ends here
+
+// [[file:../../vasp-tools.note::b28e04bf][b28e04bf]]
+#[test]
+fn test_incar_profile() -> Result<()> {
+    assert_eq!(parse_incar_params("prec=Normal; EDIFF = 1E-4\n")?, ["PREC = Normal", "EDIFF = 1E-4"]);
+    assert!(parse_incar_params("PREC Normal").is_err());
+    assert_eq!("Fast".parse::<Profile>()?, Profile::Fast);
+    assert!("medium".parse::<Profile>().is_err());
+
+    let tdir = tempfile::tempdir()?;
+    let dir = tdir.path();
+    gut::fs::write_to_file(dir.join("INCAR"), "ENCUT = 400\nEDIFF = 1E-5\nINTERACTIVE = .TRUE.")?;
+    let profiles = IncarProfiles::default();
+    assert_eq!(profiles.apply(dir, Profile::Fast)?, ["PREC = Normal", "EDIFF = 1E-4"]);
+    let tags = incar::parse_tags(&dir.join("INCAR"))?;
+    assert_eq!(tags["EDIFF"], "1E-4");
+    assert_eq!(tags["ENCUT"], "400");
+    assert_eq!(tags["INTERACTIVE"], ".TRUE.");
+
+    gut::fs::write_to_file(dir.join("WAVECAR"), "wavecar")?;
+    let params = profiles.apply(dir, Profile::Accurate)?;
+    assert_eq!(params, ["PREC = Accurate", "EDIFF = 1E-6", "ISTART = 1"]);
+    let tags = incar::parse_tags(&dir.join("INCAR"))?;
+    assert_eq!(tags["PREC"], "Accurate");
+    assert_eq!(tags["EDIFF"], "1E-6");
+
+    Ok(())
+}
+// b28e04bf ends here
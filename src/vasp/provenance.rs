@@ -0,0 +1,173 @@
+// [[file:../../vasp-tools.note::6e52d772][6e52d772]]
+use super::*;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+// 6e52d772 ends here
+
+// [[file:../../vasp-tools.note::bfa90b1d][bfa90b1d]]
+/// The file recording provenance metadata in run directory
+pub const PROVENANCE_FILE: &str = "provenance.json";
+
+/// One potential in POTCAR
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PotcarEntry {
+    /// The title line, e.g. "PAW_PBE H 15Jun2001"
+    pub title: String,
+    /// The sha256 hash of the potential data
+    pub sha256: String,
+}
+
+/// Environment and input metadata of a VASP run for reproducibility audits
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Provenance {
+    /// The version of vasp-tools
+    pub crate_version: String,
+    /// VASP version from the OUTCAR header
+    pub vasp_version: Option<String>,
+    pub potcar: Vec<PotcarEntry>,
+    /// The content of INCAR
+    pub incar: Option<String>,
+    pub hostname: String,
+    /// The number of MPI ranks from the OUTCAR header
+    pub mpi_ranks: Option<usize>,
+    /// The capture time in RFC 3339 format
+    pub created: String,
+}
+
+/// Parse VASP version from the first lines of OUTCAR text `s`, e.g.
+/// "vasp.6.3.0 20Jan22 (build Mar 11 2022 13:48:16) complex"
+fn parse_vasp_version(s: &str) -> Option<String> {
+    s.lines()
+        .take(5)
+        .map(|line| line.trim())
+        .find(|line| line.starts_with("vasp."))
+        .map(|line| line.to_owned())
+}
+
+/// Parse the number of MPI ranks from OUTCAR header text `s`. VASP 6
+/// prints "running   16 mpi-ranks, ...", and VASP 5 prints "running on   16
+/// total cores".
+fn parse_mpi_ranks(s: &str) -> Option<usize> {
+    s.lines().take(50).find_map(|line| {
+        let line = line.trim();
+        let rest = line.strip_prefix("running")?;
+        let rest = rest.trim_start().strip_prefix("on").unwrap_or(rest);
+        let n = rest.split_whitespace().next()?;
+        if rest.contains("mpi-ranks") || rest.contains("total cores") {
+            n.parse().ok()
+        } else {
+            None
+        }
+    })
+}
+
+/// Split POTCAR text `s` into potentials, and hash each of them.
+fn hash_potcar(s: &str) -> Vec<PotcarEntry> {
+    let mut entries = vec![];
+    let mut lines = vec![];
+    for line in s.lines() {
+        lines.push(line);
+        if line.trim() == "End of Dataset" {
+            let data = lines.join("\n");
+            entries.push(PotcarEntry {
+                title: lines[0].trim().to_owned(),
+                sha256: format!("{:x}", Sha256::digest(data.as_bytes())),
+            });
+            lines.clear();
+        }
+    }
+    entries
+}
+
+pub(crate) fn hostname() -> String {
+    let mut buf = vec![0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } != 0 {
+        return "unknown".into();
+    }
+    let n = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..n]).into_owned()
+}
+
+/// Read the first bytes of `f` for header information
+fn read_header(f: &Path) -> Option<String> {
+    use std::io::Read;
+
+    let mut buf = vec![];
+    std::fs::File::open(f).ok()?.take(64 * 1024).read_to_end(&mut buf).ok()?;
+    Some(String::from_utf8_lossy(&buf).into_owned())
+}
+
+impl Provenance {
+    /// Capture provenance metadata of VASP run in `dir`. Missing files are
+    /// recorded as unknown.
+    pub fn capture(dir: &Path) -> Result<Self> {
+        let header = read_header(&dir.join("OUTCAR")).unwrap_or_default();
+        let potcar = dir.join("POTCAR");
+        let potcar = if potcar.exists() {
+            hash_potcar(&gut::fs::read_file(&potcar)?)
+        } else {
+            vec![]
+        };
+        let incar = gut::fs::read_file(dir.join("INCAR")).ok();
+
+        let prov = Self {
+            crate_version: env!("CARGO_PKG_VERSION").into(),
+            vasp_version: parse_vasp_version(&header),
+            potcar,
+            incar,
+            hostname: hostname(),
+            mpi_ranks: parse_mpi_ranks(&header),
+            created: chrono::Local::now().to_rfc3339(),
+        };
+        Ok(prov)
+    }
+
+    /// Capture provenance metadata of VASP run in `dir` and write it into
+    /// `provenance.json` in `dir`.
+    pub fn record(dir: &Path) -> Result<Self> {
+        let prov = Self::capture(dir)?;
+        let json = serde_json::to_string_pretty(&prov)?;
+        gut::fs::write_to_file(dir.join(PROVENANCE_FILE), &json)?;
+        Ok(prov)
+    }
+
+    /// Read provenance metadata from `provenance.json` in `dir`.
+    pub fn read(dir: &Path) -> Result<Self> {
+        let f = dir.join(PROVENANCE_FILE);
+        let s = gut::fs::read_file(&f).with_context(|| format!("read {:?}", f))?;
+        let prov = serde_json::from_str(&s).with_context(|| format!("invalid provenance file: {:?}", f))?;
+        Ok(prov)
+    }
+}
+// bfa90b1d ends here
+
+// [[file:../../vasp-tools.note::ffafc5db][ffafc5db]]
+#[test]
+fn test_provenance() -> Result<()> {
+    let header = " vasp.6.3.0 20Jan22 (build Mar 11 2022 13:48:16) complex\n  \n executed on             LinuxIFC date 2022.05.20  10:31:02\n running   16 mpi-ranks, with    1 threads/rank\n";
+    assert_eq!(
+        parse_vasp_version(header).as_deref(),
+        Some("vasp.6.3.0 20Jan22 (build Mar 11 2022 13:48:16) complex")
+    );
+    assert_eq!(parse_mpi_ranks(header), Some(16));
+    assert_eq!(parse_mpi_ranks(" running on    8 total cores\n"), Some(8));
+    assert_eq!(parse_mpi_ranks("running\n"), None);
+
+    let potcar = gut::fs::read_file("./tests/files/live-vasp/POTCAR")?;
+    let entries = hash_potcar(&potcar);
+    assert_eq!(entries.len(), 4);
+    assert_eq!(entries[0].title, "PAW_PBE H 15Jun2001");
+    assert_eq!(entries[0].sha256.len(), 64);
+
+    let tdir = tempfile::tempdir()?;
+    gut::fs::write_to_file(tdir.path().join("OUTCAR"), header)?;
+    gut::fs::write_to_file(tdir.path().join("INCAR"), "ENCUT = 400\n")?;
+    let prov = Provenance::record(tdir.path())?;
+    assert_eq!(prov.mpi_ranks, Some(16));
+    assert!(prov.potcar.is_empty());
+    assert_eq!(Provenance::read(tdir.path())?, prov);
+
+    Ok(())
+}
+// ffafc5db ends here
@@ -0,0 +1,368 @@
+// [[file:../../vasp-tools.note::f94f54e4][f94f54e4]]
+use super::*;
+
+use crate::plot::{escape_xml, AsciiPlot};
+use vasp_parsers::outcar::parse_timing;
+use std::collections::BTreeMap;
+// f94f54e4 ends here
+
+// [[file:../../vasp-tools.note::bd3740f9][bd3740f9]]
+/// INCAR tags shown in report if present
+const KEY_TAGS: &[&str] = &[
+    "SYSTEM", "PREC", "ENCUT", "ISTART", "ICHARG", "ISPIN", "MAGMOM", "GGA", "IVDW", "LDAU", "ISMEAR", "SIGMA",
+    "EDIFF", "EDIFFG", "NELM", "NSW", "IBRION", "ISIF", "POTIM", "LREAL", "ALGO", "NCORE", "KPAR",
+];
+
+/// The document format of run report
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    Html,
+    Markdown,
+}
+
+impl std::str::FromStr for ReportFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "html" => Ok(Self::Html),
+            "markdown" | "md" => Ok(Self::Markdown),
+            _ => bail!("unsupported report format: {:?}", s),
+        }
+    }
+}
+
+impl ReportFormat {
+    /// The file extension for report
+    pub fn extension(&self) -> &str {
+        match self {
+            Self::Html => "html",
+            Self::Markdown => "md",
+        }
+    }
+}
+
+/// Building blocks of report document
+enum Block {
+    Heading(String),
+    Paragraph(String),
+    Table(Vec<[String; 2]>),
+    Code(String),
+    Svg(String),
+}
+
+/// Escape the column separator in Markdown table cell
+fn escape_cell(s: &str) -> String {
+    s.replace('|', "\\|")
+}
+
+fn base64_encode(data: &[u8]) -> String {
+    const TABLE: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(TABLE[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn render(title: &str, blocks: &[Block], format: ReportFormat) -> String {
+    let mut doc = String::new();
+    match format {
+        ReportFormat::Markdown => {
+            doc += &format!("# {}\n\n", title);
+            for block in blocks {
+                match block {
+                    Block::Heading(s) => doc += &format!("## {}\n\n", s),
+                    Block::Paragraph(s) => doc += &format!("{}\n\n", s),
+                    Block::Table(rows) => {
+                        doc += "| item | value |\n|---|---|\n";
+                        for [k, v] in rows {
+                            doc += &format!("| {} | {} |\n", escape_cell(k), escape_cell(v));
+                        }
+                        doc += "\n";
+                    }
+                    Block::Code(s) => doc += &format!("```\n{}\n```\n\n", s.trim_end()),
+                    // NOTE: embed SVG as data URI to keep document self-contained
+                    Block::Svg(s) => doc += &format!("![](data:image/svg+xml;base64,{})\n\n", base64_encode(s.as_bytes())),
+                }
+            }
+        }
+        ReportFormat::Html => {
+            doc += "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n";
+            doc += &format!("<title>{}</title>\n", escape_xml(title));
+            doc += "<style>body { font-family: sans-serif; max-width: 960px; margin: auto; } \
+                    table { border-collapse: collapse; } td { border: 1px solid #ccc; padding: 2px 8px; }</style>\n";
+            doc += &format!("</head>\n<body>\n<h1>{}</h1>\n", escape_xml(title));
+            for block in blocks {
+                match block {
+                    Block::Heading(s) => doc += &format!("<h2>{}</h2>\n", escape_xml(s)),
+                    Block::Paragraph(s) => doc += &format!("<p>{}</p>\n", escape_xml(s)),
+                    Block::Table(rows) => {
+                        doc += "<table>\n";
+                        for [k, v] in rows {
+                            doc += &format!("<tr><td>{}</td><td>{}</td></tr>\n", escape_xml(k), escape_xml(v));
+                        }
+                        doc += "</table>\n";
+                    }
+                    Block::Code(s) => doc += &format!("<pre>{}</pre>\n", escape_xml(s.trim_end())),
+                    Block::Svg(s) => doc += &format!("<div>{}</div>\n", s),
+                }
+            }
+            doc += "</body>\n</html>\n";
+        }
+    }
+    doc
+}
+
+/// The verdict on convergence of a VASP calculation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Verdict {
+    /// VASP is still running or was killed
+    #[default]
+    Unfinished,
+    /// SCF of the last ionic step reached NELM
+    ScfNotConverged,
+    /// Single point calculation finished, or ionic relaxation reached
+    /// required accuracy
+    Converged,
+    /// Molecular dynamics finished, which has no convergence criterion
+    Finished,
+    /// Ionic relaxation exhausted NSW or stopped early
+    NotConverged,
+}
+
+impl Verdict {
+    /// VASP finished normally
+    pub fn is_finished(&self) -> bool {
+        *self != Self::Unfinished
+    }
+
+    /// The calculation converged, or finished without convergence
+    /// criterion (molecular dynamics)
+    pub fn is_acceptable(&self) -> bool {
+        matches!(self, Self::Converged | Self::Finished)
+    }
+}
+
+/// Return a verdict with short description on convergence of the
+/// calculation in OUTCAR `s` with INCAR `tags`.
+fn convergence_verdict(
+    s: &str,
+    tags: &BTreeMap<String, String>,
+    nsteps: usize,
+    last_nscf: Option<usize>,
+) -> (Verdict, String) {
+    let get = |tag: &str, default: f64| tags.get(tag).and_then(|x| x.parse::<f64>().ok()).unwrap_or(default);
+    let nelm = get("NELM", 60.0) as usize;
+    let nsw = get("NSW", 0.0) as usize;
+    let ibrion = get("IBRION", if nsw == 0 { -1.0 } else { 0.0 }) as i32;
+
+    if !s.contains("General timing and accounting informations") {
+        (Verdict::Unfinished, "unfinished: VASP is still running or was killed".into())
+    } else if last_nscf.map_or(false, |n| n >= nelm) {
+        let msg = format!("not converged: SCF of the last ionic step reached NELM ({})", nelm);
+        (Verdict::ScfNotConverged, msg)
+    } else if nsw == 0 || ibrion == -1 {
+        (Verdict::Converged, "converged: single point calculation finished".into())
+    } else if s.contains("reached required accuracy") {
+        let msg = format!("converged: ionic relaxation reached required accuracy in {} steps", nsteps);
+        (Verdict::Converged, msg)
+    } else if ibrion == 0 {
+        (Verdict::Finished, format!("finished: molecular dynamics of {} steps", nsteps))
+    } else if nsteps >= nsw {
+        (Verdict::NotConverged, format!("not converged: ionic relaxation exhausted NSW ({})", nsw))
+    } else {
+        (Verdict::NotConverged, "not converged: ionic relaxation stopped early".into())
+    }
+}
+
+/// The convergence status of a VASP run directory
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Convergence {
+    /// A short verdict as in report, e.g. "converged: ionic relaxation
+    /// reached required accuracy in 12 steps"
+    pub verdict: String,
+    /// The typed verdict for deciding what to do with the run
+    #[serde(default)]
+    pub outcome: Verdict,
+    /// VASP finished normally with timing information in OUTCAR
+    pub finished: bool,
+    pub converged: bool,
+    /// The number of ionic steps
+    pub nsteps: usize,
+    /// The energy of last ionic step in eV
+    pub energy: Option<f64>,
+    /// The max force of last ionic step in eV/Å
+    pub fmax: Option<f64>,
+}
+
+/// Check the convergence of VASP run in directory `dir`.
+pub fn check_convergence(dir: &Path) -> Result<Convergence> {
+    let outcar = dir.join("OUTCAR");
+    ensure!(outcar.exists(), "no OUTCAR in {:?}", dir);
+    let s = gut::fs::read_file(&outcar)?;
+    let incar = dir.join("INCAR");
+    let tags = if incar.exists() { incar::parse_tags(&incar)? } else { BTreeMap::new() };
+    let parts = outcar::parse_opt_iters(&outcar, true).unwrap_or_else(|e| {
+        warn!("parse ionic steps failed: {:?}", e);
+        vec![]
+    });
+    let last = parts.last();
+    let (outcome, verdict) = convergence_verdict(&s, &tags, parts.len(), last.and_then(|p| p.nscf));
+    let conv = Convergence {
+        finished: outcome.is_finished(),
+        converged: outcome == Verdict::Converged,
+        verdict,
+        outcome,
+        nsteps: parts.len(),
+        energy: last.and_then(|p| p.energy),
+        fmax: last.and_then(|p| p.fmax),
+    };
+    Ok(conv)
+}
+
+/// Generate a self-contained report of VASP run directory `dir` in `format`.
+pub fn generate_report(dir: &Path, format: ReportFormat) -> Result<String> {
+    let outcar = dir.join("OUTCAR");
+    ensure!(outcar.exists(), "no OUTCAR in {:?}", dir);
+    let s = gut::fs::read_file(&outcar)?;
+    let incar = dir.join("INCAR");
+    let tags = if incar.exists() { incar::parse_tags(&incar)? } else { BTreeMap::new() };
+    let parts = outcar::parse_opt_iters(&outcar, true).unwrap_or_else(|e| {
+        warn!("parse ionic steps failed: {:?}", e);
+        vec![]
+    });
+
+    let mut blocks = vec![];
+    blocks.push(Block::Paragraph(format!(
+        "Generated at {} for {:?}.",
+        chrono::Local::now().to_rfc3339(),
+        dir.canonicalize()?
+    )));
+
+    blocks.push(Block::Heading("Convergence".into()));
+    let last = parts.last();
+    let (_, verdict) = convergence_verdict(&s, &tags, parts.len(), last.and_then(|p| p.nscf));
+    blocks.push(Block::Paragraph(verdict));
+    let mut rows = vec![["ionic steps".to_owned(), parts.len().to_string()]];
+    if let Some(p) = last {
+        let fmt = |x: Option<f64>| x.map(|x| format!("{:.6}", x)).unwrap_or("--".into());
+        rows.push(["final energy (eV)".into(), fmt(p.energy)]);
+        if let Some(e) = vasp_parsers::outcar::parse_last_energies(&s) {
+            rows.push(["final energy without entropy (eV)".into(), fmt(e.without_entropy)]);
+            rows.push(["final energy(sigma→0) (eV)".into(), fmt(e.sigma0)]);
+        }
+        rows.push(["final fmax (eV/Å)".into(), fmt(p.fmax)]);
+        rows.push(["final volume (Å^3)".into(), fmt(p.volume)]);
+        rows.push(["final magnetization".into(), fmt(p.mag)]);
+    }
+    blocks.push(Block::Table(rows));
+
+    blocks.push(Block::Heading("Ionic steps".into()));
+    let mut table = String::from("step   energy (eV)        fmax (eV/Å)   SCF\n");
+    for p in &parts {
+        table += &format!(
+            "{:<6} {:<18} {:<13} {}\n",
+            p.i,
+            p.energy.map(|e| format!("{:.6}", e)).unwrap_or("--".into()),
+            p.fmax.map(|f| format!("{:.6}", f)).unwrap_or("--".into()),
+            p.nscf.map(|n| n.to_string()).unwrap_or("--".into()),
+        );
+    }
+    blocks.push(Block::Code(table));
+    if !parts.is_empty() {
+        let x: Vec<_> = parts.iter().map(|p| p.i as f64).collect();
+        let plots: [(&str, &str, Vec<f64>); 3] = [
+            ("Energy", "energy (eV)", parts.iter().map(|p| p.energy.unwrap_or(f64::NAN)).collect()),
+            (
+                "Max force",
+                "log10(fmax) (eV/Å)",
+                parts.iter().map(|p| p.fmax.map_or(f64::NAN, |f| f.log10())).collect(),
+            ),
+            (
+                "SCF iterations",
+                "SCF",
+                parts.iter().map(|p| p.nscf.map_or(f64::NAN, |n| n as f64)).collect(),
+            ),
+        ];
+        for (title, ylabel, y) in plots {
+            if y.iter().any(|v| v.is_finite()) {
+                let mut plot = AsciiPlot::new();
+                plot.set_title(title);
+                plot.set_xlabel("opt. step");
+                plot.set_ylabel(ylabel);
+                blocks.push(Block::Svg(plot.plot_svg(&x, &y)));
+            }
+        }
+    }
+
+    blocks.push(Block::Heading("Final structure".into()));
+    match ["CONTCAR", "POSCAR"].iter().map(|x| dir.join(x)).find(|f| f.exists()) {
+        Some(f) => {
+            blocks.push(Block::Paragraph(format!("From {:?}:", f.file_name().unwrap())));
+            blocks.push(Block::Code(gut::fs::read_file(&f)?));
+        }
+        None => blocks.push(Block::Paragraph("No CONTCAR or POSCAR found.".into())),
+    }
+
+    blocks.push(Block::Heading("Key INCAR tags".into()));
+    let rows: Vec<_> = KEY_TAGS
+        .iter()
+        .filter_map(|&tag| tags.get(tag).map(|v| [tag.to_owned(), v.to_owned()]))
+        .collect();
+    blocks.push(Block::Table(rows));
+
+    let timing = parse_timing(&s);
+    if !timing.is_empty() {
+        blocks.push(Block::Heading("Timing".into()));
+        blocks.push(Block::Table(timing));
+    }
+
+    let title = tags.get("SYSTEM").map(|x| x.as_str()).unwrap_or("VASP run report");
+    Ok(render(title, &blocks, format))
+}
+// bd3740f9 ends here
+
+// [[file:../../vasp-tools.note::287d9adf][287d9adf]]
+#[test]
+fn test_run_report() {
+    assert_eq!(base64_encode(b"svg"), "c3Zn");
+    assert_eq!(base64_encode(b"<svg>"), "PHN2Zz4=");
+
+    let s = " reached required accuracy - stopping structural energy minimisation
+ General timing and accounting informations for this job:
+                  Total CPU time used (sec):     3061.787
+                          Elapsed time (sec):     3105.242
+                   Maximum memory used (kb):      624112.
+";
+    let timing = parse_timing(s);
+    assert_eq!(timing.len(), 3);
+    assert_eq!(timing[1], ["Elapsed time (sec)".to_owned(), "3105.242".to_owned()]);
+
+    let mut tags = BTreeMap::new();
+    tags.insert("NSW".to_owned(), "100".to_owned());
+    tags.insert("IBRION".to_owned(), "2".to_owned());
+    let (verdict, msg) = convergence_verdict(s, &tags, 10, Some(12));
+    assert_eq!(verdict, Verdict::Converged);
+    assert!(msg.starts_with("converged"));
+    assert_eq!(convergence_verdict(s, &tags, 10, Some(60)).0, Verdict::ScfNotConverged);
+    assert_eq!(convergence_verdict("", &tags, 10, Some(12)).0, Verdict::Unfinished);
+    tags.insert("IBRION".to_owned(), "0".to_owned());
+    assert_eq!(convergence_verdict("General timing and accounting informations", &tags, 10, None).0, Verdict::Finished);
+    assert!(Verdict::Finished.is_acceptable());
+    assert!(!Verdict::NotConverged.is_acceptable());
+
+    let blocks = vec![Block::Heading("a < b".into()), Block::Table(vec![["x|y".into(), "1".into()]])];
+    assert!(render("test", &blocks, ReportFormat::Html).contains("<h2>a &lt; b</h2>"));
+    assert!(render("test", &blocks, ReportFormat::Markdown).contains("| x\\|y | 1 |"));
+}
+// 287d9adf ends here
@@ -0,0 +1,232 @@
+// [[file:../../vasp-tools.note::3b5bd0cd][3b5bd0cd]]
+use super::*;
+// 3b5bd0cd ends here
+
+// [[file:../../vasp-tools.note::f5605e86][f5605e86]]
+/// Files to back up before continuing: (name, keep a copy in place)
+const BACKUP_FILES: &[(&str, bool)] = &[("POSCAR", false), ("CONTCAR", true), ("OUTCAR", false), ("OSZICAR", false)];
+
+/// Return the smallest step index `n` (starting from 1) that no backup file
+/// with suffix `.n` exists in `dir`.
+fn next_backup_index(dir: &Path) -> usize {
+    (1..)
+        .find(|n| {
+            BACKUP_FILES
+                .iter()
+                .all(|(name, _)| !dir.join(format!("{}.{}", name, n)).exists())
+        })
+        .unwrap()
+}
+
+/// Prepare VASP run directory `dir` for continuing the calculation: back up
+/// POSCAR, CONTCAR, OUTCAR and OSZICAR with step-indexed suffixes (e.g.
+/// POSCAR.1), and copy CONTCAR to POSCAR. If `restart_from_files` is true,
+/// INCAR will be updated to read WAVECAR (ISTART = 1) and CHGCAR (ICHARG =
+/// 1) if they are available. Return the step index used for backup.
+pub fn continue_run(dir: &Path, restart_from_files: bool) -> Result<usize> {
+    let contcar = dir.join("CONTCAR");
+    let ok = contcar.metadata().map(|m| m.len() > 0).unwrap_or(false);
+    ensure!(ok, "no valid CONTCAR in {:?}", dir);
+
+    let n = next_backup_index(dir);
+    for &(name, keep) in BACKUP_FILES {
+        let src = dir.join(name);
+        if !src.exists() {
+            continue;
+        }
+        let dst = dir.join(format!("{}.{}", name, n));
+        info!("back up {:?} to {:?}", src, dst);
+        if keep {
+            std::fs::copy(&src, &dst).with_context(|| format!("copy {:?} to {:?}", src, dst))?;
+        } else {
+            std::fs::rename(&src, &dst).with_context(|| format!("rename {:?} to {:?}", src, dst))?;
+        }
+    }
+    std::fs::copy(&contcar, dir.join("POSCAR")).context("copy CONTCAR to POSCAR")?;
+
+    if restart_from_files {
+        let nonempty = |name: &str| dir.join(name).metadata().map(|m| m.len() > 0).unwrap_or(false);
+        let mut params = vec![];
+        if nonempty("WAVECAR") {
+            params.push("ISTART = 1");
+        }
+        if nonempty("CHGCAR") {
+            params.push("ICHARG = 1");
+        }
+        let incar = dir.join("INCAR");
+        if !params.is_empty() && incar.exists() {
+            info!("update INCAR with {:?}", params);
+            let txt = incar::update_with_mandatory_params(&incar, &params)?;
+            gut::fs::write_to_file(&incar, &txt)?;
+        }
+    }
+
+    Ok(n)
+}
+
+/// How to handle WAVECAR or CHGCAR left by previous run when VASP is
+/// restarted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReusePolicy {
+    /// Read the file in the restarted run if available (ISTART/ICHARG = 1)
+    Reuse,
+    /// Remove the file and start from scratch (ISTART = 0, ICHARG = 2)
+    Discard,
+    /// Leave the file and INCAR untouched
+    Keep,
+}
+
+impl std::str::FromStr for ReusePolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "reuse" => Ok(Self::Reuse),
+            "discard" => Ok(Self::Discard),
+            "keep" => Ok(Self::Keep),
+            _ => bail!("invalid reuse policy: {:?}, expect reuse, discard or keep", s),
+        }
+    }
+}
+
+/// The policies for WAVECAR and CHGCAR when VASP is restarted
+#[derive(Debug, Clone)]
+pub struct ReuseOptions {
+    pub wavecar: ReusePolicy,
+    pub chgcar: ReusePolicy,
+}
+
+impl Default for ReuseOptions {
+    fn default() -> Self {
+        Self {
+            wavecar: ReusePolicy::Reuse,
+            chgcar: ReusePolicy::Reuse,
+        }
+    }
+}
+
+/// Handle WAVECAR and CHGCAR in `dir` following `opts` before VASP is
+/// restarted, and update ISTART/ICHARG in INCAR accordingly. Files left by
+/// `crashed` run could be incomplete, so they are discarded instead of
+/// reused. The outputs of previous run are backed up using `continue_run`
+/// if CONTCAR is available. Return the actions taken, which are also logged.
+pub fn prepare_reuse(dir: &Path, opts: &ReuseOptions, crashed: bool) -> Result<Vec<String>> {
+    let mut actions = vec![];
+    if dir.join("CONTCAR").metadata().map(|m| m.len() > 0).unwrap_or(false) {
+        let n = continue_run(dir, false)?;
+        actions.push(format!("back up previous run with suffix .{}", n));
+    }
+
+    let files = [
+        ("WAVECAR", opts.wavecar, "ISTART = 1", "ISTART = 0"),
+        ("CHGCAR", opts.chgcar, "ICHARG = 1", "ICHARG = 2"),
+    ];
+    let mut params = vec![];
+    for (name, policy, reuse, fresh) in files {
+        let f = dir.join(name);
+        let nonempty = f.metadata().map(|m| m.len() > 0).unwrap_or(false);
+        let action = match policy {
+            ReusePolicy::Keep => format!("keep {} as is", name),
+            ReusePolicy::Reuse if nonempty && !crashed => {
+                params.push(reuse);
+                format!("reuse {} ({})", name, reuse)
+            }
+            ReusePolicy::Reuse if !nonempty => {
+                params.push(fresh);
+                format!("no {} to reuse ({})", name, fresh)
+            }
+            _ => {
+                if f.exists() {
+                    std::fs::remove_file(&f).with_context(|| format!("remove {:?}", f))?;
+                }
+                params.push(fresh);
+                if crashed && policy == ReusePolicy::Reuse {
+                    format!("discard {} left by crashed run ({})", name, fresh)
+                } else {
+                    format!("discard {} ({})", name, fresh)
+                }
+            }
+        };
+        info!("{}", action);
+        actions.push(action);
+    }
+    let incar = dir.join("INCAR");
+    if !params.is_empty() && incar.exists() {
+        let txt = incar::update_with_mandatory_params(&incar, &params)?;
+        gut::fs::write_to_file(&incar, &txt)?;
+    }
+    Ok(actions)
+}
+// f5605e86 ends here
+
+// [[file:../../vasp-tools.note::8f6add22][8f6add22]]
+#[test]
+fn test_continue_run() -> Result<()> {
+    let tdir = tempfile::tempdir()?;
+    let dir = tdir.path();
+    assert!(continue_run(dir, false).is_err());
+
+    gut::fs::write_to_file(dir.join("INCAR"), "ISTART = 0\nENCUT = 400")?;
+    gut::fs::write_to_file(dir.join("POSCAR"), "poscar0")?;
+    gut::fs::write_to_file(dir.join("CONTCAR"), "contcar1")?;
+    gut::fs::write_to_file(dir.join("OUTCAR"), "outcar1")?;
+    gut::fs::write_to_file(dir.join("WAVECAR"), "wavecar")?;
+    assert_eq!(continue_run(dir, true)?, 1);
+    assert_eq!(gut::fs::read_file(dir.join("POSCAR"))?, "contcar1");
+    assert_eq!(gut::fs::read_file(dir.join("POSCAR.1"))?, "poscar0");
+    assert_eq!(gut::fs::read_file(dir.join("OUTCAR.1"))?, "outcar1");
+    assert!(!dir.join("OUTCAR").exists());
+    let incar = gut::fs::read_file(dir.join("INCAR"))?;
+    assert!(incar.contains("ISTART = 1"));
+    assert!(!incar.contains("ISTART = 0"));
+    assert!(!incar.contains("ICHARG"));
+
+    gut::fs::write_to_file(dir.join("CONTCAR"), "contcar2")?;
+    assert_eq!(continue_run(dir, false)?, 2);
+    assert_eq!(gut::fs::read_file(dir.join("POSCAR.2"))?, "contcar1");
+    assert_eq!(gut::fs::read_file(dir.join("POSCAR"))?, "contcar2");
+
+    Ok(())
+}
+// 8f6add22 ends here
+
+// [[file:../../vasp-tools.note::0789a60d][0789a60d]]
+#[test]
+fn test_prepare_reuse() -> Result<()> {
+    let tdir = tempfile::tempdir()?;
+    let dir = tdir.path();
+    gut::fs::write_to_file(dir.join("INCAR"), "ISTART = 0\nENCUT = 400")?;
+    gut::fs::write_to_file(dir.join("WAVECAR"), "wavecar")?;
+    gut::fs::write_to_file(dir.join("CHGCAR"), "chgcar")?;
+
+    let actions = prepare_reuse(dir, &ReuseOptions::default(), false)?;
+    assert_eq!(actions, vec!["reuse WAVECAR (ISTART = 1)", "reuse CHGCAR (ICHARG = 1)"]);
+    let incar = gut::fs::read_file(dir.join("INCAR"))?;
+    assert!(incar.contains("ISTART = 1"));
+    assert!(incar.contains("ICHARG = 1"));
+
+    let opts = ReuseOptions {
+        chgcar: "discard".parse()?,
+        ..Default::default()
+    };
+    let actions = prepare_reuse(dir, &opts, true)?;
+    assert_eq!(actions[0], "discard WAVECAR left by crashed run (ISTART = 0)");
+    assert!(!dir.join("WAVECAR").exists());
+    assert!(!dir.join("CHGCAR").exists());
+    let incar = gut::fs::read_file(dir.join("INCAR"))?;
+    assert!(incar.contains("ISTART = 0"));
+    assert!(incar.contains("ICHARG = 2"));
+    assert!("reset".parse::<ReusePolicy>().is_err());
+
+    // continue from CONTCAR of previous run
+    gut::fs::write_to_file(dir.join("POSCAR"), "poscar0")?;
+    gut::fs::write_to_file(dir.join("CONTCAR"), "contcar1")?;
+    gut::fs::write_to_file(dir.join("OUTCAR"), "outcar1")?;
+    let actions = prepare_reuse(dir, &ReuseOptions::default(), false)?;
+    assert_eq!(actions[0], "back up previous run with suffix .1");
+    assert_eq!(gut::fs::read_file(dir.join("POSCAR"))?, "contcar1");
+    assert_eq!(gut::fs::read_file(dir.join("OUTCAR.1"))?, "outcar1");
+
+    Ok(())
+}
+// 0789a60d ends here
@@ -0,0 +1,180 @@
+// [[file:../../vasp-tools.note::57c30927][57c30927]]
+use super::*;
+
+use crate::bbm::Properties;
+use gosh::model::ModelProperties;
+// 57c30927 ends here
+
+// [[file:../../vasp-tools.note::eaaf75dc][eaaf75dc]]
+/// The source where computed results are parsed from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum ResultsSource {
+    Stdout,
+    Vasprun,
+    Outcar,
+}
+
+/// The error when computed results cannot be parsed from any source
+#[derive(Debug)]
+pub struct ParseResultsError {
+    /// The error message of each source tried
+    pub errors: Vec<(ResultsSource, String)>,
+}
+
+impl std::fmt::Display for ParseResultsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "no computed results found")?;
+        for (source, err) in &self.errors {
+            write!(f, "; {:?}: {}", source, err)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ParseResultsError {}
+
+fn check_natoms(props: &Properties, natoms: Option<usize>) -> Result<()> {
+    let n = props.mp.get_forces().map(|f| f.len());
+    ensure!(n.is_some(), "no forces");
+    if let Some(natoms) = natoms {
+        ensure!(n == Some(natoms), "expect forces of {} atoms, got {:?}", natoms, n);
+    }
+    Ok(())
+}
+
+/// Parse stress and energies of the last ionic step in OUTCAR text `s`,
+/// only if its free energy agrees with `free` read from stdout, as OUTCAR
+/// could be outdated. Stress is printed before energies in the same ionic
+/// step.
+fn parse_outcar_step(s: &str, free: f64) -> Option<(Option<[f64; 6]>, outcar::Energies)> {
+    const TOTEN: &str = "free  energy   TOTEN  =";
+
+    let energies = vasp_parsers::outcar::parse_last_energies(s).filter(|e| (e.free - free).abs() < 1e-4)?;
+    let i = s.rfind(TOTEN)?;
+    let j = s[..i].rfind(TOTEN).unwrap_or(0);
+    // NOTE: stress is available only when ISIF >= 1
+    let stress = s[j..i]
+        .lines()
+        .rev()
+        .find(|line| line.trim_start().starts_with("in kB"))
+        .and_then(|line| outcar::parse_stress_line(line).ok());
+    Some((stress, energies))
+}
+
+fn parse_from_stdout(stdout: &str, dir: &Path) -> Result<Properties> {
+    let (energies, forces) = stdout::parse_energies_and_forces(stdout)?;
+    let mut mp = ModelProperties::default();
+    // NOTE: use free energy to be consistent with forces
+    mp.set_energy(energies.free);
+    mp.set_forces(forces);
+    // energy without entropy is only available in OUTCAR, and energies in
+    // stdout are less precise
+    let (stress, energies) = gut::fs::read_file(dir.join("OUTCAR"))
+        .ok()
+        .and_then(|s| parse_outcar_step(&s, energies.free))
+        .unwrap_or((None, energies));
+    Ok(Properties {
+        mp,
+        stress,
+        energies: energies.into(),
+    })
+}
+
+fn parse_from_outcar(dir: &Path) -> Result<Properties> {
+    use gosh::adaptor::ModelAdaptor;
+
+    let f = dir.join("OUTCAR");
+    let mp = gosh::adaptor::Vasp().parse_last(&f)?;
+    let stress = outcar::parse_last_stress(&f).ok();
+    let energies = outcar::parse_last_energies(&f)?;
+    Ok(Properties { mp, stress, energies })
+}
+
+/// Parse computed results of the last step of interactive VASP calculation
+/// in directory `dir`, trying VASP `stdout` first, then vasprun.xml and
+/// OUTCAR. For large systems, there may have no forces in stdout. Return the
+/// results with the source used, or `ParseResultsError` if all failed. If
+/// `natoms` is set, results with forces of a different number of atoms will
+/// be rejected.
+pub async fn parse_last_results(stdout: &str, dir: &Path, natoms: Option<usize>) -> Result<(Properties, ResultsSource)> {
+    let mut errors = vec![];
+    for source in [ResultsSource::Stdout, ResultsSource::Vasprun, ResultsSource::Outcar] {
+        let props = match source {
+            ResultsSource::Stdout => parse_from_stdout(stdout, dir),
+            ResultsSource::Vasprun => {
+                let f = dir.join("vasprun.xml");
+                if f.exists() {
+                    // VASP may be still writing the last calculation, and
+                    // vasprun.xml left by previous run could be outdated
+                    let nsteps = outcar::parse_scf_counts(&dir.join("OUTCAR")).ok().map(|x| x.len());
+                    if nsteps.is_some_and(|n| vasprun::is_outdated(&f, n)) {
+                        Err(format_err!("outdated vasprun.xml"))
+                    } else {
+                        vasprun::read_last_calculation(&f, 1.0, nsteps).await
+                    }
+                } else {
+                    Err(format_err!("no vasprun.xml"))
+                }
+            }
+            ResultsSource::Outcar => parse_from_outcar(dir),
+        };
+        match props.and_then(|props| check_natoms(&props, natoms).map(|_| props)) {
+            Ok(props) => {
+                debug!("computed results read from {:?}", source);
+                return Ok((props, source));
+            }
+            Err(e) => {
+                debug!("parse results from {:?} failed: {:?}", source, e);
+                errors.push((source, format!("{}", e)));
+            }
+        }
+    }
+    Err(ParseResultsError { errors }.into())
+}
+// eaaf75dc ends here
+
+// [[file:../../vasp-tools.note::eed9a31f][eed9a31f]]
+#[tokio::test]
+async fn test_parse_last_results() -> Result<()> {
+    let tdir = tempfile::tempdir()?;
+    let dir = tdir.path();
+
+    let stdout = "FORCES:\n     0.1000000     0.2000000     0.3000000\n   1 F= -.85097948E+02 E0= -.85096866E+02  d E =-.850979E+02  mag=     2.9646\n";
+    let (props, source) = parse_last_results(stdout, dir, Some(1)).await?;
+    assert_eq!(source, ResultsSource::Stdout);
+    assert_eq!(props.mp.get_energy(), Some(-0.85097948E+02));
+    assert_eq!(props.energies.unwrap().sigma0, Some(-0.85096866E+02));
+    assert_eq!(props.stress, None);
+
+    // stress is attached only from OUTCAR of the same ionic step
+    let outcar = "  in kB      1.0     2.0     3.0     0.0     0.0     0.0
+  free  energy   TOTEN  =       -80.00000000 eV
+  in kB      4.0     5.0     6.0     0.0     0.0     0.0
+  free  energy   TOTEN  =       -85.09794800 eV
+";
+    gut::fs::write_to_file(dir.join("OUTCAR"), outcar)?;
+    let (props, _) = parse_last_results(stdout, dir, Some(1)).await?;
+    assert_eq!(props.stress, Some([4.0, 5.0, 6.0, 0.0, 0.0, 0.0]));
+    gut::fs::write_to_file(dir.join("OUTCAR"), &outcar[..outcar.rfind("  in kB").unwrap()])?;
+    let (props, _) = parse_last_results(stdout, dir, Some(1)).await?;
+    assert_eq!(props.stress, None);
+    std::fs::remove_file(dir.join("OUTCAR"))?;
+
+    // fall back to vasprun.xml when stdout has no forces
+    let mut mp = ModelProperties::default();
+    mp.set_energy(-1.0);
+    mp.set_forces(vec![[0.0; 3]; 2]);
+    let s = vasprun::format_calculation(&mp.into());
+    gut::fs::write_to_file(dir.join("vasprun.xml"), &s)?;
+    let (props, source) = parse_last_results("garbage", dir, Some(2)).await?;
+    assert_eq!(source, ResultsSource::Vasprun);
+    assert_eq!(props.mp.get_energy(), Some(-1.0));
+
+    // inconsistent number of atoms
+    let e = parse_last_results(stdout, dir, Some(3)).await.unwrap_err();
+    let e = e.downcast_ref::<ParseResultsError>().unwrap();
+    assert_eq!(e.errors.len(), 3);
+
+    Ok(())
+}
+// eed9a31f ends here
@@ -0,0 +1,115 @@
+// [[file:../../vasp-tools.note::003a2bd3][003a2bd3]]
+use super::*;
+// 003a2bd3 ends here
+
+// [[file:../../vasp-tools.note::a164590a][a164590a]]
+/// Input files copied into scratch directory. WAVECAR and CHGCAR are
+/// copied as VASP will overwrite them.
+const COPIED_FILES: &[&str] = &["INCAR", "POSCAR", "KPOINTS", "ICONST", "WAVECAR", "CHGCAR"];
+/// Read-only input files symlinked into scratch directory
+const LINKED_FILES: &[&str] = &["POTCAR", "vdw_kernel.bindat"];
+/// Output files synced back by default
+pub const DEFAULT_SYNC_FILES: &[&str] = &["OUTCAR", "CONTCAR", "OSZICAR", "vasprun.xml"];
+
+/// A fresh scratch directory with a copy of input set, so that VASP
+/// servers started from the same directory will not trample each other's
+/// output files. The directory is removed on drop unless kept.
+#[derive(Debug)]
+pub struct ScratchDir {
+    // the directory with the original input files
+    origin: PathBuf,
+    tdir: tempfile::TempDir,
+}
+
+impl ScratchDir {
+    /// Create a scratch directory under `root` (the system temporary
+    /// directory if None), with input files of `origin` copied and POTCAR
+    /// symlinked. Missing files are skipped.
+    pub fn create(origin: &Path, root: Option<&Path>) -> Result<Self> {
+        let origin = origin.canonicalize().with_context(|| format!("invalid directory: {:?}", origin))?;
+        let root = root.map(|r| r.to_owned()).unwrap_or_else(std::env::temp_dir);
+        let tdir = tempfile::Builder::new()
+            .prefix("vasp-")
+            .tempdir_in(&root)
+            .with_context(|| format!("create scratch dir in {:?}", root))?;
+        let dir = tdir.path();
+        for name in COPIED_FILES {
+            let src = origin.join(name);
+            if src.exists() {
+                std::fs::copy(&src, dir.join(name)).with_context(|| format!("copy {:?} into {:?}", src, dir))?;
+            }
+        }
+        for name in LINKED_FILES {
+            let src = origin.join(name);
+            if src.exists() {
+                std::os::unix::fs::symlink(&src, dir.join(name))
+                    .with_context(|| format!("link {:?} into {:?}", src, dir))?;
+            }
+        }
+        info!("created scratch dir {:?} for {:?}", dir, origin);
+
+        Ok(Self { origin, tdir })
+    }
+
+    /// The scratch directory where VASP runs
+    pub fn path(&self) -> &Path {
+        self.tdir.path()
+    }
+
+    /// The directory with the original input files
+    pub fn origin(&self) -> &Path {
+        &self.origin
+    }
+
+    /// Copy output `files` in scratch directory back into the original
+    /// directory. Missing files are skipped.
+    pub fn sync_back(&self, files: &[String]) -> Result<()> {
+        for name in files {
+            let src = self.path().join(name);
+            if !src.exists() {
+                continue;
+            }
+            let dst = self.origin.join(name);
+            std::fs::copy(&src, &dst).with_context(|| format!("copy {:?} to {:?}", src, dst))?;
+            debug!("synced {:?} back", name);
+        }
+        Ok(())
+    }
+
+    /// Keep the scratch directory after drop, and return its path.
+    pub fn keep(self) -> PathBuf {
+        self.tdir.into_path()
+    }
+}
+// a164590a ends here
+
+// [[file:../../vasp-tools.note::df9c669b][df9c669b]]
+#[test]
+fn test_scratch_dir() -> Result<()> {
+    let tdir = tempfile::tempdir()?;
+    let origin = tdir.path();
+    gut::fs::write_to_file(origin.join("INCAR"), "IBRION = -1\n")?;
+    gut::fs::write_to_file(origin.join("POTCAR"), "potcar")?;
+    gut::fs::write_to_file(origin.join("OUTCAR"), "old outcar")?;
+
+    let root = tempfile::tempdir()?;
+    let scr = ScratchDir::create(origin, root.path().into())?;
+    let dir = scr.path().to_owned();
+    assert!(dir.starts_with(root.path()));
+    assert_eq!(gut::fs::read_file(dir.join("INCAR"))?, "IBRION = -1\n");
+    assert!(dir.join("POTCAR").symlink_metadata()?.file_type().is_symlink());
+    assert!(!dir.join("OUTCAR").exists());
+    assert!(!dir.join("POSCAR").exists());
+
+    gut::fs::write_to_file(dir.join("OUTCAR"), "new outcar")?;
+    let files: Vec<_> = DEFAULT_SYNC_FILES.iter().map(|x| x.to_string()).collect();
+    scr.sync_back(&files)?;
+    assert_eq!(gut::fs::read_file(origin.join("OUTCAR"))?, "new outcar");
+    assert!(!origin.join("CONTCAR").exists());
+
+    drop(scr);
+    assert!(!dir.exists());
+
+    Ok(())
+}
+// df9c669b ends here
@@ -0,0 +1,100 @@
+// [[file:../../vasp-tools.note::f9675a2c][f9675a2c]]
+use super::*;
+// f9675a2c ends here
+
+// [[file:../../vasp-tools.note::c1bcf7ac][c1bcf7ac]]
+/// Output files saved for each interaction: (name, appended by VASP in
+/// interactive mode). Appended files are always copied, as hard links would
+/// keep growing with the original ones.
+const SNAPSHOT_FILES: &[(&str, bool)] = &[
+    ("OUTCAR", true),
+    ("vasprun.xml", true),
+    ("OSZICAR", true),
+    ("CONTCAR", false),
+];
+
+/// How to save output files into snapshot directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotMode {
+    /// Copy files
+    Copy,
+    /// Create hard links for files rewritten by VASP in each step (e.g.
+    /// CONTCAR), falling back to copy on failure (e.g. across file
+    /// systems). Files appended by VASP are copied.
+    Link,
+}
+
+impl std::str::FromStr for SnapshotMode {
+    type Err = Error;
+
+    /// Parse from "copy" or "link"
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "copy" => Ok(Self::Copy),
+            "link" | "hardlink" => Ok(Self::Link),
+            _ => bail!("invalid snapshot mode: {:?}", s),
+        }
+    }
+}
+
+/// Save OUTCAR, vasprun.xml, OSZICAR and CONTCAR in `wrk_dir` into `step_%04d`
+/// subdirectory for interaction `step`, so that results of each single
+/// point calculation remain inspectable. Missing files are skipped. Return
+/// the snapshot directory.
+pub fn save_step_snapshot(wrk_dir: &Path, step: usize, mode: SnapshotMode) -> Result<PathBuf> {
+    let dir = wrk_dir.join(format!("step_{:04}", step));
+    std::fs::create_dir_all(&dir).with_context(|| format!("create snapshot dir {:?}", dir))?;
+    for &(name, appended) in SNAPSHOT_FILES {
+        let src = wrk_dir.join(name);
+        if !src.exists() {
+            continue;
+        }
+        let dst = dir.join(name);
+        // hard link fails if dst exists
+        if dst.exists() {
+            std::fs::remove_file(&dst)?;
+        }
+        if mode == SnapshotMode::Link && !appended && std::fs::hard_link(&src, &dst).is_ok() {
+            continue;
+        }
+        std::fs::copy(&src, &dst).with_context(|| format!("copy {:?} to {:?}", src, dst))?;
+    }
+    debug!("saved snapshot of step {} in {:?}", step, dir);
+
+    Ok(dir)
+}
+// c1bcf7ac ends here
+
+// [[file:../../vasp-tools.note::87c3f5e0][87c3f5e0]]
+#[test]
+fn test_save_step_snapshot() -> Result<()> {
+    let tdir = tempfile::tempdir()?;
+    let dir = tdir.path();
+    gut::fs::write_to_file(dir.join("OUTCAR"), "outcar1")?;
+    gut::fs::write_to_file(dir.join("OSZICAR"), "oszicar1")?;
+
+    let d = save_step_snapshot(dir, 1, SnapshotMode::Copy)?;
+    assert!(d.ends_with("step_0001"));
+    assert_eq!(gut::fs::read_file(d.join("OUTCAR"))?, "outcar1");
+    assert!(!d.join("vasprun.xml").exists());
+
+    gut::fs::write_to_file(dir.join("OUTCAR"), "outcar2")?;
+    gut::fs::write_to_file(dir.join("CONTCAR"), "contcar2")?;
+    let d = save_step_snapshot(dir, 2, SnapshotMode::Link)?;
+    assert_eq!(gut::fs::read_file(d.join("OUTCAR"))?, "outcar2");
+    assert_eq!(gut::fs::read_file(d.join("CONTCAR"))?, "contcar2");
+    // appended files are copied instead of linked
+    {
+        use std::os::unix::fs::MetadataExt;
+        assert_eq!(d.join("OUTCAR").metadata()?.nlink(), 1);
+        assert_eq!(d.join("CONTCAR").metadata()?.nlink(), 2);
+    }
+    // the previous snapshot is not overwritten
+    assert_eq!(gut::fs::read_file(dir.join("step_0001/OUTCAR"))?, "outcar1");
+
+    assert_eq!("copy".parse::<SnapshotMode>()?, SnapshotMode::Copy);
+    assert!("xx".parse::<SnapshotMode>().is_err());
+
+    Ok(())
+}
+// 87c3f5e0 ends here
@@ -0,0 +1,366 @@
+// [[file:../../vasp-tools.note::b700cbdd][b700cbdd]]
+use super::*;
+
+use crate::units::EV_PER_A2_IN_J_PER_M2;
+use gosh::gchemol::prelude::*;
+use gosh::gchemol::Molecule;
+// b700cbdd ends here
+
+// [[file:../../vasp-tools.note::fa5242e1][fa5242e1]]
+/// Read the number of atoms and lattice vectors from CONTCAR in `dir`, or
+/// POSCAR if CONTCAR is not available.
+fn read_cell(dir: &Path) -> Result<(usize, [[f64; 3]; 3])> {
+    let contcar = dir.join("CONTCAR");
+    let f = if contcar.exists() && std::fs::metadata(&contcar)?.len() > 0 {
+        contcar
+    } else {
+        dir.join("POSCAR")
+    };
+    let s = gut::fs::read_file(&f)?;
+    let lines: Vec<_> = s.lines().collect();
+    let (_, natoms) = poscar::locate_positions(&lines).with_context(|| format!("read {:?}", f))?;
+    let vectors = poscar::read_lattice_vectors(&lines).with_context(|| format!("read {:?}", f))?;
+    Ok((natoms, vectors))
+}
+
+/// Return the surface area in Å^2 of slab with lattice `vectors`, assuming
+/// the surface normal is along the third vector.
+pub fn surface_area(vectors: &[[f64; 3]; 3]) -> f64 {
+    let [a, b, _] = vectors;
+    let n = [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ];
+    (n[0] * n[0] + n[1] * n[1] + n[2] * n[2]).sqrt()
+}
+
+/// Return surface energy in eV/Å^2 of symmetric slab with energy `e_slab`
+/// of `natoms` atoms and surface area `area`, relative to bulk energy per
+/// atom `e_bulk`.
+pub fn surface_energy(e_slab: f64, natoms: usize, e_bulk: f64, area: f64) -> f64 {
+    (e_slab - natoms as f64 * e_bulk) / (2.0 * area)
+}
+
+/// Fit slab energies linearly in the number of atoms, E = E0 + N ε, and
+/// return the bulk energy per atom ε and intercept E0. This avoids the
+/// divergence of surface energy from inconsistent bulk energy in thickness
+/// sweep (Fiorentini and Methfessel).
+pub fn fit_slab_energies(natoms: &[usize], energies: &[f64]) -> Result<(f64, f64)> {
+    ensure!(natoms.len() == energies.len(), "inconsistent number of slabs and energies");
+    ensure!(natoms.len() >= 2, "at least 2 slabs are required for linear fit");
+    let n = natoms.len() as f64;
+    let xm = natoms.iter().map(|&x| x as f64).sum::<f64>() / n;
+    let ym = energies.iter().sum::<f64>() / n;
+    let sxx: f64 = natoms.iter().map(|&x| (x as f64 - xm).powi(2)).sum();
+    ensure!(sxx > 0.0, "slabs should have different number of atoms for linear fit");
+    let sxy: f64 = natoms.iter().zip(energies).map(|(&x, y)| (x as f64 - xm) * (y - ym)).sum();
+    let slope = sxy / sxx;
+    Ok((slope, ym - slope * xm))
+}
+
+/// The surface energy of one slab run directory
+#[derive(Debug, Clone)]
+pub struct SlabEnergy {
+    pub dir: PathBuf,
+    pub natoms: usize,
+    /// The surface area in Å^2
+    pub area: f64,
+    /// The energy of last ionic step in eV
+    pub energy: Option<f64>,
+    pub converged: bool,
+    pub verdict: String,
+    /// The surface energy relative to bulk run in eV/Å^2
+    pub gamma: Option<f64>,
+}
+
+/// The surface energies of slabs with increasing thickness
+#[derive(Debug, Clone)]
+pub struct SurfaceReport {
+    /// The bulk energy per atom in eV
+    pub bulk_energy: Option<f64>,
+    pub slabs: Vec<SlabEnergy>,
+    /// The bulk energy per atom and surface energy in eV/Å^2 from linear fit
+    /// of slab energies, if more than two slabs with the same area
+    pub fitted: Option<(f64, f64)>,
+    /// Warnings about convergence or inconsistent slabs
+    pub warnings: Vec<String>,
+}
+
+/// Compute surface energies of `slabs` (run directories, in increasing
+/// thickness) relative to `bulk` run directory.
+pub fn surface_energies(bulk: &Path, slabs: &[PathBuf]) -> Result<SurfaceReport> {
+    ensure!(!slabs.is_empty(), "no slab run directory");
+    let mut warnings = vec![];
+    let conv = report::check_convergence(bulk).with_context(|| format!("check bulk run in {:?}", bulk))?;
+    if !conv.converged {
+        warnings.push(format!("bulk run in {:?}: {}", bulk, conv.verdict));
+    }
+    let (nbulk, _) = read_cell(bulk)?;
+    let bulk_energy = conv.energy.map(|e| e / nbulk as f64);
+
+    let mut all = vec![];
+    for dir in slabs {
+        let conv = report::check_convergence(dir).with_context(|| format!("check slab run in {:?}", dir))?;
+        if !conv.converged {
+            warnings.push(format!("slab run in {:?}: {}", dir, conv.verdict));
+        }
+        let (natoms, vectors) = read_cell(dir)?;
+        let area = surface_area(&vectors);
+        let gamma = conv.energy.zip(bulk_energy).map(|(e, eb)| surface_energy(e, natoms, eb, area));
+        all.push(SlabEnergy {
+            dir: dir.to_owned(),
+            natoms,
+            area,
+            energy: conv.energy,
+            converged: conv.converged,
+            verdict: conv.verdict,
+            gamma,
+        });
+    }
+
+    let same_area = all.iter().all(|s| (s.area - all[0].area).abs() < 1e-3 * all[0].area);
+    if !same_area {
+        warnings.push("slabs have different surface areas".into());
+    }
+    let (natoms, energies): (Vec<_>, Vec<_>) = all.iter().filter_map(|s| Some((s.natoms, s.energy?))).unzip();
+    let fitted = if same_area && natoms.len() > 2 {
+        let (e_bulk, e0) = fit_slab_energies(&natoms, &energies)?;
+        Some((e_bulk, e0 / (2.0 * all[0].area)))
+    } else {
+        None
+    };
+    Ok(SurfaceReport {
+        bulk_energy,
+        slabs: all,
+        fitted,
+        warnings,
+    })
+}
+
+/// Format surface energies in `report` as a table, with changes from the
+/// thinner slab for thickness convergence.
+pub fn format_surface(report: &SurfaceReport) -> String {
+    let fmt = |x: Option<f64>, prec: usize| x.map(|x| format!("{:.*}", prec, x)).unwrap_or("--".into());
+    let mut txt = format!(
+        "{:<30} {:>6} {:>12} {:>18} {:>14} {:>12} {:>12}\n",
+        "slab", "atoms", "area (Å^2)", "energy (eV)", "γ (eV/Å^2)", "γ (J/m^2)", "Δγ (J/m^2)"
+    );
+    let mut last: Option<f64> = None;
+    for s in &report.slabs {
+        let gamma_si = s.gamma.map(|x| x * EV_PER_A2_IN_J_PER_M2);
+        let delta = gamma_si.zip(last).map(|(x, y)| x - y);
+        txt += &format!(
+            "{:<30} {:>6} {:>12.4} {:>18} {:>14} {:>12} {:>12}\n",
+            s.dir.display(),
+            s.natoms,
+            s.area,
+            fmt(s.energy, 6),
+            fmt(s.gamma, 6),
+            fmt(gamma_si, 4),
+            fmt(delta, 4)
+        );
+        last = gamma_si;
+    }
+    txt += &format!("bulk energy per atom: {} eV\n", fmt(report.bulk_energy, 6));
+    if let Some((e_bulk, gamma)) = report.fitted {
+        txt += &format!(
+            "linear fit of slab energies: bulk energy per atom = {:.6} eV, γ = {:.6} eV/Å^2 ({:.4} J/m^2)\n",
+            e_bulk,
+            gamma,
+            gamma * EV_PER_A2_IN_J_PER_M2
+        );
+    }
+    for w in &report.warnings {
+        txt += &format!("WARNING: {}\n", w);
+    }
+    txt
+}
+// fa5242e1 ends here
+
+// [[file:../../vasp-tools.note::1e673171][1e673171]]
+/// A layer of atoms in slab
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layer {
+    /// The atom indices (starting from 1)
+    pub atoms: Vec<usize>,
+    /// The composition, e.g. "Ti2O4"
+    pub composition: String,
+    /// The mean height in Å along the surface normal
+    pub height: f64,
+    /// The height difference in Å between the highest and lowest atoms
+    pub rumpling: f64,
+}
+
+/// Cluster atoms with element `symbols` and fractional coordinates
+/// `fracs` in lattice `vectors` into layers along the surface normal
+/// (perpendicular to the first two vectors). Atoms with height gap smaller
+/// than `tol` in Å are in the same layer. Layers are sorted from bottom to
+/// top, with slabs crossing the cell boundary unwrapped at the vacuum gap.
+pub fn find_layers(vectors: &[[f64; 3]; 3], fracs: &[[f64; 3]], symbols: &[String], tol: f64) -> Result<Vec<Layer>> {
+    ensure!(!fracs.is_empty(), "no atoms");
+    ensure!(fracs.len() == symbols.len(), "fractional coordinates and symbols mismatch");
+    let area = surface_area(vectors);
+    ensure!(area > 0.0, "invalid lattice: {:?}", vectors);
+    // the height of cell along surface normal
+    let [a, b, c] = vectors;
+    let n = [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ];
+    let hc = ((c[0] * n[0] + c[1] * n[1] + c[2] * n[2]) / area).abs();
+
+    // start from the upper end of the largest gap, i.e. the vacuum
+    let mut zs: Vec<_> = fracs.iter().map(|x| x[2].rem_euclid(1.0)).collect();
+    zs.sort_by(|a, b| a.total_cmp(b));
+    let nz = zs.len();
+    let i = (0..nz)
+        .max_by(|&i, &j| {
+            let gap = |k: usize| (zs[(k + 1) % nz] - zs[k]).rem_euclid(1.0);
+            gap(i).total_cmp(&gap(j))
+        })
+        .unwrap();
+    let origin = zs[(i + 1) % nz];
+
+    let mut heights: Vec<(f64, usize)> = fracs
+        .iter()
+        .enumerate()
+        .map(|(k, x)| ((x[2] - origin).rem_euclid(1.0) * hc, k))
+        .collect();
+    heights.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let mut groups: Vec<Vec<(f64, usize)>> = vec![];
+    for (h, k) in heights {
+        match groups.last_mut() {
+            Some(g) if h - g.last().unwrap().0 < tol => g.push((h, k)),
+            _ => groups.push(vec![(h, k)]),
+        }
+    }
+
+    let layers = groups
+        .into_iter()
+        .map(|g| {
+            let mut composition: Vec<(&str, usize)> = vec![];
+            for &(_, k) in &g {
+                match composition.iter_mut().find(|(e, _)| *e == symbols[k]) {
+                    Some((_, n)) => *n += 1,
+                    None => composition.push((&symbols[k], 1)),
+                }
+            }
+            let first = g.first().unwrap().0;
+            let last = g.last().unwrap().0;
+            Layer {
+                atoms: g.iter().map(|&(_, k)| k + 1).collect(),
+                composition: composition.iter().map(|(e, n)| format!("{}{}", e, n)).collect(),
+                height: g.iter().map(|&(h, _)| h).sum::<f64>() / g.len() as f64,
+                rumpling: last - first,
+            }
+        })
+        .collect();
+    Ok(layers)
+}
+
+/// Detect layers in slab `mol`. See also `find_layers`.
+pub fn detect_layers(mol: &Molecule, tol: f64) -> Result<Vec<Layer>> {
+    let vectors = mol.get_lattice().context("slab has no lattice")?.vectors();
+    let fracs: Vec<_> = mol.get_scaled_positions().context("no fractional coordinates")?.collect();
+    let symbols: Vec<_> = mol.symbols().map(|x| x.to_owned()).collect();
+    find_layers(&vectors, &fracs, &symbols, tol)
+}
+
+/// Format layers of slab `before` relaxation, and interlayer spacings
+/// with their relaxation (Δd) in percent. The reference spacing is
+/// `bulk_spacing` if set, otherwise the spacings before relaxation, which
+/// are bulk-like for slab cut from bulk. Spacings `after` relaxation are
+/// compared if available.
+pub fn format_layers(before: &[Layer], after: Option<&[Layer]>, bulk_spacing: Option<f64>) -> Result<String> {
+    if let Some(after) = after {
+        ensure!(
+            after.len() == before.len(),
+            "different number of layers before and after relaxation: {} vs {}",
+            before.len(),
+            after.len()
+        );
+    }
+    let fmt = |x: Option<f64>, prec: usize| x.map(|x| format!("{:.*}", prec, x)).unwrap_or("--".into());
+    let spacing = |layers: &[Layer], i: usize| layers.get(i + 1).map(|l| l.height - layers[i].height);
+
+    let mut txt = format!(
+        "{:>5} {:>12} {:>10} {:>10} {:>12} {:>12} {:>8}
+",
+        "layer", "composition", "height", "rumpling", "d (before)", "d (after)", "Δd (%)"
+    );
+    for (i, l) in before.iter().enumerate() {
+        let l = after.map_or(l, |a| &a[i]);
+        let d0 = spacing(before, i);
+        let d1 = after.and_then(|a| spacing(a, i));
+        let reference = bulk_spacing.or(d0);
+        let current = if after.is_some() { d1 } else { d0 };
+        let delta = current.zip(reference).map(|(d, r)| (d - r) / r * 100.0);
+        txt += &format!(
+            "{:>5} {:>12} {:>10.4} {:>10.4} {:>12} {:>12} {:>8}\n",
+            i + 1,
+            l.composition,
+            l.height,
+            l.rumpling,
+            fmt(d0, 4),
+            fmt(d1, 4),
+            fmt(delta.filter(|_| after.is_some() || bulk_spacing.is_some()), 2)
+        );
+    }
+    Ok(txt)
+}
+// 1e673171 ends here
+
+// [[file:../../vasp-tools.note::d14f006c][d14f006c]]
+#[test]
+fn test_surface_energy() -> Result<()> {
+    let vectors = [[3.0, 0.0, 0.0], [1.5, 2.0, 0.0], [0.0, 0.0, 20.0]];
+    assert_relative_eq!(surface_area(&vectors), 6.0, epsilon = 1e-10);
+    assert_relative_eq!(surface_energy(-40.0, 10, -4.1, 6.0), 1.0 / 12.0, epsilon = 1e-10);
+
+    // slabs with surface energy of 0.05 eV/Å^2
+    let natoms = [4, 6, 8];
+    let energies: Vec<_> = natoms.iter().map(|&n| n as f64 * -4.0 + 2.0 * 6.0 * 0.05).collect();
+    let (e_bulk, e0) = fit_slab_energies(&natoms, &energies)?;
+    assert_relative_eq!(e_bulk, -4.0, epsilon = 1e-10);
+    assert_relative_eq!(e0 / 12.0, 0.05, epsilon = 1e-10);
+    assert!(fit_slab_energies(&[4, 4], &energies[..2]).is_err());
+
+    let (natoms, vectors) = read_cell("./tests/files/live-vasp".as_ref())?;
+    assert_eq!(natoms, 25);
+    assert!(surface_area(&vectors) > 0.0);
+
+    Ok(())
+}
+// d14f006c ends here
+
+// [[file:../../vasp-tools.note::ee7696bd][ee7696bd]]
+#[test]
+fn test_slab_layers() -> Result<()> {
+    // 4 layers with spacing of 2 Å, the bottom one wrapped to the top
+    let vectors = [[3.0, 0.0, 0.0], [0.0, 3.0, 0.0], [0.0, 0.0, 20.0]];
+    let fracs = [[0.0, 0.0, 0.95], [0.5, 0.5, 0.05], [0.0, 0.0, 0.15], [0.5, 0.5, 0.2505]];
+    let symbols: Vec<String> = ["Pt", "Pt", "Pt", "O"].iter().map(|x| x.to_string()).collect();
+    let layers = find_layers(&vectors, &fracs, &symbols, 0.5)?;
+    assert_eq!(layers.len(), 4);
+    assert_eq!(layers[0].atoms, vec![1]);
+    assert_eq!(layers[3].composition, "O1");
+    assert_relative_eq!(layers[1].height - layers[0].height, 2.0, epsilon = 1e-8);
+
+    // two atoms in the same layer
+    let layers2 = find_layers(&vectors, &fracs, &symbols, 2.5)?;
+    assert_eq!(layers2.len(), 1);
+    assert_eq!(layers2[0].composition, "Pt3O1");
+    assert_relative_eq!(layers2[0].rumpling, 6.01, epsilon = 1e-8);
+
+    // top layer relaxed inwards by 10%
+    let mut relaxed = layers.clone();
+    relaxed[3].height -= 0.201;
+    let txt = format_layers(&layers, Some(&relaxed), None)?;
+    assert!(txt.lines().nth(3).unwrap().trim_end().ends_with("-10.00"), "{}", txt);
+    assert!(format_layers(&layers, Some(&layers2), None).is_err());
+
+    Ok(())
+}
+// ee7696bd ends here
@@ -0,0 +1,503 @@
+// [[file:../../vasp-tools.note::49e3ef4a][49e3ef4a]]
+use super::*;
+
+use gosh::gchemol::prelude::*;
+use gosh::gchemol::{Atom, Lattice, Molecule};
+use serde::Serialize;
+// 49e3ef4a ends here
+
+// [[file:../../vasp-tools.note::640b9daa][640b9daa]]
+type Matrix3 = [[f64; 3]; 3];
+type Rotation = [[i32; 3]; 3];
+
+const IDENTITY: Rotation = [[1, 0, 0], [0, 1, 0], [0, 0, 1]];
+
+fn dot(a: &[f64; 3], b: &[f64; 3]) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn det(m: &Matrix3) -> f64 {
+    m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1]) - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+        + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+}
+
+fn inverse(m: &Matrix3) -> Option<Matrix3> {
+    let d = det(m);
+    if d.abs() < 1e-12 {
+        return None;
+    }
+    let inv = [0, 1, 2].map(|i| {
+        [0, 1, 2].map(|j| {
+            // the cofactor of m[j][i]
+            let (j1, j2, i1, i2) = ((j + 1) % 3, (j + 2) % 3, (i + 1) % 3, (i + 2) % 3);
+            (m[j1][i1] * m[j2][i2] - m[j1][i2] * m[j2][i1]) / d
+        })
+    });
+    Some(inv)
+}
+
+/// Return row vector `x` multiplied by matrix `m`, e.g. Cartesian position
+/// of fractional coordinates `x` in lattice `m`.
+fn mul_vec(x: &[f64; 3], m: &Matrix3) -> [f64; 3] {
+    [0, 1, 2].map(|j| x[0] * m[0][j] + x[1] * m[1][j] + x[2] * m[2][j])
+}
+
+/// Apply rotation `w` on fractional coordinates `x` (column vector).
+fn rotate(w: &Rotation, x: &[f64; 3]) -> [f64; 3] {
+    w.map(|r| r[0] as f64 * x[0] + r[1] as f64 * x[1] + r[2] as f64 * x[2])
+}
+
+/// Wrap fractional coordinate into [0, 1)
+fn wrap(x: f64) -> f64 {
+    let y = x - x.floor();
+    if y > 1.0 - 1e-10 {
+        0.0
+    } else {
+        y
+    }
+}
+
+/// A periodic structure in fractional coordinates
+#[derive(Debug, Clone)]
+struct Cell {
+    /// The lattice vectors in rows
+    lattice: Matrix3,
+    fracs: Vec<[f64; 3]>,
+    symbols: Vec<String>,
+}
+
+impl Cell {
+    fn from_molecule(mol: &Molecule) -> Result<Self> {
+        let lat = mol.get_lattice().context("structure has no lattice")?;
+        ensure!(mol.natoms() > 0, "structure has no atoms");
+        Ok(Self {
+            lattice: lat.vectors(),
+            fracs: mol.get_scaled_positions().context("no fractional coordinates")?.collect(),
+            symbols: mol.symbols().map(|x| x.to_owned()).collect(),
+        })
+    }
+
+    fn to_molecule(&self) -> Molecule {
+        let atoms: Vec<_> = self
+            .fracs
+            .iter()
+            .zip(&self.symbols)
+            .map(|(x, s)| Atom::new(s.as_str(), mul_vec(x, &self.lattice)))
+            .collect();
+        let mut mol = Molecule::from_atoms(atoms);
+        mol.set_lattice(Lattice::new(self.lattice));
+        mol
+    }
+
+    fn natoms(&self) -> usize {
+        self.fracs.len()
+    }
+
+    /// Return the same structure using `lattice` vectors, with fractional
+    /// coordinates wrapped into the new cell.
+    fn with_lattice(&self, lattice: Matrix3) -> Self {
+        let inv = inverse(&lattice).expect("singular lattice");
+        let fracs = self
+            .fracs
+            .iter()
+            .map(|x| mul_vec(&mul_vec(x, &self.lattice), &inv).map(wrap))
+            .collect();
+        Self {
+            lattice,
+            fracs,
+            symbols: self.symbols.clone(),
+        }
+    }
+
+    /// The Cartesian distance between fractional positions `a` and `b`
+    /// under periodic boundary conditions.
+    fn distance(&self, a: &[f64; 3], b: &[f64; 3]) -> f64 {
+        let d = [0, 1, 2].map(|k| a[k] - b[k] - (a[k] - b[k]).round());
+        let r = mul_vec(&d, &self.lattice);
+        dot(&r, &r).sqrt()
+    }
+
+    /// Return the index of atom with `symbol` at fractional position `x`.
+    fn find_atom(&self, symbol: &str, x: &[f64; 3], symprec: f64) -> Option<usize> {
+        (0..self.natoms()).find(|&k| self.symbols[k] == symbol && self.distance(x, &self.fracs[k]) < symprec)
+    }
+}
+
+/// Reduce lattice vectors into short and nearly orthogonal ones, by adding
+/// or subtracting other vectors until no vector can be shortened. The
+/// returned vectors are sorted by length and right-handed.
+fn reduce_lattice(lattice: &Matrix3) -> Matrix3 {
+    let mut v = *lattice;
+    loop {
+        let mut changed = false;
+        for i in 0..3 {
+            let (j, k) = ((i + 1) % 3, (i + 2) % 3);
+            for sj in [-1.0, 0.0, 1.0] {
+                for sk in [-1.0, 0.0, 1.0] {
+                    let w = [0, 1, 2].map(|x| v[i][x] + sj * v[j][x] + sk * v[k][x]);
+                    if dot(&w, &w) < dot(&v[i], &v[i]) - 1e-8 {
+                        v[i] = w;
+                        changed = true;
+                    }
+                }
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+    v.sort_by(|a, b| dot(a, a).total_cmp(&dot(b, b)));
+    if det(&v) < 0.0 {
+        v[2] = v[2].map(|x| -x);
+    }
+    v
+}
+
+/// Return rotations (in fractional coordinates) keeping the metric of
+/// `lattice`, which should be reduced.
+fn lattice_rotations(lattice: &Matrix3, symprec: f64) -> Vec<Rotation> {
+    let g = [0, 1, 2].map(|i| [0, 1, 2].map(|j| dot(&lattice[i], &lattice[j])));
+    let lengths = [0, 1, 2].map(|i| g[i][i].sqrt());
+    let mut rotations = vec![];
+    for n in 0..3usize.pow(9) {
+        let mut w = [[0; 3]; 3];
+        for (k, x) in w.iter_mut().flatten().enumerate() {
+            *x = (n / 3usize.pow(k as u32) % 3) as i32 - 1;
+        }
+        let wf = w.map(|r| r.map(|x| x as f64));
+        if det(&wf).abs() != 1.0 {
+            continue;
+        }
+        // W^T G W == G
+        let keep = (0..3).all(|i| {
+            (0..3).all(|j| {
+                let gij: f64 = (0..3)
+                    .flat_map(|k| (0..3).map(move |l| (k, l)))
+                    .map(|(k, l)| wf[k][i] * g[k][l] * wf[l][j])
+                    .sum();
+                (gij - g[i][j]).abs() < 2.0 * symprec * lengths[i].max(lengths[j])
+            })
+        });
+        if keep {
+            rotations.push(w);
+        }
+    }
+    rotations
+}
+
+/// A space group operation acting on fractional coordinates as x' = Wx + t
+#[derive(Debug, Clone, PartialEq)]
+struct SymOp {
+    rotation: Rotation,
+    translation: [f64; 3],
+}
+
+/// Find space group operations of `cell` with tolerance `symprec` in Å,
+/// including pure translations of non-primitive cell.
+fn find_operations(cell: &Cell, symprec: f64) -> Vec<SymOp> {
+    // the atom of the least frequent element as reference
+    let count = |s: &str| cell.symbols.iter().filter(|x| *x == s).count();
+    let iref = (0..cell.natoms()).min_by_key(|&i| count(&cell.symbols[i])).expect("no atoms");
+    let sref = &cell.symbols[iref];
+
+    let mut ops = vec![];
+    for w in lattice_rotations(&cell.lattice, symprec) {
+        let wx = rotate(&w, &cell.fracs[iref]);
+        for j in (0..cell.natoms()).filter(|&j| &cell.symbols[j] == sref) {
+            let t = [0, 1, 2].map(|k| wrap(cell.fracs[j][k] - wx[k]));
+            let mapped = (0..cell.natoms()).all(|i| {
+                let x = rotate(&w, &cell.fracs[i]);
+                let x = [0, 1, 2].map(|k| x[k] + t[k]);
+                cell.find_atom(&cell.symbols[i], &x, symprec).is_some()
+            });
+            if mapped {
+                ops.push(SymOp {
+                    rotation: w,
+                    translation: t,
+                });
+            }
+        }
+    }
+    ops
+}
+
+/// Find the primitive cell of `cell` with pure translations in `ops`.
+fn find_primitive(cell: &Cell, ops: &[SymOp], symprec: f64) -> Result<Cell> {
+    let translations: Vec<_> = ops
+        .iter()
+        .filter(|op| op.rotation == IDENTITY)
+        .map(|op| op.translation.map(|x| x - x.round()))
+        .collect();
+    let n = translations.len();
+    if n <= 1 {
+        return Ok(cell.clone());
+    }
+
+    // three shortest lattice vectors spanning the volume of primitive cell
+    let mut candidates: Vec<[f64; 3]> = translations.into_iter().filter(|t| dot(t, t) > 1e-8).collect();
+    candidates.extend([[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]]);
+    candidates.sort_by(|a, b| {
+        let (a, b) = (mul_vec(a, &cell.lattice), mul_vec(b, &cell.lattice));
+        dot(&a, &a).total_cmp(&dot(&b, &b))
+    });
+    let m = candidates.len();
+    let basis = (0..m)
+        .flat_map(|i| (i + 1..m).flat_map(move |j| (j + 1..m).map(move |k| (i, j, k))))
+        .map(|(i, j, k)| [candidates[i], candidates[j], candidates[k]])
+        .find(|b| (det(b).abs() * n as f64 - 1.0).abs() < 1e-3)
+        .context("no basis found for primitive cell")?;
+    let lattice = reduce_lattice(&basis.map(|x| mul_vec(&x, &cell.lattice)));
+
+    let wrapped = cell.with_lattice(lattice);
+    let mut primitive = Cell {
+        lattice,
+        fracs: vec![],
+        symbols: vec![],
+    };
+    for (x, s) in wrapped.fracs.iter().zip(&wrapped.symbols) {
+        if primitive.find_atom(s, x, symprec).is_none() {
+            primitive.fracs.push(*x);
+            primitive.symbols.push(s.clone());
+        }
+    }
+    ensure!(
+        primitive.natoms() * n == cell.natoms(),
+        "expect {} atoms in primitive cell, got {}",
+        cell.natoms() / n,
+        primitive.natoms()
+    );
+    Ok(primitive)
+}
+
+/// The 32 crystallographic point groups: Hermann–Mauguin symbol,
+/// Schoenflies symbol, crystal system, and the numbers of rotation types
+/// -6, -4, -3, -2, -1, 1, 2, 3, 4, 6.
+const POINT_GROUPS: [(&str, &str, &str, [usize; 10]); 32] = [
+    ("1", "C1", "triclinic", [0, 0, 0, 0, 0, 1, 0, 0, 0, 0]),
+    ("-1", "Ci", "triclinic", [0, 0, 0, 0, 1, 1, 0, 0, 0, 0]),
+    ("2", "C2", "monoclinic", [0, 0, 0, 0, 0, 1, 1, 0, 0, 0]),
+    ("m", "Cs", "monoclinic", [0, 0, 0, 1, 0, 1, 0, 0, 0, 0]),
+    ("2/m", "C2h", "monoclinic", [0, 0, 0, 1, 1, 1, 1, 0, 0, 0]),
+    ("222", "D2", "orthorhombic", [0, 0, 0, 0, 0, 1, 3, 0, 0, 0]),
+    ("mm2", "C2v", "orthorhombic", [0, 0, 0, 2, 0, 1, 1, 0, 0, 0]),
+    ("mmm", "D2h", "orthorhombic", [0, 0, 0, 3, 1, 1, 3, 0, 0, 0]),
+    ("4", "C4", "tetragonal", [0, 0, 0, 0, 0, 1, 1, 0, 2, 0]),
+    ("-4", "S4", "tetragonal", [0, 2, 0, 0, 0, 1, 1, 0, 0, 0]),
+    ("4/m", "C4h", "tetragonal", [0, 2, 0, 1, 1, 1, 1, 0, 2, 0]),
+    ("422", "D4", "tetragonal", [0, 0, 0, 0, 0, 1, 5, 0, 2, 0]),
+    ("4mm", "C4v", "tetragonal", [0, 0, 0, 4, 0, 1, 1, 0, 2, 0]),
+    ("-42m", "D2d", "tetragonal", [0, 2, 0, 2, 0, 1, 3, 0, 0, 0]),
+    ("4/mmm", "D4h", "tetragonal", [0, 2, 0, 5, 1, 1, 5, 0, 2, 0]),
+    ("3", "C3", "trigonal", [0, 0, 0, 0, 0, 1, 0, 2, 0, 0]),
+    ("-3", "C3i", "trigonal", [0, 0, 2, 0, 1, 1, 0, 2, 0, 0]),
+    ("32", "D3", "trigonal", [0, 0, 0, 0, 0, 1, 3, 2, 0, 0]),
+    ("3m", "C3v", "trigonal", [0, 0, 0, 3, 0, 1, 0, 2, 0, 0]),
+    ("-3m", "D3d", "trigonal", [0, 0, 2, 3, 1, 1, 3, 2, 0, 0]),
+    ("6", "C6", "hexagonal", [0, 0, 0, 0, 0, 1, 1, 2, 0, 2]),
+    ("-6", "C3h", "hexagonal", [2, 0, 0, 1, 0, 1, 0, 2, 0, 0]),
+    ("6/m", "C6h", "hexagonal", [2, 0, 2, 1, 1, 1, 1, 2, 0, 2]),
+    ("622", "D6", "hexagonal", [0, 0, 0, 0, 0, 1, 7, 2, 0, 2]),
+    ("6mm", "C6v", "hexagonal", [0, 0, 0, 6, 0, 1, 1, 2, 0, 2]),
+    ("-6m2", "D3h", "hexagonal", [2, 0, 0, 4, 0, 1, 3, 2, 0, 0]),
+    ("6/mmm", "D6h", "hexagonal", [2, 0, 2, 7, 1, 1, 7, 2, 0, 2]),
+    ("23", "T", "cubic", [0, 0, 0, 0, 0, 1, 3, 8, 0, 0]),
+    ("m-3", "Th", "cubic", [0, 0, 8, 3, 1, 1, 3, 8, 0, 0]),
+    ("432", "O", "cubic", [0, 0, 0, 0, 0, 1, 9, 8, 6, 0]),
+    ("-43m", "Td", "cubic", [0, 6, 0, 6, 0, 1, 3, 8, 0, 0]),
+    ("m-3m", "Oh", "cubic", [0, 6, 8, 9, 1, 1, 9, 8, 6, 0]),
+];
+
+/// Return the index of rotation type of `w` (-6, -4, -3, -2, -1, 1, 2, 3,
+/// 4, 6) in `POINT_GROUPS`.
+fn rotation_type(w: &Rotation) -> Option<usize> {
+    let d = det(&w.map(|r| r.map(|x| x as f64))).round() as i32;
+    let trace = w[0][0] + w[1][1] + w[2][2];
+    let i = match (d, trace) {
+        (-1, -2) => 0,
+        (-1, -1) => 1,
+        (-1, 0) => 2,
+        (-1, 1) => 3,
+        (-1, -3) => 4,
+        (1, 3) => 5,
+        (1, -1) => 6,
+        (1, 0) => 7,
+        (1, 1) => 8,
+        (1, 2) => 9,
+        _ => return None,
+    };
+    Some(i)
+}
+
+/// Symmetry of a periodic structure
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SymmetryInfo {
+    /// The point group in Hermann–Mauguin notation
+    pub point_group: String,
+    /// The point group in Schoenflies notation, as in VASP OUTCAR
+    pub schoenflies: String,
+    pub crystal_system: String,
+    /// The number of symmetry operations without pure translations, i.e.
+    /// the order of point group
+    pub nops: usize,
+    /// The number of lattice points in the input cell
+    pub nlattice_points: usize,
+    pub natoms: usize,
+    /// The number of atoms in primitive cell
+    pub natoms_primitive: usize,
+}
+
+fn analyze_cell(cell: &Cell, symprec: f64) -> Result<SymmetryInfo> {
+    let cell = cell.with_lattice(reduce_lattice(&cell.lattice));
+    let ops = find_operations(&cell, symprec);
+    let nlattice_points = ops.iter().filter(|op| op.rotation == IDENTITY).count();
+    ensure!(nlattice_points > 0, "identity not found, symprec {} too small?", symprec);
+    let mut rotations: Vec<_> = ops.iter().map(|op| op.rotation).collect();
+    rotations.sort();
+    rotations.dedup();
+    let mut counts = [0; 10];
+    for w in &rotations {
+        let i = rotation_type(w).context("invalid rotation")?;
+        counts[i] += 1;
+    }
+    let (hm, sch, system, _) = POINT_GROUPS
+        .iter()
+        .find(|x| x.3 == counts)
+        .with_context(|| format!("no point group found for rotations: {:?}", counts))?;
+
+    Ok(SymmetryInfo {
+        point_group: hm.to_string(),
+        schoenflies: sch.to_string(),
+        crystal_system: system.to_string(),
+        nops: rotations.len(),
+        nlattice_points,
+        natoms: cell.natoms(),
+        natoms_primitive: cell.natoms() / nlattice_points,
+    })
+}
+
+/// Detect symmetry of periodic structure `mol` with tolerance `symprec` in
+/// Å. The space group type is not resolved: structures of different space
+/// groups with the same point group and lattice points are not told apart,
+/// but this is sufficient for choosing ISYM or checking symmetry breaking
+/// in relaxation.
+pub fn analyze_symmetry(mol: &Molecule, symprec: f64) -> Result<SymmetryInfo> {
+    analyze_cell(&Cell::from_molecule(mol)?, symprec)
+}
+
+/// Return the primitive cell of `mol`, with reduced lattice vectors.
+pub fn primitive_cell(mol: &Molecule, symprec: f64) -> Result<Molecule> {
+    let cell = Cell::from_molecule(mol)?;
+    let cell = cell.with_lattice(reduce_lattice(&cell.lattice));
+    let ops = find_operations(&cell, symprec);
+    Ok(find_primitive(&cell, &ops, symprec)?.to_molecule())
+}
+
+/// Return the reduced cell of `mol`: the primitive cell with short and
+/// nearly orthogonal lattice vectors in right-handed order, with atoms
+/// wrapped into the cell and grouped by element as POSCAR does. NOTE: this
+/// is not the conventional standard cell in International Tables.
+pub fn reduced_cell(mol: &Molecule, symprec: f64) -> Result<Molecule> {
+    let mut cell = Cell::from_molecule(&primitive_cell(mol, symprec)?)?;
+    let mut elements: Vec<&str> = vec![];
+    for s in mol.symbols() {
+        if !elements.contains(&s) {
+            elements.push(s);
+        }
+    }
+    let mut atoms: Vec<_> = cell.fracs.into_iter().zip(cell.symbols).collect();
+    atoms.sort_by_key(|(_, s)| elements.iter().position(|x| *x == s.as_str()));
+    (cell.fracs, cell.symbols) = atoms.into_iter().unzip();
+    Ok(cell.to_molecule())
+}
+
+/// Format symmetry `info` as text.
+pub fn format_symmetry(info: &SymmetryInfo) -> String {
+    format!(
+        "point group:     {} ({})
+crystal system:  {}
+symmetry ops:    {}
+lattice points:  {}
+atoms:           {} ({} in primitive cell)
+space group:     not resolved
+",
+        info.point_group,
+        info.schoenflies,
+        info.crystal_system,
+        info.nops,
+        info.nlattice_points,
+        info.natoms,
+        info.natoms_primitive
+    )
+}
+
+/// Format symmetry `before` and `after` relaxation, with a warning if
+/// symmetry changed. Changes keeping point group and lattice points, e.g.
+/// between space groups of the same point group, are not detected.
+pub fn format_symmetry_change(before: &SymmetryInfo, after: &SymmetryInfo) -> String {
+    let mut txt = format!("== before ==\n{}\n== after ==\n{}", format_symmetry(before), format_symmetry(after));
+    if before.nops != after.nops
+        || before.point_group != after.point_group
+        || before.nlattice_points != after.nlattice_points
+    {
+        txt += &format!(
+            "WARNING: symmetry changed in relaxation: {} ({} ops, {} points) -> {} ({} ops, {} points)\n",
+            before.point_group,
+            before.nops,
+            before.nlattice_points,
+            after.point_group,
+            after.nops,
+            after.nlattice_points
+        );
+    }
+    txt
+}
+// 640b9daa ends here
+
+// [[file:../../vasp-tools.note::8b7b711e][8b7b711e]]
+#[test]
+fn test_symmetry() -> Result<()> {
+    // rock salt in conventional cell
+    let a = 5.64;
+    let fcc = [[0.0, 0.0, 0.0], [0.0, 0.5, 0.5], [0.5, 0.0, 0.5], [0.5, 0.5, 0.0]];
+    let mut cell = Cell {
+        lattice: [[a, 0.0, 0.0], [0.0, a, 0.0], [0.0, 0.0, a]],
+        fracs: vec![],
+        symbols: vec![],
+    };
+    for x in fcc {
+        cell.fracs.push(x);
+        cell.symbols.push("Na".into());
+        cell.fracs.push(x.map(|v| wrap(v + 0.5)));
+        cell.symbols.push("Cl".into());
+    }
+    let info = analyze_cell(&cell, 1e-3)?;
+    assert_eq!(info.point_group, "m-3m");
+    assert_eq!(info.schoenflies, "Oh");
+    assert_eq!(info.nops, 48);
+    assert_eq!(info.nlattice_points, 4);
+    assert_eq!(info.natoms_primitive, 2);
+
+    let ops = find_operations(&cell, 1e-3);
+    let primitive = find_primitive(&cell, &ops, 1e-3)?;
+    assert_eq!(primitive.natoms(), 2);
+    assert!((det(&primitive.lattice) - a.powi(3) / 4.0).abs() < 1e-6);
+    assert_eq!(analyze_cell(&primitive, 1e-3)?.nops, 48);
+
+    // tetragonal distortion
+    let mut distorted = cell.clone();
+    distorted.lattice[2][2] *= 1.05;
+    let after = analyze_cell(&distorted, 1e-3)?;
+    assert_eq!(after.point_group, "4/mmm");
+    assert_eq!(after.nops, 16);
+    let txt = format_symmetry_change(&info, &after);
+    assert!(txt.contains("WARNING: symmetry changed"), "{}", txt);
+
+    // small noise within tolerance
+    let mut noisy = cell.clone();
+    noisy.fracs[1][0] += 1e-4;
+    assert_eq!(analyze_cell(&noisy, 1e-2)?.nops, 48);
+    assert_eq!(analyze_cell(&noisy, 1e-5)?.point_group, "4mm");
+
+    Ok(())
+}
+// 8b7b711e ends here
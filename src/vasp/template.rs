@@ -0,0 +1,99 @@
+// [[file:../../vasp-tools.note::cf98dd5a][cf98dd5a]]
+use super::*;
+
+use gosh::gchemol::prelude::*;
+use gosh::gchemol::Molecule;
+// cf98dd5a ends here
+
+// [[file:../../vasp-tools.note::f9fa004f][f9fa004f]]
+/// The extension of template files, rendered with handlebars as BBM
+/// templates
+const TEMPLATE_EXT: &str = "hbs";
+
+/// Prepare VASP input files in `wrk_dir` for `mol` from files in template
+/// directory `tpl_dir`: files with ".hbs" extension (e.g. INCAR.hbs,
+/// KPOINTS.hbs) are rendered with `mol` into files without the extension,
+/// and other files (e.g. POTCAR) are copied as is. POSCAR is written from
+/// `mol` if no template for it. Return the names of files written.
+pub fn render_inputs(tpl_dir: &Path, mol: &Molecule, wrk_dir: &Path) -> Result<Vec<String>> {
+    ensure!(tpl_dir.is_dir(), "invalid template directory: {:?}", tpl_dir);
+    let mut written = vec![];
+    let mut entries: Vec<_> = std::fs::read_dir(tpl_dir)?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.is_file())
+        .collect();
+    // NOTE: "INCAR" sorts before "INCAR.hbs", so rendered files take
+    // precedence over plain ones with the same name
+    entries.sort();
+    for f in entries {
+        let name = f.file_name().expect("template file name").to_string_lossy().to_string();
+        if f.extension().map_or(false, |x| x == TEMPLATE_EXT) {
+            let name = f.file_stem().expect("template file stem").to_string_lossy().to_string();
+            let txt = mol.render_with(&f).with_context(|| format!("render template {:?}", f))?;
+            gut::fs::write_to_file(wrk_dir.join(&name), &txt)?;
+            written.push(name);
+        } else {
+            let dst = wrk_dir.join(&name);
+            // NOTE: template directory could be the working directory
+            if dst.canonicalize().ok() != f.canonicalize().ok() {
+                std::fs::copy(&f, &dst).with_context(|| format!("copy {:?} to {:?}", f, dst))?;
+            }
+            written.push(name);
+        }
+    }
+    written.sort();
+    written.dedup();
+    if !written.iter().any(|x| x == "POSCAR") {
+        ensure!(mol.get_lattice().is_some(), "structure for POSCAR has no lattice");
+        gut::fs::write_to_file(wrk_dir.join("POSCAR"), &mol.format_as("vasp/input")?)?;
+        written.push("POSCAR".into());
+    }
+    ensure!(
+        written.iter().any(|x| x == "INCAR"),
+        "no INCAR or INCAR.{} in template directory {:?}",
+        TEMPLATE_EXT,
+        tpl_dir
+    );
+    info!("prepared VASP input files from template {:?}: {:?}", tpl_dir, written);
+
+    Ok(written)
+}
+
+/// Prepare VASP input files in `wrk_dir` for structure read from file
+/// `structure` (xyz, cif, POSCAR, ...) using templates in `tpl_dir`. See
+/// also `render_inputs`.
+pub fn render_inputs_from(tpl_dir: &Path, structure: &Path, wrk_dir: &Path) -> Result<Vec<String>> {
+    let mol = Molecule::from_file(structure).with_context(|| format!("read structure from {:?}", structure))?;
+    render_inputs(tpl_dir, &mol, wrk_dir)
+}
+// f9fa004f ends here
+
+// [[file:../../vasp-tools.note::d59299a1][d59299a1]]
+#[test]
+fn test_render_inputs() -> Result<()> {
+    let tdir = tempfile::tempdir()?;
+    let tpl = tdir.path().join("template");
+    let wrk = tdir.path().join("run");
+    std::fs::create_dir(&tpl)?;
+    std::fs::create_dir(&wrk)?;
+    gut::fs::write_to_file(tpl.join("INCAR.hbs"), "ENCUT = 400\n")?;
+    gut::fs::write_to_file(tpl.join("POSCAR.hbs"), "H2\n1.0\n")?;
+    gut::fs::write_to_file(tpl.join("POTCAR"), "potcar")?;
+    let mol = Molecule::from_atoms(vec![("H", [0.0, 0.0, 0.0]), ("H", [0.0, 0.0, 0.74])]);
+
+    let written = render_inputs(&tpl, &mol, &wrk)?;
+    assert_eq!(written, vec!["INCAR", "POSCAR", "POTCAR"]);
+    assert_eq!(gut::fs::read_file(wrk.join("INCAR"))?, "ENCUT = 400\n");
+    assert_eq!(gut::fs::read_file(wrk.join("POTCAR"))?, "potcar");
+
+    // POSCAR from structure requires lattice
+    std::fs::remove_file(tpl.join("POSCAR.hbs"))?;
+    assert!(render_inputs(&tpl, &mol, &wrk).is_err());
+    std::fs::remove_file(tpl.join("INCAR.hbs"))?;
+    gut::fs::write_to_file(tpl.join("POSCAR"), "H2\n1.0\n")?;
+    assert!(render_inputs(&tpl, &mol, &wrk).is_err());
+
+    Ok(())
+}
+// d59299a1 ends here
@@ -0,0 +1,254 @@
+// [[file:../../vasp-tools.note::86c7b6bf][86c7b6bf]]
+use super::*;
+
+use crate::bbm::Properties;
+use gosh::model::ModelProperties;
+// 86c7b6bf ends here
+
+// [[file:../../vasp-tools.note::af9ba8f1][af9ba8f1]]
+/// Return the text of the last complete `<calculation>` block in vasprun.xml
+/// content `s`. Return None if the last calculation is still being written.
+fn last_complete_calculation(s: &str) -> Option<&str> {
+    let start = s.rfind("<calculation>")?;
+    let block = &s[start..];
+    let end = block.find("</calculation>")?;
+    Some(&block[..end])
+}
+
+/// Return the number of complete `<calculation>` blocks in vasprun.xml
+/// content `s`, and the last one.
+fn complete_calculations(s: &str) -> (usize, Option<&str>) {
+    let mut n = 0;
+    let mut last = None;
+    let mut rest = s;
+    while let Some(start) = rest.find("<calculation>") {
+        let block = &rest[start..];
+        let next = block[1..].find("<calculation>").map(|i| i + 1).unwrap_or(block.len());
+        match block[..next].find("</calculation>") {
+            Some(end) => {
+                n += 1;
+                last = Some(&block[..end]);
+            }
+            None if next == block.len() => break,
+            // should not happen for vasprun.xml written by VASP
+            None => warn!("found incomplete calculation in the middle of vasprun.xml"),
+        }
+        rest = &block[next..];
+    }
+    (n, last)
+}
+
+/// Parse values in `<v>` elements of `<varray name="{name}">` in `s`.
+fn parse_varray(s: &str, name: &str) -> Option<Vec<Vec<f64>>> {
+    let tag = format!("<varray name=\"{}\"", name);
+    let start = s.find(&tag)?;
+    let block = &s[start..];
+    let end = block.find("</varray>")?;
+    let values = block[..end]
+        .lines()
+        .filter_map(|line| {
+            let line = line.trim();
+            let v = line.strip_prefix("<v>")?.strip_suffix("</v>")?;
+            v.split_whitespace().map(|x| x.parse().ok()).collect()
+        })
+        .collect();
+    Some(values)
+}
+
+/// Parse the value of `<i name="{name}">` in `s`
+fn parse_item(s: &str, name: &str) -> Option<f64> {
+    let tag = format!("<i name=\"{}\">", name);
+    let start = s.find(&tag)? + tag.len();
+    let end = s[start..].find("</i>")?;
+    s[start..start + end].trim().parse().ok()
+}
+
+/// Parse energy, forces and stress from the calculation block `calc`.
+fn parse_calculation(calc: &str) -> Result<Properties> {
+    // the final energies after all scsteps
+    let energy_block = &calc[calc.rfind("<energy>").ok_or(format_err!("no energy in calculation"))?..];
+    // NOTE: use free energy to be consistent with forces
+    let energy = parse_item(energy_block, "e_fr_energy").ok_or(format_err!("no e_fr_energy found"))?;
+    let energies = outcar::Energies {
+        free: energy,
+        without_entropy: parse_item(energy_block, "e_wo_entrp"),
+        sigma0: parse_item(energy_block, "e_0_energy"),
+    };
+    let forces: Vec<[f64; 3]> = parse_varray(calc, "forces")
+        .ok_or(format_err!("no forces found"))?
+        .into_iter()
+        .map(|v| match v.as_slice() {
+            &[x, y, z] => Ok([x, y, z]),
+            _ => bail!("invalid forces: {:?}", v),
+        })
+        .collect::<Result<_>>()?;
+    // stress tensor as 3x3 matrix in kB
+    let stress = parse_varray(calc, "stress").and_then(|m| match m.as_slice() {
+        [a, b, c] if a.len() == 3 && b.len() == 3 && c.len() == 3 => {
+            // XX, YY, ZZ, XY, YZ, ZX as in OUTCAR
+            Some([a[0], b[1], c[2], a[1], b[2], c[0]])
+        }
+        _ => None,
+    });
+
+    let mut mp = ModelProperties::default();
+    mp.set_energy(energy);
+    mp.set_forces(forces);
+    Ok(Properties {
+        mp,
+        stress,
+        energies: energies.into(),
+    })
+}
+
+/// Read energy, forces and stress of the last calculation in vasprun.xml
+/// `f`. As VASP may be still writing the file, it will be read again until
+/// the last calculation is complete for max `timeout` seconds.
+///
+/// If `nsteps` is set, e.g. the number of ionic steps in OUTCAR, vasprun.xml
+/// having less complete calculations is outdated, and an error will be
+/// returned after `timeout`. When the last calculation is still incomplete
+/// after `timeout`, the previous complete one is used if up to date.
+pub async fn read_last_calculation(f: &Path, timeout: f64, nsteps: Option<usize>) -> Result<Properties> {
+    let interval = std::time::Duration::from_secs_f64(0.1);
+    let t = std::time::Instant::now();
+    loop {
+        let s = gut::fs::read_file(f)?;
+        let (n, last) = complete_calculations(&s);
+        let up_to_date = nsteps.map_or(true, |nsteps| n >= nsteps);
+        if up_to_date {
+            if let Some(calc) = last_complete_calculation(&s) {
+                return parse_calculation(calc).with_context(|| format!("parse {:?}", f));
+            }
+        }
+        if t.elapsed().as_secs_f64() >= timeout {
+            ensure!(up_to_date, "outdated {:?}: {} calculations, but {:?} ionic steps", f, n, nsteps);
+            let calc = last.with_context(|| format!("no complete calculation in {:?} after {} seconds", f, timeout))?;
+            warn!("last calculation in {:?} is incomplete, use the previous one", f);
+            return parse_calculation(calc).with_context(|| format!("parse {:?}", f));
+        }
+        debug!("last calculation in {:?} is incomplete or outdated, wait ...", f);
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Return true if vasprun.xml `f` is outdated for `nsteps` ionic steps,
+/// e.g. left by previous run, lagging behind more than the calculation VASP
+/// may be still writing.
+pub fn is_outdated(f: &Path, nsteps: usize) -> bool {
+    match gut::fs::read_file(f) {
+        Ok(s) => complete_calculations(&s).0 + 1 < nsteps,
+        Err(_) => true,
+    }
+}
+
+/// Format `props` as a `<calculation>` block of vasprun.xml, which can be
+/// read back using `read_last_calculation`. This is for simulating VASP
+/// output in tests.
+pub(crate) fn format_calculation(props: &Properties) -> String {
+    let mut s = String::from(" <calculation>\n");
+    if let Some(forces) = props.mp.get_forces() {
+        s += "  <varray name=\"forces\" >\n";
+        for [x, y, z] in forces {
+            s += &format!("   <v> {:16.8} {:16.8} {:16.8} </v>\n", x, y, z);
+        }
+        s += "  </varray>\n";
+    }
+    if let Some([xx, yy, zz, xy, yz, zx]) = props.stress {
+        s += "  <varray name=\"stress\" >\n";
+        for row in [[xx, xy, zx], [xy, yy, yz], [zx, yz, zz]] {
+            s += &format!("   <v> {:16.8} {:16.8} {:16.8} </v>\n", row[0], row[1], row[2]);
+        }
+        s += "  </varray>\n";
+    }
+    let energy = props.mp.get_energy().unwrap_or(f64::NAN);
+    s += &format!("  <energy>\n   <i name=\"e_fr_energy\"> {:16.8} </i>\n  </energy>\n", energy);
+    s += " </calculation>\n";
+    s
+}
+// af9ba8f1 ends here
+
+// [[file:../../vasp-tools.note::e6ee99af][e6ee99af]]
+#[test]
+fn test_vasprun_last_calculation() -> Result<()> {
+    let s = r#"
+ <calculation>
+  <scstep>
+   <energy>
+    <i name="e_fr_energy">    -10.00000000 </i>
+   </energy>
+  </scstep>
+  <varray name="forces" >
+   <v>       0.10000000      0.00000000     -0.20000000 </v>
+   <v>      -0.10000000      0.00000000      0.20000000 </v>
+  </varray>
+  <varray name="stress" >
+   <v>       1.00000000      4.00000000      6.00000000 </v>
+   <v>       4.00000000      2.00000000      5.00000000 </v>
+   <v>       6.00000000      5.00000000      3.00000000 </v>
+  </varray>
+  <energy>
+   <i name="e_fr_energy">    -12.34567890 </i>
+   <i name="e_wo_entrp">    -12.30000000 </i>
+   <i name="e_0_energy">    -12.32000000 </i>
+  </energy>
+ </calculation>
+ <calculation>
+  <scstep>
+"#;
+    // the last one is incomplete
+    let calc = last_complete_calculation(s);
+    assert!(calc.is_none());
+
+    let s = &s[..s.rfind("<calculation>").unwrap()];
+    let calc = last_complete_calculation(s).unwrap();
+    let props = parse_calculation(calc)?;
+    assert_eq!(props.mp.get_energy(), Some(-12.3456789));
+    assert_eq!(props.mp.get_forces().unwrap().len(), 2);
+    assert_eq!(props.stress, Some([1.0, 2.0, 3.0, 4.0, 5.0, 6.0]));
+    let energies = props.energies.unwrap();
+    assert_eq!(energies.without_entropy, Some(-12.3));
+    assert_eq!(energies.sigma0, Some(-12.32));
+
+    let (n, last) = complete_calculations(s);
+    assert_eq!(n, 1);
+    assert_eq!(parse_calculation(last.unwrap())?.mp.get_energy(), Some(-12.3456789));
+
+    let s = format_calculation(&props);
+    let props_ = parse_calculation(last_complete_calculation(&s).unwrap())?;
+    assert_eq!(props_.mp.get_energy(), props.mp.get_energy());
+    assert_eq!(props_.mp.get_forces(), props.mp.get_forces());
+    assert_eq!(props_.stress, props.stress);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_vasprun_read_last_calculation() -> Result<()> {
+    use gosh::model::ModelProperties;
+
+    let tdir = tempfile::tempdir()?;
+    let f = tdir.path().join("vasprun.xml");
+    let calc = |energy| {
+        let mut mp = ModelProperties::default();
+        mp.set_energy(energy);
+        mp.set_forces(vec![[0.0; 3]]);
+        format_calculation(&mp.into())
+    };
+    let s = calc(-1.0) + &calc(-2.0);
+    gut::fs::write_to_file(&f, &s)?;
+    let props = read_last_calculation(&f, 0.0, Some(2)).await?;
+    assert_eq!(props.mp.get_energy(), Some(-2.0));
+    // outdated: less calculations than ionic steps in OUTCAR
+    assert!(read_last_calculation(&f, 0.2, Some(3)).await.is_err());
+    assert!(!is_outdated(&f, 3));
+    assert!(is_outdated(&f, 4));
+
+    // fall back to the previous complete calculation
+    gut::fs::write_to_file(&f, &format!("{} <calculation>\n  <scstep>\n", s))?;
+    let props = read_last_calculation(&f, 0.2, None).await?;
+    assert_eq!(props.mp.get_energy(), Some(-2.0));
+
+    Ok(())
+}
+// e6ee99af ends here
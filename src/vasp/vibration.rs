@@ -0,0 +1,278 @@
+// [[file:../../vasp-tools.note::4128d713][4128d713]]
+use super::*;
+
+use gosh::gchemol::Molecule;
+// 4128d713 ends here
+
+// [[file:../../vasp-tools.note::47798996][47798996]]
+/// The format for exporting vibrational modes to visualization tools
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VibFormat {
+    Molden,
+    /// A Gaussian-log-like output with "Harmonic frequencies" section
+    Gaussian,
+}
+
+impl std::str::FromStr for VibFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "molden" => Ok(Self::Molden),
+            "gaussian" | "log" => Ok(Self::Gaussian),
+            _ => bail!("unsupported vibration format: {:?}", s),
+        }
+    }
+}
+
+/// Read the structure for element symbols from POSCAR or CONTCAR next to
+/// OUTCAR `f`.
+pub fn read_structure_for_outcar(f: &Path) -> Result<Molecule> {
+    use gosh::gchemol::prelude::*;
+
+    let f = ["POSCAR", "CONTCAR"]
+        .iter()
+        .map(|x| f.with_file_name(x))
+        .find(|x| x.exists())
+        .with_context(|| format!("no POSCAR or CONTCAR next to {:?}", f))?;
+    Molecule::from_file(&f).with_context(|| format!("read structure from {:?}", f))
+}
+
+/// Return element symbols and atomic numbers of `mol`, checking it has
+/// the same number of atoms as `modes`.
+fn atom_kinds(mol: &Molecule, modes: &[&VibMode]) -> Result<Vec<(String, usize)>> {
+    let kinds: Vec<_> = mol.atoms().map(|(_, a)| (a.symbol().to_owned(), a.number())).collect();
+    for m in modes {
+        ensure!(
+            m.positions.len() == kinds.len(),
+            "mode {} has {} atoms, but the structure has {}",
+            m.index,
+            m.positions.len(),
+            kinds.len()
+        );
+    }
+    Ok(kinds)
+}
+
+/// Format `modes` in Molden format with element symbols from `mol`.
+/// Atom positions are taken from the first mode.
+pub fn format_molden(mol: &Molecule, modes: &[&VibMode]) -> Result<String> {
+    use crate::units::angstrom_to_bohr;
+
+    ensure!(!modes.is_empty(), "no mode to export");
+    let kinds = atom_kinds(mol, modes)?;
+    let positions = &modes[0].positions;
+
+    let mut txt = String::from("[Molden Format]\n[Atoms] Angs\n");
+    for (i, ((symbol, number), [x, y, z])) in kinds.iter().zip(positions).enumerate() {
+        txt += &format!("{:<3} {:>5} {:>3} {:15.8} {:15.8} {:15.8}\n", symbol, i + 1, number, x, y, z);
+    }
+    txt += "[FREQ]\n";
+    for m in modes {
+        txt += &format!("{:12.4}\n", m.frequency);
+    }
+    txt += "[FR-COORD]\n";
+    for ((symbol, _), p) in kinds.iter().zip(positions) {
+        let [x, y, z] = p.map(angstrom_to_bohr);
+        txt += &format!("{:<3} {:15.8} {:15.8} {:15.8}\n", symbol, x, y, z);
+    }
+    txt += "[FR-NORM-COORD]\n";
+    for (i, m) in modes.iter().enumerate() {
+        txt += &format!("vibration {:>5}\n", i + 1);
+        for [dx, dy, dz] in &m.displacements {
+            txt += &format!("{:12.6} {:12.6} {:12.6}\n", dx, dy, dz);
+        }
+    }
+    Ok(txt)
+}
+
+/// Format `modes` in a Gaussian-log-like format with element symbols
+/// from `mol`, which is understood by GaussView, Molden, Jmol or cclib.
+pub fn format_gaussian_log(mol: &Molecule, modes: &[&VibMode]) -> Result<String> {
+    ensure!(!modes.is_empty(), "no mode to export");
+    let kinds = atom_kinds(mol, modes)?;
+    let positions = &modes[0].positions;
+    let dashes = format!(" {}\n", "-".repeat(69));
+
+    let mut txt = String::from(" Entering Gaussian System (converted from VASP OUTCAR by vasp-tools)\n");
+    txt += "                         Standard orientation:\n";
+    txt += &dashes;
+    txt += " Center     Atomic      Atomic             Coordinates (Angstroms)\n";
+    txt += " Number     Number       Type             X           Y           Z\n";
+    txt += &dashes;
+    for (i, ((_, number), [x, y, z])) in kinds.iter().zip(positions).enumerate() {
+        txt += &format!("{:>7}{:>11}{:>12}    {:12.6}{:12.6}{:12.6}\n", i + 1, number, 0, x, y, z);
+    }
+    txt += &dashes;
+
+    txt += " Harmonic frequencies (cm**-1), IR intensities (KM/Mole), Raman scattering\n";
+    txt += " activities (A**4/AMU), depolarization ratios for plane and unpolarized\n";
+    txt += " incident light, reduced masses (AMU), force constants (mDyne/A),\n";
+    txt += " and normal coordinates:\n";
+    for (k, chunk) in modes.chunks(3).enumerate() {
+        let mut line = String::new();
+        for i in 0..chunk.len() {
+            line += &format!("{:>23}", k * 3 + i + 1);
+        }
+        txt += &format!("{}\n", line);
+        txt += &format!("{}\n", "                      A".repeat(chunk.len()));
+        let row = |label: &str, values: Vec<f64>| {
+            let values: String = values.iter().map(|x| format!("{:>11.4}{:12}", x, "")).collect();
+            format!(" {:<14}{}\n", label, values.trim_end())
+        };
+        txt += &row("Frequencies --", chunk.iter().map(|m| m.frequency).collect());
+        txt += &row("Red. masses --", vec![1.0; chunk.len()]);
+        txt += &row("Frc consts  --", vec![0.0; chunk.len()]);
+        txt += &row("IR Inten    --", vec![0.0; chunk.len()]);
+        txt += &format!("  Atom  AN{}\n", "      X      Y      Z  ".repeat(chunk.len()).trim_end());
+        for (i, (_, number)) in kinds.iter().enumerate() {
+            let mut line = format!("{:>6}{:>4}", i + 1, number);
+            for m in chunk {
+                let [dx, dy, dz] = m.displacements[i];
+                line += &format!("  {:>7.2}{:>7.2}{:>7.2}", dx, dy, dz);
+            }
+            txt += &format!("{}\n", line);
+        }
+    }
+    txt += " Normal termination of Gaussian\n";
+    Ok(txt)
+}
+
+/// Parse selection of atoms (1-based) like "1-4,7", and check that they
+/// are in range of `natoms`.
+pub fn parse_atom_selection(s: &str, natoms: usize) -> Result<Vec<usize>> {
+    let atoms = crate::process::parse_cpu_list(s).with_context(|| format!("invalid atom selection: {:?}", s))?;
+    for &i in &atoms {
+        ensure!(i >= 1 && i <= natoms, "atom {} out of range 1-{}", i, natoms);
+    }
+    Ok(atoms)
+}
+
+/// The projection of a vibrational mode onto selected atoms
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ModeProjection {
+    pub index: usize,
+    /// The frequency in cm-1, negative for imaginary modes
+    pub frequency: f64,
+    /// The fraction of squared displacements on selected atoms
+    pub weight: f64,
+    /// The participation ratio of the mode: 1 if all atoms move equally,
+    /// and 1/N if only one atom moves.
+    pub participation: f64,
+}
+
+/// Project each of `modes` onto `atoms` (1-based).
+pub fn project_modes(modes: &[&VibMode], atoms: &[usize]) -> Vec<ModeProjection> {
+    modes
+        .iter()
+        .map(|m| {
+            let d2: Vec<f64> = m.displacements.iter().map(|[x, y, z]| x * x + y * y + z * z).collect();
+            let total: f64 = d2.iter().sum();
+            let selected: f64 = atoms.iter().filter_map(|&i| d2.get(i - 1)).sum();
+            let sum4: f64 = d2.iter().map(|x| x * x).sum();
+            let (weight, participation) = if total > 0.0 {
+                (selected / total, total * total / (d2.len() as f64 * sum4))
+            } else {
+                (0.0, 0.0)
+            };
+            ModeProjection {
+                index: m.index,
+                frequency: m.frequency,
+                weight,
+                participation,
+            }
+        })
+        .collect()
+}
+
+/// Format `projections` as a table. Modes with weight on selected atoms no
+/// less than `threshold` are marked as belonging to them.
+pub fn format_projections(projections: &[ModeProjection], threshold: f64) -> String {
+    let mut txt = format!(
+        "{:>6} {:>14} {:>10} {:>14}  {}\n",
+        "mode", "freq (cm-1)", "weight", "participation", "belongs to"
+    );
+    for p in projections {
+        let owner = if p.weight >= threshold { "selected" } else { "others" };
+        txt += &format!(
+            "{:>6} {:>14.6} {:>10.4} {:>14.4}  {}\n",
+            p.index, p.frequency, p.weight, p.participation, owner
+        );
+    }
+    txt
+}
+
+/// Format `modes` in `format` with element symbols from `mol`.
+pub fn format_vib_modes_as(mol: &Molecule, modes: &[&VibMode], format: VibFormat) -> Result<String> {
+    match format {
+        VibFormat::Molden => format_molden(mol, modes),
+        VibFormat::Gaussian => format_gaussian_log(mol, modes),
+    }
+}
+// 47798996 ends here
+
+// [[file:../../vasp-tools.note::f9aa0ef5][f9aa0ef5]]
+#[test]
+fn test_vib_export() -> Result<()> {
+    use gosh::gchemol::Atom;
+
+    let atoms = vec![Atom::new("H", [0.0, 0.0, 0.0]), Atom::new("O", [1.0, 0.0, 0.0])];
+    let mol = Molecule::from_atoms(atoms);
+    let modes: Vec<_> = (1..=4)
+        .map(|i| VibMode {
+            index: i,
+            frequency: 100.0 * i as f64 - 150.0,
+            energy: 0.0,
+            positions: vec![[0.0, 0.0, 0.0], [1.0, 0.0, 0.0]],
+            displacements: vec![[0.5, 0.0, 0.0], [-0.5, 0.0, 0.0]],
+        })
+        .collect();
+    let modes: Vec<_> = modes.iter().collect();
+
+    let s = format_molden(&mol, &modes)?;
+    assert!(s.starts_with("[Molden Format]\n[Atoms] Angs\nH "));
+    assert!(s.contains("[FREQ]\n    -50.0000\n"));
+    assert!(s.contains("vibration     4\n"));
+
+    let s = format_gaussian_log(&mol, &modes)?;
+    let freq_lines: Vec<_> = s.lines().filter(|x| x.starts_with(" Frequencies --")).collect();
+    assert_eq!(freq_lines.len(), 2);
+    let values: Vec<f64> = freq_lines[0][15..].split_whitespace().map(|x| x.parse().unwrap()).collect();
+    assert_eq!(values, vec![-50.0, 50.0, 150.0]);
+    assert!(s.contains("     2   8    -0.50   0.00   0.00"));
+
+    let mol = Molecule::from_atoms(vec![Atom::new("H", [0.0, 0.0, 0.0])]);
+    assert!(format_molden(&mol, &modes).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_vib_projection() -> Result<()> {
+    assert_eq!(parse_atom_selection("1-2, 4", 4)?, vec![1, 2, 4]);
+    assert!(parse_atom_selection("0-2", 4).is_err());
+    assert!(parse_atom_selection("5", 4).is_err());
+
+    let mode = |index, displacements| VibMode {
+        index,
+        frequency: 100.0,
+        energy: 0.0,
+        positions: vec![[0.0; 3]; 4],
+        displacements,
+    };
+    // only the adsorbate (atom 4) moves
+    let m1 = mode(1, vec![[0.0; 3], [0.0; 3], [0.0; 3], [0.0, 0.0, 1.0]]);
+    // all atoms move equally
+    let m2 = mode(2, vec![[0.5, 0.0, 0.0]; 4]);
+    let p = project_modes(&[&m1, &m2], &[4]);
+    assert_relative_eq!(p[0].weight, 1.0);
+    assert_relative_eq!(p[0].participation, 0.25);
+    assert_relative_eq!(p[1].weight, 0.25);
+    assert_relative_eq!(p[1].participation, 1.0);
+    let s = format_projections(&p, 0.5);
+    assert!(s.lines().nth(1).unwrap().ends_with("selected"));
+    assert!(s.lines().nth(2).unwrap().ends_with("others"));
+
+    Ok(())
+}
+// f9aa0ef5 ends here
@@ -0,0 +1,299 @@
+// [[file:../../vasp-tools.note::77535389][77535389]]
+use super::*;
+
+use super::report::Convergence;
+use std::collections::HashSet;
+// 77535389 ends here
+
+// [[file:../../vasp-tools.note::8bbaa5c5][8bbaa5c5]]
+/// The format of index file for finished runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexFormat {
+    Csv,
+    /// One JSON object per line
+    Jsonl,
+}
+
+impl std::str::FromStr for IndexFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "csv" => Ok(Self::Csv),
+            "jsonl" | "json" => Ok(Self::Jsonl),
+            _ => bail!("unsupported index format: {:?}", s),
+        }
+    }
+}
+
+impl IndexFormat {
+    /// Guess format from the extension of index file `f`. The default is
+    /// JSONL.
+    pub fn from_path(f: &Path) -> Self {
+        match f.extension().and_then(|x| x.to_str()) {
+            Some("csv") => Self::Csv,
+            _ => Self::Jsonl,
+        }
+    }
+}
+
+/// One finished VASP run in index
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct IndexEntry {
+    directory: PathBuf,
+    #[serde(flatten)]
+    convergence: Convergence,
+    /// The time when the run indexed
+    indexed: String,
+}
+
+const CSV_HEADER: &str = "directory,finished,converged,nsteps,energy,fmax,verdict,indexed";
+
+/// Quote `s` as a CSV field if needed.
+pub(crate) fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
+
+impl IndexEntry {
+    fn to_csv_line(&self) -> String {
+        let c = &self.convergence;
+        let fmt = |x: Option<f64>| x.map(|x| x.to_string()).unwrap_or_default();
+        [
+            csv_field(&self.directory.to_string_lossy()),
+            c.finished.to_string(),
+            c.converged.to_string(),
+            c.nsteps.to_string(),
+            fmt(c.energy),
+            fmt(c.fmax),
+            csv_field(&c.verdict),
+            self.indexed.clone(),
+        ]
+        .join(",")
+    }
+}
+
+/// Watch a tree of VASP run directories, and append summaries of finished
+/// runs into an index file.
+pub struct DirWatcher {
+    root: PathBuf,
+    index: PathBuf,
+    format: IndexFormat,
+    // directories already in index
+    indexed: HashSet<PathBuf>,
+}
+
+impl DirWatcher {
+    /// Create watcher for run directories under `root`, appending results
+    /// into `index` in `format`. Directories already in `index` will be
+    /// skipped.
+    pub fn new(root: &Path, index: &Path, format: IndexFormat) -> Result<Self> {
+        let root = root.canonicalize().with_context(|| format!("invalid root directory: {:?}", root))?;
+        let mut watcher = Self {
+            root,
+            index: index.to_owned(),
+            format,
+            indexed: HashSet::new(),
+        };
+        if index.exists() {
+            watcher.load_index()?;
+        }
+        Ok(watcher)
+    }
+
+    fn load_index(&mut self) -> Result<()> {
+        let s = gut::fs::read_file(&self.index)?;
+        for line in s.lines().filter(|x| !x.trim().is_empty()) {
+            let dir = match self.format {
+                IndexFormat::Jsonl => match serde_json::from_str::<IndexEntry>(line) {
+                    Ok(entry) => entry.directory,
+                    Err(e) => {
+                        warn!("ignore invalid line in index {:?}: {:?}", self.index, e);
+                        continue;
+                    }
+                },
+                IndexFormat::Csv if line == CSV_HEADER => continue,
+                // NOTE: quoted directory name is not expected in practice
+                IndexFormat::Csv => line.split(',').next().unwrap_or_default().trim_matches('"').into(),
+            };
+            self.indexed.insert(dir);
+        }
+        info!("{} runs already in index {:?}", self.indexed.len(), self.index);
+        Ok(())
+    }
+
+    fn append_entry(&self, entry: &IndexEntry) -> Result<()> {
+        use std::io::Write;
+
+        let new_file = !self.index.exists();
+        let mut fp = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.index)
+            .with_context(|| format!("open index file {:?}", self.index))?;
+        match self.format {
+            IndexFormat::Jsonl => writeln!(fp, "{}", serde_json::to_string(entry)?)?,
+            IndexFormat::Csv => {
+                if new_file {
+                    writeln!(fp, "{}", CSV_HEADER)?;
+                }
+                writeln!(fp, "{}", entry.to_csv_line())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Index run directory `dir` if VASP finished in it and not indexed
+    /// yet. Return true if it is newly indexed.
+    pub fn check_dir(&mut self, dir: &Path) -> Result<bool> {
+        let outcar = dir.join("OUTCAR");
+        if self.indexed.contains(dir) || !outcar.is_file() || !outcar::vasp_finished(&outcar)? {
+            return Ok(false);
+        }
+        let convergence = super::report::check_convergence(dir)?;
+        info!("indexed {:?}: {}", dir, convergence.verdict);
+        let entry = IndexEntry {
+            directory: dir.to_owned(),
+            convergence,
+            indexed: chrono::Local::now().to_rfc3339(),
+        };
+        self.append_entry(&entry)?;
+        self.indexed.insert(dir.to_owned());
+        Ok(true)
+    }
+
+    /// Scan all directories under root for finished runs. Return the number
+    /// of newly indexed runs.
+    pub fn scan(&mut self) -> Result<usize> {
+        let mut dirs = vec![];
+        collect_dirs(&self.root, &mut dirs);
+        let mut n = 0;
+        for dir in dirs {
+            match self.check_dir(&dir) {
+                Ok(true) => n += 1,
+                Ok(false) => {}
+                Err(e) => warn!("check {:?} failed: {:?}", dir, e),
+            }
+        }
+        Ok(n)
+    }
+
+    /// Watch root directory for changes of OUTCAR files using inotify, and
+    /// index runs once finished. A full scan will be done every `interval`
+    /// seconds, which is the only way to find finished runs if inotify is
+    /// not available (e.g. on some network file systems).
+    pub fn watch(&mut self, interval: f64) -> Result<()> {
+        use notify::{RecursiveMode, Watcher};
+        use std::sync::mpsc::{channel, RecvTimeoutError};
+
+        let n = self.scan()?;
+        info!("initial scan: {} runs indexed", n);
+
+        let (tx, rx) = channel();
+        let watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+            if let Ok(event) = res {
+                for path in event.paths {
+                    let _ = tx.send(path);
+                }
+            }
+        });
+        // NOTE: keep watcher alive during the loop
+        let _watcher = match watcher {
+            Ok(mut w) => match w.watch(&self.root, RecursiveMode::Recursive) {
+                Ok(_) => Some(w),
+                Err(e) => {
+                    warn!("cannot watch {:?}, fall back to polling: {:?}", self.root, e);
+                    None
+                }
+            },
+            Err(e) => {
+                warn!("inotify not available, fall back to polling: {:?}", e);
+                None
+            }
+        };
+
+        let interval = std::time::Duration::from_secs_f64(interval);
+        info!("watching {:?} for finished VASP runs ...", self.root);
+        // NOTE: a stream of file events should not postpone the full scan
+        let mut last_scan = std::time::Instant::now();
+        loop {
+            let remaining = interval.saturating_sub(last_scan.elapsed());
+            match rx.recv_timeout(remaining) {
+                Ok(path) => {
+                    if path.file_name().map_or(false, |x| x == "OUTCAR") {
+                        if let Some(dir) = path.parent() {
+                            if let Err(e) = self.check_dir(dir) {
+                                warn!("check {:?} failed: {:?}", dir, e);
+                            }
+                        }
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => std::thread::sleep(remaining),
+            }
+            if last_scan.elapsed() >= interval {
+                let n = self.scan()?;
+                debug!("periodic scan: {} runs indexed", n);
+                last_scan = std::time::Instant::now();
+            }
+        }
+    }
+}
+
+/// Collect `dir` and all its sub-directories into `dirs`. Symbolic links
+/// and unreadable directories are ignored.
+fn collect_dirs(dir: &Path, dirs: &mut Vec<PathBuf>) {
+    dirs.push(dir.to_owned());
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("read directory {:?} failed: {:?}", dir, e);
+            return;
+        }
+    };
+    let mut subdirs: Vec<_> = entries
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().map_or(false, |t| t.is_dir()))
+        .map(|e| e.path())
+        .collect();
+    subdirs.sort();
+    for d in subdirs {
+        collect_dirs(&d, dirs);
+    }
+}
+// 8bbaa5c5 ends here
+
+// [[file:../../vasp-tools.note::7ffeab48][7ffeab48]]
+#[test]
+fn test_watch_dir() -> Result<()> {
+    assert_eq!(csv_field("a,b"), "\"a,b\"");
+    assert_eq!(csv_field("converged: ok"), "converged: ok");
+    assert_eq!(IndexFormat::from_path("index.csv".as_ref()), IndexFormat::Csv);
+
+    let tdir = tempfile::tempdir()?;
+    let run1 = tdir.path().join("run1");
+    let run2 = tdir.path().join("a/run2");
+    std::fs::create_dir_all(&run1)?;
+    std::fs::create_dir_all(&run2)?;
+    gut::fs::write_to_file(run1.join("OUTCAR"), " General timing and accounting informations for this job:\n")?;
+    gut::fs::write_to_file(run2.join("OUTCAR"), " running\n")?;
+
+    for (name, format) in [("index.csv", IndexFormat::Csv), ("index.jsonl", IndexFormat::Jsonl)] {
+        let index = tdir.path().join(name);
+        let mut watcher = DirWatcher::new(tdir.path(), &index, format)?;
+        assert_eq!(watcher.scan()?, 1);
+        assert_eq!(watcher.scan()?, 0);
+        // reload from index
+        let mut watcher = DirWatcher::new(tdir.path(), &index, format)?;
+        assert_eq!(watcher.scan()?, 0);
+    }
+    let s = gut::fs::read_file(tdir.path().join("index.csv"))?;
+    assert_eq!(s.lines().count(), 2);
+    assert!(s.contains(",true,true,0,,,converged: single point calculation finished,"));
+
+    Ok(())
+}
+// 7ffeab48 ends here